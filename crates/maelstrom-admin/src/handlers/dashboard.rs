@@ -11,7 +11,7 @@
 //! | `GET /_maelstrom/admin/`        | Overview: uptime, memory, DB health     |
 //! | `GET /_maelstrom/admin/users`   | User management table                   |
 //! | `GET /_maelstrom/admin/rooms`   | Room management table                   |
-//! | `GET /_maelstrom/admin/federation` | Federation status and signing keys   |
+//! | `GET /_maelstrom/admin/federation` | Signing keys and destination health  |
 //!
 //! The overview page gathers live system metrics via the `sysinfo` crate
 //! (memory usage) and the [`AdminState`] (uptime, DB health check).
@@ -26,6 +26,7 @@ use maelstrom_core::matrix::error::MatrixError;
 
 use crate::AdminState;
 use crate::auth::AdminUser;
+use crate::handlers::federation;
 use crate::templates;
 
 pub fn routes() -> Router<AdminState> {
@@ -89,8 +90,36 @@ async fn federation_page(
         .map(|k| k.len())
         .unwrap_or(0);
 
+    let now = chrono::Utc::now();
+    let format_time = |t: Option<chrono::DateTime<chrono::Utc>>| {
+        t.map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let destinations = federation::collect_destinations(&state)
+        .await
+        .into_iter()
+        .map(|d| templates::DestinationRow {
+            status: match d.retry_at {
+                Some(t) if t > now => "Backing off",
+                _ if d.failure_count > 0 => "Retrying",
+                _ => "Healthy",
+            },
+            failure_count: d.failure_count,
+            last_success: format_time(d.last_success_at),
+            retry_at: format_time(d.retry_at.filter(|t| *t > now)),
+            last_error: d.last_error.clone().unwrap_or_default(),
+            pending_pdus: d.pending_pdus,
+            pending_edus: d.pending_edus,
+            pdu_lag: federation::pdu_lag_ms(&d)
+                .map(|ms| format!("{}s", ms / 1000))
+                .unwrap_or_else(|| "-".to_string()),
+            server_name: d.server_name,
+        })
+        .collect();
+
     render(templates::FederationPage {
         server_name: state.server_name().as_str().to_string(),
         signing_key_count: key_count,
+        destinations,
     })
 }
@@ -1,31 +1,83 @@
-//! Federation status and diagnostics.
+//! Federation status, diagnostics and signing key management.
 //!
-//! Provides read-only federation health information. All endpoints require
-//! admin authentication.
+//! All endpoints require admin authentication.
 //!
 //! ## Routes
 //!
-//! | Method | Path                                        | Operation                        |
-//! |--------|---------------------------------------------|----------------------------------|
-//! | `GET`  | `/_maelstrom/admin/v1/federation/stats`     | Signing key count and status     |
+//! | Method | Path                                             | Operation                        |
+//! |--------|--------------------------------------------------|----------------------------------|
+//! | `GET`  | `/_maelstrom/admin/v1/federation/stats`          | Signing key count and status     |
+//! | `GET`  | `/_maelstrom/admin/v1/federation/keys`           | List active and expired keys     |
+//! | `POST` | `/_maelstrom/admin/v1/federation/keys/rotate`    | Generate a new signing key       |
+//! | `GET`  | `/_maelstrom/admin/v1/federation/destinations`   | Delivery health of all destinations |
+//! | `GET`  | `/_maelstrom/admin/v1/federation/destinations/{serverName}` | Delivery health of one destination |
+//! | `POST` | `/_maelstrom/admin/v1/federation/destinations/{serverName}/reset-backoff` | Clear failures and backoff |
+//! | `POST` | `/_maelstrom/admin/v1/federation/destinations/{serverName}/retry` | Retry now, keeping the failure count |
+//! | `POST` | `/_maelstrom/admin/v1/federation/destinations/{serverName}/purge` | Drop the queued PDUs and EDUs |
+//! | `GET`  | `/_maelstrom/admin/v1/federation/policy`         | Allow/deny lists and rejection counts |
+//! | `PUT`  | `/_maelstrom/admin/v1/federation/policy`         | Replace the allow and/or deny list |
 //!
-//! Returns the server name, number of active Ed25519 signing keys, and an
-//! overall federation status indicator.
+//! The stats endpoint returns the server name, number of active Ed25519 signing
+//! keys, and an overall federation status indicator. Rotation is described in
+//! [`crate::signing_keys`].
+//!
+//! Destination health comes from the persisted records written by the
+//! transaction sender, overlaid with the live state of this node's sender
+//! (queue sizes, PDU lag). The controls act on this node's sender and its
+//! persisted record; without a sender they only update the stored record.
+//!
+//! Policy changes apply immediately to inbound requests, outbound requests, media
+//! proxying and key fetches, but are not persisted -- a restart restores the
+//! `[federation]` config. See [`maelstrom_federation::policy`].
 
-use axum::extract::State;
-use axum::routing::get;
+use axum::extract::{Path, State};
+use axum::routing::{get, post};
 use axum::{Json, Router};
+use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_federation::policy::DomainLists;
+use maelstrom_storage::traits::{DestinationRecord, ServerKeyRecord, StorageError};
 
 use crate::AdminState;
 use crate::auth::AdminUser;
+use crate::signing_keys;
 
 pub fn routes() -> Router<AdminState> {
-    Router::new().route(
-        "/_maelstrom/admin/v1/federation/stats",
-        get(federation_stats),
-    )
+    Router::new()
+        .route(
+            "/_maelstrom/admin/v1/federation/stats",
+            get(federation_stats),
+        )
+        .route("/_maelstrom/admin/v1/federation/keys", get(list_keys))
+        .route(
+            "/_maelstrom/admin/v1/federation/keys/rotate",
+            post(rotate_key),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/destinations",
+            get(list_destinations),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/destinations/{serverName}",
+            get(get_destination),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/destinations/{serverName}/reset-backoff",
+            post(reset_backoff),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/destinations/{serverName}/retry",
+            post(force_retry),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/destinations/{serverName}/purge",
+            post(purge_queue),
+        )
+        .route(
+            "/_maelstrom/admin/v1/federation/policy",
+            get(get_policy).put(set_policy),
+        )
 }
 
 async fn federation_stats(
@@ -44,3 +96,286 @@ async fn federation_stats(
         "status": "operational",
     })))
 }
+
+/// Public view of a signing key -- never includes the private key.
+fn key_json(record: &ServerKeyRecord) -> serde_json::Value {
+    serde_json::json!({
+        "key_id": record.key_id,
+        "public_key": record.public_key,
+        "valid_until_ts": record.valid_until.timestamp_millis(),
+        "expired_ts": record.expired_at.map(|t| t.timestamp_millis()),
+    })
+}
+
+async fn list_keys(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let active = storage
+        .get_active_server_keys()
+        .await
+        .map_err(|e| MatrixError::unknown(e.to_string()))?;
+    let expired = storage
+        .get_expired_server_keys()
+        .await
+        .map_err(|e| MatrixError::unknown(e.to_string()))?;
+
+    Ok(Json(serde_json::json!({
+        "current": state.signing_key().map(|k| k.current().key_id().to_string()),
+        "active": active.iter().map(key_json).collect::<Vec<_>>(),
+        "expired": expired.iter().map(key_json).collect::<Vec<_>>(),
+    })))
+}
+
+async fn rotate_key(
+    State(state): State<AdminState>,
+    admin: AdminUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let key = signing_keys::rotate_signing_key(state.storage())
+        .await
+        .map_err(|e| MatrixError::unknown(format!("Key rotation failed: {e}")))?;
+
+    if let Some(shared) = state.signing_key() {
+        shared.replace(key.clone());
+    }
+
+    tracing::info!(admin = %admin.user_id, key_id = %key.key_id(), "Signing key rotated via admin API");
+
+    Ok(Json(serde_json::json!({
+        "key_id": key.key_id(),
+        "public_key": key.public_key_base64(),
+    })))
+}
+
+/// Persisted destination records overlaid with this node's live sender state.
+pub(crate) async fn collect_destinations(state: &AdminState) -> Vec<DestinationRecord> {
+    let mut destinations = state
+        .storage()
+        .list_destinations()
+        .await
+        .unwrap_or_default();
+
+    if let Some(sender) = state.transaction_sender() {
+        for live in sender.destinations() {
+            match destinations
+                .iter_mut()
+                .find(|d| d.server_name == live.server_name)
+            {
+                Some(stored) => *stored = live,
+                None => destinations.push(live),
+            }
+        }
+        destinations.sort_by(|a, b| a.server_name.cmp(&b.server_name));
+    }
+
+    destinations
+}
+
+/// Milliseconds since the oldest undelivered PDU was created, if any is queued.
+pub(crate) fn pdu_lag_ms(record: &DestinationRecord) -> Option<i64> {
+    record
+        .oldest_pending_pdu_ts
+        .map(|ts| (chrono::Utc::now().timestamp_millis() - ts).max(0))
+}
+
+fn destination_error(e: StorageError) -> MatrixError {
+    match e {
+        StorageError::NotFound => MatrixError::not_found("Unknown destination"),
+        other => MatrixError::unknown(other.to_string()),
+    }
+}
+
+fn destination_json(record: &DestinationRecord) -> serde_json::Value {
+    serde_json::json!({
+        "server_name": record.server_name,
+        "last_success_ts": record.last_success_at.map(|t| t.timestamp_millis()),
+        "last_failure_ts": record.last_failure_at.map(|t| t.timestamp_millis()),
+        "failure_count": record.failure_count,
+        "retry_at_ts": record.retry_at.map(|t| t.timestamp_millis()),
+        "last_error": record.last_error,
+        "pending_pdus": record.pending_pdus,
+        "pending_edus": record.pending_edus,
+        "pdu_lag_ms": pdu_lag_ms(record),
+    })
+}
+
+async fn find_destination(
+    state: &AdminState,
+    server_name: &str,
+) -> Result<DestinationRecord, MatrixError> {
+    if let Some(record) = state
+        .transaction_sender()
+        .and_then(|s| s.destination(server_name))
+    {
+        return Ok(record);
+    }
+    state
+        .storage()
+        .get_destination(server_name)
+        .await
+        .map_err(destination_error)
+}
+
+/// This node's sender, if it is responsible for `server_name`. Destinations only
+/// known from storage (e.g. written by another node) are updated in storage.
+fn live_sender<'a>(
+    state: &'a AdminState,
+    server_name: &str,
+) -> Option<&'a maelstrom_federation::sender::TransactionSender> {
+    state
+        .transaction_sender()
+        .filter(|s| s.destination(server_name).is_some())
+}
+
+/// Apply `update` to the stored record of a destination (used when there is no sender).
+async fn update_stored(
+    state: &AdminState,
+    server_name: &str,
+    update: impl FnOnce(&mut DestinationRecord),
+) -> Result<(), MatrixError> {
+    let mut record = state
+        .storage()
+        .get_destination(server_name)
+        .await
+        .map_err(destination_error)?;
+    update(&mut record);
+    state
+        .storage()
+        .upsert_destination(&record)
+        .await
+        .map_err(|e| MatrixError::unknown(e.to_string()))
+}
+
+async fn list_destinations(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let destinations = collect_destinations(&state).await;
+    Ok(Json(serde_json::json!({
+        "destinations": destinations.iter().map(destination_json).collect::<Vec<_>>(),
+        "total": destinations.len(),
+    })))
+}
+
+async fn get_destination(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let record = find_destination(&state, &server_name).await?;
+    Ok(Json(destination_json(&record)))
+}
+
+async fn reset_backoff(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    find_destination(&state, &server_name).await?;
+    match live_sender(&state, &server_name) {
+        Some(sender) => sender.reset_backoff(&server_name).await,
+        None => {
+            update_stored(&state, &server_name, |record| {
+                record.failure_count = 0;
+                record.retry_at = None;
+                record.last_error = None;
+            })
+            .await?
+        }
+    }
+    Ok(Json(serde_json::json!({})))
+}
+
+async fn force_retry(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    find_destination(&state, &server_name).await?;
+    match live_sender(&state, &server_name) {
+        Some(sender) => sender.force_retry(&server_name).await,
+        None => update_stored(&state, &server_name, |record| record.retry_at = None).await?,
+    }
+    Ok(Json(serde_json::json!({})))
+}
+
+async fn purge_queue(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(server_name): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    find_destination(&state, &server_name).await?;
+    let (pdus, edus) = match live_sender(&state, &server_name) {
+        Some(sender) => sender.purge_queue(&server_name).await,
+        None => {
+            update_stored(&state, &server_name, |record| {
+                record.pending_pdus = 0;
+                record.pending_edus = 0;
+                record.oldest_pending_pdu_ts = None;
+            })
+            .await?;
+            (0, 0)
+        }
+    };
+    Ok(Json(serde_json::json!({
+        "purged_pdus": pdus,
+        "purged_edus": edus,
+    })))
+}
+
+fn policy_json(state: &AdminState) -> serde_json::Value {
+    let policy = state.federation_policy();
+    let lists = policy.lists();
+    serde_json::json!({
+        "allow": lists.allow,
+        "deny": lists.deny,
+        "rejections": policy.rejections(),
+    })
+}
+
+/// GET /_maelstrom/admin/v1/federation/policy — current allow/deny lists.
+async fn get_policy(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    Ok(Json(policy_json(&state)))
+}
+
+#[derive(Deserialize)]
+struct PolicyUpdate {
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+}
+
+/// PUT /_maelstrom/admin/v1/federation/policy — replace the lists that are given.
+async fn set_policy(
+    State(state): State<AdminState>,
+    admin: AdminUser,
+    Json(body): Json<PolicyUpdate>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let current = state.federation_policy().lists();
+    let lists = DomainLists {
+        allow: body.allow.unwrap_or(current.allow),
+        deny: body.deny.unwrap_or(current.deny),
+    };
+
+    if lists
+        .allow
+        .iter()
+        .chain(&lists.deny)
+        .any(|p| p.trim().is_empty())
+    {
+        return Err(MatrixError::bad_json("Patterns must not be empty"));
+    }
+
+    tracing::info!(
+        admin = %admin.user_id,
+        allow = ?lists.allow,
+        deny = ?lists.deny,
+        "Federation policy updated via admin API"
+    );
+    state.federation_policy().set_lists(lists);
+
+    Ok(Json(policy_json(&state)))
+}
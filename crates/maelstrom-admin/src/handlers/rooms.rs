@@ -20,6 +20,7 @@ use axum::{Json, Router};
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_storage::traits::DirectoryNetwork;
 
 use crate::AdminState;
 use crate::auth::AdminUser;
@@ -52,7 +53,12 @@ async fn list_rooms(
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let (rooms, total) = state
         .storage()
-        .get_public_rooms(query.limit, query.from.as_deref(), None)
+        .get_public_rooms(
+            query.limit,
+            query.from.as_deref(),
+            None,
+            DirectoryNetwork::Matrix,
+        )
         .await
         .map_err(|e| MatrixError::unknown(format!("{e}")))?;
 
@@ -85,6 +85,8 @@ async fn prometheus_metrics(
     let uptime = state.uptime_secs();
     let db_healthy = state.storage().is_healthy().await;
 
+    let rejections = state.federation_policy().rejections();
+
     let metrics = format!(
         "# HELP maelstrom_uptime_seconds Server uptime in seconds\n\
          # TYPE maelstrom_uptime_seconds gauge\n\
@@ -97,10 +99,20 @@ async fn prometheus_metrics(
          maelstrom_memory_total_bytes {}\n\
          # HELP maelstrom_database_up Database connectivity (1=up, 0=down)\n\
          # TYPE maelstrom_database_up gauge\n\
-         maelstrom_database_up {}\n",
+         maelstrom_database_up {}\n\
+         # HELP maelstrom_federation_policy_rejections_total Federation requests rejected by the allow/deny policy\n\
+         # TYPE maelstrom_federation_policy_rejections_total counter\n\
+         maelstrom_federation_policy_rejections_total{{direction=\"inbound\"}} {}\n\
+         maelstrom_federation_policy_rejections_total{{direction=\"outbound\"}} {}\n\
+         maelstrom_federation_policy_rejections_total{{direction=\"media\"}} {}\n\
+         maelstrom_federation_policy_rejections_total{{direction=\"key_fetch\"}} {}\n",
         sys.used_memory(),
         sys.total_memory(),
         if db_healthy { 1 } else { 0 },
+        rejections.inbound,
+        rejections.outbound,
+        rejections.media,
+        rejections.key_fetch,
     );
 
     Ok(metrics)
@@ -15,13 +15,15 @@
 //! | `DELETE` | `/_maelstrom/admin/v1/users/{userId}/admin`       | Revoke admin flag   |
 //! | `POST`   | `/_maelstrom/admin/v1/users/{userId}/reset-password` | Reset password   |
 //! | `GET`    | `/_maelstrom/admin/v1/users/{userId}/devices`     | List user devices   |
+//! | `GET`    | `/_maelstrom/admin/v1/users/{userId}/cross-signing` | Cross-signing keys and master key history |
+//! | `POST`   | `/_maelstrom/admin/v1/users/{userId}/cross-signing/approve-reset` | Allow a key reset without UIA |
 
 use axum::extract::{Path, State};
 use axum::routing::{delete, get, post, put};
 use axum::{Json, Router};
 use serde::Deserialize;
 
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
 use maelstrom_storage::traits::StorageError;
 
 use crate::AdminState;
@@ -52,8 +54,20 @@ pub fn routes() -> Router<AdminState> {
             "/_maelstrom/admin/v1/users/{userId}/devices",
             get(list_devices),
         )
+        .route(
+            "/_maelstrom/admin/v1/users/{userId}/cross-signing",
+            get(get_cross_signing),
+        )
+        .route(
+            "/_maelstrom/admin/v1/users/{userId}/cross-signing/approve-reset",
+            post(approve_cross_signing_reset),
+        )
 }
 
+/// How long a user has to reset their cross-signing keys after an admin
+/// approved it.
+const CROSS_SIGNING_RESET_WINDOW: chrono::Duration = chrono::Duration::minutes(10);
+
 async fn list_users(
     State(_state): State<AdminState>,
     _admin: AdminUser,
@@ -252,3 +266,79 @@ async fn list_devices(
 
     Ok(Json(serde_json::json!({"devices": device_list})))
 }
+
+async fn get_cross_signing(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let keys = storage
+        .get_cross_signing_keys(&user_id)
+        .await
+        .map_err(|e| MatrixError::unknown(format!("{e}")))?;
+    let history = storage
+        .get_master_key_history(&user_id)
+        .await
+        .map_err(|e| MatrixError::unknown(format!("{e}")))?;
+    let reset_allowed_until = storage
+        .get_cross_signing_reset_allowed_until(&user_id)
+        .await
+        .map_err(|e| MatrixError::unknown(format!("{e}")))?
+        .filter(|until| *until > chrono::Utc::now());
+
+    let history: Vec<serde_json::Value> = history
+        .iter()
+        .map(|replaced| {
+            serde_json::json!({
+                "key": replaced.key,
+                "replaced_at": replaced.replaced_at.to_rfc3339(),
+            })
+        })
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "user_id": user_id,
+        "keys": keys,
+        "master_key_history": history,
+        "reset_allowed_until": reset_allowed_until.map(|until| until.to_rfc3339()),
+    })))
+}
+
+/// Let the user replace their cross-signing keys without UIA for a while, for
+/// users who lost their password or have none (MSC4312). Only local users
+/// can be approved: a remote user's keys are replaced on their own server.
+async fn approve_cross_signing_reset(
+    State(state): State<AdminState>,
+    _admin: AdminUser,
+    Path(user_id): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let uid = maelstrom_core::matrix::id::UserId::parse(&user_id)
+        .map_err(|_| MatrixError::bad_json("Invalid user ID"))?;
+    if uid.server_name() != state.server_name().as_str() {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "Not a local user",
+        ));
+    }
+    state
+        .storage()
+        .get_user(uid.localpart())
+        .await
+        .map_err(|e| match e {
+            StorageError::NotFound => MatrixError::not_found("User not found"),
+            other => MatrixError::unknown(format!("{other}")),
+        })?;
+
+    let until = chrono::Utc::now() + CROSS_SIGNING_RESET_WINDOW;
+    state
+        .storage()
+        .set_cross_signing_reset_allowed_until(&user_id, Some(until))
+        .await
+        .map_err(|e| MatrixError::unknown(format!("{e}")))?;
+
+    Ok(Json(serde_json::json!({
+        "reset_allowed_until": until.to_rfc3339(),
+    })))
+}
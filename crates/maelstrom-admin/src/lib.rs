@@ -22,17 +22,25 @@
 //! ## State
 //!
 //! All handlers share an [`AdminState`] that wraps a boxed `Storage` trait object,
-//! the server name, process uptime, and a mutable [`RetentionConfig`] that the
-//! media retention endpoint can update at runtime.
+//! the server name, process uptime, a mutable [`RetentionConfig`] that the
+//! media retention endpoint can update at runtime, and optionally the running
+//! server's signing key handle (so key rotation takes effect immediately) and
+//! federation transaction sender (for destination health and controls). The
+//! server-wide federation policy is shared with the federation subsystem, so
+//! allow/deny list changes apply to the running server.
 
 pub mod auth;
 pub mod handlers;
 pub mod router;
+pub mod signing_keys;
 pub mod templates;
 
 use std::sync::{Arc, Mutex};
 
 use maelstrom_core::matrix::id::ServerName;
+use maelstrom_core::matrix::keys::SharedKeyPair;
+use maelstrom_federation::policy::FederationPolicy;
+use maelstrom_federation::sender::TransactionSender;
 use maelstrom_storage::traits::Storage;
 
 /// Media retention policy configuration.
@@ -64,6 +72,12 @@ struct AdminStateInner {
     server_name: ServerName,
     start_time: std::time::Instant,
     retention_config: Mutex<RetentionConfig>,
+    /// The running server's signing key, switched immediately on rotation.
+    signing_key: Option<SharedKeyPair>,
+    /// This node's outbound federation sender, for destination controls.
+    transaction_sender: Option<Arc<TransactionSender>>,
+    /// Federation allow/deny lists, shared with the federation subsystem.
+    federation_policy: Arc<FederationPolicy>,
 }
 
 impl AdminState {
@@ -74,6 +88,9 @@ impl AdminState {
                 server_name,
                 start_time: std::time::Instant::now(),
                 retention_config: Mutex::new(RetentionConfig::default()),
+                signing_key: None,
+                transaction_sender: None,
+                federation_policy: Arc::default(),
             }),
         }
     }
@@ -89,10 +106,37 @@ impl AdminState {
                 server_name,
                 start_time: std::time::Instant::now(),
                 retention_config: Mutex::new(retention),
+                signing_key: None,
+                transaction_sender: None,
+                federation_policy: Arc::default(),
             }),
         }
     }
 
+    /// Attach the signing key handle shared with the federation subsystem, so a
+    /// rotation through the admin API takes effect on this node right away.
+    pub fn with_signing_key(mut self, signing_key: SharedKeyPair) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("AdminState already shared");
+        inner.signing_key = Some(signing_key);
+        self
+    }
+
+    /// Attach this node's federation transaction sender so destination health
+    /// includes live queue sizes and backoff controls act on the running sender.
+    pub fn with_transaction_sender(mut self, sender: Arc<TransactionSender>) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("AdminState already shared");
+        inner.transaction_sender = Some(sender);
+        self
+    }
+
+    /// Share the running server's federation policy, so allow/deny list changes
+    /// through the admin API are enforced immediately.
+    pub fn with_federation_policy(mut self, policy: Arc<FederationPolicy>) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("AdminState already shared");
+        inner.federation_policy = policy;
+        self
+    }
+
     pub fn storage(&self) -> &dyn Storage {
         &*self.inner.storage
     }
@@ -112,4 +156,16 @@ impl AdminState {
     pub fn set_retention_config(&self, config: RetentionConfig) {
         *self.inner.retention_config.lock().unwrap() = config;
     }
+
+    pub fn signing_key(&self) -> Option<&SharedKeyPair> {
+        self.inner.signing_key.as_ref()
+    }
+
+    pub fn transaction_sender(&self) -> Option<&TransactionSender> {
+        self.inner.transaction_sender.as_deref()
+    }
+
+    pub fn federation_policy(&self) -> &FederationPolicy {
+        &self.inner.federation_policy
+    }
 }
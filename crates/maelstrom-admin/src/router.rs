@@ -3,10 +3,10 @@
 //! Builds the complete admin [`Router`] by merging two groups of routes:
 //!
 //! **JSON API routes** (all require admin auth):
-//! - `/_maelstrom/admin/v1/users/*`       -- user management (list, get, deactivate, admin flag, reset password)
+//! - `/_maelstrom/admin/v1/users/*`       -- user management (list, get, deactivate, admin flag, reset password, cross-signing resets)
 //! - `/_maelstrom/admin/v1/rooms/*`       -- room inspection and shutdown
 //! - `/_maelstrom/admin/v1/media/*`       -- per-user media listing, quarantine, retention config
-//! - `/_maelstrom/admin/v1/federation/*`  -- federation signing-key stats
+//! - `/_maelstrom/admin/v1/federation/*`  -- federation signing-key stats and rotation
 //! - `/_maelstrom/admin/v1/server/*`      -- server info, detailed health, Prometheus metrics
 //! - `/_maelstrom/admin/v1/reports`       -- content-report review
 //!
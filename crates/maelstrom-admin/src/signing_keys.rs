@@ -0,0 +1,79 @@
+//! Server signing key management.
+//!
+//! The server signs events and federation requests with a single active Ed25519
+//! key, stored in [`FederationKeyStore`]. Rotating the key generates a new one,
+//! stores it, and marks every previously active key as expiring once
+//! [`ROTATION_GRACE_SECS`] have passed. Expired keys are never deleted: the key
+//! server publishes them under `old_verify_keys` so that signatures made before
+//! the rotation stay verifiable.
+//!
+//! Rotation is shared by the `rotate-signing-key` CLI command and the
+//! `POST /_maelstrom/admin/v1/federation/keys/rotate` endpoint. Running nodes
+//! pick up the new key with [`load_signing_key`] -- each node polls storage
+//! every [`KEY_REFRESH_INTERVAL_SECS`], so a rotation performed on one node (or
+//! from the CLI) reaches the whole cluster without a restart. The new key is
+//! published as soon as it is stored; the old one stays valid through the
+//! grace period, so what nodes sign with it before they switch still verifies.
+//!
+//! [`FederationKeyStore`]: maelstrom_storage::traits::FederationKeyStore
+
+use maelstrom_core::matrix::keys::KeyPair;
+use maelstrom_storage::traits::{ServerKeyRecord, Storage, StorageError, StorageResult};
+use tracing::info;
+
+/// How far ahead the `valid_until` of a freshly generated key is set.
+const KEY_VALIDITY_DAYS: i64 = 365;
+
+/// How often running nodes reload the signing key from storage.
+pub const KEY_REFRESH_INTERVAL_SECS: u64 = 60;
+
+/// How long a rotated-out key stays valid: several refresh intervals, so every
+/// node has switched to the new key well before the old one expires.
+pub const ROTATION_GRACE_SECS: u64 = 10 * KEY_REFRESH_INTERVAL_SECS;
+
+/// Load the newest active signing key, or `None` if no key has been generated yet.
+pub async fn load_signing_key(storage: &dyn Storage) -> StorageResult<Option<KeyPair>> {
+    let keys = storage.get_active_server_keys().await?;
+    let Some(record) = keys.into_iter().next() else {
+        return Ok(None);
+    };
+
+    KeyPair::from_base64(record.key_id.clone(), &record.private_key)
+        .map(Some)
+        .ok_or_else(|| {
+            StorageError::Query(format!("Invalid stored private key for {}", record.key_id))
+        })
+}
+
+/// Generate and store a new signing key, expiring every previously active key
+/// once [`ROTATION_GRACE_SECS`] have passed.
+///
+/// Also used on first boot, when there is nothing to expire.
+pub async fn rotate_signing_key(storage: &dyn Storage) -> StorageResult<KeyPair> {
+    let previous = storage.get_active_server_keys().await?;
+
+    let key = KeyPair::generate();
+    let now = chrono::Utc::now();
+    storage
+        .store_server_key(&ServerKeyRecord {
+            key_id: key.key_id().to_string(),
+            algorithm: "ed25519".to_string(),
+            public_key: key.public_key_base64(),
+            private_key: key.private_key_base64(),
+            valid_until: now + chrono::Duration::days(KEY_VALIDITY_DAYS),
+            expired_at: None,
+        })
+        .await?;
+
+    // Expire the old keys only once the new one is stored, so there is never
+    // a moment without an active key, and only after the grace period, as
+    // other nodes sign with them until their next refresh.
+    let expires_at = now + chrono::Duration::seconds(ROTATION_GRACE_SECS as i64);
+    for old in previous {
+        storage.expire_server_key(&old.key_id, expires_at).await?;
+        info!(key_id = %old.key_id, %expires_at, "Expiring signing key");
+    }
+
+    info!(key_id = %key.key_id(), "Generated new signing key");
+    Ok(key)
+}
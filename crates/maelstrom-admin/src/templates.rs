@@ -36,4 +36,18 @@ pub struct RoomsPage {}
 pub struct FederationPage {
     pub server_name: String,
     pub signing_key_count: usize,
+    pub destinations: Vec<DestinationRow>,
+}
+
+/// One row of the federation page's destination table, pre-formatted for display.
+pub struct DestinationRow {
+    pub server_name: String,
+    pub status: &'static str,
+    pub failure_count: u32,
+    pub last_success: String,
+    pub retry_at: String,
+    pub last_error: String,
+    pub pending_pdus: u64,
+    pub pending_edus: u64,
+    pub pdu_lag: String,
 }
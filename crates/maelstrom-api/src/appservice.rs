@@ -22,9 +22,34 @@
 //! rate_limited: false
 //! protocols:
 //!   - slack
+//! receive_ephemeral: true   # MSC2409, also read as de.sorunome.msc2409.push_ephemeral
+//! org.matrix.msc3202: true  # device lists and one-time key counts
 //! ```
+//!
+//! # Registration files
+//!
+//! Besides the admin API, registrations come from the files (or directories
+//! of `*.yaml` / `*.yml` files) listed in the server configuration.
+//! [`load_registration_files`] runs at startup and again on `SIGHUP`: it
+//! (re-)registers every file and removes services whose file is no longer
+//! listed.  A file that fails to parse is skipped and the service it
+//! registered before keeps running unchanged.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use dashmap::DashMap;
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_storage::traits::{AppServiceRecord, NamespaceRule, Storage};
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::state::AppState;
 
-use maelstrom_storage::traits::{AppServiceRecord, NamespaceRule};
+/// Compiled namespace patterns, keyed by the pattern as registered.  `None`
+/// marks a pattern that does not compile (it never matches).
+static NAMESPACE_REGEXES: LazyLock<DashMap<String, Option<Regex>>> = LazyLock::new(DashMap::new);
 
 /// Parse an application service registration YAML string into an [`AppServiceRecord`].
 ///
@@ -39,11 +64,12 @@ pub fn parse_appservice_yaml(yaml_str: &str) -> Result<AppServiceRecord, String>
         .ok_or("Missing required field: id")?
         .to_string();
 
-    let url = doc
-        .get("url")
-        .and_then(|v| v.as_str())
-        .ok_or("Missing required field: url")?
-        .to_string();
+    // `url: null` registers an AS that only talks to the homeserver and is
+    // never pushed to
+    let url = match doc.get("url").ok_or("Missing required field: url")? {
+        serde_yaml::Value::Null => String::new(),
+        v => v.as_str().ok_or("Invalid field: url")?.to_string(),
+    };
 
     let as_token = doc
         .get("as_token")
@@ -78,6 +104,15 @@ pub fn parse_appservice_yaml(yaml_str: &str) -> Result<AppServiceRecord, String>
         })
         .unwrap_or_default();
 
+    let receive_ephemeral = ["receive_ephemeral", "de.sorunome.msc2409.push_ephemeral"]
+        .iter()
+        .any(|key| doc.get(key).and_then(|v| v.as_bool()).unwrap_or(false));
+
+    let msc3202 = doc
+        .get("org.matrix.msc3202")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
     let namespaces = doc.get("namespaces");
 
     let user_namespaces = namespaces
@@ -104,6 +139,9 @@ pub fn parse_appservice_yaml(yaml_str: &str) -> Result<AppServiceRecord, String>
         alias_namespaces,
         rate_limited,
         protocols,
+        receive_ephemeral,
+        msc3202,
+        registration_file: None,
     })
 }
 
@@ -124,3 +162,197 @@ fn parse_namespace_rules(seq: &[serde_yaml::Value]) -> Result<Vec<NamespaceRule>
         })
         .collect()
 }
+
+/// Whether `value` matches one of `rules`.  Patterns must match the whole ID.
+pub fn matches_namespace(rules: &[NamespaceRule], value: &str) -> bool {
+    rules.iter().any(|rule| {
+        NAMESPACE_REGEXES
+            .entry(rule.regex.clone())
+            .or_insert_with(|| Regex::new(&format!("^(?:{})$", rule.regex)).ok())
+            .as_ref()
+            .is_some_and(|re| re.is_match(value))
+    })
+}
+
+/// Whether `user_id` belongs to the AS: its sender user or a user namespace match.
+pub fn is_appservice_user(record: &AppServiceRecord, user_id: &str, server_name: &str) -> bool {
+    user_id == format!("@{}:{server_name}", record.sender_localpart)
+        || matches_namespace(&record.user_namespaces, user_id)
+}
+
+/// Reject `user_id` if another application service claims it exclusively.
+///
+/// `caller` is the ID of the application service making the request, if any;
+/// an AS may always use IDs in its own namespaces.
+pub async fn check_exclusive_user(
+    storage: &dyn Storage,
+    user_id: &str,
+    caller: Option<&str>,
+) -> Result<(), MatrixError> {
+    if claimed_by_other(storage, caller, |record| {
+        exclusive_match(&record.user_namespaces, user_id)
+    })
+    .await
+    {
+        return Err(MatrixError::exclusive(
+            "User ID is reserved by an application service",
+        ));
+    }
+    Ok(())
+}
+
+/// Reject `alias` if another application service claims it exclusively.
+pub async fn check_exclusive_alias(
+    storage: &dyn Storage,
+    alias: &str,
+    caller: Option<&str>,
+) -> Result<(), MatrixError> {
+    if claimed_by_other(storage, caller, |record| {
+        exclusive_match(&record.alias_namespaces, alias)
+    })
+    .await
+    {
+        return Err(MatrixError::exclusive(
+            "Room alias is reserved by an application service",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `value` matches one of the exclusive rules in `rules`.
+fn exclusive_match(rules: &[NamespaceRule], value: &str) -> bool {
+    rules
+        .iter()
+        .filter(|rule| rule.exclusive)
+        .any(|rule| matches_namespace(std::slice::from_ref(rule), value))
+}
+
+/// Whether an application service other than `caller` satisfies `claims`.
+async fn claimed_by_other(
+    storage: &dyn Storage,
+    caller: Option<&str>,
+    claims: impl Fn(&AppServiceRecord) -> bool,
+) -> bool {
+    let appservices = storage.list_appservices().await.unwrap_or_default();
+    appservices
+        .iter()
+        .any(|record| Some(record.id.as_str()) != caller && claims(record))
+}
+
+/// What a call to [`load_registration_files`] did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RegistrationReload {
+    /// Services registered or updated from a file.
+    pub loaded: usize,
+    /// File-loaded services removed because their file is no longer listed.
+    pub removed: usize,
+    /// Files that could not be read or parsed.
+    pub failed: usize,
+}
+
+/// Register the application services described by the registration files at
+/// `paths` and remove file-loaded services that are no longer listed.
+///
+/// Each path is a YAML file or a directory whose `*.yaml` / `*.yml` files
+/// are loaded in name order.
+pub async fn load_registration_files(state: &AppState, paths: &[PathBuf]) -> RegistrationReload {
+    let mut report = RegistrationReload::default();
+    let mut loaded_ids = HashSet::new();
+    // Services whose file failed keep their previous registration
+    let mut failed_files = HashSet::new();
+
+    let (files, unreadable_dirs) = registration_files(paths);
+    for file in files {
+        let path = file.display().to_string();
+        let record = std::fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| parse_appservice_yaml(&yaml));
+        let mut record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                warn!(path = %path, error = %e, "Failed to load application service registration");
+                failed_files.insert(path);
+                report.failed += 1;
+                continue;
+            }
+        };
+        if !loaded_ids.insert(record.id.clone()) {
+            warn!(path = %path, id = %record.id, "Duplicate application service ID, skipping");
+            failed_files.insert(path);
+            report.failed += 1;
+            continue;
+        }
+
+        record.registration_file = Some(path.clone());
+        if let Err(e) = state.storage().register_appservice(&record).await {
+            warn!(path = %path, id = %record.id, error = %e, "Failed to register application service");
+            failed_files.insert(path);
+            report.failed += 1;
+            continue;
+        }
+        state.appservice_query().forget(&record.id);
+        report.loaded += 1;
+    }
+
+    let registered = state.storage().list_appservices().await.unwrap_or_default();
+    for record in registered {
+        let Some(file) = &record.registration_file else {
+            continue;
+        };
+        if loaded_ids.contains(&record.id)
+            || failed_files.contains(file)
+            || unreadable_dirs
+                .iter()
+                .any(|dir| Path::new(file).starts_with(dir))
+        {
+            continue;
+        }
+        match state.storage().delete_appservice(&record.id).await {
+            Ok(()) => {
+                info!(id = %record.id, path = %file, "Removed application service no longer configured");
+                report.removed += 1;
+            }
+            Err(e) => warn!(id = %record.id, error = %e, "Failed to remove application service"),
+        }
+    }
+
+    info!(
+        loaded = report.loaded,
+        removed = report.removed,
+        failed = report.failed,
+        "Application service registrations loaded"
+    );
+    report
+}
+
+/// The registration files named by `paths`, expanding directories, and the
+/// directories that could not be read.
+fn registration_files(paths: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut files = Vec::new();
+    let mut unreadable = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut entries: Vec<PathBuf> = match std::fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && is_yaml(p))
+                .collect(),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Failed to read application service directory");
+                unreadable.push(path.clone());
+                continue;
+            }
+        };
+        entries.sort();
+        files.extend(entries);
+    }
+    (files, unreadable)
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "yaml" || ext == "yml")
+}
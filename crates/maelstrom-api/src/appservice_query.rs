@@ -0,0 +1,213 @@
+//! # Application Service Queries
+//!
+//! Requests the homeserver makes *to* application services on behalf of a
+//! client, as opposed to the transactions pushed by the
+//! [`AppServiceSender`](crate::appservice_sender::AppServiceSender).
+//!
+//! ## Third-party lookups
+//!
+//! The client `/_matrix/client/v3/thirdparty/*` endpoints are answered by
+//! asking every matching bridge:
+//!
+//! | Client endpoint | AS endpoint | Asked |
+//! |-----------------|-------------|-------|
+//! | `protocol/{protocol}` | `/_matrix/app/v1/thirdparty/protocol/{protocol}` | ASes bridging the protocol |
+//! | `location/{protocol}` | `/_matrix/app/v1/thirdparty/location/{protocol}` | ASes bridging the protocol |
+//! | `user/{protocol}` | `/_matrix/app/v1/thirdparty/user/{protocol}` | ASes bridging the protocol |
+//! | `location?alias=` | `/_matrix/app/v1/thirdparty/location?alias=` | ASes whose alias namespaces match |
+//! | `user?userid=` | `/_matrix/app/v1/thirdparty/user?userid=` | ASes whose user namespaces match |
+//!
+//! The ASes are queried concurrently and their result arrays concatenated.
+//! Each query is bounded by [`QUERY_TIMEOUT`]; an AS that fails or times out
+//! simply contributes nothing.
+//!
+//! Protocol metadata changes rarely but clients fetch it whenever they open
+//! the room directory, so answers are cached per AS and protocol: successful
+//! ones for [`PROTOCOL_CACHE_TTL`], failures for [`PROTOCOL_FAILURE_TTL`], so
+//! that a bridge that is down does not hold up every directory request by a
+//! full [`QUERY_TIMEOUT`].
+//!
+//! ## Ping
+//!
+//! [`AppServiceQuerier::ping`] backs `POST /_matrix/client/v1/appservice/{id}/ping`:
+//! it calls the AS's `/_matrix/app/v1/ping` and reports how long that took.
+
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use maelstrom_storage::traits::AppServiceRecord;
+use serde_json::Value;
+use tokio::task::JoinSet;
+use tracing::warn;
+
+/// How long an application service may take to answer a query.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long fetched protocol metadata is reused.
+pub const PROTOCOL_CACHE_TTL: Duration = Duration::from_secs(300);
+/// How long a failed protocol metadata query is remembered.
+pub const PROTOCOL_FAILURE_TTL: Duration = Duration::from_secs(30);
+
+/// HTTP client for querying application services, with the protocol
+/// metadata cache.
+pub struct AppServiceQuerier {
+    http: reqwest::Client,
+    /// Protocol metadata keyed by `(appservice_id, protocol)`; `None` when
+    /// the AS failed to answer.
+    protocols: DashMap<(String, String), (Instant, Option<Value>)>,
+}
+
+impl Default for AppServiceQuerier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppServiceQuerier {
+    pub fn new() -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(QUERY_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            protocols: DashMap::new(),
+        }
+    }
+
+    /// Protocol metadata from the AS, served from the cache when fresh.
+    /// `None` when the AS could not be asked or answered with something
+    /// other than an object.
+    pub async fn protocol(&self, record: &AppServiceRecord, protocol: &str) -> Option<Value> {
+        let key = (record.id.clone(), protocol.to_string());
+        if let Some(entry) = self.protocols.get(&key) {
+            let (fetched_at, metadata) = entry.value();
+            let ttl = if metadata.is_some() {
+                PROTOCOL_CACHE_TTL
+            } else {
+                PROTOCOL_FAILURE_TTL
+            };
+            if fetched_at.elapsed() < ttl {
+                return metadata.clone();
+            }
+        }
+
+        let path = format!(
+            "/_matrix/app/v1/thirdparty/protocol/{}",
+            urlencoding::encode(protocol)
+        );
+        let metadata = query(&self.http, record, &path, &[])
+            .await
+            .filter(Value::is_object);
+        self.protocols
+            .insert(key, (Instant::now(), metadata.clone()));
+        metadata
+    }
+
+    /// Call the AS's ping endpoint, returning the round-trip time.
+    pub async fn ping(
+        &self,
+        record: &AppServiceRecord,
+        transaction_id: Option<&str>,
+    ) -> Result<Duration, PingError> {
+        let url = format!("{}/_matrix/app/v1/ping", record.url.trim_end_matches('/'));
+        let body = match transaction_id {
+            Some(txn_id) => serde_json::json!({ "transaction_id": txn_id }),
+            None => serde_json::json!({}),
+        };
+
+        let started = Instant::now();
+        let response = self
+            .http
+            .post(&url)
+            .header("Authorization", format!("Bearer {}", record.hs_token))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| {
+                if e.is_timeout() {
+                    PingError::Timeout
+                } else {
+                    PingError::Connection(e.to_string())
+                }
+            })?;
+        let elapsed = started.elapsed();
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(PingError::Status {
+                status: status.as_u16(),
+                body: response.text().await.unwrap_or_default(),
+            });
+        }
+        Ok(elapsed)
+    }
+
+    /// Drop the cached metadata of an AS, e.g. after it was re-registered.
+    pub fn forget(&self, appservice_id: &str) {
+        self.protocols.retain(|(id, _), _| id != appservice_id);
+    }
+
+    /// Ask each of `appservices` for `path` and concatenate the result arrays,
+    /// in the order of `appservices`.
+    pub async fn lookup(
+        &self,
+        appservices: Vec<AppServiceRecord>,
+        path: &str,
+        params: &[(String, String)],
+    ) -> Vec<Value> {
+        let mut queries = JoinSet::new();
+        for (index, record) in appservices.into_iter().enumerate() {
+            let http = self.http.clone();
+            let path = path.to_string();
+            let params = params.to_vec();
+            queries.spawn(async move { (index, query(&http, &record, &path, &params).await) });
+        }
+
+        let mut answers = Vec::new();
+        while let Some(result) = queries.join_next().await {
+            if let Ok((index, Some(Value::Array(entries)))) = result {
+                answers.push((index, entries));
+            }
+        }
+        answers.sort_by_key(|(index, _)| *index);
+        answers
+            .into_iter()
+            .flat_map(|(_, entries)| entries)
+            .filter(Value::is_object)
+            .collect()
+    }
+}
+
+/// Why an application service did not answer a ping.
+#[derive(Debug)]
+pub enum PingError {
+    /// The AS answered with a non-2xx status.
+    Status { status: u16, body: String },
+    /// The AS did not answer within [`QUERY_TIMEOUT`].
+    Timeout,
+    /// The AS could not be reached.
+    Connection(String),
+}
+
+/// `GET` `path` from the AS, authenticated with its `hs_token`.
+async fn query(
+    http: &reqwest::Client,
+    record: &AppServiceRecord,
+    path: &str,
+    params: &[(String, String)],
+) -> Option<Value> {
+    let url = format!("{}{path}", record.url.trim_end_matches('/'));
+    let result = http
+        .get(&url)
+        .header("Authorization", format!("Bearer {}", record.hs_token))
+        .query(params)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match result {
+        Ok(response) => response.json().await.ok(),
+        Err(e) => {
+            warn!(as_id = %record.id, path = %path, error = %e, "Application service query failed");
+            None
+        }
+    }
+}
@@ -0,0 +1,776 @@
+//! # Application Service Transaction Sender
+//!
+//! Pushes events to registered application services (bridges, bots) with
+//! `PUT /_matrix/app/v1/transactions/{txnId}`, the way the federation
+//! [`TransactionSender`](maelstrom_federation::sender::TransactionSender) pushes
+//! them to other servers -- except that the queue lives in storage, so nothing
+//! is lost when either side restarts.
+//!
+//! ## Durable Queue
+//!
+//! Every AS has its own queue ([`ApplicationServiceStore::enqueue_appservice_item`]).
+//! Room events are picked up by following the event stream: each AS remembers
+//! the stream position it has been scanned up to, and events it is interested
+//! in are appended to its queue. An AS is interested in an event when the
+//! sender or the membership target is one of its users, or when the room has a
+//! joined member in its user namespaces or an alias in its alias namespaces.
+//! A newly registered AS starts at the current stream position; history is not
+//! replayed. The stream is read at most [`MAX_EVENTS_PER_PASS`] events at a
+//! time, so a service that fell far behind catches up page by page.
+//!
+//! Handlers append the data that is not part of the event stream directly:
+//!
+//! | Data | Queued by | Sent as |
+//! |------|-----------|---------|
+//! | Typing, receipts, presence | [`queue_room_ephemeral`], [`queue_presence`] | `ephemeral` (MSC2409) |
+//! | To-device messages for AS users | [`queue_to_device`] | `de.sorunome.msc2409.to_device` |
+//! | Device list changes | [`queue_device_list_change`] | `org.matrix.msc3202.device_lists` |
+//! | One-time and fallback key state of AS users | [`queue_one_time_key_count`] | `org.matrix.msc3202.device_one_time_keys_count`, `org.matrix.msc3202.device_unused_fallback_key_types` |
+//!
+//! Ephemeral data and to-device messages are only queued for services
+//! registered with `receive_ephemeral`, device data only for services with
+//! `org.matrix.msc3202`.
+//!
+//! ## Ordering and Retries
+//!
+//! Transactions carry up to **100** queue items, oldest first, and only one
+//! transaction per AS is outstanding at a time. The items of the outstanding
+//! transaction and its ID are recorded in the AS's [`AppServiceDelivery`]
+//! before the first attempt, so a retry -- even after a restart -- resends the
+//! same items under the same transaction ID, and the AS can deduplicate.
+//! Items are removed from the queue only once the AS answers with a 2xx.
+//!
+//! Failed attempts back off exponentially: 1 second, doubling up to 10 minutes.
+//!
+//! ## Clusters
+//!
+//! In a cluster every node runs a sender, but only one delivers to a given AS:
+//! the one holding its lease
+//! ([`ApplicationServiceStore::acquire_appservice_lease`]), renewed while the
+//! node is alive and taken over by another node once it expires. A node that
+//! loses or gains a lease drops its cached delivery state and reloads it from
+//! storage, and state for services that are no longer registered is dropped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use dashmap::DashMap;
+use maelstrom_core::matrix::event::Pdu;
+use maelstrom_core::matrix::room::{Membership, event_type as et};
+use maelstrom_storage::traits::{
+    AppServiceDelivery, AppServiceItemKind, AppServiceQueueItem, AppServiceRecord, Storage,
+    StorageError,
+};
+use serde_json::Value;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::appservice::{is_appservice_user, matches_namespace};
+use crate::state::AppState;
+
+/// How often the sender checks for new events and queued items.
+const POLL_INTERVAL_MS: u64 = 200;
+/// Maximum queue items per transaction.
+const MAX_ITEMS_PER_TXN: usize = 100;
+/// Backoff wait after the first failure.
+const INITIAL_BACKOFF_MS: u64 = 1000;
+/// Maximum backoff wait before retrying a failed application service.
+const MAX_BACKOFF_MS: u64 = 600_000; // 10 minutes
+/// How long an application service may take to answer a transaction.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum events read from the stream per pass.
+const MAX_EVENTS_PER_PASS: usize = 500;
+/// How long a delivery lease lasts without renewal.
+const LEASE_TTL: Duration = Duration::from_secs(60);
+/// How often a lease is renewed, or another node's lease checked for expiry.
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Background sender delivering queued items to application services.
+///
+/// Attach it to the [`AppState`] with
+/// [`with_appservice_sender`](AppState::with_appservice_sender) so handlers
+/// queue ephemeral data, then spawn [`run`](Self::run) with that state.
+pub struct AppServiceSender {
+    http: reqwest::Client,
+    /// Identifies this node as a lease holder.
+    node_id: String,
+    /// Delivery state per AS, mirrored to storage.
+    delivery: DashMap<String, AppServiceDelivery>,
+    /// Per AS: whether this node holds its lease, and when to check again.
+    leases: DashMap<String, (bool, Instant)>,
+    /// Woken when an item is queued, so delivery does not wait for the next poll.
+    wake: Notify,
+}
+
+impl Default for AppServiceSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AppServiceSender {
+    pub fn new() -> Self {
+        Self {
+            http: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            node_id: uuid::Uuid::new_v4().to_string(),
+            delivery: DashMap::new(),
+            leases: DashMap::new(),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Run the sender loop. Call this as a spawned tokio task.
+    pub async fn run(self: Arc<Self>, state: AppState) {
+        info!("Application service sender started");
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MS)) => {}
+                _ = self.wake.notified() => {}
+            }
+
+            let appservices = match state.storage().list_appservices().await {
+                Ok(list) => list,
+                Err(e) => {
+                    warn!(error = %e, "Failed to list application services");
+                    continue;
+                }
+            };
+
+            // Forget services that were unregistered
+            self.delivery
+                .retain(|id, _| appservices.iter().any(|r| &r.id == id));
+            self.leases
+                .retain(|id, _| appservices.iter().any(|r| &r.id == id));
+
+            // Services registered with `url: null` are never pushed to
+            let appservices: Vec<AppServiceRecord> = appservices
+                .into_iter()
+                .filter(|r| !r.url.is_empty())
+                .collect();
+            let appservices = self.leased(state.storage(), appservices).await;
+            if appservices.is_empty() {
+                continue;
+            }
+
+            if self.queue_new_events(&state, &appservices).await {
+                // More of the stream is waiting; do not sleep before the next page
+                self.wake.notify_one();
+            }
+
+            let mut deliveries = JoinSet::new();
+            for record in appservices {
+                let sender = self.clone();
+                let state = state.clone();
+                deliveries.spawn(async move { sender.deliver(&state, &record).await });
+            }
+            while deliveries.join_next().await.is_some() {}
+        }
+    }
+
+    /// The AS's delivery state, loaded from storage on first use.  A service
+    /// seen for the first time starts at the current stream position.
+    ///
+    /// Any other failure to load it is returned, and nothing is cached, so
+    /// the next pass tries again rather than skipping the service's backlog.
+    async fn delivery_state(
+        &self,
+        storage: &dyn Storage,
+        appservice_id: &str,
+    ) -> Result<AppServiceDelivery, StorageError> {
+        if let Some(record) = self.delivery.get(appservice_id) {
+            return Ok(record.clone());
+        }
+
+        let record = match storage.get_appservice_delivery(appservice_id).await {
+            Ok(record) => record,
+            Err(StorageError::NotFound) => AppServiceDelivery {
+                appservice_id: appservice_id.to_string(),
+                stream_position: storage.current_stream_position().await?,
+                txn_id: 1,
+                ..Default::default()
+            },
+            Err(e) => return Err(e),
+        };
+        self.delivery
+            .insert(appservice_id.to_string(), record.clone());
+        Ok(record)
+    }
+
+    /// Persist and cache the AS's delivery state.
+    async fn save_delivery(&self, storage: &dyn Storage, record: AppServiceDelivery) {
+        if let Err(e) = storage.upsert_appservice_delivery(&record).await {
+            warn!(as_id = %record.appservice_id, error = %e, "Failed to persist application service delivery state");
+        }
+        self.delivery.insert(record.appservice_id.clone(), record);
+    }
+
+    /// The services this node delivers to: those whose lease it holds.
+    ///
+    /// Leases are renewed (or, when another node holds them, checked for
+    /// expiry) every [`LEASE_CHECK_INTERVAL`]. Whenever a lease is not held
+    /// throughout, the cached delivery state is dropped, since another node
+    /// may have changed it in the meantime.
+    async fn leased(
+        &self,
+        storage: &dyn Storage,
+        appservices: Vec<AppServiceRecord>,
+    ) -> Vec<AppServiceRecord> {
+        let now = Instant::now();
+        let mut held = Vec::with_capacity(appservices.len());
+        for record in appservices {
+            let previous = self.leases.get(&record.id).map(|entry| *entry);
+            let holds = match previous {
+                Some((holds, check_at)) if now < check_at => holds,
+                _ => {
+                    let expires_at =
+                        Utc::now() + chrono::Duration::from_std(LEASE_TTL).unwrap_or_default();
+                    let holds = storage
+                        .acquire_appservice_lease(&record.id, &self.node_id, expires_at)
+                        .await
+                        .unwrap_or_else(|e| {
+                            warn!(as_id = %record.id, error = %e, "Failed to acquire application service lease");
+                            false
+                        });
+                    if !(holds && previous.is_some_and(|(held, _)| held)) {
+                        self.delivery.remove(&record.id);
+                    }
+                    if holds != previous.is_some_and(|(held, _)| held) {
+                        debug!(as_id = %record.id, holds, "Application service lease changed");
+                    }
+                    self.leases
+                        .insert(record.id.clone(), (holds, now + LEASE_CHECK_INTERVAL));
+                    holds
+                }
+            };
+            if holds {
+                held.push(record);
+            }
+        }
+        held
+    }
+
+    /// Append events stored since each AS's stream position to its queue.
+    ///
+    /// Reads one page of the stream; returns whether there may be more.
+    async fn queue_new_events(&self, state: &AppState, appservices: &[AppServiceRecord]) -> bool {
+        let storage = state.storage();
+
+        let mut positions = Vec::with_capacity(appservices.len());
+        for record in appservices {
+            match self.delivery_state(storage, &record.id).await {
+                Ok(delivery) => positions.push((record, delivery)),
+                Err(e) => {
+                    warn!(as_id = %record.id, error = %e, "Failed to load application service delivery state");
+                }
+            }
+        }
+        let Some(since) = positions.iter().map(|(_, d)| d.stream_position).min() else {
+            return false;
+        };
+
+        let events = match storage.get_events_after(since, MAX_EVENTS_PER_PASS).await {
+            Ok(events) if !events.is_empty() => events,
+            Ok(_) => return false,
+            Err(e) => {
+                warn!(error = %e, "Failed to read the event stream for application services");
+                return false;
+            }
+        };
+        let more = events.len() >= MAX_EVENTS_PER_PASS;
+        let latest = events
+            .iter()
+            .map(|e| e.stream_position)
+            .max()
+            .unwrap_or(since);
+
+        let mut room_interest: HashMap<(String, String), bool> = HashMap::new();
+        for (record, mut delivery) in positions {
+            if delivery.stream_position >= latest {
+                continue;
+            }
+
+            let mut queued = 0;
+            let mut complete = true;
+            let start = delivery.stream_position;
+            for event in events.iter().filter(|e| e.stream_position > start) {
+                if !is_interested_in_event(state, record, event, &mut room_interest).await {
+                    continue;
+                }
+                let Ok(client_event) = serde_json::to_value(event.to_client_event()) else {
+                    continue;
+                };
+                if let Err(e) = storage
+                    .enqueue_appservice_item(&record.id, AppServiceItemKind::Event, &client_event)
+                    .await
+                {
+                    // Stop at this event so it is picked up again on the next pass.
+                    warn!(as_id = %record.id, event_id = %event.event_id, error = %e, "Failed to queue event for application service");
+                    complete = false;
+                    break;
+                }
+                delivery.stream_position = event.stream_position;
+                queued += 1;
+
+                if record.msc3202
+                    && let Some((kind, content)) =
+                        device_list_membership_change(state, record, event).await
+                    && let Err(e) = storage
+                        .enqueue_appservice_item(&record.id, kind, &content)
+                        .await
+                {
+                    warn!(as_id = %record.id, error = %e, "Failed to queue device list change for application service");
+                }
+            }
+
+            if queued > 0 {
+                debug!(as_id = %record.id, count = queued, "Queued events for application service");
+            }
+            if complete {
+                delivery.stream_position = latest;
+            }
+            self.save_delivery(storage, delivery).await;
+        }
+        more
+    }
+
+    /// Send the AS's oldest queued items, or retry its outstanding transaction.
+    async fn deliver(&self, state: &AppState, record: &AppServiceRecord) {
+        let storage = state.storage();
+        let mut delivery = match self.delivery_state(storage, &record.id).await {
+            Ok(delivery) => delivery,
+            Err(e) => {
+                warn!(as_id = %record.id, error = %e, "Failed to load application service delivery state");
+                return;
+            }
+        };
+        if delivery.retry_at.is_some_and(|t| Utc::now() < t) {
+            return;
+        }
+
+        let mut items = match storage
+            .get_appservice_queue(&record.id, MAX_ITEMS_PER_TXN)
+            .await
+        {
+            Ok(items) => items,
+            Err(e) => {
+                warn!(as_id = %record.id, error = %e, "Failed to read application service queue");
+                return;
+            }
+        };
+        if let Some(up_to) = delivery.in_flight_up_to {
+            items.retain(|item| item.id <= up_to);
+        }
+        let Some(up_to) = items.last().map(|item| item.id) else {
+            if delivery.in_flight_up_to.take().is_some() {
+                self.save_delivery(storage, delivery).await;
+            }
+            return;
+        };
+
+        // Fix the contents of the transaction before the first attempt.
+        if delivery.in_flight_up_to.is_none() {
+            delivery.in_flight_up_to = Some(up_to);
+            self.save_delivery(storage, delivery.clone()).await;
+        }
+
+        let body = build_transaction(storage, &items).await;
+        let url = format!(
+            "{}/_matrix/app/v1/transactions/{}",
+            record.url.trim_end_matches('/'),
+            delivery.txn_id
+        );
+        let result = self
+            .http
+            .put(&url)
+            .header("Authorization", format!("Bearer {}", record.hs_token))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|response| {
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP {}", response.status()))
+                }
+            });
+
+        match result {
+            Ok(()) => {
+                debug!(as_id = %record.id, txn_id = delivery.txn_id, count = items.len(), "Sent application service transaction");
+                if let Err(e) = storage.delete_appservice_queue(&record.id, up_to).await {
+                    // The AS will see the transaction again and deduplicate it.
+                    warn!(as_id = %record.id, error = %e, "Failed to remove delivered items");
+                    return;
+                }
+                delivery.txn_id += 1;
+                delivery.in_flight_up_to = None;
+                delivery.last_success_at = Some(Utc::now());
+                delivery.failure_count = 0;
+                delivery.retry_at = None;
+                delivery.last_error = None;
+            }
+            Err(error) => {
+                warn!(as_id = %record.id, txn_id = delivery.txn_id, error = %error, "Application service transaction failed");
+                let now = Utc::now();
+                delivery.failure_count = delivery.failure_count.saturating_add(1);
+                delivery.last_failure_at = Some(now);
+                delivery.retry_at = Some(now + backoff_for(delivery.failure_count));
+                delivery.last_error = Some(error);
+            }
+        }
+        self.save_delivery(storage, delivery).await;
+    }
+}
+
+/// Assemble the transaction body from queued items.
+async fn build_transaction(storage: &dyn Storage, items: &[AppServiceQueueItem]) -> Value {
+    let mut events = Vec::new();
+    let mut ephemeral = Vec::new();
+    let mut to_device = Vec::new();
+    let mut changed = Vec::new();
+    let mut left = Vec::new();
+    let mut otk_devices = Vec::new();
+
+    for item in items {
+        let user_id = || {
+            item.content
+                .get("user_id")
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+        match item.kind {
+            AppServiceItemKind::Event => events.push(item.content.clone()),
+            AppServiceItemKind::Ephemeral => ephemeral.push(item.content.clone()),
+            AppServiceItemKind::ToDevice => to_device.push(item.content.clone()),
+            AppServiceItemKind::DeviceListChanged => changed.extend(user_id()),
+            AppServiceItemKind::DeviceListLeft => left.extend(user_id()),
+            AppServiceItemKind::OneTimeKeyCount => {
+                let device_id = item.content.get("device_id").and_then(|v| v.as_str());
+                if let (Some(user_id), Some(device_id)) = (user_id(), device_id) {
+                    otk_devices.push((user_id, device_id.to_string()));
+                }
+            }
+        }
+    }
+
+    let mut body = serde_json::json!({ "events": events });
+    if !ephemeral.is_empty() {
+        body["ephemeral"] = Value::Array(ephemeral.clone());
+        body["de.sorunome.msc2409.ephemeral"] = Value::Array(ephemeral);
+    }
+    if !to_device.is_empty() {
+        body["de.sorunome.msc2409.to_device"] = Value::Array(to_device);
+    }
+    if !changed.is_empty() || !left.is_empty() {
+        changed.sort();
+        changed.dedup();
+        left.sort();
+        left.dedup();
+        left.retain(|u| !changed.contains(u));
+        body["org.matrix.msc3202.device_lists"] =
+            serde_json::json!({ "changed": changed, "left": left });
+    }
+    if !otk_devices.is_empty() {
+        // Counts are read now rather than when queued, so the AS sees the latest value.
+        let mut counts: BTreeMap<String, BTreeMap<String, Value>> = BTreeMap::new();
+        let mut fallback_types: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+        for (user_id, device_id) in otk_devices {
+            if counts
+                .get(&user_id)
+                .is_some_and(|d| d.contains_key(&device_id))
+            {
+                continue;
+            }
+            let count = storage
+                .count_one_time_keys(&user_id, &device_id)
+                .await
+                .unwrap_or_else(|_| serde_json::json!({}));
+            let unused = storage
+                .get_unused_fallback_key_types(&user_id, &device_id)
+                .await
+                .unwrap_or_default();
+            counts
+                .entry(user_id.clone())
+                .or_default()
+                .insert(device_id.clone(), count);
+            fallback_types
+                .entry(user_id)
+                .or_default()
+                .insert(device_id, unused);
+        }
+        body["org.matrix.msc3202.device_one_time_keys_count"] =
+            serde_json::to_value(counts).unwrap_or_default();
+        body["org.matrix.msc3202.device_unused_fallback_key_types"] =
+            serde_json::to_value(fallback_types).unwrap_or_default();
+    }
+    body
+}
+
+/// Whether the AS should receive `event` (see the module docs).
+async fn is_interested_in_event(
+    state: &AppState,
+    record: &AppServiceRecord,
+    event: &Pdu,
+    room_interest: &mut HashMap<(String, String), bool>,
+) -> bool {
+    let server_name = state.server_name().as_str();
+    if is_appservice_user(record, &event.sender, server_name) {
+        return true;
+    }
+    if event.event_type == et::MEMBER
+        && let Some(target) = &event.state_key
+        && is_appservice_user(record, target, server_name)
+    {
+        return true;
+    }
+
+    let key = (record.id.clone(), event.room_id.clone());
+    if let Some(interested) = room_interest.get(&key) {
+        return *interested;
+    }
+    let interested = is_interested_in_room(state, record, &event.room_id).await;
+    room_interest.insert(key, interested);
+    interested
+}
+
+/// MSC3202: a user joining a room the AS is in has devices the AS must now
+/// track; a user leaving may no longer share any room with the AS's users.
+async fn device_list_membership_change(
+    state: &AppState,
+    record: &AppServiceRecord,
+    event: &Pdu,
+) -> Option<(AppServiceItemKind, Value)> {
+    if event.event_type != et::MEMBER {
+        return None;
+    }
+    let target = event.state_key.as_deref()?;
+    let membership = event.content.get("membership")?.as_str()?;
+
+    let kind = if membership == Membership::Join.as_str() {
+        AppServiceItemKind::DeviceListChanged
+    } else if membership == Membership::Leave.as_str() || membership == Membership::Ban.as_str() {
+        let rooms = state
+            .storage()
+            .get_joined_rooms(target)
+            .await
+            .unwrap_or_default();
+        if is_appservice_user(record, target, state.server_name().as_str())
+            || shares_room(state, record, &rooms).await
+        {
+            return None;
+        }
+        AppServiceItemKind::DeviceListLeft
+    } else {
+        return None;
+    };
+    Some((kind, serde_json::json!({ "user_id": target })))
+}
+
+/// Whether the room has a joined AS user or an alias in the AS's namespaces.
+async fn is_interested_in_room(state: &AppState, record: &AppServiceRecord, room_id: &str) -> bool {
+    let storage = state.storage();
+    let server_name = state.server_name().as_str();
+
+    let members = storage
+        .get_room_members(room_id, Membership::Join.as_str())
+        .await
+        .unwrap_or_default();
+    if members
+        .iter()
+        .any(|m| is_appservice_user(record, m, server_name))
+    {
+        return true;
+    }
+
+    !record.alias_namespaces.is_empty()
+        && storage
+            .get_room_aliases(room_id)
+            .await
+            .unwrap_or_default()
+            .iter()
+            .any(|alias| matches_namespace(&record.alias_namespaces, alias))
+}
+
+/// The registered application services, if a sender is attached to take their items.
+async fn appservices(state: &AppState) -> Option<(&AppServiceSender, Vec<AppServiceRecord>)> {
+    let sender = state.appservice_sender()?;
+    let mut appservices = state.storage().list_appservices().await.ok()?;
+    appservices.retain(|r| !r.url.is_empty());
+    Some((sender, appservices))
+}
+
+/// Whether any of `rooms` is of interest to the AS.
+async fn shares_room(state: &AppState, record: &AppServiceRecord, rooms: &[String]) -> bool {
+    for room_id in rooms {
+        if is_interested_in_room(state, record, room_id).await {
+            return true;
+        }
+    }
+    false
+}
+
+/// Append an item to the queues of `targets`, then wake the sender.
+async fn enqueue(
+    state: &AppState,
+    sender: &AppServiceSender,
+    targets: &[&AppServiceRecord],
+    kind: AppServiceItemKind,
+    content: Value,
+) {
+    let mut queued = false;
+    for record in targets {
+        match state
+            .storage()
+            .enqueue_appservice_item(&record.id, kind, &content)
+            .await
+        {
+            Ok(_) => queued = true,
+            Err(e) => {
+                warn!(as_id = %record.id, kind = kind.as_str(), error = %e, "Failed to queue item for application service")
+            }
+        }
+    }
+    if queued {
+        sender.wake.notify_one();
+    }
+}
+
+/// Queue a typing or receipt EDU (`{"type", "room_id", "content"}`) for
+/// every AS that receives ephemeral data and is interested in the room.
+pub async fn queue_room_ephemeral(state: &AppState, room_id: &str, edu: Value) {
+    let Some((sender, appservices)) = appservices(state).await else {
+        return;
+    };
+    let mut targets = Vec::new();
+    for record in appservices.iter().filter(|a| a.receive_ephemeral) {
+        if is_interested_in_room(state, record, room_id).await {
+            targets.push(record);
+        }
+    }
+    enqueue(state, sender, &targets, AppServiceItemKind::Ephemeral, edu).await;
+}
+
+/// Queue an `m.presence` EDU for every AS that receives ephemeral data and
+/// either owns `user_id` or shares a room with it.
+pub async fn queue_presence(state: &AppState, user_id: &str, edu: Value) {
+    let Some((sender, appservices)) = appservices(state).await else {
+        return;
+    };
+    let rooms = state
+        .storage()
+        .get_joined_rooms(user_id)
+        .await
+        .unwrap_or_default();
+    let mut targets = Vec::new();
+    for record in appservices.iter().filter(|a| a.receive_ephemeral) {
+        if is_appservice_user(record, user_id, state.server_name().as_str())
+            || shares_room(state, record, &rooms).await
+        {
+            targets.push(record);
+        }
+    }
+    enqueue(state, sender, &targets, AppServiceItemKind::Ephemeral, edu).await;
+}
+
+/// Forward a to-device message addressed to one of an AS's users.
+pub async fn queue_to_device(
+    state: &AppState,
+    to_user_id: &str,
+    to_device_id: &str,
+    sender_id: &str,
+    event_type: &str,
+    content: &Value,
+) {
+    let Some((sender, appservices)) = appservices(state).await else {
+        return;
+    };
+    let targets: Vec<&AppServiceRecord> = appservices
+        .iter()
+        .filter(|a| {
+            a.receive_ephemeral && is_appservice_user(a, to_user_id, state.server_name().as_str())
+        })
+        .collect();
+    let message = serde_json::json!({
+        "type": event_type,
+        "sender": sender_id,
+        "content": content,
+        "to_user_id": to_user_id,
+        "to_device_id": to_device_id,
+    });
+    enqueue(
+        state,
+        sender,
+        &targets,
+        AppServiceItemKind::ToDevice,
+        message,
+    )
+    .await;
+}
+
+/// Tell every MSC3202 AS that owns `user_id` or shares a room with it that
+/// the user's device list changed.
+pub async fn queue_device_list_change(state: &AppState, user_id: &str) {
+    let Some((sender, appservices)) = appservices(state).await else {
+        return;
+    };
+    let rooms = state
+        .storage()
+        .get_joined_rooms(user_id)
+        .await
+        .unwrap_or_default();
+    let mut targets = Vec::new();
+    for record in appservices.iter().filter(|a| a.msc3202) {
+        if is_appservice_user(record, user_id, state.server_name().as_str())
+            || shares_room(state, record, &rooms).await
+        {
+            targets.push(record);
+        }
+    }
+    let content = serde_json::json!({ "user_id": user_id });
+    enqueue(
+        state,
+        sender,
+        &targets,
+        AppServiceItemKind::DeviceListChanged,
+        content,
+    )
+    .await;
+}
+
+/// Tell the MSC3202 AS owning `user_id` that the device's one-time key count
+/// or unused fallback keys changed.
+pub async fn queue_one_time_key_count(state: &AppState, user_id: &str, device_id: &str) {
+    let Some((sender, appservices)) = appservices(state).await else {
+        return;
+    };
+    let targets: Vec<&AppServiceRecord> = appservices
+        .iter()
+        .filter(|a| a.msc3202 && is_appservice_user(a, user_id, state.server_name().as_str()))
+        .collect();
+    let content = serde_json::json!({ "user_id": user_id, "device_id": device_id });
+    enqueue(
+        state,
+        sender,
+        &targets,
+        AppServiceItemKind::OneTimeKeyCount,
+        content,
+    )
+    .await;
+}
+
+/// Exponential backoff: 1s, 2s, 4s, 8s... up to 10 minutes.
+fn backoff_for(failure_count: u32) -> chrono::Duration {
+    let exponent = failure_count.saturating_sub(1).min(31);
+    let wait = INITIAL_BACKOFF_MS
+        .saturating_mul(1u64 << exponent)
+        .min(MAX_BACKOFF_MS);
+    chrono::Duration::milliseconds(wait as i64)
+}
@@ -0,0 +1,198 @@
+//! # Device List Changes
+//!
+//! Whenever a local user's set of devices or their device keys change, the
+//! change is appended to the user's device list stream in storage
+//! (see [`DeviceListChange`]), and everyone tracking the user's devices is
+//! told:
+//!
+//! - local users sharing a room with them, through `device_lists.changed` in
+//!   `/sync` (woken by a room notification) and `/keys/changes`;
+//! - MSC3202 application services, through the transaction's device list
+//!   section;
+//! - remote servers sharing a room with them, through an
+//!   `m.device_list_update` EDU. Its `stream_id` is the change's position in
+//!   the user's stream and `prev_id` the position before it, so receivers can
+//!   tell when they missed an update and resync the whole list.
+//!
+//! Deleting a device also deletes its keys and the signatures on them, so it
+//! disappears from `/keys/query` at once, and the sync bookkeeping kept for
+//! it in memory.
+//!
+//! Changes to a user's cross-signing keys or to the signatures on their master
+//! key go through [`cross_signing_changed`], which records a key change on
+//! the global stream so local users re-query the user's keys. It takes no
+//! `stream_id` from the user's device list stream, which would leave a gap in
+//! the `prev_id` chain remote servers follow. New master or
+//! self-signing keys are also sent to remote servers sharing a room with the
+//! user, in an `m.signing_key_update` EDU (see [`signing_keys_changed`]).
+
+use maelstrom_core::matrix::id::{DeviceId, UserId};
+use maelstrom_federation::user_keys::{apply_key_signatures, cross_signing_key_id};
+use maelstrom_storage::traits::DeviceListChange;
+use tracing::warn;
+
+use crate::appservice_sender;
+use crate::notify::Notification;
+use crate::state::AppState;
+
+/// Record that a local user's device was added or updated (`deleted: false`)
+/// or deleted, and tell everyone tracking the user's devices.
+pub async fn device_changed(state: &AppState, user_id: &str, device_id: &str, deleted: bool) {
+    let storage = state.storage();
+    if deleted {
+        state.sync_state().forget_device(user_id, device_id);
+        if let Err(e) = storage.delete_device_keys(user_id, Some(device_id)).await {
+            warn!(user_id = %user_id, device_id = %device_id, error = %e, "Failed to delete keys of a deleted device");
+        }
+        if let Err(e) = storage.delete_key_signatures(user_id, device_id).await {
+            warn!(user_id = %user_id, device_id = %device_id, error = %e, "Failed to delete signatures of a deleted device");
+        }
+    }
+    let change = match storage
+        .add_device_list_change(user_id, device_id, deleted)
+        .await
+    {
+        Ok(change) => change,
+        Err(e) => {
+            warn!(user_id = %user_id, device_id = %device_id, error = %e, "Failed to record device list change");
+            return;
+        }
+    };
+
+    appservice_sender::queue_device_list_change(state, user_id).await;
+
+    if let Some(sender) = state.transaction_sender() {
+        let remote_servers = crate::handlers::util::servers_sharing_rooms(
+            storage,
+            user_id,
+            state.server_name().as_str(),
+        )
+        .await;
+        if !remote_servers.is_empty() {
+            let edu = device_list_update_edu(state, &change).await;
+            for server in remote_servers {
+                sender.queue_edu(&server, edu.clone());
+            }
+        }
+    }
+
+    // Wake the syncs of everyone sharing a room with the user
+    if let Ok(rooms) = storage.get_joined_rooms(user_id).await {
+        for room_id in rooms {
+            state
+                .notifier()
+                .notify(Notification::RoomEvent { room_id })
+                .await;
+        }
+    }
+}
+
+/// Record that a user's cross-signing keys or the signatures on them changed,
+/// so local users sharing a room with them (and the user's other devices)
+/// re-query their keys.
+///
+/// `user_id` may be remote when a local user signed their master key.
+pub async fn cross_signing_changed(state: &AppState, user_id: &str) {
+    let storage = state.storage();
+    if let Err(e) = storage.add_key_change(user_id).await {
+        warn!(user_id = %user_id, error = %e, "Failed to record cross-signing change");
+        return;
+    }
+
+    appservice_sender::queue_device_list_change(state, user_id).await;
+
+    if let Ok(rooms) = storage.get_joined_rooms(user_id).await {
+        for room_id in rooms {
+            state
+                .notifier()
+                .notify(Notification::RoomEvent { room_id })
+                .await;
+        }
+    }
+}
+
+/// Record that a local user uploaded new cross-signing keys (`keys` holds the
+/// uploaded ones by key type), and send the public ones to remote servers
+/// sharing a room with them.
+pub async fn signing_keys_changed(state: &AppState, user_id: &str, keys: &serde_json::Value) {
+    cross_signing_changed(state, user_id).await;
+
+    let Some(sender) = state.transaction_sender() else {
+        return;
+    };
+    // The user-signing key is private to the user's own server
+    let mut content = serde_json::json!({ "user_id": user_id });
+    for key_type in ["master_key", "self_signing_key"] {
+        if let Some(key) = keys.get(key_type) {
+            content[key_type] = key.clone();
+        }
+    }
+    if content.as_object().is_some_and(|c| c.len() == 1) {
+        return;
+    }
+    if let Some(master_key) = content.get_mut("master_key")
+        && let Some(key_id) = cross_signing_key_id(master_key).map(str::to_string)
+        && let Ok(signatures) = state.storage().get_key_signatures(user_id).await
+    {
+        apply_key_signatures(&signatures, None, &key_id, master_key);
+    }
+
+    let remote_servers = crate::handlers::util::servers_sharing_rooms(
+        state.storage(),
+        user_id,
+        state.server_name().as_str(),
+    )
+    .await;
+    let edu = serde_json::json!({
+        "edu_type": "m.signing_key_update",
+        "content": content,
+    });
+    for server in remote_servers {
+        sender.queue_edu(&server, edu.clone());
+    }
+}
+
+/// The `m.device_list_update` EDU announcing `change`.
+async fn device_list_update_edu(state: &AppState, change: &DeviceListChange) -> serde_json::Value {
+    let prev_id: Vec<i64> = (change.stream_id > 1)
+        .then_some(change.stream_id - 1)
+        .into_iter()
+        .collect();
+    let mut content = serde_json::json!({
+        "user_id": change.user_id,
+        "device_id": change.device_id,
+        "stream_id": change.stream_id,
+        "prev_id": prev_id,
+        "deleted": change.deleted,
+    });
+
+    if !change.deleted {
+        let storage = state.storage();
+        if let Ok(keys) = storage
+            .get_device_keys(std::slice::from_ref(&change.user_id))
+            .await
+            && let Some(device_keys) = keys
+                .get(&change.user_id)
+                .and_then(|devices| devices.get(&change.device_id))
+        {
+            let mut device_keys = device_keys.clone();
+            if let Ok(signatures) = storage.get_key_signatures(&change.user_id).await {
+                apply_key_signatures(&signatures, None, &change.device_id, &mut device_keys);
+            }
+            content["keys"] = device_keys;
+        }
+        if let Ok(user_id) = UserId::parse(&change.user_id)
+            && let Ok(device) = storage
+                .get_device(&user_id, &DeviceId::new(change.device_id.clone()))
+                .await
+            && let Some(name) = device.display_name
+        {
+            content["device_display_name"] = serde_json::Value::String(name);
+        }
+    }
+
+    serde_json::json!({
+        "edu_type": "m.device_list_update",
+        "content": content,
+    })
+}
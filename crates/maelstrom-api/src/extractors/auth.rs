@@ -13,11 +13,22 @@
 //! and device.  If the token is missing or invalid, the handler never runs --
 //! Axum returns a `401 M_UNKNOWN_TOKEN` or `401 M_MISSING_TOKEN` error
 //! directly.
+//!
+//! Application services authenticate with their `as_token` instead.  They act
+//! as their sender user by default, or as any user in their user namespaces by
+//! passing `user_id=<mxid>` (identity assertion).  With MSC3202 they may also
+//! pick one of that user's devices with `device_id=<id>`.
+//!
+//! Endpoints that only application services may call (such as publishing
+//! bridged rooms to the directory) use [`AuthenticatedAppService`] instead,
+//! which accepts nothing but a registered `as_token`; `Option<AuthenticatedAppService>`
+//! lets open endpoints like registration recognise an application service.
 
-use axum::extract::FromRequestParts;
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
 use http::request::Parts;
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
 use maelstrom_core::matrix::id::{DeviceId, UserId};
+use maelstrom_storage::traits::{AppServiceRecord, StorageError};
 
 use crate::state::AppState;
 
@@ -33,6 +44,11 @@ use crate::state::AppState;
 /// 1. Checks the `Authorization: Bearer <token>` header first.
 /// 2. Falls back to the `access_token` query parameter.
 /// 3. Looks up the token in the device store to resolve the owning user and device.
+/// 4. Otherwise treats it as an application service `as_token`, asserting the
+///    identity given by the `user_id` (and `device_id`) query parameters.
+///
+/// Requests made with a user's own access token, other than `/sync`, count
+/// as presence activity (see [`crate::presence`]).
 ///
 /// # Example
 ///
@@ -51,6 +67,9 @@ pub struct AuthenticatedUser {
     pub device_id: DeviceId,
     /// The raw access token string (useful for token revocation).
     pub access_token: String,
+    /// The ID of the application service acting as this user, when the
+    /// request was authenticated with an `as_token`.
+    pub appservice_id: Option<String>,
 }
 
 impl AuthenticatedUser {
@@ -86,12 +105,86 @@ impl AuthenticatedUser {
         if let Some(query) = parts.uri.query() {
             for pair in query.split('&') {
                 if let Some((key, value)) = pair.split_once('=') {
-                    map.insert(key.to_string(), value.to_string());
+                    let value = urlencoding::decode(value)
+                        .map(|v| v.into_owned())
+                        .unwrap_or_else(|_| value.to_string());
+                    map.insert(key.to_string(), value);
                 }
             }
         }
         map
     }
+
+    /// Resolve the identity an application service asserts for this request.
+    ///
+    /// Without `user_id` the AS acts as its sender user.  An asserted user must
+    /// be local, inside the AS's user namespaces and already registered.  An
+    /// asserted `device_id` (MSC3202) must belong to that user.
+    async fn assert_appservice_identity(
+        parts: &Parts,
+        state: &AppState,
+        appservice: &AppServiceRecord,
+    ) -> Result<(UserId, DeviceId), MatrixError> {
+        let query_params = Self::query_params(parts);
+        let sender = UserId::new(&appservice.sender_localpart, state.server_name());
+
+        let user_id = match query_params.get("user_id") {
+            Some(asserted) => {
+                let user_id = UserId::parse(asserted).map_err(|_| {
+                    MatrixError::new(
+                        http::StatusCode::BAD_REQUEST,
+                        ErrorCode::InvalidParam,
+                        "Invalid user_id",
+                    )
+                })?;
+                if user_id.server_name() != state.server_name().as_str()
+                    || !crate::appservice::is_appservice_user(
+                        appservice,
+                        user_id.as_ref(),
+                        state.server_name().as_str(),
+                    )
+                {
+                    return Err(MatrixError::forbidden(
+                        "Application service cannot masquerade as this user",
+                    ));
+                }
+                if user_id != sender {
+                    match state.storage().get_user(user_id.localpart()).await {
+                        Ok(_) => {}
+                        Err(StorageError::NotFound) => {
+                            return Err(MatrixError::forbidden(
+                                "Application service has not registered this user",
+                            ));
+                        }
+                        Err(e) => return Err(crate::extractors::storage_error(e)),
+                    }
+                }
+                user_id
+            }
+            None => sender,
+        };
+
+        let device_id = match query_params
+            .get("device_id")
+            .or_else(|| query_params.get("org.matrix.msc3202.device_id"))
+        {
+            Some(device_id) => {
+                let device_id = DeviceId::new(device_id.clone());
+                match state.storage().get_device(&user_id, &device_id).await {
+                    Ok(_) => device_id,
+                    Err(StorageError::NotFound) => {
+                        return Err(MatrixError::forbidden(
+                            "Application service cannot use a device that does not exist",
+                        ));
+                    }
+                    Err(e) => return Err(crate::extractors::storage_error(e)),
+                }
+            }
+            None => DeviceId::new("appservice"),
+        };
+
+        Ok((user_id, device_id))
+    }
 }
 
 impl FromRequestParts<AppState> for AuthenticatedUser {
@@ -115,10 +208,16 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
                     UserId::new(&device.user_id, state.server_name())
                 };
 
+                // Any request but a sync is presence activity
+                if !parts.uri.path().ends_with("/sync") {
+                    crate::presence::record_activity(state, user_id.as_ref());
+                }
+
                 Ok(AuthenticatedUser {
                     user_id,
                     device_id: DeviceId::new(device.device_id),
                     access_token: token,
+                    appservice_id: None,
                 })
             }
             Err(_) => {
@@ -132,24 +231,67 @@ impl FromRequestParts<AppState> for AuthenticatedUser {
                         MatrixError::unauthorized("Unknown or expired access token")
                     })?;
 
-                let query_params = Self::query_params(parts);
-
-                // AS authenticated -- check for user impersonation
-                let user_id = if let Some(impersonate) = query_params.get("user_id") {
-                    // Verify the impersonated user is in the AS's namespace
-                    UserId::parse(impersonate)
-                        .map_err(|_| MatrixError::forbidden("Invalid user_id"))?
-                } else {
-                    // Default to AS's sender user
-                    UserId::new(&appservice.sender_localpart, state.server_name())
-                };
+                let (user_id, device_id) =
+                    Self::assert_appservice_identity(parts, state, &appservice).await?;
 
                 Ok(AuthenticatedUser {
                     user_id,
-                    device_id: DeviceId::new("appservice"),
+                    device_id,
                     access_token: token,
+                    appservice_id: Some(appservice.id),
                 })
             }
         }
     }
 }
+
+/// Authentication gate for endpoints reserved to application services.
+///
+/// The token is resolved the same way as for [`AuthenticatedUser`], but must be
+/// the `as_token` of a registered application service.  A valid user access
+/// token is rejected with `403 M_FORBIDDEN`; an unknown token with `401`.
+pub struct AuthenticatedAppService {
+    /// The registration of the calling application service.
+    pub appservice: AppServiceRecord,
+}
+
+impl FromRequestParts<AppState> for AuthenticatedAppService {
+    type Rejection = MatrixError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = AuthenticatedUser::extract_token(parts)?;
+
+        match state.storage().get_appservice_by_token(&token).await {
+            Ok(appservice) => Ok(AuthenticatedAppService { appservice }),
+            Err(_) if state.storage().get_device_by_token(&token).await.is_ok() => Err(
+                MatrixError::forbidden("Only application services may use this endpoint"),
+            ),
+            Err(_) => Err(MatrixError::unauthorized("Unknown or expired access token")),
+        }
+    }
+}
+
+/// Optional form for endpoints open to everyone that behave differently for
+/// application services (registration and login): anything but a registered
+/// `as_token` yields `None`.
+impl OptionalFromRequestParts<AppState> for AuthenticatedAppService {
+    type Rejection = MatrixError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let Ok(token) = AuthenticatedUser::extract_token(parts) else {
+            return Ok(None);
+        };
+        Ok(state
+            .storage()
+            .get_appservice_by_token(&token)
+            .await
+            .ok()
+            .map(|appservice| AuthenticatedAppService { appservice }))
+    }
+}
@@ -6,11 +6,13 @@
 //! extraction fails, the handler is never invoked and the extractor's `Rejection`
 //! type is returned to the client instead.
 //!
-//! This module provides two extractors that most handlers use:
+//! This module provides the extractors that most handlers use:
 //!
 //! - [`AuthenticatedUser`] -- validates the access token and provides the
 //!   caller's `user_id` and `device_id`.  Any handler that includes this in
 //!   its parameter list automatically requires authentication.
+//! - [`AuthenticatedAppService`] -- like `AuthenticatedUser`, but only accepts
+//!   an application service's `as_token`.
 //! - [`MatrixJson`] -- parses a JSON request body and returns Matrix-spec
 //!   error codes (`M_NOT_JSON`, `M_BAD_JSON`) on failure, instead of Axum's
 //!   default plain-text error.
@@ -21,7 +23,7 @@
 pub mod auth;
 pub mod json;
 
-pub use auth::AuthenticatedUser;
+pub use auth::{AuthenticatedAppService, AuthenticatedUser};
 pub use json::MatrixJson;
 
 use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
@@ -79,9 +79,10 @@ pub async fn start(
                     user_id,
                     status,
                     status_msg,
+                    last_active_ts,
                 } => {
                     let key = format!("{PRESENCE_PREFIX}{user_id}");
-                    let value = encode_presence(&status, status_msg.as_deref(), now_ms());
+                    let value = encode_presence(&status, status_msg.as_deref(), last_active_ts);
                     state.set(key, value);
                 }
             }
@@ -118,8 +119,8 @@ pub async fn start(
             return;
         }
         let user_id = evt.key; // key suffix after prefix strip
-        if let Some((status, status_msg, _ts)) = decode_presence(evt.value) {
-            ephemeral.merge_presence(user_id, &status, status_msg.as_deref());
+        if let Some((status, status_msg, last_active_ts)) = decode_presence(evt.value) {
+            ephemeral.merge_presence(user_id, &status, status_msg.as_deref(), last_active_ts);
             notifier.notify_sync(Notification::Presence {
                 user_id: user_id.to_string(),
             });
@@ -171,11 +171,20 @@ async fn deactivate(
         .map_err(crate::extractors::storage_error)?;
 
     // Remove all devices
+    let devices = state
+        .storage()
+        .list_devices(&auth.user_id)
+        .await
+        .unwrap_or_default();
     state
         .storage()
         .remove_all_devices(&auth.user_id)
         .await
         .map_err(crate::extractors::storage_error)?;
+    for device in &devices {
+        crate::device_lists::device_changed(&state, auth.user_id.as_ref(), &device.device_id, true)
+            .await;
+    }
 
     Ok((
         StatusCode::OK,
@@ -258,11 +267,28 @@ async fn change_password(
 
     // Optionally log out all other devices (keep current session)
     if body.logout_devices {
+        let devices = state
+            .storage()
+            .list_devices(&auth.user_id)
+            .await
+            .unwrap_or_default();
         state
             .storage()
             .remove_all_devices_except(&auth.user_id, &auth.device_id)
             .await
             .map_err(crate::extractors::storage_error)?;
+        for device in devices
+            .iter()
+            .filter(|d| d.device_id != auth.device_id.as_str())
+        {
+            crate::device_lists::device_changed(
+                &state,
+                auth.user_id.as_ref(),
+                &device.device_id,
+                true,
+            )
+            .await;
+        }
 
         // Remove pushers created by other sessions (keep current token's pushers)
         let user_id = auth.user_id.to_string();
@@ -12,6 +12,15 @@
 //! | `GET`    | `/_maelstrom/admin/v1/appservices`       | List registered ASes |
 //! | `DELETE` | `/_maelstrom/admin/v1/appservice/{asId}` | Unregister an AS |
 //!
+//! # Application service endpoints (Matrix spec)
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `POST` | `/_matrix/client/v1/appservice/{appserviceId}/ping` | Check that the HS can reach the AS |
+//!
+//! Besides the admin API, registrations are loaded from YAML files listed in
+//! the server configuration; see [`crate::appservice::load_registration_files`].
+//!
 //! # Third-party protocol endpoints (Matrix spec)
 //!
 //! | Method | Path | Description |
@@ -20,25 +29,35 @@
 //! | `GET` | `/_matrix/client/v3/thirdparty/protocol/{protocol}`  | Protocol details |
 //! | `GET` | `/_matrix/client/v3/thirdparty/location/{protocol}`  | Search locations by protocol |
 //! | `GET` | `/_matrix/client/v3/thirdparty/user/{protocol}`      | Search users by protocol |
-//! | `GET` | `/_matrix/client/v3/thirdparty/location`             | Search all locations |
-//! | `GET` | `/_matrix/client/v3/thirdparty/user`                 | Search all users |
+//! | `GET` | `/_matrix/client/v3/thirdparty/location`             | Locations for a room alias |
+//! | `GET` | `/_matrix/client/v3/thirdparty/user`                 | Third-party users for a user ID |
+//!
+//! These are proxied to the bridges through
+//! [`AppServiceQuerier`](crate::appservice_query::AppServiceQuerier).
 //!
 //! # Event push
 //!
-//! The [`notify_appservices`] function checks whether a newly stored event
-//! matches any registered AS's user namespace and pushes it via HTTP PUT
-//! to `/_matrix/app/v1/transactions/{txnId}` on the AS's URL.  Currently
-//! this is called synchronously after event storage; a production deployment
-//! would use a background queue.
+//! Events and ephemeral data are pushed to each AS's
+//! `/_matrix/app/v1/transactions/{txnId}` by the background
+//! [`AppServiceSender`](crate::appservice_sender::AppServiceSender), from a
+//! durable per-AS queue.
+
+use std::collections::HashMap;
 
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Response};
 use axum::routing::{delete, get, post};
 use axum::{Json, Router};
-use maelstrom_core::matrix::error::MatrixError;
-use maelstrom_core::matrix::event::Pdu;
-use maelstrom_storage::traits::{AppServiceRecord, Storage};
+use http::StatusCode;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_storage::traits::AppServiceRecord;
+use serde::Deserialize;
 use serde_json::Value;
+use tokio::task::JoinSet;
 
+use crate::appservice::{is_appservice_user, matches_namespace};
+use crate::appservice_query::PingError;
+use crate::extractors::{AuthenticatedAppService, AuthenticatedUser, MatrixJson};
 use crate::state::AppState;
 
 /// Build the router for application service endpoints.
@@ -51,6 +70,10 @@ pub fn routes() -> Router<AppState> {
             "/_maelstrom/admin/v1/appservice/{asId}",
             delete(delete_appservice),
         )
+        .route(
+            "/_matrix/client/v1/appservice/{appserviceId}/ping",
+            post(ping_appservice),
+        )
         // Third-party protocol endpoints
         .route(
             "/_matrix/client/v3/thirdparty/protocols",
@@ -93,6 +116,8 @@ async fn register_appservice(
             MatrixError::unknown(format!("Failed to register appservice: {e}"))
         })?;
 
+    state.appservice_query().forget(&record.id);
+
     Ok(Json(serde_json::json!({ "id": record.id })))
 }
 
@@ -121,30 +146,202 @@ async fn delete_appservice(
     Ok(Json(serde_json::json!({})))
 }
 
+// ---------------------------------------------------------------------------
+// Ping
+// ---------------------------------------------------------------------------
+
+#[derive(Deserialize)]
+struct PingRequest {
+    transaction_id: Option<String>,
+}
+
+/// Ask the homeserver to ping the calling AS, returning the round trip in
+/// `duration_ms`.  An AS may only ping itself.
+async fn ping_appservice(
+    State(state): State<AppState>,
+    AuthenticatedAppService { appservice }: AuthenticatedAppService,
+    Path(appservice_id): Path<String>,
+    MatrixJson(body): MatrixJson<PingRequest>,
+) -> Result<Response, MatrixError> {
+    if appservice.id != appservice_id {
+        return Err(MatrixError::forbidden(
+            "Application services may only ping themselves",
+        ));
+    }
+    if appservice.url.is_empty() {
+        return Err(MatrixError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::UrlNotSet,
+            "Application service has no URL",
+        ));
+    }
+
+    match state
+        .appservice_query()
+        .ping(&appservice, body.transaction_id.as_deref())
+        .await
+    {
+        Ok(elapsed) => Ok(
+            Json(serde_json::json!({ "duration_ms": elapsed.as_millis() as u64 })).into_response(),
+        ),
+        // The AS's answer is passed back so its developer can see what failed
+        Err(PingError::Status { status, body }) => Ok((
+            StatusCode::BAD_GATEWAY,
+            Json(serde_json::json!({
+                "errcode": ErrorCode::BadStatus,
+                "error": format!("Application service returned HTTP {status}"),
+                "status": status,
+                "body": body,
+            })),
+        )
+            .into_response()),
+        Err(PingError::Timeout) => Err(MatrixError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::ConnectionTimeout,
+            "Application service did not respond in time",
+        )),
+        Err(PingError::Connection(e)) => Err(MatrixError::new(
+            StatusCode::BAD_GATEWAY,
+            ErrorCode::ConnectionFailed,
+            format!("Could not reach application service: {e}"),
+        )),
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Third-party protocol endpoints
 // ---------------------------------------------------------------------------
 
+/// Room directory instance ID of a bridged network: `{asId}|{networkId}`.
+///
+/// Clients pass this as `third_party_instance_id` to `/publicRooms`.
+pub(crate) fn network_instance_id(as_id: &str, network_id: &str) -> String {
+    format!("{as_id}|{network_id}")
+}
+
+/// Whether `instance_id` names a network instance of a registered AS.
+pub(crate) fn is_known_network_instance(
+    appservices: &[AppServiceRecord],
+    instance_id: &str,
+) -> bool {
+    appservices.iter().any(|a| {
+        a.protocols
+            .iter()
+            .any(|p| network_instance_id(&a.id, p) == instance_id)
+    })
+}
+
+/// Merged metadata for `protocol` from every AS bridging it.
+///
+/// The ASes are asked concurrently. The first AS (in registration order) to
+/// answer provides the field descriptions; the network instances of all of
+/// them are concatenated, each with an `instance_id` the room directory
+/// understands.  An AS that cannot be reached still contributes one instance
+/// whose network ID is the protocol name.
+async fn protocol_metadata(
+    state: &AppState,
+    appservices: &[AppServiceRecord],
+    protocol: &str,
+) -> Value {
+    let mut queries = JoinSet::new();
+    for (index, record) in appservices
+        .iter()
+        .filter(|a| bridges(a, protocol))
+        .cloned()
+        .enumerate()
+    {
+        let state = state.clone();
+        let protocol = protocol.to_string();
+        queries.spawn(async move {
+            let metadata = state.appservice_query().protocol(&record, &protocol).await;
+            (index, record, metadata)
+        });
+    }
+    let mut answers = Vec::new();
+    while let Some(result) = queries.join_next().await {
+        answers.extend(result.ok());
+    }
+    answers.sort_by_key(|(index, _, _)| *index);
+
+    let mut merged = None;
+    let mut instances = Vec::new();
+    for (_, record, metadata) in answers {
+        let Some(mut metadata) = metadata else {
+            instances.push(serde_json::json!({
+                "desc": record.id,
+                "network_id": protocol,
+                "instance_id": network_instance_id(&record.id, protocol),
+                "fields": {},
+            }));
+            continue;
+        };
+        if let Some(Value::Array(bridged)) = metadata.get_mut("instances").map(Value::take) {
+            for mut instance in bridged.into_iter().filter(Value::is_object) {
+                let network_id = instance["network_id"]
+                    .as_str()
+                    .unwrap_or(protocol)
+                    .to_string();
+                instance["instance_id"] =
+                    Value::String(network_instance_id(&record.id, &network_id));
+                instances.push(instance);
+            }
+        }
+        merged.get_or_insert(metadata);
+    }
+
+    let mut merged = merged.unwrap_or_else(|| {
+        serde_json::json!({
+            "user_fields": [],
+            "location_fields": [],
+            "icon": "",
+            "field_types": {},
+        })
+    });
+    merged["instances"] = Value::Array(instances);
+    merged
+}
+
+/// Whether the AS bridges `protocol`.
+fn bridges(record: &AppServiceRecord, protocol: &str) -> bool {
+    record.protocols.iter().any(|p| p == protocol)
+}
+
+/// The client's query parameters, minus its access token, to forward to an AS.
+fn lookup_params(mut query: HashMap<String, String>) -> Vec<(String, String)> {
+    query.remove("access_token");
+    query.into_iter().collect()
+}
+
 /// List all third-party protocols advertised by registered ASes.
 ///
-/// Returns a map of protocol name to protocol metadata.  Currently the
-/// metadata is minimal (empty fields/instances); full delegation to the
-/// AS would require an HTTP call to the AS's URL.
-async fn get_protocols(State(state): State<AppState>) -> Result<Json<Value>, MatrixError> {
+/// Returns a map of protocol name to protocol metadata, as merged by
+/// [`protocol_metadata`].
+async fn get_protocols(
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+) -> Result<Json<Value>, MatrixError> {
     let appservices = state.storage().list_appservices().await.unwrap_or_default();
+    let mut names: Vec<String> = appservices
+        .iter()
+        .flat_map(|a| a.protocols.iter().cloned())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    let appservices = std::sync::Arc::new(appservices);
+    let mut queries = JoinSet::new();
+    for protocol in names {
+        let state = state.clone();
+        let appservices = appservices.clone();
+        queries.spawn(async move {
+            let metadata = protocol_metadata(&state, &appservices, &protocol).await;
+            (protocol, metadata)
+        });
+    }
     let mut protocols = serde_json::Map::new();
-    for as_record in &appservices {
-        for proto in &as_record.protocols {
-            protocols.insert(
-                proto.clone(),
-                serde_json::json!({
-                    "user_fields": [],
-                    "location_fields": [],
-                    "icon": "",
-                    "field_types": {},
-                    "instances": []
-                }),
-            );
+    while let Some(result) = queries.join_next().await {
+        if let Ok((protocol, metadata)) = result {
+            protocols.insert(protocol, metadata);
         }
     }
     Ok(Json(Value::Object(protocols)))
@@ -153,94 +350,121 @@ async fn get_protocols(State(state): State<AppState>) -> Result<Json<Value>, Mat
 /// Get details for a specific third-party protocol.
 async fn get_protocol(
     State(state): State<AppState>,
+    _auth: AuthenticatedUser,
     Path(protocol): Path<String>,
 ) -> Result<Json<Value>, MatrixError> {
     let appservices = state.storage().list_appservices().await.unwrap_or_default();
-    let has_protocol = appservices.iter().any(|a| a.protocols.contains(&protocol));
-
-    if !has_protocol {
+    if !appservices.iter().any(|a| bridges(a, &protocol)) {
         return Err(MatrixError::not_found("Protocol not found"));
     }
 
-    Ok(Json(serde_json::json!({
-        "user_fields": [],
-        "location_fields": [],
-        "icon": "",
-        "field_types": {},
-        "instances": []
-    })))
+    Ok(Json(
+        protocol_metadata(&state, &appservices, &protocol).await,
+    ))
 }
 
-/// Search for third-party locations by protocol.
+/// Search for third-party locations by protocol, using the protocol's
+/// location fields from the query string.
 async fn get_location_by_protocol(
-    State(_state): State<AppState>,
-    Path(_protocol): Path<String>,
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+    Path(protocol): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, MatrixError> {
-    // Full implementation would query the AS via HTTP.
-    Ok(Json(serde_json::json!([])))
+    lookup_by_protocol(&state, "location", &protocol, query).await
 }
 
-/// Search for third-party users by protocol.
+/// Search for third-party users by protocol, using the protocol's user
+/// fields from the query string.
 async fn get_user_by_protocol(
-    State(_state): State<AppState>,
-    Path(_protocol): Path<String>,
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+    Path(protocol): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
 ) -> Result<Json<Value>, MatrixError> {
-    Ok(Json(serde_json::json!([])))
+    lookup_by_protocol(&state, "user", &protocol, query).await
 }
 
-/// Search all third-party locations across all protocols.
-async fn get_locations(State(_state): State<AppState>) -> Result<Json<Value>, MatrixError> {
-    Ok(Json(serde_json::json!([])))
-}
+async fn lookup_by_protocol(
+    state: &AppState,
+    kind: &str,
+    protocol: &str,
+    query: HashMap<String, String>,
+) -> Result<Json<Value>, MatrixError> {
+    let appservices: Vec<AppServiceRecord> = state
+        .storage()
+        .list_appservices()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|a| bridges(a, protocol))
+        .collect();
+    if appservices.is_empty() {
+        return Err(MatrixError::not_found("Protocol not found"));
+    }
 
-/// Search all third-party users across all protocols.
-async fn get_users(State(_state): State<AppState>) -> Result<Json<Value>, MatrixError> {
-    Ok(Json(serde_json::json!([])))
+    let path = format!(
+        "/_matrix/app/v1/thirdparty/{kind}/{}",
+        urlencoding::encode(protocol)
+    );
+    let results = state
+        .appservice_query()
+        .lookup(appservices, &path, &lookup_params(query))
+        .await;
+    Ok(Json(Value::Array(results)))
 }
 
-// ---------------------------------------------------------------------------
-// Event push to application services
-// ---------------------------------------------------------------------------
+/// Resolve a Matrix room alias to third-party locations, asking the ASes
+/// whose alias namespaces cover it.
+async fn get_locations(
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, MatrixError> {
+    let alias = query
+        .get("alias")
+        .ok_or_else(|| MatrixError::missing_param("Missing alias"))?;
+    let appservices: Vec<AppServiceRecord> = state
+        .storage()
+        .list_appservices()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|a| matches_namespace(&a.alias_namespaces, alias))
+        .collect();
 
-/// Check if any registered application service should receive this event
-/// and push it via HTTP PUT to the AS's transaction endpoint.
-///
-/// Called synchronously after storing events.  A production implementation
-/// would use a background queue to avoid blocking the request path.
-pub async fn notify_appservices(storage: &dyn Storage, event: &Pdu, http_client: &reqwest::Client) {
-    let appservices = match storage.list_appservices().await {
-        Ok(list) => list,
-        Err(_) => return,
-    };
-
-    for as_record in appservices {
-        // Check if event sender matches any user namespace regex
-        let matches = as_record.user_namespaces.iter().any(|ns| {
-            regex::Regex::new(&ns.regex)
-                .map(|re| re.is_match(&event.sender))
-                .unwrap_or(false)
-        });
+    let params = [("alias".to_string(), alias.clone())];
+    let results = state
+        .appservice_query()
+        .lookup(appservices, "/_matrix/app/v1/thirdparty/location", &params)
+        .await;
+    Ok(Json(Value::Array(results)))
+}
 
-        if matches {
-            let txn_id = format!("{}_{}", event.stream_position, event.event_id);
-            let url = format!("{}/_matrix/app/v1/transactions/{}", as_record.url, txn_id);
-            let body = serde_json::json!({
-                "events": [event.to_federation_json()]
-            });
-
-            if let Err(e) = http_client
-                .put(&url)
-                .header("Authorization", format!("Bearer {}", as_record.hs_token))
-                .json(&body)
-                .send()
-                .await
-            {
-                tracing::warn!(
-                    as_id = %as_record.id,
-                    event_id = %event.event_id,
-                    "Failed to push event to appservice: {e}"
-                );
-            }
-        }
-    }
+/// Resolve a Matrix user ID to third-party users, asking the ASes whose
+/// user namespaces cover it.
+async fn get_users(
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<Value>, MatrixError> {
+    let user_id = query
+        .get("userid")
+        .ok_or_else(|| MatrixError::missing_param("Missing userid"))?;
+    let server_name = state.server_name().as_str();
+    let appservices: Vec<AppServiceRecord> = state
+        .storage()
+        .list_appservices()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|a| is_appservice_user(a, user_id, server_name))
+        .collect();
+
+    let params = [("userid".to_string(), user_id.clone())];
+    let results = state
+        .appservice_query()
+        .lookup(appservices, "/_matrix/app/v1/thirdparty/user", &params)
+        .await;
+    Ok(Json(Value::Array(results)))
 }
@@ -25,6 +25,12 @@
 //! that other users sharing rooms with this user will see the new device on their
 //! next `/sync`.
 //!
+//! # Application service login (`m.login.application_service`)
+//!
+//! An application service may log in as any registered user in its user
+//! namespaces by sending its `as_token` and the user identifier without a
+//! password.  This gives the AS a real device (and token) for that user.
+//!
 //! # Logout
 //!
 //! - `POST /logout` removes only the device (and its access token) that made the
@@ -42,7 +48,8 @@ use maelstrom_core::matrix::id::{DeviceId, UserId};
 use maelstrom_core::matrix::room::account_data_type;
 use maelstrom_storage::traits::{DeviceRecord, StorageError};
 
-use crate::extractors::{AuthenticatedUser, MatrixJson};
+use crate::extractors::{AuthenticatedAppService, AuthenticatedUser, MatrixJson};
+use crate::handlers::register::APPSERVICE_LOGIN_TYPE;
 use crate::handlers::util;
 use crate::state::AppState;
 
@@ -65,8 +72,8 @@ pub fn routes() -> Router<AppState> {
 
 /// Response body for `GET /login`.
 ///
-/// Returns the list of supported authentication flows. Currently only
-/// `m.login.password` is advertised.
+/// Returns the list of supported authentication flows: `m.login.password`
+/// and `m.login.application_service`.
 #[derive(Serialize)]
 struct LoginFlowsResponse {
     flows: Vec<LoginFlow>,
@@ -80,9 +87,14 @@ struct LoginFlow {
 
 async fn get_login() -> Json<LoginFlowsResponse> {
     Json(LoginFlowsResponse {
-        flows: vec![LoginFlow {
-            flow_type: "m.login.password",
-        }],
+        flows: vec![
+            LoginFlow {
+                flow_type: "m.login.password",
+            },
+            LoginFlow {
+                flow_type: APPSERVICE_LOGIN_TYPE,
+            },
+        ],
     })
 }
 
@@ -129,16 +141,18 @@ struct LoginResponse {
 
 async fn post_login(
     State(state): State<AppState>,
+    appservice: Option<AuthenticatedAppService>,
     MatrixJson(body): MatrixJson<LoginRequest>,
 ) -> Result<Json<LoginResponse>, MatrixError> {
-    if body.login_type != "m.login.password" {
-        return Err(MatrixError::unknown("Unsupported login type"));
-    }
-
-    let password = body
-        .password
-        .as_deref()
-        .ok_or_else(|| MatrixError::bad_json("Missing password field"))?;
+    let appservice = match body.login_type.as_str() {
+        "m.login.password" => None,
+        APPSERVICE_LOGIN_TYPE => Some(
+            appservice
+                .ok_or_else(MatrixError::missing_token)?
+                .appservice,
+        ),
+        _ => return Err(MatrixError::unknown("Unsupported login type")),
+    };
 
     // Resolve the username from identifier or legacy user field
     let raw_user = body
@@ -181,15 +195,36 @@ async fn post_login(
         ));
     }
 
-    // Verify password
-    let hash = user
-        .password_hash
-        .as_deref()
-        .ok_or_else(|| MatrixError::forbidden("Invalid username or password"))?;
-
-    util::verify_password(password.to_string(), hash.to_string())
-        .await
-        .map_err(|_| MatrixError::forbidden("Invalid username or password"))?;
+    match &appservice {
+        // The AS vouches for users in its namespaces
+        Some(appservice) => {
+            let user_id = UserId::new(&localpart, state.server_name());
+            if !crate::appservice::is_appservice_user(
+                appservice,
+                user_id.as_ref(),
+                state.server_name().as_str(),
+            ) {
+                return Err(MatrixError::forbidden(
+                    "User is not in the application service's namespaces",
+                ));
+            }
+        }
+        None => {
+            let password = body
+                .password
+                .as_deref()
+                .ok_or_else(|| MatrixError::bad_json("Missing password field"))?;
+
+            let hash = user
+                .password_hash
+                .as_deref()
+                .ok_or_else(|| MatrixError::forbidden("Invalid username or password"))?;
+
+            util::verify_password(password.to_string(), hash.to_string())
+                .await
+                .map_err(|_| MatrixError::forbidden("Invalid username or password"))?;
+        }
+    }
 
     // Create device and access token
     let device_id = body
@@ -212,52 +247,7 @@ async fn post_login(
         .await
         .map_err(crate::extractors::storage_error)?;
 
-    // Record device list change for sync tracking
-    // Increment stream position so other users' syncs see a change
-    let change_pos = state.storage().next_stream_position().await.unwrap_or(0);
-    let _ = state
-        .storage()
-        .set_account_data(
-            user_id.as_ref(),
-            None,
-            "_maelstrom.device_change_pos",
-            &serde_json::json!({"pos": change_pos}),
-        )
-        .await;
-
-    // Notify remote servers about new device via federation EDU
-    if let Some(sender) = state.transaction_sender() {
-        let remote_servers = crate::handlers::util::servers_sharing_rooms(
-            state.storage(),
-            user_id.as_ref(),
-            state.server_name().as_str(),
-        )
-        .await;
-        for server in remote_servers {
-            sender.queue_edu(
-                &server,
-                serde_json::json!({
-                    "edu_type": "m.device_list_update",
-                    "content": {
-                        "user_id": user_id.to_string(),
-                        "device_id": device_id,
-                        "stream_id": change_pos,
-                        "deleted": false,
-                    }
-                }),
-            );
-        }
-    }
-
-    // Notify all rooms the user is in so other users' syncs wake up
-    if let Ok(rooms) = state.storage().get_joined_rooms(user_id.as_ref()).await {
-        for room_id in rooms {
-            state
-                .notifier()
-                .notify(crate::notify::Notification::RoomEvent { room_id })
-                .await;
-        }
-    }
+    crate::device_lists::device_changed(&state, user_id.as_ref(), &device_id, false).await;
 
     Ok(Json(LoginResponse {
         user_id: user_id.to_string(),
@@ -296,40 +286,7 @@ async fn post_logout(
         tracing::warn!(user = %auth.user_id, key = %notif_key, error = %e, "Failed to delete device notification settings on logout");
     }
 
-    // Record device list change and notify remote servers
-    let change_pos = state.storage().current_stream_position().await.unwrap_or(0);
-    let _ = state
-        .storage()
-        .set_account_data(
-            &user_id,
-            None,
-            "_maelstrom.device_change_pos",
-            &serde_json::json!({"pos": change_pos}),
-        )
-        .await;
-
-    if let Some(sender) = state.transaction_sender() {
-        let remote_servers = crate::handlers::util::servers_sharing_rooms(
-            state.storage(),
-            &user_id,
-            state.server_name().as_str(),
-        )
-        .await;
-        for server in remote_servers {
-            sender.queue_edu(
-                &server,
-                serde_json::json!({
-                    "edu_type": "m.device_list_update",
-                    "content": {
-                        "user_id": user_id,
-                        "device_id": device_id,
-                        "stream_id": change_pos,
-                        "deleted": true,
-                    }
-                }),
-            );
-        }
-    }
+    crate::device_lists::device_changed(&state, &user_id, &device_id, true).await;
 
     Ok(Json(serde_json::json!({})))
 }
@@ -368,38 +325,8 @@ async fn post_logout_all(
             .await;
     }
 
-    // Record device list change and notify remote servers (all devices removed)
-    let change_pos = state.storage().current_stream_position().await.unwrap_or(0);
-    let _ = state
-        .storage()
-        .set_account_data(
-            &user_id,
-            None,
-            "_maelstrom.device_change_pos",
-            &serde_json::json!({"pos": change_pos}),
-        )
-        .await;
-
-    if let Some(sender) = state.transaction_sender() {
-        let remote_servers = crate::handlers::util::servers_sharing_rooms(
-            state.storage(),
-            &user_id,
-            state.server_name().as_str(),
-        )
-        .await;
-        for server in remote_servers {
-            sender.queue_edu(
-                &server,
-                serde_json::json!({
-                    "edu_type": "m.device_list_update",
-                    "content": {
-                        "user_id": user_id,
-                        "stream_id": change_pos,
-                        "deleted": true,
-                    }
-                }),
-            );
-        }
+    for dev in &devices {
+        crate::device_lists::device_changed(&state, &user_id, &dev.device_id, true).await;
     }
 
     Ok(Json(serde_json::json!({})))
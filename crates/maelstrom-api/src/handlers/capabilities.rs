@@ -7,6 +7,8 @@
 //!   Clients use this to decide which features to enable in their UI.
 //! * **Event filters** -- server-side filter definitions that clients create
 //!   once and reference by ID in `/sync` requests to limit returned data.
+//!   The grammar and its matching rules live in
+//!   [`maelstrom_core::matrix::filter`].
 //! * **Account data** -- arbitrary per-user (and per-room) JSON blobs that
 //!   clients store on the server (e.g. push rules, ignored users, client
 //!   settings). Includes unstable MSC3391 deletion support.
@@ -61,6 +63,7 @@ use http::StatusCode;
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_core::matrix::filter::Filter;
 use maelstrom_core::matrix::id::DeviceId;
 use maelstrom_core::matrix::room::event_type as et;
 use maelstrom_storage::traits::StorageError;
@@ -274,6 +277,9 @@ async fn create_filter(
         }
     }
 
+    // The rest of the grammar (`event_format`, limits, flags) must parse too
+    Filter::from_json(&body).map_err(|e| MatrixError::bad_json(format!("Invalid filter: {e}")))?;
+
     // Generate a unique filter ID and store the filter via account data
     let sender = auth.user_id.to_string();
     let storage = state.storage();
@@ -325,6 +331,25 @@ async fn get_filter(
     Ok(Json(filter))
 }
 
+/// Resolve the `filter` parameter of `/sync`: inline JSON, or the ID of a
+/// filter the user created. `None` if it is neither.
+pub(crate) async fn load_filter(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    filter: &str,
+) -> Option<Filter> {
+    let json = if filter.starts_with('{') {
+        serde_json::from_str(filter).ok()?
+    } else {
+        let filter_key = format!("_maelstrom.filter.{filter}");
+        storage
+            .get_account_data(user_id, None, &filter_key)
+            .await
+            .ok()?
+    };
+    Filter::from_json(&json).ok()
+}
+
 // -- Account Data --
 
 async fn get_account_data(
@@ -976,9 +1001,12 @@ async fn list_devices(
         .list_devices(&auth.user_id)
         .await
         .map_err(crate::extractors::storage_error)?;
+    let dehydrated =
+        crate::handlers::dehydrated_device::dehydrated_device_id(&state, &auth.user_id).await;
 
     let device_list: Vec<serde_json::Value> = devices
         .iter()
+        .filter(|d| dehydrated.as_deref() != Some(d.device_id.as_str()))
         .map(|d| {
             serde_json::json!({
                 "device_id": d.device_id,
@@ -992,12 +1020,28 @@ async fn list_devices(
     Ok(Json(serde_json::json!({ "devices": device_list })))
 }
 
+/// The dehydrated device is managed through its own endpoints, so the device
+/// endpoints act as though it does not exist.
+async fn reject_dehydrated_device(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    device_id: &str,
+) -> Result<(), MatrixError> {
+    let dehydrated =
+        crate::handlers::dehydrated_device::dehydrated_device_id(state, &auth.user_id).await;
+    if dehydrated.as_deref() == Some(device_id) {
+        return Err(MatrixError::not_found("Device not found"));
+    }
+    Ok(())
+}
+
 async fn get_device(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     Path(device_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let did = DeviceId::new(&device_id);
+    reject_dehydrated_device(&state, &auth, &device_id).await?;
     let device = state
         .storage()
         .get_device(&auth.user_id, &did)
@@ -1027,6 +1071,7 @@ async fn update_device(
     MatrixJson(body): MatrixJson<UpdateDeviceRequest>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let did = DeviceId::new(&device_id);
+    reject_dehydrated_device(&state, &auth, &device_id).await?;
 
     // Check device exists and belongs to user
     let _device = state
@@ -1045,6 +1090,8 @@ async fn update_device(
             .update_device_display_name(&auth.user_id, &did, Some(name))
             .await
             .map_err(crate::extractors::storage_error)?;
+        crate::device_lists::device_changed(&state, auth.user_id.as_ref(), did.as_str(), false)
+            .await;
     }
 
     Ok(Json(serde_json::json!({})))
@@ -1086,6 +1133,7 @@ async fn delete_device(
         serde_json::from_slice(&body).ok()
     };
     let did = DeviceId::new(&device_id);
+    reject_dehydrated_device(&state, &auth, &device_id).await?;
 
     // Check device belongs to authenticated user
     match state.storage().get_device(&auth.user_id, &did).await {
@@ -1195,41 +1243,7 @@ async fn delete_device(
         })
         .await;
 
-    // Record device list change and notify remote servers
-    let user_id = auth.user_id.to_string();
-    let change_pos = state.storage().current_stream_position().await.unwrap_or(0);
-    let _ = state
-        .storage()
-        .set_account_data(
-            &user_id,
-            None,
-            "_maelstrom.device_change_pos",
-            &serde_json::json!({"pos": change_pos}),
-        )
-        .await;
-
-    if let Some(sender) = state.transaction_sender() {
-        let remote_servers = crate::handlers::util::servers_sharing_rooms(
-            state.storage(),
-            &user_id,
-            state.server_name().as_str(),
-        )
-        .await;
-        for server in remote_servers {
-            sender.queue_edu(
-                &server,
-                serde_json::json!({
-                    "edu_type": "m.device_list_update",
-                    "content": {
-                        "user_id": user_id,
-                        "device_id": device_id,
-                        "stream_id": change_pos,
-                        "deleted": true,
-                    }
-                }),
-            );
-        }
-    }
+    crate::device_lists::device_changed(&state, auth.user_id.as_ref(), &device_id, true).await;
 
     Ok((http::StatusCode::OK, Json(serde_json::json!({}))))
 }
@@ -0,0 +1,255 @@
+//! Dehydrated devices (MSC3814).
+//!
+//! A user who is offline on every device misses the room keys other devices
+//! send them as to-device messages, so they cannot decrypt what was said in
+//! the meantime. A client can leave a **dehydrated device** behind: a device
+//! with keys but no client, whose encrypted pickle (`device_data`) is kept on
+//! the server. Other devices encrypt to it like to any other device; the next
+//! client to log in rehydrates it from the pickle and collects its to-device
+//! messages with the `events` endpoint.
+//!
+//! A user has at most one dehydrated device. Uploading a new one deletes the
+//! previous one, along with its keys and undelivered messages.
+//!
+//! The dehydrated device is listed wherever other users and servers look for
+//! devices to encrypt to (`/keys/query`, federation `/user/devices`, `*`
+//! to-device targets), but not among the user's own sessions in `/devices`,
+//! where it cannot be renamed or deleted either.
+//!
+//! # Endpoints
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `PUT`  | `/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device` | Upload a dehydrated device and its keys |
+//! | `GET`  | `/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device` | Get the dehydrated device's pickle |
+//! | `DELETE` | `/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device` | Delete the dehydrated device |
+//! | `POST` | `/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events` | Fetch the dehydrated device's to-device messages |
+//!
+//! # Matrix spec
+//!
+//! * [MSC3814: Dehydrated devices with SSSS](https://github.com/matrix-org/matrix-spec-proposals/pull/3814)
+
+use axum::extract::{Path, State};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::id::{DeviceId, UserId};
+use maelstrom_storage::traits::{DehydratedDevice, DeviceRecord, StorageError};
+
+use crate::extractors::{AuthenticatedUser, MatrixJson, storage_error};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device",
+            get(get_dehydrated_device)
+                .put(put_dehydrated_device)
+                .delete(delete_dehydrated_device),
+        )
+        .route(
+            "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events",
+            post(get_dehydrated_device_events),
+        )
+}
+
+/// The ID of the user's dehydrated device, if they have one.
+pub(crate) async fn dehydrated_device_id(state: &AppState, user_id: &UserId) -> Option<String> {
+    state
+        .storage()
+        .get_dehydrated_device(user_id)
+        .await
+        .ok()
+        .map(|device| device.device_id)
+}
+
+/// The user's dehydrated device, or `M_NOT_FOUND`.
+async fn current_dehydrated_device(
+    state: &AppState,
+    user_id: &UserId,
+) -> Result<DehydratedDevice, MatrixError> {
+    state
+        .storage()
+        .get_dehydrated_device(user_id)
+        .await
+        .map_err(|e| match e {
+            StorageError::NotFound => MatrixError::not_found("No dehydrated device"),
+            other => storage_error(other),
+        })
+}
+
+/// Remove a dehydrated device with its keys and messages, and tell everyone
+/// tracking the user's devices.
+async fn remove_dehydrated_device(
+    state: &AppState,
+    user_id: &UserId,
+    device_id: &str,
+) -> Result<(), MatrixError> {
+    let storage = state.storage();
+    storage
+        .remove_device(user_id, &DeviceId::new(device_id))
+        .await
+        .map_err(storage_error)?;
+    storage
+        .delete_to_device_messages(user_id.as_ref(), device_id, i64::MAX)
+        .await
+        .map_err(storage_error)?;
+    crate::device_lists::device_changed(state, user_id.as_ref(), device_id, true).await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PutDehydratedDeviceRequest {
+    device_id: String,
+    device_data: serde_json::Value,
+    initial_device_display_name: Option<String>,
+    /// `device_keys`, `one_time_keys` and `fallback_keys`, as for `/keys/upload`.
+    #[serde(flatten)]
+    keys: serde_json::Value,
+}
+
+/// PUT /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+///
+/// Upload a dehydrated device, replacing the previous one, together with its
+/// keys.
+async fn put_dehydrated_device(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    MatrixJson(body): MatrixJson<PutDehydratedDeviceRequest>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    if !body.device_data.is_object() {
+        return Err(MatrixError::bad_json("device_data must be an object"));
+    }
+    if body.device_id.is_empty() {
+        return Err(MatrixError::bad_json("device_id must not be empty"));
+    }
+
+    let previous = dehydrated_device_id(&state, &auth.user_id).await;
+    if previous.as_deref() != Some(body.device_id.as_str())
+        && storage
+            .get_device(&auth.user_id, &DeviceId::new(&body.device_id))
+            .await
+            .is_ok()
+    {
+        return Err(MatrixError::bad_json("device_id is already in use"));
+    }
+    if let Some(previous) = previous {
+        remove_dehydrated_device(&state, &auth.user_id, &previous).await?;
+    }
+
+    // The device gets an access token nobody is told, so it cannot be used
+    // to log in
+    storage
+        .create_device(&DeviceRecord {
+            device_id: body.device_id.clone(),
+            user_id: auth.user_id.to_string(),
+            display_name: body.initial_device_display_name,
+            access_token: crate::handlers::util::generate_access_token(),
+            created_at: chrono::Utc::now(),
+        })
+        .await
+        .map_err(storage_error)?;
+    storage
+        .set_dehydrated_device(
+            &auth.user_id,
+            &DehydratedDevice {
+                device_id: body.device_id.clone(),
+                device_data: body.device_data,
+            },
+        )
+        .await
+        .map_err(storage_error)?;
+
+    // Uploading device keys announces the new device; without them it is
+    // announced here
+    crate::handlers::keys::upload_keys(&state, auth.user_id.as_ref(), &body.device_id, &body.keys)
+        .await?;
+    if body.keys.get("device_keys").is_none() {
+        crate::device_lists::device_changed(&state, auth.user_id.as_ref(), &body.device_id, false)
+            .await;
+    }
+
+    Ok(Json(serde_json::json!({ "device_id": body.device_id })))
+}
+
+/// GET /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+///
+/// Get the dehydrated device's ID and pickle, to rehydrate it.
+async fn get_dehydrated_device(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let device = current_dehydrated_device(&state, &auth.user_id).await?;
+
+    Ok(Json(serde_json::json!({
+        "device_id": device.device_id,
+        "device_data": device.device_data,
+    })))
+}
+
+/// DELETE /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device
+///
+/// Delete the dehydrated device, its keys and its undelivered messages.
+async fn delete_dehydrated_device(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let device = current_dehydrated_device(&state, &auth.user_id).await?;
+    remove_dehydrated_device(&state, &auth.user_id, &device.device_id).await?;
+
+    Ok(Json(serde_json::json!({ "device_id": device.device_id })))
+}
+
+#[derive(Deserialize)]
+struct DehydratedDeviceEventsRequest {
+    next_batch: Option<String>,
+}
+
+/// POST /_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device/{deviceId}/events
+///
+/// Fetch the to-device messages the dehydrated device received. Passing the
+/// returned `next_batch` back acknowledges, and deletes, the messages before
+/// it.
+async fn get_dehydrated_device_events(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(device_id): Path<String>,
+    MatrixJson(body): MatrixJson<DehydratedDeviceEventsRequest>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let device = current_dehydrated_device(&state, &auth.user_id).await?;
+    if device.device_id != device_id {
+        return Err(MatrixError::forbidden("Not the dehydrated device"));
+    }
+
+    let since = match body.next_batch.as_deref() {
+        Some(token) => token
+            .parse::<i64>()
+            .map_err(|_| MatrixError::bad_json("Invalid next_batch"))?,
+        None => 0,
+    };
+    if since > 0 {
+        storage
+            .delete_to_device_messages(auth.user_id.as_ref(), &device_id, since)
+            .await
+            .map_err(storage_error)?;
+    }
+
+    // Anything stored after this position is left for the next batch
+    let next_batch = storage
+        .current_stream_position()
+        .await
+        .map_err(storage_error)?;
+    let events = storage
+        .get_to_device_messages(auth.user_id.as_ref(), &device_id, since, next_batch)
+        .await
+        .map_err(storage_error)?;
+
+    Ok(Json(serde_json::json!({
+        "events": events,
+        "next_batch": next_batch.to_string(),
+    })))
+}
@@ -12,6 +12,19 @@
 //! * **Public room list** -- the browseable/searchable directory of rooms whose
 //!   visibility is set to `public`.
 //!
+//! The public room list can also be read from another server by passing
+//! `server`: the request is proxied to that server's
+//! `/_matrix/federation/v1/publicRooms` and the response cached for a few
+//! minutes, so paging through a large directory does not hit the remote server
+//! for every repeated page. Bridged networks are selected with
+//! `third_party_instance_id` (`{appserviceId}|{networkId}`, as listed by
+//! `/thirdparty/protocols`), or included alongside Matrix rooms with
+//! `include_all_networks`. Appservices publish rooms to their networks with
+//! `PUT /directory/list/appservice/{networkId}/{roomId}`, for networks named
+//! in their registration's `protocols` and rooms in their namespaces (created
+//! by one of their users, or with an alias they own); these entries are kept
+//! apart from the room's own Matrix directory visibility.
+//!
 //! # Endpoints
 //!
 //! | Method | Path | Description |
@@ -21,6 +34,7 @@
 //! | `DELETE` | `/_matrix/client/v3/directory/room/{roomAlias}` | Delete a room alias |
 //! | `GET`    | `/_matrix/client/v3/rooms/{roomId}/aliases` | List all aliases for a room |
 //! | `PUT`    | `/_matrix/client/v3/directory/list/room/{roomId}` | Set the visibility of a room in the directory |
+//! | `PUT`    | `/_matrix/client/v3/directory/list/appservice/{networkId}/{roomId}` | Publish a bridged room under an appservice network (AS token only) |
 //! | `GET`    | `/_matrix/client/v3/publicRooms` | Get the public room list (simple) |
 //! | `POST`   | `/_matrix/client/v3/publicRooms` | Search/filter the public room list |
 //!
@@ -28,21 +42,37 @@
 //!
 //! * [Room aliases](https://spec.matrix.org/v1.18/client-server-api/#room-aliases)
 //! * [Listing rooms](https://spec.matrix.org/v1.18/client-server-api/#listing-rooms)
+//! * [Server-Server public room directory](https://spec.matrix.org/v1.18/server-server-api/#public-room-directory)
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
 use axum::extract::{Path, State};
 use axum::routing::{get, put};
 use axum::{Json, Router};
+use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
 use maelstrom_core::matrix::id::server_name_from_sigil_id;
 use maelstrom_core::matrix::room::{Membership, event_type as et};
+use maelstrom_federation::client::FederationError;
+use maelstrom_federation::queries::directory_network;
 use maelstrom_storage::traits::StorageError;
 
-use crate::extractors::{AuthenticatedUser, MatrixJson};
+use crate::appservice::{is_appservice_user, matches_namespace};
+use crate::extractors::{AuthenticatedAppService, AuthenticatedUser, MatrixJson};
+use crate::handlers::appservice::{is_known_network_instance, network_instance_id};
 use crate::handlers::util::require_membership;
 use crate::state::AppState;
 
+/// How long a remote directory page is reused before asking the server again.
+const REMOTE_DIRECTORY_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Remote `publicRooms` responses keyed by `(server, request JSON)`.
+static REMOTE_DIRECTORY_CACHE: LazyLock<DashMap<(String, String), (Instant, serde_json::Value)>> =
+    LazyLock::new(DashMap::new);
+
 pub fn routes() -> Router<AppState> {
     Router::new()
         .route(
@@ -59,6 +89,10 @@ pub fn routes() -> Router<AppState> {
             "/_matrix/client/v3/directory/list/room/{roomId}",
             put(set_room_visibility),
         )
+        .route(
+            "/_matrix/client/v3/directory/list/appservice/{networkId}/{roomId}",
+            put(set_appservice_room_visibility),
+        )
         .route(
             "/_matrix/client/v3/publicRooms",
             get(get_public_rooms).post(search_public_rooms),
@@ -87,6 +121,9 @@ async fn set_room_alias(
         other => crate::extractors::storage_error(other),
     })?;
 
+    crate::appservice::check_exclusive_alias(storage, &room_alias, auth.appservice_id.as_deref())
+        .await?;
+
     storage
         .set_room_alias(&room_alias, &body.room_id, &sender)
         .await
@@ -333,12 +370,110 @@ async fn set_room_visibility(
     Ok(Json(serde_json::json!({})))
 }
 
-// -- GET /_matrix/client/v3/publicRooms --
+// -- PUT /_matrix/client/v3/directory/list/appservice/{networkId}/{roomId} --
+
+async fn set_appservice_room_visibility(
+    State(state): State<AppState>,
+    auth: AuthenticatedAppService,
+    Path((network_id, room_id)): Path<(String, String)>,
+    MatrixJson(body): MatrixJson<SetVisibilityRequest>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+
+    if body.visibility != "public" && body.visibility != "private" {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "visibility must be \"public\" or \"private\"",
+        ));
+    }
+
+    let appservice = &auth.appservice;
+    if !is_known_network_instance(
+        std::slice::from_ref(appservice),
+        &network_instance_id(&appservice.id, &network_id),
+    ) {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "Network is not one of the application service's protocols",
+        ));
+    }
+
+    let room = storage.get_room(&room_id).await.map_err(|e| match e {
+        StorageError::NotFound => MatrixError::not_found("Room not found"),
+        other => crate::extractors::storage_error(other),
+    })?;
+
+    // The room must be the bridge's own: created by one of its users, or
+    // reachable through an alias in its namespaces.
+    let owned = is_appservice_user(appservice, &room.creator, state.server_name().as_str())
+        || storage
+            .get_room_aliases(&room_id)
+            .await
+            .unwrap_or_default()
+            .iter()
+            .any(|alias| matches_namespace(&appservice.alias_namespaces, alias));
+    if !owned {
+        return Err(MatrixError::forbidden(
+            "Room is not in the application service's namespaces",
+        ));
+    }
+
+    storage
+        .set_appservice_room_visibility(
+            &auth.appservice.id,
+            &network_id,
+            &room_id,
+            &body.visibility,
+        )
+        .await
+        .map_err(crate::extractors::storage_error)?;
+
+    Ok(Json(serde_json::json!({})))
+}
+
+// -- GET/POST /_matrix/client/v3/publicRooms --
 
 #[derive(Deserialize, Default)]
 struct PublicRoomsQuery {
     limit: Option<usize>,
     since: Option<String>,
+    server: Option<String>,
+    #[serde(default)]
+    include_all_networks: bool,
+    third_party_instance_id: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct ServerQuery {
+    server: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct SearchPublicRoomsRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<String>,
+    /// Kept as raw JSON so fields we do not interpret locally (`room_types`)
+    /// are still forwarded to remote servers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<serde_json::Value>,
+    #[serde(default)]
+    include_all_networks: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    third_party_instance_id: Option<String>,
+}
+
+impl SearchPublicRoomsRequest {
+    fn search_term(&self) -> Option<&str> {
+        self.filter
+            .as_ref()
+            .and_then(|f| f.get("generic_search_term"))
+            .and_then(|t| t.as_str())
+            .filter(|t| !t.is_empty())
+    }
 }
 
 #[derive(Serialize)]
@@ -368,17 +503,74 @@ struct PublicRoomsResponse {
 async fn get_public_rooms(
     State(state): State<AppState>,
     axum::extract::Query(query): axum::extract::Query<PublicRoomsQuery>,
-) -> Result<Json<PublicRoomsResponse>, MatrixError> {
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let request = SearchPublicRoomsRequest {
+        limit: query.limit,
+        since: query.since,
+        filter: None,
+        include_all_networks: query.include_all_networks,
+        third_party_instance_id: query.third_party_instance_id,
+    };
+    public_rooms(&state, query.server.as_deref(), request).await
+}
+
+async fn search_public_rooms(
+    State(state): State<AppState>,
+    _auth: AuthenticatedUser,
+    axum::extract::Query(query): axum::extract::Query<ServerQuery>,
+    MatrixJson(body): MatrixJson<SearchPublicRoomsRequest>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    public_rooms(&state, query.server.as_deref(), body).await
+}
+
+/// Serve a page of the room directory of `server` (ours if `None`).
+async fn public_rooms(
+    state: &AppState,
+    server: Option<&str>,
+    request: SearchPublicRoomsRequest,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    if request.include_all_networks && request.third_party_instance_id.is_some() {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "Cannot combine include_all_networks with third_party_instance_id",
+        ));
+    }
+
+    match server {
+        Some(server) if server != state.server_name().as_str() => {
+            remote_public_rooms(state, server, &request).await.map(Json)
+        }
+        _ => local_public_rooms(state, &request).await,
+    }
+}
+
+async fn local_public_rooms(
+    state: &AppState,
+    request: &SearchPublicRoomsRequest,
+) -> Result<Json<serde_json::Value>, MatrixError> {
     let storage = state.storage();
-    let limit = query.limit.unwrap_or(20).min(100);
+    let limit = request.limit.unwrap_or(20).min(100);
+
+    let network = directory_network(
+        storage,
+        request.include_all_networks,
+        request.third_party_instance_id.as_deref(),
+    )
+    .await?;
 
     let (rooms, total) = storage
-        .get_public_rooms(limit, query.since.as_deref(), None)
+        .get_public_rooms(
+            limit,
+            request.since.as_deref(),
+            request.search_term(),
+            network,
+        )
         .await
         .map_err(crate::extractors::storage_error)?;
 
     let next_batch = if rooms.len() >= limit {
-        let start = query
+        let start = request
             .since
             .as_deref()
             .and_then(|s| s.parse::<usize>().ok())
@@ -402,72 +594,89 @@ async fn get_public_rooms(
         })
         .collect();
 
-    Ok(Json(PublicRoomsResponse {
+    serde_json::to_value(PublicRoomsResponse {
         chunk,
         total_room_count_estimate: total,
         next_batch,
-    }))
-}
-
-// -- POST /_matrix/client/v3/publicRooms --
-
-#[derive(Deserialize, Default)]
-struct SearchPublicRoomsRequest {
-    limit: Option<usize>,
-    since: Option<String>,
-    filter: Option<PublicRoomsFilter>,
+    })
+    .map(Json)
+    .map_err(|e| MatrixError::unknown(e.to_string()))
 }
 
-#[derive(Deserialize, Default)]
-struct PublicRoomsFilter {
-    generic_search_term: Option<String>,
-}
+/// Fetch a directory page from a remote server, reusing a recent response for
+/// the same request.
+///
+/// Plain listings use `GET /_matrix/federation/v1/publicRooms`; searches need
+/// the `POST` variant, which carries the filter.
+async fn remote_public_rooms(
+    state: &AppState,
+    server: &str,
+    request: &SearchPublicRoomsRequest,
+) -> Result<serde_json::Value, MatrixError> {
+    let body = serde_json::to_value(request).map_err(|e| MatrixError::unknown(e.to_string()))?;
+    let key = (server.to_string(), body.to_string());
+    if let Some(entry) = REMOTE_DIRECTORY_CACHE.get(&key) {
+        let (fetched_at, response) = entry.value();
+        if fetched_at.elapsed() < REMOTE_DIRECTORY_CACHE_TTL {
+            return Ok(response.clone());
+        }
+    }
 
-async fn search_public_rooms(
-    State(state): State<AppState>,
-    _auth: AuthenticatedUser,
-    MatrixJson(body): MatrixJson<SearchPublicRoomsRequest>,
-) -> Result<Json<PublicRoomsResponse>, MatrixError> {
-    let storage = state.storage();
-    let limit = body.limit.unwrap_or(20).min(100);
-    let filter_term = body
-        .filter
-        .as_ref()
-        .and_then(|f| f.generic_search_term.as_deref());
+    let fed = state
+        .federation()
+        .ok_or_else(|| MatrixError::not_found("Federation is not enabled"))?;
 
-    let (rooms, total) = storage
-        .get_public_rooms(limit, body.since.as_deref(), filter_term)
-        .await
-        .map_err(crate::extractors::storage_error)?;
-
-    let next_batch = if rooms.len() >= limit {
-        let start = body
-            .since
-            .as_deref()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0);
-        Some((start + rooms.len()).to_string())
+    let result = if request.filter.is_some() {
+        fed.post_json(server, "/_matrix/federation/v1/publicRooms", &body)
+            .await
     } else {
-        None
+        let mut params = vec![format!(
+            "include_all_networks={}",
+            request.include_all_networks
+        )];
+        if let Some(limit) = request.limit {
+            params.push(format!("limit={limit}"));
+        }
+        if let Some(since) = &request.since {
+            params.push(format!(
+                "since={}",
+                crate::handlers::util::percent_encode(since)
+            ));
+        }
+        if let Some(instance_id) = &request.third_party_instance_id {
+            params.push(format!(
+                "third_party_instance_id={}",
+                crate::handlers::util::percent_encode(instance_id)
+            ));
+        }
+        let path = format!("/_matrix/federation/v1/publicRooms?{}", params.join("&"));
+        fed.get(server, &path).await
     };
 
-    let chunk: Vec<PublicRoomEntry> = rooms
-        .into_iter()
-        .map(|r| PublicRoomEntry {
-            room_id: r.room_id,
-            name: r.name,
-            topic: r.topic,
-            canonical_alias: r.canonical_alias,
-            avatar_url: r.avatar_url,
-            num_joined_members: r.num_joined_members,
-            world_readable: r.world_readable,
-            guest_can_join: r.guest_can_join,
-        })
-        .collect();
+    let response = result.map_err(|e| match e {
+        FederationError::Blocked(_) => {
+            MatrixError::forbidden(format!("Federation with {server} is not allowed"))
+        }
+        other => {
+            tracing::warn!(server = %server, error = %other, "Remote publicRooms query failed");
+            MatrixError::new(
+                http::StatusCode::BAD_GATEWAY,
+                ErrorCode::Unknown,
+                format!("Failed to fetch the room directory of {server}"),
+            )
+        }
+    })?;
 
-    Ok(Json(PublicRoomsResponse {
-        chunk,
-        total_room_count_estimate: total,
-        next_batch,
-    }))
+    if response.get("chunk").is_none_or(|c| !c.is_array()) {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_GATEWAY,
+            ErrorCode::Unknown,
+            format!("Invalid room directory response from {server}"),
+        ));
+    }
+
+    REMOTE_DIRECTORY_CACHE
+        .retain(|_, (fetched_at, _)| fetched_at.elapsed() < REMOTE_DIRECTORY_CACHE_TTL);
+    REMOTE_DIRECTORY_CACHE.insert(key, (Instant::now(), response.clone()));
+    Ok(response)
 }
@@ -8,6 +8,7 @@
 //! | `PUT`  | `/rooms/{roomId}/send/{eventType}/{txnId}` | Send a message event |
 //! | `GET`  | `/rooms/{roomId}/event/{eventId}` | Fetch a single event |
 //! | `GET`  | `/rooms/{roomId}/messages` | Paginate room timeline |
+//! | `GET`  | `/rooms/{roomId}/context/{eventId}` | Events around an event |
 //! | `PUT`  | `/rooms/{roomId}/state/{eventType}/{stateKey}` | Set state event |
 //! | `GET`  | `/rooms/{roomId}/state/{eventType}/{stateKey}` | Get state event |
 //! | `GET`  | `/rooms/{roomId}/state` | Get all current state |
@@ -36,17 +37,34 @@
 //!
 //! # Retrieving events
 //!
-//! **Single event** (`GET /event`) returns one event by ID. Access is governed
-//! by the room's `m.room.history_visibility` setting:
-//! - `world_readable` -- anyone can see events
-//! - `shared` -- any current or former member
-//! - `invited` -- must have been at least invited at the event's stream position
-//! - `joined` -- must have been joined at the event's stream position
+//! Every event handed out is subject to the room's `m.room.history_visibility`
+//! as it was at that event, judged from the user's membership at the event
+//! (see [`maelstrom_federation::visibility`]):
+//! - `world_readable` -- anyone can see events, even non-members
+//! - `shared` -- members who were joined at some point after the event
+//! - `invited` -- must have been at least invited at the event
+//! - `joined` -- must have been joined at the event
+//!
+//! **Single event** (`GET /event`) returns one event by ID, or `404` if the
+//! user may not see it.
 //!
 //! **Messages** (`GET /messages`) paginates the room timeline forward (`dir=f`)
-//! or backward (`dir=b`) from a stream-position token. Supports `lazy_load_members`
-//! and `related_by_rel_types` (MSC3874) filters. For departed users, events are
-//! capped at the stream position of their leave event.
+//! or backward (`dir=b`) from a stream-position token, leaving out the events
+//! the user may not see. Its room event filter (see
+//! [`maelstrom_core::matrix::filter`]) is applied while paging, so a chunk
+//! holds up to `limit` matching events; MSC3874's `related_by_rel_types` and
+//! `rel_types` are supported too. Rooms that are `world_readable` can be
+//! paginated without joining them.
+//!
+//! **Context** (`GET /context`) returns an event with up to `limit` events
+//! around it, each subject to the same visibility rules as a single event and
+//! to the room event filter, plus the room state (only the senders' members
+//! with `lazy_load_members`).
+//!
+//! With `lazy_load_members`, member events the device was already sent (by
+//! `/sync`, `/messages` or `/context`) are left out unless the filter sets
+//! `include_redundant_members`.
+//! Its `start` and `end` tokens continue with `/messages`.
 //!
 //! **Full state** (`GET /state`) returns all current state events. For departed
 //! users, state is frozen at the point they left.
@@ -71,9 +89,11 @@ use serde::{Deserialize, Serialize};
 
 use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::event::{Pdu, generate_event_id, timestamp_ms};
+use maelstrom_core::matrix::filter::RoomEventFilter;
 use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_core::matrix::room::Membership;
 use maelstrom_core::matrix::room::event_type as et;
-use maelstrom_core::matrix::room::{HistoryVisibility, Membership};
+use maelstrom_federation::visibility;
 use maelstrom_storage::traits::StorageError;
 use tracing::warn;
 
@@ -105,6 +125,10 @@ pub fn routes() -> Router<AppState> {
                 &format!("{prefix}/rooms/{{roomId}}/messages"),
                 get(get_messages),
             )
+            .route(
+                &format!("{prefix}/rooms/{{roomId}}/context/{{eventId}}"),
+                get(get_context),
+            )
             .route(
                 &format!("{prefix}/rooms/{{roomId}}/state/{{eventType}}/{{stateKey}}"),
                 put(set_state_event).get(get_state_event),
@@ -242,19 +266,6 @@ async fn get_event(
     let storage = state.storage();
     let sender = auth.user_id.to_string();
 
-    // Check history_visibility for this room
-    let history_visibility = storage
-        .get_state_event(&room_id, et::HISTORY_VISIBILITY, "")
-        .await
-        .ok()
-        .and_then(|ev| {
-            ev.content
-                .get("history_visibility")
-                .and_then(|v| v.as_str())
-                .map(|s| s.to_string())
-        })
-        .unwrap_or_else(|| HistoryVisibility::Shared.as_str().to_string());
-
     // Fetch the event first
     let event = storage.get_event(&event_id).await.map_err(|e| match e {
         StorageError::NotFound => MatrixError::not_found("Event not found"),
@@ -265,73 +276,13 @@ async fn get_event(
         return Err(MatrixError::not_found("Event not found"));
     }
 
-    // Check user membership
-    let membership = storage.get_membership(&sender, &room_id).await;
-    let is_joined = membership
-        .as_deref()
-        .map(|m| m == Membership::Join.as_str())
-        .unwrap_or(false);
-
-    // world_readable: anyone can see events
-    if history_visibility == HistoryVisibility::WorldReadable.as_str() {
-        let m = membership.as_deref().unwrap_or(Membership::Leave.as_str());
-        return Ok(Json(event.to_client_event().with_membership(m).into_json()));
-    }
-
-    // Not world_readable — must be a current or former member
-    if !is_joined && membership.is_err() {
-        // Never been a member — return 404 to not reveal room existence
+    if !visibility::event_visible_to_user(storage, &sender, &event).await {
+        // 404 rather than 403, to not reveal the room's existence
         return Err(MatrixError::not_found("Event not found"));
     }
 
-    // For "joined" visibility, the user must have been joined when the event was sent
-    if history_visibility == HistoryVisibility::Joined.as_str() {
-        // Get the user's join event to see when they joined
-        let join_event = storage
-            .get_state_event(&room_id, et::MEMBER, &sender)
-            .await
-            .ok();
-
-        let user_joined_at = join_event
-            .as_ref()
-            .filter(|e| {
-                e.content.get("membership").and_then(|m| m.as_str())
-                    == Some(Membership::Join.as_str())
-            })
-            .map(|e| e.stream_position)
-            .unwrap_or(i64::MAX);
+    let membership = storage.get_membership(&sender, &room_id).await.ok();
 
-        if event.stream_position < user_joined_at {
-            return Err(MatrixError::not_found("Event not found"));
-        }
-    }
-
-    // For "invited" visibility, user must have been at least invited at the time of the event.
-    if history_visibility == HistoryVisibility::Invited.as_str() {
-        // Check if the user had an invite or join membership at the event's stream position
-        let membership_at_event = storage
-            .get_state_event_at(&room_id, et::MEMBER, &sender, event.stream_position)
-            .await
-            .ok()
-            .and_then(|e| {
-                e.content
-                    .get("membership")
-                    .and_then(|m| m.as_str())
-                    .map(|s| s.to_string())
-            });
-
-        match membership_at_event.as_deref() {
-            Some(m) if m == Membership::Join.as_str() || m == Membership::Invite.as_str() => {
-                // User was invited or joined at the time — allow access
-            }
-            _ => {
-                // User wasn't yet invited at the time of this event
-                return Err(MatrixError::not_found("Event not found"));
-            }
-        }
-    }
-
-    // "shared" visibility: any current or former member can see
     let m = membership.as_deref().unwrap_or(Membership::Leave.as_str());
     Ok(Json(event.to_client_event().with_membership(m).into_json()))
 }
@@ -343,8 +294,9 @@ async fn get_event(
 /// - `from` / `to`: stream-position pagination tokens
 /// - `dir`: `"b"` for backward (newest-first, default) or `"f"` for forward
 /// - `limit`: max events to return (capped at 100)
-/// - `filter`: optional JSON filter supporting `lazy_load_members` and
-///   `related_by_rel_types`
+/// - `filter`: optional JSON room event filter (see
+///   [`maelstrom_core::matrix::filter`]), including MSC3874's
+///   `related_by_rel_types` and `rel_types`
 #[derive(Deserialize)]
 #[allow(dead_code)]
 struct MessagesQuery {
@@ -379,8 +331,10 @@ async fn get_messages(
     let storage = state.storage();
     let sender = auth.user_id.to_string();
 
-    // Check user is a member (or was a member)
-    let membership = require_membership(storage, &sender, &room_id).await?;
+    // Members, former members and, in world_readable rooms, anyone
+    if !visibility::user_may_read_room(storage, &sender, &room_id).await {
+        return Err(MatrixError::forbidden("You are not in this room"));
+    }
 
     // Forgotten rooms must not be accessible (spec: CS API § 8.4)
     if storage
@@ -405,17 +359,7 @@ async fn get_messages(
         }
     };
 
-    // For departed users: limit messages to events up to when they left/were banned
-    let leave_pos =
-        if membership == Membership::Leave.as_str() || membership == Membership::Ban.as_str() {
-            storage
-                .get_state_event(&room_id, et::MEMBER, &sender)
-                .await
-                .ok()
-                .map(|e| e.stream_position)
-        } else {
-            None
-        };
+    let filter = parse_room_event_filter(query.filter.as_deref())?;
 
     // Fetch extra events to account for state events that will be filtered out
     // (we exclude non-member state events from the messages response).
@@ -491,163 +435,75 @@ async fn get_messages(
             }
         }
     }
-    // Filter out events after the user left
-    let events: Vec<_> = if let Some(lp) = leave_pos {
-        events
-            .into_iter()
-            .filter(|e| e.stream_position <= lp)
-            .collect()
-    } else {
-        events
-    };
-
     let start = query.from.unwrap_or_else(|| from.to_string());
 
-    // Parse filter JSON
-    let filter_json: Option<serde_json::Value> = query
-        .filter
-        .as_deref()
-        .and_then(|f| serde_json::from_str(f).ok());
-
-    let lazy_load = filter_json
-        .as_ref()
-        .and_then(|f| f.get("lazy_load_members")?.as_bool())
-        .unwrap_or(false);
-
-    // Check for related_by_rel_types filter — events with child relations of the given type
-    let related_by_rel_types: Option<Vec<String>> = filter_json
-        .as_ref()
-        .and_then(|f| f.get("related_by_rel_types"))
-        .and_then(|v| v.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
-        });
-
-    // MSC3874: org.matrix.msc3874.rel_types — only include events that THEMSELVES
-    // have a m.relates_to with one of the given relation types
-    let own_rel_type_filter: Option<Vec<String>> = filter_json
-        .as_ref()
-        .and_then(|f| {
-            f.get("org.matrix.msc3874.rel_types")
-                .or_else(|| f.get("rel_types"))
-        })
-        .and_then(|v| v.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
-        });
-
-    // MSC3874: org.matrix.msc3874.not_rel_types — exclude events that have
-    // a m.relates_to with one of the given relation types
-    let own_not_rel_type_filter: Option<Vec<String>> = filter_json
-        .as_ref()
-        .and_then(|f| {
-            f.get("org.matrix.msc3874.not_rel_types")
-                .or_else(|| f.get("not_rel_types"))
-        })
-        .and_then(|v| v.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
-        });
-
-    // If related_by_rel_types filter is set, build a set of event IDs that have matching child relations
-    let related_event_ids: Option<std::collections::HashSet<String>> =
-        if let Some(ref rel_types) = related_by_rel_types {
-            let mut ids = std::collections::HashSet::new();
-            for event in &events {
-                for rel_type in rel_types {
-                    if let Ok(relations) = storage
-                        .get_relations(&event.event_id, Some(rel_type), None, 1, None)
-                        .await
-                        && !relations.is_empty()
-                    {
-                        ids.insert(event.event_id.clone());
-                    }
-                }
+    // Page through the timeline until `limit` events pass the filter. Only
+    // messages and membership changes are timeline events here, and only
+    // the ones the user may see (e.g. nothing after they left).
+    let mut chunk_events: Vec<Pdu> = Vec::new();
+    let mut scanned = 0;
+    let mut scanned_to = None;
+    let mut page = events;
+    loop {
+        let exhausted = page.len() < fetch_limit;
+        scanned += page.len();
+        if let Some(last) = page.last() {
+            scanned_to = Some(last.stream_position);
+        }
+        for event in visibility::filter_events_for_user(storage, &sender, page).await {
+            if chunk_events.len() == limit {
+                break;
             }
-            Some(ids)
-        } else {
-            None
-        };
-
-    // Include message events and membership events, but exclude other state events.
-    // Collect both the JSON and stream_position so we can compute `end` correctly.
-    let filtered_events: Vec<&Pdu> = events
-        .iter()
-        .filter(|e| !e.is_state() || e.event_type == et::MEMBER)
-        .filter(|e| {
-            // related_by_rel_types: only include events with matching child relations
-            if let Some(ref ids) = related_event_ids
-                && !ids.contains(&e.event_id)
+            if (!event.is_state() || event.event_type == et::MEMBER)
+                && filter.matches(&event)
+                && has_related_child(storage, &event, filter.related_by_rel_types.as_deref()).await
             {
-                return false;
-            }
-            // org.matrix.msc3874.rel_types: only include events with own relation type
-            if let Some(ref rel_types) = own_rel_type_filter {
-                let event_rel_type = e
-                    .content
-                    .get("m.relates_to")
-                    .and_then(|r| r.get("rel_type"))
-                    .and_then(|v| v.as_str());
-                match event_rel_type {
-                    Some(rt) => {
-                        if !rel_types.iter().any(|f| f == rt) {
-                            return false;
-                        }
-                    }
-                    None => return false, // No relation = excluded
-                }
+                chunk_events.push(event);
             }
-            // org.matrix.msc3874.not_rel_types: exclude events with own relation type
-            if let Some(ref not_rel_types) = own_not_rel_type_filter {
-                let event_rel_type = e
-                    .content
-                    .get("m.relates_to")
-                    .and_then(|r| r.get("rel_type"))
-                    .and_then(|v| v.as_str());
-                if let Some(rt) = event_rel_type
-                    && not_rel_types.iter().any(|f| f == rt)
-                {
-                    return false;
-                }
-            }
-            true
-        })
-        .take(limit)
-        .collect();
+        }
+        if chunk_events.len() == limit || exhausted || scanned >= MAX_MESSAGES_SCAN {
+            break;
+        }
+        let Some(next) = scanned_to else { break };
+        page = storage
+            .get_room_events(&room_id, next, fetch_limit, dir)
+            .await
+            .map_err(crate::extractors::storage_error)?;
+    }
 
-    let end = filtered_events
-        .last()
-        .map(|e| e.stream_position.to_string());
+    // Without a full chunk, a scan that stopped early still has more to see
+    let end = match chunk_events.last() {
+        Some(last) if chunk_events.len() == limit => Some(last.stream_position),
+        _ if scanned >= MAX_MESSAGES_SCAN => scanned_to,
+        last => last.map(|e| e.stream_position),
+    }
+    .map(|pos| pos.to_string());
 
-    let chunk: Vec<serde_json::Value> = filtered_events
+    let chunk: Vec<serde_json::Value> = chunk_events
         .iter()
-        .map(|e| {
-            e.to_client_event()
-                .with_membership(Membership::Join.as_str())
-                .into_json()
-        })
+        .map(|e| filter.render(e, Some(Membership::Join.as_str())))
         .collect();
 
     // If lazy_load_members, include m.room.member state for senders in the chunk
-    let state = if lazy_load {
+    let state = if filter.lazy_load_members {
         let mut seen_senders = std::collections::HashSet::new();
-        let mut state_events = Vec::new();
-        for event in &events {
-            if seen_senders.insert(event.sender.clone())
+        let mut member_events = Vec::new();
+        for event in &chunk_events {
+            if seen_senders.insert(event.sender.as_str())
                 && let Ok(member_event) = storage
                     .get_state_event(&room_id, et::MEMBER, &event.sender)
                     .await
             {
-                state_events.push(member_event.to_client_event().into_json());
+                member_events.push(member_event);
             }
         }
-        Some(state_events)
+        let member_events = drop_redundant_members(&state, &auth, &room_id, &filter, member_events);
+        Some(
+            member_events
+                .iter()
+                .map(|e| filter.render(e, None))
+                .collect(),
+        )
     } else {
         None
     };
@@ -660,6 +516,213 @@ async fn get_messages(
     }))
 }
 
+// -- GET /rooms/{roomId}/context/{eventId} --
+
+/// Query parameters for `GET /rooms/{roomId}/context/{eventId}`.
+///
+/// - `limit`: max events to return around the event, split between before
+///   and after (default 10, capped at 100)
+/// - `filter`: optional JSON room event filter, applied to the events around
+///   the event
+#[derive(Deserialize)]
+struct ContextQuery {
+    limit: Option<usize>,
+    filter: Option<String>,
+}
+
+/// Response for `GET /rooms/{roomId}/context/{eventId}`.
+///
+/// `events_before` is newest-first and `events_after` oldest-first. `start`
+/// and `end` are `/messages` tokens for paginating further back (`dir=b`)
+/// and forward (`dir=f`).
+#[derive(Serialize)]
+struct ContextResponse {
+    event: serde_json::Value,
+    events_before: Vec<serde_json::Value>,
+    events_after: Vec<serde_json::Value>,
+    start: String,
+    end: String,
+    state: Vec<serde_json::Value>,
+}
+
+async fn get_context(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, event_id)): Path<(String, String)>,
+    Query(query): Query<ContextQuery>,
+) -> Result<Json<ContextResponse>, MatrixError> {
+    let storage = state.storage();
+    let sender = auth.user_id.to_string();
+
+    if storage
+        .is_room_forgotten(&sender, &room_id)
+        .await
+        .unwrap_or(false)
+    {
+        return Err(MatrixError::forbidden("You have forgotten this room"));
+    }
+
+    let event = storage.get_event(&event_id).await.map_err(|e| match e {
+        StorageError::NotFound => MatrixError::not_found("Event not found"),
+        other => crate::extractors::storage_error(other),
+    })?;
+    if event.room_id != room_id {
+        return Err(MatrixError::not_found("Event not found"));
+    }
+
+    if !visibility::event_visible_to_user(storage, &sender, &event).await {
+        return Err(MatrixError::not_found("Event not found"));
+    }
+    let membership = require_membership(storage, &sender, &room_id).await.ok();
+    let m = membership
+        .clone()
+        .unwrap_or_else(|| Membership::Leave.as_str().to_string());
+
+    // Departed users see the state as it was when they left
+    let leave_pos = if m == Membership::Leave.as_str() || m == Membership::Ban.as_str() {
+        storage
+            .get_state_event(&room_id, et::MEMBER, &sender)
+            .await
+            .ok()
+            .map(|e| e.stream_position)
+    } else {
+        None
+    };
+
+    let filter = parse_room_event_filter(query.filter.as_deref())?;
+
+    let limit = query.limit.unwrap_or(10).min(100);
+    let limit_before = limit / 2;
+    let limit_after = limit - limit_before;
+
+    // As in /messages, only messages and membership changes are timeline
+    // events here
+    let mut around = Vec::with_capacity(2);
+    for (dir, side_limit) in [("b", limit_before), ("f", limit_after)] {
+        let mut side = Vec::new();
+        if side_limit > 0 {
+            let candidates: Vec<Pdu> = storage
+                .get_room_events(&room_id, event.stream_position, side_limit * 2 + 10, dir)
+                .await
+                .map_err(crate::extractors::storage_error)?
+                .into_iter()
+                .filter(|candidate| {
+                    (!candidate.is_state() || candidate.event_type == et::MEMBER)
+                        && filter.matches(candidate)
+                })
+                .collect();
+            side = visibility::filter_events_for_user(storage, &sender, candidates).await;
+            side.truncate(side_limit);
+        }
+        around.push(side);
+    }
+    let events_after = around.pop().unwrap_or_default();
+    let events_before = around.pop().unwrap_or_default();
+
+    let start = events_before
+        .last()
+        .map_or(event.stream_position, |e| e.stream_position);
+    let end = events_after
+        .last()
+        .map_or(event.stream_position, |e| e.stream_position);
+
+    // The room state, frozen for departed users at the point they left. With
+    // lazy-loading, only the members who sent the returned events.
+    let mut room_state = storage
+        .get_current_state(&room_id)
+        .await
+        .map_err(crate::extractors::storage_error)?;
+    if let Some(lp) = leave_pos {
+        room_state.retain(|e| e.stream_position <= lp);
+    }
+    if filter.lazy_load_members {
+        let senders: std::collections::HashSet<&str> = std::iter::once(&event)
+            .chain(&events_before)
+            .chain(&events_after)
+            .map(|e| e.sender.as_str())
+            .collect();
+        room_state.retain(|e| {
+            e.event_type == et::MEMBER
+                && e.state_key
+                    .as_deref()
+                    .is_some_and(|key| senders.contains(key))
+        });
+        room_state = drop_redundant_members(&state, &auth, &room_id, &filter, room_state);
+    }
+
+    let to_json = |e: &Pdu| filter.render(e, Some(&m));
+    Ok(Json(ContextResponse {
+        event: to_json(&event),
+        events_before: events_before.iter().map(to_json).collect(),
+        events_after: events_after.iter().map(to_json).collect(),
+        start: start.to_string(),
+        end: end.to_string(),
+        state: room_state.iter().map(|e| filter.render(e, None)).collect(),
+    }))
+}
+
+/// How many events `/messages` looks through at most for ones that pass
+/// the filter.
+const MAX_MESSAGES_SCAN: usize = 1000;
+
+/// Parse the `filter` query parameter of `/messages` and `/context`.
+fn parse_room_event_filter(filter: Option<&str>) -> Result<RoomEventFilter, MatrixError> {
+    let Some(filter) = filter else {
+        return Ok(RoomEventFilter::default());
+    };
+    serde_json::from_str::<serde_json::Value>(filter)
+        .ok()
+        .and_then(|json| RoomEventFilter::from_json(&json).ok())
+        .ok_or_else(|| MatrixError::bad_json("Invalid filter JSON"))
+}
+
+/// Whether the event has a child relation of one of `rel_types`, the
+/// `related_by_rel_types` filter field. Always true without one.
+async fn has_related_child(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    event: &Pdu,
+    rel_types: Option<&[String]>,
+) -> bool {
+    let Some(rel_types) = rel_types else {
+        return true;
+    };
+    for rel_type in rel_types {
+        if storage
+            .get_relations(&event.event_id, Some(rel_type), None, 1, None)
+            .await
+            .is_ok_and(|relations| !relations.is_empty())
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// Leave out the lazy-loaded member events the requesting device already
+/// has, unless the filter sets `include_redundant_members`.
+fn drop_redundant_members(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    room_id: &str,
+    filter: &RoomEventFilter,
+    member_events: Vec<Pdu>,
+) -> Vec<Pdu> {
+    let sent_members = state
+        .sync_state()
+        .sent_members(auth.user_id.as_ref(), auth.device_id.as_ref());
+    let unsent = sent_members.mark_sent(
+        room_id,
+        member_events.iter().filter_map(|e| e.state_key.as_deref()),
+    );
+    member_events
+        .into_iter()
+        .filter(|e| {
+            filter.include_redundant_members
+                || e.state_key.as_ref().is_some_and(|key| unsent.contains(key))
+        })
+        .collect()
+}
+
 // -- PUT /rooms/{roomId}/state/{eventType}/{stateKey} --
 
 async fn set_state_event(
@@ -0,0 +1,589 @@
+//! Server-side key backup.
+//!
+//! Clients back up the Megolm session keys they receive, encrypted with a
+//! backup key the server never sees, so that a new device can decrypt the
+//! room history after restoring the backup key.
+//!
+//! A backup has numbered **versions**. Creating a version makes it the
+//! current one; keys can only be uploaded to the current version, and an
+//! upload to any other is refused with `M_WRONG_ROOM_KEYS_VERSION` (carrying
+//! `current_version`) so a client still using an old backup notices that
+//! another device replaced it. Each version reports the number of keys it
+//! holds (`count`) and an `etag` that changes whenever they change.
+//!
+//! When an uploaded key is for a session the backup already holds, the
+//! spec's replacement rules decide which one to keep (see [`is_better_key`]).
+//!
+//! # Endpoints
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `POST` | `/_matrix/client/v3/room_keys/version` | Create a new backup version |
+//! | `GET`  | `/_matrix/client/v3/room_keys/version` | Get the current backup version |
+//! | `GET`  | `/_matrix/client/v3/room_keys/version/{version}` | Get a backup version |
+//! | `PUT`  | `/_matrix/client/v3/room_keys/version/{version}` | Update a backup version's `auth_data` |
+//! | `DELETE` | `/_matrix/client/v3/room_keys/version/{version}` | Delete a backup version and its keys |
+//! | `PUT` / `GET` / `DELETE` | `/_matrix/client/v3/room_keys/keys` | Store, retrieve or delete the keys of all rooms |
+//! | `PUT` / `GET` / `DELETE` | `/_matrix/client/v3/room_keys/keys/{roomId}` | Store, retrieve or delete the keys of a room |
+//! | `PUT` / `GET` / `DELETE` | `/_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}` | Store, retrieve or delete a session's key |
+//!
+//! The `keys` endpoints take the backup version in the `version` query
+//! parameter.
+//!
+//! # Matrix spec
+//!
+//! * [Server-side key backups](https://spec.matrix.org/v1.18/client-server-api/#server-side-key-backups)
+
+use std::collections::BTreeMap;
+
+use axum::extract::{Path, Query, State};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_storage::traits::{BackedUpRoomKey, KeyBackupVersion, StorageError};
+
+use crate::extractors::{AuthenticatedUser, storage_error};
+use crate::state::AppState;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/_matrix/client/v3/room_keys/version",
+            get(get_current_version).post(create_version),
+        )
+        .route(
+            "/_matrix/client/v3/room_keys/version/{version}",
+            get(get_version).put(update_version).delete(delete_version),
+        )
+        .route(
+            "/_matrix/client/v3/room_keys/keys",
+            put(put_all_keys).get(get_all_keys).delete(delete_all_keys),
+        )
+        .route(
+            "/_matrix/client/v3/room_keys/keys/{roomId}",
+            put(put_room_keys)
+                .get(get_room_keys)
+                .delete(delete_room_keys),
+        )
+        .route(
+            "/_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}",
+            put(put_session_key)
+                .get(get_session_key)
+                .delete(delete_session_key),
+        )
+}
+
+/// An error from the key backup endpoints.
+///
+/// `M_WRONG_ROOM_KEYS_VERSION` carries a `current_version` field that a
+/// plain [`MatrixError`] has no room for.
+enum BackupError {
+    Matrix(MatrixError),
+    WrongVersion { current_version: String },
+}
+
+impl From<MatrixError> for BackupError {
+    fn from(e: MatrixError) -> Self {
+        Self::Matrix(e)
+    }
+}
+
+impl IntoResponse for BackupError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Matrix(e) => e.into_response(),
+            Self::WrongVersion { current_version } => (
+                http::StatusCode::FORBIDDEN,
+                Json(serde_json::json!({
+                    "errcode": ErrorCode::WrongRoomKeysVersion,
+                    "error": "Wrong backup version",
+                    "current_version": current_version,
+                })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// The `version` query parameter of the `keys` endpoints.
+#[derive(Deserialize)]
+struct VersionQuery {
+    version: Option<String>,
+}
+
+impl VersionQuery {
+    fn version(self) -> Result<String, MatrixError> {
+        self.version
+            .ok_or_else(|| MatrixError::missing_param("Missing version parameter"))
+    }
+}
+
+/// `KeyBackupData`: one session's key as clients upload and download it.
+#[derive(Serialize, Deserialize)]
+struct KeyBackupData {
+    first_message_index: i64,
+    forwarded_count: i64,
+    is_verified: bool,
+    session_data: serde_json::Map<String, serde_json::Value>,
+}
+
+impl From<BackedUpRoomKey> for KeyBackupData {
+    fn from(key: BackedUpRoomKey) -> Self {
+        Self {
+            first_message_index: key.first_message_index,
+            forwarded_count: key.forwarded_count,
+            is_verified: key.is_verified,
+            session_data: match key.session_data {
+                serde_json::Value::Object(data) => data,
+                _ => serde_json::Map::new(),
+            },
+        }
+    }
+}
+
+/// `RoomKeyBackup`: the keys of one room, by session ID.
+#[derive(Serialize, Deserialize, Default)]
+struct RoomKeyBackup {
+    sessions: BTreeMap<String, KeyBackupData>,
+}
+
+/// The keys of every room, by room ID.
+#[derive(Serialize, Deserialize, Default)]
+struct KeysBackup {
+    rooms: BTreeMap<String, RoomKeyBackup>,
+}
+
+fn parse_body<T: serde::de::DeserializeOwned>(body: serde_json::Value) -> Result<T, MatrixError> {
+    serde_json::from_value(body).map_err(|e| MatrixError::bad_json(e.to_string()))
+}
+
+fn version_not_found(e: StorageError) -> MatrixError {
+    match e {
+        StorageError::NotFound => MatrixError::not_found("Unknown backup version"),
+        e => storage_error(e),
+    }
+}
+
+/// The version info returned by the `version` endpoints.
+async fn version_info(
+    state: &AppState,
+    version: KeyBackupVersion,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let count = state
+        .storage()
+        .count_backed_up_room_keys(&version.user_id, &version.version)
+        .await
+        .map_err(storage_error)?;
+    Ok(Json(serde_json::json!({
+        "algorithm": version.algorithm,
+        "auth_data": version.auth_data,
+        "count": count,
+        "etag": version.etag.to_string(),
+        "version": version.version,
+    })))
+}
+
+/// The `count` and `etag` returned by the endpoints that change keys.
+async fn keys_summary(
+    state: &AppState,
+    user_id: &str,
+    version: &str,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let info = storage
+        .get_key_backup_version(user_id, Some(version))
+        .await
+        .map_err(version_not_found)?;
+    let count = storage
+        .count_backed_up_room_keys(user_id, version)
+        .await
+        .map_err(storage_error)?;
+    Ok(Json(serde_json::json!({
+        "count": count,
+        "etag": info.etag.to_string(),
+    })))
+}
+
+/// Whether an uploaded key should replace the backed-up key for the same
+/// session: a verified key beats an unverified one, then the key that can
+/// decrypt more of the session (lower `first_message_index`) wins, then the
+/// one that was forwarded fewer times.
+fn is_better_key(new: &KeyBackupData, existing: &BackedUpRoomKey) -> bool {
+    if new.is_verified != existing.is_verified {
+        return new.is_verified;
+    }
+    if new.first_message_index != existing.first_message_index {
+        return new.first_message_index < existing.first_message_index;
+    }
+    new.forwarded_count < existing.forwarded_count
+}
+
+/// Store uploaded keys in the user's current backup version, keeping the
+/// better key for sessions it already holds.
+async fn store_keys(
+    state: &AppState,
+    user_id: &str,
+    version: &str,
+    rooms: KeysBackup,
+) -> Result<Json<serde_json::Value>, BackupError> {
+    let storage = state.storage();
+    let current = storage
+        .get_key_backup_version(user_id, None)
+        .await
+        .map_err(|e| match e {
+            StorageError::NotFound => MatrixError::not_found("No backup version exists"),
+            e => storage_error(e),
+        })?;
+    if current.version != version {
+        return Err(BackupError::WrongVersion {
+            current_version: current.version,
+        });
+    }
+
+    // One read per room for the keys already held, one write for the upload
+    let mut better = Vec::new();
+    for (room_id, room) in rooms.rooms {
+        let existing: BTreeMap<String, BackedUpRoomKey> = storage
+            .get_backed_up_room_keys(user_id, version, Some(&room_id), None)
+            .await
+            .map_err(storage_error)?
+            .into_iter()
+            .map(|key| (key.session_id.clone(), key))
+            .collect();
+        for (session_id, key) in room.sessions {
+            if existing
+                .get(&session_id)
+                .is_some_and(|existing| !is_better_key(&key, existing))
+            {
+                continue;
+            }
+            better.push(BackedUpRoomKey {
+                room_id: room_id.clone(),
+                session_id,
+                first_message_index: key.first_message_index,
+                forwarded_count: key.forwarded_count,
+                is_verified: key.is_verified,
+                session_data: serde_json::Value::Object(key.session_data),
+            });
+        }
+    }
+    if !better.is_empty() {
+        storage
+            .put_backed_up_room_keys(user_id, version, &better)
+            .await
+            .map_err(storage_error)?;
+    }
+
+    Ok(keys_summary(state, user_id, version).await?)
+}
+
+/// Get the keys of a backup version, grouped by room and session.
+async fn load_keys(
+    state: &AppState,
+    user_id: &str,
+    version: &str,
+    room_id: Option<&str>,
+) -> Result<KeysBackup, MatrixError> {
+    let storage = state.storage();
+    storage
+        .get_key_backup_version(user_id, Some(version))
+        .await
+        .map_err(version_not_found)?;
+    let keys = storage
+        .get_backed_up_room_keys(user_id, version, room_id, None)
+        .await
+        .map_err(storage_error)?;
+
+    let mut backup = KeysBackup::default();
+    for key in keys {
+        backup
+            .rooms
+            .entry(key.room_id.clone())
+            .or_default()
+            .sessions
+            .insert(key.session_id.clone(), key.into());
+    }
+    Ok(backup)
+}
+
+/// Delete keys from a backup version.
+async fn remove_keys(
+    state: &AppState,
+    user_id: &str,
+    version: &str,
+    room_id: Option<&str>,
+    session_id: Option<&str>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    state
+        .storage()
+        .delete_backed_up_room_keys(user_id, version, room_id, session_id)
+        .await
+        .map_err(version_not_found)?;
+    keys_summary(state, user_id, version).await
+}
+
+/// POST /_matrix/client/v3/room_keys/version
+///
+/// Create a backup version, which becomes the current one.
+async fn create_version(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let algorithm = body
+        .get("algorithm")
+        .and_then(|a| a.as_str())
+        .ok_or_else(|| MatrixError::bad_json("Missing algorithm"))?;
+    let auth_data = body
+        .get("auth_data")
+        .filter(|a| a.is_object())
+        .ok_or_else(|| MatrixError::bad_json("Missing auth_data"))?;
+
+    let version = state
+        .storage()
+        .create_key_backup_version(auth.user_id.as_ref(), algorithm, auth_data)
+        .await
+        .map_err(storage_error)?;
+
+    Ok(Json(serde_json::json!({ "version": version })))
+}
+
+/// GET /_matrix/client/v3/room_keys/version
+///
+/// Get the current backup version.
+async fn get_current_version(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let version = state
+        .storage()
+        .get_key_backup_version(auth.user_id.as_ref(), None)
+        .await
+        .map_err(|e| match e {
+            StorageError::NotFound => MatrixError::not_found("No backup version exists"),
+            e => storage_error(e),
+        })?;
+    version_info(&state, version).await
+}
+
+/// GET /_matrix/client/v3/room_keys/version/{version}
+async fn get_version(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(version): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let version = state
+        .storage()
+        .get_key_backup_version(auth.user_id.as_ref(), Some(&version))
+        .await
+        .map_err(version_not_found)?;
+    version_info(&state, version).await
+}
+
+/// PUT /_matrix/client/v3/room_keys/version/{version}
+///
+/// Replace a backup version's `auth_data`. The algorithm cannot change.
+async fn update_version(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(version): Path<String>,
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+
+    if let Some(body_version) = body.get("version")
+        && body_version.as_str() != Some(version.as_str())
+    {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "version in body does not match the path",
+        ));
+    }
+    let existing = storage
+        .get_key_backup_version(&user_id, Some(&version))
+        .await
+        .map_err(version_not_found)?;
+    let algorithm = body
+        .get("algorithm")
+        .and_then(|a| a.as_str())
+        .ok_or_else(|| MatrixError::bad_json("Missing algorithm"))?;
+    if algorithm != existing.algorithm {
+        return Err(MatrixError::new(
+            http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidParam,
+            "The algorithm of a backup version cannot change",
+        ));
+    }
+    let auth_data = body
+        .get("auth_data")
+        .filter(|a| a.is_object())
+        .ok_or_else(|| MatrixError::bad_json("Missing auth_data"))?;
+
+    storage
+        .update_key_backup_version(&user_id, &version, auth_data)
+        .await
+        .map_err(version_not_found)?;
+
+    Ok(Json(serde_json::json!({})))
+}
+
+/// DELETE /_matrix/client/v3/room_keys/version/{version}
+///
+/// Delete a backup version and all keys in it. If it was the current one,
+/// the previous version that still exists becomes current.
+async fn delete_version(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(version): Path<String>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    state
+        .storage()
+        .delete_key_backup_version(auth.user_id.as_ref(), &version)
+        .await
+        .map_err(version_not_found)?;
+    Ok(Json(serde_json::json!({})))
+}
+
+/// PUT /_matrix/client/v3/room_keys/keys
+async fn put_all_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<VersionQuery>,
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, BackupError> {
+    let version = query.version()?;
+    let rooms: KeysBackup = parse_body(body)?;
+    store_keys(&state, auth.user_id.as_ref(), &version, rooms).await
+}
+
+/// GET /_matrix/client/v3/room_keys/keys
+async fn get_all_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<KeysBackup>, MatrixError> {
+    let version = query.version()?;
+    Ok(Json(
+        load_keys(&state, auth.user_id.as_ref(), &version, None).await?,
+    ))
+}
+
+/// DELETE /_matrix/client/v3/room_keys/keys
+async fn delete_all_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let version = query.version()?;
+    remove_keys(&state, auth.user_id.as_ref(), &version, None, None).await
+}
+
+/// PUT /_matrix/client/v3/room_keys/keys/{roomId}
+async fn put_room_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<VersionQuery>,
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, BackupError> {
+    let version = query.version()?;
+    let room: RoomKeyBackup = parse_body(body)?;
+    let rooms = KeysBackup {
+        rooms: BTreeMap::from([(room_id, room)]),
+    };
+    store_keys(&state, auth.user_id.as_ref(), &version, rooms).await
+}
+
+/// GET /_matrix/client/v3/room_keys/keys/{roomId}
+///
+/// A room without keys in the backup has no sessions rather than being an
+/// error.
+async fn get_room_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<RoomKeyBackup>, MatrixError> {
+    let version = query.version()?;
+    let mut backup = load_keys(&state, auth.user_id.as_ref(), &version, Some(&room_id)).await?;
+    Ok(Json(backup.rooms.remove(&room_id).unwrap_or_default()))
+}
+
+/// DELETE /_matrix/client/v3/room_keys/keys/{roomId}
+async fn delete_room_keys(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let version = query.version()?;
+    remove_keys(
+        &state,
+        auth.user_id.as_ref(),
+        &version,
+        Some(&room_id),
+        None,
+    )
+    .await
+}
+
+/// PUT /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}
+async fn put_session_key(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, session_id)): Path<(String, String)>,
+    Query(query): Query<VersionQuery>,
+    axum::Json(body): axum::Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, BackupError> {
+    let version = query.version()?;
+    let key: KeyBackupData = parse_body(body)?;
+    let rooms = KeysBackup {
+        rooms: BTreeMap::from([(
+            room_id,
+            RoomKeyBackup {
+                sessions: BTreeMap::from([(session_id, key)]),
+            },
+        )]),
+    };
+    store_keys(&state, auth.user_id.as_ref(), &version, rooms).await
+}
+
+/// GET /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}
+async fn get_session_key(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, session_id)): Path<(String, String)>,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<KeyBackupData>, MatrixError> {
+    let version = query.version()?;
+    let mut backup = load_keys(&state, auth.user_id.as_ref(), &version, Some(&room_id)).await?;
+    backup
+        .rooms
+        .remove(&room_id)
+        .and_then(|mut room| room.sessions.remove(&session_id))
+        .map(Json)
+        .ok_or_else(|| MatrixError::not_found("No key backed up for this session"))
+}
+
+/// DELETE /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}
+async fn delete_session_key(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, session_id)): Path<(String, String)>,
+    Query(query): Query<VersionQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let version = query.version()?;
+    remove_keys(
+        &state,
+        auth.user_id.as_ref(),
+        &version,
+        Some(&room_id),
+        Some(&session_id),
+    )
+    .await
+}
@@ -10,12 +10,13 @@
 //! * **One-time keys** -- ephemeral Curve25519 keys consumed during Olm session
 //!   setup. The server hands one out each time another device wants to start an
 //!   encrypted conversation.
+//! * **Fallback keys** (MSC2732) -- one per algorithm and device, handed out
+//!   when the device has run out of one-time keys, until it uploads a new one.
 //! * **Cross-signing keys** -- master, self-signing, and user-signing keys that
 //!   let a user verify their own devices and other users without per-device
 //!   trust.
 //!
-//! Additionally, server-side **key backup** endpoints allow clients to store
-//! encrypted Megolm session keys so they can be recovered on new devices.
+//! Server-side **key backup** lives in [`key_backup`](super::key_backup).
 //!
 //! # Endpoints
 //!
@@ -26,29 +27,25 @@
 //! | `POST` | `/_matrix/client/v3/keys/claim` | Claim one-time keys for establishing Olm sessions |
 //! | `GET`  | `/_matrix/client/v3/keys/changes` | Get the list of users whose devices have changed since a given point |
 //! | `POST` | `/_matrix/client/v3/keys/device_signing/upload` | Upload cross-signing keys (master, self-signing, user-signing) |
-//! | `POST` | `/_matrix/client/v3/keys/signatures/upload` | Upload cross-signing signatures for devices or other keys |
-//! | `POST` | `/_matrix/client/v3/room_keys/version` | Create a new key backup version |
-//! | `GET`  | `/_matrix/client/v3/room_keys/version` | Get the current key backup version |
-//! | `GET`  | `/_matrix/client/v3/room_keys/version/{version}` | Get info about a specific backup version |
-//! | `PUT`  | `/_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}` | Store a key for a specific session in a room |
-//! | `GET`  | `/_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}` | Retrieve a key for a specific session |
-//! | `PUT`  | `/_matrix/client/v3/room_keys/keys/{roomId}` | Store keys for all sessions in a room |
-//! | `GET`  | `/_matrix/client/v3/room_keys/keys/{roomId}` | Retrieve keys for all sessions in a room |
-//! | `PUT`  | `/_matrix/client/v3/room_keys/keys` | Store keys for all rooms |
-//! | `GET`  | `/_matrix/client/v3/room_keys/keys` | Retrieve keys for all rooms |
+//! | `POST` | `/_matrix/client/v3/keys/signatures/upload` | Upload signatures on device keys and master keys |
 //!
 //! # Matrix spec
 //!
 //! * [End-to-End Encryption](https://spec.matrix.org/v1.18/client-server-api/#end-to-end-encryption)
-//! * [Server-side key backups](https://spec.matrix.org/v1.18/client-server-api/#server-side-key-backups)
 
 use axum::extract::State;
 use axum::routing::{get, post};
 use axum::{Json, Router};
 
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
 use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_core::matrix::json::CanonicalJson;
+use maelstrom_core::matrix::keys::public_key_from_base64;
+use maelstrom_core::matrix::signing::verify_event_signature_canonical;
+use maelstrom_federation::user_keys::{apply_key_signatures, cross_signing_key_id};
+use maelstrom_storage::traits::KeySignature;
 
+use crate::appservice_sender;
 use crate::extractors::{AuthenticatedUser, storage_error};
 use crate::state::AppState;
 
@@ -66,39 +63,41 @@ pub fn routes() -> Router<AppState> {
             "/_matrix/client/v3/keys/signatures/upload",
             post(keys_signatures_upload),
         )
-        .route(
-            "/_matrix/client/v3/room_keys/version",
-            post(create_key_backup).get(get_key_backup),
-        )
-        .route(
-            "/_matrix/client/v3/room_keys/version/{version}",
-            get(get_key_backup_version),
-        )
-        .route(
-            "/_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}",
-            axum::routing::put(put_room_key).get(get_room_key),
-        )
-        .route(
-            "/_matrix/client/v3/room_keys/keys/{roomId}",
-            axum::routing::put(put_room_keys).get(get_room_keys),
-        )
-        .route(
-            "/_matrix/client/v3/room_keys/keys",
-            axum::routing::put(put_all_room_keys).get(get_all_room_keys),
-        )
 }
 
 /// POST /_matrix/client/v3/keys/upload
 ///
-/// Upload device keys and/or one-time keys.
+/// Upload device keys, one-time keys and/or fallback keys.
 async fn keys_upload(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     axum::Json(body): axum::Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
+    let counts = upload_keys(
+        &state,
+        auth.user_id.as_ref(),
+        auth.device_id.as_ref(),
+        &body,
+    )
+    .await?;
+
+    Ok(Json(serde_json::json!({
+        "one_time_key_counts": counts
+    })))
+}
+
+/// Store the `device_keys`, `one_time_keys` and `fallback_keys` of a
+/// `/keys/upload`-shaped body for one device, and return the device's
+/// one-time key counts.
+///
+/// Also used for the keys uploaded with a dehydrated device.
+pub(crate) async fn upload_keys(
+    state: &AppState,
+    user_id: &str,
+    device_id: &str,
+    body: &serde_json::Value,
+) -> Result<serde_json::Value, MatrixError> {
     let storage = state.storage();
-    let user_id = auth.user_id.to_string();
-    let device_id = auth.device_id.to_string();
 
     // Store device keys if provided
     if let Some(device_keys) = body.get("device_keys") {
@@ -148,75 +147,79 @@ async fn keys_upload(
             ));
         }
 
+        // Signatures on the device's previous keys do not hold for new ones
+        let previous = storage
+            .get_device_keys(&[user_id.to_string()])
+            .await
+            .map_err(storage_error)?;
+        if previous
+            .get(user_id)
+            .and_then(|devices| devices.get(device_id))
+            .is_some_and(|previous| previous.get("keys") != device_keys.get("keys"))
+        {
+            storage
+                .delete_key_signatures(user_id, device_id)
+                .await
+                .map_err(storage_error)?;
+        }
+
         storage
-            .set_device_keys(&user_id, &device_id, device_keys)
+            .set_device_keys(user_id, device_id, device_keys)
             .await
             .map_err(storage_error)?;
 
-        // Record device list change for sync tracking
-        let change_pos = storage.current_stream_position().await.unwrap_or(0);
-        let _ = storage
-            .set_account_data(
-                &user_id,
-                None,
-                "_maelstrom.device_change_pos",
-                &serde_json::json!({"pos": change_pos}),
-            )
-            .await;
-
-        // Notify remote servers about device key changes via federation EDU
-        if let Some(sender) = state.transaction_sender() {
-            let remote_servers = crate::handlers::util::servers_sharing_rooms(
-                storage,
-                &user_id,
-                state.server_name().as_str(),
-            )
-            .await;
-            for server in remote_servers {
-                sender.queue_edu(
-                    &server,
-                    serde_json::json!({
-                        "edu_type": "m.device_list_update",
-                        "content": {
-                            "user_id": user_id,
-                            "device_id": device_id,
-                            "stream_id": change_pos,
-                            "deleted": false,
-                        }
-                    }),
-                );
-            }
-        }
+        crate::device_lists::device_changed(state, user_id, device_id, false).await;
     }
 
     // Store one-time keys if provided
     if let Some(otks) = body.get("one_time_keys") {
         storage
-            .store_one_time_keys(&user_id, &device_id, otks)
+            .store_one_time_keys(user_id, device_id, otks)
+            .await
+            .map_err(storage_error)?;
+        appservice_sender::queue_one_time_key_count(state, user_id, device_id).await;
+    }
+
+    // Store fallback keys if provided (MSC2732, also under its unstable name)
+    if let Some(fallback_keys) = body
+        .get("fallback_keys")
+        .or_else(|| body.get("org.matrix.msc2732.fallback_keys"))
+    {
+        if !fallback_keys.is_object() {
+            return Err(MatrixError::bad_json("fallback_keys must be an object"));
+        }
+        storage
+            .store_fallback_keys(user_id, device_id, fallback_keys)
             .await
             .map_err(storage_error)?;
+        appservice_sender::queue_one_time_key_count(state, user_id, device_id).await;
     }
 
     // Return current OTK counts
-    let counts = storage
-        .count_one_time_keys(&user_id, &device_id)
+    storage
+        .count_one_time_keys(user_id, device_id)
         .await
-        .map_err(storage_error)?;
-
-    Ok(Json(serde_json::json!({
-        "one_time_key_counts": counts
-    })))
+        .map_err(storage_error)
 }
 
 /// POST /_matrix/client/v3/keys/query
 ///
 /// Query device keys and cross-signing keys for users.
+///
+/// Signatures uploaded with `/keys/signatures/upload` are merged into the
+/// keys; signatures on another user's master key only show to their signer.
+///
+/// Remote users sharing a room with this server are answered from the
+/// remote device list cache, which `m.device_list_update` EDUs keep current;
+/// an uncached list is fetched once with `/user/devices` and cached. Other
+/// remote users are queried with `/user/keys/query`, batched per server.
 async fn keys_query(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     axum::Json(body): axum::Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let storage = state.storage();
+    let requester = auth.user_id.to_string();
 
     // Validate: device_keys values must be arrays (lists of device IDs), not objects
     if let Some(device_keys) = body.get("device_keys")
@@ -245,23 +248,42 @@ async fn keys_query(
     let mut remote_users: std::collections::HashMap<String, Vec<String>> =
         std::collections::HashMap::new();
 
-    for uid in &user_ids {
+    // Remote users whose cached device list can be served like a local one
+    let mut cached_user_ids: Vec<String> = Vec::new();
+
+    let (local, remote): (Vec<String>, Vec<String>) = user_ids.iter().cloned().partition(|uid| {
         let server = server_name_from_sigil_id(uid);
-        if server == local_server || server.is_empty() {
-            local_user_ids.push(uid.clone());
+        server == local_server || server.is_empty()
+    });
+    local_user_ids.extend(local);
+    let cached = remote_device_lists_cached(&state, &remote).await;
+    for uid in remote {
+        if cached.contains(&uid) {
+            cached_user_ids.push(uid);
         } else {
             remote_users
-                .entry(server.to_string())
+                .entry(server_name_from_sigil_id(&uid).to_string())
                 .or_default()
-                .push(uid.clone());
+                .push(uid);
         }
     }
 
-    // Get device keys for local users
+    // Get device keys for local users and cached remote users
     let mut device_keys = storage
         .get_device_keys(&local_user_ids)
         .await
         .map_err(storage_error)?;
+    let cached_keys = storage
+        .get_device_keys(&cached_user_ids)
+        .await
+        .map_err(storage_error)?;
+    if let Some(dk_obj) = device_keys.as_object_mut()
+        && let Some(cached_obj) = cached_keys.as_object()
+    {
+        for (uid, devices) in cached_obj {
+            dk_obj.insert(uid.clone(), devices.clone());
+        }
+    }
 
     // Get cross-signing keys for local users
     let mut master_keys = serde_json::Map::new();
@@ -287,6 +309,20 @@ async fn keys_query(
         }
     }
 
+    // Remote users only publish their master and self-signing keys
+    for uid in &cached_user_ids {
+        let cross_keys = storage
+            .get_cross_signing_keys(uid)
+            .await
+            .map_err(storage_error)?;
+        if let Some(mk) = cross_keys.get("master_key") {
+            master_keys.insert(uid.clone(), mk.clone());
+        }
+        if let Some(ssk) = cross_keys.get("self_signing_key") {
+            self_signing_keys.insert(uid.clone(), ssk.clone());
+        }
+    }
+
     // Query remote servers via federation for remote users' device keys
     let mut failures = serde_json::Map::new();
     if let Some(fed) = state.federation() {
@@ -366,6 +402,28 @@ async fn keys_query(
         }
     }
 
+    // Merge in uploaded signatures the requester may see, including their own
+    // signatures on remote users' master keys
+    for uid in &user_ids {
+        let signatures = storage
+            .get_key_signatures(uid)
+            .await
+            .map_err(storage_error)?;
+        if signatures.is_empty() {
+            continue;
+        }
+        if let Some(devices) = device_keys.get_mut(uid).and_then(|d| d.as_object_mut()) {
+            for (device_id, keys) in devices {
+                apply_key_signatures(&signatures, Some(&requester), device_id, keys);
+            }
+        }
+        if let Some(master_key) = master_keys.get_mut(uid)
+            && let Some(key_id) = cross_signing_key_id(master_key).map(str::to_string)
+        {
+            apply_key_signatures(&signatures, Some(&requester), &key_id, master_key);
+        }
+    }
+
     let mut response = serde_json::json!({
         "device_keys": device_keys,
         "failures": failures,
@@ -385,37 +443,169 @@ async fn keys_query(
     Ok(Json(response))
 }
 
+/// Which of the given remote users have their device list in the local cache.
+/// Users sharing a room with this server but not cached yet are fetched
+/// first, concurrently, joining any resync already in progress for them.
+///
+/// Lists of users sharing no rooms are never trusted: no updates arrive for
+/// them, so the cache may be stale.
+async fn remote_device_lists_cached(
+    state: &AppState,
+    user_ids: &[String],
+) -> std::collections::HashSet<String> {
+    let storage = state.storage();
+    let mut cached = std::collections::HashSet::new();
+    let mut resyncs = tokio::task::JoinSet::new();
+    for user_id in user_ids {
+        let shares_room = storage
+            .get_joined_rooms(user_id)
+            .await
+            .is_ok_and(|rooms| !rooms.is_empty());
+        if !shares_room {
+            continue;
+        }
+        if let Ok(Some(_)) = storage.get_remote_device_stream_id(user_id).await {
+            cached.insert(user_id.clone());
+            continue;
+        }
+        if state.federation().is_none() {
+            continue;
+        }
+        let state = state.clone();
+        let user_id = user_id.clone();
+        resyncs.spawn(async move {
+            let Some(fed) = state.federation() else {
+                return (user_id, false);
+            };
+            let fresh =
+                maelstrom_federation::device_lists::resync(state.storage(), fed, &user_id).await;
+            (user_id, fresh)
+        });
+    }
+    while let Some(joined) = resyncs.join_next().await {
+        if let Ok((user_id, true)) = joined {
+            cached.insert(user_id);
+        }
+    }
+    cached
+}
+
 /// POST /_matrix/client/v3/keys/claim
 ///
 /// Claim one-time keys for use in establishing encrypted sessions.
+///
+/// A device that has run out of one-time keys hands out its fallback key.
+/// Keys of remote users are claimed with `/user/keys/claim`, batched per
+/// server and sent to all servers at once; servers that fail are reported in
+/// `failures`. A server's answer can only fill in the devices asked of it.
 async fn keys_claim(
     State(state): State<AppState>,
     _auth: AuthenticatedUser,
     axum::Json(body): axum::Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let storage = state.storage();
+    let local_server = state.server_name().as_str();
 
-    let claims = body
-        .get("one_time_keys")
-        .cloned()
-        .unwrap_or(serde_json::json!({}));
+    // Partition claims into local and remote, per server
+    let mut local_claims = serde_json::Map::new();
+    let mut remote_claims: std::collections::HashMap<String, serde_json::Map<String, _>> =
+        std::collections::HashMap::new();
+    if let Some(claims) = body.get("one_time_keys").and_then(|v| v.as_object()) {
+        for (uid, devices) in claims {
+            let server = server_name_from_sigil_id(uid);
+            if server == local_server || server.is_empty() {
+                local_claims.insert(uid.clone(), devices.clone());
+            } else {
+                remote_claims
+                    .entry(server.to_string())
+                    .or_default()
+                    .insert(uid.clone(), devices.clone());
+            }
+        }
+    }
 
-    let claimed = storage
-        .claim_one_time_keys(&claims)
+    let mut claimed = storage
+        .claim_one_time_keys(&serde_json::Value::Object(local_claims))
         .await
         .map_err(storage_error)?;
 
+    if let Some(users) = claimed.as_object() {
+        for (user_id, devices) in users {
+            for device_id in devices.as_object().into_iter().flat_map(|d| d.keys()) {
+                appservice_sender::queue_one_time_key_count(&state, user_id, device_id).await;
+            }
+        }
+    }
+
+    // Ask the servers concurrently, taking from each only the keys of the
+    // devices asked of it
+    let mut failures = serde_json::Map::new();
+    let mut requests = tokio::task::JoinSet::new();
+    if let Some(fed) = state.federation_arc() {
+        for (server, claims) in remote_claims {
+            let fed = fed.clone();
+            requests.spawn(async move {
+                let query_body = serde_json::json!({ "one_time_keys": claims });
+                let response = fed
+                    .post_json(
+                        &server,
+                        "/_matrix/federation/v1/user/keys/claim",
+                        &query_body,
+                    )
+                    .await;
+                (server, claims, response)
+            });
+        }
+    }
+    while let Some(joined) = requests.join_next().await {
+        let Ok((server, claims, response)) = joined else {
+            continue;
+        };
+        match response {
+            Ok(resp) => {
+                if let Some(remote) = resp.get("one_time_keys").and_then(|k| k.as_object())
+                    && let Some(claimed_obj) = claimed.as_object_mut()
+                {
+                    for (uid, devices) in remote {
+                        let (Some(requested), Some(devices)) = (
+                            claims.get(uid).and_then(|d| d.as_object()),
+                            devices.as_object(),
+                        ) else {
+                            continue;
+                        };
+                        let devices: serde_json::Map<String, serde_json::Value> = devices
+                            .iter()
+                            .filter(|(device_id, _)| requested.contains_key(*device_id))
+                            .map(|(device_id, keys)| (device_id.clone(), keys.clone()))
+                            .collect();
+                        claimed_obj.insert(uid.clone(), serde_json::Value::Object(devices));
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!(server = %server, error = %e, "Failed to claim keys from remote server");
+                failures.insert(
+                    server.clone(),
+                    serde_json::json!({
+                        "errcode": "M_UNKNOWN",
+                        "error": e.to_string()
+                    }),
+                );
+            }
+        }
+    }
+
     Ok(Json(serde_json::json!({
         "one_time_keys": claimed,
-        "failures": {}
+        "failures": failures,
     })))
 }
 
 /// GET /_matrix/client/v3/keys/changes
 ///
-/// Get users whose device lists have changed (stub for now).
+/// Get the caller and the users sharing a room with them whose device lists
+/// or cross-signing keys changed between the `from` and `to` sync tokens.
 #[derive(serde::Deserialize)]
-#[allow(dead_code)]
 struct KeysChangesQuery {
     from: String,
     to: String,
@@ -431,25 +621,29 @@ async fn keys_changes(
     let from: i64 = query.from.parse().unwrap_or(0);
     let to: i64 = query.to.parse().unwrap_or(i64::MAX);
 
-    // Get all users in shared rooms
+    let changed_users: std::collections::HashSet<String> = storage
+        .get_device_list_changed_users(from, to)
+        .await
+        .map_err(crate::extractors::storage_error)?
+        .into_iter()
+        .collect();
+
+    // Only report the caller and users sharing a room with them
     let joined_rooms = storage.get_joined_rooms(&user_id).await.unwrap_or_default();
     let mut changed: Vec<String> = Vec::new();
-
     let mut seen = std::collections::HashSet::new();
+    if changed_users.contains(&user_id) {
+        seen.insert(user_id.clone());
+        changed.push(user_id.clone());
+    }
     for room_id in &joined_rooms {
         if let Ok(members) = storage.get_room_members(room_id, "join").await {
             for member in members {
-                if member != user_id && seen.insert(member.clone()) {
-                    // Check if this user's device_change_pos is in the range [from, to]
-                    if let Ok(data) = storage
-                        .get_account_data(&member, None, "_maelstrom.device_change_pos")
-                        .await
-                        && let Some(pos) = data.get("pos").and_then(|p| p.as_i64())
-                        && pos >= from
-                        && pos <= to
-                    {
-                        changed.push(member);
-                    }
+                if member != user_id
+                    && changed_users.contains(&member)
+                    && seen.insert(member.clone())
+                {
+                    changed.push(member);
                 }
             }
         }
@@ -461,319 +655,384 @@ async fn keys_changes(
     })))
 }
 
+/// The cross-signing keys a client can upload, with the `usage` each must
+/// have.
+const CROSS_SIGNING_KEY_TYPES: [(&str, &str); 3] = [
+    ("master_key", "master"),
+    ("self_signing_key", "self_signing"),
+    ("user_signing_key", "user_signing"),
+];
+
+/// UIA stage completed by having the reset approved out of band, by a server
+/// admin (MSC4312). The client retries the upload once it was approved.
+const CROSS_SIGNING_RESET_STAGE: &str = "org.matrix.cross_signing_reset";
+
 /// POST /_matrix/client/v3/keys/device_signing/upload
 ///
-/// Upload cross-signing keys. Requires UIA (MSC3967).
+/// Upload cross-signing keys.
+///
+/// Setting up cross-signing needs no UIA, but replacing keys the user already
+/// has does (MSC3967): otherwise whoever holds an access token could silently
+/// swap the user's identity. Re-uploading the current keys, as clients do
+/// when retrying, is not a replacement.
 async fn keys_device_signing_upload(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     axum::Json(body): axum::Json<serde_json::Value>,
 ) -> Result<(http::StatusCode, Json<serde_json::Value>), MatrixError> {
-    // Require UIA
-    let auth_ok = body
-        .get("auth")
-        .and_then(|a| a.get("type"))
-        .and_then(|t| t.as_str());
-    match auth_ok {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+
+    let mut uploaded = serde_json::Map::new();
+    for (key_type, usage) in CROSS_SIGNING_KEY_TYPES {
+        let Some(key) = body.get(key_type) else {
+            continue;
+        };
+        let has_usage = key
+            .get("usage")
+            .and_then(|u| u.as_array())
+            .is_some_and(|u| u.iter().any(|v| v == usage));
+        if key.get("user_id").and_then(|u| u.as_str()) != Some(user_id.as_str())
+            || !has_usage
+            || cross_signing_key_id(key).is_none()
+        {
+            return Err(MatrixError::new(
+                http::StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidParam,
+                format!("Invalid {key_type}"),
+            ));
+        }
+        uploaded.insert(key_type.to_string(), key.clone());
+    }
+
+    let existing = storage
+        .get_cross_signing_keys(&user_id)
+        .await
+        .map_err(storage_error)?;
+    let has_master_key = existing.get("master_key").is_some();
+    if !uploaded.is_empty() && !uploaded.contains_key("master_key") && !has_master_key {
+        return Err(MatrixError::missing_param("A master key is required"));
+    }
+
+    let replaces = has_master_key
+        && uploaded.iter().any(|(key_type, key)| {
+            existing.get(key_type).and_then(|k| k.get("keys")) != key.get("keys")
+        });
+    if replaces && let Some(response) = cross_signing_reset_auth(&state, &auth, &body).await? {
+        return Ok(response);
+    }
+
+    let changed = uploaded
+        .iter()
+        .any(|(key_type, key)| existing.get(key_type) != Some(key));
+    let uploaded = serde_json::Value::Object(uploaded);
+    storage
+        .set_cross_signing_keys(&user_id, &uploaded)
+        .await
+        .map_err(storage_error)?;
+    if replaces {
+        // An approval covers one reset
+        storage
+            .set_cross_signing_reset_allowed_until(&user_id, None)
+            .await
+            .map_err(storage_error)?;
+    }
+    if changed {
+        crate::device_lists::signing_keys_changed(&state, &user_id, &uploaded).await;
+    }
+
+    Ok((http::StatusCode::OK, Json(serde_json::json!({}))))
+}
+
+/// Check the UIA for replacing the user's cross-signing keys: their password,
+/// or a reset an admin approved that has not expired. Returns the 401 UIA
+/// response if neither was given.
+async fn cross_signing_reset_auth(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    body: &serde_json::Value,
+) -> Result<Option<(http::StatusCode, Json<serde_json::Value>)>, MatrixError> {
+    let storage = state.storage();
+    let allowed_until = storage
+        .get_cross_signing_reset_allowed_until(auth.user_id.as_ref())
+        .await
+        .map_err(storage_error)?;
+    if allowed_until.is_some_and(|until| until > chrono::Utc::now()) {
+        return Ok(None);
+    }
+
+    let user = storage
+        .get_user(auth.user_id.localpart())
+        .await
+        .map_err(storage_error)?;
+    let uia = body.get("auth");
+    let session = uia
+        .and_then(|a| a.get("session"))
+        .and_then(|s| s.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(crate::handlers::util::generate_session_id);
+    let mut flows = Vec::new();
+    if user.password_hash.is_some() {
+        flows.push(serde_json::json!({"stages": ["m.login.password"]}));
+    }
+    flows.push(serde_json::json!({"stages": [CROSS_SIGNING_RESET_STAGE]}));
+    let mut response = serde_json::json!({
+        "flows": flows,
+        "params": {},
+        "session": session,
+    });
+
+    match uia.and_then(|a| a.get("type")).and_then(|t| t.as_str()) {
         Some("m.login.password") => {
-            let password = body
-                .get("auth")
+            let password = uia
                 .and_then(|a| a.get("password"))
                 .and_then(|p| p.as_str())
                 .ok_or_else(|| MatrixError::bad_json("Missing password in auth"))?;
-
-            let user = state
-                .storage()
-                .get_user(auth.user_id.localpart())
-                .await
-                .map_err(storage_error)?;
-
             let hash = user
                 .password_hash
                 .as_deref()
                 .ok_or_else(|| MatrixError::forbidden("Cannot verify password"))?;
-
             if crate::handlers::util::verify_password(password.to_string(), hash.to_string())
                 .await
-                .is_err()
+                .is_ok()
             {
-                let session = crate::handlers::util::generate_session_id();
-                return Ok((
-                    http::StatusCode::UNAUTHORIZED,
-                    Json(serde_json::json!({
-                        "flows": [{"stages": ["m.login.password"]}, {"stages": ["m.login.dummy"]}],
-                        "session": session,
-                        "errcode": "M_FORBIDDEN",
-                        "error": "Invalid password"
-                    })),
-                ));
+                return Ok(None);
             }
+            response["errcode"] = "M_FORBIDDEN".into();
+            response["error"] = "Invalid password".into();
         }
-        Some("m.login.dummy") => {}
-        _ => {
-            let session = crate::handlers::util::generate_session_id();
-            return Ok((
-                http::StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "flows": [{"stages": ["m.login.password"]}, {"stages": ["m.login.dummy"]}],
-                    "session": session
-                })),
-            ));
+        Some(CROSS_SIGNING_RESET_STAGE) => {
+            response["errcode"] = "M_FORBIDDEN".into();
+            response["error"] = "The cross-signing reset has not been approved".into();
         }
+        _ => {}
     }
 
-    let storage = state.storage();
-    let user_id = auth.user_id.to_string();
-
-    storage
-        .set_cross_signing_keys(&user_id, &body)
-        .await
-        .map_err(storage_error)?;
-
-    Ok((http::StatusCode::OK, Json(serde_json::json!({}))))
+    Ok(Some((http::StatusCode::UNAUTHORIZED, Json(response))))
 }
 
 /// POST /_matrix/client/v3/keys/signatures/upload
 ///
-/// Upload key signatures (stub -- accepts and returns success).
-async fn keys_signatures_upload(_auth: AuthenticatedUser) -> Json<serde_json::Value> {
-    Json(serde_json::json!({ "failures": {} }))
-}
-
-/// POST /_matrix/client/v3/room_keys/version — create a new key backup version
-async fn create_key_backup(
+/// Upload signatures the caller made on device keys and master keys.
+///
+/// The caller may sign their own devices with their self-signing key, their
+/// own master key with one of their devices, and other users' master keys
+/// with their user-signing key. A signature is kept only if it verifies and
+/// the signed object is the stored key; every other key is reported in
+/// `failures` as `user_id -> key_id -> error`.
+async fn keys_signatures_upload(
     State(state): State<AppState>,
     auth: AuthenticatedUser,
     axum::Json(body): axum::Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
-    let user_id = auth.user_id.to_string();
+    let storage = state.storage();
+    let signer = auth.user_id.to_string();
+    let body = body
+        .as_object()
+        .ok_or_else(|| MatrixError::bad_json("Body must be an object"))?;
 
-    // Get current version counter
-    let current = state
-        .storage()
-        .get_account_data(&user_id, None, "_maelstrom.key_backup_version")
+    let own_devices = storage
+        .get_device_keys(std::slice::from_ref(&signer))
         .await
-        .ok()
-        .and_then(|v| v.get("version").and_then(|n| n.as_u64()))
-        .unwrap_or(0);
-
-    let new_version = current + 1;
-    let version_str = new_version.to_string();
-
-    // Store the backup info
-    let _ = state
-        .storage()
-        .set_account_data(
-            &user_id,
-            None,
-            &format!("_maelstrom.key_backup.{version_str}"),
-            &body,
-        )
-        .await;
-
-    // Update version counter
-    let _ = state
-        .storage()
-        .set_account_data(
-            &user_id,
-            None,
-            "_maelstrom.key_backup_version",
-            &serde_json::json!({"version": new_version}),
-        )
-        .await;
-
-    Ok(Json(serde_json::json!({ "version": version_str })))
-}
-
-/// GET /_matrix/client/v3/room_keys/version — get current key backup version
-async fn get_key_backup(
-    State(state): State<AppState>,
-    auth: AuthenticatedUser,
-) -> Result<Json<serde_json::Value>, MatrixError> {
-    let user_id = auth.user_id.to_string();
-
-    let current = state
-        .storage()
-        .get_account_data(&user_id, None, "_maelstrom.key_backup_version")
+        .map_err(storage_error)?
+        .get(&signer)
+        .cloned()
+        .unwrap_or_else(|| serde_json::json!({}));
+    let own_cross_keys = storage
+        .get_cross_signing_keys(&signer)
         .await
-        .ok()
-        .and_then(|v| v.get("version").and_then(|n| n.as_u64()));
-
-    match current {
-        Some(version) => {
-            let version_str = version.to_string();
-            let info = state
-                .storage()
-                .get_account_data(
-                    &user_id,
-                    None,
-                    &format!("_maelstrom.key_backup.{version_str}"),
-                )
+        .map_err(storage_error)?;
+
+    let mut failures = serde_json::Map::new();
+    let mut signed_devices = Vec::new();
+    let mut signed_users = std::collections::BTreeSet::new();
+
+    for (user_id, keys) in body {
+        let keys = keys.as_object().ok_or_else(|| {
+            MatrixError::bad_json(format!("Signed keys of '{user_id}' must be an object"))
+        })?;
+        let cross_keys = if *user_id == signer {
+            own_cross_keys.clone()
+        } else {
+            // Make sure a remote user's master key is cached before checking it
+            if server_name_from_sigil_id(user_id) != state.server_name().as_str() {
+                remote_device_lists_cached(&state, std::slice::from_ref(user_id)).await;
+            }
+            storage
+                .get_cross_signing_keys(user_id)
                 .await
-                .unwrap_or(serde_json::json!({}));
+                .map_err(storage_error)?
+        };
+        let signing = SigningKeys {
+            signer: &signer,
+            own_devices: &own_devices,
+            own_cross_keys: &own_cross_keys,
+        };
 
-            let mut response = info;
-            response["version"] = serde_json::Value::String(version_str);
-            response["count"] = serde_json::json!(0);
-            response["etag"] = serde_json::Value::String("0".to_string());
-            Ok(Json(response))
+        for (key_id, signed) in keys {
+            match signing.check(user_id, key_id, signed, &cross_keys) {
+                Ok(signatures) => {
+                    for signature in &signatures {
+                        storage
+                            .add_key_signature(signature)
+                            .await
+                            .map_err(storage_error)?;
+                    }
+                    if *user_id == signer && own_devices.get(key_id).is_some() {
+                        signed_devices.push(key_id.clone());
+                    } else {
+                        signed_users.insert(user_id.clone());
+                    }
+                }
+                Err(e) => {
+                    let user_failures = failures
+                        .entry(user_id.clone())
+                        .or_insert_with(|| serde_json::json!({}));
+                    user_failures[key_id] = serde_json::to_value(&e).unwrap_or_default();
+                }
+            }
         }
-        None => Err(MatrixError::not_found("No key backup found")),
     }
-}
-
-/// GET /_matrix/client/v3/room_keys/version/{version}
-async fn get_key_backup_version(
-    State(state): State<AppState>,
-    auth: AuthenticatedUser,
-    axum::extract::Path(version): axum::extract::Path<String>,
-) -> Result<Json<serde_json::Value>, MatrixError> {
-    let user_id = auth.user_id.to_string();
 
-    let info = state
-        .storage()
-        .get_account_data(&user_id, None, &format!("_maelstrom.key_backup.{version}"))
-        .await
-        .map_err(|_| MatrixError::not_found("Key backup version not found"))?;
+    for device_id in &signed_devices {
+        crate::device_lists::device_changed(&state, &signer, device_id, false).await;
+    }
+    for user_id in &signed_users {
+        crate::device_lists::cross_signing_changed(&state, user_id).await;
+    }
 
-    let mut response = info;
-    response["version"] = serde_json::Value::String(version);
-    response["count"] = serde_json::json!(0);
-    response["etag"] = serde_json::Value::String("0".to_string());
-    Ok(Json(response))
+    Ok(Json(serde_json::json!({ "failures": failures })))
 }
 
-/// PUT /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}
-async fn put_room_key(
-    State(state): State<AppState>,
-    auth: AuthenticatedUser,
-    axum::extract::Path((room_id, session_id)): axum::extract::Path<(String, String)>,
-    axum::Json(body): axum::Json<serde_json::Value>,
-) -> Result<Json<serde_json::Value>, MatrixError> {
-    let user_id = auth.user_id.to_string();
+/// The keys with which the uploader of signatures may sign.
+struct SigningKeys<'a> {
+    signer: &'a str,
+    /// The signer's devices: `device_id -> keys`.
+    own_devices: &'a serde_json::Value,
+    own_cross_keys: &'a serde_json::Value,
+}
 
-    // Get current backup version
-    let ver = state
-        .storage()
-        .get_account_data(&user_id, None, "_maelstrom.key_backup_version")
-        .await
-        .ok()
-        .and_then(|v| v.get("version").and_then(|n| n.as_u64()))
-        .unwrap_or(0)
-        .to_string();
-
-    let key = format!("_maelstrom.room_key.{ver}.{room_id}.{session_id}");
-
-    // Apply replacement rules per spec: only replace if new key is "better"
-    let should_replace =
-        if let Ok(existing) = state.storage().get_account_data(&user_id, None, &key).await {
-            let old_verified = existing
-                .get("is_verified")
-                .and_then(|v| v.as_bool())
-                .unwrap_or(false);
-            let new_verified = body
-                .get("is_verified")
-                .and_then(|v| v.as_bool())
-                .unwrap_or(false);
-            let old_fmi = existing
-                .get("first_message_index")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(i64::MAX);
-            let new_fmi = body
-                .get("first_message_index")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(i64::MAX);
-            let old_fc = existing
-                .get("forwarded_count")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(i64::MAX);
-            let new_fc = body
-                .get("forwarded_count")
-                .and_then(|v| v.as_i64())
-                .unwrap_or(i64::MAX);
-
-            // New key wins if: verified beats unverified, or lower first_message_index, or lower forwarded_count
-            if new_verified && !old_verified {
-                true
-            } else if !new_verified && old_verified {
-                false
-            } else if new_fmi < old_fmi {
-                true
-            } else if new_fmi > old_fmi {
-                false
+impl SigningKeys<'_> {
+    /// Check the signer's signatures on the key `key_id` of `user_id`, whose
+    /// cross-signing keys are `cross_keys`, and return them for storing.
+    fn check(
+        &self,
+        user_id: &str,
+        key_id: &str,
+        signed: &serde_json::Value,
+        cross_keys: &serde_json::Value,
+    ) -> Result<Vec<KeySignature>, MatrixError> {
+        let master_key = cross_keys
+            .get("master_key")
+            .filter(|key| cross_signing_key_id(key) == Some(key_id));
+
+        // The stored key, and the signer's keys that may sign it
+        let (stored, allowed) = if user_id == self.signer
+            && let Some(device) = self.own_devices.get(key_id)
+        {
+            (device, self.cross_signing_key("self_signing_key"))
+        } else if let Some(master_key) = master_key {
+            let allowed = if user_id == self.signer {
+                self.device_keys()
             } else {
-                new_fc < old_fc
-            }
+                self.cross_signing_key("user_signing_key")
+            };
+            (master_key, allowed)
         } else {
-            true // No existing key
+            return Err(MatrixError::not_found(format!(
+                "No key '{key_id}' of '{user_id}' to sign"
+            )));
         };
 
-    if should_replace {
-        let _ = state
-            .storage()
-            .set_account_data(&user_id, None, &key, &body)
-            .await;
-    }
-
-    Ok(Json(serde_json::json!({
-        "count": 1,
-        "etag": "1",
-    })))
-}
-
-/// GET /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId}
-async fn get_room_key(
-    State(state): State<AppState>,
-    auth: AuthenticatedUser,
-    axum::extract::Path((room_id, session_id)): axum::extract::Path<(String, String)>,
-) -> Result<Json<serde_json::Value>, MatrixError> {
-    let user_id = auth.user_id.to_string();
-    let ver = state
-        .storage()
-        .get_account_data(&user_id, None, "_maelstrom.key_backup_version")
-        .await
-        .ok()
-        .and_then(|v| v.get("version").and_then(|n| n.as_u64()))
-        .unwrap_or(0)
-        .to_string();
-
-    let key = format!("_maelstrom.room_key.{ver}.{room_id}.{session_id}");
-    let data = state
-        .storage()
-        .get_account_data(&user_id, None, &key)
-        .await
-        .map_err(|_| MatrixError::not_found("Key not found"))?;
+        if strip_signatures(signed) != strip_signatures(stored) {
+            return Err(invalid_signature(
+                "The signed object does not match the stored key",
+            ));
+        }
+        let canonical = CanonicalJson::from_value(signed)
+            .map_err(|_| MatrixError::bad_json("Signed object is not canonical JSON"))?;
+
+        let mut signatures = Vec::new();
+        for (signing_key_id, public_key) in allowed {
+            let Some(signature) = signed
+                .get("signatures")
+                .and_then(|s| s.get(self.signer))
+                .and_then(|s| s.get(&signing_key_id))
+                .and_then(|s| s.as_str())
+            else {
+                continue;
+            };
+            let verified = public_key_from_base64(&public_key).is_some_and(|public_key| {
+                verify_event_signature_canonical(
+                    &canonical,
+                    &public_key,
+                    self.signer,
+                    &signing_key_id,
+                )
+            });
+            if !verified {
+                return Err(invalid_signature(format!(
+                    "Signature by '{signing_key_id}' does not verify"
+                )));
+            }
+            signatures.push(KeySignature {
+                signed_user_id: user_id.to_string(),
+                signed_key_id: key_id.to_string(),
+                signing_user_id: self.signer.to_string(),
+                signing_key_id,
+                signature: signature.to_string(),
+            });
+        }
 
-    Ok(Json(data))
-}
+        if signatures.is_empty() {
+            return Err(invalid_signature(
+                "No signature by a key allowed to sign this key",
+            ));
+        }
+        Ok(signatures)
+    }
 
-/// PUT /_matrix/client/v3/room_keys/keys/{roomId}
-async fn put_room_keys(
-    State(_state): State<AppState>,
-    _auth: AuthenticatedUser,
-    axum::extract::Path(_room_id): axum::extract::Path<String>,
-    axum::Json(_body): axum::Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    Json(serde_json::json!({"count": 0, "etag": "0"}))
-}
+    /// The signer's cross-signing key of `key_type`, as `(key ID, public key)`.
+    fn cross_signing_key(&self, key_type: &str) -> Vec<(String, String)> {
+        self.own_cross_keys
+            .get(key_type)
+            .and_then(cross_signing_key_id)
+            .map(|public_key| (format!("ed25519:{public_key}"), public_key.to_string()))
+            .into_iter()
+            .collect()
+    }
 
-/// GET /_matrix/client/v3/room_keys/keys/{roomId}
-async fn get_room_keys(
-    _auth: AuthenticatedUser,
-    axum::extract::Path(_room_id): axum::extract::Path<String>,
-) -> Json<serde_json::Value> {
-    Json(serde_json::json!({"sessions": {}}))
+    /// The Ed25519 keys of the signer's devices, as `(key ID, public key)`.
+    fn device_keys(&self) -> Vec<(String, String)> {
+        let Some(devices) = self.own_devices.as_object() else {
+            return Vec::new();
+        };
+        devices
+            .iter()
+            .filter_map(|(device_id, device)| {
+                let key_id = format!("ed25519:{device_id}");
+                let public_key = device.get("keys")?.get(&key_id)?.as_str()?.to_string();
+                Some((key_id, public_key))
+            })
+            .collect()
+    }
 }
 
-/// PUT /_matrix/client/v3/room_keys/keys
-async fn put_all_room_keys(
-    _auth: AuthenticatedUser,
-    axum::Json(_body): axum::Json<serde_json::Value>,
-) -> Json<serde_json::Value> {
-    Json(serde_json::json!({"count": 0, "etag": "0"}))
+/// A key without its `signatures` and `unsigned` data, for comparing the
+/// signed object with the stored key.
+fn strip_signatures(key: &serde_json::Value) -> serde_json::Value {
+    let mut key = key.clone();
+    if let Some(obj) = key.as_object_mut() {
+        obj.remove("signatures");
+        obj.remove("unsigned");
+    }
+    key
 }
 
-/// GET /_matrix/client/v3/room_keys/keys
-async fn get_all_room_keys(_auth: AuthenticatedUser) -> Json<serde_json::Value> {
-    Json(serde_json::json!({"rooms": {}}))
+fn invalid_signature(msg: impl Into<String>) -> MatrixError {
+    MatrixError::new(
+        http::StatusCode::BAD_REQUEST,
+        ErrorCode::InvalidSignature,
+        msg,
+    )
 }
@@ -50,6 +50,7 @@ use serde::{Deserialize, Serialize};
 use tracing::debug;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_federation::policy::PolicyCheck;
 use maelstrom_storage::traits::MediaRecord;
 
 use crate::extractors::AuthenticatedUser;
@@ -253,7 +254,15 @@ async fn serve_media(
                 .map_err(|_| MatrixError::unknown("Failed to build response"))
         }
         Err(_) if server_name != state.server_name().as_str() => {
-            // Remote media — proxy from the origin server
+            // Remote media — proxy from the origin server, unless federation
+            // with it is blocked server-wide
+            if let Some(client) = state.federation()
+                && !client.policy().check(server_name, PolicyCheck::Media)
+            {
+                return Err(MatrixError::forbidden(format!(
+                    "Federation with {server_name} is not allowed by this server"
+                )));
+            }
             proxy_remote_media(server_name, media_id, filename_override).await
         }
         Err(e) => Err(crate::extractors::storage_error(e)),
@@ -11,9 +11,13 @@
 //! | [`account`] | Account data, deactivation, whoami |
 //! | [`rooms`] | Room creation, joining, leaving, state, sending events |
 //! | [`sync`] | The `/sync` long-poll endpoint -- the heart of the client API |
+//! | [`sliding_sync`] | Sliding sync (MSC3575) and simplified sliding sync (MSC4186) |
 //! | [`events`] | Fetching individual events and room context |
+//! | [`peek`] | Room previews of `world_readable` rooms (`initialSync`, `/events`) |
 //! | [`directory`] | Room directory (public room lists, room aliases) |
 //! | [`keys`] | End-to-end encryption key uploads, queries, and claims |
+//! | [`key_backup`] | Server-side backup of encrypted room keys |
+//! | [`dehydrated_device`] | Dehydrated devices that receive keys while the user is offline |
 //! | [`to_device`] | Device-to-device messaging (key sharing, verification) |
 //! | [`typing`] | Typing indicators |
 //! | [`receipts`] | Read receipts |
@@ -25,6 +29,7 @@
 //! | [`knock`] | Knock-to-join |
 //! | [`reporting`] | Reporting abusive content |
 //! | [`threads`] | Thread listing |
+//! | [`thread_subscriptions`] | Thread subscriptions (MSC4306) |
 //! | [`capabilities`] | Server capability advertisement |
 //! | [`versions`] | Supported spec versions |
 //! | [`wellknown`] | `.well-known` server/client discovery |
@@ -41,12 +46,15 @@ pub mod account;
 pub mod appservice;
 pub mod auth;
 pub mod capabilities;
+pub mod dehydrated_device;
 pub mod directory;
 pub mod events;
 pub mod health;
+pub mod key_backup;
 pub mod keys;
 pub mod knock;
 pub mod media;
+pub mod peek;
 pub mod presence;
 pub mod profile;
 pub mod receipts;
@@ -55,8 +63,10 @@ pub mod relations;
 pub mod reporting;
 pub mod rooms;
 pub mod search;
+pub mod sliding_sync;
 pub mod spaces;
 pub mod sync;
+pub mod thread_subscriptions;
 pub mod threads;
 pub mod to_device;
 pub mod typing;
@@ -0,0 +1,673 @@
+//! Room previews (peeking).
+//!
+//! Clients can look into a room before joining it when its history is
+//! `world_readable`: fetch a snapshot of its state and latest messages with
+//! `initialSync`, then follow new events with `/events?room_id=`. Members and
+//! former members can use both endpoints for their own rooms as well. What
+//! comes back is subject to the room's history visibility, like every other
+//! read path (see [`maelstrom_federation::visibility`]).
+//!
+//! When the room lives on another server and none of our users are in it,
+//! there are no local copies of its events. [`peek_remote_room`] then asks the
+//! room's server for a snapshot over federation (MSC2444) and stores the
+//! events that check out, so the local read paths can serve them. The
+//! snapshot is renewed once the `renewal_interval` its server asked for has
+//! passed. Such a room is recorded as `peek_only`: this server is not in it,
+//! so joining it still goes over federation, and its members are not taken
+//! into the membership index that decides whose EDUs we accept.
+//!
+//! # Endpoints
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `GET` | `/_matrix/client/v3/rooms/{roomId}/initialSync` | Snapshot of a room's state and latest messages |
+//! | `GET` | `/_matrix/client/v3/events` | Events since `from`, for one room (`room_id`) or all joined rooms |
+//!
+//! # Matrix spec
+//!
+//! * [Room previews](https://spec.matrix.org/v1.18/client-server-api/#room-previews)
+//! * [MSC2444: Peeking over federation](https://github.com/matrix-org/matrix-spec-proposals/pull/2444)
+
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use dashmap::DashMap;
+use serde::Deserialize;
+
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::event::Pdu;
+use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_core::matrix::json::CanonicalJson;
+use maelstrom_core::matrix::room::{EventIdFormat, Membership, RoomVersion, event_type as et};
+use maelstrom_core::matrix::signing;
+use maelstrom_federation::client::FederationClient;
+use maelstrom_federation::{key_server, visibility};
+
+use crate::extractors::{AuthenticatedUser, storage_error};
+use crate::state::AppState;
+
+/// How many of a remote room's latest events a federated peek backfills.
+const PEEK_BACKFILL_LIMIT: usize = 20;
+
+/// How long a peek lasts when the room's server does not say, in milliseconds.
+const DEFAULT_RENEWAL_INTERVAL_MS: u64 = 60 * 60 * 1000;
+
+/// When the peek into each remote room is due for renewal.
+static PEEKS: LazyLock<DashMap<String, Instant>> = LazyLock::new(DashMap::new);
+
+/// The longest `/events` waits for new events, in milliseconds.
+const MAX_EVENTS_TIMEOUT_MS: u64 = 60_000;
+
+/// How many events `/events` fetches from each room at a time.
+const EVENTS_LIMIT: usize = 100;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/_matrix/client/v3/rooms/{roomId}/initialSync",
+            get(room_initial_sync),
+        )
+        .route("/_matrix/client/v3/events", get(get_events))
+}
+
+/// Fetch a snapshot of a remote room none of our users are in, so a user who
+/// is not in it either can peek at it.
+///
+/// Rooms of this server, rooms some local user is in (their events arrive
+/// over federation anyway) and servers without federation are left alone.
+/// A snapshot is reused until the `renewal_interval` its server asked for
+/// has passed. Failing to reach the room's server only matters if we have no
+/// copy of the room at all.
+///
+/// Nothing from the snapshot is stored before it checks out: every event must
+/// be signed by its sender's server, and its auth events must be events that
+/// checked out too (or that we already had). The room's current state is only
+/// taken from such events.
+pub(crate) async fn peek_remote_room(state: &AppState, room_id: &str) -> Result<(), MatrixError> {
+    let storage = state.storage();
+    let room_server = server_name_from_sigil_id(room_id);
+    if room_server.is_empty() || room_server == state.server_name().as_str() {
+        return Ok(());
+    }
+    let local_members = storage
+        .get_room_members(room_id, Membership::Join.as_str())
+        .await
+        .unwrap_or_default();
+    if local_members
+        .iter()
+        .any(|member| server_name_from_sigil_id(member) == state.server_name().as_str())
+    {
+        return Ok(());
+    }
+    if PEEKS
+        .get(room_id)
+        .is_some_and(|renew_at| *renew_at > Instant::now())
+    {
+        return Ok(());
+    }
+    let Some(fed) = state.federation() else {
+        return Ok(());
+    };
+    let have_copy = storage
+        .get_state_event(room_id, et::CREATE, "")
+        .await
+        .is_ok();
+    let unavailable = || {
+        if have_copy {
+            Ok(())
+        } else {
+            Err(MatrixError::forbidden("Cannot peek into this room"))
+        }
+    };
+
+    let path = format!(
+        "/_matrix/federation/unstable/org.matrix.msc2444/peek/{}/{}",
+        crate::handlers::util::percent_encode(room_id),
+        crate::handlers::util::generate_session_id(),
+    );
+    let snapshot = match fed
+        .put_json(room_server, &path, &serde_json::json!({}))
+        .await
+    {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::debug!(room_id, error = %e, "Federated peek failed");
+            return unavailable();
+        }
+    };
+
+    let version = snapshot
+        .get("room_version")
+        .and_then(|v| v.as_str())
+        .unwrap_or("1")
+        .to_string();
+    let Some(room_version) = RoomVersion::parse(&version) else {
+        tracing::debug!(room_id, version, "Peeked room has an unsupported version");
+        return unavailable();
+    };
+
+    let pdus = |key: &str| -> Vec<serde_json::Value> {
+        snapshot
+            .get(key)
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default()
+    };
+    let mut verifier = PeekVerifier::new(storage, fed, room_id, room_version);
+    let auth_and_state = verifier
+        .accept(
+            pdus("auth_chain")
+                .into_iter()
+                .chain(pdus("state"))
+                .collect(),
+        )
+        .await;
+    let state_ids: HashSet<String> = pdus("state").iter().filter_map(pdu_event_id).collect();
+    let room_state: Vec<&serde_json::Value> = auth_and_state
+        .iter()
+        .filter(|e| {
+            e.get("event_id")
+                .and_then(|i| i.as_str())
+                .is_some_and(|event_id| state_ids.contains(event_id))
+        })
+        .collect();
+    if !room_state
+        .iter()
+        .any(|e| e.get("type").and_then(|t| t.as_str()) == Some(et::CREATE))
+    {
+        tracing::debug!(room_id, "Peeked room state did not check out");
+        return unavailable();
+    }
+
+    let mut timeline: Vec<serde_json::Value> =
+        snapshot.get("latest_event").into_iter().cloned().collect();
+    if let Some(latest_id) = timeline.first().and_then(pdu_event_id) {
+        let path = format!(
+            "/_matrix/federation/v1/backfill/{}?limit={}&v={}",
+            crate::handlers::util::percent_encode(room_id),
+            PEEK_BACKFILL_LIMIT,
+            crate::handlers::util::percent_encode(&latest_id),
+        );
+        match fed.get(room_server, &path).await {
+            Ok(response) => timeline.extend(
+                response
+                    .get("pdus")
+                    .and_then(|p| p.as_array())
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            Err(e) => tracing::debug!(room_id, error = %e, "Peek backfill failed"),
+        }
+    }
+    timeline.sort_by_key(|e| e.get("depth").and_then(|d| d.as_i64()).unwrap_or(0));
+    let timeline = verifier.accept(timeline).await;
+
+    let _ = storage
+        .create_room(&maelstrom_storage::traits::RoomRecord {
+            room_id: room_id.to_string(),
+            version,
+            creator: String::new(),
+            is_direct: false,
+            peek_only: true,
+        })
+        .await;
+    // The state first, so the messages after it are judged by it
+    for event_json in auth_and_state.iter().chain(&timeline) {
+        store_peeked_event(storage, room_id, event_json).await;
+    }
+
+    // Older state events in the timeline must not win over the current state
+    for event_json in room_state {
+        let field = |key: &str| event_json.get(key).and_then(|v| v.as_str());
+        if let (Some(event_id), Some(event_type), Some(state_key)) =
+            (field("event_id"), field("type"), field("state_key"))
+        {
+            let _ = storage
+                .set_room_state(room_id, event_type, state_key, event_id)
+                .await;
+        }
+    }
+
+    let renewal_interval = snapshot
+        .get("renewal_interval")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(DEFAULT_RENEWAL_INTERVAL_MS);
+    let now = Instant::now();
+    PEEKS.retain(|_, renew_at| *renew_at > now);
+    PEEKS.insert(
+        room_id.to_string(),
+        now + Duration::from_millis(renewal_interval),
+    );
+    Ok(())
+}
+
+/// The ID of a PDU: given, or its reference hash for room versions that
+/// leave it out.
+fn pdu_event_id(pdu: &serde_json::Value) -> Option<String> {
+    match pdu.get("event_id") {
+        Some(event_id) => event_id.as_str().map(str::to_string),
+        None => CanonicalJson::from_value(pdu)
+            .ok()
+            .map(|canonical| signing::reference_hash_canonical(&canonical)),
+    }
+}
+
+/// The IDs of a PDU's auth events, in either the `[id, hashes]` form of
+/// room versions 1 and 2 or the plain form of later ones.
+fn auth_event_ids(pdu: &serde_json::Value) -> Vec<&str> {
+    pdu.get("auth_events")
+        .and_then(|a| a.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|a| a.as_str().or_else(|| a.get(0)?.as_str()))
+        .collect()
+}
+
+/// Checks the events of a peeked room before any of them is stored.
+struct PeekVerifier<'a> {
+    storage: &'a dyn maelstrom_storage::traits::Storage,
+    fed: &'a FederationClient,
+    room_id: &'a str,
+    room_version: RoomVersion,
+    /// Current public keys looked up so far, by server and key ID.
+    keys: HashMap<(String, String), Option<[u8; 32]>>,
+    /// Events that checked out.
+    accepted: HashSet<String>,
+}
+
+impl<'a> PeekVerifier<'a> {
+    fn new(
+        storage: &'a dyn maelstrom_storage::traits::Storage,
+        fed: &'a FederationClient,
+        room_id: &'a str,
+        room_version: RoomVersion,
+    ) -> Self {
+        Self {
+            storage,
+            fed,
+            room_id,
+            room_version,
+            keys: HashMap::new(),
+            accepted: HashSet::new(),
+        }
+    }
+
+    /// The events of `pdus` that check out, oldest first and with their
+    /// `event_id` filled in.
+    async fn accept(&mut self, pdus: Vec<serde_json::Value>) -> Vec<serde_json::Value> {
+        let mut pending = Vec::new();
+        for mut pdu in pdus {
+            let Some(event_id) = pdu_event_id(&pdu) else {
+                continue;
+            };
+            if self.accepted.contains(&event_id)
+                || pdu.get("room_id").and_then(|r| r.as_str()) != Some(self.room_id)
+                || !self.signed(&pdu).await
+            {
+                continue;
+            }
+            pdu["event_id"] = serde_json::Value::String(event_id.clone());
+            pending.push((event_id, pdu));
+        }
+
+        // As many passes as the auth chain is deep
+        let mut accepted = Vec::new();
+        loop {
+            let mut progress = false;
+            let mut rest = Vec::new();
+            for (event_id, pdu) in pending {
+                if self.authorised(&pdu).await {
+                    self.accepted.insert(event_id);
+                    accepted.push(pdu);
+                    progress = true;
+                } else {
+                    rest.push((event_id, pdu));
+                }
+            }
+            pending = rest;
+            if !progress || pending.is_empty() {
+                break;
+            }
+        }
+        if !pending.is_empty() {
+            tracing::debug!(
+                room_id = self.room_id,
+                dropped = pending.len(),
+                "Dropping peeked events with unknown auth events"
+            );
+        }
+        // Oldest first, so they are stored in the order they happened
+        accepted.sort_by_key(|pdu| pdu.get("depth").and_then(|d| d.as_i64()).unwrap_or(0));
+        accepted
+    }
+
+    /// Whether the PDU is signed by its sender's server.
+    async fn signed(&mut self, pdu: &serde_json::Value) -> bool {
+        let Some(sender) = pdu.get("sender").and_then(|s| s.as_str()) else {
+            return false;
+        };
+        let server = server_name_from_sigil_id(sender);
+        let key_ids: Vec<String> = pdu
+            .get("signatures")
+            .and_then(|s| s.get(server))
+            .and_then(|s| s.as_object())
+            .map(|sigs| sigs.keys().cloned().collect())
+            .unwrap_or_default();
+        // Servers that derive event IDs from the event do not sign the ID
+        let without_id = (self.room_version.event_id_format() == EventIdFormat::ReferenceHash)
+            .then(|| {
+                let mut stripped = pdu.clone();
+                stripped.as_object_mut()?.remove("event_id");
+                Some(stripped)
+            })
+            .flatten();
+        let signed_at = pdu.get("origin_server_ts").and_then(|t| t.as_i64());
+        for key_id in key_ids {
+            let current = match self.keys.get(&(server.to_string(), key_id.clone())) {
+                Some(key) => *key,
+                None => {
+                    let key =
+                        key_server::fetch_verify_key(self.storage, self.fed, server, &key_id, None)
+                            .await;
+                    self.keys.insert((server.to_string(), key_id.clone()), key);
+                    key
+                }
+            };
+            // An old key only verifies what it signed before it expired
+            let key = match (current, signed_at) {
+                (Some(key), _) => Some(key),
+                (None, Some(_)) => {
+                    key_server::fetch_verify_key(self.storage, self.fed, server, &key_id, signed_at)
+                        .await
+                }
+                (None, None) => None,
+            };
+            if let Some(key) = key
+                && std::iter::once(pdu)
+                    .chain(&without_id)
+                    .any(|e| signing::verify_event_signature(e, &key, server, &key_id))
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether all of the PDU's auth events checked out or are stored
+    /// already. Only the create event has none.
+    async fn authorised(&self, pdu: &serde_json::Value) -> bool {
+        let auth_events = auth_event_ids(pdu);
+        if auth_events.is_empty() {
+            return pdu.get("type").and_then(|t| t.as_str()) == Some(et::CREATE)
+                && pdu.get("state_key").and_then(|k| k.as_str()) == Some("");
+        }
+        for event_id in auth_events {
+            if !self.accepted.contains(event_id) && self.storage.get_event(event_id).await.is_err()
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Store an event of a peeked room, unless we already have it.
+async fn store_peeked_event(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    room_id: &str,
+    event_json: &serde_json::Value,
+) {
+    let Some(event_id) = event_json.get("event_id").and_then(|e| e.as_str()) else {
+        return;
+    };
+    if event_json.get("room_id").and_then(|r| r.as_str()) == Some(room_id)
+        && storage.get_event(event_id).await.is_err()
+    {
+        crate::handlers::rooms::store_federation_event(storage, event_json).await;
+    }
+}
+
+/// The user's current membership in the room, with the position it took
+/// effect at.
+async fn current_membership(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    room_id: &str,
+) -> Option<(String, i64)> {
+    let member_event = storage
+        .get_state_event(room_id, et::MEMBER, user_id)
+        .await
+        .ok()?;
+    let membership = member_event
+        .content
+        .get("membership")?
+        .as_str()?
+        .to_string();
+    Some((membership, member_event.stream_position))
+}
+
+/// Make sure the user may read the room, peeking at it over federation first
+/// if they are not in it.
+async fn require_readable(
+    state: &AppState,
+    user_id: &str,
+    room_id: &str,
+) -> Result<Option<(String, i64)>, MatrixError> {
+    let storage = state.storage();
+    let membership = current_membership(storage, user_id, room_id).await;
+    if membership.is_none() {
+        peek_remote_room(state, room_id).await?;
+    }
+    if !visibility::user_may_read_room(storage, user_id, room_id).await {
+        return Err(MatrixError::forbidden(
+            "You are not in this room and it is not world_readable",
+        ));
+    }
+    Ok(membership)
+}
+
+/// The room's state as it was at `position`, given its current state. State
+/// set only later is left out.
+async fn state_at(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    room_id: &str,
+    current_state: Vec<Pdu>,
+    position: i64,
+) -> Vec<Pdu> {
+    let mut state = Vec::new();
+    for event in current_state {
+        if event.stream_position <= position {
+            state.push(event);
+        } else if let Some(state_key) = &event.state_key
+            && let Ok(earlier) = storage
+                .get_state_event_at(room_id, &event.event_type, state_key, position)
+                .await
+        {
+            state.push(earlier);
+        }
+    }
+    state
+}
+
+/// Timeline events, as in `/messages`: messages and membership changes.
+fn is_timeline_event(event: &Pdu) -> bool {
+    !event.is_state() || event.event_type == et::MEMBER
+}
+
+// -- GET /rooms/{roomId}/initialSync --
+
+#[derive(Deserialize)]
+struct InitialSyncQuery {
+    limit: Option<usize>,
+}
+
+/// GET /_matrix/client/v3/rooms/{roomId}/initialSync
+///
+/// The room's state (as of leaving, for departed users) and its latest
+/// messages the user may see, with tokens to paginate back and to follow
+/// the room with `/events`.
+async fn room_initial_sync(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<InitialSyncQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+    let membership = require_readable(&state, &user_id, &room_id).await?;
+
+    let current = storage
+        .current_stream_position()
+        .await
+        .map_err(storage_error)?;
+    let mut room_state = storage
+        .get_current_state(&room_id)
+        .await
+        .map_err(storage_error)?;
+    if let Some((m, left_at)) = &membership
+        && (m == Membership::Leave.as_str() || m == Membership::Ban.as_str())
+    {
+        room_state = state_at(storage, &room_id, room_state, *left_at).await;
+    }
+
+    let limit = query.limit.unwrap_or(10).min(100);
+    let candidates: Vec<Pdu> = storage
+        .get_room_events(&room_id, current + 1, limit * 2 + 10, "b")
+        .await
+        .map_err(storage_error)?
+        .into_iter()
+        .filter(is_timeline_event)
+        .collect();
+    let mut chunk = visibility::filter_events_for_user(storage, &user_id, candidates).await;
+    chunk.truncate(limit);
+    chunk.reverse();
+    let start = chunk.first().map_or(current, |e| e.stream_position);
+
+    let account_data: Vec<serde_json::Value> = storage
+        .get_all_room_account_data(&user_id, &room_id)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(data_type, content)| serde_json::json!({"type": data_type, "content": content}))
+        .collect();
+
+    let mut response = serde_json::json!({
+        "room_id": room_id,
+        "messages": {
+            "chunk": chunk.iter().map(|e| e.to_client_event().into_json()).collect::<Vec<_>>(),
+            "start": start.to_string(),
+            "end": current.to_string(),
+        },
+        "state": room_state.iter().map(|e| e.to_client_event().into_json()).collect::<Vec<_>>(),
+        "presence": [],
+        "account_data": account_data,
+    });
+    if let Some((m, _)) = membership {
+        response["membership"] = serde_json::json!(m);
+    }
+    Ok(Json(response))
+}
+
+// -- GET /events --
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    room_id: Option<String>,
+    from: Option<String>,
+    timeout: Option<u64>,
+}
+
+/// GET /_matrix/client/v3/events
+///
+/// The events after `from` (default: now) in `room_id`, which the user may
+/// peek at, or in all their joined rooms. Waits up to `timeout` ms for
+/// something to arrive.
+async fn get_events(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<EventsQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+
+    let rooms = match &query.room_id {
+        Some(room_id) => {
+            require_readable(&state, &user_id, room_id).await?;
+            vec![room_id.clone()]
+        }
+        None => storage
+            .get_joined_rooms(&user_id)
+            .await
+            .map_err(storage_error)?,
+    };
+    let from = match query.from.as_deref() {
+        Some(token) => token
+            .parse::<i64>()
+            .map_err(|_| MatrixError::bad_json("Invalid from token"))?,
+        None => storage
+            .current_stream_position()
+            .await
+            .map_err(storage_error)?,
+    };
+    let timeout = query.timeout.unwrap_or(0).min(MAX_EVENTS_TIMEOUT_MS);
+
+    // Subscribe before looking, so nothing slips in between
+    let mut rx = if timeout > 0 {
+        Some(state.notifier().subscribe(&rooms, None).await)
+    } else {
+        None
+    };
+    let (mut chunk, mut end) = events_since(storage, &user_id, &rooms, from).await?;
+    if chunk.is_empty()
+        && let Some(mut rx) = rx.take()
+    {
+        tokio::select! {
+            _ = rx.recv() => {}
+            _ = tokio::time::sleep(Duration::from_millis(timeout)) => {}
+        }
+        (chunk, end) = events_since(storage, &user_id, &rooms, from).await?;
+    }
+
+    Ok(Json(serde_json::json!({
+        "chunk": chunk.iter().map(|e| e.to_client_event().into_json()).collect::<Vec<_>>(),
+        "start": from.to_string(),
+        "end": end.to_string(),
+    })))
+}
+
+/// The events after `from` in the rooms that the user may see, oldest first,
+/// and the position they run up to.
+///
+/// A room with more new events than one fetch returns cuts the chunk off at
+/// its last fetched event, so no other room's later events carry the end
+/// past the ones it has yet to send.
+async fn events_since(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    rooms: &[String],
+    from: i64,
+) -> Result<(Vec<Pdu>, i64), MatrixError> {
+    let mut events = Vec::new();
+    let mut cutoff = i64::MAX;
+    for room_id in rooms {
+        let room_events = storage
+            .get_room_events(room_id, from, EVENTS_LIMIT, "f")
+            .await
+            .map_err(storage_error)?;
+        if room_events.len() >= EVENTS_LIMIT
+            && let Some(last) = room_events.iter().map(|e| e.stream_position).max()
+        {
+            cutoff = cutoff.min(last);
+        }
+        events.extend(room_events);
+    }
+    events.retain(|e| e.stream_position <= cutoff);
+    events.sort_by_key(|e| e.stream_position);
+    let end = events.last().map_or(from, |e| e.stream_position);
+    let events = visibility::filter_events_for_user(storage, user_id, events).await;
+    Ok((events, end))
+}
@@ -12,7 +12,10 @@
 //! string like "In a meeting").
 //!
 //! Presence is ephemeral and held in memory; it is delivered to other users via
-//! the `presence` section of `/sync`.
+//! the `presence` section of `/sync`.  Besides the explicit `PUT` below, it is
+//! driven by client activity, `/sync` and idle/offline timers -- see
+//! [`crate::presence`].  With presence turned off server-wide, `PUT` is
+//! accepted but ignored and every user reads as `offline`.
 //!
 //! # Endpoints
 //!
@@ -31,9 +34,10 @@ use axum::{Json, Router};
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::event::timestamp_ms;
 
 use crate::extractors::{AuthenticatedUser, MatrixJson};
-use crate::notify::Notification;
+use crate::presence::{broadcast, presence_content};
 use crate::state::AppState;
 
 pub fn routes() -> Router<AppState> {
@@ -50,26 +54,12 @@ async fn get_presence(
     _auth: AuthenticatedUser,
     Path(user_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
-    match state.ephemeral().get_presence(&user_id) {
-        Some(record) => {
-            let now_ms = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_millis() as u64;
-
-            let last_active_ago = now_ms.saturating_sub(record.last_active_ts);
-
-            let mut response = serde_json::json!({
-                "presence": record.status,
-                "last_active_ago": last_active_ago,
-            });
-
-            if let Some(msg) = &record.status_msg {
-                response["status_msg"] = serde_json::Value::String(msg.clone());
-            }
-
-            Ok(Json(response))
-        }
+    let record = state
+        .ephemeral()
+        .get_presence(&user_id)
+        .filter(|_| state.ephemeral().presence_mode().enabled());
+    match record {
+        Some(record) => Ok(Json(presence_content(&record, timestamp_ms()))),
         None => {
             // Return default "offline" presence for users without explicit presence
             Ok(Json(serde_json::json!({
@@ -112,45 +102,15 @@ async fn set_presence(
         }
     }
 
+    if !state.ephemeral().presence_mode().enabled() {
+        return Ok(Json(serde_json::json!({})));
+    }
+
     state
         .ephemeral()
         .set_presence(&sender, &body.presence, body.status_msg.as_deref());
-
-    state
-        .notifier()
-        .notify(Notification::Presence {
-            user_id: sender.clone(),
-        })
-        .await;
-
-    // Send presence EDU to remote servers sharing rooms with this user
-    if let Some(tx_sender) = state.transaction_sender() {
-        let remote_servers = crate::handlers::util::servers_sharing_rooms(
-            state.storage(),
-            &sender,
-            state.server_name().as_str(),
-        )
-        .await;
-        for server in remote_servers {
-            let mut entry = serde_json::json!({
-                "user_id": sender,
-                "presence": body.presence,
-                "last_active_ago": 0,
-                "currently_active": body.presence == "online",
-            });
-            if let Some(ref msg) = body.status_msg {
-                entry["status_msg"] = serde_json::Value::String(msg.clone());
-            }
-            tx_sender.queue_edu(
-                &server,
-                serde_json::json!({
-                    "edu_type": "m.presence",
-                    "content": {
-                        "push": [entry],
-                    },
-                }),
-            );
-        }
+    if let Some(record) = state.ephemeral().get_presence(&sender) {
+        broadcast(&state, &record).await;
     }
 
     Ok(Json(serde_json::json!({})))
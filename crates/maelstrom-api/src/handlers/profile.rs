@@ -42,7 +42,7 @@ use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::event::{Pdu, generate_event_id, timestamp_ms};
 use maelstrom_core::matrix::id::UserId;
 use maelstrom_core::matrix::room::{Membership, event_type as et};
-use maelstrom_storage::traits::StorageError;
+use maelstrom_storage::traits::{DirectoryNetwork, StorageError};
 
 use crate::extractors::{AuthenticatedUser, MatrixJson};
 use crate::notify::Notification;
@@ -313,7 +313,7 @@ async fn search_user_directory(
 
     // Cache public room IDs for visibility checks.
     let public_room_ids: std::collections::HashSet<String> = storage
-        .get_public_rooms(1000, None, None)
+        .get_public_rooms(1000, None, None, DirectoryNetwork::Matrix)
         .await
         .map(|(rooms, _)| rooms.into_iter().map(|r| r.room_id).collect())
         .unwrap_or_default();
@@ -13,6 +13,12 @@
 //! persistent event DAG, though the server stores the latest receipt per-user
 //! to include in future `/sync` responses.
 //!
+//! A receipt may carry a `thread_id` (`"main"` or a thread root) to track
+//! reading per thread; it travels with the receipt to `/sync`, application
+//! services and remote servers.  Only `m.read` receipts are shared: private
+//! receipts are never sent over federation or to application services, and
+//! `/sync` shows them to their owner only.
+//!
 //! # Endpoints
 //!
 //! | Method | Path | Description |
@@ -29,14 +35,17 @@ use axum::{Json, Router};
 
 use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::event::timestamp_ms;
-use maelstrom_core::matrix::id::server_name_from_sigil_id;
 use maelstrom_core::matrix::room::Membership;
 
+use crate::appservice_sender;
 use crate::extractors::AuthenticatedUser;
-use crate::handlers::util::require_membership;
+use crate::handlers::util::{remote_servers_in_room, require_membership};
 use crate::notify::Notification;
 use crate::state::AppState;
 
+/// The only receipt type shared beyond the local server.
+const PUBLIC_RECEIPT: &str = "m.read";
+
 pub fn routes() -> Router<AppState> {
     Router::new().route(
         "/_matrix/client/v3/rooms/{roomId}/receipt/{receiptType}/{eventId}",
@@ -80,23 +89,29 @@ async fn send_receipt(
         })
         .await;
 
+    if receipt_type != PUBLIC_RECEIPT {
+        return Ok(Json(serde_json::json!({})));
+    }
+
+    let mut receipt_data = serde_json::json!({ "ts": timestamp_ms() });
+    if !thread_id.is_empty() {
+        receipt_data["thread_id"] = serde_json::Value::String(thread_id.to_string());
+    }
+    appservice_sender::queue_room_ephemeral(
+        &state,
+        &room_id,
+        serde_json::json!({
+            "type": "m.receipt",
+            "room_id": room_id,
+            "content": { &event_id: { &receipt_type: { &sender: receipt_data.clone() } } },
+        }),
+    )
+    .await;
+
     // Queue m.receipt EDU to remote servers that share this room
     if let Some(tx_sender) = state.transaction_sender() {
-        let local_server = state.server_name().as_str();
-        let mut remote_servers = std::collections::HashSet::new();
-        if let Ok(members) = storage
-            .get_room_members(&room_id, Membership::Join.as_str())
-            .await
-        {
-            for member in members {
-                let server = server_name_from_sigil_id(&member);
-                if !server.is_empty() && server != local_server {
-                    remote_servers.insert(server.to_string());
-                }
-            }
-        }
-
-        let ts = timestamp_ms();
+        let remote_servers =
+            remote_servers_in_room(storage, &room_id, state.server_name().as_str()).await;
         for server in remote_servers {
             tx_sender.queue_edu(
                 &server,
@@ -107,7 +122,7 @@ async fn send_receipt(
                             &receipt_type: {
                                 &sender: {
                                     "event_ids": [&event_id],
-                                    "data": { "ts": ts }
+                                    "data": receipt_data,
                                 }
                             }
                         }
@@ -18,6 +18,14 @@
 //! which amounts to open registration -- the client just re-sends the request
 //! with `auth: { "type": "m.login.dummy" }`.
 //!
+//! # Application services
+//!
+//! An application service registers users in its namespaces by sending its
+//! `as_token` with `type: "m.login.application_service"` (top-level or as the
+//! `auth` type).  This skips UIA, and the username must be inside the AS's
+//! user namespaces.  Everyone else is refused usernames that an application
+//! service has claimed exclusively (`400 M_EXCLUSIVE`).
+//!
 //! # Username validation
 //!
 //! Usernames (localparts) must:
@@ -55,10 +63,13 @@ use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::id::{DeviceId, UserId};
 use maelstrom_storage::traits::{DeviceRecord, UserRecord};
 
-use crate::extractors::MatrixJson;
+use crate::extractors::{AuthenticatedAppService, MatrixJson};
 use crate::handlers::util;
 use crate::state::AppState;
 
+/// Registration and login type used by application services.
+pub(crate) const APPSERVICE_LOGIN_TYPE: &str = "m.login.application_service";
+
 /// Register all registration and username-availability routes.
 ///
 /// Routes:
@@ -94,6 +105,8 @@ async fn get_available(
     Query(query): Query<AvailableQuery>,
 ) -> Result<Json<AvailableResponse>, MatrixError> {
     validate_username(&query.username)?;
+    let user_id = UserId::new(&query.username, state.server_name());
+    crate::appservice::check_exclusive_user(state.storage(), user_id.as_ref(), None).await?;
 
     let exists = state
         .storage()
@@ -118,6 +131,8 @@ async fn get_available(
 /// When `inhibit_login` is true, no device or access token is created.
 #[derive(Deserialize)]
 struct RegisterRequest {
+    #[serde(rename = "type")]
+    login_type: Option<String>,
     auth: Option<AuthData>,
     username: Option<String>,
     password: Option<String>,
@@ -150,10 +165,23 @@ struct RegisterResponse {
 
 async fn post_register(
     State(state): State<AppState>,
+    appservice: Option<AuthenticatedAppService>,
     MatrixJson(body): MatrixJson<RegisterRequest>,
 ) -> Result<impl IntoResponse, MatrixError> {
+    let appservice_registration = body.login_type.as_deref() == Some(APPSERVICE_LOGIN_TYPE)
+        || body
+            .auth
+            .as_ref()
+            .is_some_and(|auth| auth.auth_type == APPSERVICE_LOGIN_TYPE);
+    let appservice = match appservice {
+        Some(AuthenticatedAppService { appservice }) if appservice_registration => Some(appservice),
+        None if appservice_registration => return Err(MatrixError::missing_token()),
+        _ => None,
+    };
+
     // Check if UIA is needed
     let auth_completed = match &body.auth {
+        _ if appservice.is_some() => true,
         Some(auth) if auth.auth_type == "m.login.dummy" => true,
         Some(_) => {
             return Err(MatrixError::new(
@@ -182,9 +210,31 @@ async fn post_register(
             validate_username(&lowered)?;
             lowered
         }
+        None if appservice.is_some() => {
+            return Err(MatrixError::bad_json("Missing username"));
+        }
         None => util::generate_localpart(),
     };
 
+    let user_id = UserId::new(&username, state.server_name());
+    match &appservice {
+        Some(appservice) => {
+            if !crate::appservice::is_appservice_user(
+                appservice,
+                user_id.as_ref(),
+                state.server_name().as_str(),
+            ) {
+                return Err(MatrixError::exclusive(
+                    "User ID is not in the application service's namespaces",
+                ));
+            }
+        }
+        None => {
+            crate::appservice::check_exclusive_user(state.storage(), user_id.as_ref(), None)
+                .await?;
+        }
+    }
+
     // Check availability
     let exists = state
         .storage()
@@ -228,8 +278,6 @@ async fn post_register(
         .await
         .map_err(crate::extractors::storage_error)?;
 
-    let user_id = UserId::new(&username, state.server_name());
-
     if body.inhibit_login {
         let response = RegisterResponse {
             user_id: user_id.to_string(),
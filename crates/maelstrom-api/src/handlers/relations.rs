@@ -15,7 +15,8 @@
 //! * **`m.reference`** -- generic references (e.g. verification events).
 //!
 //! The endpoints support filtering by relation type and/or event type, and
-//! return paginated results.
+//! return paginated results. Both the parent and the returned events are
+//! subject to the room's history visibility.
 //!
 //! # Endpoints
 //!
@@ -36,6 +37,7 @@ use axum::{Json, Router};
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_federation::visibility;
 
 use crate::extractors::AuthenticatedUser;
 use crate::state::AppState;
@@ -104,18 +106,17 @@ struct RelationsByTypeAndEventParams {
 /// GET /rooms/{roomId}/relations/{eventId} — all relations.
 async fn get_relations(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     Path(params): Path<RelationsParams>,
     Query(query): Query<RelationsQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     fetch_relations(
         &state,
+        auth.user_id.as_ref(),
         &params.event_id,
         None,
         None,
-        query.limit,
-        query.from.as_deref(),
-        query.dir.as_deref(),
+        &query,
     )
     .await
 }
@@ -123,18 +124,17 @@ async fn get_relations(
 /// GET /rooms/{roomId}/relations/{eventId}/{relType}
 async fn get_relations_by_type(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     Path(params): Path<RelationsByTypeParams>,
     Query(query): Query<RelationsQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     fetch_relations(
         &state,
+        auth.user_id.as_ref(),
         &params.event_id,
         Some(&params.rel_type),
         None,
-        query.limit,
-        query.from.as_deref(),
-        query.dir.as_deref(),
+        &query,
     )
     .await
 }
@@ -142,51 +142,59 @@ async fn get_relations_by_type(
 /// GET /rooms/{roomId}/relations/{eventId}/{relType}/{eventType}
 async fn get_relations_by_type_and_event_type(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     Path(params): Path<RelationsByTypeAndEventParams>,
     Query(query): Query<RelationsQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     fetch_relations(
         &state,
+        auth.user_id.as_ref(),
         &params.event_id,
         Some(&params.rel_type),
         Some(&params.event_type),
-        query.limit,
-        query.from.as_deref(),
-        query.dir.as_deref(),
+        &query,
     )
     .await
 }
 
 async fn fetch_relations(
     state: &AppState,
+    user_id: &str,
     event_id: &str,
     rel_type: Option<&str>,
     event_type: Option<&str>,
-    limit: usize,
-    from: Option<&str>,
-    dir: Option<&str>,
+    query: &RelationsQuery,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
-    let effective_limit = limit.min(100);
-    let dir = dir.unwrap_or("b"); // default: newest first (backward)
-    let from_pos: Option<i64> = from.and_then(|s| s.parse().ok());
+    let effective_limit = query.limit.min(100);
+    let dir = query.dir.as_deref().unwrap_or("b"); // default: newest first (backward)
+    let from_pos: Option<i64> = query.from.as_deref().and_then(|s| s.parse().ok());
+
+    let storage = state.storage();
+    if let Ok(parent) = storage.get_event(event_id).await
+        && !visibility::event_visible_to_user(storage, user_id, &parent).await
+    {
+        return Err(MatrixError::not_found("Event not found"));
+    }
 
     // Fetch all relations (no cursor at storage level — we paginate in handler)
-    let relations = state
-        .storage()
+    let relations = storage
         .get_relations(event_id, rel_type, event_type, 1000, None)
         .await
         .map_err(crate::extractors::storage_error)?;
 
-    // Fetch the actual events and their stream positions
-    let mut events_with_pos: Vec<(serde_json::Value, i64)> = Vec::new();
+    // Fetch the actual events the user may see, and their stream positions
+    let mut related = Vec::new();
     for rel in &relations {
-        if let Ok(event) = state.storage().get_event(&rel.event_id).await {
-            let pos = event.stream_position;
-            let json = event.to_client_event().into_json();
-            events_with_pos.push((json, pos));
+        if let Ok(event) = storage.get_event(&rel.event_id).await {
+            related.push(event);
         }
     }
+    let mut events_with_pos: Vec<(serde_json::Value, i64)> =
+        visibility::filter_events_for_user(storage, user_id, related)
+            .await
+            .into_iter()
+            .map(|event| (event.to_client_event().into_json(), event.stream_position))
+            .collect();
 
     // Sort by stream_position according to direction
     if dir == "f" {
@@ -244,6 +244,16 @@ async fn create_room(
         ));
     }
 
+    if let Some(alias_name) = &body.room_alias_name {
+        let full_alias = format!("#{}:{}", alias_name, server_name);
+        crate::appservice::check_exclusive_alias(
+            state.storage(),
+            &full_alias,
+            auth.appservice_id.as_deref(),
+        )
+        .await?;
+    }
+
     let preset = body.preset.as_deref().unwrap_or("private_chat");
     let (join_rule, history_visibility) = match preset {
         "public_chat" => (JoinRule::Public.as_str(), "shared"),
@@ -257,6 +267,7 @@ async fn create_room(
         version: room_version.clone(),
         creator: sender.clone(),
         is_direct: body.is_direct,
+        peek_only: false,
     };
 
     state
@@ -533,8 +544,12 @@ async fn do_join(
     let storage = state.storage();
     let sender = auth.user_id.to_string();
 
-    // Check if room exists locally
-    let room_exists_locally = storage.get_room(room_id).await.is_ok();
+    // Check if room exists locally. A room we only peeked into is not ours
+    // to join locally: the room's servers must hear of the join.
+    let room_exists_locally = storage
+        .get_room(room_id)
+        .await
+        .is_ok_and(|room| !room.peek_only);
 
     if !room_exists_locally {
         // Room not local — attempt federation join
@@ -725,6 +740,7 @@ async fn do_federation_join(
         version: room_version,
         creator: String::new(), // Will be filled from state
         is_direct: false,
+        peek_only: false,
     };
     // Create room (ignore if already exists from race)
     let _ = storage.create_room(&room_record).await;
@@ -796,7 +812,7 @@ async fn do_federation_join(
 }
 
 /// Store a federation event (from send_join state/auth_chain) into local storage.
-async fn store_federation_event(
+pub(crate) async fn store_federation_event(
     storage: &dyn maelstrom_storage::traits::Storage,
     event_json: &serde_json::Value,
 ) {
@@ -1567,6 +1583,7 @@ async fn upgrade_room(
         version: body.new_version.clone(),
         creator: sender.clone(),
         is_direct: false,
+        peek_only: false,
     };
 
     storage
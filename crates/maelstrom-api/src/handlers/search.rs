@@ -1,10 +1,12 @@
 //! Server-side message search.
 //!
 //! Provides full-text search over room events. Clients submit a search term and
-//! optional filters (by room, sender, etc.) and the server returns matching
+//! an optional room event filter (rooms, senders, types, `contains_url`, ...,
+//! see [`maelstrom_core::matrix::filter`]) and the server returns matching
 //! events ranked by relevance using BM25 scoring.
 //!
-//! Search only covers rooms the requesting user is a member of. Results include
+//! Search only covers rooms the requesting user is a member of, and only the
+//! events (and context) the room's history visibility lets them see. Results include
 //! the matched events along with optional context (events before/after the
 //! match) and highlight information so clients can render snippets.
 //!
@@ -28,6 +30,8 @@ use axum::{Json, Router};
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::filter::RoomEventFilter;
+use maelstrom_federation::visibility;
 
 use crate::extractors::{AuthenticatedUser, MatrixJson};
 use crate::state::AppState;
@@ -53,7 +57,7 @@ struct RoomEventSearch {
     #[serde(default)]
     keys: Vec<String>,
     #[serde(default)]
-    filter: Option<SearchFilter>,
+    filter: RoomEventFilter,
     #[serde(default)]
     event_context: Option<EventContext>,
     #[serde(default)]
@@ -63,13 +67,6 @@ struct RoomEventSearch {
     from: Option<String>,
 }
 
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct SearchFilter {
-    rooms: Option<Vec<String>>,
-    limit: Option<usize>,
-}
-
 #[derive(Deserialize)]
 #[allow(dead_code)]
 struct EventContext {
@@ -107,13 +104,15 @@ async fn search(
         .unwrap_or(0);
 
     // Determine which rooms to search
-    let mut room_ids = match &room_search.filter {
-        Some(filter) if filter.rooms.is_some() => filter.rooms.clone().unwrap_or_default(),
-        _ => storage
+    let filter = &room_search.filter;
+    let mut room_ids = match &filter.rooms {
+        Some(rooms) => rooms.clone(),
+        None => storage
             .get_joined_rooms(&user_id)
             .await
             .map_err(crate::extractors::storage_error)?,
     };
+    room_ids.retain(|room_id| filter.allows_room(room_id));
 
     // Traverse room upgrade chains to include predecessor rooms in search
     let room_id_set: HashSet<&str> = room_ids.iter().map(|s| s.as_str()).collect();
@@ -129,12 +128,7 @@ async fn search(
     }
     room_ids.extend(predecessor_set);
 
-    let limit = room_search
-        .filter
-        .as_ref()
-        .and_then(|f| f.limit)
-        .unwrap_or(10)
-        .min(50);
+    let limit = filter.limit.unwrap_or(10).min(50);
 
     let search_lower = room_search.search_term.to_lowercase();
     let order_by = room_search.order_by.as_deref().unwrap_or("rank");
@@ -213,9 +207,15 @@ async fn search(
             .collect();
     }
 
+    // Only what passes the filter and the user may see under each room's
+    // history visibility
+    all_filtered.retain(|e| filter.matches(e));
+    let mut all_filtered =
+        visibility::filter_events_for_user(storage, &user_id, all_filtered).await;
+
     // Sort results based on order_by
     if order_by == "recent" {
-        all_filtered.sort_by(|a, b| b.origin_server_ts.cmp(&a.origin_server_ts));
+        all_filtered.sort_by_key(|e| std::cmp::Reverse(e.origin_server_ts));
     }
 
     let total_count = all_filtered.len();
@@ -237,7 +237,7 @@ async fn search(
     for event in &filtered {
         let mut result = serde_json::json!({
             "rank": 1,
-            "result": event.to_client_event().into_json(),
+            "result": filter.render(event, None),
         });
 
         if include_context {
@@ -252,25 +252,21 @@ async fn search(
                 .get_room_events(&event.room_id, event.stream_position, before_limit, "b")
                 .await
             {
+                let before = visibility::filter_events_for_user(storage, &user_id, before).await;
                 if let Some(first) = before.last() {
                     start_token = first.stream_position.to_string();
                 }
-                before_events = before
-                    .iter()
-                    .map(|e| e.to_client_event().into_json())
-                    .collect();
+                before_events = before.iter().map(|e| filter.render(e, None)).collect();
             }
             if let Ok(after) = storage
                 .get_room_events(&event.room_id, event.stream_position, after_limit, "f")
                 .await
             {
+                let after = visibility::filter_events_for_user(storage, &user_id, after).await;
                 if let Some(last) = after.last() {
                     end_token = last.stream_position.to_string();
                 }
-                after_events = after
-                    .iter()
-                    .map(|e| e.to_client_event().into_json())
-                    .collect();
+                after_events = after.iter().map(|e| filter.render(e, None)).collect();
             }
 
             result["context"] = serde_json::json!({
@@ -0,0 +1,1363 @@
+//! Sliding sync -- MSC3575 and simplified sliding sync (MSC4186).
+//!
+//! | Method | Path | Handler |
+//! |--------|------|---------|
+//! | `POST` | `/_matrix/client/v3/sync` | Sliding sync (MSC3575) |
+//! | `POST` | `/_matrix/client/unstable/org.matrix.simplified_msc3575/sync` | Simplified sliding sync (MSC4186) |
+//!
+//! A bandwidth-efficient alternative to [`/sync`](super::sync) where the
+//! client declares named **room lists** with index ranges (e.g. "show me
+//! rooms 0-19 sorted by recency") and explicit `room_subscriptions`, and the
+//! server sends data only for the rooms inside them. Both endpoints share one
+//! handler; the MSC3575 one also returns the `SYNC` list `ops` older clients
+//! expect.
+//!
+//! # Connections and `pos`
+//!
+//! A client keeps a connection (one per `conn_id`) going by sending back the
+//! `pos` of the previous response. The server remembers what each connection
+//! has been sent ([`SlidingConnection`](crate::sync_state::SlidingConnection))
+//! and only sends a room again when it changed: new events, another
+//! membership, other unread counts, or a different `required_state` or
+//! `timeline_limit`. Extensions work the same way, so account data, typing
+//! and receipts are only sent when they differ from what the connection has.
+//! A `pos` the server does not know (after a restart, say) is answered with
+//! `M_UNKNOWN_POS` and the client starts over.
+//!
+//! `pos` is `{stream position}_{sequence}`: the stream position dates the
+//! `e2ee` device list changes and thread subscriptions, the sequence number
+//! picks the connection snapshot.
+//!
+//! # Long-polling
+//!
+//! With a `pos` and `timeout > 0`, a response with nothing new waits on the
+//! [`Notifier`](crate::notify::Notifier) for the user's rooms until something
+//! happens or the timeout runs out, as `/sync` does.
+//!
+//! # Lists
+//!
+//! A list holds the joined, invited and knocked rooms passing its `filters`
+//! (`is_dm`, `is_invite`, `is_encrypted`, `room_types`/`not_room_types` and
+//! `spaces`), ordered by its `sort` (`by_recency` by default, also `by_name`).
+//! Notification counts are not tracked, so `by_notification_count` and
+//! `by_notification_level` are skipped, like any other key the server cannot
+//! sort by. Its `range` (or MSC3575 `ranges`) picks the rooms to send;
+//! without one, all of them are.
+//!
+//! Lists are built from a summary of each room (name, type, encryption,
+//! latest event and `bump_stamp`) taken from a few state lookups; the
+//! connection keeps them and only rooms with events since its `pos` are
+//! summarized again. A room's full state is only loaded to send it.
+//!
+//! # Rooms
+//!
+//! Rooms carry `required_state` (`[type, state_key]` patterns with `*`,
+//! `$ME` and `$LAZY` for the members of the timeline's senders, sent with
+//! every timeline that has them rather than only when they change), the
+//! `timeline`, `bump_stamp` (the position of the last event that should move
+//! the room up the list), `heroes` for rooms without a name, member counts
+//! and `is_dm`; not `notification_count` or `highlight_count`, which are not
+//! tracked. Invited and knocked rooms carry stripped `invite_state` or
+//! `knock_state` instead. A room the user left is sent once more with its
+//! final events and then forgotten by the connection.
+//!
+//! `room_subscriptions` may name rooms the user is not in while they are
+//! `world_readable` (peeking, see [`peek`](super::peek)); timelines only hold
+//! the events the room's history visibility lets the user see.
+//!
+//! # Extensions
+//!
+//! - `to_device` -- to-device messages since the extension's `since`.
+//! - `e2ee` -- one-time key counts, unused fallback key types and device list
+//!   changes.
+//! - `account_data` -- global account data and that of the rooms in the
+//!   response.
+//! - `typing` and `receipts` -- for the user's joined rooms.
+//! - `thread_subscriptions` (MSC4308) -- the
+//!   [thread subscriptions](super::thread_subscriptions) and unsubscriptions
+//!   made since `pos`, newest first up to `limit`, and a `prev_batch` to page
+//!   back through the rest when there were more.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use axum::extract::{Query, State};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_core::matrix::event::Pdu;
+use maelstrom_core::matrix::filter::EventFilter;
+use maelstrom_core::matrix::room::{Membership, event_type as et};
+use maelstrom_federation::visibility;
+
+use crate::extractors::{AuthenticatedUser, MatrixJson};
+use crate::handlers::sync::{build_global_account_data, compute_device_lists, device_key_counts};
+use crate::handlers::thread_subscriptions::{self, SubscriptionChanges};
+use crate::state::AppState;
+use crate::sync_state::{RoomSummary, SentRoom, SlidingConnection};
+
+/// Register sliding sync routes.
+///
+/// Routes:
+/// - `POST /_matrix/client/v3/sync` -- sliding sync (MSC3575)
+/// - `POST /_matrix/client/unstable/org.matrix.simplified_msc3575/sync` --
+///   simplified sliding sync (MSC4186)
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/_matrix/client/v3/sync", post(sliding_sync))
+        .route(
+            "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync",
+            post(simplified_sliding_sync),
+        )
+}
+
+/// Event types that bump a room up a `by_recency` list.
+const BUMP_TYPES: &[&str] = &[
+    et::CREATE,
+    et::MESSAGE,
+    et::ENCRYPTED,
+    et::STICKER,
+    et::CALL_INVITE,
+    "m.poll.start",
+    "org.matrix.msc3381.poll.start",
+    "m.beacon_info",
+    "org.matrix.msc3672.beacon_info",
+];
+
+/// How many recent events are searched for a room's `bump_stamp`.
+const MAX_BUMP_SCAN: usize = 50;
+
+/// How many events since a connection's `pos` are looked at to tell which
+/// rooms changed. With more, every room is summarized again.
+const MAX_CHANGED_SCAN: usize = 1000;
+
+/// State shared with invited and knocked rooms as stripped state.
+const STRIPPED_STATE_TYPES: &[&str] = &[
+    et::CREATE,
+    et::JOIN_RULES,
+    et::NAME,
+    et::AVATAR,
+    et::CANONICAL_ALIAS,
+    et::TOPIC,
+    et::ENCRYPTION,
+];
+
+/// How many members are offered as `heroes` of a room without a name.
+const MAX_HEROES: usize = 5;
+
+fn default_timeline_limit() -> usize {
+    10
+}
+
+// -- Request types --
+
+#[derive(Deserialize, Default)]
+struct SlidingSyncQuery {
+    pos: Option<String>,
+    /// How long to wait for something new, in milliseconds.
+    #[serde(default)]
+    timeout: u64,
+}
+
+#[derive(Deserialize)]
+struct SlidingSyncRequest {
+    /// Which of the device's connections this is.
+    #[serde(default)]
+    conn_id: String,
+    /// MSC3575 sends `pos` in the body rather than the query string.
+    pos: Option<String>,
+    #[serde(default)]
+    lists: HashMap<String, SlidingSyncList>,
+    #[serde(default)]
+    room_subscriptions: HashMap<String, RoomConfig>,
+    #[serde(default)]
+    extensions: SlidingSyncExtensions,
+}
+
+#[derive(Deserialize)]
+struct SlidingSyncList {
+    /// The MSC4186 range.
+    range: Option<[u64; 2]>,
+    /// The MSC3575 ranges.
+    #[serde(default)]
+    ranges: Vec<[u64; 2]>,
+    #[serde(flatten)]
+    config: RoomConfig,
+    #[serde(default)]
+    sort: Vec<String>,
+    #[serde(default)]
+    filters: ListFilters,
+}
+
+/// What to send for a room: by list or by room subscription.
+#[derive(Deserialize, Clone)]
+struct RoomConfig {
+    #[serde(default)]
+    required_state: Vec<[String; 2]>,
+    #[serde(default = "default_timeline_limit")]
+    timeline_limit: usize,
+}
+
+impl RoomConfig {
+    /// Widen this config to cover `other` too, for a room in several lists
+    /// or subscriptions.
+    fn merge(&mut self, other: &RoomConfig) {
+        self.timeline_limit = self.timeline_limit.max(other.timeline_limit);
+        for pattern in &other.required_state {
+            if !self.required_state.contains(pattern) {
+                self.required_state.push(pattern.clone());
+            }
+        }
+    }
+
+    /// The config as the connection remembers it, independent of order.
+    fn fingerprint(&self) -> serde_json::Value {
+        let mut required_state = self.required_state.clone();
+        required_state.sort();
+        serde_json::json!({
+            "required_state": required_state,
+            "timeline_limit": self.timeline_limit,
+        })
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct ListFilters {
+    is_dm: Option<bool>,
+    is_invite: Option<bool>,
+    is_encrypted: Option<bool>,
+    /// Only rooms that are children of one of these spaces.
+    #[serde(default)]
+    spaces: Vec<String>,
+    /// Only rooms of these types (`null` for rooms without a type).
+    room_types: Option<Vec<Option<String>>>,
+    #[serde(default)]
+    not_room_types: Vec<Option<String>>,
+}
+
+impl ListFilters {
+    fn matches(&self, room: &RoomSummary, space_children: &HashSet<String>) -> bool {
+        self.is_dm.is_none_or(|is_dm| is_dm == room.is_dm)
+            && self
+                .is_invite
+                .is_none_or(|is_invite| is_invite == (room.membership == Some(Membership::Invite)))
+            && self
+                .is_encrypted
+                .is_none_or(|is_encrypted| is_encrypted == room.is_encrypted)
+            && (self.spaces.is_empty() || space_children.contains(&room.room_id))
+            && self
+                .room_types
+                .as_ref()
+                .is_none_or(|types| types.contains(&room.room_type))
+            && !self.not_room_types.contains(&room.room_type)
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct SlidingSyncExtensions {
+    e2ee: Option<ExtensionConfig>,
+    to_device: Option<ExtensionConfig>,
+    typing: Option<ExtensionConfig>,
+    receipts: Option<ExtensionConfig>,
+    account_data: Option<ExtensionConfig>,
+    thread_subscriptions: Option<ExtensionConfig>,
+}
+
+#[derive(Deserialize)]
+struct ExtensionConfig {
+    #[serde(default)]
+    enabled: bool,
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Whether the client turned an extension on.
+fn enabled(config: &Option<ExtensionConfig>) -> bool {
+    config.as_ref().is_some_and(|c| c.enabled)
+}
+
+// -- Response types --
+
+#[derive(Serialize)]
+struct SlidingSyncResponse {
+    pos: String,
+    lists: HashMap<String, ListResponse>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    rooms: HashMap<String, RoomResponse>,
+    extensions: ExtensionsResponse,
+    /// Stream position the response was built at.
+    #[serde(skip)]
+    position: i64,
+    /// Whether a list's room count changed.
+    #[serde(skip)]
+    lists_changed: bool,
+}
+
+impl SlidingSyncResponse {
+    /// Whether there is anything the client has not seen, or the request
+    /// may long-poll.
+    fn has_updates(&self) -> bool {
+        self.lists_changed || !self.rooms.is_empty() || self.extensions.has_updates()
+    }
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ops: Option<Vec<ListOp>>,
+}
+
+/// An MSC3575 list operation.
+#[derive(Serialize)]
+struct ListOp {
+    op: &'static str,
+    range: [u64; 2],
+    room_ids: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct RoomResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heroes: Option<Vec<Hero>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_dm: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    required_state: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    timeline: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limited: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_live: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bump_stamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    joined_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invited_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invite_state: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    knock_state: Option<Vec<serde_json::Value>>,
+}
+
+/// A member shown in place of the name of a room without one.
+#[derive(Serialize)]
+struct Hero {
+    user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+}
+
+#[derive(Serialize, Default)]
+struct ExtensionsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e2ee: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_device: Option<ToDeviceResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typing: Option<RoomsExtension>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipts: Option<RoomsExtension>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_data: Option<AccountDataExtension>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_subscriptions: Option<ThreadSubscriptionsExtension>,
+}
+
+impl ExtensionsResponse {
+    fn has_updates(&self) -> bool {
+        self.e2ee.is_some()
+            || self
+                .to_device
+                .as_ref()
+                .is_some_and(|t| !t.events.is_empty())
+            || self.typing.as_ref().is_some_and(|t| !t.rooms.is_empty())
+            || self.receipts.as_ref().is_some_and(|r| !r.rooms.is_empty())
+            || self
+                .account_data
+                .as_ref()
+                .is_some_and(|a| !a.global.is_empty() || !a.rooms.is_empty())
+            || self
+                .thread_subscriptions
+                .as_ref()
+                .is_some_and(|t| !t.changes.is_empty())
+    }
+}
+
+#[derive(Serialize)]
+struct ToDeviceResponse {
+    next_batch: String,
+    events: Vec<serde_json::Value>,
+}
+
+/// The `typing` and `receipts` extensions: one ephemeral event per room.
+#[derive(Serialize, Default)]
+struct RoomsExtension {
+    rooms: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Default)]
+struct AccountDataExtension {
+    global: Vec<serde_json::Value>,
+    rooms: HashMap<String, Vec<serde_json::Value>>,
+}
+
+#[derive(Serialize)]
+struct ThreadSubscriptionsExtension {
+    #[serde(flatten)]
+    changes: SubscriptionChanges,
+    /// Where to page back from with the MSC4308 endpoint, when `limit` left
+    /// changes out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_batch: Option<String>,
+}
+
+// -- Handlers --
+
+/// POST /_matrix/client/v3/sync
+async fn sliding_sync(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<SlidingSyncQuery>,
+    MatrixJson(body): MatrixJson<SlidingSyncRequest>,
+) -> Result<Json<SlidingSyncResponse>, MatrixError> {
+    handle_sliding_sync(&state, &auth, query, body, true).await
+}
+
+/// POST /_matrix/client/unstable/org.matrix.simplified_msc3575/sync
+async fn simplified_sliding_sync(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<SlidingSyncQuery>,
+    MatrixJson(body): MatrixJson<SlidingSyncRequest>,
+) -> Result<Json<SlidingSyncResponse>, MatrixError> {
+    handle_sliding_sync(&state, &auth, query, body, false).await
+}
+
+fn unknown_pos() -> MatrixError {
+    MatrixError::new(
+        http::StatusCode::BAD_REQUEST,
+        ErrorCode::UnknownPos,
+        "Unknown pos, start a new connection",
+    )
+}
+
+/// Split a `pos` into its stream position and connection sequence number.
+fn parse_pos(pos: &str) -> Option<(i64, u64)> {
+    let (position, seq) = pos.split_once('_')?;
+    Some((position.parse().ok()?, seq.parse().ok()?))
+}
+
+async fn handle_sliding_sync(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    query: SlidingSyncQuery,
+    body: SlidingSyncRequest,
+    with_ops: bool,
+) -> Result<Json<SlidingSyncResponse>, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+    let device_id = auth.device_id.to_string();
+
+    let pos = query.pos.as_deref().or(body.pos.as_deref());
+    let (since, seq) = match pos {
+        Some(pos) => {
+            let (since, seq) = parse_pos(pos).ok_or_else(unknown_pos)?;
+            (Some(since), Some(seq))
+        }
+        None => (None, None),
+    };
+    let base = state
+        .sync_state()
+        .resume_connection(&user_id, &device_id, &body.conn_id, seq)
+        .ok_or_else(unknown_pos)?;
+
+    // Subscribe before building the response so nothing that happens in
+    // between is missed
+    let mut rx = if since.is_some() && query.timeout > 0 {
+        let mut rooms = storage
+            .get_joined_rooms(&user_id)
+            .await
+            .map_err(crate::extractors::storage_error)?;
+        rooms.extend(
+            storage
+                .get_invited_rooms(&user_id)
+                .await
+                .unwrap_or_default(),
+        );
+        rooms.extend(
+            storage
+                .get_knocked_rooms(&user_id)
+                .await
+                .unwrap_or_default(),
+        );
+        rooms.extend(body.room_subscriptions.keys().cloned());
+        Some(state.notifier().subscribe(&rooms, Some(&user_id)).await)
+    } else {
+        None
+    };
+
+    let mut conn = base.clone();
+    let mut response = build_response(state, auth, &body, since, &mut conn, with_ops).await?;
+    if !response.has_updates()
+        && let Some(rx) = rx.as_mut()
+    {
+        tokio::select! {
+            _ = rx.recv() => {}
+            _ = tokio::time::sleep(Duration::from_millis(query.timeout)) => {}
+        }
+        conn = base;
+        response = build_response(state, auth, &body, since, &mut conn, with_ops).await?;
+    }
+
+    let seq = state
+        .sync_state()
+        .save_connection(&user_id, &device_id, &body.conn_id, conn);
+    response.pos = format!("{}_{seq}", response.position);
+    Ok(Json(response))
+}
+
+/// The room summaries of one request: the connection's, for rooms without
+/// new events since its `pos`, fresh ones for the rest.
+struct Summaries<'a> {
+    storage: &'a dyn maelstrom_storage::traits::Storage,
+    user_id: &'a str,
+    current_position: i64,
+    dm_rooms: &'a HashSet<String>,
+    previous: HashMap<String, RoomSummary>,
+    /// Rooms with events since the connection's `pos`; `None` when unknown.
+    changed: Option<HashSet<String>>,
+}
+
+impl Summaries<'_> {
+    async fn get(&self, room_id: &str, membership: Option<Membership>) -> RoomSummary {
+        let unchanged = self
+            .changed
+            .as_ref()
+            .is_some_and(|changed| !changed.contains(room_id));
+        let mut summary = match self.previous.get(room_id) {
+            Some(summary) if unchanged && summary.membership == membership => summary.clone(),
+            _ => {
+                summarize_room(
+                    self.storage,
+                    self.user_id,
+                    room_id,
+                    membership,
+                    self.current_position,
+                )
+                .await
+            }
+        };
+        summary.is_dm = self.dm_rooms.contains(room_id);
+        summary
+    }
+}
+
+/// The rooms with events after `since`, or `None` if there are too many
+/// events to tell.
+async fn rooms_changed_since(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    since: i64,
+) -> Option<HashSet<String>> {
+    let events = storage
+        .get_events_after(since, MAX_CHANGED_SCAN)
+        .await
+        .ok()?;
+    (events.len() < MAX_CHANGED_SCAN).then(|| events.into_iter().map(|e| e.room_id).collect())
+}
+
+/// The content of a room's state event with an empty state key.
+async fn state_content(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    room_id: &str,
+    event_type: &str,
+) -> Option<serde_json::Value> {
+    storage
+        .get_state_event(room_id, event_type, "")
+        .await
+        .ok()
+        .map(|e| e.content)
+}
+
+/// Summarize a room for the lists, from the few state events they need
+/// rather than the room's whole state.
+async fn summarize_room(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    room_id: &str,
+    membership: Option<Membership>,
+    current_position: i64,
+) -> RoomSummary {
+    let name = state_content(storage, room_id, et::NAME)
+        .await
+        .and_then(|c| c.get("name")?.as_str().map(String::from))
+        .filter(|n| !n.is_empty());
+    let room_type = state_content(storage, room_id, et::CREATE)
+        .await
+        .and_then(|c| c.get("type")?.as_str().map(String::from));
+    let is_encrypted = state_content(storage, room_id, et::ENCRYPTION)
+        .await
+        .is_some();
+
+    let (latest, bump_stamp, inviter) = match membership {
+        // The room's events are not ours to read yet: the invite or knock
+        // is what moves the room
+        Some(Membership::Invite | Membership::Knock) => {
+            let member = storage
+                .get_state_event(room_id, et::MEMBER, user_id)
+                .await
+                .ok();
+            let position = member.as_ref().map_or(0, |e| e.stream_position);
+            (position, position, member.map(|e| e.sender))
+        }
+        _ => {
+            let recent = storage
+                .get_room_events(room_id, current_position + 1, MAX_BUMP_SCAN, "b")
+                .await
+                .unwrap_or_default();
+            let latest = recent.first().map_or(0, |e| e.stream_position);
+            let bump_stamp = recent
+                .iter()
+                .find(|e| BUMP_TYPES.contains(&e.event_type.as_str()))
+                .map_or(latest, |e| e.stream_position);
+            (latest, bump_stamp, None)
+        }
+    };
+
+    RoomSummary {
+        room_id: room_id.to_string(),
+        membership,
+        latest,
+        bump_stamp,
+        name,
+        is_dm: false,
+        is_encrypted,
+        room_type,
+        inviter,
+    }
+}
+
+/// Rooms listed in the user's `m.direct` account data.
+async fn dm_rooms(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+) -> HashSet<String> {
+    storage
+        .get_account_data(user_id, None, "m.direct")
+        .await
+        .ok()
+        .and_then(|direct| direct.as_object().cloned())
+        .unwrap_or_default()
+        .values()
+        .filter_map(|rooms| rooms.as_array())
+        .flatten()
+        .filter_map(|room| room.as_str().map(String::from))
+        .collect()
+}
+
+/// Users whose invites the user ignores.
+async fn ignored_users(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+) -> HashSet<String> {
+    storage
+        .get_account_data(user_id, None, "m.ignored_user_list")
+        .await
+        .ok()
+        .and_then(|v| {
+            v.get("ignored_users")
+                .and_then(|u| u.as_object())
+                .map(|obj| obj.keys().cloned().collect())
+        })
+        .unwrap_or_default()
+}
+
+/// The rooms that are children of the given spaces.
+async fn space_children(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    spaces: &[String],
+) -> HashSet<String> {
+    let mut children = HashSet::new();
+    for space in spaces {
+        let state = storage.get_current_state(space).await.unwrap_or_default();
+        children.extend(
+            state
+                .into_iter()
+                .filter(|e| {
+                    // A child event without `via` has been removed
+                    e.event_type == et::SPACE_CHILD
+                        && e.content.get("via").and_then(|v| v.as_array()).is_some()
+                })
+                .filter_map(|e| e.state_key),
+        );
+    }
+    children
+}
+
+/// Order a list's rooms by its `sort`, most significant first, skipping keys
+/// there is nothing to sort by; ties keep the most recent room first.
+fn sort_rooms(rooms: &mut [&RoomSummary], sort: &[String]) {
+    rooms.sort_by(|a, b| {
+        let by_recency = b.bump_stamp.cmp(&a.bump_stamp);
+        sort.iter()
+            .map(|key| match key.as_str() {
+                "by_recency" => by_recency,
+                "by_name" => {
+                    let name = |r: &RoomSummary| r.name.clone().unwrap_or_default().to_lowercase();
+                    name(a).cmp(&name(b))
+                }
+                _ => std::cmp::Ordering::Equal,
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(by_recency)
+    });
+}
+
+async fn build_response(
+    state: &AppState,
+    auth: &AuthenticatedUser,
+    body: &SlidingSyncRequest,
+    since: Option<i64>,
+    conn: &mut SlidingConnection,
+    with_ops: bool,
+) -> Result<SlidingSyncResponse, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+
+    let current_position = storage
+        .current_stream_position()
+        .await
+        .map_err(crate::extractors::storage_error)?;
+    let joined_rooms = storage
+        .get_joined_rooms(&user_id)
+        .await
+        .map_err(crate::extractors::storage_error)?;
+    let ignored = ignored_users(storage, &user_id).await;
+    let dms = dm_rooms(storage, &user_id).await;
+
+    // Rooms without new events keep the summary the connection has of them
+    let changed = match since {
+        Some(since) => rooms_changed_since(storage, since).await,
+        None => None,
+    };
+    let summaries = Summaries {
+        storage,
+        user_id: &user_id,
+        current_position,
+        dm_rooms: &dms,
+        previous: std::mem::take(&mut conn.summaries),
+        changed,
+    };
+
+    let mut rooms: Vec<RoomSummary> = Vec::new();
+    for room_id in &joined_rooms {
+        rooms.push(summaries.get(room_id, Some(Membership::Join)).await);
+    }
+    for room_id in storage
+        .get_invited_rooms(&user_id)
+        .await
+        .unwrap_or_default()
+    {
+        let room = summaries.get(&room_id, Some(Membership::Invite)).await;
+        if !room
+            .inviter
+            .as_ref()
+            .is_some_and(|inviter| ignored.contains(inviter))
+        {
+            rooms.push(room);
+        }
+    }
+    for room_id in storage
+        .get_knocked_rooms(&user_id)
+        .await
+        .unwrap_or_default()
+    {
+        rooms.push(summaries.get(&room_id, Some(Membership::Knock)).await);
+    }
+
+    // -- Lists: which rooms to send, and with what config --
+    let mut wanted: HashMap<String, RoomConfig> = HashMap::new();
+    let mut want = |room_id: &str, config: &RoomConfig| {
+        wanted
+            .entry(room_id.to_string())
+            .and_modify(|c| c.merge(config))
+            .or_insert_with(|| config.clone());
+    };
+
+    let mut lists = HashMap::new();
+    let mut list_counts = HashMap::new();
+    for (list_name, list) in &body.lists {
+        let children = space_children(storage, &list.filters.spaces).await;
+        let mut listed: Vec<&RoomSummary> = rooms
+            .iter()
+            .filter(|room| list.filters.matches(room, &children))
+            .collect();
+        sort_rooms(&mut listed, &list.sort);
+
+        let mut ranges: Vec<[u64; 2]> = list.range.into_iter().collect();
+        ranges.extend(&list.ranges);
+        if ranges.is_empty() && !listed.is_empty() {
+            ranges.push([0, listed.len() as u64 - 1]);
+        }
+
+        let mut ops = Vec::new();
+        for [start, end] in ranges {
+            let end = end.min((listed.len() as u64).saturating_sub(1));
+            let in_range: Vec<&RoomSummary> = listed
+                .iter()
+                .skip(start as usize)
+                .take((end + 1).saturating_sub(start) as usize)
+                .copied()
+                .collect();
+            for room in &in_range {
+                want(&room.room_id, &list.config);
+            }
+            ops.push(ListOp {
+                op: "SYNC",
+                range: [start, end],
+                room_ids: in_range.iter().map(|r| r.room_id.clone()).collect(),
+            });
+        }
+
+        list_counts.insert(list_name.clone(), listed.len());
+        lists.insert(
+            list_name.clone(),
+            ListResponse {
+                count: listed.len(),
+                ops: with_ops.then_some(ops),
+            },
+        );
+    }
+    let lists_changed = list_counts != conn.list_counts;
+    conn.list_counts = list_counts;
+
+    // -- Room subscriptions, including rooms peeked into --
+    for (room_id, config) in &body.room_subscriptions {
+        if rooms.iter().any(|r| &r.room_id == room_id) {
+            want(room_id, config);
+            continue;
+        }
+        let readable = crate::handlers::peek::peek_remote_room(state, room_id)
+            .await
+            .is_ok()
+            && visibility::user_may_read_room(storage, &user_id, room_id).await;
+        if readable {
+            rooms.push(summaries.get(room_id, None).await);
+            want(room_id, config);
+        }
+    }
+
+    // -- Rooms the connection had that the user has since left --
+    let mut left = Vec::new();
+    for (room_id, sent) in &conn.rooms {
+        if rooms.iter().any(|r| &r.room_id == room_id) {
+            continue;
+        }
+        let membership = storage
+            .get_membership(&user_id, room_id)
+            .await
+            .ok()
+            .and_then(|m| Membership::parse(&m));
+        if matches!(membership, Some(Membership::Leave | Membership::Ban)) {
+            let config: RoomConfig =
+                serde_json::from_value(sent.config.clone()).unwrap_or(RoomConfig {
+                    required_state: Vec::new(),
+                    timeline_limit: default_timeline_limit(),
+                });
+            rooms.push(summaries.get(room_id, membership).await);
+            want(room_id, &config);
+            left.push(room_id.clone());
+        }
+    }
+
+    // -- Room data, for the rooms that changed --
+    let mut room_responses = HashMap::new();
+    for room in &rooms {
+        let Some(config) = wanted.get(&room.room_id) else {
+            continue;
+        };
+        let sent = SentRoom {
+            stream_position: room.latest,
+            membership: room.membership.map_or("peek", |m| m.as_str()).to_string(),
+            config: config.fingerprint(),
+        };
+        let previous = conn.rooms.get(&room.room_id);
+        if previous == Some(&sent) {
+            continue;
+        }
+        // Rooms sent before with the same config only get what is new
+        let since_sent = previous
+            .filter(|p| p.config == sent.config)
+            .map(|p| p.stream_position);
+        let response = build_room(
+            storage,
+            &user_id,
+            room,
+            config,
+            previous.is_none(),
+            since_sent,
+            current_position,
+        )
+        .await?;
+        room_responses.insert(room.room_id.clone(), response);
+        conn.rooms.insert(room.room_id.clone(), sent);
+    }
+    for room_id in &left {
+        conn.rooms.remove(room_id);
+    }
+    conn.summaries = rooms
+        .iter()
+        .filter(|room| !left.contains(&room.room_id))
+        .map(|room| (room.room_id.clone(), room.clone()))
+        .collect();
+
+    // -- Extensions --
+    let ext = &body.extensions;
+    let mut extensions = ExtensionsResponse::default();
+    if enabled(&ext.to_device) {
+        extensions.to_device = Some(
+            to_device_extension(
+                storage,
+                &user_id,
+                auth.device_id.as_ref(),
+                ext.to_device.as_ref(),
+                current_position,
+            )
+            .await,
+        );
+    }
+    if enabled(&ext.e2ee) {
+        extensions.e2ee = e2ee_extension(
+            storage,
+            &joined_rooms,
+            &user_id,
+            auth.device_id.as_ref(),
+            since,
+            conn,
+        )
+        .await;
+    }
+    if enabled(&ext.typing) {
+        extensions.typing = Some(typing_extension(state, &joined_rooms, conn));
+    }
+    if enabled(&ext.receipts) {
+        extensions.receipts =
+            Some(receipts_extension(storage, &joined_rooms, &user_id, conn).await);
+    }
+    if enabled(&ext.account_data) {
+        let rooms: Vec<&String> = joined_rooms
+            .iter()
+            .filter(|room_id| wanted.contains_key(*room_id))
+            .collect();
+        extensions.account_data =
+            Some(account_data_extension(storage, &user_id, &rooms, conn).await);
+    }
+    if let Some(config) = ext.thread_subscriptions.as_ref().filter(|c| c.enabled) {
+        extensions.thread_subscriptions = Some(
+            thread_subscriptions_extension(
+                storage,
+                &joined_rooms,
+                &user_id,
+                since,
+                config
+                    .limit
+                    .unwrap_or(thread_subscriptions::DEFAULT_CHANGES_LIMIT),
+            )
+            .await,
+        );
+    }
+
+    Ok(SlidingSyncResponse {
+        pos: String::new(),
+        lists,
+        rooms: room_responses,
+        extensions,
+        position: current_position,
+        lists_changed,
+    })
+}
+
+/// Build one room of the response.
+///
+/// `since_sent` is the newest event the connection already has of the room,
+/// when only what came after it needs sending.
+async fn build_room(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    room: &RoomSummary,
+    config: &RoomConfig,
+    initial: bool,
+    since_sent: Option<i64>,
+    current_position: i64,
+) -> Result<RoomResponse, MatrixError> {
+    let mut response = RoomResponse {
+        name: room.name.clone(),
+        is_dm: room.is_dm.then_some(true),
+        initial: initial.then_some(true),
+        bump_stamp: Some(room.bump_stamp),
+        ..Default::default()
+    };
+    let state = storage
+        .get_current_state(&room.room_id)
+        .await
+        .map_err(crate::extractors::storage_error)?;
+
+    // Invited and knocked rooms only get stripped state
+    if matches!(
+        room.membership,
+        Some(Membership::Invite | Membership::Knock)
+    ) {
+        let stripped: Vec<serde_json::Value> = state
+            .iter()
+            .filter(|e| {
+                STRIPPED_STATE_TYPES.contains(&e.event_type.as_str())
+                    || (e.event_type == et::MEMBER && e.state_key.as_deref() == Some(user_id))
+            })
+            .filter_map(|e| serde_json::to_value(e.to_stripped()).ok())
+            .collect();
+        if room.membership == Some(Membership::Invite) {
+            response.invite_state = Some(stripped);
+        } else {
+            response.knock_state = Some(stripped);
+        }
+        return Ok(response);
+    }
+
+    // Timeline: the latest events, or those since the connection last had
+    // the room
+    let limit = config.timeline_limit;
+    let recent = storage
+        .get_room_events(&room.room_id, current_position + 1, limit + 1, "b")
+        .await
+        .map_err(crate::extractors::storage_error)?;
+    let mut recent: Vec<Pdu> = recent
+        .into_iter()
+        .filter(|e| since_sent.is_none_or(|since| e.stream_position > since))
+        .collect();
+    let limited = recent.len() > limit;
+    recent.truncate(limit);
+    let mut timeline = visibility::filter_events_for_user(storage, user_id, recent).await;
+    timeline.reverse();
+
+    let senders: HashSet<&str> = timeline.iter().map(|e| e.sender.as_str()).collect();
+    // Once the connection has the room, only changed state is sent, along
+    // with the members of this timeline's senders: the client may have
+    // never been sent them
+    let is_sender_member = |e: &Pdu| {
+        e.event_type == et::MEMBER
+            && e.state_key
+                .as_deref()
+                .is_some_and(|key| senders.contains(key))
+    };
+    response.required_state = state
+        .iter()
+        .filter(|e| since_sent.is_none_or(|since| e.stream_position > since) || is_sender_member(e))
+        .filter(|e| state_is_required(e, &config.required_state, user_id, &senders))
+        .map(|e| e.to_client_event().into_json())
+        .collect();
+
+    response.prev_batch = timeline.first().map(|e| e.stream_position.to_string());
+    response.limited = Some(limited);
+    if since_sent.is_some() {
+        response.num_live = Some(timeline.len());
+    }
+    response.timeline = timeline
+        .iter()
+        .map(|e| e.to_client_event().into_json())
+        .collect();
+
+    let members_with = |membership: Membership| {
+        state.iter().filter(move |e| {
+            e.event_type == et::MEMBER
+                && e.content.get("membership").and_then(|m| m.as_str()) == Some(membership.as_str())
+        })
+    };
+    response.joined_count = Some(members_with(Membership::Join).count() as u64);
+    response.invited_count = Some(members_with(Membership::Invite).count() as u64);
+
+    response.avatar = state
+        .iter()
+        .find(|e| e.event_type == et::AVATAR && e.state_key.as_deref() == Some(""))
+        .and_then(|e| e.content.get("url"))
+        .and_then(|u| u.as_str())
+        .map(String::from);
+    if room.name.is_none() {
+        let heroes: Vec<Hero> = members_with(Membership::Join)
+            .chain(members_with(Membership::Invite))
+            .filter(|e| e.state_key.as_deref() != Some(user_id))
+            .take(MAX_HEROES)
+            .map(|e| Hero {
+                user_id: e.state_key.clone().unwrap_or_default(),
+                displayname: e
+                    .content
+                    .get("displayname")
+                    .and_then(|d| d.as_str())
+                    .map(String::from),
+                avatar_url: e
+                    .content
+                    .get("avatar_url")
+                    .and_then(|a| a.as_str())
+                    .map(String::from),
+            })
+            .collect();
+        response.heroes = (!heroes.is_empty()).then_some(heroes);
+    }
+
+    Ok(response)
+}
+
+/// Whether a state event matches one of the `required_state` patterns.
+///
+/// `*` matches any type or state key, `$ME` the user's own state key and
+/// `$LAZY` (for members) the user and the timeline's senders.
+fn state_is_required(
+    event: &Pdu,
+    patterns: &[[String; 2]],
+    user_id: &str,
+    senders: &HashSet<&str>,
+) -> bool {
+    let state_key = event.state_key.as_deref().unwrap_or_default();
+    patterns.iter().any(|[event_type, key]| {
+        (event_type == "*" || *event_type == event.event_type)
+            && match key.as_str() {
+                "*" => true,
+                "$ME" => state_key == user_id,
+                "$LAZY" => {
+                    event.event_type == et::MEMBER
+                        && (state_key == user_id || senders.contains(state_key))
+                }
+                key => key == state_key,
+            }
+    })
+}
+
+/// The `to_device` extension: messages since the extension's `since`, after
+/// dropping the ones that `since` acknowledges.
+async fn to_device_extension(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    device_id: &str,
+    config: Option<&ExtensionConfig>,
+    current_position: i64,
+) -> ToDeviceResponse {
+    let since: i64 = config
+        .and_then(|c| c.since.as_deref())
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0);
+    let events = storage
+        .get_to_device_messages(user_id, device_id, since, current_position)
+        .await
+        .unwrap_or_default();
+    if since > 0 {
+        let _ = storage
+            .delete_to_device_messages(user_id, device_id, since)
+            .await;
+    }
+    ToDeviceResponse {
+        next_batch: current_position.to_string(),
+        events,
+    }
+}
+
+/// The `e2ee` extension, when the key counts changed or device lists did.
+async fn e2ee_extension(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    joined_rooms: &[String],
+    user_id: &str,
+    device_id: &str,
+    since: Option<i64>,
+    conn: &mut SlidingConnection,
+) -> Option<serde_json::Value> {
+    let (counts, fallback_types) = device_key_counts(storage, user_id, device_id).await;
+    let counts = serde_json::json!({
+        "device_one_time_keys_count": counts,
+        "device_unused_fallback_key_types": fallback_types,
+    });
+    let device_lists = match since {
+        Some(since) => {
+            serde_json::to_value(compute_device_lists(storage, joined_rooms, user_id, since).await)
+                .unwrap_or_default()
+        }
+        None => serde_json::json!({}),
+    };
+    let lists_changed = device_lists.as_object().is_some_and(|o| !o.is_empty());
+    if !lists_changed && conn.key_counts.as_ref() == Some(&counts) {
+        return None;
+    }
+    conn.key_counts = Some(counts.clone());
+    let mut e2ee = counts;
+    e2ee["device_lists"] = device_lists;
+    Some(e2ee)
+}
+
+/// The `typing` extension: rooms whose typing users changed.
+fn typing_extension(
+    state: &AppState,
+    joined_rooms: &[String],
+    conn: &mut SlidingConnection,
+) -> RoomsExtension {
+    let mut rooms = HashMap::new();
+    for room_id in joined_rooms {
+        let users = state.ephemeral().get_typing_users(room_id);
+        let event = serde_json::json!({
+            "type": "m.typing",
+            "content": { "user_ids": users },
+        });
+        let unchanged = match conn.typing.get(room_id) {
+            Some(sent) => *sent == event,
+            None => users.is_empty(),
+        };
+        if unchanged {
+            continue;
+        }
+        if users.is_empty() {
+            conn.typing.remove(room_id);
+        } else {
+            conn.typing.insert(room_id.clone(), event.clone());
+        }
+        rooms.insert(room_id.clone(), event);
+    }
+    RoomsExtension { rooms }
+}
+
+/// The `receipts` extension: rooms whose receipts changed. Private receipts
+/// are only shown to their owner.
+async fn receipts_extension(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    joined_rooms: &[String],
+    user_id: &str,
+    conn: &mut SlidingConnection,
+) -> RoomsExtension {
+    let mut rooms = HashMap::new();
+    for room_id in joined_rooms {
+        let receipts = storage.get_receipts(room_id).await.unwrap_or_default();
+        let mut content: HashMap<String, HashMap<String, HashMap<String, serde_json::Value>>> =
+            HashMap::new();
+        for r in receipts
+            .iter()
+            .filter(|r| r.receipt_type != "m.read.private" || r.user_id == user_id)
+        {
+            let mut receipt = serde_json::json!({ "ts": r.ts });
+            if !r.thread_id.is_empty() {
+                receipt["thread_id"] = serde_json::json!(r.thread_id);
+            }
+            content
+                .entry(r.event_id.clone())
+                .or_default()
+                .entry(r.receipt_type.clone())
+                .or_default()
+                .insert(r.user_id.clone(), receipt);
+        }
+        if content.is_empty() {
+            continue;
+        }
+        let event = serde_json::json!({ "type": "m.receipt", "content": content });
+        if conn.receipts.get(room_id) == Some(&event) {
+            continue;
+        }
+        conn.receipts.insert(room_id.clone(), event.clone());
+        rooms.insert(room_id.clone(), event);
+    }
+    RoomsExtension { rooms }
+}
+
+/// The `account_data` extension: global and room account data the
+/// connection does not have yet. Entries removed since are sent with empty
+/// content.
+async fn account_data_extension(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    rooms: &[&String],
+    conn: &mut SlidingConnection,
+) -> AccountDataExtension {
+    let mut current: HashMap<(String, String), serde_json::Value> = HashMap::new();
+    if let Some(global) =
+        build_global_account_data(storage, user_id, true, 0, &EventFilter::default()).await
+    {
+        for event in global.events {
+            if let Some(data_type) = event.get("type").and_then(|t| t.as_str()) {
+                current.insert(
+                    (String::new(), data_type.to_string()),
+                    event["content"].clone(),
+                );
+            }
+        }
+    }
+    for room_id in rooms {
+        for (data_type, content) in storage
+            .get_all_room_account_data(user_id, room_id)
+            .await
+            .unwrap_or_default()
+        {
+            let deleted = content.get("_msc3391_deleted").and_then(|v| v.as_bool()) == Some(true);
+            if !deleted {
+                current.insert(((*room_id).clone(), data_type), content);
+            }
+        }
+    }
+
+    let mut extension = AccountDataExtension::default();
+    let mut add = |room_id: &str, data_type: &str, content: serde_json::Value| {
+        let event = serde_json::json!({ "type": data_type, "content": content });
+        if room_id.is_empty() {
+            extension.global.push(event);
+        } else {
+            extension
+                .rooms
+                .entry(room_id.to_string())
+                .or_default()
+                .push(event);
+        }
+    };
+    for ((room_id, data_type), content) in &current {
+        if conn.account_data.get(&(room_id.clone(), data_type.clone())) != Some(content) {
+            add(room_id, data_type, content.clone());
+        }
+    }
+    // Global entries and those of rooms still in view that went away
+    let in_view: HashSet<&str> = rooms.iter().map(|r| r.as_str()).collect();
+    for (room_id, data_type) in conn.account_data.keys() {
+        if !current.contains_key(&(room_id.clone(), data_type.clone()))
+            && (room_id.is_empty() || in_view.contains(room_id.as_str()))
+        {
+            add(room_id, data_type, serde_json::json!({}));
+        }
+    }
+    conn.account_data
+        .retain(|(room_id, _), _| !room_id.is_empty() && !in_view.contains(room_id.as_str()));
+    conn.account_data.extend(current);
+    extension
+}
+
+/// The `thread_subscriptions` extension: subscriptions and unsubscriptions
+/// since `since` (only current subscriptions on a new connection), newest
+/// first up to `limit`, with a `prev_batch` to page through the rest.
+async fn thread_subscriptions_extension(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    joined_rooms: &[String],
+    user_id: &str,
+    since: Option<i64>,
+    limit: usize,
+) -> ThreadSubscriptionsExtension {
+    let (changes, prev_batch) =
+        thread_subscriptions::changes_between(storage, user_id, joined_rooms, since, None, limit)
+            .await;
+    ThreadSubscriptionsExtension {
+        changes,
+        prev_batch,
+    }
+}
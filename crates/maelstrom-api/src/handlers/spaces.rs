@@ -5,15 +5,26 @@
 //! (keyed by the child room ID), and a child can point back to its parent
 //! with `m.space.parent`.
 //!
-//! The hierarchy endpoint walks this tree starting from a given space room,
-//! returning the rooms reachable from it up to a configurable depth. Each
-//! entry includes stripped state (name, topic, avatar, join rules, membership
-//! counts) so clients can render a browseable directory without joining every
-//! room.
+//! The hierarchy endpoint walks this tree depth-first starting from a given
+//! space room, returning the rooms reachable from it up to a configurable
+//! depth. Each entry includes stripped state (name, topic, avatar, join rules,
+//! membership counts) so clients can render a browseable directory without
+//! joining every room.
 //!
-//! Rooms that are not world-readable or that the user has not joined are
-//! filtered out. The `suggested_only` parameter limits results to rooms marked
-//! as suggested by the space.
+//! Children this server does not participate in are resolved over federation
+//! by asking the `via` servers of the `m.space.child` event for
+//! `/_matrix/federation/v1/hierarchy/{roomId}`. Remote responses are cached
+//! for a few minutes so that paginating through a large space does not hit
+//! the remote servers on every page.
+//!
+//! Rooms the user has not joined (or been invited to) are only included when
+//! they are peekable -- world-readable, or with a join rule that lets outsiders
+//! in. The `suggested_only` parameter limits results to rooms marked as
+//! suggested by the space. Pagination tokens (`from` / `next_batch`) are
+//! offsets into the traversal order, which is stable because children are
+//! sorted by their `order` field, timestamp and room ID. A token also records
+//! the `suggested_only` and `max_depth` it was issued for, and is rejected
+//! with `M_INVALID_PARAM` when those change between pages.
 //!
 //! # Endpoints
 //!
@@ -24,18 +35,36 @@
 //! # Matrix spec
 //!
 //! * [Spaces](https://spec.matrix.org/v1.18/client-server-api/#spaces)
+//! * [Server-Server hierarchy](https://spec.matrix.org/v1.18/server-server-api/#spaces)
+
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
 
 use axum::extract::{Path, Query, State};
 use axum::routing::get;
 use axum::{Json, Router};
+use dashmap::DashMap;
 use serde::Deserialize;
 
-use maelstrom_core::matrix::error::MatrixError;
-use maelstrom_core::matrix::room::{JoinRule, Membership, event_type as et};
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_core::matrix::room::Membership;
+use maelstrom_federation::hierarchy::{child_entries, is_accessible, room_summary};
 
 use crate::extractors::AuthenticatedUser;
 use crate::state::AppState;
 
+/// How long a remote `/hierarchy` response is reused before asking again.
+const REMOTE_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Upper bound on the page size a client may request.
+const MAX_LIMIT: usize = 100;
+
+/// Remote hierarchy responses keyed by `(room_id, suggested_only)`.
+static REMOTE_CACHE: LazyLock<DashMap<(String, bool), (Instant, serde_json::Value)>> =
+    LazyLock::new(DashMap::new);
+
 pub fn routes() -> Router<AppState> {
     Router::new().route(
         "/_matrix/client/v1/rooms/{roomId}/hierarchy",
@@ -49,7 +78,6 @@ struct HierarchyQuery {
     limit: usize,
     #[serde(default = "default_max_depth")]
     max_depth: usize,
-    #[allow(dead_code)]
     from: Option<String>,
     #[serde(default)]
     suggested_only: bool,
@@ -63,130 +91,237 @@ fn default_max_depth() -> usize {
     5
 }
 
+/// A room waiting to be visited during the traversal.
+struct Pending {
+    room_id: String,
+    depth: usize,
+    via: Vec<String>,
+}
+
+/// Per-request knowledge gathered from remote hierarchy responses.
+#[derive(Default)]
+struct RemoteHints {
+    /// Child summaries returned alongside a remote parent.
+    children: HashMap<String, serde_json::Value>,
+    /// Children a remote server told us we may not see.
+    inaccessible: HashSet<String>,
+}
+
 /// GET /rooms/{roomId}/hierarchy — traverse the space hierarchy.
 async fn get_hierarchy(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     Path(room_id): Path<String>,
     Query(query): Query<HierarchyQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
-    let storage = state.storage();
+    let offset = match query.from.as_deref() {
+        Some(token) => parse_token(token, &query).ok_or_else(|| {
+            MatrixError::new(
+                http::StatusCode::BAD_REQUEST,
+                ErrorCode::InvalidParam,
+                "Invalid pagination token, or the query changed between pages",
+            )
+        })?,
+        None => 0,
+    };
+    let limit = query.limit.clamp(1, MAX_LIMIT);
+    let user_id = auth.user_id.to_string();
 
-    // Build hierarchy by traversing m.space.child state events
+    let mut hints = RemoteHints::default();
     let mut rooms = Vec::new();
-    let mut visited = std::collections::HashSet::new();
-    let mut queue = vec![(room_id.clone(), 0usize)];
+    let mut next_batch = None;
+    let mut position = 0usize;
+    let mut visited = HashSet::new();
+    let mut stack = vec![Pending {
+        room_id: room_id.clone(),
+        depth: 0,
+        via: Vec::new(),
+    }];
 
-    while let Some((current_room, depth)) = queue.pop() {
-        if depth > query.max_depth || visited.contains(&current_room) || rooms.len() >= query.limit
-        {
+    while let Some(pending) = stack.pop() {
+        if !visited.insert(pending.room_id.clone()) {
             continue;
         }
-        visited.insert(current_room.clone());
 
-        // Get room info
-        let _room = match storage.get_room(&current_room).await {
-            Ok(r) => r,
-            Err(_) => continue,
+        let Some(mut summary) =
+            resolve_room(&state, &user_id, &pending, query.suggested_only, &mut hints).await
+        else {
+            if pending.depth == 0 {
+                return Err(MatrixError::forbidden(
+                    "You are not allowed to view this room",
+                ));
+            }
+            continue;
         };
 
-        // Get current state to extract name, topic, membership count
-        let current_state = storage
-            .get_current_state(&current_room)
-            .await
-            .unwrap_or_default();
-
-        let name = current_state
-            .iter()
-            .find(|e| e.event_type == et::NAME)
-            .and_then(|e| e.content.get("name").and_then(|n| n.as_str()))
-            .map(|s| s.to_string());
-
-        let topic = current_state
-            .iter()
-            .find(|e| e.event_type == et::TOPIC)
-            .and_then(|e| e.content.get("topic").and_then(|t| t.as_str()))
-            .map(|s| s.to_string());
-
-        let canonical_alias = current_state
-            .iter()
-            .find(|e| e.event_type == et::CANONICAL_ALIAS)
-            .and_then(|e| e.content.get("alias").and_then(|a| a.as_str()))
-            .map(|s| s.to_string());
-
-        let join_rule = current_state
-            .iter()
-            .find(|e| e.event_type == et::JOIN_RULES)
-            .and_then(|e| e.content.get("join_rule").and_then(|j| j.as_str()))
-            .unwrap_or(JoinRule::Invite.as_str());
-
-        let num_joined = storage
-            .get_room_members(&current_room, Membership::Join.as_str())
-            .await
-            .map(|m| m.len())
-            .unwrap_or(0);
-
-        // Determine room type (space or regular)
-        let room_type = current_state
-            .iter()
-            .find(|e| e.event_type == et::CREATE)
-            .and_then(|e| e.content.get("type").and_then(|t| t.as_str()))
-            .map(|s| s.to_string());
-
-        // Collect children (m.space.child state events)
-        let mut children_state = Vec::new();
-        for event in &current_state {
-            if event.event_type == et::SPACE_CHILD
-                && let Some(state_key) = &event.state_key
-            {
-                let suggested = event
-                    .content
-                    .get("suggested")
-                    .and_then(|s| s.as_bool())
-                    .unwrap_or(false);
-
-                if query.suggested_only && !suggested {
-                    continue;
-                }
-
-                // Check via is not empty (required per spec)
-                let has_via = event
-                    .content
-                    .get("via")
-                    .and_then(|v| v.as_array())
-                    .is_some_and(|a| !a.is_empty());
+        let children = child_entries(&summary);
+        if query.suggested_only {
+            let suggested: Vec<serde_json::Value> = children
+                .iter()
+                .filter(|c| c.suggested)
+                .map(|c| c.event.clone())
+                .collect();
+            summary["children_state"] = serde_json::json!(suggested);
+        }
 
-                if has_via {
-                    children_state.push(event.to_client_event().into_json());
-                    queue.push((state_key.clone(), depth + 1));
+        if pending.depth < query.max_depth {
+            // Push in reverse so the first child is visited next (depth-first, in order).
+            for child in children.into_iter().rev() {
+                if (query.suggested_only && !child.suggested)
+                    || visited.contains(&child.room_id)
+                    || hints.inaccessible.contains(&child.room_id)
+                {
+                    continue;
                 }
+                stack.push(Pending {
+                    room_id: child.room_id,
+                    depth: pending.depth + 1,
+                    via: child.via,
+                });
             }
         }
 
-        let mut room_entry = serde_json::json!({
-            "room_id": current_room,
-            "num_joined_members": num_joined,
-            "world_readable": join_rule == JoinRule::Public.as_str(),
-            "guest_can_join": false,
-            "join_rule": join_rule,
-            "children_state": children_state,
-        });
-
-        if let Some(n) = name {
-            room_entry["name"] = serde_json::json!(n);
-        }
-        if let Some(t) = topic {
-            room_entry["topic"] = serde_json::json!(t);
+        if position >= offset {
+            if rooms.len() >= limit {
+                next_batch = Some(format_token(position, &query));
+                break;
+            }
+            rooms.push(summary);
         }
-        if let Some(a) = canonical_alias {
-            room_entry["canonical_alias"] = serde_json::json!(a);
+        position += 1;
+    }
+
+    let mut response = serde_json::json!({ "rooms": rooms });
+    if let Some(token) = next_batch {
+        response["next_batch"] = serde_json::json!(token);
+    }
+    Ok(Json(response))
+}
+
+/// Encode a pagination token: `{offset}_{max_depth}_{suggested_only}`.
+fn format_token(offset: usize, query: &HierarchyQuery) -> String {
+    format!(
+        "{offset}_{}_{}",
+        query.max_depth,
+        u8::from(query.suggested_only)
+    )
+}
+
+/// Decode a pagination token into its offset, if it was issued for the same
+/// `max_depth` and `suggested_only` as this query.
+fn parse_token(token: &str, query: &HierarchyQuery) -> Option<usize> {
+    let mut parts = token.split('_');
+    let offset = parts.next()?.parse::<usize>().ok()?;
+    let max_depth = parts.next()?.parse::<usize>().ok()?;
+    let suggested_only = parts.next()? == "1";
+    (parts.next().is_none()
+        && max_depth == query.max_depth
+        && suggested_only == query.suggested_only)
+        .then_some(offset)
+}
+
+/// Produce the summary of a room for this user, locally if possible and over
+/// federation otherwise. Returns `None` if the room is unknown or not visible.
+async fn resolve_room(
+    state: &AppState,
+    user_id: &str,
+    pending: &Pending,
+    suggested_only: bool,
+    hints: &mut RemoteHints,
+) -> Option<serde_json::Value> {
+    let storage = state.storage();
+
+    if let Some(summary) = room_summary(storage, &pending.room_id).await {
+        let membership = storage.get_membership(user_id, &pending.room_id).await.ok();
+        let is_member = membership.as_deref() == Some(Membership::Join.as_str())
+            || membership.as_deref() == Some(Membership::Invite.as_str());
+        return (is_member || is_accessible(&summary)).then_some(summary);
+    }
+
+    // A remote parent may already have told us about this child. Plain rooms need
+    // nothing more; spaces still need their own children, so ask for those.
+    let hint = hints.children.get(&pending.room_id).map(|summary| {
+        let mut summary = summary.clone();
+        summary["children_state"] = serde_json::json!([]);
+        summary
+    });
+    if let Some(summary) = &hint
+        && summary.get("room_type").and_then(|t| t.as_str()) != Some("m.space")
+    {
+        return hint;
+    }
+
+    let Some(response) = fetch_remote_hierarchy(state, pending, suggested_only).await else {
+        return hint;
+    };
+
+    if let Some(children) = response.get("children").and_then(|c| c.as_array()) {
+        for child in children {
+            if let Some(id) = child.get("room_id").and_then(|r| r.as_str()) {
+                hints.children.insert(id.to_string(), child.clone());
+            }
         }
-        if let Some(rt) = room_type {
-            room_entry["room_type"] = serde_json::json!(rt);
+    }
+    if let Some(ids) = response
+        .get("inaccessible_children")
+        .and_then(|c| c.as_array())
+    {
+        hints
+            .inaccessible
+            .extend(ids.iter().filter_map(|id| id.as_str().map(String::from)));
+    }
+
+    let mut summary = response.get("room")?.clone();
+    if summary.get("children_state").is_none() {
+        summary["children_state"] = serde_json::json!([]);
+    }
+    Some(summary)
+}
+
+/// Ask the `via` servers for a room's hierarchy, using the cache when fresh.
+async fn fetch_remote_hierarchy(
+    state: &AppState,
+    pending: &Pending,
+    suggested_only: bool,
+) -> Option<serde_json::Value> {
+    let key = (pending.room_id.clone(), suggested_only);
+    if let Some(entry) = REMOTE_CACHE.get(&key) {
+        let (fetched_at, response) = entry.value();
+        if fetched_at.elapsed() < REMOTE_CACHE_TTL {
+            return Some(response.clone());
         }
+    }
+
+    let fed = state.federation()?;
+    let local_server = state.server_name().as_str();
 
-        rooms.push(room_entry);
+    let mut servers = pending.via.clone();
+    let room_server = server_name_from_sigil_id(&pending.room_id);
+    if !room_server.is_empty() && !servers.iter().any(|s| s == room_server) {
+        servers.push(room_server.to_string());
+    }
+
+    let path = format!(
+        "/_matrix/federation/v1/hierarchy/{}?suggested_only={}",
+        crate::handlers::util::percent_encode(&pending.room_id),
+        suggested_only,
+    );
+
+    for server in servers.iter().filter(|s| s.as_str() != local_server) {
+        match fed.get(server, &path).await {
+            Ok(response) if response.get("room").is_some() => {
+                REMOTE_CACHE.retain(|_, (fetched_at, _)| fetched_at.elapsed() < REMOTE_CACHE_TTL);
+                REMOTE_CACHE.insert(key, (Instant::now(), response.clone()));
+                return Some(response);
+            }
+            Ok(_) => {
+                tracing::debug!(server = %server, room_id = %pending.room_id, "Remote hierarchy response missing room");
+            }
+            Err(e) => {
+                tracing::debug!(server = %server, room_id = %pending.room_id, error = %e, "Remote hierarchy query failed");
+            }
+        }
     }
 
-    Ok(Json(serde_json::json!({ "rooms": rooms })))
+    None
 }
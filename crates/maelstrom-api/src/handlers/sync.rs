@@ -1,4 +1,4 @@
-//! Sync endpoints -- traditional `/sync` and sliding sync.
+//! Sync endpoints -- traditional `/sync`.
 //!
 //! Implements the following Matrix Client-Server API endpoints
 //! ([spec: 10 Sync](https://spec.matrix.org/v1.18/client-server-api/#syncing)):
@@ -6,7 +6,9 @@
 //! | Method | Path | Handler |
 //! |--------|------|---------|
 //! | `GET`  | `/_matrix/client/v3/sync` | Traditional sync |
-//! | `POST` | `/_matrix/client/v3/sync` | Sliding sync (MSC3575) |
+//!
+//! Sliding sync (`POST /sync` and MSC4186) lives in
+//! [`sliding_sync`](super::sliding_sync) and shares some of the helpers here.
 //!
 //! # Traditional sync (`GET /sync`)
 //!
@@ -42,6 +44,8 @@
 //!   (respects `history_visibility`).
 //! - **`to_device`** -- Encrypted key-sharing and other device-to-device messages.
 //! - **`device_lists`** -- Users whose device lists changed since `since`.
+//! - **`device_one_time_keys_count`** / **`device_unused_fallback_key_types`**
+//!   -- The syncing device's remaining one-time keys and unused fallback keys.
 //! - **`account_data`** -- Global account data (push rules, ignored users, etc.).
 //! - **`presence`** -- Presence status for users in shared rooms.
 //!
@@ -57,23 +61,23 @@
 //! ## Filters
 //!
 //! The `filter` query parameter accepts either inline JSON or a stored filter
-//! ID (looked up from account data). Supported filter fields include
-//! `room.timeline.limit`, `room.timeline.types`, `room.state.types`,
-//! `room.state.lazy_load_members`, and `room.include_leave`.
-//!
-//! # Sliding sync (`POST /sync`, MSC3575)
-//!
-//! A bandwidth-efficient alternative where the client declares named **room
-//! lists** with index ranges (e.g., "show me rooms 0-19 sorted by recency").
-//! The server returns data only for rooms within the requested ranges that
-//! have changed since the last `pos`.
+//! ID (looked up from account data). The whole grammar applies (see
+//! [`maelstrom_core::matrix::filter`]):
 //!
-//! Key differences from traditional sync:
-//! - Rooms are sorted by latest activity (most recent first).
-//! - The response includes `lists` with `SYNC` operations containing room IDs.
-//! - Room data uses `required_state` patterns instead of sending all state.
-//! - Extensions (`e2ee`, `to_device`, `account_data`) are opt-in.
-//! - The `pos` token works the same way as `next_batch` (stream position).
+//! - `room.rooms`/`room.not_rooms` pick the rooms in the `rooms` section, and
+//!   `room.include_leave` whether left rooms appear in an initial sync.
+//! - `room.timeline`, `room.state`, `room.ephemeral` and `room.account_data`
+//!   filter each section by type (with `*` wildcards), sender, room and
+//!   `contains_url`; `room.timeline.limit` caps the timeline.
+//! - `room.state.lazy_load_members` only sends the members of the timeline's
+//!   senders, and only those the device does not have yet unless
+//!   `include_redundant_members` is set (see [`crate::sync_state`]).
+//! - `room.timeline.unread_thread_notifications` is accepted but has no
+//!   effect: notification counts are not tracked (they are always zero), so
+//!   there are no per-thread counts to split off.
+//! - `presence` and `account_data` filter the global sections.
+//! - `event_format` (`client` or `federation`) and `event_fields` shape every
+//!   room event.
 
 use std::collections::{HashMap, HashSet};
 use std::time::Duration;
@@ -84,18 +88,20 @@ use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::event::Pdu;
+use maelstrom_core::matrix::filter::{EventFilter, Filter, RoomEventFilter};
 use maelstrom_core::matrix::room::{HistoryVisibility, Membership, event_type as et};
 
-use crate::extractors::{AuthenticatedUser, MatrixJson};
+use crate::extractors::AuthenticatedUser;
 use crate::state::AppState;
+use crate::sync_state::SentMembers;
 
 /// Register sync routes.
 ///
 /// Routes:
 /// - `GET  /_matrix/client/v3/sync` -- traditional sync (initial + incremental)
-/// - `POST /_matrix/client/v3/sync` -- sliding sync (MSC3575)
 pub fn routes() -> Router<AppState> {
-    Router::new().route("/_matrix/client/v3/sync", get(sync).post(sliding_sync))
+    Router::new().route("/_matrix/client/v3/sync", get(sync))
 }
 
 // ---------------------------------------------------------------------------
@@ -133,6 +139,10 @@ struct SyncResponse {
     to_device: Option<SyncToDevice>,
     #[serde(skip_serializing_if = "Option::is_none")]
     device_lists: Option<DeviceLists>,
+    /// One-time keys the syncing device has left, by algorithm.
+    device_one_time_keys_count: serde_json::Value,
+    /// Algorithms whose fallback key the device should replace (MSC2732).
+    device_unused_fallback_key_types: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     account_data: Option<AccountDataResponse>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -150,7 +160,7 @@ struct PresenceResponse {
 /// previous sync. `left` lists user IDs who no longer share any rooms with
 /// the syncing user (their device keys can be discarded).
 #[derive(Serialize)]
-struct DeviceLists {
+pub(crate) struct DeviceLists {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     changed: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -175,8 +185,8 @@ struct RoomsResponse {
 }
 
 #[derive(Serialize)]
-struct AccountDataResponse {
-    events: Vec<serde_json::Value>,
+pub(crate) struct AccountDataResponse {
+    pub(crate) events: Vec<serde_json::Value>,
 }
 
 /// Per-room data for a joined room in the sync response.
@@ -199,6 +209,24 @@ struct JoinedRoomResponse {
     summary: Option<RoomSummary>,
 }
 
+impl JoinedRoomResponse {
+    /// A room entry with nothing in it yet.
+    fn empty(prev_batch: String) -> Self {
+        Self {
+            timeline: TimelineResponse {
+                events: vec![],
+                prev_batch,
+                limited: false,
+            },
+            state: StateResponse { events: vec![] },
+            ephemeral: EphemeralResponse { events: vec![] },
+            unread_notifications: UnreadNotifications::default(),
+            account_data: None,
+            summary: None,
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct RoomSummary {
     #[serde(rename = "m.joined_member_count")]
@@ -230,10 +258,10 @@ struct EphemeralResponse {
     events: Vec<serde_json::Value>,
 }
 
-#[derive(Serialize)]
-struct UnreadNotifications {
-    highlight_count: u64,
-    notification_count: u64,
+#[derive(Serialize, Default)]
+pub(crate) struct UnreadNotifications {
+    pub(crate) highlight_count: u64,
+    pub(crate) notification_count: u64,
 }
 
 async fn sync(
@@ -244,18 +272,9 @@ async fn sync(
     let storage = state.storage();
     let user_id = auth.user_id.to_string();
 
-    // Handle set_presence query parameter
-    if let Some(ref presence) = query.set_presence {
-        match presence.as_str() {
-            "online" | "offline" | "unavailable" => {
-                state.ephemeral().set_presence(&user_id, presence, None);
-            }
-            _ => {} // ignore invalid values
-        }
-    } else {
-        // Default: set user to "online" on sync
-        state.ephemeral().set_presence(&user_id, "online", None);
-    }
+    // Mark the user as syncing (see `crate::presence`) until this returns
+    let _presence =
+        crate::presence::SyncPresence::start(&state, &user_id, query.set_presence.as_deref()).await;
 
     let since: i64 = query
         .since
@@ -267,83 +286,44 @@ async fn sync(
     let timeout = query.timeout.unwrap_or(0);
     let device_id = auth.device_id.to_string();
 
-    // Parse sync filter (can be inline JSON or a filter ID)
-    let sync_filter: Option<serde_json::Value> = if let Some(ref filter_str) = query.filter {
-        if filter_str.starts_with('{') {
-            // Inline JSON filter
-            serde_json::from_str(filter_str).ok()
-        } else {
-            // Filter ID — look up from account data
-            let filter_key = format!("_maelstrom.filter.{filter_str}");
-            storage
-                .get_account_data(&user_id, None, &filter_key)
-                .await
-                .ok()
-        }
-    } else {
-        None
+    // The sync filter, inline JSON or a filter ID
+    let loaded_filter = match query.filter.as_deref() {
+        Some(filter) => super::capabilities::load_filter(storage, &user_id, filter).await,
+        None => None,
     };
-
-    // Extract filter settings
-    let include_leave = sync_filter
-        .as_ref()
-        .and_then(|f| f.get("room"))
-        .and_then(|r| r.get("include_leave"))
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
-    let timeline_limit = sync_filter
-        .as_ref()
-        .and_then(|f| f.get("room"))
-        .and_then(|r| r.get("timeline"))
-        .and_then(|t| t.get("limit"))
-        .and_then(|l| l.as_u64())
-        .map(|l| l as usize);
-
-    let timeline_types: Option<Vec<String>> = sync_filter
-        .as_ref()
-        .and_then(|f| f.get("room"))
-        .and_then(|r| r.get("timeline"))
-        .and_then(|t| t.get("types"))
-        .and_then(|t| t.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                .collect()
-        });
-
-    let state_types: Option<Vec<String>> = sync_filter
-        .as_ref()
-        .and_then(|f| f.get("room"))
-        .and_then(|r| r.get("state"))
-        .and_then(|t| t.get("types"))
-        .and_then(|t| t.as_array())
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(|s| s.to_string()))
-                .collect()
-        });
-
-    let lazy_load_members = sync_filter
-        .as_ref()
-        .and_then(|f| f.get("room"))
-        .and_then(|r| r.get("state"))
-        .and_then(|s| s.get("lazy_load_members"))
-        .and_then(|v| v.as_bool())
-        .unwrap_or(false);
-
-    // Get user's rooms by membership state
+    let has_filter = loaded_filter.is_some();
+    let filter = loaded_filter.unwrap_or_default();
+    let include_leave = filter.room.include_leave;
+    let sent_members = state.sync_state().sent_members(&user_id, &device_id);
+
+    // Get user's rooms by membership state. Only the rooms the filter allows
+    // appear in the `rooms` section; presence and device lists still cover
+    // every joined room.
     let joined_rooms = storage
         .get_joined_rooms(&user_id)
         .await
         .map_err(crate::extractors::storage_error)?;
+    let synced_rooms: Vec<String> = joined_rooms
+        .iter()
+        .filter(|room_id| filter.room.allows_room(room_id))
+        .cloned()
+        .collect();
 
-    let invited_rooms = storage
+    let invited_rooms: Vec<String> = storage
         .get_invited_rooms(&user_id)
         .await
-        .unwrap_or_default();
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|room_id| filter.room.allows_room(room_id))
+        .collect();
 
-    let left_rooms = storage.get_left_rooms(&user_id).await.unwrap_or_default();
+    let left_rooms: Vec<String> = storage
+        .get_left_rooms(&user_id)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|room_id| filter.room.allows_room(room_id))
+        .collect();
 
     // Get current stream position
     let current_position = storage
@@ -355,21 +335,32 @@ async fn sync(
 
     // Build the sync response
     let join_map = if is_initial {
+        // A fresh start: the device has no lazy-loaded members yet
+        sent_members.reset();
         build_initial_sync(
             storage,
-            &joined_rooms,
+            &synced_rooms,
             current_position,
             &user_id,
-            lazy_load_members,
+            &filter,
+            &sent_members,
         )
         .await?
     } else {
-        build_incremental_sync(storage, &joined_rooms, since, &user_id, timeline_limit).await?
+        build_incremental_sync(
+            storage,
+            &synced_rooms,
+            since,
+            &user_id,
+            &filter,
+            &sent_members,
+        )
+        .await?
     };
 
     // Fetch to-device messages
     let to_device_events = storage
-        .get_to_device_messages(&user_id, &device_id, since)
+        .get_to_device_messages(&user_id, &device_id, since, current_position)
         .await
         .unwrap_or_default();
 
@@ -397,62 +388,29 @@ async fn sync(
     // Check ephemeral events (typing, receipts) before deciding to long-poll.
     // force_ephemeral=false: before long-poll, only include rooms with active
     // ephemeral data (non-empty typing or receipts).
-    let mut join_map =
-        add_ephemeral_events(storage, state.ephemeral(), join_map, &joined_rooms, false).await?;
+    let mut join_map = add_ephemeral_events(
+        storage,
+        state.ephemeral(),
+        join_map,
+        &synced_rooms,
+        &user_id,
+        false,
+        &filter.room.ephemeral,
+    )
+    .await?;
 
     // Add per-room account_data — check ALL joined rooms, not just those
     // already in join_map, so that account-data-only changes are included.
     if !is_initial {
-        for room_id in &joined_rooms {
-            let room_ad = storage
-                .get_all_room_account_data(&user_id, room_id)
-                .await
-                .unwrap_or_default();
-            if !room_ad.is_empty() {
-                // MSC3391: handle deleted room account data with _pos tracking
-                let events: Vec<_> = room_ad
-                    .into_iter()
-                    .filter_map(|(dtype, content)| {
-                        let is_deleted =
-                            content.get("_msc3391_deleted").and_then(|v| v.as_bool()) == Some(true);
-                        if is_deleted {
-                            let del_pos = content.get("_pos").and_then(|v| v.as_i64()).unwrap_or(0);
-                            if del_pos > since {
-                                Some(serde_json::json!({ "type": dtype, "content": {} }))
-                            } else {
-                                None
-                            }
-                        } else {
-                            Some(serde_json::json!({ "type": dtype, "content": content }))
-                        }
-                    })
-                    .collect();
-                if !events.is_empty() {
-                    if let Some(room_response) = join_map.get_mut(room_id.as_str()) {
-                        room_response.account_data = Some(AccountDataResponse { events });
-                    } else {
-                        join_map.insert(
-                            room_id.clone(),
-                            JoinedRoomResponse {
-                                state: StateResponse { events: vec![] },
-                                timeline: TimelineResponse {
-                                    events: vec![],
-                                    prev_batch: since.to_string(),
-                                    limited: false,
-                                },
-                                ephemeral: EphemeralResponse { events: vec![] },
-                                unread_notifications: UnreadNotifications {
-                                    highlight_count: 0,
-                                    notification_count: 0,
-                                },
-                                account_data: Some(AccountDataResponse { events }),
-                                summary: None,
-                            },
-                        );
-                    }
-                }
-            }
-        }
+        add_room_account_data(
+            storage,
+            &mut join_map,
+            &synced_rooms,
+            &user_id,
+            since,
+            &filter.room.account_data,
+        )
+        .await;
     }
 
     // Check if there are any new events (including ephemeral)
@@ -474,162 +432,77 @@ async fn sync(
         let mut join_map = build_incremental_sync_with_ephemeral(
             storage,
             state.ephemeral(),
-            &joined_rooms,
+            &synced_rooms,
             since,
             &user_id,
+            &filter,
+            &sent_members,
         )
         .await?;
 
         // Check per-room account_data for all joined rooms (handles account_data-only changes)
-        for room_id in &joined_rooms {
-            let room_ad = storage
-                .get_all_room_account_data(&user_id, room_id)
-                .await
-                .unwrap_or_default();
-            if !room_ad.is_empty() {
-                // MSC3391: handle deleted room account data
-                let events: Vec<_> = room_ad
-                    .into_iter()
-                    .filter_map(|(dtype, content)| {
-                        let is_deleted =
-                            content.get("_msc3391_deleted").and_then(|v| v.as_bool()) == Some(true);
-                        if is_deleted {
-                            let del_pos = content.get("_pos").and_then(|v| v.as_i64()).unwrap_or(0);
-                            if del_pos > since {
-                                Some(serde_json::json!({ "type": dtype, "content": {} }))
-                            } else {
-                                None
-                            }
-                        } else {
-                            Some(serde_json::json!({ "type": dtype, "content": content }))
-                        }
-                    })
-                    .collect();
-                let ad_response = AccountDataResponse { events };
-                if let Some(room_response) = join_map.get_mut(room_id.as_str()) {
-                    room_response.account_data = Some(ad_response);
-                } else {
-                    join_map.insert(
-                        room_id.clone(),
-                        JoinedRoomResponse {
-                            state: StateResponse { events: vec![] },
-                            timeline: TimelineResponse {
-                                events: vec![],
-                                prev_batch: since.to_string(),
-                                limited: false,
-                            },
-                            ephemeral: EphemeralResponse { events: vec![] },
-                            unread_notifications: UnreadNotifications {
-                                highlight_count: 0,
-                                notification_count: 0,
-                            },
-                            account_data: Some(ad_response),
-                            summary: None,
-                        },
-                    );
-                }
-            }
-        }
+        add_room_account_data(
+            storage,
+            &mut join_map,
+            &synced_rooms,
+            &user_id,
+            since,
+            &filter.room.account_data,
+        )
+        .await;
 
         let to_device_events = storage
-            .get_to_device_messages(&user_id, &device_id, since)
+            .get_to_device_messages(&user_id, &device_id, since, new_position)
             .await
             .unwrap_or_default();
 
         // Re-query invited/left rooms after wake-up
-        let invited_rooms = storage
+        let invited_rooms: Vec<String> = storage
             .get_invited_rooms(&user_id)
             .await
-            .unwrap_or_default();
-        let mut invite_map: HashMap<String, serde_json::Value> = HashMap::new();
-        for room_id in &invited_rooms {
-            let state = storage.get_current_state(room_id).await.unwrap_or_default();
-            let invite_state: Vec<serde_json::Value> = state
-                .iter()
-                .filter(|e| {
-                    e.event_type == et::CREATE
-                        || e.event_type == et::JOIN_RULES
-                        || e.event_type == et::NAME
-                        || (e.event_type == et::MEMBER && e.state_key.as_deref() == Some(&user_id))
-                })
-                .map(|e| e.to_client_event().into_json())
-                .collect();
-            invite_map.insert(
-                room_id.clone(),
-                serde_json::json!({ "invite_state": { "events": invite_state } }),
-            );
-        }
-
-        // Re-query left rooms after wake-up
-        let left_rooms = storage.get_left_rooms(&user_id).await.unwrap_or_default();
-        let mut leave_map: HashMap<String, serde_json::Value> = HashMap::new();
-        {
-            for room_id in &left_rooms {
-                // Get leave position
-                let leave_pos = storage
-                    .get_state_event(room_id, et::MEMBER, &user_id)
-                    .await
-                    .ok()
-                    .map(|e| e.stream_position)
-                    .unwrap_or(new_position);
-
-                // Only include rooms where the leave happened after `since`
-                if leave_pos <= since {
-                    continue;
-                }
-
-                // For incremental sync, include timeline events between
-                // `since` and the leave position, plus the leave event itself.
-                let effective_limit = timeline_limit.unwrap_or(10);
-                let mut timeline_events = Vec::new();
-                if let Ok(events) = storage
-                    .get_room_events(room_id, since, effective_limit + 10, "f")
-                    .await
-                {
-                    for event in events {
-                        if event.stream_position <= leave_pos {
-                            timeline_events.push(event.to_client_event().into_json());
-                            if timeline_events.len() >= effective_limit {
-                                break;
-                            }
-                        }
-                    }
-                }
-                // Ensure the leave event itself is included
-                if let Ok(member_event) =
-                    storage.get_state_event(room_id, et::MEMBER, &user_id).await
-                {
-                    let leave_json = member_event.to_client_event().into_json();
-                    let leave_eid = leave_json
-                        .get("event_id")
-                        .and_then(|e| e.as_str())
-                        .unwrap_or("");
-                    let already = timeline_events
-                        .iter()
-                        .any(|e| e.get("event_id").and_then(|eid| eid.as_str()) == Some(leave_eid));
-                    if !already {
-                        timeline_events.push(leave_json);
-                    }
-                }
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|room_id| filter.room.allows_room(room_id))
+            .collect();
+        let invite_map = build_invites(storage, &invited_rooms, &user_id).await;
 
-                let timeline_events =
-                    annotate_membership(timeline_events, Membership::Leave.as_str());
-                leave_map.insert(room_id.clone(), serde_json::json!({
-                    "state": { "events": [] },
-                    "timeline": { "events": timeline_events, "prev_batch": since.to_string(), "limited": false },
-                }));
-            }
-        }
+        let left_rooms: Vec<String> = storage
+            .get_left_rooms(&user_id)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|room_id| filter.room.allows_room(room_id))
+            .collect();
+        let leave_map = build_left_rooms(
+            storage,
+            &left_rooms,
+            &user_id,
+            since,
+            false,
+            new_position,
+            &filter,
+        )
+        .await;
 
         // Compute device_lists.changed — users in shared rooms with new events
         let device_lists = compute_device_lists(storage, &joined_rooms, &user_id, since).await;
 
         // Build global account_data for response
-        let global_account_data = build_global_account_data(storage, &user_id, false, since).await;
+        let global_account_data =
+            build_global_account_data(storage, &user_id, false, since, &filter.account_data).await;
 
         // Build presence events
-        let presence =
-            build_presence_events(storage, state.ephemeral(), &joined_rooms, &user_id).await;
+        let presence = build_presence_events(
+            storage,
+            state.ephemeral(),
+            &joined_rooms,
+            &user_id,
+            &filter.presence,
+        )
+        .await;
+
+        let (device_one_time_keys_count, device_unused_fallback_key_types) =
+            device_key_counts(storage, &user_id, &device_id).await;
 
         return Ok(Json(SyncResponse {
             next_batch: new_position.to_string(),
@@ -642,6 +515,8 @@ async fn sync(
                 events: to_device_events,
             }),
             device_lists: Some(device_lists),
+            device_one_time_keys_count,
+            device_unused_fallback_key_types,
             account_data: global_account_data,
             presence,
         }));
@@ -650,9 +525,78 @@ async fn sync(
     // Ephemeral events already added above (before long-poll check)
 
     // Build invite section — rooms where user has pending invites
-    // Filter out invites from ignored users
-    let ignored_users: std::collections::HashSet<String> = storage
-        .get_account_data(&user_id, None, "m.ignored_user_list")
+    let invite_map = build_invites(storage, &invited_rooms, &user_id).await;
+
+    // Build leave section. For incremental sync, newly-left rooms always appear
+    // in the leave section regardless of the include_leave filter (per spec, the
+    // filter only controls whether LEFT rooms appear in initial sync). For initial
+    // sync, respect include_leave.
+    let leave_map = if !is_initial || include_leave || !has_filter {
+        build_left_rooms(
+            storage,
+            &left_rooms,
+            &user_id,
+            since,
+            is_initial,
+            current_position,
+            &filter,
+        )
+        .await
+    } else {
+        HashMap::new()
+    };
+
+    // Compute device_lists for initial/immediate response
+    let device_lists = if !is_initial {
+        Some(compute_device_lists(storage, &joined_rooms, &user_id, since).await)
+    } else {
+        None
+    };
+
+    // Build global account_data for response
+    let global_account_data =
+        build_global_account_data(storage, &user_id, is_initial, since, &filter.account_data).await;
+
+    // Build presence events
+    let presence = build_presence_events(
+        storage,
+        state.ephemeral(),
+        &joined_rooms,
+        &user_id,
+        &filter.presence,
+    )
+    .await;
+
+    let (device_one_time_keys_count, device_unused_fallback_key_types) =
+        device_key_counts(storage, &user_id, &device_id).await;
+
+    Ok(Json(SyncResponse {
+        next_batch: current_position.to_string(),
+        rooms: RoomsResponse {
+            join: join_map,
+            invite: invite_map,
+            leave: leave_map,
+        },
+        to_device: Some(SyncToDevice {
+            events: to_device_events,
+        }),
+        device_lists,
+        device_one_time_keys_count,
+        device_unused_fallback_key_types,
+        account_data: global_account_data,
+        presence,
+    }))
+}
+
+/// Build the invite section: stripped state for each room the user is
+/// invited to, leaving out invites from users they ignore.
+async fn build_invites(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    invited_rooms: &[String],
+    user_id: &str,
+) -> HashMap<String, serde_json::Value> {
+    let ignored_users: HashSet<String> = storage
+        .get_account_data(user_id, None, "m.ignored_user_list")
         .await
         .ok()
         .and_then(|v| {
@@ -663,7 +607,7 @@ async fn sync(
         .unwrap_or_default();
 
     let mut invite_map: HashMap<String, serde_json::Value> = HashMap::new();
-    for room_id in &invited_rooms {
+    for room_id in invited_rooms {
         let state = storage.get_current_state(room_id).await.unwrap_or_default();
 
         // Check if the invite was from an ignored user
@@ -671,7 +615,7 @@ async fn sync(
             .iter()
             .find(|e| {
                 e.event_type == et::MEMBER
-                    && e.state_key.as_deref() == Some(&user_id)
+                    && e.state_key.as_deref() == Some(user_id)
                     && e.content.get("membership").and_then(|m| m.as_str())
                         == Some(Membership::Invite.as_str())
             })
@@ -689,7 +633,7 @@ async fn sync(
                 e.event_type == et::CREATE
                     || e.event_type == et::JOIN_RULES
                     || e.event_type == et::NAME
-                    || (e.event_type == et::MEMBER && e.state_key.as_deref() == Some(&user_id))
+                    || (e.event_type == et::MEMBER && e.state_key.as_deref() == Some(user_id))
             })
             .map(|e| e.to_client_event().into_json())
             .collect();
@@ -699,215 +643,255 @@ async fn sync(
             serde_json::json!({ "invite_state": { "events": invite_state } }),
         );
     }
+    invite_map
+}
 
-    // Build leave section. For incremental sync, newly-left rooms always appear
-    // in the leave section regardless of the include_leave filter (per spec, the
-    // filter only controls whether LEFT rooms appear in initial sync). For initial
-    // sync, respect include_leave.
-    // Include forgotten rooms in incremental sync so other devices can see the leave.
-    let mut all_left_rooms = left_rooms.clone();
-    if !is_initial && let Ok(forgotten) = storage.get_forgotten_rooms(&user_id).await {
+/// Build the leave section for the rooms the user has left.
+///
+/// On an incremental sync, only rooms left after `since` appear (forgotten
+/// ones too, so other devices see the leave), with the timeline up to the
+/// leave and the leave event itself. On an initial sync, every left room
+/// appears with the timeline leading up to the leave.
+async fn build_left_rooms(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    left_rooms: &[String],
+    user_id: &str,
+    since: i64,
+    is_initial: bool,
+    current_position: i64,
+    filter: &Filter,
+) -> HashMap<String, serde_json::Value> {
+    let mut all_left_rooms = left_rooms.to_vec();
+    if !is_initial && let Ok(forgotten) = storage.get_forgotten_rooms(user_id).await {
         for room_id in forgotten {
-            if !all_left_rooms.contains(&room_id) {
+            if filter.room.allows_room(&room_id) && !all_left_rooms.contains(&room_id) {
                 all_left_rooms.push(room_id);
             }
         }
     }
-    let mut leave_map: HashMap<String, serde_json::Value> = HashMap::new();
-    if !is_initial || include_leave || sync_filter.is_none() {
-        for room_id in &all_left_rooms {
-            // For incremental sync, only include rooms where the leave happened after `since`
-            if !is_initial {
-                let leave_pos = storage
-                    .get_state_event(room_id, et::MEMBER, &user_id)
-                    .await
-                    .ok()
-                    .map(|e| e.stream_position)
-                    .unwrap_or(0);
-
-                if leave_pos <= since {
-                    continue; // Left before the since token — skip
-                }
-            }
 
-            // Check history visibility
-            let history_vis = storage
-                .get_state_event(room_id, et::HISTORY_VISIBILITY, "")
-                .await
-                .ok()
-                .and_then(|e| {
-                    e.content
-                        .get("history_visibility")
-                        .and_then(|v| v.as_str())
-                        .map(|s| s.to_string())
-                })
-                .unwrap_or_else(|| HistoryVisibility::Shared.as_str().to_string());
+    let timeline_filter = &filter.room.timeline;
+    let mut leave_map: HashMap<String, serde_json::Value> = HashMap::new();
+    for room_id in &all_left_rooms {
+        // The user's leave event marks the departure point
+        let member_event = storage
+            .get_state_event(room_id, et::MEMBER, user_id)
+            .await
+            .ok();
 
-            let can_see_history = HistoryVisibility::parse(&history_vis)
-                .map(|h| h.visible_to_departed())
-                .unwrap_or(false);
+        // For incremental sync, only include rooms where the leave happened after `since`
+        if !is_initial
+            && member_event
+                .as_ref()
+                .is_none_or(|e| e.stream_position <= since)
+        {
+            continue;
+        }
+        let leave_pos = member_event
+            .as_ref()
+            .map_or(current_position, |e| e.stream_position);
 
-            // Get the user's leave event to determine the departure point
-            let leave_pos = storage
-                .get_state_event(room_id, et::MEMBER, &user_id)
-                .await
-                .ok()
-                .map(|e| e.stream_position)
-                .unwrap_or(current_position);
+        // Check history visibility
+        let can_see_history = storage
+            .get_state_event(room_id, et::HISTORY_VISIBILITY, "")
+            .await
+            .ok()
+            .and_then(|e| {
+                e.content
+                    .get("history_visibility")
+                    .and_then(|v| v.as_str())
+                    .and_then(HistoryVisibility::parse)
+            })
+            .unwrap_or(HistoryVisibility::Shared)
+            .visible_to_departed();
 
-            // Determine effective timeline limit
-            let effective_timeline_limit = timeline_limit.unwrap_or(10);
+        let effective_timeline_limit = timeline_filter.limit.unwrap_or(10);
 
-            let mut timeline_events = Vec::new();
-            let mut state_events = Vec::new();
+        let mut timeline_events: Vec<Pdu> = Vec::new();
+        let mut state_events: Vec<Pdu> = Vec::new();
 
-            if can_see_history && effective_timeline_limit > 0 {
-                if is_initial {
-                    // Fetch events backward from the leave position
-                    if let Ok(events) = storage
-                        .get_room_events(room_id, leave_pos + 1, effective_timeline_limit + 10, "b")
-                        .await
-                    {
-                        for event in events {
-                            if event.stream_position <= leave_pos {
-                                // Apply timeline type filter if specified
-                                if let Some(ref types) = timeline_types
-                                    && !types.contains(&event.event_type)
-                                {
-                                    continue;
-                                }
-                                timeline_events.push(event.to_client_event().into_json());
-                                if timeline_events.len() >= effective_timeline_limit {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                } else {
-                    // For incremental sync, include timeline events between
-                    // `since` and the leave position, plus the leave event itself.
-                    if let Ok(events) = storage
-                        .get_room_events(room_id, since, effective_timeline_limit + 10, "f")
-                        .await
-                    {
-                        for event in events {
-                            if event.stream_position <= leave_pos {
-                                if let Some(ref types) = timeline_types
-                                    && !types.contains(&event.event_type)
-                                {
-                                    continue;
-                                }
-                                timeline_events.push(event.to_client_event().into_json());
-                                if timeline_events.len() >= effective_timeline_limit {
-                                    break;
-                                }
-                            }
+        if can_see_history && effective_timeline_limit > 0 {
+            // Initial sync: backward from the leave. Incremental sync:
+            // forward from `since` up to the leave.
+            let (from, dir) = if is_initial {
+                (leave_pos + 1, "b")
+            } else {
+                (since, "f")
+            };
+            if let Ok(events) = storage
+                .get_room_events(room_id, from, effective_timeline_limit + 10, dir)
+                .await
+            {
+                for event in events {
+                    if event.stream_position <= leave_pos && timeline_filter.matches(&event) {
+                        timeline_events.push(event);
+                        if timeline_events.len() >= effective_timeline_limit {
+                            break;
                         }
                     }
                 }
             }
-
-            // Always include the user's own leave event (even when
-            // history_visibility is "joined"), but only if the timeline type
-            // filter allows m.room.member events (or there is no filter).
-            if !is_initial {
-                let leave_passes_filter = timeline_types
-                    .as_ref()
-                    .map(|types| types.contains(&et::MEMBER.to_string()))
-                    .unwrap_or(true);
-                if leave_passes_filter
-                    && let Ok(member_event) =
-                        storage.get_state_event(room_id, et::MEMBER, &user_id).await
-                {
-                    let leave_json = member_event.to_client_event().into_json();
-                    let leave_eid = leave_json
-                        .get("event_id")
-                        .and_then(|e| e.as_str())
-                        .unwrap_or("");
-                    let already = timeline_events
-                        .iter()
-                        .any(|e| e.get("event_id").and_then(|eid| eid.as_str()) == Some(leave_eid));
-                    if !already {
-                        timeline_events.push(leave_json);
-                    }
-                }
-            }
-
-            // For initial sync, reverse to chronological order (backward query returns newest first).
-            // For incremental sync, events are already in chronological order (forward query).
+            // Backward query returns newest first
             if is_initial {
                 timeline_events.reverse();
             }
+        }
 
-            // If timeline_limit is 0, put relevant events in state section instead
-            if effective_timeline_limit == 0 {
-                // Include user's leave event and relevant state in state section
-                if let Ok(member_event) =
-                    storage.get_state_event(room_id, et::MEMBER, &user_id).await
-                {
-                    state_events.push(member_event.to_client_event().into_json());
-                }
-                // Include state events from before the user left
-                if let Ok(events) = storage
-                    .get_room_events(room_id, leave_pos + 1, 50, "b")
-                    .await
-                {
-                    for event in events {
-                        if event.stream_position <= leave_pos
-                            && event.is_state()
-                            && event.event_type != et::MEMBER
-                        {
-                            // Apply state type filter if specified
-                            if let Some(ref types) = state_types
-                                && !types.contains(&event.event_type)
-                            {
-                                continue;
-                            }
-                            state_events.push(event.to_client_event().into_json());
-                        }
-                    }
-                }
-            }
+        // Always include the user's own leave event (even when
+        // history_visibility is "joined") if the timeline filter allows it.
+        if !is_initial
+            && let Some(member_event) = &member_event
+            && timeline_filter.matches(member_event)
+            && !timeline_events
+                .iter()
+                .any(|e| e.event_id == member_event.event_id)
+        {
+            timeline_events.push(member_event.clone());
+        }
 
-            let state_events = annotate_membership(state_events, Membership::Leave.as_str());
-            let timeline_events = annotate_membership(timeline_events, Membership::Leave.as_str());
-            leave_map.insert(
-                room_id.clone(),
-                serde_json::json!({
-                    "state": { "events": state_events },
-                    "timeline": { "events": timeline_events, "prev_batch": current_position.to_string(), "limited": false },
-                }),
-            );
+        // If timeline_limit is 0, put relevant events in state section instead
+        if effective_timeline_limit == 0 {
+            // Include user's leave event and relevant state in state section
+            if let Some(member_event) = &member_event {
+                state_events.push(member_event.clone());
+            }
+            // Include state events from before the user left
+            if let Ok(events) = storage
+                .get_room_events(room_id, leave_pos + 1, 50, "b")
+                .await
+            {
+                state_events.extend(events.into_iter().filter(|event| {
+                    event.stream_position <= leave_pos
+                        && event.is_state()
+                        && event.event_type != et::MEMBER
+                        && filter.room.state.matches(event)
+                }));
+            }
         }
+
+        leave_map.insert(
+            room_id.clone(),
+            serde_json::json!({
+                "state": { "events": render_events(filter, &state_events, Membership::Leave) },
+                "timeline": {
+                    "events": render_events(filter, &timeline_events, Membership::Leave),
+                    "prev_batch": current_position.to_string(),
+                    "limited": false,
+                },
+            }),
+        );
     }
+    leave_map
+}
 
-    // Compute device_lists for initial/immediate response
-    let device_lists = if !is_initial {
-        Some(compute_device_lists(storage, &joined_rooms, &user_id, since).await)
-    } else {
-        None
-    };
+/// How many events [`recent_timeline`] looks through at most for ones that
+/// pass the timeline filter.
+const MAX_TIMELINE_SCAN: usize = 1000;
 
-    // Build global account_data for response
-    let global_account_data = build_global_account_data(storage, &user_id, is_initial, since).await;
+/// The last `limit` events before stream position `before` that pass the
+/// timeline filter, oldest first, and whether earlier ones were left out.
+async fn recent_timeline(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    room_id: &str,
+    before: i64,
+    limit: usize,
+    filter: &RoomEventFilter,
+) -> Result<(Vec<Pdu>, bool), MatrixError> {
+    let mut events = Vec::new();
+    if limit == 0 {
+        return Ok((events, false));
+    }
+    let mut from = before;
+    let mut scanned = 0;
+    loop {
+        let page_size = limit + 10;
+        let page = storage
+            .get_room_events(room_id, from, page_size, "b")
+            .await
+            .map_err(crate::extractors::storage_error)?;
+        let exhausted = page.len() < page_size;
+        scanned += page.len();
+        for event in page {
+            from = event.stream_position;
+            if filter.matches(&event) {
+                if events.len() == limit {
+                    events.reverse();
+                    return Ok((events, true));
+                }
+                events.push(event);
+            }
+        }
+        if exhausted {
+            break;
+        }
+        if scanned >= MAX_TIMELINE_SCAN {
+            events.reverse();
+            return Ok((events, true));
+        }
+    }
+    events.reverse();
+    Ok((events, false))
+}
 
-    // Build presence events
-    let presence = build_presence_events(storage, state.ephemeral(), &joined_rooms, &user_id).await;
+/// Render room events for a sync response as the filter asks, with the
+/// user's `membership` in their `unsigned`.
+fn render_events(
+    filter: &Filter,
+    events: &[Pdu],
+    membership: Membership,
+) -> Vec<serde_json::Value> {
+    events
+        .iter()
+        .map(|e| filter.render(e, Some(membership.as_str())))
+        .collect()
+}
 
-    Ok(Json(SyncResponse {
-        next_batch: current_position.to_string(),
-        rooms: RoomsResponse {
-            join: join_map,
-            invite: invite_map,
-            leave: leave_map,
-        },
-        to_device: Some(SyncToDevice {
-            events: to_device_events,
-        }),
-        device_lists,
-        account_data: global_account_data,
-        presence,
-    }))
+/// Trim the member events in a room's `state` to the ones a lazy-loading
+/// client needs for `timeline`: its senders' and the user's own.
+///
+/// Senders whose member event is not in `state` get their current one,
+/// unless the device already has it and the filter does not ask for
+/// `include_redundant_members`.
+async fn lazy_load_members(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    room_id: &str,
+    user_id: &str,
+    timeline: &[Pdu],
+    state: &mut Vec<Pdu>,
+    filter: &RoomEventFilter,
+    sent_members: &SentMembers<'_>,
+) {
+    let mut needed: HashSet<&str> = timeline.iter().map(|e| e.sender.as_str()).collect();
+    needed.insert(user_id);
+    state.retain(|e| {
+        e.event_type != et::MEMBER
+            || e.state_key
+                .as_deref()
+                .is_some_and(|member| needed.contains(member))
+    });
+
+    let present: HashSet<String> = state
+        .iter()
+        .filter(|e| e.event_type == et::MEMBER)
+        .filter_map(|e| e.state_key.clone())
+        .collect();
+    sent_members.mark_sent(room_id, present.iter().map(String::as_str));
+
+    for member in needed {
+        if present.contains(member) {
+            continue;
+        }
+        let Ok(member_event) = storage.get_state_event(room_id, et::MEMBER, member).await else {
+            continue;
+        };
+        if !filter.matches(&member_event) {
+            continue;
+        }
+        let unsent = !sent_members.mark_sent(room_id, [member]).is_empty();
+        if unsent || filter.include_redundant_members {
+            state.push(member_event);
+        }
+    }
 }
 
 async fn build_initial_sync(
@@ -915,56 +899,47 @@ async fn build_initial_sync(
     joined_rooms: &[String],
     current_position: i64,
     user_id: &str,
-    lazy_load_members: bool,
+    filter: &Filter,
+    sent_members: &SentMembers<'_>,
 ) -> Result<HashMap<String, JoinedRoomResponse>, MatrixError> {
     let mut join_map: HashMap<String, JoinedRoomResponse> = HashMap::new();
+    let timeline_limit = filter.room.timeline.limit.unwrap_or(10);
 
     // TODO(M9): Use futures::future::join_all to fetch room data in parallel
     // for better performance when a user is in many rooms.
     for room_id in joined_rooms {
-        let state_events = storage
+        let mut state_events = storage
             .get_current_state(room_id)
             .await
             .map_err(crate::extractors::storage_error)?;
+        state_events.retain(|e| filter.room.state.matches(e));
 
-        let timeline_events = storage
-            .get_room_events(room_id, current_position + 1, 10, "b")
-            .await
-            .map_err(crate::extractors::storage_error)?;
-
-        let mut timeline_events = timeline_events;
-        timeline_events.reverse();
+        let (timeline_events, limited) = recent_timeline(
+            storage,
+            room_id,
+            current_position + 1,
+            timeline_limit,
+            &filter.room.timeline,
+        )
+        .await?;
 
         let prev_batch = timeline_events
             .first()
             .map(|e| e.stream_position.to_string())
             .unwrap_or_else(|| "0".to_string());
 
-        let timeline_client: Vec<serde_json::Value> = timeline_events
-            .iter()
-            .map(|e| e.to_client_event().into_json())
-            .collect();
-
-        let state_client: Vec<serde_json::Value> = if lazy_load_members {
-            // Only include m.room.member for senders that appear in the timeline
-            let timeline_senders: HashSet<String> =
-                timeline_events.iter().map(|e| e.sender.clone()).collect();
-            state_events
-                .into_iter()
-                .filter(|e| {
-                    e.event_type != et::MEMBER
-                        || e.state_key
-                            .as_ref()
-                            .is_some_and(|sk| timeline_senders.contains(sk))
-                })
-                .map(|e| e.to_client_event().into_json())
-                .collect()
-        } else {
-            state_events
-                .into_iter()
-                .map(|e| e.to_client_event().into_json())
-                .collect()
-        };
+        if filter.room.state.lazy_load_members {
+            lazy_load_members(
+                storage,
+                room_id,
+                user_id,
+                &timeline_events,
+                &mut state_events,
+                &filter.room.state,
+                sent_members,
+            )
+            .await;
+        }
 
         // Fetch per-room account data
         let room_account_data = storage
@@ -973,9 +948,14 @@ async fn build_initial_sync(
             .unwrap_or_default();
         let room_ad_events: Vec<_> = room_account_data
             .into_iter()
-            .filter(|(_, content)| {
+            .filter(|(dtype, content)| {
                 content.get("_msc3391_deleted").and_then(|v| v.as_bool()) != Some(true)
+                    && filter
+                        .room
+                        .account_data
+                        .matches_fields(room_id, dtype, None)
             })
+            .take(filter.room.account_data.limit.unwrap_or(usize::MAX))
             .map(|(dtype, content)| serde_json::json!({ "type": dtype, "content": content }))
             .collect();
         let room_ad = Some(AccountDataResponse {
@@ -994,30 +974,23 @@ async fn build_initial_sync(
             .map(|m| m.len() as u64)
             .unwrap_or(0);
 
-        let state_client = annotate_membership(state_client, Membership::Join.as_str());
-        let timeline_client = annotate_membership(timeline_client, Membership::Join.as_str());
-
         join_map.insert(
             room_id.clone(),
             JoinedRoomResponse {
                 state: StateResponse {
-                    events: state_client,
+                    events: render_events(filter, &state_events, Membership::Join),
                 },
                 timeline: TimelineResponse {
-                    events: timeline_client,
+                    events: render_events(filter, &timeline_events, Membership::Join),
                     prev_batch,
-                    limited: false,
-                },
-                ephemeral: EphemeralResponse { events: vec![] },
-                unread_notifications: UnreadNotifications {
-                    highlight_count: 0,
-                    notification_count: 0,
+                    limited,
                 },
                 account_data: room_ad,
                 summary: Some(RoomSummary {
                     joined_member_count: joined_count,
                     invited_member_count: invited_count,
                 }),
+                ..JoinedRoomResponse::empty(String::new())
             },
         );
     }
@@ -1030,7 +1003,8 @@ async fn build_incremental_sync(
     joined_rooms: &[String],
     since: i64,
     user_id: &str,
-    timeline_limit: Option<usize>,
+    filter: &Filter,
+    sent_members: &SentMembers<'_>,
 ) -> Result<HashMap<String, JoinedRoomResponse>, MatrixError> {
     let new_events = storage
         .get_events_since(since)
@@ -1069,21 +1043,18 @@ async fn build_incremental_sync(
         }
     }
 
-    // Separate state events from timeline events per room.
-    // Timeline entries carry their stream_position so we can compute prev_batch.
-    let mut room_state: HashMap<String, Vec<serde_json::Value>> = HashMap::new();
-    let mut room_timeline: HashMap<String, Vec<(i64, serde_json::Value)>> = HashMap::new();
+    // Separate state events from timeline events per room
+    let mut room_state: HashMap<String, Vec<Pdu>> = HashMap::new();
+    let mut room_timeline: HashMap<String, Vec<Pdu>> = HashMap::new();
     for event in new_events {
         if joined_set.contains(event.room_id.as_str()) {
             let is_newly_joined = newly_joined.contains(&event.room_id);
-            let pos = event.stream_position;
-            let client_event = event.to_client_event().into_json();
 
             if event.is_state() {
                 room_state
                     .entry(event.room_id.clone())
                     .or_default()
-                    .push(client_event.clone());
+                    .push(event.clone());
 
                 // For existing rooms, state events also go in timeline
                 // For newly-joined rooms, state events only go in state section
@@ -1091,14 +1062,14 @@ async fn build_incremental_sync(
                     room_timeline
                         .entry(event.room_id.clone())
                         .or_default()
-                        .push((pos, client_event));
+                        .push(event);
                 }
             } else {
                 // Non-state events always go in timeline
                 room_timeline
                     .entry(event.room_id.clone())
                     .or_default()
-                    .push((pos, client_event));
+                    .push(event);
             }
         }
     }
@@ -1107,45 +1078,36 @@ async fn build_incremental_sync(
     for room_id in joined_rooms {
         let is_newly_joined = newly_joined.contains(room_id);
 
-        let state_events = if is_newly_joined {
+        let mut state_events = if is_newly_joined {
             // For newly joined rooms, include full current state
-            let current_state = storage.get_current_state(room_id).await.unwrap_or_default();
-            current_state
-                .iter()
-                .map(|e| e.to_client_event().into_json())
-                .collect()
+            storage.get_current_state(room_id).await.unwrap_or_default()
         } else {
             room_state.remove(room_id).unwrap_or_default()
         };
+        state_events.retain(|e| filter.room.state.matches(e));
 
-        let mut timeline_with_pos = room_timeline.remove(room_id).unwrap_or_default();
+        let mut timeline_events = room_timeline.remove(room_id).unwrap_or_default();
+        timeline_events.retain(|e| filter.room.timeline.matches(e));
 
         // Apply timeline limit and set limited/prev_batch for gaps
-        let effective_limit = timeline_limit.unwrap_or(20);
-        let limited = is_newly_joined || timeline_with_pos.len() > effective_limit;
-        if timeline_with_pos.len() > effective_limit {
+        let effective_limit = filter.room.timeline.limit.unwrap_or(20);
+        let limited = is_newly_joined || timeline_events.len() > effective_limit;
+        if timeline_events.len() > effective_limit {
             // Keep only the most recent events (last N)
-            timeline_with_pos =
-                timeline_with_pos.split_off(timeline_with_pos.len() - effective_limit);
+            timeline_events = timeline_events.split_off(timeline_events.len() - effective_limit);
         }
 
-        // Compute prev_batch from the first event's stream_position (before
-        // we strip positions out).  When the timeline is limited, prev_batch
-        // points to just before the first returned event so the client can
-        // paginate backward.
+        // When the timeline is limited, prev_batch points to just before the
+        // first returned event so the client can paginate backward.
         let prev_batch = if limited {
-            timeline_with_pos
+            timeline_events
                 .first()
-                .map(|(pos, _)| (*pos - 1).to_string())
+                .map(|e| (e.stream_position - 1).to_string())
                 .unwrap_or_else(|| since.to_string())
         } else {
             since.to_string()
         };
 
-        // Strip stream positions — from here on we only need the JSON values.
-        let timeline_events: Vec<serde_json::Value> =
-            timeline_with_pos.into_iter().map(|(_, ev)| ev).collect();
-
         if state_events.is_empty() && timeline_events.is_empty() {
             continue;
         }
@@ -1153,10 +1115,21 @@ async fn build_incremental_sync(
         // Room summary for incremental sync (include when there are membership changes)
         let has_membership_change = timeline_events
             .iter()
-            .any(|e| e.get("type").and_then(|t| t.as_str()) == Some(et::MEMBER))
-            || state_events
-                .iter()
-                .any(|e| e.get("type").and_then(|t| t.as_str()) == Some(et::MEMBER));
+            .chain(&state_events)
+            .any(|e| e.event_type == et::MEMBER);
+
+        if filter.room.state.lazy_load_members {
+            lazy_load_members(
+                storage,
+                room_id,
+                user_id,
+                &timeline_events,
+                &mut state_events,
+                &filter.room.state,
+                sent_members,
+            )
+            .await;
+        }
 
         let summary = if is_newly_joined || has_membership_change {
             let joined_count = storage
@@ -1177,28 +1150,20 @@ async fn build_incremental_sync(
             None
         };
 
-        // Add unsigned.membership per spec — for joined rooms it's always "join"
-        let state_events = annotate_membership(state_events, Membership::Join.as_str());
-        let timeline_events = annotate_membership(timeline_events, Membership::Join.as_str());
-
+        // unsigned.membership per spec — for joined rooms it's always "join"
         join_map.insert(
             room_id.clone(),
             JoinedRoomResponse {
                 state: StateResponse {
-                    events: state_events,
+                    events: render_events(filter, &state_events, Membership::Join),
                 },
                 timeline: TimelineResponse {
-                    events: timeline_events,
+                    events: render_events(filter, &timeline_events, Membership::Join),
                     prev_batch,
                     limited,
                 },
-                ephemeral: EphemeralResponse { events: vec![] },
-                unread_notifications: UnreadNotifications {
-                    highlight_count: 0,
-                    notification_count: 0,
-                },
-                account_data: None,
                 summary,
+                ..JoinedRoomResponse::empty(String::new())
             },
         );
     }
@@ -1206,22 +1171,45 @@ async fn build_incremental_sync(
     Ok(join_map)
 }
 
-/// Add `unsigned.membership` to a list of client events.
-fn annotate_membership(events: Vec<serde_json::Value>, membership: &str) -> Vec<serde_json::Value> {
-    events
-        .into_iter()
-        .map(|mut e| {
-            if let Some(obj) = e.as_object_mut() {
-                let unsigned = obj
-                    .entry("unsigned")
-                    .or_insert_with(|| serde_json::json!({}));
-                if let Some(u) = unsigned.as_object_mut() {
-                    u.insert("membership".to_string(), serde_json::json!(membership));
+/// Add each room's account data to an incremental sync, creating room
+/// entries for rooms with no other changes.
+async fn add_room_account_data(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    join_map: &mut HashMap<String, JoinedRoomResponse>,
+    joined_rooms: &[String],
+    user_id: &str,
+    since: i64,
+    filter: &RoomEventFilter,
+) {
+    for room_id in joined_rooms {
+        let room_ad = storage
+            .get_all_room_account_data(user_id, room_id)
+            .await
+            .unwrap_or_default();
+        // MSC3391: deletions since `since` are sent once, with empty content
+        let events: Vec<_> = room_ad
+            .into_iter()
+            .filter(|(dtype, _)| filter.matches_fields(room_id, dtype, None))
+            .filter_map(|(dtype, content)| {
+                let is_deleted =
+                    content.get("_msc3391_deleted").and_then(|v| v.as_bool()) == Some(true);
+                if is_deleted {
+                    let del_pos = content.get("_pos").and_then(|v| v.as_i64()).unwrap_or(0);
+                    (del_pos > since).then(|| serde_json::json!({ "type": dtype, "content": {} }))
+                } else {
+                    Some(serde_json::json!({ "type": dtype, "content": content }))
                 }
-            }
-            e
-        })
-        .collect()
+            })
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .collect();
+        if events.is_empty() {
+            continue;
+        }
+        join_map
+            .entry(room_id.clone())
+            .or_insert_with(|| JoinedRoomResponse::empty(since.to_string()))
+            .account_data = Some(AccountDataResponse { events });
+    }
 }
 
 async fn build_incremental_sync_with_ephemeral(
@@ -1230,15 +1218,51 @@ async fn build_incremental_sync_with_ephemeral(
     joined_rooms: &[String],
     since: i64,
     user_id: &str,
+    filter: &Filter,
+    sent_members: &SentMembers<'_>,
 ) -> Result<HashMap<String, JoinedRoomResponse>, MatrixError> {
-    let join_map = build_incremental_sync(storage, joined_rooms, since, user_id, None).await?;
+    let join_map =
+        build_incremental_sync(storage, joined_rooms, since, user_id, filter, sent_members).await?;
     // force_ephemeral=true: after long-poll wake-up, always include ephemeral
     // for all joined rooms so typing-stop (empty user_ids) is delivered.
-    add_ephemeral_events(storage, ephemeral, join_map, joined_rooms, true).await
+    add_ephemeral_events(
+        storage,
+        ephemeral,
+        join_map,
+        joined_rooms,
+        user_id,
+        true,
+        &filter.room.ephemeral,
+    )
+    .await
+}
+
+/// The syncing device's one-time key counts and the algorithms of its unused
+/// fallback keys.
+///
+/// `signed_curve25519` is always counted, so clients see when they run out.
+pub(crate) async fn device_key_counts(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    device_id: &str,
+) -> (serde_json::Value, Vec<String>) {
+    let mut counts = storage
+        .count_one_time_keys(user_id, device_id)
+        .await
+        .unwrap_or_else(|_| serde_json::json!({}));
+    if let Some(obj) = counts.as_object_mut() {
+        obj.entry("signed_curve25519")
+            .or_insert(serde_json::json!(0));
+    }
+    let fallback_types = storage
+        .get_unused_fallback_key_types(user_id, device_id)
+        .await
+        .unwrap_or_default();
+    (counts, fallback_types)
 }
 
 /// Compute device_lists.changed — users in shared rooms whose devices may have changed.
-async fn compute_device_lists(
+pub(crate) async fn compute_device_lists(
     storage: &dyn maelstrom_storage::traits::Storage,
     joined_rooms: &[String],
     my_user_id: &str,
@@ -1266,16 +1290,14 @@ async fn compute_device_lists(
         }
     }
 
-    // Check each user's device change position — only include if changed after `since`
-    for user_id in &room_users {
-        if let Ok(data) = storage
-            .get_account_data(user_id, None, "_maelstrom.device_change_pos")
-            .await
-            && let Some(pos) = data.get("pos").and_then(|p| p.as_i64())
-            && pos >= since
-        {
-            changed.insert(user_id.clone());
-        }
+    // Users whose device list stream moved on since `since`, including the
+    // user themselves, so their other devices see new devices and signatures
+    if let Ok(users) = storage.get_device_list_changed_users(since, i64::MAX).await {
+        changed.extend(
+            users
+                .into_iter()
+                .filter(|u| u == my_user_id || room_users.contains(u)),
+        );
     }
 
     // Also check new member join/leave events since last sync.
@@ -1376,7 +1398,9 @@ async fn add_ephemeral_events(
     ephemeral: &maelstrom_core::matrix::ephemeral::EphemeralStore,
     mut join_map: HashMap<String, JoinedRoomResponse>,
     joined_rooms: &[String],
+    user_id: &str,
     force_ephemeral: bool,
+    filter: &RoomEventFilter,
 ) -> Result<HashMap<String, JoinedRoomResponse>, MatrixError> {
     for room_id in joined_rooms {
         // Build ephemeral events for this room
@@ -1397,6 +1421,11 @@ async fn add_ephemeral_events(
             }
         }));
 
+        // Private receipts are only visible to their sender
+        let receipts: Vec<_> = receipts
+            .into_iter()
+            .filter(|r| r.receipt_type != "m.read.private" || r.user_id == user_id)
+            .collect();
         if !receipts.is_empty() {
             let mut content: HashMap<String, HashMap<String, HashMap<String, serde_json::Value>>> =
                 HashMap::new();
@@ -1421,6 +1450,12 @@ async fn add_ephemeral_events(
             }));
         }
 
+        ephemeral_events.retain(|e| {
+            let event_type = e.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+            filter.matches_fields(room_id, event_type, None)
+        });
+        ephemeral_events.truncate(filter.limit.unwrap_or(usize::MAX));
+
         // Determine whether there's something worth reporting beyond empty typing.
         let has_active_ephemeral =
             ephemeral_events
@@ -1437,26 +1472,15 @@ async fn add_ephemeral_events(
         if let Some(room_response) = join_map.get_mut(room_id.as_str()) {
             // Room already in response — always attach ephemeral events.
             room_response.ephemeral.events = ephemeral_events;
-        } else if has_active_ephemeral || force_ephemeral {
+        } else if (has_active_ephemeral || force_ephemeral) && !ephemeral_events.is_empty() {
             // New room entry only for active typing or receipts.
             join_map.insert(
                 room_id.clone(),
                 JoinedRoomResponse {
-                    state: StateResponse { events: vec![] },
-                    timeline: TimelineResponse {
-                        events: vec![],
-                        prev_batch: "0".to_string(),
-                        limited: false,
-                    },
                     ephemeral: EphemeralResponse {
                         events: ephemeral_events,
                     },
-                    unread_notifications: UnreadNotifications {
-                        highlight_count: 0,
-                        notification_count: 0,
-                    },
-                    account_data: None,
-                    summary: None,
+                    ..JoinedRoomResponse::empty("0".to_string())
                 },
             );
         }
@@ -1465,125 +1489,19 @@ async fn add_ephemeral_events(
     Ok(join_map)
 }
 
-// ---------------------------------------------------------------------------
-// Sliding Sync — POST /_matrix/client/v3/sync (MSC3575)
-// ---------------------------------------------------------------------------
-
-fn default_timeline_limit() -> usize {
-    10
-}
-
-#[derive(Deserialize)]
-struct SlidingSyncRequest {
-    #[serde(default)]
-    lists: HashMap<String, SlidingSyncList>,
-    #[serde(default)]
-    room_subscriptions: HashMap<String, RoomSubscription>,
-    #[serde(default)]
-    extensions: SlidingSyncExtensions,
-    pos: Option<String>,
-}
-
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct SlidingSyncList {
-    #[serde(default)]
-    ranges: Vec<[u64; 2]>,
-    #[serde(default)]
-    required_state: Vec<[String; 2]>,
-    #[serde(default = "default_timeline_limit")]
-    timeline_limit: usize,
-    #[serde(default)]
-    sort: Vec<String>,
-}
-
-#[derive(Deserialize)]
-struct RoomSubscription {
-    #[serde(default)]
-    required_state: Vec<[String; 2]>,
-    #[serde(default = "default_timeline_limit")]
-    timeline_limit: usize,
-}
-
-#[derive(Deserialize, Default)]
-struct SlidingSyncExtensions {
-    #[serde(default)]
-    to_device: Option<ExtensionConfig>,
-    #[serde(default)]
-    typing: Option<ExtensionConfig>,
-    #[serde(default)]
-    receipts: Option<ExtensionConfig>,
-}
-
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct ExtensionConfig {
-    #[serde(default)]
-    enabled: bool,
-    since: Option<String>,
-}
-
-// -- Response types --
-
-#[derive(Serialize)]
-struct SlidingSyncResponse {
-    pos: String,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    lists: HashMap<String, SlidingSyncListResponse>,
-    #[serde(skip_serializing_if = "HashMap::is_empty")]
-    rooms: HashMap<String, SlidingSyncRoomResponse>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    extensions: Option<SlidingSyncExtensionsResponse>,
-}
-
-#[derive(Serialize)]
-struct SlidingSyncListResponse {
-    count: u64,
-    ops: Vec<SlidingSyncOp>,
-}
-
-#[derive(Serialize)]
-struct SlidingSyncOp {
-    op: String,
-    range: [u64; 2],
-    room_ids: Vec<String>,
-}
-
-#[derive(Serialize)]
-struct SlidingSyncRoomResponse {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    required_state: Vec<serde_json::Value>,
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    timeline: Vec<serde_json::Value>,
-    notification_count: u64,
-    highlight_count: u64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    initial: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    joined_count: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    invited_count: Option<u64>,
-}
-
-#[derive(Serialize)]
-struct SlidingSyncExtensionsResponse {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    to_device: Option<ToDeviceResponse>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    typing: Option<serde_json::Value>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    receipts: Option<serde_json::Value>,
-}
-
 /// Build top-level presence events for users in shared rooms.
 async fn build_presence_events(
     storage: &dyn maelstrom_storage::traits::Storage,
     ephemeral: &maelstrom_core::matrix::ephemeral::EphemeralStore,
     joined_rooms: &[String],
     user_id: &str,
+    filter: &EventFilter,
 ) -> Option<PresenceResponse> {
+    if !ephemeral.presence_mode().enabled() {
+        return None;
+    }
+
+    let now_ms = maelstrom_core::matrix::event::timestamp_ms();
     let mut seen_users = HashSet::new();
     let mut events = Vec::new();
 
@@ -1597,25 +1515,10 @@ async fn build_presence_events(
                     continue;
                 }
                 if let Some(presence) = ephemeral.get_presence(&member) {
-                    let now_ms = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_millis() as u64;
-                    let last_active_ago = now_ms.saturating_sub(presence.last_active_ts);
-
-                    let mut content = serde_json::json!({
-                        "presence": presence.status,
-                        "last_active_ago": last_active_ago,
-                        "currently_active": presence.status == "online",
-                    });
-                    if let Some(msg) = &presence.status_msg {
-                        content["status_msg"] = serde_json::Value::String(msg.clone());
-                    }
-
                     events.push(serde_json::json!({
                         "type": "m.presence",
                         "sender": member,
-                        "content": content,
+                        "content": crate::presence::presence_content(&presence, now_ms),
                     }));
                 }
             }
@@ -1624,28 +1527,14 @@ async fn build_presence_events(
 
     // Also include the user's own presence
     if let Some(presence) = ephemeral.get_presence(user_id) {
-        let now_ms = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
-        let last_active_ago = now_ms.saturating_sub(presence.last_active_ts);
-
-        let mut content = serde_json::json!({
-            "presence": presence.status,
-            "last_active_ago": last_active_ago,
-            "currently_active": presence.status == "online",
-        });
-        if let Some(msg) = &presence.status_msg {
-            content["status_msg"] = serde_json::Value::String(msg.clone());
-        }
-
         events.push(serde_json::json!({
             "type": "m.presence",
             "sender": user_id,
-            "content": content,
+            "content": crate::presence::presence_content(&presence, now_ms),
         }));
     }
 
+    let events = filter_global_events(events, filter);
     if events.is_empty() {
         None
     } else {
@@ -1657,11 +1546,12 @@ async fn build_presence_events(
 /// On initial sync, always includes push rules and all user account data.
 /// On incremental sync, includes account data if any exists (since we don't
 /// track per-item change timestamps yet).
-async fn build_global_account_data(
+pub(crate) async fn build_global_account_data(
     storage: &dyn maelstrom_storage::traits::Storage,
     user_id: &str,
     is_initial: bool,
     since: i64,
+    filter: &EventFilter,
 ) -> Option<AccountDataResponse> {
     let mut events = Vec::new();
 
@@ -1790,6 +1680,7 @@ async fn build_global_account_data(
         }
     }
 
+    let events = filter_global_events(events, filter);
     if events.is_empty() {
         None
     } else {
@@ -1797,367 +1688,18 @@ async fn build_global_account_data(
     }
 }
 
-#[derive(Serialize)]
-struct ToDeviceResponse {
-    next_batch: String,
+/// Keep the presence or global account data events that pass `filter`, up
+/// to its limit.
+fn filter_global_events(
     events: Vec<serde_json::Value>,
-}
-
-async fn sliding_sync(
-    State(state): State<AppState>,
-    auth: AuthenticatedUser,
-    MatrixJson(body): MatrixJson<SlidingSyncRequest>,
-) -> Result<Json<SlidingSyncResponse>, MatrixError> {
-    let storage = state.storage();
-    let user_id = auth.user_id.to_string();
-
-    let since: Option<i64> = body.pos.as_deref().and_then(|s| s.parse().ok());
-    let is_initial = since.is_none();
-
-    // Get user's joined rooms
-    let joined_rooms = storage
-        .get_joined_rooms(&user_id)
-        .await
-        .map_err(crate::extractors::storage_error)?;
-
-    // Get current stream position — this becomes the response `pos`
-    let current_position = storage
-        .current_stream_position()
-        .await
-        .map_err(crate::extractors::storage_error)?;
-
-    // Sort rooms by recency: find the latest stream_position in each room.
-    // For incremental sync we only need rooms with changes since `pos`.
-    let mut room_latest: Vec<(String, i64)> = Vec::with_capacity(joined_rooms.len());
-    for room_id in &joined_rooms {
-        // Fetch the single most recent event to get its stream_position.
-        let events = storage
-            .get_room_events(room_id, current_position + 1, 1, "b")
-            .await
-            .unwrap_or_default();
-        let latest = events.first().map(|e| e.stream_position).unwrap_or(0);
-        room_latest.push((room_id.clone(), latest));
-    }
-
-    // Sort descending by latest activity (most recent first).
-    room_latest.sort_by(|a, b| b.1.cmp(&a.1));
-
-    let total_rooms = room_latest.len() as u64;
-
-    // For incremental sync, determine which rooms have new activity.
-    let rooms_with_changes: HashSet<String> = if let Some(since_pos) = since {
-        room_latest
-            .iter()
-            .filter(|(_, pos)| *pos > since_pos)
-            .map(|(id, _)| id.clone())
-            .collect()
-    } else {
-        // Initial: all rooms are "changed"
-        room_latest.iter().map(|(id, _)| id.clone()).collect()
-    };
-
-    // Collect the set of room_ids we need to return full room data for.
-    let mut rooms_to_fetch: HashMap<String, RoomFetchParams> = HashMap::new();
-
-    // -- Process lists --
-    let mut list_responses: HashMap<String, SlidingSyncListResponse> = HashMap::new();
-
-    for (list_name, list) in &body.lists {
-        let mut ops = Vec::new();
-
-        for range in &list.ranges {
-            let start = range[0] as usize;
-            let end = (range[1] as usize).min(room_latest.len().saturating_sub(1));
-
-            let mut range_room_ids = Vec::new();
-            for i in start..=end {
-                if i < room_latest.len() {
-                    let room_id = &room_latest[i].0;
-                    // For incremental, only include rooms with changes in the
-                    // response room data, but always include room_ids in the
-                    // list ops so the client knows the ordering.
-                    if rooms_with_changes.contains(room_id) || is_initial {
-                        rooms_to_fetch
-                            .entry(room_id.clone())
-                            .or_insert_with(|| RoomFetchParams {
-                                required_state: list.required_state.clone(),
-                                timeline_limit: list.timeline_limit,
-                            });
-                    }
-                    range_room_ids.push(room_id.clone());
-                }
-            }
-
-            ops.push(SlidingSyncOp {
-                op: "SYNC".to_string(),
-                range: [range[0], end as u64],
-                room_ids: range_room_ids,
-            });
-        }
-
-        list_responses.insert(
-            list_name.clone(),
-            SlidingSyncListResponse {
-                count: total_rooms,
-                ops,
-            },
-        );
-    }
-
-    // -- Process room_subscriptions (override list params if also present) --
-    for (room_id, sub) in &body.room_subscriptions {
-        if joined_rooms.contains(room_id) {
-            rooms_to_fetch.insert(
-                room_id.clone(),
-                RoomFetchParams {
-                    required_state: sub.required_state.clone(),
-                    timeline_limit: sub.timeline_limit,
-                },
-            );
-        }
-    }
-
-    // -- Fetch room data --
-    let mut room_responses: HashMap<String, SlidingSyncRoomResponse> = HashMap::new();
-
-    for (room_id, params) in &rooms_to_fetch {
-        // Fetch required state
-        let required_state =
-            fetch_required_state(storage, room_id, &params.required_state, &user_id).await?;
-
-        // Extract room name from state events
-        let name = required_state.iter().find_map(|ev| {
-            if ev.get("type").and_then(|t| t.as_str()) == Some(et::NAME) {
-                ev.get("content")
-                    .and_then(|c| c.get("name"))
-                    .and_then(|n| n.as_str())
-                    .map(|s| s.to_string())
-            } else {
-                None
-            }
-        });
-
-        // Fetch timeline events
-        let timeline_events = storage
-            .get_room_events(room_id, current_position + 1, params.timeline_limit, "b")
-            .await
-            .map_err(crate::extractors::storage_error)?;
-
-        let mut timeline_events = timeline_events;
-        timeline_events.reverse();
-
-        let timeline: Vec<serde_json::Value> = timeline_events
-            .into_iter()
-            .map(|e| e.to_client_event().into_json())
-            .collect();
-
-        // Get member counts
-        let joined_members = storage
-            .get_room_members(room_id, Membership::Join.as_str())
-            .await
-            .unwrap_or_default();
-        let invited_members = storage
-            .get_room_members(room_id, Membership::Invite.as_str())
-            .await
-            .unwrap_or_default();
-
-        room_responses.insert(
-            room_id.clone(),
-            SlidingSyncRoomResponse {
-                name,
-                required_state,
-                timeline,
-                notification_count: 0,
-                highlight_count: 0,
-                initial: if is_initial { Some(true) } else { None },
-                joined_count: Some(joined_members.len() as u64),
-                invited_count: Some(invited_members.len() as u64),
-            },
-        );
-    }
-
-    // -- Extensions --
-    let extensions = build_extensions(
-        storage,
-        state.ephemeral(),
-        &body.extensions,
-        &joined_rooms,
-        current_position,
-        &user_id,
-        auth.device_id.as_ref(),
-    )
-    .await?;
-
-    Ok(Json(SlidingSyncResponse {
-        pos: current_position.to_string(),
-        lists: list_responses,
-        rooms: room_responses,
-        extensions: Some(extensions),
-    }))
-}
-
-/// Parameters for fetching a room in sliding sync.
-struct RoomFetchParams {
-    required_state: Vec<[String; 2]>,
-    timeline_limit: usize,
-}
-
-/// Fetch the required state events for a room based on the filter spec.
-///
-/// - `["*", "*"]` — all current state
-/// - `["m.room.name", ""]` — specific event type with empty state key
-/// - `["m.room.member", "$LAZY"]` — only the authenticated user's membership
-async fn fetch_required_state(
-    storage: &dyn maelstrom_storage::traits::Storage,
-    room_id: &str,
-    required: &[[String; 2]],
-    user_id: &str,
-) -> Result<Vec<serde_json::Value>, MatrixError> {
-    if required.is_empty() {
-        return Ok(vec![]);
-    }
-
-    // Check for wildcard — return all state.
-    let wants_all = required.iter().any(|[t, k]| t == "*" && k == "*");
-
-    if wants_all {
-        let all_state = storage
-            .get_current_state(room_id)
-            .await
-            .map_err(crate::extractors::storage_error)?;
-        return Ok(all_state
-            .into_iter()
-            .map(|e| e.to_client_event().into_json())
-            .collect());
-    }
-
-    let mut result = Vec::new();
-    let mut fetched = HashSet::new();
-
-    for [event_type, state_key] in required {
-        let actual_key = if state_key == "$LAZY" {
-            user_id.to_string()
-        } else {
-            state_key.clone()
-        };
-
-        let dedup_key = (event_type.clone(), actual_key.clone());
-        if fetched.contains(&dedup_key) {
-            continue;
-        }
-
-        match storage
-            .get_state_event(room_id, event_type, &actual_key)
-            .await
-        {
-            Ok(event) => {
-                result.push(event.to_client_event().into_json());
-                fetched.insert(dedup_key);
-            }
-            Err(maelstrom_storage::traits::StorageError::NotFound) => {
-                // State event doesn't exist — skip silently.
-            }
-            Err(e) => return Err(crate::extractors::storage_error(e)),
-        }
-    }
-
-    Ok(result)
-}
-
-/// Build extension responses for typing, receipts, and to_device.
-async fn build_extensions(
-    storage: &dyn maelstrom_storage::traits::Storage,
-    ephemeral: &maelstrom_core::matrix::ephemeral::EphemeralStore,
-    ext: &SlidingSyncExtensions,
-    joined_rooms: &[String],
-    current_position: i64,
-    user_id: &str,
-    device_id: &str,
-) -> Result<SlidingSyncExtensionsResponse, MatrixError> {
-    // -- to_device --
-    let to_device = if ext.to_device.as_ref().is_some_and(|c| c.enabled) {
-        let since: i64 = ext
-            .to_device
-            .as_ref()
-            .and_then(|c| c.since.as_deref())
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(0);
-
-        let events = storage
-            .get_to_device_messages(user_id, device_id, since)
-            .await
-            .unwrap_or_default();
-
-        // Delete acknowledged messages
-        if since > 0 {
-            let _ = storage
-                .delete_to_device_messages(user_id, device_id, since)
-                .await;
-        }
-
-        Some(ToDeviceResponse {
-            next_batch: current_position.to_string(),
-            events,
+    filter: &EventFilter,
+) -> Vec<serde_json::Value> {
+    events
+        .into_iter()
+        .filter(|e| {
+            let event_type = e.get("type").and_then(|t| t.as_str()).unwrap_or_default();
+            filter.matches(event_type, e.get("sender").and_then(|s| s.as_str()))
         })
-    } else {
-        None
-    };
-
-    // -- typing --
-    let typing = if ext.typing.as_ref().is_some_and(|c| c.enabled) {
-        let mut rooms: HashMap<String, serde_json::Value> = HashMap::new();
-        for room_id in joined_rooms {
-            let users = ephemeral.get_typing_users(room_id);
-            if !users.is_empty() {
-                rooms.insert(
-                    room_id.clone(),
-                    serde_json::json!({
-                        "type": "m.typing",
-                        "content": { "user_ids": users }
-                    }),
-                );
-            }
-        }
-        Some(serde_json::json!({ "rooms": rooms }))
-    } else {
-        None
-    };
-
-    // -- receipts --
-    let receipts = if ext.receipts.as_ref().is_some_and(|c| c.enabled) {
-        let mut rooms: HashMap<String, serde_json::Value> = HashMap::new();
-        for room_id in joined_rooms {
-            let room_receipts = storage.get_receipts(room_id).await.unwrap_or_default();
-            if !room_receipts.is_empty() {
-                let mut content: HashMap<
-                    String,
-                    HashMap<String, HashMap<String, serde_json::Value>>,
-                > = HashMap::new();
-                for r in &room_receipts {
-                    content
-                        .entry(r.event_id.clone())
-                        .or_default()
-                        .entry(r.receipt_type.clone())
-                        .or_default()
-                        .insert(r.user_id.clone(), serde_json::json!({ "ts": r.ts }));
-                }
-                rooms.insert(
-                    room_id.clone(),
-                    serde_json::json!({
-                        "type": "m.receipt",
-                        "content": content,
-                    }),
-                );
-            }
-        }
-        Some(serde_json::json!({ "rooms": rooms }))
-    } else {
-        None
-    };
-
-    Ok(SlidingSyncExtensionsResponse {
-        to_device,
-        typing,
-        receipts,
-    })
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .collect()
 }
@@ -0,0 +1,344 @@
+//! Thread subscriptions (MSC4306).
+//!
+//! A user subscribes to a thread to be notified about every reply in it,
+//! either by hand or automatically (a client subscribes on the user's behalf
+//! when, say, they are mentioned in the thread). Unsubscribing is remembered
+//! too: an automatic subscription triggered by an event older than the
+//! unsubscription is refused with `IO_ELEMENT_MSC4306_CONFLICTING_UNSUBSCRIPTION`,
+//! so a client catching up on old mentions does not undo the user's choice.
+//!
+//! Subscriptions are stored one per user and thread
+//! ([`ThreadSubscription`]). Every change takes a fresh stream position as its
+//! `bump_stamp`, which is how the `thread_subscriptions` extension of
+//! [sliding sync](super::sliding_sync) finds what changed since a client's
+//! `pos` (MSC4308). The extension sends the newest changes up to its `limit`
+//! with a `prev_batch` token; the rest are paged through backwards from it
+//! with `GET .../thread_subscriptions`, down to the `pos` the client had.
+//!
+//! # Endpoints
+//!
+//! | Method | Path | Description |
+//! |--------|------|-------------|
+//! | `GET` | `/_matrix/client/unstable/io.element.msc4306/rooms/{roomId}/thread/{threadRootId}/subscription` | Whether and how the user is subscribed |
+//! | `PUT` | `/_matrix/client/unstable/io.element.msc4306/rooms/{roomId}/thread/{threadRootId}/subscription` | Subscribe, optionally `automatic` |
+//! | `DELETE` | `/_matrix/client/unstable/io.element.msc4306/rooms/{roomId}/thread/{threadRootId}/subscription` | Unsubscribe |
+//! | `GET` | `/_matrix/client/unstable/io.element.msc4308/thread_subscriptions` | Page backwards through subscription changes |
+//!
+//! # Matrix spec
+//!
+//! * [MSC4306: Thread subscriptions](https://github.com/matrix-org/matrix-spec-proposals/pull/4306)
+//! * [MSC4308: Thread subscriptions in sliding sync](https://github.com/matrix-org/matrix-spec-proposals/pull/4308)
+
+use std::collections::HashMap;
+
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_core::matrix::room::Membership;
+use maelstrom_storage::traits::ThreadSubscription;
+
+use crate::extractors::{AuthenticatedUser, storage_error};
+use crate::handlers::util::require_membership;
+use crate::notify::Notification;
+use crate::state::AppState;
+
+/// Default `limit` of a page of subscription changes.
+pub(crate) const DEFAULT_CHANGES_LIMIT: usize = 100;
+
+/// The most subscription changes sent at once.
+const MAX_CHANGES_LIMIT: usize = 1000;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/_matrix/client/unstable/io.element.msc4306/rooms/{roomId}/thread/{threadRootId}/subscription",
+            get(get_subscription)
+                .put(subscribe)
+                .delete(unsubscribe),
+        )
+        .route(
+            "/_matrix/client/unstable/io.element.msc4308/thread_subscriptions",
+            get(get_changes),
+        )
+}
+
+/// Subscription changes grouped by room and thread root, as the sliding sync
+/// extension and [`get_changes`] send them.
+#[derive(Serialize, Default)]
+pub(crate) struct SubscriptionChanges {
+    pub subscribed: HashMap<String, serde_json::Map<String, serde_json::Value>>,
+    pub unsubscribed: HashMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+impl SubscriptionChanges {
+    pub fn is_empty(&self) -> bool {
+        self.subscribed.is_empty() && self.unsubscribed.is_empty()
+    }
+}
+
+/// The user's subscription changes in `rooms` with a `bump_stamp` after
+/// `after` and before `before`, newest first up to `limit`. Without `after`
+/// only current subscriptions count, as for a client starting out.
+///
+/// Also returns the token to page on from when `limit` left changes out.
+pub(crate) async fn changes_between(
+    storage: &dyn maelstrom_storage::traits::Storage,
+    user_id: &str,
+    rooms: &[String],
+    after: Option<i64>,
+    before: Option<i64>,
+    limit: usize,
+) -> (SubscriptionChanges, Option<String>) {
+    let mut changes: Vec<ThreadSubscription> = storage
+        .get_thread_subscriptions(user_id, after.unwrap_or(0))
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|sub| after.is_some() || sub.subscribed)
+        .filter(|sub| before.is_none_or(|before| sub.bump_stamp < before))
+        .filter(|sub| rooms.contains(&sub.room_id))
+        .collect();
+    changes.sort_by_key(|sub| std::cmp::Reverse(sub.bump_stamp));
+    let limit = limit.max(1);
+    let prev_batch = (changes.len() > limit).then(|| changes[limit - 1].bump_stamp.to_string());
+    changes.truncate(limit);
+
+    let mut grouped = SubscriptionChanges::default();
+    for sub in changes {
+        if sub.subscribed {
+            grouped.subscribed.entry(sub.room_id).or_default().insert(
+                sub.thread_root,
+                serde_json::json!({
+                    "automatic": sub.automatic,
+                    "bump_stamp": sub.bump_stamp,
+                }),
+            );
+        } else {
+            grouped.unsubscribed.entry(sub.room_id).or_default().insert(
+                sub.thread_root,
+                serde_json::json!({ "bump_stamp": sub.bump_stamp }),
+            );
+        }
+    }
+    (grouped, prev_batch)
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    from: Option<String>,
+    to: Option<String>,
+    dir: String,
+    limit: Option<usize>,
+}
+
+fn invalid_param(msg: &str) -> MatrixError {
+    MatrixError::new(http::StatusCode::BAD_REQUEST, ErrorCode::InvalidParam, msg)
+}
+
+/// A pagination token: a `prev_batch` or `end`, or a sliding sync `pos`
+/// (whose stream position comes first).
+fn parse_token(token: &str) -> Result<i64, MatrixError> {
+    token
+        .split('_')
+        .next()
+        .and_then(|position| position.parse().ok())
+        .ok_or_else(|| invalid_param("Invalid pagination token"))
+}
+
+/// GET /_matrix/client/unstable/io.element.msc4308/thread_subscriptions
+///
+/// Subscription changes before `from` (default: all) and after `to`,
+/// newest first. Only backwards pagination (`dir=b`) is supported.
+async fn get_changes(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Query(query): Query<ChangesQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    if query.dir != "b" {
+        return Err(invalid_param("Only dir=b is supported"));
+    }
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+    let before = query.from.as_deref().map(parse_token).transpose()?;
+    let after = query.to.as_deref().map(parse_token).transpose()?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_CHANGES_LIMIT)
+        .clamp(1, MAX_CHANGES_LIMIT);
+    let rooms = storage
+        .get_joined_rooms(&user_id)
+        .await
+        .map_err(storage_error)?;
+
+    let (changes, end) = changes_between(storage, &user_id, &rooms, after, before, limit).await;
+    let mut response = serde_json::to_value(changes).unwrap_or_default();
+    if let Some(end) = end {
+        response["end"] = serde_json::json!(end);
+    }
+    Ok(Json(response))
+}
+
+#[derive(Deserialize, Default)]
+struct SubscribeRequest {
+    /// The event that made the client subscribe automatically, if it did.
+    automatic: Option<String>,
+}
+
+/// The user's subscription to a thread, or unsubscription from it.
+async fn get_entry(
+    state: &AppState,
+    user_id: &str,
+    room_id: &str,
+    thread_root: &str,
+) -> Result<Option<ThreadSubscription>, MatrixError> {
+    match state
+        .storage()
+        .get_thread_subscription(user_id, room_id, thread_root)
+        .await
+    {
+        Ok(subscription) => Ok(Some(subscription)),
+        Err(maelstrom_storage::traits::StorageError::NotFound) => Ok(None),
+        Err(e) => Err(storage_error(e)),
+    }
+}
+
+/// Check the user is in the room and the thread root is one of its events.
+async fn check_thread(
+    state: &AppState,
+    user_id: &str,
+    room_id: &str,
+    thread_root: &str,
+) -> Result<(), MatrixError> {
+    let storage = state.storage();
+    let membership = require_membership(storage, user_id, room_id).await?;
+    if membership != Membership::Join.as_str() {
+        return Err(MatrixError::forbidden("You are not in this room"));
+    }
+    match storage.get_event(thread_root).await {
+        Ok(event) if event.room_id == room_id => Ok(()),
+        _ => Err(MatrixError::not_found("Thread root not found")),
+    }
+}
+
+/// Store one thread's subscription entry with a fresh `bump_stamp`.
+async fn set_subscription(
+    state: &AppState,
+    user_id: &str,
+    room_id: &str,
+    thread_root: &str,
+    subscribed: bool,
+    automatic: bool,
+) -> Result<(), MatrixError> {
+    let storage = state.storage();
+    let bump_stamp = storage
+        .next_stream_position()
+        .await
+        .map_err(storage_error)?;
+    storage
+        .set_thread_subscription(
+            user_id,
+            &ThreadSubscription {
+                room_id: room_id.to_string(),
+                thread_root: thread_root.to_string(),
+                subscribed,
+                automatic,
+                bump_stamp,
+            },
+        )
+        .await
+        .map_err(storage_error)?;
+
+    state
+        .notifier()
+        .notify(Notification::AccountData {
+            user_id: user_id.to_string(),
+        })
+        .await;
+    Ok(())
+}
+
+/// GET .../thread/{threadRootId}/subscription
+async fn get_subscription(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, thread_root)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let user_id = auth.user_id.to_string();
+    check_thread(&state, &user_id, &room_id, &thread_root).await?;
+
+    match get_entry(&state, &user_id, &room_id, &thread_root).await? {
+        Some(entry) if entry.subscribed => Ok(Json(serde_json::json!({
+            "automatic": entry.automatic,
+        }))),
+        _ => Err(MatrixError::not_found("Not subscribed to this thread")),
+    }
+}
+
+/// PUT .../thread/{threadRootId}/subscription
+async fn subscribe(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, thread_root)): Path<(String, String)>,
+    body: Option<Json<SubscribeRequest>>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let storage = state.storage();
+    let user_id = auth.user_id.to_string();
+    check_thread(&state, &user_id, &room_id, &thread_root).await?;
+    let body = body.map(|Json(b)| b).unwrap_or_default();
+
+    let existing = get_entry(&state, &user_id, &room_id, &thread_root).await?;
+    if let Some(cause) = &body.automatic {
+        let cause = storage
+            .get_event(cause)
+            .await
+            .ok()
+            .filter(|e| e.room_id == room_id)
+            .ok_or_else(|| MatrixError::not_found("Automatic subscription cause not found"))?;
+        if let Some(existing) = &existing {
+            // An existing subscription is kept as it is, manual ones included
+            if existing.subscribed {
+                return Ok(Json(serde_json::json!({})));
+            }
+            if existing.bump_stamp > cause.stream_position {
+                return Err(MatrixError::new(
+                    http::StatusCode::CONFLICT,
+                    ErrorCode::ConflictingUnsubscription,
+                    "The thread was unsubscribed from after the automatic subscription's cause",
+                ));
+            }
+        }
+    }
+
+    set_subscription(
+        &state,
+        &user_id,
+        &room_id,
+        &thread_root,
+        true,
+        body.automatic.is_some(),
+    )
+    .await?;
+    Ok(Json(serde_json::json!({})))
+}
+
+/// DELETE .../thread/{threadRootId}/subscription
+async fn unsubscribe(
+    State(state): State<AppState>,
+    auth: AuthenticatedUser,
+    Path((room_id, thread_root)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let user_id = auth.user_id.to_string();
+    check_thread(&state, &user_id, &room_id, &thread_root).await?;
+
+    if !get_entry(&state, &user_id, &room_id, &thread_root)
+        .await?
+        .is_some_and(|entry| entry.subscribed)
+    {
+        return Err(MatrixError::not_found("Not subscribed to this thread"));
+    }
+    set_subscription(&state, &user_id, &room_id, &thread_root, false, false).await?;
+    Ok(Json(serde_json::json!({})))
+}
@@ -6,7 +6,8 @@
 //! conversations without scrolling through the full timeline.
 //!
 //! Results can optionally be filtered to only threads the current user has
-//! participated in (via the `include` query parameter).
+//! participated in (via the `include` query parameter). Thread roots the
+//! user may not see under the room's history visibility are left out.
 //!
 //! # Endpoints
 //!
@@ -24,6 +25,7 @@ use axum::{Json, Router};
 use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_federation::visibility;
 
 use crate::extractors::AuthenticatedUser;
 use crate::state::AppState;
@@ -52,7 +54,7 @@ fn default_limit() -> usize {
 /// GET /rooms/{roomId}/threads — list threads in a room.
 async fn get_threads(
     State(state): State<AppState>,
-    _auth: AuthenticatedUser,
+    auth: AuthenticatedUser,
     Path(room_id): Path<String>,
     Query(query): Query<ThreadsQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
@@ -66,24 +68,32 @@ async fn get_threads(
         .await
         .map_err(crate::extractors::storage_error)?;
 
-    // Fetch the actual root events with thread summaries
-    let mut chunk = Vec::new();
+    // Fetch the actual root events the user may see, with thread summaries
+    let mut roots = Vec::new();
     for root_id in &thread_roots {
         if let Ok(event) = state.storage().get_event(root_id).await {
-            let mut client_event = event.to_client_event().into_json();
+            roots.push(event);
+        }
+    }
+    let roots =
+        visibility::filter_events_for_user(state.storage(), auth.user_id.as_ref(), roots).await;
 
-            // Add thread aggregation to unsigned
-            if let Some(agg) = super::relations::build_aggregations(state.storage(), root_id).await
-                && let Some(obj) = client_event.as_object_mut()
-            {
-                let unsigned = obj.entry("unsigned").or_insert(serde_json::json!({}));
-                if let Some(u) = unsigned.as_object_mut() {
-                    u.insert("m.relations".to_string(), agg["m.relations"].clone());
-                }
-            }
+    let mut chunk = Vec::new();
+    for event in roots {
+        let mut client_event = event.to_client_event().into_json();
 
-            chunk.push(client_event);
+        // Add thread aggregation to unsigned
+        if let Some(agg) =
+            super::relations::build_aggregations(state.storage(), &event.event_id).await
+            && let Some(obj) = client_event.as_object_mut()
+        {
+            let unsigned = obj.entry("unsigned").or_insert(serde_json::json!({}));
+            if let Some(u) = unsigned.as_object_mut() {
+                u.insert("m.relations".to_string(), agg["m.relations"].clone());
+            }
         }
+
+        chunk.push(client_event);
     }
 
     let next_batch = if chunk.len() == limit {
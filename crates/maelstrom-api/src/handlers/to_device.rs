@@ -32,7 +32,9 @@ use axum::{Json, Router};
 use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::id::{UserId, server_name_from_sigil_id};
 
+use crate::appservice_sender;
 use crate::extractors::{AuthenticatedUser, storage_error};
+use crate::notify::Notification;
 use crate::state::AppState;
 
 pub fn routes() -> Router<AppState> {
@@ -62,6 +64,8 @@ async fn send_to_device(
         HashMap<String, serde_json::Map<String, serde_json::Value>>,
     > = HashMap::new();
 
+    let mut local_recipients: Vec<String> = Vec::new();
+
     if let Some(messages) = body.get("messages").and_then(|v| v.as_object()) {
         for (target_user, devices) in messages {
             let target_server = server_name_from_sigil_id(target_user);
@@ -69,6 +73,7 @@ async fn send_to_device(
             if target_server == local_server {
                 // Local user -- store messages directly
                 if let Some(device_map) = devices.as_object() {
+                    local_recipients.push(target_user.clone());
                     for (target_device, content) in device_map {
                         if target_device == "*" {
                             // Broadcast to all devices for this user
@@ -90,6 +95,15 @@ async fn send_to_device(
                                     )
                                     .await
                                     .map_err(storage_error)?;
+                                appservice_sender::queue_to_device(
+                                    &state,
+                                    target_user,
+                                    &device.device_id,
+                                    &sender,
+                                    &event_type,
+                                    content,
+                                )
+                                .await;
                             }
                         } else {
                             storage
@@ -102,6 +116,15 @@ async fn send_to_device(
                                 )
                                 .await
                                 .map_err(storage_error)?;
+                            appservice_sender::queue_to_device(
+                                &state,
+                                target_user,
+                                target_device,
+                                &sender,
+                                &event_type,
+                                content,
+                            )
+                            .await;
                         }
                     }
                 }
@@ -121,6 +144,14 @@ async fn send_to_device(
         }
     }
 
+    // Wake the recipients' syncs
+    for user_id in local_recipients {
+        state
+            .notifier()
+            .notify(Notification::ToDevice { user_id })
+            .await;
+    }
+
     // Queue m.direct_to_device EDUs to remote servers
     if let Some(tx_sender) = state.transaction_sender() {
         for (server, user_device_messages) in remote_messages {
@@ -9,6 +9,11 @@
 //! If the user stops typing (or the timeout expires without renewal), the
 //! indicator is automatically cleared.
 //!
+//! Changes are also sent as `m.typing` EDUs to the other servers in the room.
+//! Remote servers expire the indicator on their own, so a client's keep-alive
+//! requests are forwarded too; the transaction sender coalesces the ones
+//! still queued for a destination.  Stopping when not typing sends nothing.
+//!
 //! # Endpoints
 //!
 //! | Method | Path | Description |
@@ -26,7 +31,9 @@ use serde::Deserialize;
 
 use maelstrom_core::matrix::error::MatrixError;
 
+use crate::appservice_sender;
 use crate::extractors::{AuthenticatedUser, MatrixJson};
+use crate::handlers::util::remote_servers_in_room;
 use crate::notify::Notification;
 use crate::state::AppState;
 
@@ -61,6 +68,10 @@ async fn set_typing(
     // sync can pick it up.  The spec default is ~30 s; clamp to at least 10 s.
     let timeout_ms = body.timeout.unwrap_or(30000).max(10000);
 
+    let was_typing = state
+        .ephemeral()
+        .get_typing_users(&room_id)
+        .contains(&sender);
     state
         .ephemeral()
         .set_typing(&sender, &room_id, body.typing, timeout_ms);
@@ -72,5 +83,36 @@ async fn set_typing(
         })
         .await;
 
+    appservice_sender::queue_room_ephemeral(
+        &state,
+        &room_id,
+        serde_json::json!({
+            "type": "m.typing",
+            "room_id": room_id,
+            "content": { "user_ids": state.ephemeral().get_typing_users(&room_id) },
+        }),
+    )
+    .await;
+
+    if (body.typing || was_typing)
+        && let Some(tx_sender) = state.transaction_sender()
+    {
+        let remote_servers =
+            remote_servers_in_room(state.storage(), &room_id, state.server_name().as_str()).await;
+        for server in remote_servers {
+            tx_sender.queue_edu(
+                &server,
+                serde_json::json!({
+                    "edu_type": "m.typing",
+                    "content": {
+                        "room_id": room_id,
+                        "user_id": sender,
+                        "typing": body.typing,
+                    },
+                }),
+            );
+        }
+    }
+
     Ok(Json(serde_json::json!({})))
 }
@@ -37,7 +37,12 @@ struct VersionsResponse {
 
 #[derive(Serialize)]
 struct UnstableFeatures {
-    // Placeholder for MSC flags — populated as features are implemented
+    /// Simplified sliding sync (MSC4186).
+    #[serde(rename = "org.matrix.simplified_msc3575")]
+    simplified_sliding_sync: bool,
+    /// Thread subscriptions (MSC4306).
+    #[serde(rename = "org.matrix.msc4306")]
+    thread_subscriptions: bool,
 }
 
 async fn get_versions() -> impl IntoResponse {
@@ -46,6 +51,9 @@ async fn get_versions() -> impl IntoResponse {
             "v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10",
             "v1.11", "v1.12", "v1.13", "v1.14", "v1.15", "v1.16", "v1.17", "v1.18",
         ],
-        unstable_features: UnstableFeatures {},
+        unstable_features: UnstableFeatures {
+            simplified_sliding_sync: true,
+            thread_subscriptions: true,
+        },
     })
 }
@@ -15,6 +15,11 @@
 //! | [`notify`] | Pub/sub notification system that connects event-producing handlers (send message, set typing, ...) to the `/sync` long-poll loop. |
 //! | [`middleware`] | Tower middleware layers (compression, CORS, tracing). |
 //! | [`gossip`] | Cluster gossip for multi-node deployments. |
+//! | [`device_lists`] | Local users' device list change stream and its fan-out to syncs, appservices and remote servers. |
+//! | [`sync_state`] | Per-device sync bookkeeping kept in memory, such as the lazy-loaded members each device has and what each sliding sync connection was sent. |
+//! | [`presence`] | Activity- and sync-driven presence, idle/offline timers and presence fan-out. |
+//! | [`appservice_query`] | Third-party lookups proxied to application services. |
+//! | [`appservice_sender`] | Durable, retrying delivery of events and ephemeral data to application services. |
 //!
 //! ## Request / response pattern
 //!
@@ -40,10 +45,15 @@
 //! 4. Done -- Axum handles deserialization, serialization, and error mapping for you.
 
 pub mod appservice;
+pub mod appservice_query;
+pub mod appservice_sender;
+pub mod device_lists;
 pub mod extractors;
 pub mod gossip;
 pub mod handlers;
 pub mod middleware;
 pub mod notify;
+pub mod presence;
 pub mod router;
 pub mod state;
+pub mod sync_state;
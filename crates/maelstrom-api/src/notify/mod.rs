@@ -48,6 +48,8 @@ pub enum Notification {
     Presence { user_id: String },
     /// A user's account data changed (push rules, direct chats, custom data).
     AccountData { user_id: String },
+    /// New to-device messages were queued for one of the user's devices.
+    ToDevice { user_id: String },
 }
 
 /// Receiver end of a notification subscription.
@@ -130,8 +132,8 @@ impl Notifier for std::sync::Arc<dyn Notifier> {
 /// - **One broadcast channel per room**, stored in a [`DashMap`] and created
 ///   lazily on first subscribe or notify.  `DashMap` is a concurrent hash map
 ///   that allows lock-free reads, so hot-path lookups don't contend.
-/// - **One shared broadcast channel for presence and account data**, since
-///   those are keyed by user rather than room.
+/// - **One shared broadcast channel for presence, account data and to-device
+///   messages**, since those are keyed by user rather than room.
 ///
 /// # The mpsc adapter pattern
 ///
@@ -150,7 +152,8 @@ impl Notifier for std::sync::Arc<dyn Notifier> {
 pub struct LocalNotifier {
     /// Broadcast channels per room, created on first subscribe or notify.
     room_channels: DashMap<String, broadcast::Sender<Notification>>,
-    /// Single broadcast channel for presence and account-data notifications.
+    /// Single broadcast channel for presence, account-data and to-device
+    /// notifications.
     presence_tx: broadcast::Sender<Notification>,
 }
 
@@ -194,7 +197,9 @@ impl Notifier for LocalNotifier {
                 let tx = self.get_or_create_room_tx(room_id);
                 let _ = tx.send(notification);
             }
-            Notification::Presence { .. } | Notification::AccountData { .. } => {
+            Notification::Presence { .. }
+            | Notification::AccountData { .. }
+            | Notification::ToDevice { .. } => {
                 let _ = self.presence_tx.send(notification);
             }
         }
@@ -224,7 +229,7 @@ impl Notifier for LocalNotifier {
             });
         }
 
-        // Subscribe to presence and account_data if requested
+        // Subscribe to presence, account_data and to-device if requested
         if let Some(uid) = user_id {
             let mut rx = self.presence_tx.subscribe();
             let mpsc_tx = mpsc_tx.clone();
@@ -237,6 +242,7 @@ impl Notifier for LocalNotifier {
                             let matches = match &notification {
                                 Notification::Presence { user_id } => *user_id == uid,
                                 Notification::AccountData { user_id } => *user_id == uid,
+                                Notification::ToDevice { user_id } => *user_id == uid,
                                 _ => false,
                             };
                             if matches && mpsc_tx.send(notification).await.is_err() {
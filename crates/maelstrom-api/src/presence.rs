@@ -0,0 +1,207 @@
+//! # Presence Tracking
+//!
+//! Keeps users' presence in step with what their clients do, on top of the
+//! explicit `PUT /presence/{userId}/status`:
+//!
+//! - **Activity** -- every authenticated request other than `/sync` is
+//!   recorded by the [`AuthenticatedUser`](crate::extractors::AuthenticatedUser)
+//!   extractor through [`record_activity`]; it brings idle users back online.
+//! - **Sync** -- a `/sync` holds a [`SyncPresence`] guard for its duration.
+//!   Its `set_presence` parameter decides the effect: `online` (the default)
+//!   brings an offline user online, `unavailable` sets that state, and
+//!   `offline` leaves presence alone.
+//! - **Timers** -- [`run`] periodically applies the idle and offline timers
+//!   of the [`EphemeralStore`](maelstrom_core::matrix::ephemeral::EphemeralStore)
+//!   and, with federated presence, re-sends the presence of users who are
+//!   not offline to the servers sharing rooms with them every
+//!   [`FEDERATION_REFRESH_INTERVAL`], so those servers do not time them out.
+//!
+//! Every change goes through [`broadcast`], which wakes `/sync`, queues the
+//! `m.presence` EDU for application services and, for local users with
+//! federated presence, sends it to remote servers.
+//!
+//! The server-wide [`PresenceMode`] is held by the ephemeral store: `off`
+//! disables all of the above, `local_only` skips federation.
+
+use std::time::Duration;
+
+use maelstrom_core::matrix::ephemeral::{PresenceMode, PresenceRecord};
+use maelstrom_core::matrix::event::timestamp_ms;
+use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::appservice_sender;
+use crate::notify::Notification;
+use crate::state::AppState;
+
+/// How often the presence timers run.
+pub const TIMER_INTERVAL: Duration = Duration::from_secs(5);
+/// How often presence is re-sent to remote servers.  Must stay below the
+/// remote servers' federation timeout (30 minutes).
+pub const FEDERATION_REFRESH_INTERVAL: Duration = Duration::from_secs(25 * 60);
+
+/// The content of an `m.presence` event or EDU for `record`.
+pub fn presence_content(record: &PresenceRecord, now_ms: u64) -> Value {
+    let mut content = serde_json::json!({
+        "presence": record.status,
+        "last_active_ago": now_ms.saturating_sub(record.last_active_ts),
+        "currently_active": record.currently_active(now_ms),
+    });
+    if let Some(msg) = &record.status_msg {
+        content["status_msg"] = Value::String(msg.clone());
+    }
+    content
+}
+
+/// Share a user's new presence with everyone who should see it.
+pub async fn broadcast(state: &AppState, record: &PresenceRecord) {
+    let mode = state.ephemeral().presence_mode();
+    if !mode.enabled() {
+        return;
+    }
+
+    state
+        .notifier()
+        .notify(Notification::Presence {
+            user_id: record.user_id.clone(),
+        })
+        .await;
+
+    let content = presence_content(record, timestamp_ms());
+    appservice_sender::queue_presence(
+        state,
+        &record.user_id,
+        serde_json::json!({
+            "type": "m.presence",
+            "sender": record.user_id,
+            "content": content,
+        }),
+    )
+    .await;
+
+    if mode.federated() && is_local(state, &record.user_id) {
+        send_federation_edu(state, std::slice::from_ref(record)).await;
+    }
+}
+
+/// Record activity by `user_id`, broadcasting if it ended their idleness.
+pub(crate) fn record_activity(state: &AppState, user_id: &str) {
+    if !state.ephemeral().presence_mode().enabled() {
+        return;
+    }
+    if let Some(record) = state.ephemeral().bump_presence_activity(user_id) {
+        let state = state.clone();
+        tokio::spawn(async move { broadcast(&state, &record).await });
+    }
+}
+
+/// Marks a user as connected while one of their `/sync` requests runs.
+///
+/// Dropping the guard, including when the client goes away mid-request,
+/// ends the sync.
+pub struct SyncPresence {
+    state: AppState,
+    user_id: String,
+}
+
+impl SyncPresence {
+    /// Start tracking a sync with the given `set_presence` parameter.
+    /// Returns `None` (no tracking) for `offline`, invalid values, or when
+    /// presence is disabled.
+    pub async fn start(
+        state: &AppState,
+        user_id: &str,
+        set_presence: Option<&str>,
+    ) -> Option<Self> {
+        if !state.ephemeral().presence_mode().enabled() {
+            return None;
+        }
+        let set_presence = set_presence.unwrap_or("online");
+        if !matches!(set_presence, "online" | "unavailable") {
+            return None;
+        }
+
+        if let Some(record) = state
+            .ephemeral()
+            .presence_sync_started(user_id, set_presence)
+        {
+            broadcast(state, &record).await;
+        }
+        Some(Self {
+            state: state.clone(),
+            user_id: user_id.to_string(),
+        })
+    }
+}
+
+impl Drop for SyncPresence {
+    fn drop(&mut self) {
+        self.state.ephemeral().presence_sync_ended(&self.user_id);
+    }
+}
+
+/// Run the presence timers.  Call this as a spawned tokio task.
+pub async fn run(state: AppState) {
+    let mode = state.ephemeral().presence_mode();
+    if !mode.enabled() {
+        return;
+    }
+    info!(mode = mode.as_str(), "Presence timers started");
+
+    let mut timers = tokio::time::interval(TIMER_INTERVAL);
+    let mut refresh = tokio::time::interval(FEDERATION_REFRESH_INTERVAL);
+    // Both intervals tick immediately; nothing needs refreshing at startup
+    refresh.tick().await;
+    loop {
+        tokio::select! {
+            _ = timers.tick() => {
+                let changed = state
+                    .ephemeral()
+                    .expire_presence(timestamp_ms(), |user_id| is_local(&state, user_id));
+                for record in changed {
+                    debug!(user_id = %record.user_id, presence = %record.status, "Presence timed out");
+                    broadcast(&state, &record).await;
+                }
+            }
+            _ = refresh.tick(), if mode == PresenceMode::Federated => {
+                let present = state
+                    .ephemeral()
+                    .present_users(|user_id| is_local(&state, user_id));
+                send_federation_edu(&state, &present).await;
+            }
+        }
+    }
+}
+
+/// Send each of `records` (local users) as an `m.presence` EDU to the
+/// servers sharing rooms with that user.
+async fn send_federation_edu(state: &AppState, records: &[PresenceRecord]) {
+    let Some(sender) = state.transaction_sender() else {
+        return;
+    };
+    let now_ms = timestamp_ms();
+    for record in records {
+        let remote_servers = crate::handlers::util::servers_sharing_rooms(
+            state.storage(),
+            &record.user_id,
+            state.server_name().as_str(),
+        )
+        .await;
+        let mut entry = presence_content(record, now_ms);
+        entry["user_id"] = Value::String(record.user_id.clone());
+        for server in remote_servers {
+            sender.queue_edu(
+                &server,
+                serde_json::json!({
+                    "edu_type": "m.presence",
+                    "content": { "push": [entry] },
+                }),
+            );
+        }
+    }
+}
+
+fn is_local(state: &AppState, user_id: &str) -> bool {
+    server_name_from_sigil_id(user_id) == state.server_name().as_str()
+}
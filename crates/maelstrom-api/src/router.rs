@@ -21,16 +21,21 @@ pub fn build(state: AppState) -> Router {
         .merge(handlers::rooms::routes())
         .merge(handlers::directory::routes())
         .merge(handlers::events::routes())
+        .merge(handlers::peek::routes())
         .merge(handlers::search::routes())
         .merge(handlers::sync::routes())
+        .merge(handlers::sliding_sync::routes())
         .merge(handlers::typing::routes())
         .merge(handlers::receipts::routes())
         .merge(handlers::presence::routes())
         .merge(handlers::keys::routes())
+        .merge(handlers::key_backup::routes())
+        .merge(handlers::dehydrated_device::routes())
         .merge(handlers::to_device::routes())
         .merge(handlers::media::routes())
         .merge(handlers::relations::routes())
         .merge(handlers::threads::routes())
+        .merge(handlers::thread_subscriptions::routes())
         .merge(handlers::spaces::routes())
         .merge(handlers::knock::routes())
         .merge(handlers::reporting::routes())
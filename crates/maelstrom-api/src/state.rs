@@ -17,7 +17,10 @@ use maelstrom_federation::sender::TransactionSender;
 use maelstrom_media::client::MediaClient;
 use maelstrom_storage::traits::Storage;
 
+use crate::appservice_query::AppServiceQuerier;
+use crate::appservice_sender::AppServiceSender;
 use crate::notify::Notifier;
+use crate::sync_state::SyncState;
 
 /// Shared application state, available to all Axum handlers via `State<AppState>`.
 ///
@@ -29,10 +32,17 @@ use crate::notify::Notifier;
 ///   events arrive. See [`crate::notify::Notifier`].
 /// - **`ephemeral`** -- in-memory store for transient data like typing indicators
 ///   and presence, which don't need to survive restarts.
+/// - **`sync_state`** -- what each device has already been sent by `/sync`
+///   and sliding sync beyond its `since` or `pos` token. See
+///   [`crate::sync_state`].
 /// - **`media`** -- optional client for the media backend (RustFS / S3-compatible).
 ///   `None` when media uploads are disabled.
 /// - **`federation`** -- optional client for server-to-server (S2S) operations.
 ///   `None` when federation is disabled.
+/// - **`appservice_sender`** -- optional background sender pushing events and
+///   ephemeral data to application services.
+/// - **`appservice_query`** -- HTTP client for querying application services
+///   (third-party lookups), with its protocol metadata cache.
 /// - **`server_name`** -- this homeserver's server name (e.g. `example.com`),
 ///   used to construct Matrix IDs like `@alice:example.com`.
 /// - **`public_base_url`** -- the externally-reachable URL for this server,
@@ -52,9 +62,12 @@ struct AppStateInner {
     storage: Box<dyn Storage>,
     notifier: Box<dyn Notifier>,
     ephemeral: Arc<EphemeralStore>,
+    sync_state: SyncState,
     media: Option<MediaClient>,
     federation: Option<Arc<FederationClient>>,
     transaction_sender: Option<Arc<TransactionSender>>,
+    appservice_sender: Option<Arc<AppServiceSender>>,
+    appservice_query: AppServiceQuerier,
     server_name: ServerName,
     public_base_url: String,
     max_upload_size: u64,
@@ -77,9 +90,12 @@ impl AppState {
                 storage: Box::new(storage),
                 notifier: Box::new(notifier),
                 ephemeral,
+                sync_state: SyncState::new(),
                 media: None,
                 federation: None,
                 transaction_sender: None,
+                appservice_sender: None,
+                appservice_query: AppServiceQuerier::new(),
                 server_name,
                 public_base_url,
                 max_upload_size: 50 * 1024 * 1024, // 50 MiB default
@@ -104,9 +120,12 @@ impl AppState {
                 storage: Box::new(storage),
                 notifier: Box::new(notifier),
                 ephemeral,
+                sync_state: SyncState::new(),
                 media: Some(media),
                 federation: None,
                 transaction_sender: None,
+                appservice_sender: None,
+                appservice_query: AppServiceQuerier::new(),
                 server_name,
                 public_base_url,
                 max_upload_size: 50 * 1024 * 1024,
@@ -129,6 +148,14 @@ impl AppState {
         self
     }
 
+    /// Attach the application service sender, so handlers queue ephemeral
+    /// data for it.  The sender's loop is spawned separately with this state.
+    pub fn with_appservice_sender(mut self, sender: Arc<AppServiceSender>) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("AppState already shared");
+        inner.appservice_sender = Some(sender);
+        self
+    }
+
     /// Access the database storage backend.
     ///
     /// Every handler that reads or writes persistent data (events, rooms, users,
@@ -153,6 +180,11 @@ impl AppState {
         &self.inner.ephemeral
     }
 
+    /// Access the per-device sync bookkeeping.
+    pub fn sync_state(&self) -> &SyncState {
+        &self.inner.sync_state
+    }
+
     /// Access the media client, if configured.
     ///
     /// Returns `None` when the server is running without media support.
@@ -186,6 +218,18 @@ impl AppState {
         self.inner.transaction_sender.as_deref()
     }
 
+    /// Access the application service sender, if configured.
+    ///
+    /// See [`crate::appservice_sender`] for the functions that queue data for it.
+    pub fn appservice_sender(&self) -> Option<&AppServiceSender> {
+        self.inner.appservice_sender.as_deref()
+    }
+
+    /// Access the client for querying application services.
+    pub fn appservice_query(&self) -> &AppServiceQuerier {
+        &self.inner.appservice_query
+    }
+
     /// This homeserver's server name (e.g. `example.com`).
     ///
     /// Used to construct fully-qualified Matrix IDs (`@user:example.com`,
@@ -0,0 +1,201 @@
+//! Per-device sync bookkeeping kept in memory between requests.
+//!
+//! Most of what `/sync` returns is derived from storage and the `since`
+//! token alone. A few things depend on what a device has already been sent,
+//! which the token does not record:
+//!
+//! * **Lazy-loaded members** -- with `lazy_load_members`, a sync only carries
+//!   the member events of the timeline's senders, and unless the filter sets
+//!   `include_redundant_members`, only those the device does not already
+//!   have. [`SentMembers`] remembers which members went to which device; an
+//!   initial sync starts over.
+//! * **Sliding sync connections** -- simplified sliding sync (MSC4186) only
+//!   resends a room or extension entry when it changed since the response
+//!   the client acknowledged with `pos`. [`SlidingConnection`] is what one
+//!   `conn_id` has been sent; the previous snapshot is kept until the next
+//!   `pos` arrives, so a retried request sees the same state again. It also
+//!   keeps a [`RoomSummary`] of every room, so a request only looks up the
+//!   rooms that changed since the `pos` it builds on.
+//!
+//! A device's entries go when the device is deleted
+//! ([`SyncState::forget_device`]). Like typing and presence in the
+//! [`EphemeralStore`](maelstrom_core::matrix::ephemeral::EphemeralStore), this
+//! is lost on restart. The cost is only some redundant member events on the
+//! next sync, and sliding sync clients being told their `pos` is unknown.
+
+use std::collections::{HashMap, HashSet};
+
+use dashmap::DashMap;
+use maelstrom_core::matrix::room::Membership;
+use serde_json::Value;
+
+/// In-memory sync state for every device, see the [module docs](self).
+#[derive(Default)]
+pub struct SyncState {
+    /// Member user IDs already sent, by `(user_id, device_id)` and room.
+    lazy_members: DashMap<(String, String), HashMap<String, HashSet<String>>>,
+    /// Sliding sync connections, by `(user_id, device_id, conn_id)`.
+    connections: DashMap<(String, String, String), ConnectionEntry>,
+}
+
+impl SyncState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The lazy-loaded members sent to one device.
+    pub fn sent_members<'a>(&'a self, user_id: &str, device_id: &str) -> SentMembers<'a> {
+        SentMembers {
+            state: self,
+            key: (user_id.to_string(), device_id.to_string()),
+        }
+    }
+
+    /// Forget everything sent to a device, once it is deleted.
+    pub fn forget_device(&self, user_id: &str, device_id: &str) {
+        self.lazy_members
+            .remove(&(user_id.to_string(), device_id.to_string()));
+        self.connections
+            .retain(|(user, device, _), _| !(user == user_id && device == device_id));
+    }
+
+    /// The state a sliding sync request builds on.
+    ///
+    /// Without `seq` (no `pos`) the connection starts over. With it, the
+    /// snapshot stored under `seq` is returned and earlier ones are dropped;
+    /// `None` means the client's `pos` is unknown.
+    pub fn resume_connection(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        conn_id: &str,
+        seq: Option<u64>,
+    ) -> Option<SlidingConnection> {
+        let key = (
+            user_id.to_string(),
+            device_id.to_string(),
+            conn_id.to_string(),
+        );
+        let mut entry = self.connections.entry(key).or_default();
+        let Some(seq) = seq else {
+            entry.acknowledged = None;
+            entry.pending = None;
+            return Some(SlidingConnection::default());
+        };
+        if entry.pending.as_ref().is_some_and(|(s, _)| *s == seq) {
+            entry.acknowledged = entry.pending.take();
+        }
+        match &entry.acknowledged {
+            Some((s, conn)) if *s == seq => Some(conn.clone()),
+            _ => None,
+        }
+    }
+
+    /// Remember what a sliding sync response sent and return the sequence
+    /// number its `pos` carries.
+    pub fn save_connection(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        conn_id: &str,
+        conn: SlidingConnection,
+    ) -> u64 {
+        let key = (
+            user_id.to_string(),
+            device_id.to_string(),
+            conn_id.to_string(),
+        );
+        let mut entry = self.connections.entry(key).or_default();
+        entry.next_seq += 1;
+        let seq = entry.next_seq;
+        entry.pending = Some((seq, conn));
+        seq
+    }
+}
+
+/// The snapshots of one sliding sync connection: the one the client last
+/// acknowledged and the one in the response it has not used yet.
+#[derive(Default)]
+struct ConnectionEntry {
+    next_seq: u64,
+    acknowledged: Option<(u64, SlidingConnection)>,
+    pending: Option<(u64, SlidingConnection)>,
+}
+
+/// What a sliding sync connection has been sent, see
+/// [`SyncState::resume_connection`].
+#[derive(Clone, Default)]
+pub struct SlidingConnection {
+    /// Rooms sent, and what they looked like then.
+    pub rooms: HashMap<String, SentRoom>,
+    /// The room count last sent for each list.
+    pub list_counts: HashMap<String, usize>,
+    /// Account data sent, by room (`""` for global) and type.
+    pub account_data: HashMap<(String, String), Value>,
+    /// Typing notifications sent, by room.
+    pub typing: HashMap<String, Value>,
+    /// Receipts sent, by room.
+    pub receipts: HashMap<String, Value>,
+    /// The `e2ee` key counts last sent.
+    pub key_counts: Option<Value>,
+    /// What the lists knew about each room, as of the response's position.
+    pub summaries: HashMap<String, RoomSummary>,
+}
+
+/// What the lists of a sliding sync connection need to know about a room.
+/// Kept with the connection, so only rooms with new events are looked at
+/// again.
+#[derive(Clone)]
+pub struct RoomSummary {
+    pub room_id: String,
+    /// `None` while peeking.
+    pub membership: Option<Membership>,
+    /// Stream position of the newest event.
+    pub latest: i64,
+    pub bump_stamp: i64,
+    pub name: Option<String>,
+    pub is_dm: bool,
+    pub is_encrypted: bool,
+    pub room_type: Option<String>,
+    /// Who sent the user's invite or knock membership, for those rooms.
+    pub inviter: Option<String>,
+}
+
+/// A room as last sent on a sliding sync connection.
+#[derive(Clone, PartialEq)]
+pub struct SentRoom {
+    /// Stream position of the newest event sent.
+    pub stream_position: i64,
+    pub membership: String,
+    /// The `required_state` and `timeline_limit` it was sent with.
+    pub config: Value,
+}
+
+/// The lazy-loaded members sent to one device, see [`SyncState::sent_members`].
+pub struct SentMembers<'a> {
+    state: &'a SyncState,
+    key: (String, String),
+}
+
+impl SentMembers<'_> {
+    /// Forget everything sent to the device, as on an initial sync.
+    pub fn reset(&self) {
+        self.state.lazy_members.remove(&self.key);
+    }
+
+    /// Record that the members of `room_id` were sent to the device and
+    /// return the ones it did not have before.
+    pub fn mark_sent<'m>(
+        &self,
+        room_id: &str,
+        members: impl IntoIterator<Item = &'m str>,
+    ) -> HashSet<String> {
+        let mut rooms = self.state.lazy_members.entry(self.key.clone()).or_default();
+        let sent = rooms.entry(room_id.to_string()).or_default();
+        members
+            .into_iter()
+            .filter(|member| sent.insert((*member).to_string()))
+            .map(String::from)
+            .collect()
+    }
+}
@@ -11,6 +11,7 @@
 //! - **Presence updates** — "Bob is online / idle / offline"
 //! - **Read receipts** — "Carol has read up to event Y"
 //! - **Device list updates** — "Dave added a new device" (for E2EE key tracking)
+//! - **Signing key updates** — "Dave reset his cross-signing keys"
 //! - **Direct-to-device messages** — encrypted key shares sent to specific devices
 //!
 //! EDUs are sent between homeservers inside federation transactions (alongside
@@ -52,6 +53,7 @@ pub enum EduContent {
     Presence(PresenceEdu),
     Receipt(ReceiptEdu),
     DeviceListUpdate(DeviceListUpdateEdu),
+    SigningKeyUpdate(SigningKeyUpdateEdu),
     DirectToDevice(DirectToDeviceEdu),
     Unknown(serde_json::Value),
 }
@@ -78,6 +80,9 @@ impl Edu {
             "m.device_list_update" => serde_json::from_value(self.content.clone())
                 .map(EduContent::DeviceListUpdate)
                 .unwrap_or(EduContent::Unknown(self.content.clone())),
+            "m.signing_key_update" => serde_json::from_value(self.content.clone())
+                .map(EduContent::SigningKeyUpdate)
+                .unwrap_or(EduContent::Unknown(self.content.clone())),
             "m.direct_to_device" => serde_json::from_value(self.content.clone())
                 .map(EduContent::DirectToDevice)
                 .unwrap_or(EduContent::Unknown(self.content.clone())),
@@ -175,6 +180,24 @@ pub struct DeviceListUpdateEdu {
     pub deleted: bool,
 }
 
+/// A cross-signing key update EDU (`m.signing_key_update`).
+///
+/// Sent when a remote user uploads new cross-signing keys. Only the public
+/// master and self-signing keys are shared; the user-signing key never leaves
+/// the user's server. Receivers tracking the user's devices replace their
+/// cached copies, so local users notice when the user's identity changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningKeyUpdateEdu {
+    /// The user whose cross-signing keys changed.
+    pub user_id: String,
+    /// The user's new master key, if it changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub master_key: Option<serde_json::Value>,
+    /// The user's new self-signing key, if it changed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub self_signing_key: Option<serde_json::Value>,
+}
+
 /// A direct-to-device message EDU (`m.direct_to_device`).
 ///
 /// Used to deliver messages directly to specific devices, bypassing the room
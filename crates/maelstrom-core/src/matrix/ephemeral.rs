@@ -31,12 +31,85 @@
 //!   [`merge_presence`](EphemeralStore::merge_presence), which apply the
 //!   state locally WITHOUT emitting a new delta — this prevents infinite
 //!   gossip feedback loops.
+//!
+//! # Presence timers
+//!
+//! Besides explicit updates, presence follows what the user does:
+//!
+//! - Every authenticated request is activity
+//!   ([`bump_presence_activity`](EphemeralStore::bump_presence_activity)); it
+//!   brings an idle (`unavailable`) user back `online`.
+//! - A `/sync` marks the user as connected for its whole duration
+//!   ([`presence_sync_started`](EphemeralStore::presence_sync_started)).
+//! - [`expire_presence`](EphemeralStore::expire_presence), called
+//!   periodically, moves local users that were inactive for [`IDLE_TIMEOUT_MS`]
+//!   to `unavailable`, and users neither syncing nor active for
+//!   [`SYNC_ONLINE_TIMEOUT_MS`] to `offline`.  Remote users go `offline` when
+//!   their server has not refreshed them for [`FEDERATION_TIMEOUT_MS`].
+//!
+//! In a cluster only the node that last saw a user's activity runs that
+//! user's timers; activity is gossiped at most once per
+//! [`LAST_ACTIVE_GRANULARITY_MS`] so the others know to stand down.
 
 use std::time::Instant;
 
 use dashmap::DashMap;
+use serde::Deserialize;
 use tokio::sync::mpsc;
 
+/// Inactivity after which an `online` local user becomes `unavailable`.
+pub const IDLE_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+/// Time without a `/sync` or other activity after which a local user
+/// becomes `offline`.
+pub const SYNC_ONLINE_TIMEOUT_MS: u64 = 30 * 1000;
+/// Time without an update after which a remote user is considered `offline`.
+pub const FEDERATION_TIMEOUT_MS: u64 = 30 * 60 * 1000;
+/// Activity closer together than this counts as continuous: the user is
+/// `currently_active`, and the activity is not gossiped again.
+pub const LAST_ACTIVE_GRANULARITY_MS: u64 = 60 * 1000;
+
+/// Server-wide presence setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceMode {
+    /// Presence is not tracked or shared at all.
+    Off,
+    /// Presence is shared between local users only.
+    LocalOnly,
+    /// Presence is shared with local users and over federation.
+    #[default]
+    Federated,
+}
+
+impl PresenceMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::LocalOnly => "local_only",
+            Self::Federated => "federated",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Self::Off),
+            "local_only" => Some(Self::LocalOnly),
+            "federated" => Some(Self::Federated),
+            _ => None,
+        }
+    }
+
+    /// Whether presence is tracked at all.
+    pub fn enabled(&self) -> bool {
+        *self != Self::Off
+    }
+
+    /// Whether presence is sent to and accepted from other servers.
+    pub fn federated(&self) -> bool {
+        *self == Self::Federated
+    }
+}
+
 /// A delta emitted by the local node when ephemeral state changes.
 ///
 /// The gossip bridge consumes these from the channel returned by
@@ -55,6 +128,7 @@ pub enum EphemeralDelta {
         user_id: String,
         status: String,
         status_msg: Option<String>,
+        last_active_ts: u64,
     },
 }
 
@@ -92,9 +166,13 @@ pub struct EphemeralStore {
     typing: DashMap<String, DashMap<String, Instant>>,
     /// Presence state: `user_id -> PresenceRecord`.
     ///
-    /// Unlike typing, presence does not expire on a timer — it persists until
-    /// explicitly updated (e.g., user goes offline) or the server restarts.
+    /// Unlike typing, presence is not pruned on read; it changes on updates,
+    /// activity and [`expire_presence`](Self::expire_presence).
     presence: DashMap<String, PresenceRecord>,
+    /// Number of `/sync` requests in progress per local user.
+    syncing: DashMap<String, usize>,
+    /// Server-wide presence setting.
+    presence_mode: PresenceMode,
     /// Optional channel for outbound gossip deltas.
     ///
     /// `None` in single-node mode. `Some(tx)` in cluster mode — every local
@@ -111,7 +189,23 @@ pub struct PresenceRecord {
     pub user_id: String,
     pub status: String,
     pub status_msg: Option<String>,
+    /// When the user was last active (ms since epoch).
     pub last_active_ts: u64,
+    /// When this record was last set or refreshed (ms since epoch).
+    pub last_update_ts: u64,
+    /// When a `/sync` by this user last started or finished on this node.
+    pub last_sync_ts: u64,
+    /// Whether this node runs the user's idle/offline timers.
+    pub tracked: bool,
+}
+
+impl PresenceRecord {
+    /// Whether the user is online and was active within
+    /// [`LAST_ACTIVE_GRANULARITY_MS`].
+    pub fn currently_active(&self, now_ms: u64) -> bool {
+        self.status == "online"
+            && now_ms.saturating_sub(self.last_active_ts) < LAST_ACTIVE_GRANULARITY_MS
+    }
 }
 
 impl EphemeralStore {
@@ -122,6 +216,8 @@ impl EphemeralStore {
         Self {
             typing: DashMap::new(),
             presence: DashMap::new(),
+            syncing: DashMap::new(),
+            presence_mode: PresenceMode::default(),
             delta_tx: None,
         }
     }
@@ -138,11 +234,24 @@ impl EphemeralStore {
         let store = Self {
             typing: DashMap::new(),
             presence: DashMap::new(),
+            syncing: DashMap::new(),
+            presence_mode: PresenceMode::default(),
             delta_tx: Some(tx),
         };
         (store, rx)
     }
 
+    /// Set the server-wide presence mode (default [`PresenceMode::Federated`]).
+    pub fn with_presence_mode(mut self, mode: PresenceMode) -> Self {
+        self.presence_mode = mode;
+        self
+    }
+
+    /// The server-wide presence mode.
+    pub fn presence_mode(&self) -> PresenceMode {
+        self.presence_mode
+    }
+
     // ── Typing (local writes — emit delta) ──────────────────────────
 
     /// Update a user's typing state (local write) and emit a gossip delta.
@@ -197,21 +306,169 @@ impl EphemeralStore {
 
     /// Update a user's presence (local write) and emit a gossip delta.
     ///
-    /// Call this when a local user changes their presence status. The state is
-    /// applied immediately, and if gossip is enabled, an
-    /// [`EphemeralDelta::Presence`] is sent to the gossip channel.
+    /// Call this when a local user explicitly sets their presence.  Setting
+    /// `online` counts as activity.  The state is applied immediately, and if
+    /// gossip is enabled, an [`EphemeralDelta::Presence`] is sent to the
+    /// gossip channel.
     pub fn set_presence(&self, user_id: &str, status: &str, status_msg: Option<&str>) {
-        self.apply_presence(user_id, status, status_msg);
+        let now_ms = super::event::timestamp_ms();
+        let mut record = self.presence_or_default(user_id, now_ms);
+        record.status = status.to_owned();
+        record.status_msg = status_msg.map(|s| s.to_owned());
+        if status == "online" {
+            record.last_active_ts = now_ms;
+        }
+        record.last_update_ts = now_ms;
+        record.tracked = true;
+        self.store_presence(record, true);
+    }
 
-        if let Some(tx) = &self.delta_tx {
-            let _ = tx.send(EphemeralDelta::Presence {
-                user_id: user_id.to_owned(),
-                status: status.to_owned(),
-                status_msg: status_msg.map(|s| s.to_owned()),
-            });
+    /// Record presence received from another server over federation.
+    ///
+    /// `last_active_ago` is relative to now.  The update is gossiped so every
+    /// node in the cluster sees it.
+    pub fn set_remote_presence(
+        &self,
+        user_id: &str,
+        status: &str,
+        status_msg: Option<&str>,
+        last_active_ago: u64,
+    ) {
+        let now_ms = super::event::timestamp_ms();
+        let record = PresenceRecord {
+            user_id: user_id.to_owned(),
+            status: status.to_owned(),
+            status_msg: status_msg.map(|s| s.to_owned()),
+            last_active_ts: now_ms.saturating_sub(last_active_ago),
+            last_update_ts: now_ms,
+            last_sync_ts: 0,
+            tracked: false,
+        };
+        self.store_presence(record, true);
+    }
+
+    /// Record activity by a local user.
+    ///
+    /// An idle (`unavailable`) user comes back `online`; the new record is
+    /// returned when the state changed so the caller can broadcast it.  Users
+    /// without presence, or `offline`, only have their activity time updated.
+    pub fn bump_presence_activity(&self, user_id: &str) -> Option<PresenceRecord> {
+        let now_ms = super::event::timestamp_ms();
+        let mut record = self.get_presence(user_id)?;
+        let previous_active = record.last_active_ts;
+        record.last_active_ts = now_ms;
+        record.tracked = true;
+
+        let changed = record.status == "unavailable";
+        if changed {
+            record.status = "online".to_owned();
+            record.last_update_ts = now_ms;
+        }
+        let gossip =
+            changed || now_ms.saturating_sub(previous_active) >= LAST_ACTIVE_GRANULARITY_MS;
+        self.store_presence(record.clone(), gossip);
+        changed.then_some(record)
+    }
+
+    /// A `/sync` by a local user started.
+    ///
+    /// `set_presence` is the sync's `set_presence` parameter: `online` brings
+    /// an `offline` user online (syncing alone does not end idleness), and
+    /// `unavailable` sets that state.
+    /// The user counts as connected until the matching
+    /// [`presence_sync_ended`](Self::presence_sync_ended).  Returns the new
+    /// record when the state changed.
+    pub fn presence_sync_started(
+        &self,
+        user_id: &str,
+        set_presence: &str,
+    ) -> Option<PresenceRecord> {
+        *self.syncing.entry(user_id.to_owned()).or_default() += 1;
+
+        let now_ms = super::event::timestamp_ms();
+        let mut record = self.presence_or_default(user_id, now_ms);
+        record.last_sync_ts = now_ms;
+        record.tracked = true;
+        let status = match set_presence {
+            "online" if record.status == "offline" => {
+                record.last_active_ts = now_ms;
+                "online"
+            }
+            "unavailable" => "unavailable",
+            _ => record.status.as_str(),
+        };
+
+        let changed = status != record.status;
+        if changed {
+            record.status = status.to_owned();
+            record.last_update_ts = now_ms;
+        }
+        self.store_presence(record.clone(), changed);
+        changed.then_some(record)
+    }
+
+    /// A `/sync` started with [`presence_sync_started`](Self::presence_sync_started)
+    /// finished (or was cancelled).
+    pub fn presence_sync_ended(&self, user_id: &str) {
+        if let Some(mut count) = self.syncing.get_mut(user_id) {
+            *count = count.saturating_sub(1);
+        }
+        self.syncing.remove_if(user_id, |_, count| *count == 0);
+        if let Some(mut record) = self.presence.get_mut(user_id) {
+            record.last_sync_ts = super::event::timestamp_ms();
         }
     }
 
+    /// Apply the idle and offline timers as of `now_ms`, returning the
+    /// records whose state changed.
+    ///
+    /// `is_local` tells local users (idle and sync timers) from remote ones
+    /// (federation timeout).  Changes to local users are gossiped.
+    pub fn expire_presence(
+        &self,
+        now_ms: u64,
+        is_local: impl Fn(&str) -> bool,
+    ) -> Vec<PresenceRecord> {
+        let mut changed = Vec::new();
+        for entry in self.presence.iter() {
+            let record = entry.value();
+            if record.status == "offline" {
+                continue;
+            }
+            let local = is_local(&record.user_id);
+            let status = if !local {
+                (now_ms.saturating_sub(record.last_update_ts) > FEDERATION_TIMEOUT_MS)
+                    .then_some("offline")
+            } else if !record.tracked {
+                None
+            } else if !self.syncing.contains_key(&record.user_id)
+                && now_ms.saturating_sub(record.last_sync_ts.max(record.last_active_ts))
+                    > SYNC_ONLINE_TIMEOUT_MS
+            {
+                Some("offline")
+            } else if record.status == "online"
+                && now_ms.saturating_sub(record.last_active_ts) > IDLE_TIMEOUT_MS
+            {
+                Some("unavailable")
+            } else {
+                None
+            };
+            if let Some(status) = status {
+                let mut record = record.clone();
+                record.status = status.to_owned();
+                record.last_update_ts = now_ms;
+                changed.push((record, local));
+            }
+        }
+        changed
+            .into_iter()
+            .map(|(record, local)| {
+                self.store_presence(record.clone(), local);
+                record
+            })
+            .collect()
+    }
+
     /// Look up a user's current presence record, if one exists.
     ///
     /// Returns `None` if the user has never set presence on this node (or
@@ -220,6 +477,15 @@ impl EphemeralStore {
         self.presence.get(user_id).map(|r| r.value().clone())
     }
 
+    /// Presence of every user matching `filter` who is not `offline`.
+    pub fn present_users(&self, filter: impl Fn(&str) -> bool) -> Vec<PresenceRecord> {
+        self.presence
+            .iter()
+            .filter(|r| r.status != "offline" && filter(&r.user_id))
+            .map(|r| r.value().clone())
+            .collect()
+    }
+
     // ── Merge (gossip-sourced — no delta emitted) ───────────────────
 
     /// Merge a remote typing update received from the gossip layer.
@@ -234,8 +500,23 @@ impl EphemeralStore {
     /// Merge a remote presence update received from the gossip layer.
     ///
     /// Same no-feedback-loop semantics as [`merge_typing`](Self::merge_typing).
-    pub fn merge_presence(&self, user_id: &str, status: &str, status_msg: Option<&str>) {
-        self.apply_presence(user_id, status, status_msg);
+    /// The sending node saw the latest activity, so it runs the user's timers
+    /// from now on and this node stops tracking them.
+    pub fn merge_presence(
+        &self,
+        user_id: &str,
+        status: &str,
+        status_msg: Option<&str>,
+        last_active_ts: u64,
+    ) {
+        let now_ms = super::event::timestamp_ms();
+        let mut record = self.presence_or_default(user_id, now_ms);
+        record.status = status.to_owned();
+        record.status_msg = status_msg.map(|s| s.to_owned());
+        record.last_active_ts = last_active_ts;
+        record.last_update_ts = now_ms;
+        record.tracked = false;
+        self.store_presence(record, false);
     }
 
     // ── Internal ────────────────────────────────────────────────────
@@ -252,18 +533,30 @@ impl EphemeralStore {
         }
     }
 
-    fn apply_presence(&self, user_id: &str, status: &str, status_msg: Option<&str>) {
-        let now_ms = super::event::timestamp_ms();
-
-        self.presence.insert(
-            user_id.to_owned(),
-            PresenceRecord {
+    /// The user's record, or a fresh `offline` one.
+    fn presence_or_default(&self, user_id: &str, now_ms: u64) -> PresenceRecord {
+        self.get_presence(user_id)
+            .unwrap_or_else(|| PresenceRecord {
                 user_id: user_id.to_owned(),
-                status: status.to_owned(),
-                status_msg: status_msg.map(|s| s.to_owned()),
+                status: "offline".to_owned(),
+                status_msg: None,
                 last_active_ts: now_ms,
-            },
-        );
+                last_update_ts: now_ms,
+                last_sync_ts: 0,
+                tracked: false,
+            })
+    }
+
+    fn store_presence(&self, record: PresenceRecord, gossip: bool) {
+        if gossip && let Some(tx) = &self.delta_tx {
+            let _ = tx.send(EphemeralDelta::Presence {
+                user_id: record.user_id.clone(),
+                status: record.status.clone(),
+                status_msg: record.status_msg.clone(),
+                last_active_ts: record.last_active_ts,
+            });
+        }
+        self.presence.insert(record.user_id.clone(), record);
     }
 }
 
@@ -272,3 +565,63 @@ impl Default for EphemeralStore {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::event::timestamp_ms;
+
+    fn is_local(user_id: &str) -> bool {
+        user_id.ends_with(":local")
+    }
+
+    #[test]
+    fn test_sync_brings_user_online_until_it_stops() {
+        let store = EphemeralStore::new();
+        let changed = store.presence_sync_started("@alice:local", "online");
+        assert_eq!(changed.unwrap().status, "online");
+
+        // Still syncing: never offline, but idle after the idle timeout
+        let later = timestamp_ms() + IDLE_TIMEOUT_MS + 1;
+        assert!(
+            store
+                .expire_presence(later - IDLE_TIMEOUT_MS, is_local)
+                .is_empty()
+        );
+        let changed = store.expire_presence(later, is_local);
+        assert_eq!(changed[0].status, "unavailable");
+
+        // Activity ends idleness
+        let changed = store.bump_presence_activity("@alice:local");
+        assert_eq!(changed.unwrap().status, "online");
+
+        // Once the sync ends, the user goes offline after the sync timeout
+        store.presence_sync_ended("@alice:local");
+        let later = timestamp_ms() + SYNC_ONLINE_TIMEOUT_MS + 1;
+        let changed = store.expire_presence(later, is_local);
+        assert_eq!(changed[0].status, "offline");
+    }
+
+    #[test]
+    fn test_remote_presence_times_out() {
+        let store = EphemeralStore::new();
+        store.set_remote_presence("@bob:remote", "online", Some("hi"), 0);
+        let now = timestamp_ms();
+        assert!(
+            store
+                .expire_presence(now + SYNC_ONLINE_TIMEOUT_MS + 1, is_local)
+                .is_empty()
+        );
+        let changed = store.expire_presence(now + FEDERATION_TIMEOUT_MS + 1, is_local);
+        assert_eq!(changed[0].status, "offline");
+        assert_eq!(changed[0].status_msg.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn test_gossiped_presence_is_not_tracked() {
+        let store = EphemeralStore::new();
+        store.merge_presence("@carol:local", "online", None, 0);
+        let later = timestamp_ms() + IDLE_TIMEOUT_MS + 1;
+        assert!(store.expire_presence(later, is_local).is_empty());
+    }
+}
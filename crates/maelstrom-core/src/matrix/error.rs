@@ -99,6 +99,11 @@ impl MatrixError {
         Self::new(StatusCode::BAD_REQUEST, ErrorCode::BadJson, msg)
     }
 
+    /// **400 / M_MISSING_PARAM** — a required query parameter is missing.
+    pub fn missing_param(msg: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, ErrorCode::MissingParam, msg)
+    }
+
     /// **400 / M_NOT_JSON** — the request body is not valid JSON at all.
     ///
     /// Returned when the `Content-Type` header is wrong or the body
@@ -353,13 +358,33 @@ pub enum ErrorCode {
     /// URL preview: the server does not have a URL preview service configured.
     #[serde(rename = "M_URL_NOT_SET")]
     UrlNotSet,
+    /// Appservice ping: the application service answered with an error status.
+    #[serde(rename = "M_BAD_STATUS")]
+    BadStatus,
+    /// Appservice ping: the application service could not be reached.
+    #[serde(rename = "M_CONNECTION_FAILED")]
+    ConnectionFailed,
+    /// Appservice ping: the application service did not answer in time.
+    #[serde(rename = "M_CONNECTION_TIMEOUT")]
+    ConnectionTimeout,
     /// The room alias in the request is malformed or doesn't resolve.
     #[serde(rename = "M_BAD_ALIAS")]
     BadAlias,
+    /// Key backup: keys were uploaded to a backup version that is not the
+    /// user's current one.
+    #[serde(rename = "M_WRONG_ROOM_KEYS_VERSION")]
+    WrongRoomKeysVersion,
+    /// Keys: an uploaded signature does not verify against the signing key.
+    #[serde(rename = "M_INVALID_SIGNATURE")]
+    InvalidSignature,
     /// MSC3706: The room's member list is not yet fully available because a
     /// partial-state join is still being resolved in the background.
     #[serde(rename = "org.matrix.msc3706.partial_state")]
     PartialState,
+    /// MSC4306: an automatic thread subscription was asked for, but the user
+    /// unsubscribed from the thread after the event that triggered it.
+    #[serde(rename = "IO_ELEMENT_MSC4306_CONFLICTING_UNSUBSCRIPTION")]
+    ConflictingUnsubscription,
 }
 
 impl std::fmt::Display for ErrorCode {
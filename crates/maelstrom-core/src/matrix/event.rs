@@ -582,7 +582,7 @@ mod tests {
     #[test]
     fn sign_adds_hashes_and_signatures() {
         let pdu = test_pdu();
-        let kp = super::keys::KeyPair::generate();
+        let kp = crate::matrix::keys::KeyPair::generate();
         let signed = pdu.sign(&kp, "example.com");
         assert!(signed.hashes.is_some());
         assert!(signed.signatures.is_some());
@@ -0,0 +1,544 @@
+//! Event filters -- the `filter` grammar of the Client-Server API.
+//!
+//! Clients narrow down what `/sync`, `/messages`, `/context` and `/search`
+//! return with filters
+//! ([spec: Filtering](https://spec.matrix.org/v1.18/client-server-api/#filtering)).
+//! A filter is plain JSON, either uploaded once (`POST /user/{userId}/filter`)
+//! and referenced by ID, or passed inline.
+//!
+//! * [`Filter`] -- the top-level filter used by `/sync`: which rooms, which
+//!   events in each section, and how events are rendered.
+//! * [`RoomFilter`] -- the `room` part of a [`Filter`], with one
+//!   [`RoomEventFilter`] per section of a room (`timeline`, `state`,
+//!   `ephemeral`, `account_data`).
+//! * [`RoomEventFilter`] -- the filter for room events, also passed on its own
+//!   to `/messages`, `/context` and `/search`.
+//! * [`EventFilter`] -- the filter for events outside rooms (presence, global
+//!   account data).
+//!
+//! # Matching
+//!
+//! An event passes a filter when every field the filter sets allows it:
+//!
+//! * `types` / `not_types` -- event types; `*` matches any run of characters,
+//!   so `m.room.*` covers every `m.room.` event. `not_types` wins over `types`.
+//! * `senders` / `not_senders` -- exact user IDs.
+//! * `rooms` / `not_rooms` -- exact room IDs.
+//! * `contains_url` -- whether the content must (or must not) have a `url`.
+//! * `rel_types` / `not_rel_types` (MSC3874) -- the event's own relation type.
+//!
+//! A missing allow-list (`types`, `senders`, `rooms`) allows everything, while
+//! an empty one allows nothing.
+//!
+//! # Rendering
+//!
+//! `event_format` picks the client format (the default) or the federation
+//! format (the full PDU), and `event_fields` keeps only the listed fields.
+//! Fields are dot-separated paths (`content.body`); a literal dot in a key is
+//! escaped as `\.`. Both are top-level [`Filter`] fields in the spec; this
+//! server also honours them in a [`RoomEventFilter`], so `/messages`,
+//! `/context` and `/search` render events the way `/sync` does.
+
+use serde::Deserialize;
+
+use crate::matrix::event::Pdu;
+
+/// How events are rendered: the client format, or the federation format
+/// (the full PDU as other servers see it).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventFormat {
+    #[default]
+    Client,
+    Federation,
+}
+
+impl EventFormat {
+    /// Render the event in this format, with `unsigned.membership` set to the
+    /// requesting user's membership for client-format events.
+    pub fn render(&self, event: &Pdu, membership: Option<&str>) -> serde_json::Value {
+        match self {
+            Self::Client => {
+                let client_event = event.to_client_event();
+                match membership {
+                    Some(membership) => client_event.with_membership(membership).into_json(),
+                    None => client_event.into_json(),
+                }
+            }
+            Self::Federation => event.to_federation_json(),
+        }
+    }
+}
+
+/// The top-level filter passed to `/sync`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Filter {
+    /// Fields to keep in each event, all of them if absent.
+    pub event_fields: Option<Vec<String>>,
+    /// Format of the events.
+    pub event_format: EventFormat,
+    /// Presence updates to include.
+    pub presence: EventFilter,
+    /// Global account data to include.
+    pub account_data: EventFilter,
+    /// Rooms and room events to include.
+    pub room: RoomFilter,
+}
+
+impl Filter {
+    /// Parse a filter from its JSON form.
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        Self::deserialize(value)
+    }
+
+    /// Render an event as this filter asks: in its `event_format`, with only
+    /// its `event_fields`.
+    pub fn render(&self, event: &Pdu, membership: Option<&str>) -> serde_json::Value {
+        self.project(self.event_format.render(event, membership))
+    }
+
+    /// Keep only the filter's `event_fields` of an already rendered event.
+    pub fn project(&self, event: serde_json::Value) -> serde_json::Value {
+        match &self.event_fields {
+            Some(fields) => project_event_fields(&event, fields),
+            None => event,
+        }
+    }
+}
+
+/// The `room` part of a [`Filter`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RoomFilter {
+    /// Rooms to include, all of them if absent.
+    pub rooms: Option<Vec<String>>,
+    /// Rooms to leave out, even if listed in `rooms`.
+    pub not_rooms: Vec<String>,
+    /// Whether rooms the user has left appear in an initial sync.
+    pub include_leave: bool,
+    /// Events in each room's timeline.
+    pub timeline: RoomEventFilter,
+    /// Events in each room's state.
+    pub state: RoomEventFilter,
+    /// Ephemeral events (typing, receipts) of each room.
+    pub ephemeral: RoomEventFilter,
+    /// Per-room account data.
+    pub account_data: RoomEventFilter,
+}
+
+impl RoomFilter {
+    /// Whether the room appears at all.
+    pub fn allows_room(&self, room_id: &str) -> bool {
+        allows(self.rooms.as_deref(), &self.not_rooms, room_id)
+    }
+}
+
+/// A filter for events outside rooms: presence and global account data.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EventFilter {
+    /// Maximum number of events to return.
+    pub limit: Option<usize>,
+    /// Event types to include, all of them if absent.
+    pub types: Option<Vec<String>>,
+    /// Event types to leave out.
+    pub not_types: Vec<String>,
+    /// Senders to include, all of them if absent.
+    pub senders: Option<Vec<String>>,
+    /// Senders to leave out.
+    pub not_senders: Vec<String>,
+}
+
+impl EventFilter {
+    /// Whether an event of this type, from this sender if it has one, passes.
+    pub fn matches(&self, event_type: &str, sender: Option<&str>) -> bool {
+        type_allowed(self.types.as_deref(), &self.not_types, event_type)
+            && sender
+                .is_none_or(|sender| allows(self.senders.as_deref(), &self.not_senders, sender))
+    }
+}
+
+/// A filter for room events, the per-section filters of a [`RoomFilter`]
+/// and the `filter` of `/messages`, `/context` and `/search`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RoomEventFilter {
+    /// Maximum number of events to return.
+    pub limit: Option<usize>,
+    /// Event types to include, all of them if absent.
+    pub types: Option<Vec<String>>,
+    /// Event types to leave out.
+    pub not_types: Vec<String>,
+    /// Senders to include, all of them if absent.
+    pub senders: Option<Vec<String>>,
+    /// Senders to leave out.
+    pub not_senders: Vec<String>,
+    /// Rooms to include, all of them if absent.
+    pub rooms: Option<Vec<String>>,
+    /// Rooms to leave out.
+    pub not_rooms: Vec<String>,
+    /// If set, only events that do (or do not) have a `url` in their content.
+    pub contains_url: Option<bool>,
+    /// Only send the members of the senders of the returned events.
+    pub lazy_load_members: bool,
+    /// With `lazy_load_members`, resend members the client already has.
+    pub include_redundant_members: bool,
+    /// Split notification counts between the main timeline and threads.
+    /// Kept with the filter, but `/sync` has no per-thread counts to split.
+    pub unread_thread_notifications: bool,
+    /// Only events whose own relation has one of these types (MSC3874).
+    #[serde(alias = "org.matrix.msc3874.rel_types")]
+    pub rel_types: Option<Vec<String>>,
+    /// Leave out events whose own relation has one of these types (MSC3874).
+    #[serde(alias = "org.matrix.msc3874.not_rel_types")]
+    pub not_rel_types: Vec<String>,
+    /// Only events that have a child relation of one of these types.
+    pub related_by_rel_types: Option<Vec<String>>,
+    /// Fields to keep in each event, all of them if absent.
+    pub event_fields: Option<Vec<String>>,
+    /// Format of the events.
+    pub event_format: EventFormat,
+}
+
+impl RoomEventFilter {
+    /// Parse a room event filter from its JSON form.
+    pub fn from_json(value: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        Self::deserialize(value)
+    }
+
+    /// Whether events of the room pass the `rooms`/`not_rooms` fields.
+    pub fn allows_room(&self, room_id: &str) -> bool {
+        allows(self.rooms.as_deref(), &self.not_rooms, room_id)
+    }
+
+    /// Whether the event passes every field of the filter except
+    /// `related_by_rel_types`, which needs the event's children.
+    pub fn matches(&self, event: &Pdu) -> bool {
+        if !self.matches_fields(&event.room_id, &event.event_type, Some(&event.sender)) {
+            return false;
+        }
+        if let Some(contains_url) = self.contains_url
+            && event.content.get("url").is_some_and(|u| u.is_string()) != contains_url
+        {
+            return false;
+        }
+        let rel_type = event
+            .content
+            .get("m.relates_to")
+            .and_then(|r| r.get("rel_type"))
+            .and_then(|r| r.as_str());
+        if let Some(rel_types) = &self.rel_types
+            && !rel_type.is_some_and(|rel_type| rel_types.iter().any(|r| r == rel_type))
+        {
+            return false;
+        }
+        !rel_type.is_some_and(|rel_type| self.not_rel_types.iter().any(|r| r == rel_type))
+    }
+
+    /// Whether an event with these fields passes the room, type and sender
+    /// parts of the filter. For events without a sender, such as typing
+    /// notifications and account data, pass `None`.
+    pub fn matches_fields(&self, room_id: &str, event_type: &str, sender: Option<&str>) -> bool {
+        self.allows_room(room_id)
+            && type_allowed(self.types.as_deref(), &self.not_types, event_type)
+            && sender
+                .is_none_or(|sender| allows(self.senders.as_deref(), &self.not_senders, sender))
+    }
+
+    /// Render an event as this filter asks: in its `event_format`, with only
+    /// its `event_fields`.
+    pub fn render(&self, event: &Pdu, membership: Option<&str>) -> serde_json::Value {
+        let rendered = self.event_format.render(event, membership);
+        match &self.event_fields {
+            Some(fields) => project_event_fields(&rendered, fields),
+            None => rendered,
+        }
+    }
+}
+
+fn allows(allowed: Option<&[String]>, denied: &[String], value: &str) -> bool {
+    !denied.iter().any(|d| d == value) && allowed.is_none_or(|a| a.iter().any(|a| a == value))
+}
+
+fn type_allowed(types: Option<&[String]>, not_types: &[String], event_type: &str) -> bool {
+    !not_types.iter().any(|p| type_matches(p, event_type))
+        && types.is_none_or(|types| types.iter().any(|p| type_matches(p, event_type)))
+}
+
+/// Whether `event_type` matches a filter type pattern, where `*` stands for
+/// any run of characters.
+pub fn type_matches(pattern: &str, event_type: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let Some(mut rest) = event_type.strip_prefix(parts.next().unwrap_or_default()) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the whole type must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Keep only the listed fields of an event. Each field is a dot-separated
+/// path into the event; `\.` is a literal dot and `\\` a literal backslash.
+/// Paths that do not exist in the event are skipped.
+pub fn project_event_fields(event: &serde_json::Value, fields: &[String]) -> serde_json::Value {
+    let mut projected = serde_json::Map::new();
+    for field in fields {
+        let path = split_field_path(field);
+        if let Some(value) = path.iter().try_fold(event, |value, key| value.get(key)) {
+            insert_at_path(&mut projected, &path, value.clone());
+        }
+    }
+    serde_json::Value::Object(projected)
+}
+
+fn insert_at_path(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    path: &[String],
+    value: serde_json::Value,
+) {
+    match path {
+        [] => {}
+        [key] => {
+            target.insert(key.clone(), value);
+        }
+        [key, rest @ ..] => {
+            let entry = target
+                .entry(key.clone())
+                .or_insert_with(|| serde_json::json!({}));
+            if let Some(object) = entry.as_object_mut() {
+                insert_at_path(object, rest, value);
+            }
+        }
+    }
+}
+
+fn split_field_path(field: &str) -> Vec<String> {
+    let mut path = vec![String::new()];
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or('\\');
+                if let Some(key) = path.last_mut() {
+                    key.push(escaped);
+                }
+            }
+            '.' => path.push(String::new()),
+            c => {
+                if let Some(key) = path.last_mut() {
+                    key.push(c);
+                }
+            }
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, sender: &str, content: serde_json::Value) -> Pdu {
+        Pdu {
+            event_id: "$event".to_string(),
+            room_id: "!room:example.com".to_string(),
+            sender: sender.to_string(),
+            event_type: event_type.to_string(),
+            state_key: None,
+            content,
+            origin_server_ts: 1,
+            unsigned: None,
+            stream_position: 1,
+            origin: None,
+            auth_events: None,
+            prev_events: None,
+            depth: None,
+            hashes: None,
+            signatures: None,
+        }
+    }
+
+    fn room_filter(json: serde_json::Value) -> RoomEventFilter {
+        RoomEventFilter::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn type_wildcards() {
+        assert!(type_matches("m.room.message", "m.room.message"));
+        assert!(!type_matches("m.room.message", "m.room.message.extra"));
+        assert!(type_matches("m.room.*", "m.room.member"));
+        assert!(!type_matches("m.room.*", "m.reaction"));
+        assert!(type_matches("*", "anything"));
+        assert!(type_matches("m.*.member", "m.room.member"));
+        assert!(!type_matches("m.*.member", "m.room.name"));
+        assert!(type_matches("*.member", "m.room.member"));
+        assert!(!type_matches("m.*room*", "m.roo"));
+        assert!(type_matches("m.*room*", "m.xroomx"));
+    }
+
+    #[test]
+    fn types_and_senders() {
+        let filter = room_filter(serde_json::json!({
+            "types": ["m.room.*"],
+            "not_types": ["m.room.member"],
+            "not_senders": ["@spam:example.com"],
+        }));
+        let message = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({}),
+        );
+        assert!(filter.matches(&message));
+        assert!(!filter.matches(&event(
+            "m.room.member",
+            "@alice:example.com",
+            serde_json::json!({})
+        )));
+        assert!(!filter.matches(&event(
+            "m.reaction",
+            "@alice:example.com",
+            serde_json::json!({})
+        )));
+        assert!(!filter.matches(&event(
+            "m.room.message",
+            "@spam:example.com",
+            serde_json::json!({})
+        )));
+
+        let filter = room_filter(serde_json::json!({"senders": ["@bob:example.com"]}));
+        assert!(!filter.matches(&message));
+        assert!(filter.matches_fields("!room:example.com", "m.typing", None));
+
+        // An empty allow-list allows nothing
+        let filter = room_filter(serde_json::json!({"types": []}));
+        assert!(!filter.matches(&message));
+        assert!(RoomEventFilter::default().matches(&message));
+    }
+
+    #[test]
+    fn rooms_and_urls() {
+        let filter = room_filter(serde_json::json!({
+            "rooms": ["!room:example.com", "!other:example.com"],
+            "not_rooms": ["!other:example.com"],
+            "contains_url": true,
+        }));
+        let image = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({"msgtype": "m.image", "url": "mxc://example.com/abc"}),
+        );
+        let text = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({"body": "hi"}),
+        );
+        assert!(filter.matches(&image));
+        assert!(!filter.matches(&text));
+        assert!(!filter.allows_room("!other:example.com"));
+        assert!(!filter.allows_room("!third:example.com"));
+
+        let filter = room_filter(serde_json::json!({"contains_url": false}));
+        assert!(!filter.matches(&image));
+        assert!(filter.matches(&text));
+
+        let rooms =
+            Filter::from_json(&serde_json::json!({"room": {"not_rooms": ["!room:example.com"]}}))
+                .unwrap()
+                .room;
+        assert!(!rooms.allows_room("!room:example.com"));
+        assert!(rooms.allows_room("!other:example.com"));
+    }
+
+    #[test]
+    fn relation_types() {
+        let thread_reply = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({"m.relates_to": {"rel_type": "m.thread", "event_id": "$root"}}),
+        );
+        let plain = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({}),
+        );
+        let filter = room_filter(serde_json::json!({"org.matrix.msc3874.rel_types": ["m.thread"]}));
+        assert!(filter.matches(&thread_reply));
+        assert!(!filter.matches(&plain));
+        let filter = room_filter(serde_json::json!({"not_rel_types": ["m.thread"]}));
+        assert!(!filter.matches(&thread_reply));
+        assert!(filter.matches(&plain));
+    }
+
+    #[test]
+    fn event_filter_for_presence_and_account_data() {
+        let filter: EventFilter = serde_json::from_value(serde_json::json!({
+            "types": ["m.push_rules"],
+            "not_senders": ["@alice:example.com"],
+        }))
+        .unwrap();
+        assert!(filter.matches("m.push_rules", None));
+        assert!(!filter.matches("m.direct", None));
+        assert!(!filter.matches("m.push_rules", Some("@alice:example.com")));
+    }
+
+    #[test]
+    fn field_projection() {
+        let rendered = serde_json::json!({
+            "type": "m.room.message",
+            "sender": "@alice:example.com",
+            "content": {"body": "hi", "msgtype": "m.text", "m.relates_to": {"rel_type": "m.thread"}},
+            "unsigned": {"age": 5},
+        });
+        let fields: Vec<String> = [
+            "type",
+            "content.body",
+            "content.m\\.relates_to.rel_type",
+            "missing.field",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        assert_eq!(
+            project_event_fields(&rendered, &fields),
+            serde_json::json!({
+                "type": "m.room.message",
+                "content": {"body": "hi", "m.relates_to": {"rel_type": "m.thread"}},
+            })
+        );
+        assert_eq!(project_event_fields(&rendered, &[]), serde_json::json!({}));
+    }
+
+    #[test]
+    fn render_formats() {
+        let message = event(
+            "m.room.message",
+            "@alice:example.com",
+            serde_json::json!({"body": "hi"}),
+        );
+        let filter = Filter::from_json(&serde_json::json!({
+            "event_format": "federation",
+            "event_fields": ["type", "room_id", "unsigned"],
+        }))
+        .unwrap();
+        let rendered = filter.render(&message, Some("join"));
+        assert_eq!(rendered["type"], "m.room.message");
+        assert_eq!(rendered["room_id"], "!room:example.com");
+        assert!(rendered.get("content").is_none());
+        assert!(rendered.get("unsigned").is_none());
+
+        let rendered = Filter::default().render(&message, Some("join"));
+        assert_eq!(rendered["unsigned"]["membership"], "join");
+        assert_eq!(rendered["content"]["body"], "hi");
+        assert!(Filter::from_json(&serde_json::json!({"event_format": "xml"})).is_err());
+    }
+}
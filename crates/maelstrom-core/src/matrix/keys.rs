@@ -18,6 +18,8 @@
 //! standalone [`verify_signature`] function is used to check signatures from
 //! remote servers when you only have their public key.
 
+use std::sync::{Arc, RwLock};
+
 use ed25519_dalek::{Signer, Verifier};
 use rand::Rng;
 
@@ -93,6 +95,17 @@ impl KeyPair {
         }
     }
 
+    /// Reconstruct a keypair from a stored key ID and unpadded base64 private key.
+    ///
+    /// Counterpart of [`private_key_base64()`](Self::private_key_base64).
+    /// Returns `None` if the base64 is malformed or does not decode to 32 bytes.
+    pub fn from_base64(key_id: String, private_key_b64: &str) -> Option<Self> {
+        use base64::Engine;
+        let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+        let bytes: [u8; 32] = engine.decode(private_key_b64).ok()?.try_into().ok()?;
+        Some(Self::from_bytes(key_id, &bytes))
+    }
+
     /// The key ID, e.g. `ed25519:abc12345`.
     ///
     /// This is included in event signatures and federation request headers so
@@ -121,6 +134,13 @@ impl KeyPair {
         self.signing_key.as_bytes()
     }
 
+    /// The raw private key as unpadded base64, the format kept in the database.
+    pub fn private_key_base64(&self) -> String {
+        use base64::Engine;
+        let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+        engine.encode(self.signing_key.as_bytes())
+    }
+
     /// The raw 32-byte public key.
     ///
     /// Used when you need to pass the public key to [`verify_signature()`] for
@@ -152,6 +172,48 @@ impl std::fmt::Debug for KeyPair {
     }
 }
 
+/// A shared handle to the key this server currently signs with.
+///
+/// Signing keys can be rotated while the server is running. Everything that
+/// signs -- the federation router, the outbound federation clients, event
+/// signing -- holds a clone of the same handle, so a call to
+/// [`replace()`](Self::replace) switches all of them to the new key at once.
+/// Cloning the handle is cheap; cloning the [`KeyPair`] returned by
+/// [`current()`](Self::current) copies 32 bytes.
+#[derive(Clone, Debug)]
+pub struct SharedKeyPair {
+    inner: Arc<RwLock<KeyPair>>,
+}
+
+impl SharedKeyPair {
+    pub fn new(key: KeyPair) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(key)),
+        }
+    }
+
+    /// The key to sign with right now.
+    pub fn current(&self) -> KeyPair {
+        self.inner.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Switch to a new signing key. Returns `false` if it was already current.
+    pub fn replace(&self, key: KeyPair) -> bool {
+        let mut current = self.inner.write().unwrap_or_else(|e| e.into_inner());
+        if current.key_id == key.key_id {
+            return false;
+        }
+        *current = key;
+        true
+    }
+}
+
+impl From<KeyPair> for SharedKeyPair {
+    fn from(key: KeyPair) -> Self {
+        Self::new(key)
+    }
+}
+
 /// Verify an Ed25519 signature from a remote server.
 ///
 /// Use this when checking signatures on events received over federation or
@@ -185,6 +247,16 @@ pub fn verify_signature(public_key_bytes: &[u8; 32], message: &[u8], signature_b
     verifying_key.verify(message, &signature).is_ok()
 }
 
+/// Decode an unpadded base64 Ed25519 public key, as published in key
+/// responses and device keys, for [`verify_signature`].
+///
+/// Returns `None` if the base64 is malformed or does not decode to 32 bytes.
+pub fn public_key_from_base64(public_key_b64: &str) -> Option<[u8; 32]> {
+    use base64::Engine;
+    let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
+    engine.decode(public_key_b64).ok()?.try_into().ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,6 +288,27 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_base64_roundtrip() {
+        let kp = KeyPair::generate();
+        let restored =
+            KeyPair::from_base64(kp.key_id().to_string(), &kp.private_key_base64()).unwrap();
+        assert_eq!(kp.public_key_base64(), restored.public_key_base64());
+        assert!(KeyPair::from_base64(kp.key_id().to_string(), "not base64!").is_none());
+    }
+
+    #[test]
+    fn test_shared_key_pair_replace() {
+        let first = KeyPair::generate();
+        let second = KeyPair::generate();
+        let shared = SharedKeyPair::new(first.clone());
+        let other_handle = shared.clone();
+
+        assert!(!shared.replace(first.clone()));
+        assert!(shared.replace(second.clone()));
+        assert_eq!(other_handle.current().key_id(), second.key_id());
+    }
+
     #[test]
     fn test_wrong_key_fails_verify() {
         let kp1 = KeyPair::generate();
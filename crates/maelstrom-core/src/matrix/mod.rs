@@ -17,6 +17,7 @@
 //!   │  event   (Pdu)          │  ← the core persistent event type
 //!   │  content (typed bodies) │  ← what goes inside an event's `content` field
 //!   │  edu     (ephemeral)    │  ← non-persisted data units (typing, receipts)
+//!   │  filter  (filters)      │  ← which events a client asked for, and in what shape
 //!   ├─────────────────────────┤
 //!   │  room    (enums)        │  ← Membership, JoinRule, HistoryVisibility, etc.
 //!   │  keys    (device keys)  │  ← one-time keys, cross-signing, key queries
@@ -73,6 +74,7 @@ pub mod edu;
 pub mod ephemeral;
 pub mod error;
 pub mod event;
+pub mod filter;
 pub mod id;
 pub mod json;
 pub mod keys;
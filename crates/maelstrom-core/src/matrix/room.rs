@@ -294,6 +294,11 @@ impl fmt::Display for JoinRule {
 /// The [`visible_to_departed`](HistoryVisibility::visible_to_departed) method returns
 /// `true` for `WorldReadable` and `Shared` -- the two settings where a user who has
 /// *left* the room can still see past events.
+///
+/// Whether a particular event is visible is decided by the setting in force *at that
+/// event*, together with the viewer's membership at the event:
+/// [`visible_to_user`](HistoryVisibility::visible_to_user) for clients and
+/// [`visible_to_server`](HistoryVisibility::visible_to_server) for federation.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum HistoryVisibility {
     /// Anyone can read the room, even non-members.
@@ -337,6 +342,38 @@ impl HistoryVisibility {
     pub const fn visible_to_departed(&self) -> bool {
         matches!(self, Self::WorldReadable | Self::Shared)
     }
+
+    /// Returns `true` if a user may see an event sent while this setting was in force.
+    ///
+    /// `membership_at_event` is the user's membership in the room state at the event
+    /// (`None` if they had none).  `joined_after_event` is whether the user was joined
+    /// at some point after the event was sent, which is all `Shared` asks for.
+    pub fn visible_to_user(
+        &self,
+        membership_at_event: Option<Membership>,
+        joined_after_event: bool,
+    ) -> bool {
+        match self {
+            Self::WorldReadable => true,
+            _ if membership_at_event == Some(Membership::Join) => true,
+            Self::Shared => joined_after_event,
+            Self::Invited => membership_at_event == Some(Membership::Invite),
+            Self::Joined => false,
+        }
+    }
+
+    /// Returns `true` if a remote server may see an event sent while this setting was
+    /// in force.
+    ///
+    /// `any_joined` / `any_invited` say whether any of the server's users was joined /
+    /// invited at the event.  Events a server may not see are only sent redacted.
+    pub const fn visible_to_server(&self, any_joined: bool, any_invited: bool) -> bool {
+        match self {
+            Self::WorldReadable | Self::Shared => true,
+            Self::Invited => any_joined || any_invited,
+            Self::Joined => any_joined,
+        }
+    }
 }
 
 impl fmt::Display for HistoryVisibility {
@@ -545,6 +582,64 @@ pub enum RedactionAlgorithm {
     V2,
 }
 
+impl RedactionAlgorithm {
+    /// The content of an event of `event_type` once redacted: only the keys
+    /// the algorithm preserves for that type are kept.
+    pub fn redact_content(
+        &self,
+        event_type: &str,
+        content: &serde_json::Value,
+    ) -> serde_json::Value {
+        let v2 = *self == Self::V2;
+        let keep: &[&str] = match event_type {
+            event_type::MEMBER if v2 => &["membership", "join_authorised_via_users_server"],
+            event_type::MEMBER => &["membership"],
+            event_type::CREATE if v2 => return content.clone(),
+            event_type::CREATE => &["creator"],
+            event_type::JOIN_RULES => &["join_rule", "allow"],
+            event_type::POWER_LEVELS if v2 => &[
+                "ban",
+                "events",
+                "events_default",
+                "invite",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            event_type::POWER_LEVELS => &[
+                "ban",
+                "events",
+                "events_default",
+                "kick",
+                "redact",
+                "state_default",
+                "users",
+                "users_default",
+            ],
+            event_type::HISTORY_VISIBILITY => &["history_visibility"],
+            event_type::REDACTION if v2 => &["redacts"],
+            _ => &[],
+        };
+        let mut redacted: serde_json::Map<String, serde_json::Value> = keep
+            .iter()
+            .filter_map(|key| Some((key.to_string(), content.get(*key)?.clone())))
+            .collect();
+        // Room version 11 keeps the signed part of a third-party invite
+        if v2
+            && event_type == event_type::MEMBER
+            && let Some(signed) = content.pointer("/third_party_invite/signed")
+        {
+            redacted.insert(
+                "third_party_invite".to_string(),
+                serde_json::json!({ "signed": signed }),
+            );
+        }
+        serde_json::Value::Object(redacted)
+    }
+}
+
 impl RoomVersion {
     /// Parse a room version string (e.g., `"10"`) into a `RoomVersion`.
     /// Returns `None` for unrecognized versions.
@@ -683,6 +778,80 @@ impl fmt::Display for RoomVersion {
     }
 }
 
+// ── Server ACL ─────────────────────────────────────────────────────────
+
+/// Evaluate whether a server is allowed by an `m.room.server_acl` event's content.
+///
+/// Pass the `content` field of the ACL state event. Returns `true` if the
+/// server is allowed, `false` if denied. When no ACL event exists in a room,
+/// callers should treat all servers as allowed (don't call this function).
+///
+/// Evaluation order per spec:
+/// 1. If `allow_ip_literals` is false, reject IP-address server names
+/// 2. Check deny list — if any pattern matches, reject
+/// 3. Check allow list — if any pattern matches, allow
+/// 4. If no allow pattern matches, reject
+pub fn server_acl_allowed(content: &serde_json::Value, server_name: &str) -> bool {
+    let allow_ip_literals = content
+        .get("allow_ip_literals")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(true);
+    let allow: Vec<&str> = content
+        .get("allow")
+        .and_then(|v| v.as_array())
+        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    let deny: Vec<&str> = content
+        .get("deny")
+        .and_then(|v| v.as_array())
+        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+
+    // 1. IP literal check
+    if !allow_ip_literals {
+        let host = server_name.split(':').next().unwrap_or(server_name);
+        if let Some(c) = host.chars().next()
+            && (c.is_ascii_digit() || c == '[')
+        {
+            return false;
+        }
+    }
+
+    // 2. Deny list
+    for pattern in &deny {
+        if server_acl_glob_match(pattern, server_name) {
+            return false;
+        }
+    }
+
+    // 3. Allow list
+    if allow.is_empty() {
+        return false;
+    }
+    for pattern in &allow {
+        if server_acl_glob_match(pattern, server_name) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Glob matching for server ACL patterns.
+///
+/// - `"*"` matches everything
+/// - `"*.suffix"` matches any server ending with `.suffix`
+/// - Anything else is an exact match
+pub fn server_acl_glob_match(pattern: &str, value: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    if let Some(suffix) = pattern.strip_prefix('*') {
+        return value.ends_with(suffix);
+    }
+    pattern == value
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -709,6 +878,40 @@ mod tests {
         assert_eq!(RoomVersion::parse("99"), None);
     }
 
+    #[test]
+    fn redaction_keeps_structural_fields() {
+        let member = serde_json::json!({
+            "membership": "join",
+            "displayname": "Alice",
+            "join_authorised_via_users_server": "@bob:example.com",
+        });
+        assert_eq!(
+            RedactionAlgorithm::V1.redact_content(event_type::MEMBER, &member),
+            serde_json::json!({"membership": "join"})
+        );
+        assert_eq!(
+            RedactionAlgorithm::V2.redact_content(event_type::MEMBER, &member),
+            serde_json::json!({
+                "membership": "join",
+                "join_authorised_via_users_server": "@bob:example.com",
+            })
+        );
+        let create = serde_json::json!({"creator": "@alice:example.com", "m.federate": false});
+        assert_eq!(
+            RedactionAlgorithm::V1.redact_content(event_type::CREATE, &create),
+            serde_json::json!({"creator": "@alice:example.com"})
+        );
+        assert_eq!(
+            RedactionAlgorithm::V2.redact_content(event_type::CREATE, &create),
+            create
+        );
+        assert_eq!(
+            RedactionAlgorithm::V2
+                .redact_content("m.room.message", &serde_json::json!({"body": "hi"})),
+            serde_json::json!({})
+        );
+    }
+
     #[test]
     fn version_features() {
         assert!(!RoomVersion::V5.strict_power_levels());
@@ -736,6 +939,29 @@ mod tests {
         assert!(!HistoryVisibility::Joined.visible_to_departed());
     }
 
+    #[test]
+    fn history_visibility_for_users() {
+        use HistoryVisibility::*;
+        assert!(WorldReadable.visible_to_user(None, false));
+        assert!(Joined.visible_to_user(Some(Membership::Join), false));
+        assert!(!Joined.visible_to_user(Some(Membership::Invite), true));
+        assert!(Invited.visible_to_user(Some(Membership::Invite), false));
+        assert!(!Invited.visible_to_user(None, true));
+        assert!(Shared.visible_to_user(None, true));
+        assert!(!Shared.visible_to_user(Some(Membership::Leave), false));
+    }
+
+    #[test]
+    fn history_visibility_for_servers() {
+        use HistoryVisibility::*;
+        assert!(Shared.visible_to_server(false, false));
+        assert!(WorldReadable.visible_to_server(false, false));
+        assert!(Invited.visible_to_server(false, true));
+        assert!(!Invited.visible_to_server(false, false));
+        assert!(Joined.visible_to_server(true, false));
+        assert!(!Joined.visible_to_server(false, true));
+    }
+
     #[test]
     fn server_acl_matching() {
         assert!(server_acl_glob_match("*", "anything.com"));
@@ -758,77 +984,3 @@ mod tests {
         assert!(!server_acl_allowed(&acl, "[::1]:8448"));
     }
 }
-
-// ── Server ACL ─────────────────────────────────────────────────────────
-
-/// Evaluate whether a server is allowed by an `m.room.server_acl` event's content.
-///
-/// Pass the `content` field of the ACL state event. Returns `true` if the
-/// server is allowed, `false` if denied. When no ACL event exists in a room,
-/// callers should treat all servers as allowed (don't call this function).
-///
-/// Evaluation order per spec:
-/// 1. If `allow_ip_literals` is false, reject IP-address server names
-/// 2. Check deny list — if any pattern matches, reject
-/// 3. Check allow list — if any pattern matches, allow
-/// 4. If no allow pattern matches, reject
-pub fn server_acl_allowed(content: &serde_json::Value, server_name: &str) -> bool {
-    let allow_ip_literals = content
-        .get("allow_ip_literals")
-        .and_then(|v| v.as_bool())
-        .unwrap_or(true);
-    let allow: Vec<&str> = content
-        .get("allow")
-        .and_then(|v| v.as_array())
-        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
-        .unwrap_or_default();
-    let deny: Vec<&str> = content
-        .get("deny")
-        .and_then(|v| v.as_array())
-        .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
-        .unwrap_or_default();
-
-    // 1. IP literal check
-    if !allow_ip_literals {
-        let host = server_name.split(':').next().unwrap_or(server_name);
-        if let Some(c) = host.chars().next()
-            && (c.is_ascii_digit() || c == '[')
-        {
-            return false;
-        }
-    }
-
-    // 2. Deny list
-    for pattern in &deny {
-        if server_acl_glob_match(pattern, server_name) {
-            return false;
-        }
-    }
-
-    // 3. Allow list
-    if allow.is_empty() {
-        return false;
-    }
-    for pattern in &allow {
-        if server_acl_glob_match(pattern, server_name) {
-            return true;
-        }
-    }
-
-    false
-}
-
-/// Glob matching for server ACL patterns.
-///
-/// - `"*"` matches everything
-/// - `"*.suffix"` matches any server ending with `.suffix`
-/// - Anything else is an exact match
-pub fn server_acl_glob_match(pattern: &str, value: &str) -> bool {
-    if pattern == "*" {
-        return true;
-    }
-    if let Some(suffix) = pattern.strip_prefix('*') {
-        return value.ends_with(suffix);
-    }
-    pattern == value
-}
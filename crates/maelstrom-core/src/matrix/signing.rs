@@ -252,6 +252,41 @@ pub fn verify_event_signature(
     verify_event_signature_canonical(&canonical, public_key_bytes, server_name, key_id)
 }
 
+/// Add this server's signature to an arbitrary JSON object.
+///
+/// This is the spec's generic "Signing JSON" algorithm, as opposed to
+/// [`sign_event`]: no content hash is computed, and signatures already present
+/// (from other servers or other keys) are preserved. Used for signing key
+/// responses, including co-signing another server's keys as a notary. Panics if
+/// the value contains floats.
+pub fn sign_json(value: &serde_json::Value, key: &KeyPair, server_name: &str) -> serde_json::Value {
+    let canonical = CanonicalJson::from_value(value)
+        .expect("Value contains float — not valid for canonical JSON");
+    let to_sign = strip_fields(&canonical, &["signatures", "unsigned"]);
+    let signature = key.sign(to_sign.encode().as_bytes());
+
+    let mut signed = value.clone();
+    if let Some(obj) = signed.as_object_mut() {
+        let signatures = obj
+            .entry("signatures")
+            .or_insert_with(|| serde_json::json!({}));
+        if !signatures.is_object() {
+            *signatures = serde_json::json!({});
+        }
+        let server_sigs = signatures
+            .as_object_mut()
+            .expect("signatures is an object")
+            .entry(server_name)
+            .or_insert_with(|| serde_json::json!({}));
+        if let Some(server_sigs) = server_sigs.as_object_mut() {
+            server_sigs.insert(key.key_id().to_owned(), serde_json::json!(signature));
+        } else {
+            *server_sigs = serde_json::json!({ key.key_id(): signature });
+        }
+    }
+    signed
+}
+
 /// Convenience wrapper: produce the canonical JSON string of a `serde_json::Value`.
 ///
 /// Prefer [`CanonicalJson::from_value()`] + [`encode()`](CanonicalJson::encode)
@@ -409,4 +444,32 @@ mod tests {
         });
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_sign_json_preserves_existing_signatures() {
+        let origin = KeyPair::generate();
+        let notary = KeyPair::generate();
+        let keys = serde_json::json!({
+            "server_name": "origin.example",
+            "verify_keys": {origin.key_id(): {"key": origin.public_key_base64()}},
+            "valid_until_ts": 1_700_000_000_000u64,
+        });
+
+        let self_signed = sign_json(&keys, &origin, "origin.example");
+        assert!(self_signed.get("hashes").is_none());
+
+        let co_signed = sign_json(&self_signed, &notary, "notary.example");
+        assert!(verify_event_signature(
+            &co_signed,
+            &origin.public_key_bytes(),
+            "origin.example",
+            origin.key_id(),
+        ));
+        assert!(verify_event_signature(
+            &co_signed,
+            &notary.public_key_bytes(),
+            "notary.example",
+            notary.key_id(),
+        ));
+    }
 }
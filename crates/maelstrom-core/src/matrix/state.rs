@@ -296,8 +296,8 @@ fn is_auth_allowed(event: &Pdu, current_state: &HashMap<StateKey, Pdu>) -> bool
 
 #[cfg(test)]
 mod tests {
-    use super::room::event_type as et;
     use super::*;
+    use crate::matrix::room::event_type as et;
 
     fn make_event(event_id: &str, event_type: &str, state_key: &str, sender: &str, ts: u64) -> Pdu {
         Pdu {
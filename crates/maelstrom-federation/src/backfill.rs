@@ -26,8 +26,13 @@
 //! `earliest_events` and returns the events in between. The current implementation
 //! is simplified -- it returns recent events up to the limit rather than performing
 //! a full DAG walk.
+//!
+//! Both endpoints send events the requesting server may not see under the
+//! room's history visibility with their content stripped, see
+//! [`visibility::filter_events_for_server`](crate::visibility::filter_events_for_server).
 
-use axum::extract::{Path, Query, State};
+use axum::extract::{OriginalUri, Path, Query, State};
+use axum::http::HeaderMap;
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde::Deserialize;
@@ -64,6 +69,8 @@ fn default_limit() -> usize {
 /// GET /_matrix/federation/v1/backfill/{roomId} — return historical events.
 async fn backfill(
     State(state): State<FederationState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
     Path(room_id): Path<String>,
     Query(query): Query<BackfillQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
@@ -80,12 +87,12 @@ async fn backfill(
             .map_err(|_| MatrixError::not_found("Start event not found"))?;
         event.stream_position
     } else {
-        // Start from the latest
+        // Start from (and including) the latest
         state
             .storage()
             .current_stream_position()
             .await
-            .unwrap_or(i64::MAX)
+            .map_or(i64::MAX, |pos| pos + 1)
     };
 
     let events = state
@@ -93,6 +100,10 @@ async fn backfill(
         .get_room_events(&room_id, from_pos, limit, "b")
         .await
         .map_err(|_| MatrixError::not_found("Room not found"))?;
+    let origin = crate::signing::request_origin(&state, &headers, "GET", &uri, None).await;
+    let events =
+        crate::visibility::filter_events_for_server(state.storage(), origin.as_deref(), events)
+            .await;
 
     let pdus: Vec<serde_json::Value> = events.iter().map(|e| e.to_federation_json()).collect();
 
@@ -127,9 +138,13 @@ struct GetMissingEventsRequest {
 /// boundary events themselves.
 async fn get_missing_events(
     State(state): State<FederationState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
     Path(room_id): Path<String>,
-    Json(body): Json<GetMissingEventsRequest>,
+    Json(content): Json<serde_json::Value>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
+    let body: GetMissingEventsRequest = serde_json::from_value(content.clone())
+        .map_err(|e| MatrixError::bad_json(e.to_string()))?;
     debug!(
         room_id = %room_id,
         earliest = ?body.earliest_events,
@@ -179,12 +194,16 @@ async fn get_missing_events(
     let earliest_set: std::collections::HashSet<&str> =
         body.earliest_events.iter().map(|s| s.as_str()).collect();
 
-    let pdus: Vec<serde_json::Value> = candidates
-        .iter()
+    let missing: Vec<_> = candidates
+        .into_iter()
         .filter(|e| e.stream_position <= max_pos && !earliest_set.contains(e.event_id.as_str()))
         .take(limit)
-        .map(|e| e.to_federation_json())
         .collect();
+    let origin =
+        crate::signing::request_origin(&state, &headers, "POST", &uri, Some(&content)).await;
+    let missing =
+        crate::visibility::filter_events_for_server(storage, origin.as_deref(), missing).await;
+    let pdus: Vec<serde_json::Value> = missing.iter().map(|e| e.to_federation_json()).collect();
 
     Ok(Json(serde_json::json!({ "events": pdus })))
 }
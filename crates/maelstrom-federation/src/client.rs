@@ -28,17 +28,23 @@
 //!
 //! ## Error Handling
 //!
-//! Federation requests can fail in three ways, represented by [`FederationError`]:
+//! Federation requests can fail in four ways, represented by [`FederationError`]:
 //!
 //! - **`Request`** -- network-level failure (DNS, TLS, timeout)
 //! - **`Remote`** -- the remote server returned an HTTP error (4xx, 5xx)
 //! - **`InvalidResponse`** -- the response body was not valid JSON
+//! - **`Blocked`** -- the destination is blocked by the server-wide
+//!   [`FederationPolicy`]; no request is sent
+
+use std::sync::Arc;
 
 use dashmap::DashMap;
 use maelstrom_core::matrix::id::ServerName;
-use maelstrom_core::matrix::keys::KeyPair;
+use maelstrom_core::matrix::keys::SharedKeyPair;
 use tracing::debug;
 
+use crate::policy::{FederationPolicy, PolicyCheck};
+
 /// Outbound federation HTTP client with server discovery and request signing.
 ///
 /// Wraps a [`reqwest::Client`] with Matrix-specific functionality: automatic server
@@ -52,14 +58,19 @@ use tracing::debug;
 /// (development mode only).
 pub struct FederationClient {
     http: reqwest::Client,
-    signing_key: KeyPair,
+    signing_key: SharedKeyPair,
     server_name: ServerName,
     /// Cache of server_name -> resolved endpoint URL.
     endpoints: DashMap<String, String>,
+    /// Server-wide allow/deny lists, checked before every request.
+    policy: Arc<FederationPolicy>,
 }
 
 impl FederationClient {
-    pub fn new(signing_key: KeyPair, server_name: ServerName) -> Self {
+    /// Create a federation client that signs with `signing_key`.
+    ///
+    /// Pass a [`SharedKeyPair`] clone to have the client follow key rotations.
+    pub fn new(signing_key: impl Into<SharedKeyPair>, server_name: ServerName) -> Self {
         Self::with_ca(signing_key, server_name, None)
     }
 
@@ -75,7 +86,11 @@ impl FederationClient {
     /// - **`ca_path` absent** -- accepts all certificates (`danger_accept_invalid_certs`).
     ///   This keeps development easy but is **insecure for production**.  A real
     ///   deployment should either provide a CA or use publicly-trusted certificates.
-    pub fn with_ca(signing_key: KeyPair, server_name: ServerName, ca_path: Option<&str>) -> Self {
+    pub fn with_ca(
+        signing_key: impl Into<SharedKeyPair>,
+        server_name: ServerName,
+        ca_path: Option<&str>,
+    ) -> Self {
         let mut builder = reqwest::Client::builder()
             .timeout(std::time::Duration::from_secs(30))
             .redirect(reqwest::redirect::Policy::none())
@@ -105,9 +120,35 @@ impl FederationClient {
 
         Self {
             http,
-            signing_key,
+            signing_key: signing_key.into(),
             server_name,
             endpoints: DashMap::new(),
+            policy: Arc::default(),
+        }
+    }
+
+    /// Refuse requests to servers blocked by `policy`. Without this the client
+    /// talks to every server.
+    pub fn with_policy(mut self, policy: Arc<FederationPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: Arc<FederationPolicy>) {
+        self.policy = policy;
+    }
+
+    /// The federation policy this client enforces.
+    pub fn policy(&self) -> &FederationPolicy {
+        &self.policy
+    }
+
+    /// Fail with [`FederationError::Blocked`] if `destination` may not be contacted.
+    fn check_policy(&self, destination: &str) -> Result<(), FederationError> {
+        if self.policy.check(destination, PolicyCheck::Outbound) {
+            Ok(())
+        } else {
+            Err(FederationError::Blocked(destination.to_string()))
         }
     }
 
@@ -204,11 +245,12 @@ impl FederationClient {
         destination: &str,
         path: &str,
     ) -> Result<serde_json::Value, FederationError> {
+        self.check_policy(destination)?;
         let base_url = self.discover(destination).await;
         let url = format!("{base_url}{path}");
 
         let auth = crate::signing::sign_request(
-            &self.signing_key,
+            &self.signing_key.current(),
             self.server_name.as_str(),
             destination,
             "GET",
@@ -251,11 +293,12 @@ impl FederationClient {
         path: &str,
         body: &serde_json::Value,
     ) -> Result<serde_json::Value, FederationError> {
+        self.check_policy(destination)?;
         let base_url = self.discover(destination).await;
         let url = format!("{base_url}{path}");
 
         let auth = crate::signing::sign_request(
-            &self.signing_key,
+            &self.signing_key.current(),
             self.server_name.as_str(),
             destination,
             "PUT",
@@ -302,11 +345,12 @@ impl FederationClient {
         path: &str,
         body: &serde_json::Value,
     ) -> Result<serde_json::Value, FederationError> {
+        self.check_policy(destination)?;
         let base_url = self.discover(destination).await;
         let url = format!("{base_url}{path}");
 
         let auth = crate::signing::sign_request(
-            &self.signing_key,
+            &self.signing_key.current(),
             self.server_name.as_str(),
             destination,
             "POST",
@@ -358,8 +402,8 @@ impl FederationClient {
 
 /// Errors that can occur during outbound federation requests.
 ///
-/// These cover the three failure modes of talking to a remote server:
-/// network issues, remote HTTP errors, and unparseable responses.
+/// These cover the failure modes of talking to a remote server: network issues,
+/// remote HTTP errors, unparseable responses, and destinations we refuse to contact.
 #[derive(Debug, thiserror::Error)]
 pub enum FederationError {
     /// Network-level failure: DNS resolution, TLS handshake, connection timeout, etc.
@@ -373,4 +417,8 @@ pub enum FederationError {
     /// The response body could not be parsed as valid JSON.
     #[error("Invalid response: {0}")]
     InvalidResponse(String),
+
+    /// The destination is blocked by the server-wide federation policy.
+    #[error("Federation with {0} is blocked by policy")]
+    Blocked(String),
 }
@@ -0,0 +1,346 @@
+//! # Remote Device Lists
+//!
+//! To encrypt for a remote user, local clients need the keys of all that
+//! user's devices. Rather than asking the remote server on every
+//! `/keys/query`, the server caches remote device lists and keeps them up to
+//! date from the `m.device_list_update` EDUs the user's server sends.
+//!
+//! ## The stream
+//!
+//! Each EDU carries the user's `stream_id` for the change and, in `prev_id`,
+//! the stream ID(s) of the update(s) before it. With the cached list at
+//! stream ID `n`, an update is:
+//!
+//! - **applied** when its `prev_id` contains `n` -- the keys of the device are
+//!   stored (or deleted, for `deleted: true`) and the cache moves to the
+//!   update's `stream_id`;
+//! - **ignored** when its `stream_id` is not past `n` (a duplicate);
+//! - otherwise a **gap**: some update was missed, so the cache is invalidated
+//!   and the whole list is fetched again with [`resync_user`]
+//!   (`GET /_matrix/federation/v1/user/devices/{userId}`).
+//!
+//! Updates for users whose list is not cached trigger a resync as well, as
+//! long as the user shares a room with this server (otherwise no further
+//! updates would arrive and the cache would go stale).
+//!
+//! Resyncs go through [`resync`], which runs at most one fetch per user at a
+//! time: callers asking for a user whose list is already being fetched wait
+//! for that fetch, and updates arriving meanwhile make it fetch once more
+//! when done instead of starting another.
+//!
+//! Every applied update or resync is recorded in the local device list change
+//! stream, so `/sync` reports the user in `device_lists.changed`.
+//!
+//! ## Cross-signing keys
+//!
+//! `m.signing_key_update` EDUs carry a user's new master and/or self-signing
+//! key. They are not part of the stream, so they replace the cached keys of
+//! any user sharing a room with this server straight away.
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use serde_json::Value;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_storage::traits::Storage;
+
+use crate::FederationState;
+use crate::client::{FederationClient, FederationError};
+
+/// Resyncs in progress, by user ID.
+static RESYNCS: LazyLock<Mutex<HashMap<String, InFlight>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct InFlight {
+    /// Set when an update arrived during the fetch, which may predate it.
+    again: bool,
+    /// Receives the outcome once the resync is done.
+    done: watch::Receiver<Option<bool>>,
+}
+
+/// Removes the in-flight entry if the resyncing task is dropped midway, so
+/// waiters get an answer and later callers start a new resync.
+struct InFlightGuard<'a>(Option<&'a str>);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(user_id) = self.0 {
+            RESYNCS.lock().unwrap().remove(user_id);
+        }
+    }
+}
+
+/// Handle an inbound `m.device_list_update` EDU from `origin`.
+pub(crate) async fn process_update(state: &FederationState, origin: &str, content: &Value) {
+    let user_id = content
+        .get("user_id")
+        .and_then(|u| u.as_str())
+        .unwrap_or_default();
+    let device_id = content
+        .get("device_id")
+        .and_then(|d| d.as_str())
+        .unwrap_or_default();
+    if server_name_from_sigil_id(user_id) != origin {
+        warn!(origin = %origin, user_id = %user_id, "Ignoring device list update for a user of another server");
+        return;
+    }
+    let stream_id = content
+        .get("stream_id")
+        .and_then(|s| s.as_i64())
+        .unwrap_or(0);
+    let prev_ids: Vec<i64> = content
+        .get("prev_id")
+        .and_then(|p| p.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.as_i64())
+        .collect();
+    let deleted = content
+        .get("deleted")
+        .and_then(|d| d.as_bool())
+        .unwrap_or(false);
+    debug!(user_id = %user_id, device_id = %device_id, stream_id, ?prev_ids, deleted, "Federation device list update EDU");
+
+    let storage = state.storage();
+    let rooms = storage.get_joined_rooms(user_id).await.unwrap_or_default();
+    match storage.get_remote_device_stream_id(user_id).await {
+        Ok(Some(cached)) if stream_id <= cached => {
+            debug!(user_id = %user_id, stream_id, cached, "Ignoring stale device list update");
+            return;
+        }
+        Ok(Some(cached)) if prev_ids.contains(&cached) && !device_id.is_empty() => {
+            let result = if deleted {
+                storage.delete_device_keys(user_id, Some(device_id)).await
+            } else if let Some(keys) = content.get("keys") {
+                storage.set_device_keys(user_id, device_id, keys).await
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                warn!(user_id = %user_id, error = %e, "Failed to apply device list update");
+                let _ = storage.set_remote_device_stream_id(user_id, None).await;
+            } else {
+                let _ = storage
+                    .set_remote_device_stream_id(user_id, Some(stream_id))
+                    .await;
+                let _ = storage
+                    .add_device_list_change(user_id, device_id, deleted)
+                    .await;
+            }
+        }
+        _ if rooms.is_empty() => {
+            debug!(user_id = %user_id, "Not tracking device list of a user sharing no rooms");
+            let _ = storage.set_remote_device_stream_id(user_id, None).await;
+            return;
+        }
+        _ => {
+            // A gap, or nothing cached: drop the cache now so nobody trusts
+            // it, and fetch the full list in the background
+            let _ = storage.set_remote_device_stream_id(user_id, None).await;
+            if rerun_resync(user_id) {
+                return;
+            }
+            let state = state.clone();
+            let user_id = user_id.to_string();
+            tokio::spawn(async move {
+                resync(state.storage(), state.client(), &user_id).await;
+                if let Ok(rooms) = state.storage().get_joined_rooms(&user_id).await {
+                    for room_id in rooms {
+                        state.notify_room(&room_id);
+                    }
+                }
+            });
+            return;
+        }
+    }
+
+    for room_id in rooms {
+        state.notify_room(&room_id);
+    }
+}
+
+/// Handle an inbound `m.signing_key_update` EDU from `origin`.
+pub(crate) async fn process_signing_key_update(
+    state: &FederationState,
+    origin: &str,
+    content: &Value,
+) {
+    let user_id = content
+        .get("user_id")
+        .and_then(|u| u.as_str())
+        .unwrap_or_default();
+    if server_name_from_sigil_id(user_id) != origin {
+        warn!(origin = %origin, user_id = %user_id, "Ignoring signing key update for a user of another server");
+        return;
+    }
+
+    let mut keys = serde_json::Map::new();
+    for key_type in ["master_key", "self_signing_key"] {
+        if let Some(key) = content.get(key_type)
+            && key.get("user_id").and_then(|u| u.as_str()) == Some(user_id)
+        {
+            keys.insert(key_type.to_string(), key.clone());
+        }
+    }
+    if keys.is_empty() {
+        return;
+    }
+
+    let storage = state.storage();
+    let rooms = storage.get_joined_rooms(user_id).await.unwrap_or_default();
+    if rooms.is_empty() {
+        debug!(user_id = %user_id, "Ignoring signing key update of a user sharing no rooms");
+        return;
+    }
+    debug!(user_id = %user_id, "Federation signing key update EDU");
+    if let Err(e) = storage
+        .set_cross_signing_keys(user_id, &Value::Object(keys))
+        .await
+    {
+        warn!(user_id = %user_id, error = %e, "Failed to store cross-signing keys");
+        return;
+    }
+    let _ = storage.add_key_change(user_id).await;
+
+    for room_id in rooms {
+        state.notify_room(&room_id);
+    }
+}
+
+/// Ask a resync of `user_id` that is in progress to fetch once more when
+/// done. Returns false when none is in progress.
+fn rerun_resync(user_id: &str) -> bool {
+    match RESYNCS.lock().unwrap().get_mut(user_id) {
+        Some(in_flight) => {
+            in_flight.again = true;
+            true
+        }
+        None => false,
+    }
+}
+
+/// [`resync_user`], unless a resync of the user is already in progress, in
+/// which case its outcome is awaited instead. Returns whether the cached list
+/// is now fresh.
+pub async fn resync(storage: &dyn Storage, client: &FederationClient, user_id: &str) -> bool {
+    let joined = {
+        let mut resyncs = RESYNCS.lock().unwrap();
+        match resyncs.get(user_id) {
+            Some(in_flight) => Err(in_flight.done.clone()),
+            None => {
+                let (sender, done) = watch::channel(None);
+                resyncs.insert(user_id.to_string(), InFlight { again: false, done });
+                Ok(sender)
+            }
+        }
+    };
+    let sender = match joined {
+        Ok(sender) => sender,
+        Err(mut done) => {
+            return done
+                .wait_for(Option::is_some)
+                .await
+                .is_ok_and(|outcome| *outcome == Some(true));
+        }
+    };
+    let mut guard = InFlightGuard(Some(user_id));
+
+    loop {
+        let fresh = match resync_user(storage, client, user_id).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(user_id = %user_id, error = %e, "Device list resync failed");
+                false
+            }
+        };
+        let rerun = {
+            let mut resyncs = RESYNCS.lock().unwrap();
+            match resyncs.get_mut(user_id) {
+                Some(in_flight) if in_flight.again => {
+                    in_flight.again = false;
+                    true
+                }
+                _ => {
+                    resyncs.remove(user_id);
+                    false
+                }
+            }
+        };
+        if !rerun {
+            guard.0 = None;
+            let _ = sender.send(Some(fresh));
+            return fresh;
+        }
+    }
+}
+
+/// Replace the cached device list of a remote user with a fresh copy from
+/// their server, including their master and self-signing keys.
+pub async fn resync_user(
+    storage: &dyn Storage,
+    client: &FederationClient,
+    user_id: &str,
+) -> Result<(), FederationError> {
+    let server = server_name_from_sigil_id(user_id);
+    let path = format!(
+        "/_matrix/federation/v1/user/devices/{}",
+        urlencoding::encode(user_id)
+    );
+    let response = client.get(server, &path).await?;
+    if response.get("user_id").and_then(|u| u.as_str()) != Some(user_id) {
+        return Err(FederationError::InvalidResponse(
+            "user_id does not match the requested user".to_string(),
+        ));
+    }
+    let stream_id = response
+        .get("stream_id")
+        .and_then(|s| s.as_i64())
+        .unwrap_or(0);
+
+    let to_storage_error = |e: maelstrom_storage::traits::StorageError| {
+        FederationError::InvalidResponse(format!("Failed to store device list: {e}"))
+    };
+    storage
+        .delete_device_keys(user_id, None)
+        .await
+        .map_err(to_storage_error)?;
+    for device in response
+        .get("devices")
+        .and_then(|d| d.as_array())
+        .into_iter()
+        .flatten()
+    {
+        if let Some(device_id) = device.get("device_id").and_then(|d| d.as_str())
+            && let Some(keys) = device.get("keys").filter(|k| k.is_object())
+        {
+            storage
+                .set_device_keys(user_id, device_id, keys)
+                .await
+                .map_err(to_storage_error)?;
+        }
+    }
+
+    let mut cross_signing = serde_json::Map::new();
+    for key_type in ["master_key", "self_signing_key"] {
+        if let Some(key) = response.get(key_type) {
+            cross_signing.insert(key_type.to_string(), key.clone());
+        }
+    }
+    if !cross_signing.is_empty() {
+        storage
+            .set_cross_signing_keys(user_id, &Value::Object(cross_signing))
+            .await
+            .map_err(to_storage_error)?;
+    }
+
+    storage
+        .set_remote_device_stream_id(user_id, Some(stream_id))
+        .await
+        .map_err(to_storage_error)?;
+    let _ = storage.add_device_list_change(user_id, "", false).await;
+    debug!(user_id = %user_id, stream_id, "Resynced remote device list");
+    Ok(())
+}
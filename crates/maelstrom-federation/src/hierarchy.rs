@@ -0,0 +1,312 @@
+//! # Space Hierarchy
+//!
+//! Spaces can contain rooms that live on other servers. When a client asks its own
+//! homeserver for the hierarchy of a space, that server only knows the rooms it
+//! participates in -- for everything else it asks one of the `via` servers listed in
+//! the parent's `m.space.child` event.
+//!
+//! `GET /_matrix/federation/v1/hierarchy/{roomId}` answers that question for rooms on
+//! this server. The response contains:
+//!
+//! - `room` -- the summary of the requested room, including the stripped
+//!   `m.space.child` events (`children_state`) so the caller can continue walking.
+//! - `children` -- summaries of the direct children this server knows about and
+//!   which the requesting server is allowed to see.
+//! - `inaccessible_children` -- room IDs of children this server knows about but
+//!   which are not peekable (invite-only, private, ...). The caller should not try
+//!   to fetch them from another server either.
+//!
+//! A room is considered accessible over federation when its join rule lets outsiders
+//! in (`public`, `knock`, `restricted`, `knock_restricted`) or when its history is
+//! `world_readable`.
+//!
+//! The summary builder [`room_summary`] is shared with the Client-Server
+//! `/hierarchy` handler so that local and remote entries have the same shape.
+
+use axum::extract::{Path, Query, State};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Deserialize;
+use tracing::debug;
+
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::room::{HistoryVisibility, JoinRule, Membership, event_type as et};
+use maelstrom_storage::traits::Storage;
+
+use crate::FederationState;
+
+/// Build the hierarchy sub-router.
+pub fn routes() -> Router<FederationState> {
+    Router::new().route(
+        "/_matrix/federation/v1/hierarchy/{roomId}",
+        get(get_hierarchy),
+    )
+}
+
+/// Query parameters for the federation hierarchy endpoint.
+#[derive(Deserialize)]
+struct HierarchyQuery {
+    /// Only include children whose `m.space.child` event has `suggested: true`.
+    #[serde(default)]
+    suggested_only: bool,
+}
+
+/// GET /_matrix/federation/v1/hierarchy/{roomId} — summarise a space and its direct children.
+async fn get_hierarchy(
+    State(state): State<FederationState>,
+    Path(room_id): Path<String>,
+    Query(query): Query<HierarchyQuery>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    debug!(room_id = %room_id, suggested_only = query.suggested_only, "Federation hierarchy request");
+
+    let storage = state.storage();
+
+    let mut room = room_summary(storage, &room_id)
+        .await
+        .filter(is_accessible)
+        .ok_or_else(|| MatrixError::not_found("Room not found or not accessible"))?;
+
+    if query.suggested_only {
+        let suggested: Vec<serde_json::Value> = child_entries(&room)
+            .into_iter()
+            .filter(|child| child.suggested)
+            .map(|child| child.event)
+            .collect();
+        room["children_state"] = serde_json::json!(suggested);
+    }
+
+    let mut children = Vec::new();
+    let mut inaccessible_children = Vec::new();
+
+    for child in child_entries(&room) {
+        let Some(mut summary) = room_summary(storage, &child.room_id).await else {
+            // Unknown here -- the caller may have better luck with another via server.
+            continue;
+        };
+
+        if is_accessible(&summary) {
+            if let Some(obj) = summary.as_object_mut() {
+                obj.remove("children_state");
+            }
+            children.push(summary);
+        } else {
+            inaccessible_children.push(child.room_id);
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "room": room,
+        "children": children,
+        "inaccessible_children": inaccessible_children,
+    })))
+}
+
+// ---------------------------------------------------------------------------
+// Shared helpers
+// ---------------------------------------------------------------------------
+
+/// A single `m.space.child` entry extracted from a room summary's `children_state`.
+#[derive(Debug, Clone)]
+pub struct ChildEntry {
+    /// The child room ID (the state key of the `m.space.child` event).
+    pub room_id: String,
+    /// Servers that can be asked about the child.
+    pub via: Vec<String>,
+    /// Whether the space marks this child as suggested.
+    pub suggested: bool,
+    /// The stripped `m.space.child` event itself.
+    pub event: serde_json::Value,
+}
+
+/// Build the hierarchy summary of a locally known room.
+///
+/// Returns `None` when this server has no state for the room. The summary has the
+/// `PublicRoomsChunk` fields plus `room_type`, `allowed_room_ids` (for restricted
+/// rooms) and `children_state`, the stripped `m.space.child` events that have a
+/// non-empty `via` list.
+pub async fn room_summary(storage: &dyn Storage, room_id: &str) -> Option<serde_json::Value> {
+    storage.get_room(room_id).await.ok()?;
+
+    let current_state = storage.get_current_state(room_id).await.ok()?;
+    if current_state.is_empty() {
+        return None;
+    }
+
+    let content_str = |event_type: &str, field: &str| -> Option<String> {
+        current_state
+            .iter()
+            .find(|e| e.event_type == event_type && e.state_key.as_deref() == Some(""))
+            .and_then(|e| e.content.get(field).and_then(|v| v.as_str()))
+            .map(|s| s.to_string())
+    };
+
+    let join_rule = content_str(et::JOIN_RULES, "join_rule")
+        .unwrap_or_else(|| JoinRule::Invite.as_str().to_string());
+    let history_visibility = content_str(et::HISTORY_VISIBILITY, "history_visibility")
+        .and_then(|h| HistoryVisibility::parse(&h))
+        .unwrap_or_default();
+    let guest_can_join =
+        content_str(et::GUEST_ACCESS, "guest_access").as_deref() == Some("can_join");
+
+    let num_joined = storage
+        .get_room_members(room_id, Membership::Join.as_str())
+        .await
+        .map(|m| m.len())
+        .unwrap_or(0);
+
+    let mut children_state = Vec::new();
+    for event in &current_state {
+        if event.event_type != et::SPACE_CHILD {
+            continue;
+        }
+        let Some(state_key) = &event.state_key else {
+            continue;
+        };
+        // A child without `via` servers has been removed from the space.
+        let has_via = event
+            .content
+            .get("via")
+            .and_then(|v| v.as_array())
+            .is_some_and(|a| !a.is_empty());
+        if has_via {
+            children_state.push(serde_json::json!({
+                "type": et::SPACE_CHILD,
+                "state_key": state_key,
+                "sender": event.sender,
+                "content": event.content,
+                "origin_server_ts": event.origin_server_ts,
+            }));
+        }
+    }
+
+    let mut summary = serde_json::json!({
+        "room_id": room_id,
+        "num_joined_members": num_joined,
+        "world_readable": history_visibility == HistoryVisibility::WorldReadable,
+        "guest_can_join": guest_can_join,
+        "join_rule": join_rule,
+        "children_state": children_state,
+    });
+
+    if let Some(n) = content_str(et::NAME, "name") {
+        summary["name"] = serde_json::json!(n);
+    }
+    if let Some(t) = content_str(et::TOPIC, "topic") {
+        summary["topic"] = serde_json::json!(t);
+    }
+    if let Some(a) = content_str(et::CANONICAL_ALIAS, "alias") {
+        summary["canonical_alias"] = serde_json::json!(a);
+    }
+    if let Some(u) = content_str(et::AVATAR, "url") {
+        summary["avatar_url"] = serde_json::json!(u);
+    }
+    if let Some(rt) = content_str(et::CREATE, "type") {
+        summary["room_type"] = serde_json::json!(rt);
+    }
+
+    if matches!(
+        JoinRule::parse(&join_rule),
+        Some(JoinRule::Restricted | JoinRule::KnockRestricted)
+    ) {
+        let allowed: Vec<String> = current_state
+            .iter()
+            .find(|e| e.event_type == et::JOIN_RULES)
+            .and_then(|e| e.content.get("allow").and_then(|a| a.as_array()).cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter(|a| a.get("type").and_then(|t| t.as_str()) == Some("m.room_membership"))
+            .filter_map(|a| a.get("room_id").and_then(|r| r.as_str()).map(String::from))
+            .collect();
+        summary["allowed_room_ids"] = serde_json::json!(allowed);
+    }
+
+    Some(summary)
+}
+
+/// Whether a room summary may be shown to users who are not members of the room.
+pub fn is_accessible(summary: &serde_json::Value) -> bool {
+    let world_readable = summary
+        .get("world_readable")
+        .and_then(|w| w.as_bool())
+        .unwrap_or(false);
+    let join_rule = summary
+        .get("join_rule")
+        .and_then(|j| j.as_str())
+        .and_then(JoinRule::parse);
+
+    world_readable
+        || matches!(
+            join_rule,
+            Some(
+                JoinRule::Public
+                    | JoinRule::Knock
+                    | JoinRule::Restricted
+                    | JoinRule::KnockRestricted
+            )
+        )
+}
+
+/// Extract the children of a room summary, in the order the spec prescribes.
+///
+/// Children are sorted by their `order` field (lexicographically, entries without a
+/// valid `order` last), then by the `origin_server_ts` of the `m.space.child` event,
+/// then by room ID. This makes the traversal -- and therefore pagination -- stable.
+pub fn child_entries(summary: &serde_json::Value) -> Vec<ChildEntry> {
+    let Some(events) = summary.get("children_state").and_then(|c| c.as_array()) else {
+        return Vec::new();
+    };
+
+    let mut entries: Vec<(Option<String>, u64, ChildEntry)> = events
+        .iter()
+        .filter_map(|event| {
+            let room_id = event.get("state_key")?.as_str()?.to_string();
+            let content = event.get("content")?;
+            let via: Vec<String> = content
+                .get("via")?
+                .as_array()?
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect();
+            if via.is_empty() {
+                return None;
+            }
+            let order = content
+                .get("order")
+                .and_then(|o| o.as_str())
+                .filter(|o| o.len() <= 50 && o.chars().all(|c| ('\x20'..='\x7e').contains(&c)))
+                .map(String::from);
+            let ts = event
+                .get("origin_server_ts")
+                .and_then(|t| t.as_u64())
+                .unwrap_or(0);
+            let suggested = content
+                .get("suggested")
+                .and_then(|s| s.as_bool())
+                .unwrap_or(false);
+            Some((
+                order,
+                ts,
+                ChildEntry {
+                    room_id,
+                    via,
+                    suggested,
+                    event: event.clone(),
+                },
+            ))
+        })
+        .collect();
+
+    entries.sort_by(|(a_order, a_ts, a), (b_order, b_ts, b)| {
+        let by_order = match (a_order, b_order) {
+            (Some(x), Some(y)) => x.cmp(y),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        };
+        by_order
+            .then(a_ts.cmp(b_ts))
+            .then_with(|| a.room_id.cmp(&b.room_id))
+    });
+
+    entries.into_iter().map(|(_, _, entry)| entry).collect()
+}
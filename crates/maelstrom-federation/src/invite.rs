@@ -158,6 +158,7 @@ async fn store_invite_event(
             .and_then(|c| c.get("is_direct"))
             .and_then(|v| v.as_bool())
             .unwrap_or(false),
+        peek_only: false,
     };
     let _ = state.storage().create_room(&room_record).await;
 
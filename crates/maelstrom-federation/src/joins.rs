@@ -103,12 +103,14 @@ async fn make_join(
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     debug!(room_id = %params.room_id, user_id = %params.user_id, "make_join request");
 
-    // Verify room exists
+    // Verify room exists, and is not one we only peek into
     let room = state
         .storage()
         .get_room(&params.room_id)
         .await
-        .map_err(|_| MatrixError::not_found("Room not found on this server"))?;
+        .ok()
+        .filter(|room| !room.peek_only)
+        .ok_or_else(|| MatrixError::not_found("Room not found on this server"))?;
 
     // Check server ACL for the joining user's server
     let joining_server = maelstrom_core::matrix::id::server_name_from_sigil_id(&params.user_id);
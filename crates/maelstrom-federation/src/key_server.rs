@@ -16,9 +16,35 @@
 //! 2. The response is **self-signed** -- the server signs the entire key response JSON
 //!    with its own key, so recipients can verify authenticity.
 //!
+//!    Keys retired by a rotation stay in [`FederationKeyStore`] with an `expired_at`
+//!    timestamp and are published under `old_verify_keys`, so events signed before
+//!    the rotation can still be verified. Old keys only verify event signatures
+//!    made before their `expired_ts`, never a request's `X-Matrix` signature.
+//!
 //! 3. Servers can also act as **notaries** -- proxying key lookups for other servers.
-//!    `GET /_matrix/key/v2/query/{serverName}` fetches and returns another server's keys,
-//!    and `POST /_matrix/key/v2/query` supports batch queries for multiple servers at once.
+//!    `GET /_matrix/key/v2/query/{serverName}` returns another server's keys, and
+//!    `POST /_matrix/key/v2/query` supports batch queries for multiple servers at once.
+//!
+//! ## Notary Behaviour
+//!
+//! Remote key responses are cached in [`FederationKeyStore`] -- both the individual
+//! keys (for signature verification) and the full signed response (so it can be
+//! handed out unmodified). A lookup via [`lookup_server_keys`]:
+//!
+//! 1. Serves the cached response if its `valid_until_ts` is still in the future and
+//!    satisfies the caller's `minimum_valid_until_ts`.
+//! 2. Otherwise fetches `/_matrix/key/v2/server` from the origin and checks that it
+//!    is correctly self-signed.
+//! 3. If the origin is unreachable or returns garbage, asks each configured
+//!    [`TrustedKeyServer`] (the "perspectives" mechanism) and accepts a response only
+//!    if it is self-signed by the origin *and* signed by the trusted notary.
+//! 4. Falls back to a stale cached response rather than returning nothing.
+//!
+//! Servers blocked by the federation [`policy`](crate::policy) get no lookup at
+//! all -- neither from cache nor from the network.
+//!
+//! Every response we hand out as a notary is co-signed with our own key. Remote
+//! validity is capped at seven days, as the spec recommends.
 //!
 //! ## Endpoints
 //!
@@ -27,15 +53,63 @@
 //! - `GET /_matrix/key/v2/query/{serverName}` -- notary: fetch another server's keys
 //! - `POST /_matrix/key/v2/query` -- notary: batch query multiple servers
 //! - `GET /_matrix/federation/v1/version` -- server name and version info
+//!
+//! [`FederationKeyStore`]: maelstrom_storage::traits::FederationKeyStore
+
+use std::collections::HashMap;
 
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use maelstrom_core::matrix::error::MatrixError;
-use maelstrom_core::matrix::signing::sign_event;
-use tracing::debug;
+use maelstrom_core::matrix::signing::{sign_json, verify_event_signature};
+use maelstrom_storage::traits::{RemoteKeyRecord, ServerKeyResponseRecord, Storage};
+use serde::Deserialize;
+use tracing::{debug, warn};
 
 use crate::FederationState;
+use crate::client::FederationClient;
+use crate::policy::PolicyCheck;
+
+/// How long our own published keys are declared valid for.
+const OWN_KEY_VALIDITY_DAYS: i64 = 7;
+
+/// Upper bound on how long a remote key response is trusted, whatever it claims.
+const MAX_REMOTE_VALIDITY_DAYS: i64 = 7;
+
+/// A notary server trusted to vouch for other servers' keys.
+///
+/// Configured under `[[federation.trusted_key_servers]]`. `verify_keys` is
+/// required: a notary response is only accepted when it carries a signature
+/// from one of them, since the origin's self-signature alone proves nothing
+/// about a key the origin could not be asked for.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TrustedKeyServer {
+    pub server_name: String,
+    /// Key ID -> unpadded base64 Ed25519 public key the notary signs with.
+    pub verify_keys: HashMap<String, String>,
+}
+
+impl TrustedKeyServer {
+    /// Check the notary's configured keys are usable, naming the problem if not.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.verify_keys.is_empty() {
+            return Err(format!(
+                "trusted key server {} has no verify_keys",
+                self.server_name
+            ));
+        }
+        for (kid, key) in &self.verify_keys {
+            if decode_ed25519_key(key).is_none() {
+                return Err(format!(
+                    "trusted key server {} has an invalid Ed25519 key {kid}",
+                    self.server_name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
 
 /// Build the key server sub-router with all key distribution endpoints.
 pub fn routes() -> Router<FederationState> {
@@ -61,83 +135,101 @@ async fn get_version() -> Json<serde_json::Value> {
 async fn get_server_keys(
     State(state): State<FederationState>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
+    Ok(Json(own_key_response(&state).await))
+}
+
+/// Build this server's self-signed key response.
+///
+/// `verify_keys` holds the key this node signs with plus any other active key in
+/// storage, so that after a rotation every node of a cluster advertises the new
+/// key even before it has switched to it. Keys retired by a rotation stay there
+/// until they expire at the end of its grace period, and the response is only
+/// valid until then, so other servers come back for the change. Expired keys
+/// are listed under `old_verify_keys` with the time they expired.
+async fn own_key_response(state: &FederationState) -> serde_json::Value {
     let key = state.signing_key();
     let server_name = state.server_name().as_str();
+    let storage = state.storage();
 
-    // Valid for 7 days from now
-    let valid_until = chrono::Utc::now() + chrono::Duration::days(7);
-    let valid_until_ts = valid_until.timestamp_millis();
+    let now = chrono::Utc::now();
+    let mut valid_until = now + chrono::Duration::days(OWN_KEY_VALIDITY_DAYS);
+
+    let mut verify_keys = serde_json::Map::new();
+    verify_keys.insert(
+        key.key_id().to_string(),
+        serde_json::json!({ "key": key.public_key_base64() }),
+    );
+    for record in storage.get_active_server_keys().await.unwrap_or_default() {
+        verify_keys
+            .entry(record.key_id)
+            .or_insert_with(|| serde_json::json!({ "key": record.public_key }));
+    }
+
+    let mut old_verify_keys = serde_json::Map::new();
+    for record in storage.get_expired_server_keys().await.unwrap_or_default() {
+        let expired_ts = record.expired_at.unwrap_or(record.valid_until);
+        if expired_ts > now {
+            valid_until = valid_until.min(expired_ts);
+            verify_keys
+                .entry(record.key_id)
+                .or_insert_with(|| serde_json::json!({ "key": record.public_key }));
+            continue;
+        }
+        if verify_keys.contains_key(&record.key_id) {
+            continue;
+        }
+        old_verify_keys.insert(
+            record.key_id,
+            serde_json::json!({
+                "key": record.public_key,
+                "expired_ts": expired_ts.timestamp_millis(),
+            }),
+        );
+    }
 
     let response = serde_json::json!({
         "server_name": server_name,
-        "verify_keys": {
-            key.key_id(): {
-                "key": key.public_key_base64(),
-            }
-        },
-        "old_verify_keys": {},
-        "valid_until_ts": valid_until_ts,
+        "verify_keys": verify_keys,
+        "old_verify_keys": old_verify_keys,
+        "valid_until_ts": valid_until.timestamp_millis(),
     });
 
-    // Self-sign the key response
-    let signed = sign_event(&response, key, server_name);
+    sign_json(&response, &key, server_name)
+}
 
-    Ok(Json(signed))
+/// Query parameters for the single-server notary endpoint.
+#[derive(Deserialize)]
+struct NotaryQuery {
+    /// The returned keys must be valid until at least this timestamp (ms).
+    #[serde(default)]
+    minimum_valid_until_ts: i64,
 }
 
-/// GET /_matrix/key/v2/query/{serverName} — notary: fetch and return another server's keys.
+/// GET /_matrix/key/v2/query/{serverName} — notary: return another server's keys.
 async fn query_server_keys(
     State(state): State<FederationState>,
     Path(target_server): Path<String>,
+    Query(query): Query<NotaryQuery>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
-    debug!(server = %target_server, "Notary key query");
-
-    // If asking about ourselves, return our own keys
-    if target_server == state.server_name().as_str() {
-        return get_server_keys(State(state)).await;
-    }
-
-    // Fetch from the target server
-    let keys = state
-        .client()
-        .fetch_server_keys(&target_server)
-        .await
-        .map_err(|e| {
-            tracing::warn!(error = %e, "Failed to fetch keys for notary query");
-            MatrixError::not_found("Could not retrieve keys for server")
-        })?;
-
-    // Cache the remote keys
-    if let Some(verify_keys) = keys.get("verify_keys").and_then(|v| v.as_object()) {
-        let mut records = Vec::new();
-        let valid_until = keys
-            .get("valid_until_ts")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(0);
-        let valid_until_dt =
-            chrono::DateTime::from_timestamp_millis(valid_until).unwrap_or_else(chrono::Utc::now);
-
-        for (key_id, key_data) in verify_keys {
-            if let Some(pub_key) = key_data.get("key").and_then(|k| k.as_str()) {
-                records.push(maelstrom_storage::traits::RemoteKeyRecord {
-                    server_name: target_server.clone(),
-                    key_id: key_id.clone(),
-                    public_key: pub_key.to_string(),
-                    valid_until: valid_until_dt,
-                });
-            }
-        }
+    debug!(server = %target_server, min_valid = query.minimum_valid_until_ts, "Notary key query");
 
-        let _ = state.storage().store_remote_server_keys(&records).await;
-    }
+    let server_keys: Vec<serde_json::Value> =
+        lookup_server_keys(&state, &target_server, query.minimum_valid_until_ts)
+            .await
+            .map(|keys| co_sign(&state, &keys))
+            .into_iter()
+            .collect();
 
-    // Wrap in the notary response format
     Ok(Json(serde_json::json!({
-        "server_keys": [keys],
+        "server_keys": server_keys,
     })))
 }
 
 /// POST /_matrix/key/v2/query — batch notary query.
+///
+/// The body maps server names to key ID criteria:
+/// `{"server_keys": {"example.org": {"ed25519:abc": {"minimum_valid_until_ts": 123}}}}`.
+/// The strictest `minimum_valid_until_ts` across a server's key IDs is applied.
 async fn query_server_keys_batch(
     State(state): State<FederationState>,
     Json(body): Json<serde_json::Value>,
@@ -149,28 +241,19 @@ async fn query_server_keys_batch(
 
     let mut results = Vec::new();
 
-    for (server_name, _key_queries) in server_keys {
-        if server_name == state.server_name().as_str() {
-            // Return our own keys
-            let key = state.signing_key();
-            let valid_until = chrono::Utc::now() + chrono::Duration::days(7);
-            let response = serde_json::json!({
-                "server_name": server_name,
-                "verify_keys": {
-                    key.key_id(): {
-                        "key": key.public_key_base64(),
-                    }
-                },
-                "old_verify_keys": {},
-                "valid_until_ts": valid_until.timestamp_millis(),
-            });
-            let signed = sign_event(&response, key, server_name);
-            results.push(signed);
-        } else {
-            // Fetch from remote
-            if let Ok(keys) = state.client().fetch_server_keys(server_name).await {
-                results.push(keys);
-            }
+    for (server_name, criteria) in server_keys {
+        let minimum_valid_until_ts = criteria
+            .as_object()
+            .map(|keys| {
+                keys.values()
+                    .filter_map(|c| c.get("minimum_valid_until_ts").and_then(|t| t.as_i64()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .unwrap_or(0);
+
+        if let Some(keys) = lookup_server_keys(&state, server_name, minimum_valid_until_ts).await {
+            results.push(co_sign(&state, &keys));
         }
     }
 
@@ -178,3 +261,330 @@ async fn query_server_keys_batch(
         "server_keys": results,
     })))
 }
+
+/// Add our own signature to a key response we are vouching for.
+fn co_sign(state: &FederationState, keys: &serde_json::Value) -> serde_json::Value {
+    sign_json(keys, &state.signing_key(), state.server_name().as_str())
+}
+
+// ---------------------------------------------------------------------------
+// Key lookup
+// ---------------------------------------------------------------------------
+
+/// Get the signed key response of `server_name`, valid until at least
+/// `minimum_valid_until_ts` if at all possible.
+///
+/// See the module documentation for the lookup order. Returns `None` only if the
+/// keys could not be obtained from anywhere and nothing is cached.
+pub async fn lookup_server_keys(
+    state: &FederationState,
+    server_name: &str,
+    minimum_valid_until_ts: i64,
+) -> Option<serde_json::Value> {
+    if server_name == state.server_name().as_str() {
+        return Some(own_key_response(state).await);
+    }
+
+    if !state.policy().check(server_name, PolicyCheck::KeyFetch) {
+        return None;
+    }
+
+    let cached = state
+        .storage()
+        .get_server_key_response(server_name)
+        .await
+        .ok();
+    let required = minimum_valid_until_ts.max(chrono::Utc::now().timestamp_millis());
+    if let Some(record) = &cached
+        && record.valid_until.timestamp_millis() >= required
+    {
+        return Some(record.response.clone());
+    }
+
+    match state.client().fetch_server_keys(server_name).await {
+        Ok(response) if validate_key_response(server_name, &response) => {
+            cache_key_response(state.storage(), server_name, &response).await;
+            return Some(response);
+        }
+        Ok(_) => {
+            warn!(server_name = %server_name, "Server key response failed validation");
+        }
+        Err(e) => {
+            warn!(server_name = %server_name, error = %e, "Failed to fetch server keys directly");
+        }
+    }
+
+    if let Some(response) = fetch_via_perspectives(state, server_name).await {
+        cache_key_response(state.storage(), server_name, &response).await;
+        return Some(response);
+    }
+
+    cached.map(|record| record.response)
+}
+
+/// Resolve the public key `key_id` of `server_name`, from cache or via
+/// [`lookup_server_keys`].
+///
+/// `signed_at` is when the signature to check was made (an event's
+/// `origin_server_ts`); an old key then counts too if it expired after that.
+/// Request signatures are checked with `None`, as only current keys may
+/// authenticate a request.
+pub async fn resolve_verify_key(
+    state: &FederationState,
+    server_name: &str,
+    key_id: &str,
+    signed_at: Option<i64>,
+) -> Option<[u8; 32]> {
+    if let Some(key) = cached_verify_key(state.storage(), server_name, key_id).await {
+        return Some(key);
+    }
+    let response = lookup_server_keys(state, server_name, 0).await?;
+    key_from_response(&response, key_id, signed_at)
+}
+
+/// Resolve the public key `key_id` of `server_name` with only storage and a
+/// client at hand, as the client-server API has: from cache, or else straight
+/// from the server itself. Notaries are not asked. `signed_at` is as for
+/// [`resolve_verify_key`].
+pub async fn fetch_verify_key(
+    storage: &dyn Storage,
+    client: &FederationClient,
+    server_name: &str,
+    key_id: &str,
+    signed_at: Option<i64>,
+) -> Option<[u8; 32]> {
+    if let Some(key) = cached_verify_key(storage, server_name, key_id).await {
+        return Some(key);
+    }
+    if let Ok(record) = storage.get_server_key_response(server_name).await
+        && record.valid_until > chrono::Utc::now()
+        && let Some(key) = key_from_response(&record.response, key_id, signed_at)
+    {
+        return Some(key);
+    }
+    if !client.policy().check(server_name, PolicyCheck::KeyFetch) {
+        return None;
+    }
+    match client.fetch_server_keys(server_name).await {
+        Ok(response) if validate_key_response(server_name, &response) => {
+            cache_key_response(storage, server_name, &response).await;
+            key_from_response(&response, key_id, signed_at)
+        }
+        Ok(_) => {
+            warn!(server_name = %server_name, "Server key response failed validation");
+            None
+        }
+        Err(e) => {
+            warn!(server_name = %server_name, error = %e, "Failed to fetch server keys directly");
+            None
+        }
+    }
+}
+
+/// The cached, still valid public key `key_id` of `server_name`.
+async fn cached_verify_key(
+    storage: &dyn Storage,
+    server_name: &str,
+    key_id: &str,
+) -> Option<[u8; 32]> {
+    let cached_keys = storage.get_remote_server_keys(server_name).await.ok()?;
+    cached_keys
+        .iter()
+        .filter(|record| record.key_id == key_id && record.valid_until > chrono::Utc::now())
+        .find_map(|record| decode_ed25519_key(&record.public_key))
+}
+
+/// The public key `key_id` from a key response: a current key, or, for a
+/// signature made at `signed_at`, an old key that expired after it.
+fn key_from_response(
+    response: &serde_json::Value,
+    key_id: &str,
+    signed_at: Option<i64>,
+) -> Option<[u8; 32]> {
+    let current = response.get("verify_keys").and_then(|k| k.get(key_id));
+    let old = response
+        .get("old_verify_keys")
+        .and_then(|k| k.get(key_id))
+        .filter(|key_data| {
+            let expired_ts = key_data.get("expired_ts").and_then(|e| e.as_i64());
+            signed_at
+                .zip(expired_ts)
+                .is_some_and(|(at, expired)| at < expired)
+        });
+    current
+        .or(old)
+        .and_then(|key_data| key_data.get("key")?.as_str())
+        .and_then(decode_ed25519_key)
+}
+
+/// Ask the configured trusted key servers for `server_name`'s keys.
+///
+/// Picks the response with the latest `valid_until_ts` across all notaries that
+/// answered with a response self-signed by the origin and signed by the notary.
+async fn fetch_via_perspectives(
+    state: &FederationState,
+    server_name: &str,
+) -> Option<serde_json::Value> {
+    let body = serde_json::json!({ "server_keys": { server_name: {} } });
+    let mut best: Option<serde_json::Value> = None;
+
+    for notary in state.trusted_key_servers() {
+        if notary.server_name == server_name {
+            continue;
+        }
+
+        let response = match state
+            .client()
+            .post_json(&notary.server_name, "/_matrix/key/v2/query", &body)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(notary = %notary.server_name, error = %e, "Trusted key server query failed");
+                continue;
+            }
+        };
+
+        let candidates = response
+            .get("server_keys")
+            .and_then(|k| k.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        for keys in candidates {
+            if !validate_key_response(server_name, &keys) || !notary_signed(notary, &keys) {
+                debug!(notary = %notary.server_name, server_name = %server_name, "Discarding unverifiable notary response");
+                continue;
+            }
+            let is_newer = best
+                .as_ref()
+                .is_none_or(|b| valid_until_ts(&keys) > valid_until_ts(b));
+            if is_newer {
+                best = Some(keys);
+            }
+        }
+    }
+
+    best
+}
+
+/// Check that a key response is for `server_name`, has a validity timestamp, and
+/// is self-signed by one of the keys it lists in `verify_keys`.
+fn validate_key_response(server_name: &str, response: &serde_json::Value) -> bool {
+    if response.get("server_name").and_then(|s| s.as_str()) != Some(server_name) {
+        return false;
+    }
+    if response
+        .get("valid_until_ts")
+        .and_then(|v| v.as_i64())
+        .is_none()
+    {
+        return false;
+    }
+
+    let Some(verify_keys) = response.get("verify_keys").and_then(|v| v.as_object()) else {
+        return false;
+    };
+
+    verify_keys.iter().any(|(kid, key_data)| {
+        key_data
+            .get("key")
+            .and_then(|k| k.as_str())
+            .and_then(decode_ed25519_key)
+            .is_some_and(|public_key| {
+                verify_event_signature(response, &public_key, server_name, kid)
+            })
+    })
+}
+
+/// Check that a notary signed a key response with one of its configured keys.
+///
+/// A notary without configured keys vouches for nothing.
+fn notary_signed(notary: &TrustedKeyServer, response: &serde_json::Value) -> bool {
+    notary.verify_keys.iter().any(|(kid, key_b64)| {
+        decode_ed25519_key(key_b64).is_some_and(|public_key| {
+            verify_event_signature(response, &public_key, &notary.server_name, kid)
+        })
+    })
+}
+
+fn valid_until_ts(response: &serde_json::Value) -> i64 {
+    response
+        .get("valid_until_ts")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0)
+}
+
+/// Store a validated key response and its individual keys.
+///
+/// Current keys are cached until `valid_until_ts` (capped at seven days from
+/// now); old keys until their `expired_ts`.
+async fn cache_key_response(
+    storage: &dyn Storage,
+    server_name: &str,
+    response: &serde_json::Value,
+) {
+    let now = chrono::Utc::now();
+    let cap = now + chrono::Duration::days(MAX_REMOTE_VALIDITY_DAYS);
+    let valid_until = chrono::DateTime::from_timestamp_millis(valid_until_ts(response))
+        .unwrap_or(now)
+        .min(cap);
+
+    let mut records = Vec::new();
+
+    if let Some(verify_keys) = response.get("verify_keys").and_then(|v| v.as_object()) {
+        for (kid, key_data) in verify_keys {
+            if let Some(pub_key) = key_data.get("key").and_then(|k| k.as_str()) {
+                records.push(RemoteKeyRecord {
+                    server_name: server_name.to_string(),
+                    key_id: kid.clone(),
+                    public_key: pub_key.to_string(),
+                    valid_until,
+                });
+            }
+        }
+    }
+
+    if let Some(old_keys) = response.get("old_verify_keys").and_then(|v| v.as_object()) {
+        for (kid, key_data) in old_keys {
+            let expired = key_data
+                .get("expired_ts")
+                .and_then(|v| v.as_i64())
+                .and_then(chrono::DateTime::from_timestamp_millis)
+                .unwrap_or(now);
+            if let Some(pub_key) = key_data.get("key").and_then(|k| k.as_str()) {
+                records.push(RemoteKeyRecord {
+                    server_name: server_name.to_string(),
+                    key_id: kid.clone(),
+                    public_key: pub_key.to_string(),
+                    valid_until: expired,
+                });
+            }
+        }
+    }
+
+    if let Err(e) = storage.store_remote_server_keys(&records).await {
+        warn!(server_name = %server_name, error = %e, "Failed to cache remote server keys");
+    }
+
+    let record = ServerKeyResponseRecord {
+        server_name: server_name.to_string(),
+        response: response.clone(),
+        valid_until,
+        fetched_at: now,
+    };
+    if let Err(e) = storage.store_server_key_response(&record).await {
+        warn!(server_name = %server_name, error = %e, "Failed to cache remote key response");
+    }
+}
+
+/// Decode a base64-encoded Ed25519 public key into a 32-byte array.
+///
+/// Returns `None` if the base64 is invalid or the decoded bytes are not exactly 32 bytes.
+pub(crate) fn decode_ed25519_key(b64: &str) -> Option<[u8; 32]> {
+    use base64::Engine;
+    let bytes = base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode(b64)
+        .ok()?;
+    <[u8; 32]>::try_from(bytes.as_slice()).ok()
+}
@@ -24,14 +24,19 @@
 //! | [`client`]      | Outbound HTTP client with server discovery and signing |
 //! | [`signing`]     | X-Matrix request signing and verification              |
 //! | [`key_server`]  | Publishing and fetching server signing keys            |
+//! | [`policy`]      | Server-wide federation allowlist/denylist              |
 //! | [`sender`]      | Outbound transaction queuing with batching and retry   |
 //! | [`receiver`]    | Inbound transaction processing (PDUs and EDUs)         |
 //! | [`joins`]       | Federation join/leave protocol (make/send handshake)   |
 //! | [`invite`]      | Federation invite flow for remote users                |
 //! | [`backfill`]    | Historical event retrieval and DAG gap filling         |
+//! | [`peek`]        | Snapshots of `world_readable` rooms for peeking servers |
 //! | [`state`]       | Room state and individual event queries                |
 //! | [`queries`]     | Profile and room alias lookups for remote servers      |
+//! | [`hierarchy`]   | Space hierarchy summaries for remote servers           |
 //! | [`user_keys`]   | Cross-server device key queries for E2EE               |
+//! | [`device_lists`] | Remote device list cache fed by device list updates   |
+//! | [`visibility`]  | History visibility filters for users and servers       |
 //! | [`router`]      | Axum router assembling all federation endpoints        |
 //!
 //! ## Shared State
@@ -42,9 +47,13 @@
 
 pub mod backfill;
 pub mod client;
+pub mod device_lists;
+pub mod hierarchy;
 pub mod invite;
 pub mod joins;
 pub mod key_server;
+pub mod peek;
+pub mod policy;
 pub mod queries;
 pub mod receiver;
 pub mod router;
@@ -52,12 +61,13 @@ pub mod sender;
 pub mod signing;
 pub mod state;
 pub mod user_keys;
+pub mod visibility;
 
 use std::sync::Arc;
 
 use maelstrom_core::matrix::ephemeral::EphemeralStore;
 use maelstrom_core::matrix::id::ServerName;
-use maelstrom_core::matrix::keys::KeyPair;
+use maelstrom_core::matrix::keys::{KeyPair, SharedKeyPair};
 use maelstrom_storage::traits::Storage;
 
 /// Shared state for all federation endpoints.
@@ -67,8 +77,8 @@ use maelstrom_storage::traits::Storage;
 ///
 /// - **Storage** -- persistent database access (events, rooms, memberships, keys)
 /// - **EphemeralStore** -- in-memory store for typing, presence, and other transient data
-/// - **KeyPair** -- this server's Ed25519 signing key, used to sign outbound requests
-///   and events
+/// - **SharedKeyPair** -- this server's current Ed25519 signing key, used to sign
+///   outbound requests and events; swapped in place when the key is rotated
 /// - **ServerName** -- this server's canonical name (e.g., `matrix.example.com`)
 /// - **FederationClient** -- HTTP client for making outbound federation requests
 ///
@@ -82,15 +92,23 @@ pub struct FederationState {
 /// Callback for notifying the sync handler about room events from federation.
 pub type RoomNotifyFn = Arc<dyn Fn(&str) + Send + Sync>;
 
+/// Callback for notifying the sync handler about events for a local user
+/// that belong to no room, such as to-device messages.
+pub type UserNotifyFn = Arc<dyn Fn(&str) + Send + Sync>;
+
 /// Inner storage for [`FederationState`], held behind an `Arc` for cheap cloning.
 struct FederationStateInner {
     storage: Box<dyn Storage>,
     ephemeral: Arc<EphemeralStore>,
-    signing_key: KeyPair,
+    signing_key: SharedKeyPair,
     server_name: ServerName,
     federation_client: client::FederationClient,
     /// Optional callback to notify sync when federation events arrive.
     room_notify: Option<RoomNotifyFn>,
+    /// Optional callback to notify sync when a user's to-device messages arrive.
+    user_notify: Option<UserNotifyFn>,
+    /// Notaries consulted when a server's keys cannot be fetched directly.
+    trusted_key_servers: Vec<key_server::TrustedKeyServer>,
 }
 
 impl FederationState {
@@ -100,9 +118,10 @@ impl FederationState {
     pub fn new(
         storage: impl Storage,
         ephemeral: Arc<EphemeralStore>,
-        signing_key: KeyPair,
+        signing_key: impl Into<SharedKeyPair>,
         server_name: ServerName,
     ) -> Self {
+        let signing_key = signing_key.into();
         let fed_client = client::FederationClient::new(signing_key.clone(), server_name.clone());
 
         Self {
@@ -113,6 +132,8 @@ impl FederationState {
                 server_name,
                 federation_client: fed_client,
                 room_notify: None,
+                user_notify: None,
+                trusted_key_servers: Vec::new(),
             }),
         }
     }
@@ -127,8 +148,13 @@ impl FederationState {
         &self.inner.ephemeral
     }
 
-    /// Access this server's Ed25519 signing key pair.
-    pub fn signing_key(&self) -> &KeyPair {
+    /// The Ed25519 key pair this server currently signs with.
+    pub fn signing_key(&self) -> KeyPair {
+        self.inner.signing_key.current()
+    }
+
+    /// The shared signing key handle, for switching keys after a rotation.
+    pub fn shared_signing_key(&self) -> &SharedKeyPair {
         &self.inner.signing_key
     }
 
@@ -149,14 +175,22 @@ impl FederationState {
         }
     }
 
+    /// Notify the sync handler that a local user has new to-device messages.
+    pub fn notify_user(&self, user_id: &str) {
+        if let Some(ref f) = self.inner.user_notify {
+            f(user_id);
+        }
+    }
+
     /// Create a new FederationState with a room notification callback.
     pub fn with_room_notify(
         storage: impl Storage,
         ephemeral: Arc<EphemeralStore>,
-        signing_key: KeyPair,
+        signing_key: impl Into<SharedKeyPair>,
         server_name: ServerName,
         notify: RoomNotifyFn,
     ) -> Self {
+        let signing_key = signing_key.into();
         let fed_client = client::FederationClient::new(signing_key.clone(), server_name.clone());
         Self {
             inner: Arc::new(FederationStateInner {
@@ -166,7 +200,44 @@ impl FederationState {
                 server_name,
                 federation_client: fed_client,
                 room_notify: Some(notify),
+                user_notify: None,
+                trusted_key_servers: Vec::new(),
             }),
         }
     }
+
+    /// Set the callback used to wake a local user's sync when to-device
+    /// messages arrive for them.
+    pub fn with_user_notify(mut self, notify: UserNotifyFn) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("FederationState already shared");
+        inner.user_notify = Some(notify);
+        self
+    }
+
+    /// Enforce the server-wide federation `policy` on inbound requests, key
+    /// fetches and this state's outbound client. Share the same `Arc` with the
+    /// other federation clients and the admin API so changes apply everywhere.
+    pub fn with_federation_policy(mut self, policy: Arc<policy::FederationPolicy>) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("FederationState already shared");
+        inner.federation_client.set_policy(policy);
+        self
+    }
+
+    /// The server-wide federation allow/deny policy.
+    pub fn policy(&self) -> &policy::FederationPolicy {
+        self.inner.federation_client.policy()
+    }
+
+    /// Configure the trusted key servers (perspectives) used when a remote
+    /// server's keys cannot be fetched from the server itself.
+    pub fn with_trusted_key_servers(mut self, servers: Vec<key_server::TrustedKeyServer>) -> Self {
+        let inner = Arc::get_mut(&mut self.inner).expect("FederationState already shared");
+        inner.trusted_key_servers = servers;
+        self
+    }
+
+    /// Notary servers trusted to vouch for other servers' signing keys.
+    pub fn trusted_key_servers(&self) -> &[key_server::TrustedKeyServer] {
+        &self.inner.trusted_key_servers
+    }
 }
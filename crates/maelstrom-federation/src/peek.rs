@@ -0,0 +1,87 @@
+//! # Federated Peeking (MSC2444)
+//!
+//! Clients can preview rooms whose history is `world_readable` without
+//! joining them. When such a room lives on another server and none of our
+//! users are in it, the local server has none of its events, so it asks a
+//! server that is in the room for a snapshot instead:
+//!
+//! `PUT /_matrix/federation/unstable/org.matrix.msc2444/peek/{roomId}/{peekId}`
+//!
+//! The response carries the room's current `state` with its `auth_chain`,
+//! the `latest_event` (to backfill from), the `room_version` and a
+//! `renewal_interval`. Only `world_readable` rooms can be peeked; everything
+//! else is `M_FORBIDDEN`, and unknown rooms are `M_NOT_FOUND`.
+//!
+//! Unlike the MSC, this server does not push new events to peeking servers.
+//! A peeking server takes a fresh snapshot instead once `renewal_interval`
+//! has passed, so the interval bounds how far behind a peek can fall.
+
+use axum::extract::{Path, State};
+use axum::routing::put;
+use axum::{Json, Router};
+use tracing::debug;
+
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::room::{HistoryVisibility, event_type as et};
+
+use crate::FederationState;
+use crate::joins::compute_auth_chain;
+
+/// How often a peeking server should renew its peek, in milliseconds. Short,
+/// since renewing is the only way it learns about new events.
+const RENEWAL_INTERVAL_MS: u64 = 5 * 60 * 1000;
+
+/// Build the peek sub-router.
+pub fn routes() -> Router<FederationState> {
+    Router::new().route(
+        "/_matrix/federation/unstable/org.matrix.msc2444/peek/{roomId}/{peekId}",
+        put(peek_room),
+    )
+}
+
+/// PUT /_matrix/federation/unstable/org.matrix.msc2444/peek/{roomId}/{peekId}
+/// — snapshot a `world_readable` room for a peeking server.
+async fn peek_room(
+    State(state): State<FederationState>,
+    Path((room_id, peek_id)): Path<(String, String)>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    debug!(room_id = %room_id, peek_id = %peek_id, "Federation peek request");
+
+    let storage = state.storage();
+    let room = storage
+        .get_room(&room_id)
+        .await
+        .ok()
+        .filter(|room| !room.peek_only)
+        .ok_or_else(|| MatrixError::not_found("Room not found"))?;
+    let current = storage.current_stream_position().await.unwrap_or(i64::MAX);
+    if crate::visibility::history_visibility_at(storage, &room_id, current).await
+        != HistoryVisibility::WorldReadable
+    {
+        return Err(MatrixError::forbidden("Room is not world_readable"));
+    }
+
+    let state_events = storage
+        .get_current_state(&room_id)
+        .await
+        .map_err(|_| MatrixError::not_found("Room not found"))?;
+    if !state_events.iter().any(|e| e.event_type == et::CREATE) {
+        return Err(MatrixError::not_found("Room not found"));
+    }
+    let latest_event = storage
+        .get_room_events(&room_id, current + 1, 1, "b")
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .next()
+        .ok_or_else(|| MatrixError::not_found("Room not found"))?;
+    let auth_chain = compute_auth_chain(storage, &state_events).await;
+
+    Ok(Json(serde_json::json!({
+        "room_version": room.version,
+        "state": state_events.iter().map(|e| e.to_federation_json()).collect::<Vec<_>>(),
+        "auth_chain": auth_chain,
+        "latest_event": latest_event.to_federation_json(),
+        "renewal_interval": RENEWAL_INTERVAL_MS,
+    })))
+}
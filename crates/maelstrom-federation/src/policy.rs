@@ -0,0 +1,248 @@
+//! # Server-Wide Federation Policy
+//!
+//! Rooms can restrict which servers participate with `m.room.server_acl`, but
+//! operators also need a global switch: "never talk to these servers" or "only
+//! talk to these servers". [`FederationPolicy`] holds a domain **allowlist** and
+//! **denylist** of glob patterns, using the same syntax as server ACLs
+//! ([`server_acl_glob_match`]): `*` matches everything, `*.example.com` matches
+//! subdomains, anything else is an exact match.
+//!
+//! A server is blocked if it matches any deny pattern, or if the allowlist is
+//! non-empty and it matches none of the allow patterns. Ports are ignored when
+//! matching, so `evil.com` also blocks `evil.com:8448`.
+//!
+//! ## Enforcement Points
+//!
+//! | Where                                      | Rejection counter |
+//! |--------------------------------------------|-------------------|
+//! | Inbound federation requests (X-Matrix origin and transaction origin) | `inbound` |
+//! | Outbound [`FederationClient`] requests and the transaction sender | `outbound` |
+//! | Remote media proxying in the Client-Server API | `media` |
+//! | Key fetches in [`lookup_server_keys`]        | `key_fetch` |
+//!
+//! The policy is shared (`Arc`) between the federation state, every federation
+//! client and the admin API, which can replace the lists at runtime. Like the
+//! media retention settings, runtime changes are not persisted: a restart goes
+//! back to the `[federation]` config section.
+//!
+//! [`server_acl_glob_match`]: maelstrom_core::matrix::room::server_acl_glob_match
+//! [`FederationClient`]: crate::client::FederationClient
+//! [`lookup_server_keys`]: crate::key_server::lookup_server_keys
+
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::room::server_acl_glob_match;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::FederationState;
+
+/// The allow and deny patterns of a [`FederationPolicy`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainLists {
+    /// If non-empty, only servers matching one of these patterns are allowed.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Servers matching any of these patterns are always blocked.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Where a blocked server was rejected, for the rejection counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyCheck {
+    /// A request from the server to us.
+    Inbound,
+    /// A request or transaction from us to the server.
+    Outbound,
+    /// Proxying media hosted on the server.
+    Media,
+    /// Fetching the server's signing keys.
+    KeyFetch,
+}
+
+/// Number of requests rejected by the policy since startup, per [`PolicyCheck`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PolicyRejections {
+    pub inbound: u64,
+    pub outbound: u64,
+    pub media: u64,
+    pub key_fetch: u64,
+}
+
+/// Server-wide federation allowlist/denylist with rejection counters.
+///
+/// The default policy allows every server.
+#[derive(Debug, Default)]
+pub struct FederationPolicy {
+    lists: RwLock<DomainLists>,
+    rejected_inbound: AtomicU64,
+    rejected_outbound: AtomicU64,
+    rejected_media: AtomicU64,
+    rejected_key_fetch: AtomicU64,
+}
+
+impl FederationPolicy {
+    pub fn new(lists: DomainLists) -> Self {
+        Self {
+            lists: RwLock::new(lists),
+            ..Self::default()
+        }
+    }
+
+    /// The current allow and deny patterns.
+    pub fn lists(&self) -> DomainLists {
+        self.lists.read().unwrap().clone()
+    }
+
+    /// Replace the allow and deny patterns. Takes effect for the next request.
+    pub fn set_lists(&self, lists: DomainLists) {
+        *self.lists.write().unwrap() = lists;
+    }
+
+    /// Whether federation with `server_name` is permitted. Does not count anything.
+    pub fn is_allowed(&self, server_name: &str) -> bool {
+        let host = strip_port(server_name).to_ascii_lowercase();
+        let lists = self.lists.read().unwrap();
+
+        if lists
+            .deny
+            .iter()
+            .any(|p| server_acl_glob_match(&p.to_ascii_lowercase(), &host))
+        {
+            return false;
+        }
+        lists.allow.is_empty()
+            || lists
+                .allow
+                .iter()
+                .any(|p| server_acl_glob_match(&p.to_ascii_lowercase(), &host))
+    }
+
+    /// Like [`is_allowed`](Self::is_allowed), but counts a rejection under `check`.
+    pub fn check(&self, server_name: &str, check: PolicyCheck) -> bool {
+        if self.is_allowed(server_name) {
+            return true;
+        }
+        let counter = match check {
+            PolicyCheck::Inbound => &self.rejected_inbound,
+            PolicyCheck::Outbound => &self.rejected_outbound,
+            PolicyCheck::Media => &self.rejected_media,
+            PolicyCheck::KeyFetch => &self.rejected_key_fetch,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        debug!(server_name = %server_name, ?check, "Blocked by federation policy");
+        false
+    }
+
+    /// Rejection counts since startup.
+    pub fn rejections(&self) -> PolicyRejections {
+        PolicyRejections {
+            inbound: self.rejected_inbound.load(Ordering::Relaxed),
+            outbound: self.rejected_outbound.load(Ordering::Relaxed),
+            media: self.rejected_media.load(Ordering::Relaxed),
+            key_fetch: self.rejected_key_fetch.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// `example.com:8448` -> `example.com`, `[::1]:8448` -> `[::1]`.
+fn strip_port(server_name: &str) -> &str {
+    match server_name.rsplit_once(':') {
+        Some((host, port))
+            if !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (!host.contains(':') || host.ends_with(']')) =>
+        {
+            host
+        }
+        _ => server_name,
+    }
+}
+
+/// Reject an inbound request from `origin` if the policy blocks it.
+pub(crate) fn check_inbound(state: &FederationState, origin: &str) -> Result<(), MatrixError> {
+    if state.policy().check(origin, PolicyCheck::Inbound) {
+        Ok(())
+    } else {
+        Err(MatrixError::forbidden(format!(
+            "Federation with {origin} is not allowed by this server"
+        )))
+    }
+}
+
+/// Router middleware rejecting federation requests whose X-Matrix origin is blocked.
+///
+/// Requests without an X-Matrix header (e.g. public key queries) pass through;
+/// endpoints that authenticate the origin themselves check it again.
+pub(crate) async fn enforce_inbound(
+    State(state): State<FederationState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let origin = crate::signing::claimed_origin(request.headers());
+
+    if let Some(origin) = origin
+        && let Err(e) = check_inbound(&state, &origin)
+    {
+        return e.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> FederationPolicy {
+        FederationPolicy::new(DomainLists {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn default_allows_everything() {
+        assert!(FederationPolicy::default().is_allowed("anything.example"));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let p = policy(&["*.example.com"], &["bad.example.com"]);
+        assert!(p.is_allowed("good.example.com"));
+        assert!(!p.is_allowed("bad.example.com"));
+        assert!(!p.is_allowed("other.org"));
+    }
+
+    #[test]
+    fn ports_and_case_are_ignored() {
+        let p = policy(&[], &["evil.com", "*.spam.net"]);
+        assert!(!p.is_allowed("evil.com:8448"));
+        assert!(!p.is_allowed("EVIL.com"));
+        assert!(!p.is_allowed("a.spam.net:443"));
+        assert!(p.is_allowed("[::1]:8448"));
+        assert_eq!(strip_port("[::1]:8448"), "[::1]");
+        assert_eq!(strip_port("::1"), "::1");
+    }
+
+    #[test]
+    fn check_counts_rejections() {
+        let p = policy(&[], &["evil.com"]);
+        assert!(p.check("fine.org", PolicyCheck::Outbound));
+        assert!(!p.check("evil.com", PolicyCheck::Outbound));
+        assert!(!p.check("evil.com", PolicyCheck::KeyFetch));
+        assert_eq!(
+            p.rejections(),
+            PolicyRejections {
+                outbound: 1,
+                key_fetch: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
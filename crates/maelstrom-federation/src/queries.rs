@@ -23,6 +23,15 @@
 //! alias points to a remote server, the local server queries the remote server to
 //! resolve the alias to a room ID. The response includes the room ID and a list of
 //! servers that can be used to join the room.
+//!
+//! ## Public Rooms
+//!
+//! `GET`/`POST /_matrix/federation/v1/publicRooms` lists the local room
+//! directory. Rooms that appservices published under a bridged network are
+//! selected with `third_party_instance_id` (`{appserviceId}|{networkId}`) or
+//! included alongside Matrix rooms with `include_all_networks`; the
+//! Client-Server directory resolves these parameters with [`directory_network`]
+//! too.
 
 use axum::extract::{Query, State};
 use axum::routing::get;
@@ -30,7 +39,8 @@ use axum::{Json, Router};
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
+use maelstrom_storage::traits::{DirectoryNetwork, Storage};
 
 use crate::FederationState;
 
@@ -155,11 +165,59 @@ async fn query_directory(
 // Federation Public Rooms (spec: Server-Server API § 11.1)
 // ---------------------------------------------------------------------------
 
+/// Map the `include_all_networks` / `third_party_instance_id` directory
+/// parameters to the part of the directory to list.
+///
+/// The instance (`{appserviceId}|{networkId}`) must name a registered
+/// appservice and one of the protocols in its registration, the only networks
+/// an appservice may publish rooms under.
+pub async fn directory_network<'a>(
+    storage: &dyn Storage,
+    include_all_networks: bool,
+    third_party_instance_id: Option<&'a str>,
+) -> Result<DirectoryNetwork<'a>, MatrixError> {
+    let invalid =
+        |msg: &str| MatrixError::new(http::StatusCode::BAD_REQUEST, ErrorCode::InvalidParam, msg);
+
+    let Some(instance_id) = third_party_instance_id else {
+        return Ok(if include_all_networks {
+            DirectoryNetwork::All
+        } else {
+            DirectoryNetwork::Matrix
+        });
+    };
+    if include_all_networks {
+        return Err(invalid(
+            "Cannot combine include_all_networks with third_party_instance_id",
+        ));
+    }
+
+    let (appservice_id, network_id) = instance_id
+        .split_once('|')
+        .filter(|(a, n)| !a.is_empty() && !n.is_empty())
+        .ok_or_else(|| invalid("Unknown third_party_instance_id"))?;
+    let appservice = storage
+        .get_appservice(appservice_id)
+        .await
+        .map_err(|_| invalid("Unknown third_party_instance_id"))?;
+    if !appservice.protocols.iter().any(|p| p == network_id) {
+        return Err(invalid("Unknown third_party_instance_id"));
+    }
+
+    Ok(DirectoryNetwork::AppService {
+        appservice_id,
+        network_id,
+    })
+}
+
 /// Query parameters for `GET /_matrix/federation/v1/publicRooms`.
 #[derive(Deserialize, Default)]
 struct PublicRoomsQuery {
     limit: Option<usize>,
     since: Option<String>,
+    #[serde(default)]
+    include_all_networks: bool,
+    third_party_instance_id: Option<String>,
 }
 
 /// A single entry in the public room directory response.
@@ -196,10 +254,16 @@ async fn get_public_rooms_fed(
     debug!("Federation publicRooms GET");
 
     let limit = query.limit.unwrap_or(20).min(100);
+    let network = directory_network(
+        state.storage(),
+        query.include_all_networks,
+        query.third_party_instance_id.as_deref(),
+    )
+    .await?;
 
     let (rooms, total) = state
         .storage()
-        .get_public_rooms(limit, query.since.as_deref(), None)
+        .get_public_rooms(limit, query.since.as_deref(), None, network)
         .await
         .map_err(|_| MatrixError::unknown("Failed to fetch public rooms"))?;
 
@@ -241,6 +305,9 @@ struct SearchPublicRoomsRequest {
     limit: Option<usize>,
     since: Option<String>,
     filter: Option<PublicRoomsFilter>,
+    #[serde(default)]
+    include_all_networks: bool,
+    third_party_instance_id: Option<String>,
 }
 
 #[derive(Deserialize, Default)]
@@ -260,10 +327,16 @@ async fn search_public_rooms_fed(
         .filter
         .as_ref()
         .and_then(|f| f.generic_search_term.as_deref());
+    let network = directory_network(
+        state.storage(),
+        body.include_all_networks,
+        body.third_party_instance_id.as_deref(),
+    )
+    .await?;
 
     let (rooms, total) = state
         .storage()
-        .get_public_rooms(limit, body.since.as_deref(), filter_term)
+        .get_public_rooms(limit, body.since.as_deref(), filter_term, network)
         .await
         .map_err(|_| MatrixError::unknown("Failed to fetch public rooms"))?;
 
@@ -26,8 +26,11 @@
 //! 3. **EDU processing** -- each EDU is dispatched by `edu_type`:
 //!    - `m.typing` -- updates the ephemeral typing state
 //!    - `m.presence` -- updates user presence status
-//!    - `m.receipt` -- stores read receipts
-//!    - `m.device_list_update` -- stores updated device keys for remote users
+//!    - `m.receipt` -- stores public (`m.read`) receipts, threaded or not
+//!    - `m.device_list_update` -- updates the remote device list cache (see
+//!      [`device_lists`](crate::device_lists))
+//!    - `m.signing_key_update` -- replaces a remote user's cached cross-signing keys
+//!    - `m.direct_to_device` -- queues to-device messages for local devices
 //!
 //! 4. **Transaction recording** -- the `(origin, txnId)` pair is stored to support
 //!    deduplication on retries.
@@ -42,9 +45,8 @@ use axum::{Json, Router};
 use serde::Deserialize;
 use tracing::{debug, warn};
 
-use maelstrom_core::matrix::error::MatrixError;
+use maelstrom_core::matrix::error::{ErrorCode, MatrixError};
 use maelstrom_core::matrix::event::Pdu;
-use maelstrom_storage::traits::RemoteKeyRecord;
 
 use crate::FederationState;
 
@@ -132,7 +134,8 @@ struct Transaction {
 ///
 /// This is currently a **soft check**: if the public key cannot be fetched we log
 /// a warning and allow the request through, since many implementations have edge
-/// cases around request signing.
+/// cases around request signing. Origins blocked by the server-wide
+/// [`FederationPolicy`](crate::policy::FederationPolicy) are always rejected.
 async fn verify_federation_auth(
     state: &FederationState,
     headers: &HeaderMap,
@@ -158,8 +161,12 @@ async fn verify_federation_auth(
         }
     };
 
+    crate::policy::check_inbound(state, &origin)?;
+
     // Fetch the origin server's public key
-    if let Some(public_key) = resolve_server_key(state, &origin, &key_id).await {
+    if let Some(public_key) =
+        crate::key_server::resolve_verify_key(state, &origin, &key_id, None).await
+    {
         let destination = state.server_name().as_str();
         if crate::signing::verify_request(
             &public_key,
@@ -221,12 +228,17 @@ async fn receive_transaction(
                 );
             }
         }
+        // A blocked origin is a policy decision, not a signing quirk
+        Err(e) if e.errcode == ErrorCode::Forbidden => return Err(e),
         Err(e) => {
             // Soft check — log and continue
             warn!(error = %e, "X-Matrix auth check failed, continuing anyway (soft check)");
         }
     }
 
+    // The body origin is what the PDUs are attributed to, so check it as well
+    crate::policy::check_inbound(&state, &txn.origin)?;
+
     // Rate limit per origin server
     check_federation_rate_limit(&txn.origin)?;
 
@@ -264,7 +276,7 @@ async fn receive_transaction(
 
     // Process EDUs
     for edu in &txn.edus {
-        process_edu(&state, edu).await;
+        process_edu(&state, edu, &txn.origin).await;
     }
 
     // Record transaction
@@ -276,161 +288,6 @@ async fn receive_transaction(
     Ok(Json(serde_json::json!({ "pdus": pdu_results })))
 }
 
-/// Decode a base64-encoded Ed25519 public key into a 32-byte array.
-///
-/// Returns `None` if the base64 is invalid or the decoded bytes are not exactly 32 bytes.
-fn decode_ed25519_key(b64: &str) -> Option<[u8; 32]> {
-    use base64::Engine;
-    let bytes = base64::engine::general_purpose::STANDARD_NO_PAD
-        .decode(b64)
-        .ok()?;
-    <[u8; 32]>::try_from(bytes.as_slice()).ok()
-}
-
-/// Fetch a remote server's Ed25519 public key, using cache when available.
-///
-/// 1. Check local cache (`FederationKeyStore::get_remote_server_keys`)
-/// 2. If not cached or expired, fetch from the remote server via `/_matrix/key/v2/server`
-/// 3. Cache the fetched keys for future use
-/// 4. Return the public key bytes for the requested `key_id`
-async fn resolve_server_key(
-    state: &FederationState,
-    server_name: &str,
-    key_id: &str,
-) -> Option<[u8; 32]> {
-    // 1. Check local cache
-    if let Ok(cached_keys) = state.storage().get_remote_server_keys(server_name).await {
-        for record in &cached_keys {
-            if record.key_id == key_id
-                && record.valid_until > chrono::Utc::now()
-                && let Some(arr) = decode_ed25519_key(&record.public_key)
-            {
-                return Some(arr);
-            }
-        }
-    }
-
-    // 2. Fetch from remote server
-    let keys_response = match state.client().fetch_server_keys(server_name).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            warn!(
-                server_name = %server_name,
-                error = %e,
-                "Failed to fetch server keys for signature verification"
-            );
-            return None;
-        }
-    };
-
-    // 2b. Verify the key response is self-signed by the server
-    if let Some(verify_keys) = keys_response.get("verify_keys").and_then(|v| v.as_object()) {
-        let mut self_sig_valid = false;
-        for (kid, key_data) in verify_keys {
-            if let Some(pub_key_b64) = key_data.get("key").and_then(|k| k.as_str())
-                && let Some(public_key) = decode_ed25519_key(pub_key_b64)
-                && maelstrom_core::matrix::signing::verify_event_signature(
-                    &keys_response,
-                    &public_key,
-                    server_name,
-                    kid,
-                )
-            {
-                self_sig_valid = true;
-                break;
-            }
-        }
-        if !self_sig_valid {
-            warn!(
-                server_name = %server_name,
-                "Server key response failed self-signature verification"
-            );
-            return None;
-        }
-    } else {
-        warn!(
-            server_name = %server_name,
-            "Server key response missing verify_keys"
-        );
-        return None;
-    }
-
-    // 3. Parse and cache the keys
-    let valid_until_ts = keys_response
-        .get("valid_until_ts")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(0);
-    let valid_until = chrono::DateTime::from_timestamp_millis(valid_until_ts as i64)
-        .unwrap_or_else(|| chrono::Utc::now() + chrono::Duration::hours(24));
-
-    let mut records = Vec::new();
-    let mut result_key: Option<[u8; 32]> = None;
-
-    if let Some(verify_keys) = keys_response.get("verify_keys").and_then(|v| v.as_object()) {
-        for (kid, key_data) in verify_keys {
-            if let Some(pub_key_b64) = key_data.get("key").and_then(|k| k.as_str()) {
-                records.push(RemoteKeyRecord {
-                    server_name: server_name.to_string(),
-                    key_id: kid.clone(),
-                    public_key: pub_key_b64.to_string(),
-                    valid_until,
-                });
-
-                if kid == key_id
-                    && let Some(arr) = decode_ed25519_key(pub_key_b64)
-                {
-                    result_key = Some(arr);
-                }
-            }
-        }
-    }
-
-    // Also check old_verify_keys in case the key rotated but we still need it
-    if result_key.is_none()
-        && let Some(old_keys) = keys_response
-            .get("old_verify_keys")
-            .and_then(|v| v.as_object())
-    {
-        for (kid, key_data) in old_keys {
-            let old_valid_until_ts = key_data
-                .get("expired_ts")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0);
-            let old_valid_until =
-                chrono::DateTime::from_timestamp_millis(old_valid_until_ts as i64)
-                    .unwrap_or_else(chrono::Utc::now);
-
-            if let Some(pub_key_b64) = key_data.get("key").and_then(|k| k.as_str()) {
-                records.push(RemoteKeyRecord {
-                    server_name: server_name.to_string(),
-                    key_id: kid.clone(),
-                    public_key: pub_key_b64.to_string(),
-                    valid_until: old_valid_until,
-                });
-
-                if kid == key_id
-                    && let Some(arr) = decode_ed25519_key(pub_key_b64)
-                {
-                    result_key = Some(arr);
-                }
-            }
-        }
-    }
-
-    // Store all fetched keys in cache
-    if !records.is_empty()
-        && let Err(e) = state.storage().store_remote_server_keys(&records).await
-    {
-        warn!(
-            server_name = %server_name,
-            error = %e,
-            "Failed to cache remote server keys"
-        );
-    }
-
-    result_key
-}
-
 /// Process a single inbound PDU.
 async fn process_pdu(
     state: &FederationState,
@@ -473,8 +330,11 @@ async fn process_pdu(
         let signing_server = origin;
         if let Some(server_sigs) = sigs.get(signing_server).and_then(|s| s.as_object()) {
             let mut verified = false;
+            let signed_at = pdu_json.get("origin_server_ts").and_then(|t| t.as_i64());
             for (key_id, _sig) in server_sigs {
-                if let Some(public_key) = resolve_server_key(state, signing_server, key_id).await
+                if let Some(public_key) =
+                    crate::key_server::resolve_verify_key(state, signing_server, key_id, signed_at)
+                        .await
                     && maelstrom_core::matrix::signing::verify_event_signature(
                         pdu_json,
                         &public_key,
@@ -699,7 +559,12 @@ async fn process_pdu(
 /// - `m.presence` -- a batch of presence updates for remote users
 /// - `m.receipt` -- read receipts for events in shared rooms
 /// - `m.device_list_update` -- a remote user's device keys changed (important for E2EE)
-async fn process_edu(state: &FederationState, edu: &serde_json::Value) {
+/// - `m.signing_key_update` -- a remote user's cross-signing keys changed
+/// - `m.direct_to_device` -- to-device messages (key shares, verification) for local devices
+///
+/// Typing, presence, receipt, device list, signing key and to-device EDUs are
+/// only accepted for users of the `origin` server.
+async fn process_edu(state: &FederationState, edu: &serde_json::Value, origin: &str) {
     let edu_type = edu
         .get("edu_type")
         .and_then(|e| e.as_str())
@@ -720,104 +585,215 @@ async fn process_edu(state: &FederationState, edu: &serde_json::Value) {
                 .get("typing")
                 .and_then(|t| t.as_bool())
                 .unwrap_or(false);
+            if !is_origin_user(user_id, origin) {
+                warn!(origin = %origin, user_id = %user_id, "Ignoring typing EDU for a user of another server");
+                return;
+            }
             debug!(room_id = %room_id, user_id = %user_id, typing = typing, "Federation typing EDU");
             state
                 .ephemeral()
                 .set_typing(user_id, room_id, typing, 30_000);
+            state.notify_room(room_id);
         }
         "m.presence" => {
+            if !state.ephemeral().presence_mode().federated() {
+                debug!("Ignoring presence EDU, federated presence is disabled");
+                return;
+            }
             // Handle batched format (content.push[]) and direct format (content.user_id)
-            if let Some(push) = content.get("push").and_then(|p| p.as_array()) {
-                for entry in push {
-                    let user_id = entry
-                        .get("user_id")
-                        .and_then(|u| u.as_str())
-                        .unwrap_or_default();
-                    let presence = entry
-                        .get("presence")
-                        .and_then(|p| p.as_str())
-                        .unwrap_or("offline");
-                    let status_msg = entry.get("status_msg").and_then(|s| s.as_str());
-                    debug!(user_id = %user_id, presence = %presence, "Federation presence EDU (batch)");
-                    state
-                        .ephemeral()
-                        .set_presence(user_id, presence, status_msg);
+            let entries = match content.get("push").and_then(|p| p.as_array()) {
+                Some(push) => push.clone(),
+                None => vec![content.clone()],
+            };
+            for entry in &entries {
+                let Some(user_id) = entry.get("user_id").and_then(|u| u.as_str()) else {
+                    continue;
+                };
+                if !is_origin_user(user_id, origin) {
+                    warn!(origin = %origin, user_id = %user_id, "Ignoring presence for a user of another server");
+                    continue;
                 }
-            } else if let Some(user_id) = content.get("user_id").and_then(|u| u.as_str()) {
-                let presence = content
+                let presence = entry
                     .get("presence")
                     .and_then(|p| p.as_str())
                     .unwrap_or("offline");
-                let status_msg = content.get("status_msg").and_then(|s| s.as_str());
+                let status_msg = entry.get("status_msg").and_then(|s| s.as_str());
+                let last_active_ago = entry
+                    .get("last_active_ago")
+                    .and_then(|a| a.as_u64())
+                    .unwrap_or(0);
                 debug!(user_id = %user_id, presence = %presence, "Federation presence EDU");
-                state
-                    .ephemeral()
-                    .set_presence(user_id, presence, status_msg);
+                state.ephemeral().set_remote_presence(
+                    user_id,
+                    presence,
+                    status_msg,
+                    last_active_ago,
+                );
             }
         }
         "m.receipt" => {
-            let room_id = content
-                .get("room_id")
-                .and_then(|r| r.as_str())
-                .unwrap_or_default();
-            if let Some(receipts) = content.get("m.read").and_then(|r| r.as_object()) {
-                for (event_id, data) in receipts {
-                    if let Some(user_ids) = data.get("user_ids").and_then(|u| u.as_array()) {
-                        for uid in user_ids {
-                            if let Some(user_id) = uid.as_str() {
-                                debug!(room_id = %room_id, user_id = %user_id, event_id = %event_id, "Federation receipt EDU");
-                                let _ = state
-                                    .storage()
-                                    .set_receipt(user_id, room_id, "m.read", event_id, "")
-                                    .await;
+            // { room_id: { receipt_type: { user_id: { event_ids, data } } } }
+            let Some(rooms) = content.as_object() else {
+                return;
+            };
+            for (room_id, receipt_types) in rooms {
+                let Some(receipt_types) = receipt_types.as_object() else {
+                    continue;
+                };
+                let mut stored = false;
+                for (receipt_type, users) in receipt_types {
+                    // Private receipts never leave their server
+                    if receipt_type != "m.read" {
+                        debug!(room_id = %room_id, receipt_type = %receipt_type, "Ignoring receipt type over federation");
+                        continue;
+                    }
+                    let Some(users) = users.as_object() else {
+                        continue;
+                    };
+                    for (user_id, receipt) in users {
+                        if !is_origin_user(user_id, origin) {
+                            warn!(origin = %origin, user_id = %user_id, "Ignoring receipt for a user of another server");
+                            continue;
+                        }
+                        let thread_id = receipt
+                            .pointer("/data/thread_id")
+                            .and_then(|t| t.as_str())
+                            .unwrap_or("");
+                        let event_ids = receipt
+                            .get("event_ids")
+                            .and_then(|e| e.as_array())
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|e| e.as_str());
+                        for event_id in event_ids {
+                            debug!(room_id = %room_id, user_id = %user_id, event_id = %event_id, thread_id = %thread_id, "Federation receipt EDU");
+                            if state
+                                .storage()
+                                .set_receipt(user_id, room_id, receipt_type, event_id, thread_id)
+                                .await
+                                .is_ok()
+                            {
+                                stored = true;
                             }
                         }
                     }
                 }
+                if stored {
+                    state.notify_room(room_id);
+                }
             }
         }
         "m.device_list_update" => {
-            // Device list updates inform us a remote user's device keys changed.
-            let user_id = content
-                .get("user_id")
-                .and_then(|u| u.as_str())
-                .unwrap_or_default();
-            let device_id = content
-                .get("device_id")
-                .and_then(|d| d.as_str())
-                .unwrap_or_default();
-            debug!(user_id = %user_id, device_id = %device_id, "Federation device list update EDU");
-            if let Some(keys) = content.get("keys") {
-                let _ = state
-                    .storage()
-                    .set_device_keys(user_id, device_id, keys)
-                    .await;
-            }
-            // Record the change position so sync's device_lists.changed picks it up
-            if !user_id.is_empty() {
-                let change_pos = state.storage().current_stream_position().await.unwrap_or(0);
-                let _ = state
-                    .storage()
-                    .set_account_data(
-                        user_id,
-                        None,
-                        "_maelstrom.device_change_pos",
-                        &serde_json::json!({"pos": change_pos}),
-                    )
-                    .await;
+            crate::device_lists::process_update(state, origin, &content).await;
+        }
+        "m.signing_key_update" => {
+            crate::device_lists::process_signing_key_update(state, origin, &content).await;
+        }
+        "m.direct_to_device" => {
+            process_direct_to_device(state, origin, &content).await;
+        }
+        other => {
+            debug!(edu_type = %other, "Unhandled federation EDU type");
+        }
+    }
+}
 
-                // Notify sync for all rooms this remote user is in
-                if let Ok(rooms) = state.storage().get_joined_rooms(user_id).await {
-                    for room_id in rooms {
-                        state.notify_room(&room_id);
+/// Queue the messages of an inbound `m.direct_to_device` EDU for local devices.
+///
+/// `messages` maps user IDs to device IDs (or `*` for all of the user's
+/// devices) to message contents. The sender's `message_id` is unique per
+/// sender, so an EDU that is delivered twice -- in retried transactions, say
+/// -- is only queued once.
+async fn process_direct_to_device(
+    state: &FederationState,
+    origin: &str,
+    content: &serde_json::Value,
+) {
+    let sender = content
+        .get("sender")
+        .and_then(|s| s.as_str())
+        .unwrap_or_default();
+    let event_type = content
+        .get("type")
+        .and_then(|t| t.as_str())
+        .unwrap_or_default();
+    let message_id = content
+        .get("message_id")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default();
+    if !is_origin_user(sender, origin) {
+        warn!(origin = %origin, sender = %sender, "Ignoring to-device EDU from a user of another server");
+        return;
+    }
+    if event_type.is_empty() || message_id.is_empty() {
+        debug!(origin = %origin, "Ignoring to-device EDU without type or message_id");
+        return;
+    }
+    let Some(messages) = content.get("messages").and_then(|m| m.as_object()) else {
+        return;
+    };
+
+    let storage = state.storage();
+    let dedup_key = format!("m.direct_to_device:{sender}:{message_id}");
+    if storage
+        .has_federation_txn(origin, &dedup_key)
+        .await
+        .unwrap_or(false)
+    {
+        debug!(sender = %sender, message_id = %message_id, "Ignoring duplicate to-device EDU");
+        return;
+    }
+    debug!(sender = %sender, event_type = %event_type, message_id = %message_id, "Federation to-device EDU");
+
+    let local_server = state.server_name().as_str();
+    for (user_id, devices) in messages {
+        if maelstrom_core::matrix::id::server_name_from_sigil_id(user_id) != local_server {
+            continue;
+        }
+        let Some(devices) = devices.as_object() else {
+            continue;
+        };
+        let mut delivered = false;
+        for (device_id, message) in devices {
+            let device_ids = if device_id == "*" {
+                match maelstrom_core::matrix::id::UserId::parse(user_id) {
+                    Ok(uid) => storage
+                        .list_devices(&uid)
+                        .await
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|d| d.device_id)
+                        .collect(),
+                    Err(_) => continue,
+                }
+            } else {
+                vec![device_id.clone()]
+            };
+            for target_device in device_ids {
+                match storage
+                    .store_to_device(user_id, &target_device, sender, event_type, message)
+                    .await
+                {
+                    Ok(()) => delivered = true,
+                    Err(e) => {
+                        warn!(user_id = %user_id, device_id = %target_device, error = %e, "Failed to store to-device message");
                     }
                 }
             }
         }
-        other => {
-            debug!(edu_type = %other, "Unhandled federation EDU type");
+        if delivered {
+            state.notify_user(user_id);
         }
     }
+
+    if let Err(e) = storage.store_federation_txn(origin, &dedup_key).await {
+        warn!(origin = %origin, error = %e, "Failed to record to-device message ID");
+    }
+}
+
+/// Whether `user_id` belongs to the `origin` server.
+fn is_origin_user(user_id: &str, origin: &str) -> bool {
+    maelstrom_core::matrix::id::server_name_from_sigil_id(user_id) == origin
 }
 
 /// Check if a server is allowed by the room's `m.room.server_acl` state event.
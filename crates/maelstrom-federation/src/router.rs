@@ -21,18 +21,26 @@
 //! | [`joins`]       | `GET make_join`, `PUT send_join`, `GET make_leave`, etc.|
 //! | [`state`]       | `GET /state/{roomId}`, `GET /state_ids/{roomId}`, `GET /event/{eventId}` |
 //! | [`backfill`]    | `GET /backfill/{roomId}`, `POST /get_missing_events/{roomId}` |
-//! | [`user_keys`]   | `POST /user/keys/query`                                 |
+//! | [`peek`]        | `PUT /peek/{roomId}/{peekId}` (MSC2444, unstable)        |
+//! | [`user_keys`]   | `POST /user/keys/query`, `POST /user/keys/claim`, `GET /user/devices/{userId}` |
 //! | [`queries`]     | `GET /query/profile`, `GET /query/directory`, `GET /publicRooms`, `POST /publicRooms` |
 //! | [`invite`]      | `PUT /invite/{roomId}/{eventId}` (v1 and v2)            |
+//! | [`hierarchy`]   | `GET /hierarchy/{roomId}`                               |
+//!
+//! Every request first passes through a middleware that rejects X-Matrix origins
+//! blocked by the server-wide [`policy`].
 //!
 //! [`key_server`]: crate::key_server
+//! [`policy`]: crate::policy
 //! [`receiver`]: crate::receiver
 //! [`joins`]: crate::joins
 //! [`state`]: crate::state
 //! [`backfill`]: crate::backfill
+//! [`peek`]: crate::peek
 //! [`user_keys`]: crate::user_keys
 //! [`queries`]: crate::queries
 //! [`invite`]: crate::invite
+//! [`hierarchy`]: crate::hierarchy
 
 use axum::Router;
 
@@ -50,9 +58,15 @@ pub fn build(state: FederationState) -> Router {
         .merge(crate::joins::routes())
         .merge(crate::state::routes())
         .merge(crate::backfill::routes())
+        .merge(crate::peek::routes())
         .merge(crate::user_keys::routes())
         .merge(crate::queries::routes())
-        .merge(crate::invite::routes());
+        .merge(crate::invite::routes())
+        .merge(crate::hierarchy::routes())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::policy::enforce_inbound,
+        ));
 
     Router::new().merge(federation_api).with_state(state)
 }
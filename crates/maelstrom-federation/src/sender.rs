@@ -16,6 +16,11 @@
 //! combined into a single `PUT /_matrix/federation/v1/send/{txnId}` request. This
 //! matches the Matrix spec recommendation for transaction sizes.
 //!
+//! EDUs that only carry the latest state of something are coalesced while
+//! queued: a new `m.typing` EDU replaces a still-pending one for the same user
+//! and room, so a destination in backoff gets one update instead of every
+//! keep-alive.
+//!
 //! ## Exponential Backoff
 //!
 //! When a transaction fails (network error, remote 5xx, etc.), the PDUs are pushed
@@ -26,16 +31,29 @@
 //! - Maximum wait: **1 hour**
 //! - On success: backoff is cleared immediately
 //!
+//! ## Destination Health
+//!
+//! Each destination's delivery state -- last success, consecutive failures,
+//! `retry_at`, last error, queue sizes and the timestamp of the oldest undelivered
+//! PDU -- is kept as a [`DestinationRecord`]. When the sender has storage attached
+//! (see [`TransactionSender::with_storage`]) the record is persisted whenever its
+//! failure count or backoff changes (not after every successful transaction) and
+//! reloaded on startup, so backoff survives restarts and the admin API can show it. Operators can [reset the backoff](TransactionSender::reset_backoff),
+//! [force a retry](TransactionSender::force_retry) or
+//! [purge the queue](TransactionSender::purge_queue) of a destination.
+//!
 //! ## Background Loop
 //!
 //! The [`TransactionSender::run`] method is designed to be spawned as a long-lived
 //! tokio task. It polls all queues every 200ms, skipping destinations that are in
 //! backoff.
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashSet, VecDeque};
 
+use chrono::Utc;
 use dashmap::DashMap;
 use maelstrom_core::matrix::event::{Pdu, timestamp_ms};
+use maelstrom_storage::traits::{DestinationRecord, Storage};
 use tracing::{debug, info, warn};
 
 /// How often the sender loop checks for queued events.
@@ -44,10 +62,13 @@ const DRAIN_INTERVAL_MS: u64 = 200;
 const MAX_PDUS_PER_TXN: usize = 50;
 /// Maximum EDUs per federation transaction.
 const MAX_EDUS_PER_TXN: usize = 100;
+/// Backoff wait after the first failure.
+const INITIAL_BACKOFF_MS: u64 = 1000;
 /// Maximum backoff wait before retrying a failed destination.
 const MAX_BACKOFF_MS: u64 = 3_600_000; // 1 hour
 
 use crate::client::FederationClient;
+use crate::policy::PolicyCheck;
 
 /// Outbound federation transaction sender with per-destination queuing and retry.
 ///
@@ -64,6 +85,9 @@ pub struct TransactionSender {
     server_name: String,
     queues: DashMap<String, VecDeque<serde_json::Value>>,
     edu_queues: DashMap<String, VecDeque<serde_json::Value>>,
+    /// Delivery state per destination, mirrored to storage when attached.
+    health: DashMap<String, DestinationRecord>,
+    storage: Option<Box<dyn Storage>>,
 }
 
 impl TransactionSender {
@@ -73,17 +97,34 @@ impl TransactionSender {
             server_name,
             queues: DashMap::new(),
             edu_queues: DashMap::new(),
+            health: DashMap::new(),
+            storage: None,
         }
     }
 
+    /// Persist destination health to `storage` and restore it when [`run`](Self::run) starts.
+    pub fn with_storage(mut self, storage: impl Storage) -> Self {
+        self.storage = Some(Box::new(storage));
+        self
+    }
+
     /// Queue a PDU for sending to a destination server.
     ///
     /// The event is serialized to federation JSON format and appended to the
-    /// destination's queue. Events addressed to this server are silently dropped.
+    /// destination's queue. Events addressed to this server, or to a server
+    /// blocked by the client's [`FederationPolicy`](crate::policy::FederationPolicy),
+    /// are silently dropped.
     pub fn queue_pdu(&self, destination: &str, event: &Pdu) {
         if destination == self.server_name {
             return; // Don't send to ourselves
         }
+        if !self
+            .client
+            .policy()
+            .check(destination, PolicyCheck::Outbound)
+        {
+            return;
+        }
 
         let pdu = event.to_federation_json();
         self.queues
@@ -98,15 +139,88 @@ impl TransactionSender {
     ///
     /// EDUs include typing notifications, presence updates, read receipts, and
     /// device list updates. They are batched alongside PDUs in the next transaction.
+    /// A pending EDU superseded by `edu` (see [`coalesce_key`]) is replaced in place.
     pub fn queue_edu(&self, destination: &str, edu: serde_json::Value) {
         if destination == self.server_name {
             return;
         }
+        if !self
+            .client
+            .policy()
+            .check(destination, PolicyCheck::Outbound)
+        {
+            return;
+        }
 
-        self.edu_queues
-            .entry(destination.to_string())
-            .or_default()
-            .push_back(edu);
+        let mut queue = self.edu_queues.entry(destination.to_string()).or_default();
+        if let Some(key) = coalesce_key(&edu)
+            && let Some(pending) = queue
+                .iter_mut()
+                .find(|pending| coalesce_key(pending).as_ref() == Some(&key))
+        {
+            *pending = edu;
+            return;
+        }
+        queue.push_back(edu);
+    }
+
+    /// Current delivery state of a destination, with live queue sizes.
+    ///
+    /// Returns `None` if nothing has ever been queued for or sent to it.
+    pub fn destination(&self, destination: &str) -> Option<DestinationRecord> {
+        let known = self.health.contains_key(destination)
+            || self.queues.contains_key(destination)
+            || self.edu_queues.contains_key(destination);
+        known.then(|| self.snapshot(destination))
+    }
+
+    /// Delivery state of every destination this sender knows about.
+    pub fn destinations(&self) -> Vec<DestinationRecord> {
+        let mut names: HashSet<String> = self.health.iter().map(|e| e.key().clone()).collect();
+        names.extend(self.queues.iter().map(|e| e.key().clone()));
+        names.extend(self.edu_queues.iter().map(|e| e.key().clone()));
+
+        let mut records: Vec<DestinationRecord> =
+            names.iter().map(|name| self.snapshot(name)).collect();
+        records.sort_by(|a, b| a.server_name.cmp(&b.server_name));
+        records
+    }
+
+    /// Forget all failures of a destination and make it eligible immediately.
+    pub async fn reset_backoff(&self, destination: &str) {
+        self.update_health(destination, |record| {
+            record.failure_count = 0;
+            record.retry_at = None;
+            record.last_error = None;
+        })
+        .await;
+        info!(destination = %destination, "Federation backoff reset");
+    }
+
+    /// Retry a destination on the next loop iteration without forgetting its
+    /// failures -- if the retry fails, backoff continues where it left off.
+    pub async fn force_retry(&self, destination: &str) {
+        self.update_health(destination, |record| record.retry_at = None)
+            .await;
+        info!(destination = %destination, "Federation retry forced");
+    }
+
+    /// Drop everything queued for a destination. Returns the number of
+    /// discarded PDUs and EDUs.
+    pub async fn purge_queue(&self, destination: &str) -> (usize, usize) {
+        let pdus = self
+            .queues
+            .remove(destination)
+            .map(|(_, q)| q.len())
+            .unwrap_or(0);
+        let edus = self
+            .edu_queues
+            .remove(destination)
+            .map(|(_, q)| q.len())
+            .unwrap_or(0);
+        self.persist_health(destination).await;
+        warn!(destination = %destination, pdus, edus, "Federation queue purged");
+        (pdus, edus)
     }
 
     /// Run the sender loop. Call this as a spawned tokio task.
@@ -118,13 +232,22 @@ impl TransactionSender {
     pub async fn run(self: std::sync::Arc<Self>) {
         info!("Federation transaction sender started");
 
-        let mut backoff: HashMap<String, u64> = HashMap::new();
+        if let Some(storage) = &self.storage {
+            match storage.list_destinations().await {
+                Ok(records) => {
+                    for record in records {
+                        self.health.insert(record.server_name.clone(), record);
+                    }
+                }
+                Err(e) => warn!(error = %e, "Failed to load federation destination state"),
+            }
+        }
 
         loop {
             tokio::time::sleep(std::time::Duration::from_millis(DRAIN_INTERVAL_MS)).await;
 
             let destinations: Vec<String> = {
-                let mut dests: std::collections::HashSet<String> = self
+                let mut dests: HashSet<String> = self
                     .queues
                     .iter()
                     .filter(|entry| !entry.value().is_empty())
@@ -140,30 +263,30 @@ impl TransactionSender {
 
             for dest in destinations {
                 // Check backoff
-                if let Some(&retry_at) = backoff.get(&dest) {
-                    let now = timestamp_ms();
-                    if now < retry_at {
-                        continue;
-                    }
+                let retry_at = self.health.get(&dest).and_then(|r| r.retry_at);
+                if retry_at.is_some_and(|t| Utc::now() < t) {
+                    continue;
                 }
 
                 // Drain up to 50 PDUs
-                let pdus: Vec<serde_json::Value> =
-                    if let Some(mut queue) = self.queues.get_mut(&dest) {
+                let pdus: Vec<serde_json::Value> = self
+                    .queues
+                    .get_mut(&dest)
+                    .map(|mut queue| {
                         let count = queue.len().min(MAX_PDUS_PER_TXN);
                         queue.drain(..count).collect()
-                    } else {
-                        continue;
-                    };
+                    })
+                    .unwrap_or_default();
 
                 // Drain up to 100 EDUs
-                let edus: Vec<serde_json::Value> =
-                    if let Some(mut queue) = self.edu_queues.get_mut(&dest) {
+                let edus: Vec<serde_json::Value> = self
+                    .edu_queues
+                    .get_mut(&dest)
+                    .map(|mut queue| {
                         let count = queue.len().min(MAX_EDUS_PER_TXN);
                         queue.drain(..count).collect()
-                    } else {
-                        Vec::new()
-                    };
+                    })
+                    .unwrap_or_default();
 
                 if pdus.is_empty() && edus.is_empty() {
                     continue;
@@ -182,7 +305,13 @@ impl TransactionSender {
                 match self.client.put_json(&dest, &path, &transaction).await {
                     Ok(_) => {
                         debug!(destination = %dest, count = pdus.len(), "Sent federation transaction");
-                        backoff.remove(&dest);
+                        self.update_health(&dest, |record| {
+                            record.last_success_at = Some(Utc::now());
+                            record.failure_count = 0;
+                            record.retry_at = None;
+                            record.last_error = None;
+                        })
+                        .await;
                     }
                     Err(e) => {
                         warn!(destination = %dest, error = %e, "Federation send failed");
@@ -195,17 +324,104 @@ impl TransactionSender {
                             }
                         }
 
-                        // Exponential backoff: 1s, 2s, 4s, 8s... up to 1 hour
-                        let current_wait = backoff.get(&dest).copied().unwrap_or(0);
-                        let next_wait = if current_wait == 0 {
-                            1000
-                        } else {
-                            (current_wait * 2).min(MAX_BACKOFF_MS)
-                        };
-                        backoff.insert(dest, timestamp_ms() + next_wait);
+                        let error = e.to_string();
+                        self.update_health(&dest, |record| {
+                            let now = Utc::now();
+                            record.failure_count = record.failure_count.saturating_add(1);
+                            record.last_failure_at = Some(now);
+                            record.retry_at = Some(now + backoff_for(record.failure_count));
+                            record.last_error = Some(error);
+                        })
+                        .await;
                     }
                 }
             }
         }
     }
+
+    /// The destination's record with queue sizes and PDU lag filled in from the live queues.
+    fn snapshot(&self, destination: &str) -> DestinationRecord {
+        let mut record = self
+            .health
+            .get(destination)
+            .map(|r| r.clone())
+            .unwrap_or_else(|| DestinationRecord {
+                server_name: destination.to_string(),
+                ..Default::default()
+            });
+
+        match self.queues.get(destination) {
+            Some(queue) => {
+                record.pending_pdus = queue.len() as u64;
+                record.oldest_pending_pdu_ts = queue
+                    .front()
+                    .and_then(|pdu| pdu.get("origin_server_ts"))
+                    .and_then(|ts| ts.as_i64());
+            }
+            None => {
+                record.pending_pdus = 0;
+                record.oldest_pending_pdu_ts = None;
+            }
+        }
+        record.pending_edus = self
+            .edu_queues
+            .get(destination)
+            .map(|q| q.len() as u64)
+            .unwrap_or(0);
+
+        record
+    }
+
+    /// Apply `update` to a destination's record, persisting it if the failure
+    /// count or backoff changed.
+    async fn update_health(&self, destination: &str, update: impl FnOnce(&mut DestinationRecord)) {
+        let changed = {
+            let mut entry = self
+                .health
+                .entry(destination.to_string())
+                .or_insert_with(|| DestinationRecord {
+                    server_name: destination.to_string(),
+                    ..Default::default()
+                });
+            let before = (entry.failure_count, entry.retry_at);
+            update(&mut entry);
+            before != (entry.failure_count, entry.retry_at)
+        };
+        if changed {
+            self.persist_health(destination).await;
+        }
+    }
+
+    /// Persist a destination's record with its current queue sizes.
+    async fn persist_health(&self, destination: &str) {
+        let record = self.snapshot(destination);
+        if let Some(storage) = &self.storage
+            && let Err(e) = storage.upsert_destination(&record).await
+        {
+            warn!(destination = %destination, error = %e, "Failed to persist federation destination state");
+        }
+        self.health.insert(destination.to_string(), record);
+    }
+}
+
+/// Exponential backoff: 1s, 2s, 4s, 8s... up to 1 hour.
+fn backoff_for(failure_count: u32) -> chrono::Duration {
+    let exponent = failure_count.saturating_sub(1).min(31);
+    let wait = INITIAL_BACKOFF_MS
+        .saturating_mul(1u64 << exponent)
+        .min(MAX_BACKOFF_MS);
+    chrono::Duration::milliseconds(wait as i64)
+}
+
+/// Identifies EDUs of which only the latest queued one matters: `m.typing`
+/// for a given room and user.
+fn coalesce_key(edu: &serde_json::Value) -> Option<(String, String)> {
+    if edu.get("edu_type")?.as_str()? != "m.typing" {
+        return None;
+    }
+    let content = edu.get("content")?;
+    Some((
+        content.get("room_id")?.as_str()?.to_string(),
+        content.get("user_id")?.as_str()?.to_string(),
+    ))
 }
@@ -33,11 +33,18 @@
 //!
 //! - [`sign_request`] -- produce an `Authorization` header value for an outbound request
 //! - [`parse_x_matrix_header`] -- extract `(origin, key_id, signature)` from an inbound header
+//! - [`claimed_origin`] -- the origin an inbound request claims, from its headers
+//! - [`request_origin`] -- the origin of an inbound request, once its signature verifies
 //! - [`verify_request`] -- verify an inbound request signature against a known public key
 
+use axum::http::{HeaderMap, Uri};
+use tracing::warn;
+
 use maelstrom_core::matrix::json::CanonicalJson;
 use maelstrom_core::matrix::keys::KeyPair;
 
+use crate::FederationState;
+
 /// Sign an outbound federation HTTP request.
 ///
 /// Constructs the canonical JSON object from the request parameters, signs it with
@@ -115,6 +122,59 @@ pub fn parse_x_matrix_header(header: &str) -> Option<(String, String, String)> {
     Some((origin?, key_id?, sig?))
 }
 
+/// The origin named in a request's X-Matrix `Authorization` header, if any.
+///
+/// This is what the request *claims*; it is not checked against the signature.
+/// Use [`request_origin`] wherever the answer depends on who is asking.
+pub fn claimed_origin(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_x_matrix_header)
+        .map(|(origin, _, _)| origin)
+}
+
+/// The origin of an inbound request, if its X-Matrix `Authorization` header
+/// is signed by one of the origin's keys.
+///
+/// `content` is the request's JSON body, if it has one. Unsigned requests,
+/// and requests whose signature does not verify against the origin's
+/// published keys, have no origin.
+pub async fn request_origin(
+    state: &FederationState,
+    headers: &HeaderMap,
+    method: &str,
+    uri: &Uri,
+    content: Option<&serde_json::Value>,
+) -> Option<String> {
+    let uri = uri.path_and_query().map_or(uri.path(), |pq| pq.as_str());
+    let (origin, key_id, signature) = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_x_matrix_header)?;
+    let Some(public_key) =
+        crate::key_server::resolve_verify_key(state, &origin, &key_id, None).await
+    else {
+        warn!(origin = %origin, key_id = %key_id, "Unknown key in X-Matrix header");
+        return None;
+    };
+    let destination = state.server_name().as_str();
+    if verify_request(
+        &public_key,
+        &origin,
+        destination,
+        method,
+        uri,
+        content,
+        &signature,
+    ) {
+        Some(origin)
+    } else {
+        warn!(origin = %origin, uri = %uri, "X-Matrix signature does not verify");
+        None
+    }
+}
+
 /// Verify an inbound federation request signature.
 ///
 /// Reconstructs the same canonical JSON that the sending server signed (from the
@@ -23,9 +23,12 @@
 //!
 //! Returns a single event by its ID. Used when a server needs a specific event
 //! it does not have -- for example, an event referenced in `auth_events` or
-//! `prev_events` that was never received in a transaction.
+//! `prev_events` that was never received in a transaction. An event the
+//! requesting server may not see under the room's history visibility comes
+//! back with its content stripped.
 
-use axum::extract::{Path, Query, State};
+use axum::extract::{OriginalUri, Path, Query, State};
+use axum::http::HeaderMap;
 use axum::routing::get;
 use axum::{Json, Router};
 use serde::Deserialize;
@@ -202,6 +205,8 @@ async fn timestamp_to_event(
 /// GET /_matrix/federation/v1/event/{eventId} — return a single event.
 async fn get_event(
     State(state): State<FederationState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
     Path(event_id): Path<String>,
 ) -> Result<Json<serde_json::Value>, MatrixError> {
     let event = state
@@ -209,6 +214,14 @@ async fn get_event(
         .get_event(&event_id)
         .await
         .map_err(|_| MatrixError::not_found("Event not found"))?;
+    let origin = crate::signing::request_origin(&state, &headers, "GET", &uri, None).await;
+    let event = crate::visibility::filter_events_for_server(
+        state.storage(),
+        origin.as_deref(),
+        vec![event],
+    )
+    .await
+    .remove(0);
 
     Ok(Json(serde_json::json!({
         "origin": state.server_name().as_str(),
@@ -33,8 +33,19 @@
 //!
 //! These keys are essential for clients to establish Olm/Megolm sessions and verify
 //! device trust.
+//!
+//! `POST /_matrix/federation/v1/user/keys/claim` hands out one-time keys of
+//! local devices, falling back to a device's fallback key (MSC2732) once its
+//! one-time keys are used up. Only requests whose X-Matrix signature verifies
+//! may claim keys.
+//!
+//! Signatures uploaded through `/keys/signatures/upload` are stored apart from
+//! the keys and merged in by [`apply_key_signatures`]. Remote servers only see
+//! the signatures users made on their own keys; a signature on another user's
+//! master key is private to the signer.
 
-use axum::extract::{Path, State};
+use axum::extract::{OriginalUri, Path, State};
+use axum::http::HeaderMap;
 use axum::routing::{get, post};
 use axum::{Json, Router};
 use serde::Deserialize;
@@ -43,6 +54,7 @@ use tracing::debug;
 
 use maelstrom_core::matrix::error::MatrixError;
 use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_storage::traits::KeySignature;
 
 use crate::FederationState;
 
@@ -53,12 +65,86 @@ pub fn routes() -> Router<FederationState> {
             "/_matrix/federation/v1/user/keys/query",
             post(query_user_keys),
         )
+        .route(
+            "/_matrix/federation/v1/user/keys/claim",
+            post(claim_user_keys),
+        )
         .route(
             "/_matrix/federation/v1/user/devices/{userId}",
             get(get_user_devices),
         )
 }
 
+/// The ID by which signatures name a cross-signing key: its unpadded base64
+/// public key, the value of the only entry in its `keys`.
+pub fn cross_signing_key_id(key: &serde_json::Value) -> Option<&str> {
+    key.get("keys")?.as_object()?.values().next()?.as_str()
+}
+
+/// Merge the uploaded `signatures` on the key `key_id` (a device ID or a
+/// master key's public key) into `key["signatures"]`.
+///
+/// A signature is included when its signer owns the key, or is `viewer`:
+/// signatures on another user's master key are only shown to their signer.
+pub fn apply_key_signatures(
+    signatures: &[KeySignature],
+    viewer: Option<&str>,
+    key_id: &str,
+    key: &mut serde_json::Value,
+) {
+    let Some(key) = key.as_object_mut() else {
+        return;
+    };
+    for signature in signatures {
+        if signature.signed_key_id != key_id
+            || (signature.signing_user_id != signature.signed_user_id
+                && viewer != Some(signature.signing_user_id.as_str()))
+        {
+            continue;
+        }
+        let all = key.entry("signatures").or_insert_with(|| json!({}));
+        if !all.is_object() {
+            *all = json!({});
+        }
+        let by_user = all
+            .as_object_mut()
+            .expect("signatures is an object")
+            .entry(signature.signing_user_id.clone())
+            .or_insert_with(|| json!({}));
+        if let Some(by_user) = by_user.as_object_mut() {
+            by_user.insert(signature.signing_key_id.clone(), json!(signature.signature));
+        }
+    }
+}
+
+/// Merge the uploaded signatures on a local user's keys into their devices
+/// (`device_id -> keys`) and master key, as seen by a remote server.
+async fn sign_user_keys(
+    state: &FederationState,
+    user_id: &str,
+    devices: &mut serde_json::Value,
+    master_key: Option<&mut serde_json::Value>,
+) {
+    let signatures = match state.storage().get_key_signatures(user_id).await {
+        Ok(signatures) if !signatures.is_empty() => signatures,
+        Ok(_) => return,
+        Err(e) => {
+            debug!(user_id = %user_id, error = %e, "Failed to get key signatures");
+            return;
+        }
+    };
+    if let Some(devices) = devices.as_object_mut() {
+        for (device_id, keys) in devices {
+            apply_key_signatures(&signatures, None, device_id, keys);
+        }
+    }
+    if let Some(master_key) = master_key
+        && let Some(key_id) = cross_signing_key_id(master_key).map(str::to_string)
+    {
+        apply_key_signatures(&signatures, None, &key_id, master_key);
+    }
+}
+
 /// Request body for the federation device key query.
 ///
 /// The `device_keys` field maps user IDs to lists of requested device IDs.
@@ -104,7 +190,9 @@ async fn query_user_keys(
         {
             Ok(keys) => {
                 if let Some(user_keys) = keys.get(user_id) {
-                    result_keys.insert(user_id.clone(), user_keys.clone());
+                    let mut user_keys = user_keys.clone();
+                    sign_user_keys(&state, user_id, &mut user_keys, None).await;
+                    result_keys.insert(user_id.clone(), user_keys);
                 }
             }
             Err(e) => {
@@ -125,7 +213,9 @@ async fn query_user_keys(
 
         if let Ok(cross_keys) = state.storage().get_cross_signing_keys(user_id).await {
             if let Some(master) = cross_keys.get("master_key") {
-                master_keys.insert(user_id.clone(), master.clone());
+                let mut master = master.clone();
+                sign_user_keys(&state, user_id, &mut json!({}), Some(&mut master)).await;
+                master_keys.insert(user_id.clone(), master);
             }
             if let Some(self_signing) = cross_keys.get("self_signing_key") {
                 self_signing_keys.insert(user_id.clone(), self_signing.clone());
@@ -140,9 +230,55 @@ async fn query_user_keys(
     })))
 }
 
+/// Request body for the federation one-time key claim.
+#[derive(Deserialize)]
+struct KeysClaimRequest {
+    /// Map of user ID to a map of device ID to algorithm.
+    one_time_keys: serde_json::Map<String, serde_json::Value>,
+}
+
+/// POST /_matrix/federation/v1/user/keys/claim
+///
+/// Claim one-time keys of local users' devices, or their fallback keys once
+/// they have run out. Claiming uses keys up, so only servers that sign the
+/// request may do it.
+async fn claim_user_keys(
+    State(state): State<FederationState>,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    Json(content): Json<serde_json::Value>,
+) -> Result<Json<serde_json::Value>, MatrixError> {
+    let Some(origin) =
+        crate::signing::request_origin(&state, &headers, "POST", &uri, Some(&content)).await
+    else {
+        return Err(MatrixError::unauthorized(
+            "Claiming keys requires a signed request",
+        ));
+    };
+    let body: KeysClaimRequest =
+        serde_json::from_value(content).map_err(|e| MatrixError::bad_json(e.to_string()))?;
+    let our_server = state.server_name().as_str();
+    let claims: serde_json::Map<String, serde_json::Value> = body
+        .one_time_keys
+        .into_iter()
+        .filter(|(user_id, _)| server_name_from_sigil_id(user_id) == our_server)
+        .collect();
+
+    debug!(origin = %origin, users = claims.len(), "Federation one-time key claim");
+
+    let claimed = state
+        .storage()
+        .claim_one_time_keys(&serde_json::Value::Object(claims))
+        .await
+        .map_err(|e| MatrixError::unknown(e.to_string()))?;
+
+    Ok(Json(json!({ "one_time_keys": claimed })))
+}
+
 /// GET /_matrix/federation/v1/user/devices/{userId}
 ///
-/// Returns all device information for a local user, including device keys.
+/// Returns all device information for a local user, including device keys
+/// and cross-signing keys, as of the user's latest device list `stream_id`.
 /// Per Server-Server API section 2.7.
 async fn get_user_devices(
     State(state): State<FederationState>,
@@ -169,7 +305,19 @@ async fn get_user_devices(
         .get_device_keys(std::slice::from_ref(&user_id))
         .await
         .unwrap_or(json!({}));
-    let user_keys = all_keys.get(&user_id).cloned().unwrap_or(json!({}));
+    let mut user_keys = all_keys.get(&user_id).cloned().unwrap_or(json!({}));
+    let mut cross_keys = state
+        .storage()
+        .get_cross_signing_keys(&user_id)
+        .await
+        .unwrap_or(json!({}));
+    sign_user_keys(
+        &state,
+        &user_id,
+        &mut user_keys,
+        cross_keys.get_mut("master_key"),
+    )
+    .await;
 
     let mut device_list = Vec::new();
     for device in &devices {
@@ -184,7 +332,13 @@ async fn get_user_devices(
         }));
     }
 
-    let stream_id = state.storage().current_stream_position().await.unwrap_or(1);
+    // The latest change in the user's device list stream, which remote
+    // servers use as the base for following `m.device_list_update` EDUs
+    let stream_id = state
+        .storage()
+        .get_device_list_stream_id(&user_id)
+        .await
+        .unwrap_or(0);
 
     debug!(
         user_id = %user_id,
@@ -192,9 +346,15 @@ async fn get_user_devices(
         "Federation user devices query"
     );
 
-    Ok(Json(json!({
+    let mut response = json!({
         "user_id": user_id,
         "stream_id": stream_id,
         "devices": device_list,
-    })))
+    });
+    for key_type in ["master_key", "self_signing_key"] {
+        if let Some(key) = cross_keys.get(key_type) {
+            response[key_type] = key.clone();
+        }
+    }
+    Ok(Json(response))
 }
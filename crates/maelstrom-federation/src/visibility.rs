@@ -0,0 +1,233 @@
+//! # History Visibility
+//!
+//! A room's `m.room.history_visibility` decides who may read which part of its
+//! history. Every path that hands out stored events -- client `/messages`,
+//! `/event`, `/context`, `/search`, `/relations` and threads, and federation
+//! `/backfill`, `/get_missing_events` and `/event` -- runs them through the
+//! filters here, so the rules are applied the same way everywhere.
+//!
+//! ## State at the event
+//!
+//! Visibility is judged per event, from the room state **at that event**: the
+//! history visibility in force when it was sent, and the viewer's membership
+//! at that point. The rules themselves live in
+//! [`HistoryVisibility::visible_to_user`] and
+//! [`HistoryVisibility::visible_to_server`]; this module looks up the state
+//! they need.
+//!
+//! ## Users
+//!
+//! [`filter_events_for_user`] drops events the user may not see. Users always
+//! see their own membership events. Rooms whose history is currently
+//! `world_readable` can be read without being in them at all (peeking), see
+//! [`user_may_read_room`].
+//!
+//! ## Servers
+//!
+//! A remote server sees an event if any of its users could, judged by their
+//! membership at the event. The remote server still needs the event to keep
+//! its copy of the room DAG intact, so [`filter_events_for_server`] sends
+//! events it may not see redacted, with only the content the room version's
+//! redaction algorithm keeps.
+
+use std::collections::HashMap;
+
+use maelstrom_core::matrix::event::Pdu;
+use maelstrom_core::matrix::id::server_name_from_sigil_id;
+use maelstrom_core::matrix::room::{
+    HistoryVisibility, Membership, RedactionAlgorithm, RoomVersion, event_type as et,
+};
+use maelstrom_storage::traits::Storage;
+
+/// The room's history visibility at stream position `pos`, `shared` if it had
+/// none.
+pub async fn history_visibility_at(
+    storage: &dyn Storage,
+    room_id: &str,
+    pos: i64,
+) -> HistoryVisibility {
+    storage
+        .get_state_event_at(room_id, et::HISTORY_VISIBILITY, "", pos)
+        .await
+        .ok()
+        .and_then(|ev| {
+            ev.content
+                .get("history_visibility")
+                .and_then(|v| v.as_str())
+                .and_then(HistoryVisibility::parse)
+        })
+        .unwrap_or_default()
+}
+
+/// The user's membership in the room at stream position `pos`.
+pub async fn membership_at(
+    storage: &dyn Storage,
+    room_id: &str,
+    user_id: &str,
+    pos: i64,
+) -> Option<Membership> {
+    storage
+        .get_state_event_at(room_id, et::MEMBER, user_id, pos)
+        .await
+        .ok()
+        .and_then(|ev| membership_of(&ev))
+}
+
+fn membership_of(member_event: &Pdu) -> Option<Membership> {
+    member_event
+        .content
+        .get("membership")
+        .and_then(|m| m.as_str())
+        .and_then(Membership::parse)
+}
+
+/// Whether the user may read the room's history at all: they are or were in
+/// the room, or its history is currently `world_readable`.
+///
+/// Individual events still have to pass [`filter_events_for_user`].
+pub async fn user_may_read_room(storage: &dyn Storage, user_id: &str, room_id: &str) -> bool {
+    if storage
+        .get_state_event(room_id, et::MEMBER, user_id)
+        .await
+        .is_ok()
+    {
+        return true;
+    }
+    let current = storage.current_stream_position().await.unwrap_or(i64::MAX);
+    history_visibility_at(storage, room_id, current).await == HistoryVisibility::WorldReadable
+}
+
+/// The stream position up to which the user was last joined to the room:
+/// unbounded while they are joined, their leave (or ban) for users who left
+/// after being joined, `None` for anyone else.
+async fn joined_until(storage: &dyn Storage, user_id: &str, room_id: &str) -> Option<i64> {
+    let current = storage
+        .get_state_event(room_id, et::MEMBER, user_id)
+        .await
+        .ok()?;
+    match membership_of(&current)? {
+        Membership::Join => Some(i64::MAX),
+        Membership::Leave | Membership::Ban => {
+            let before =
+                membership_at(storage, room_id, user_id, current.stream_position - 1).await;
+            (before == Some(Membership::Join)).then_some(current.stream_position)
+        }
+        _ => None,
+    }
+}
+
+/// Whether the user may see the event under the room's history visibility.
+pub async fn event_visible_to_user(storage: &dyn Storage, user_id: &str, event: &Pdu) -> bool {
+    let joined_until = joined_until(storage, user_id, &event.room_id).await;
+    visible_to_user(storage, user_id, joined_until, event).await
+}
+
+async fn visible_to_user(
+    storage: &dyn Storage,
+    user_id: &str,
+    joined_until: Option<i64>,
+    event: &Pdu,
+) -> bool {
+    if event.event_type == et::MEMBER && event.state_key.as_deref() == Some(user_id) {
+        return true;
+    }
+    let visibility = history_visibility_at(storage, &event.room_id, event.stream_position).await;
+    if visibility == HistoryVisibility::WorldReadable {
+        return true;
+    }
+    let membership = membership_at(storage, &event.room_id, user_id, event.stream_position).await;
+    visibility.visible_to_user(
+        membership,
+        joined_until.is_some_and(|until| event.stream_position < until),
+    )
+}
+
+/// Drop the events the user may not see, keeping the order of the rest.
+pub async fn filter_events_for_user(
+    storage: &dyn Storage,
+    user_id: &str,
+    events: Vec<Pdu>,
+) -> Vec<Pdu> {
+    let mut joined_until_by_room: HashMap<String, Option<i64>> = HashMap::new();
+    let mut visible = Vec::with_capacity(events.len());
+    for event in events {
+        let joined_until = match joined_until_by_room.get(&event.room_id) {
+            Some(until) => *until,
+            None => {
+                let until = joined_until(storage, user_id, &event.room_id).await;
+                joined_until_by_room.insert(event.room_id.clone(), until);
+                until
+            }
+        };
+        if visible_to_user(storage, user_id, joined_until, &event).await {
+            visible.push(event);
+        }
+    }
+    visible
+}
+
+/// Redact the events `server_name` may not see, by the room version's
+/// redaction algorithm, keeping them all in order. Without a server name (an unauthenticated request), only
+/// `shared` and `world_readable` history is left intact.
+pub async fn filter_events_for_server(
+    storage: &dyn Storage,
+    server_name: Option<&str>,
+    mut events: Vec<Pdu>,
+) -> Vec<Pdu> {
+    // The server's users per room: everyone with a member event from it
+    let mut users_by_room: HashMap<String, Vec<String>> = HashMap::new();
+    let mut redaction_by_room: HashMap<String, RedactionAlgorithm> = HashMap::new();
+    for event in &mut events {
+        let visibility =
+            history_visibility_at(storage, &event.room_id, event.stream_position).await;
+        if visibility.visible_to_server(false, false) {
+            continue;
+        }
+
+        if !users_by_room.contains_key(&event.room_id) {
+            let users = match server_name {
+                Some(server_name) => storage
+                    .get_current_state(&event.room_id)
+                    .await
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|e| e.event_type == et::MEMBER)
+                    .filter_map(|e| e.state_key)
+                    .filter(|user_id| server_name_from_sigil_id(user_id) == server_name)
+                    .collect(),
+                None => Vec::new(),
+            };
+            users_by_room.insert(event.room_id.clone(), users);
+        }
+
+        let (mut any_joined, mut any_invited) = (false, false);
+        for user_id in &users_by_room[&event.room_id] {
+            match membership_at(storage, &event.room_id, user_id, event.stream_position).await {
+                Some(Membership::Join) => any_joined = true,
+                Some(Membership::Invite) => any_invited = true,
+                _ => {}
+            }
+            if any_joined {
+                break;
+            }
+        }
+        if !visibility.visible_to_server(any_joined, any_invited) {
+            let algorithm = match redaction_by_room.get(&event.room_id) {
+                Some(algorithm) => *algorithm,
+                None => {
+                    let algorithm = storage
+                        .get_room(&event.room_id)
+                        .await
+                        .ok()
+                        .and_then(|room| RoomVersion::parse(&room.version))
+                        .unwrap_or(RoomVersion::V10)
+                        .redaction_algorithm();
+                    redaction_by_room.insert(event.room_id.clone(), algorithm);
+                    algorithm
+                }
+            };
+            event.content = algorithm.redact_content(&event.event_type, &event.content);
+        }
+    }
+    events
+}
@@ -47,6 +47,8 @@ pub struct MockStorage {
     users: Mutex<HashMap<String, UserRecord>>,
     profiles: Mutex<HashMap<String, ProfileRecord>>,
     devices: Mutex<HashMap<String, DeviceRecord>>,
+    /// Dehydrated devices: user_id -> device
+    dehydrated_devices: Mutex<HashMap<String, DehydratedDevice>>,
     healthy: Mutex<bool>,
     rooms: Mutex<HashMap<String, RoomRecord>>,
     membership: Mutex<HashMap<(String, String), String>>,
@@ -60,10 +62,30 @@ pub struct MockStorage {
     device_keys: Mutex<HashMap<(String, String), serde_json::Value>>,
     /// E2EE one-time keys: (user_id, device_id, key_id) -> key data
     one_time_keys: Mutex<HashMap<(String, String, String), serde_json::Value>>,
+    /// E2EE fallback keys: (user_id, device_id, algorithm) -> (key_id, key data, used)
+    fallback_keys: Mutex<HashMap<(String, String, String), (String, serde_json::Value, bool)>>,
     /// E2EE cross-signing keys: (user_id, key_type) -> key data
     cross_signing_keys: Mutex<HashMap<(String, String), serde_json::Value>>,
+    /// Replaced master keys per user, oldest first
+    master_key_history: Mutex<HashMap<String, Vec<ReplacedMasterKey>>>,
+    /// Approved cross-signing resets: user_id -> allowed until
+    cross_signing_resets: Mutex<HashMap<String, chrono::DateTime<chrono::Utc>>>,
+    /// Uploaded signatures on device and master keys
+    key_signatures: Mutex<Vec<KeySignature>>,
+    /// Device list change streams, in insertion order
+    device_list_changes: Mutex<Vec<DeviceListChange>>,
+    /// Cross-signing key changes: (user_id, stream_pos)
+    key_changes: Mutex<Vec<(String, i64)>>,
+    /// Cached remote device lists: user_id -> stream_id
+    remote_device_streams: Mutex<HashMap<String, i64>>,
+    /// Key backup versions, with whether each was deleted
+    key_backup_versions: Mutex<Vec<(KeyBackupVersion, bool)>>,
+    /// Backed-up room keys: (user_id, version) -> keys
+    backed_up_room_keys: Mutex<HashMap<(String, String), Vec<BackedUpRoomKey>>>,
     /// To-device messages: (target_user, target_device, stream_pos, event)
     to_device_messages: Mutex<Vec<(String, String, i64, serde_json::Value)>>,
+    /// Bridged network directories: (appservice_id, network_id, room_id)
+    appservice_rooms: Mutex<Vec<(String, String, String)>>,
     /// Room aliases: alias -> (room_id, creator)
     room_aliases: Mutex<HashMap<String, (String, String)>>,
     /// Forgotten rooms: (user_id, room_id)
@@ -76,14 +98,28 @@ pub struct MockStorage {
     server_keys: Mutex<HashMap<String, ServerKeyRecord>>,
     /// Remote server keys: server_name -> Vec<RemoteKeyRecord>
     remote_keys: Mutex<HashMap<String, Vec<RemoteKeyRecord>>>,
+    /// Cached remote key responses: server_name -> ServerKeyResponseRecord
+    server_key_responses: Mutex<HashMap<String, ServerKeyResponseRecord>>,
+    /// Outbound destination health: server_name -> DestinationRecord
+    destinations: Mutex<HashMap<String, DestinationRecord>>,
     /// Federation transaction dedup: (origin, txn_id)
     federation_txns: Mutex<HashSet<(String, String)>>,
     /// Event relations
     relations: Mutex<Vec<RelationRecord>>,
     /// Event reports
     reports: Mutex<Vec<ReportRecord>>,
+    /// Thread subscriptions: (user_id, room_id, thread_root) -> subscription
+    thread_subscriptions: Mutex<HashMap<(String, String, String), ThreadSubscription>>,
     /// Application service registrations
     appservices: Mutex<Vec<AppServiceRecord>>,
+    /// Application service delivery queues, in queue order
+    appservice_queue: Mutex<Vec<AppServiceQueueItem>>,
+    /// Last assigned application service queue item ID
+    appservice_queue_seq: AtomicI64,
+    /// Application service delivery state: appservice_id -> record
+    appservice_delivery: Mutex<HashMap<String, AppServiceDelivery>>,
+    /// Application service delivery leases: appservice_id -> (holder, expires_at)
+    appservice_leases: Mutex<HashMap<String, (String, chrono::DateTime<chrono::Utc>)>>,
 }
 
 impl MockStorage {
@@ -261,6 +297,7 @@ impl DeviceStore for MockStorage {
     async fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> StorageResult<()> {
         let key = format!("{user_id}:{device_id}");
         self.devices.lock().unwrap().remove(&key);
+        self.forget_removed_dehydrated_devices();
         Ok(())
     }
 
@@ -270,6 +307,7 @@ impl DeviceStore for MockStorage {
             .lock()
             .unwrap()
             .retain(|_, d| d.user_id != user_str);
+        self.forget_removed_dehydrated_devices();
         Ok(())
     }
 
@@ -284,6 +322,7 @@ impl DeviceStore for MockStorage {
             .lock()
             .unwrap()
             .retain(|_, d| d.user_id != user_str || d.device_id == keep);
+        self.forget_removed_dehydrated_devices();
         Ok(())
     }
 
@@ -302,13 +341,49 @@ impl DeviceStore for MockStorage {
             Err(StorageError::NotFound)
         }
     }
+
+    async fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device: &DehydratedDevice,
+    ) -> StorageResult<()> {
+        self.dehydrated_devices
+            .lock()
+            .unwrap()
+            .insert(user_id.to_string(), device.clone());
+        Ok(())
+    }
+
+    async fn get_dehydrated_device(&self, user_id: &UserId) -> StorageResult<DehydratedDevice> {
+        self.dehydrated_devices
+            .lock()
+            .unwrap()
+            .get(user_id.as_str())
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+}
+
+impl MockStorage {
+    /// Forget dehydrated devices whose device record was removed.
+    fn forget_removed_dehydrated_devices(&self) {
+        let devices = self.devices.lock().unwrap();
+        self.dehydrated_devices
+            .lock()
+            .unwrap()
+            .retain(|user_id, d| devices.contains_key(&format!("{user_id}:{}", d.device_id)));
+    }
 }
 
 #[async_trait]
 impl RoomStore for MockStorage {
     async fn create_room(&self, room: &RoomRecord) -> StorageResult<()> {
         let mut rooms = self.rooms.lock().unwrap();
-        if rooms.contains_key(&room.room_id) {
+        if let Some(existing) = rooms.get_mut(&room.room_id) {
+            if existing.peek_only && !room.peek_only {
+                existing.peek_only = false;
+                return Ok(());
+            }
             return Err(StorageError::Duplicate(room.room_id.clone()));
         }
         rooms.insert(room.room_id.clone(), room.clone());
@@ -364,6 +439,15 @@ impl RoomStore for MockStorage {
             .collect())
     }
 
+    async fn get_knocked_rooms(&self, user_id: &str) -> StorageResult<Vec<String>> {
+        let membership = self.membership.lock().unwrap();
+        Ok(membership
+            .iter()
+            .filter(|((uid, _), state)| uid == user_id && *state == Membership::Knock.as_str())
+            .map(|((_, room_id), _)| room_id.clone())
+            .collect())
+    }
+
     async fn get_left_rooms(&self, user_id: &str) -> StorageResult<Vec<String>> {
         let membership = self.membership.lock().unwrap();
         let forgotten = self.forgotten.lock().unwrap();
@@ -469,28 +553,65 @@ impl RoomStore for MockStorage {
         Ok(())
     }
 
+    async fn set_appservice_room_visibility(
+        &self,
+        appservice_id: &str,
+        network_id: &str,
+        room_id: &str,
+        visibility: &str,
+    ) -> StorageResult<()> {
+        let mut entries = self.appservice_rooms.lock().unwrap();
+        let entry = (
+            appservice_id.to_string(),
+            network_id.to_string(),
+            room_id.to_string(),
+        );
+        entries.retain(|e| *e != entry);
+        if visibility == "public" {
+            entries.push(entry);
+        }
+        Ok(())
+    }
+
     async fn get_public_rooms(
         &self,
         limit: usize,
         since: Option<&str>,
         filter: Option<&str>,
+        network: DirectoryNetwork<'_>,
     ) -> StorageResult<(Vec<PublicRoom>, usize)> {
         let rooms = self.rooms.lock().unwrap();
         let room_state = self.room_state.lock().unwrap();
         let membership = self.membership.lock().unwrap();
         let events = self.events.lock().unwrap();
 
-        // Find rooms with visibility = "public"
-        let public_room_ids: Vec<String> = rooms
-            .keys()
-            .filter(|rid| {
-                room_state
+        // Matrix rooms with visibility = "public", then bridged network entries
+        let mut public_room_ids: Vec<String> = Vec::new();
+        if matches!(network, DirectoryNetwork::Matrix | DirectoryNetwork::All) {
+            for rid in rooms.keys() {
+                let is_public = room_state
                     .get(&(rid.to_string(), "__visibility".to_string(), String::new()))
                     .map(|v| v == "public")
-                    .unwrap_or(false)
-            })
-            .cloned()
-            .collect();
+                    .unwrap_or(false);
+                if is_public && !public_room_ids.contains(rid) {
+                    public_room_ids.push(rid.clone());
+                }
+            }
+        }
+        if network != DirectoryNetwork::Matrix {
+            for (as_id, net_id, rid) in self.appservice_rooms.lock().unwrap().iter() {
+                let selected = match network {
+                    DirectoryNetwork::AppService {
+                        appservice_id,
+                        network_id,
+                    } => as_id == appservice_id && net_id == network_id,
+                    _ => true,
+                };
+                if selected && rooms.contains_key(rid) && !public_room_ids.contains(rid) {
+                    public_room_ids.push(rid.clone());
+                }
+            }
+        }
 
         let total = public_room_ids.len();
         let start = since.and_then(|s| s.parse::<usize>().ok()).unwrap_or(0);
@@ -696,7 +817,7 @@ impl EventStore for MockStorage {
                     .filter(|e| e.room_id == room_id && e.stream_position < from)
                     .cloned()
                     .collect();
-                result.sort_by(|a, b| b.stream_position.cmp(&a.stream_position));
+                result.sort_by_key(|e| std::cmp::Reverse(e.stream_position));
                 result.truncate(limit);
                 Ok(result)
             }
@@ -707,7 +828,7 @@ impl EventStore for MockStorage {
                     .filter(|e| e.room_id == room_id && e.stream_position > from)
                     .cloned()
                     .collect();
-                result.sort_by(|a, b| a.stream_position.cmp(&b.stream_position));
+                result.sort_by_key(|a| a.stream_position);
                 result.truncate(limit);
                 Ok(result)
             }
@@ -721,7 +842,13 @@ impl EventStore for MockStorage {
             .filter(|e| e.stream_position > since)
             .cloned()
             .collect();
-        result.sort_by(|a, b| a.stream_position.cmp(&b.stream_position));
+        result.sort_by_key(|a| a.stream_position);
+        Ok(result)
+    }
+
+    async fn get_events_after(&self, since: i64, limit: usize) -> StorageResult<Vec<Pdu>> {
+        let mut result = self.get_events_since(since).await?;
+        result.truncate(limit);
         Ok(result)
     }
 
@@ -961,6 +1088,27 @@ impl KeyStore for MockStorage {
         Ok(serde_json::Value::Object(result))
     }
 
+    async fn delete_device_keys(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+    ) -> StorageResult<()> {
+        let keep = |u: &String, d: &String| u != user_id || device_id.is_some_and(|id| id != d);
+        self.device_keys
+            .lock()
+            .unwrap()
+            .retain(|(u, d), _| keep(u, d));
+        self.one_time_keys
+            .lock()
+            .unwrap()
+            .retain(|(u, d, _), _| keep(u, d));
+        self.fallback_keys
+            .lock()
+            .unwrap()
+            .retain(|(u, d, _), _| keep(u, d));
+        Ok(())
+    }
+
     async fn store_one_time_keys(
         &self,
         user_id: &str,
@@ -1026,6 +1174,16 @@ impl KeyStore for MockStorage {
                                 user_result
                                     .insert(did.clone(), serde_json::Value::Object(device_keys));
                             }
+                        } else if let Some((key_id, key_data, used)) = self
+                            .fallback_keys
+                            .lock()
+                            .unwrap()
+                            .get_mut(&(uid.clone(), did.clone(), algo.to_string()))
+                        {
+                            *used = true;
+                            let mut device_keys = serde_json::Map::new();
+                            device_keys.insert(key_id.clone(), key_data.clone());
+                            user_result.insert(did.clone(), serde_json::Value::Object(device_keys));
                         }
                     }
                 }
@@ -1038,6 +1196,45 @@ impl KeyStore for MockStorage {
         Ok(serde_json::Value::Object(result))
     }
 
+    async fn store_fallback_keys(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        keys: &serde_json::Value,
+    ) -> StorageResult<()> {
+        let mut map = self.fallback_keys.lock().unwrap();
+        for (key_id, key_data) in keys.as_object().into_iter().flatten() {
+            let algorithm = key_id.split(':').next().unwrap_or_default();
+            let slot = (
+                user_id.to_string(),
+                device_id.to_string(),
+                algorithm.to_string(),
+            );
+            let used = map
+                .get(&slot)
+                .is_some_and(|(current, _, used)| current == key_id && *used);
+            map.insert(slot, (key_id.clone(), key_data.clone(), used));
+        }
+        Ok(())
+    }
+
+    async fn get_unused_fallback_key_types(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> StorageResult<Vec<String>> {
+        let mut types: Vec<String> = self
+            .fallback_keys
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((u, d, _), (_, _, used))| u == user_id && d == device_id && !used)
+            .map(|((_, _, algorithm), _)| algorithm.clone())
+            .collect();
+        types.sort();
+        Ok(types)
+    }
+
     async fn set_cross_signing_keys(
         &self,
         user_id: &str,
@@ -1046,7 +1243,22 @@ impl KeyStore for MockStorage {
         let mut map = self.cross_signing_keys.lock().unwrap();
         if let Some(obj) = keys.as_object() {
             for (key_type, key_data) in obj {
-                map.insert((user_id.to_string(), key_type.clone()), key_data.clone());
+                let previous =
+                    map.insert((user_id.to_string(), key_type.clone()), key_data.clone());
+                if key_type == "master_key"
+                    && let Some(previous) = previous
+                    && previous.get("keys") != key_data.get("keys")
+                {
+                    self.master_key_history
+                        .lock()
+                        .unwrap()
+                        .entry(user_id.to_string())
+                        .or_default()
+                        .push(ReplacedMasterKey {
+                            key: previous,
+                            replaced_at: chrono::Utc::now(),
+                        });
+                }
             }
         }
         Ok(())
@@ -1062,6 +1274,326 @@ impl KeyStore for MockStorage {
         }
         Ok(serde_json::Value::Object(result))
     }
+
+    async fn get_master_key_history(&self, user_id: &str) -> StorageResult<Vec<ReplacedMasterKey>> {
+        Ok(self
+            .master_key_history
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> StorageResult<()> {
+        let mut resets = self.cross_signing_resets.lock().unwrap();
+        match until {
+            Some(until) => resets.insert(user_id.to_string(), until),
+            None => resets.remove(user_id),
+        };
+        Ok(())
+    }
+
+    async fn get_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+    ) -> StorageResult<Option<chrono::DateTime<chrono::Utc>>> {
+        Ok(self
+            .cross_signing_resets
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .copied())
+    }
+
+    async fn add_key_signature(&self, signature: &KeySignature) -> StorageResult<()> {
+        let mut signatures = self.key_signatures.lock().unwrap();
+        signatures.retain(|s| {
+            !(s.signed_user_id == signature.signed_user_id
+                && s.signed_key_id == signature.signed_key_id
+                && s.signing_user_id == signature.signing_user_id
+                && s.signing_key_id == signature.signing_key_id)
+        });
+        signatures.push(signature.clone());
+        Ok(())
+    }
+
+    async fn get_key_signatures(&self, signed_user_id: &str) -> StorageResult<Vec<KeySignature>> {
+        Ok(self
+            .key_signatures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.signed_user_id == signed_user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_key_signatures(
+        &self,
+        signed_user_id: &str,
+        signed_key_id: &str,
+    ) -> StorageResult<()> {
+        self.key_signatures
+            .lock()
+            .unwrap()
+            .retain(|s| !(s.signed_user_id == signed_user_id && s.signed_key_id == signed_key_id));
+        Ok(())
+    }
+
+    async fn add_device_list_change(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        deleted: bool,
+    ) -> StorageResult<DeviceListChange> {
+        let stream_pos = self.next_stream_position().await?;
+        let mut changes = self.device_list_changes.lock().unwrap();
+        let stream_id = changes
+            .iter()
+            .filter(|c| c.user_id == user_id)
+            .map(|c| c.stream_id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let change = DeviceListChange {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            stream_id,
+            stream_pos,
+            deleted,
+        };
+        changes.push(change.clone());
+        Ok(change)
+    }
+
+    async fn get_device_list_stream_id(&self, user_id: &str) -> StorageResult<i64> {
+        Ok(self
+            .device_list_changes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.user_id == user_id)
+            .map(|c| c.stream_id)
+            .max()
+            .unwrap_or(0))
+    }
+
+    async fn get_device_list_changed_users(
+        &self,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<String>> {
+        let changes = self.device_list_changes.lock().unwrap();
+        let key_changes = self.key_changes.lock().unwrap();
+        let users: HashSet<String> = changes
+            .iter()
+            .filter(|c| (from..=to).contains(&c.stream_pos))
+            .map(|c| c.user_id.clone())
+            .chain(
+                key_changes
+                    .iter()
+                    .filter(|(_, pos)| (from..=to).contains(pos))
+                    .map(|(user_id, _)| user_id.clone()),
+            )
+            .collect();
+        Ok(users.into_iter().collect())
+    }
+
+    async fn add_key_change(&self, user_id: &str) -> StorageResult<i64> {
+        let stream_pos = self.next_stream_position().await?;
+        self.key_changes
+            .lock()
+            .unwrap()
+            .push((user_id.to_string(), stream_pos));
+        Ok(stream_pos)
+    }
+
+    async fn get_remote_device_stream_id(&self, user_id: &str) -> StorageResult<Option<i64>> {
+        Ok(self
+            .remote_device_streams
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .copied())
+    }
+
+    async fn set_remote_device_stream_id(
+        &self,
+        user_id: &str,
+        stream_id: Option<i64>,
+    ) -> StorageResult<()> {
+        let mut streams = self.remote_device_streams.lock().unwrap();
+        match stream_id {
+            Some(stream_id) => streams.insert(user_id.to_string(), stream_id),
+            None => streams.remove(user_id),
+        };
+        Ok(())
+    }
+}
+
+impl MockStorage {
+    /// Change the etag of a live key backup version.
+    fn bump_key_backup_etag(&self, user_id: &str, version: &str) -> StorageResult<()> {
+        let mut versions = self.key_backup_versions.lock().unwrap();
+        let (record, _) = versions
+            .iter_mut()
+            .find(|(v, deleted)| !deleted && v.user_id == user_id && v.version == version)
+            .ok_or(StorageError::NotFound)?;
+        record.etag += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl KeyBackupStore for MockStorage {
+    async fn create_key_backup_version(
+        &self,
+        user_id: &str,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<String> {
+        let mut versions = self.key_backup_versions.lock().unwrap();
+        let next = versions
+            .iter()
+            .filter(|(v, _)| v.user_id == user_id)
+            .filter_map(|(v, _)| v.version.parse::<i64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let version = next.to_string();
+        versions.push((
+            KeyBackupVersion {
+                user_id: user_id.to_string(),
+                version: version.clone(),
+                algorithm: algorithm.to_string(),
+                auth_data: auth_data.clone(),
+                etag: 0,
+            },
+            false,
+        ));
+        Ok(version)
+    }
+
+    async fn get_key_backup_version(
+        &self,
+        user_id: &str,
+        version: Option<&str>,
+    ) -> StorageResult<KeyBackupVersion> {
+        let versions = self.key_backup_versions.lock().unwrap();
+        let mut live = versions
+            .iter()
+            .filter(|(v, deleted)| !deleted && v.user_id == user_id)
+            .map(|(v, _)| v);
+        match version {
+            Some(version) => live.find(|v| v.version == version),
+            None => live.max_by_key(|v| v.version.parse::<i64>().unwrap_or(0)),
+        }
+        .cloned()
+        .ok_or(StorageError::NotFound)
+    }
+
+    async fn update_key_backup_version(
+        &self,
+        user_id: &str,
+        version: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<()> {
+        let mut versions = self.key_backup_versions.lock().unwrap();
+        let (record, _) = versions
+            .iter_mut()
+            .find(|(v, deleted)| !deleted && v.user_id == user_id && v.version == version)
+            .ok_or(StorageError::NotFound)?;
+        record.auth_data = auth_data.clone();
+        Ok(())
+    }
+
+    async fn delete_key_backup_version(&self, user_id: &str, version: &str) -> StorageResult<()> {
+        let mut versions = self.key_backup_versions.lock().unwrap();
+        let (_, deleted) = versions
+            .iter_mut()
+            .find(|(v, deleted)| !deleted && v.user_id == user_id && v.version == version)
+            .ok_or(StorageError::NotFound)?;
+        *deleted = true;
+        self.backed_up_room_keys
+            .lock()
+            .unwrap()
+            .remove(&(user_id.to_string(), version.to_string()));
+        Ok(())
+    }
+
+    async fn put_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        keys: &[BackedUpRoomKey],
+    ) -> StorageResult<()> {
+        self.bump_key_backup_etag(user_id, version)?;
+        let mut all_keys = self.backed_up_room_keys.lock().unwrap();
+        let stored = all_keys
+            .entry((user_id.to_string(), version.to_string()))
+            .or_default();
+        for key in keys {
+            stored.retain(|k| !(k.room_id == key.room_id && k.session_id == key.session_id));
+            stored.push(key.clone());
+        }
+        Ok(())
+    }
+
+    async fn get_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<Vec<BackedUpRoomKey>> {
+        Ok(self
+            .backed_up_room_keys
+            .lock()
+            .unwrap()
+            .get(&(user_id.to_string(), version.to_string()))
+            .into_iter()
+            .flatten()
+            .filter(|k| room_id.is_none_or(|r| k.room_id == r))
+            .filter(|k| session_id.is_none_or(|s| k.session_id == s))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<()> {
+        self.bump_key_backup_etag(user_id, version)?;
+        if let Some(keys) = self
+            .backed_up_room_keys
+            .lock()
+            .unwrap()
+            .get_mut(&(user_id.to_string(), version.to_string()))
+        {
+            keys.retain(|k| {
+                !(room_id.is_none_or(|r| k.room_id == r)
+                    && session_id.is_none_or(|s| k.session_id == s))
+            });
+        }
+        Ok(())
+    }
+
+    async fn count_backed_up_room_keys(&self, user_id: &str, version: &str) -> StorageResult<i64> {
+        Ok(self
+            .backed_up_room_keys
+            .lock()
+            .unwrap()
+            .get(&(user_id.to_string(), version.to_string()))
+            .map_or(0, |keys| keys.len() as i64))
+    }
 }
 
 #[async_trait]
@@ -1094,11 +1626,14 @@ impl ToDeviceStore for MockStorage {
         user_id: &str,
         device_id: &str,
         since: i64,
+        up_to: i64,
     ) -> StorageResult<Vec<serde_json::Value>> {
         let msgs = self.to_device_messages.lock().unwrap();
         Ok(msgs
             .iter()
-            .filter(|(u, d, pos, _)| u == user_id && d == device_id && *pos > since)
+            .filter(|(u, d, pos, _)| {
+                u == user_id && d == device_id && *pos > since && *pos <= up_to
+            })
             .map(|(_, _, _, event)| event.clone())
             .collect())
     }
@@ -1277,6 +1812,55 @@ impl RelationStore for MockStorage {
         Ok(roots)
     }
 
+    async fn set_thread_subscription(
+        &self,
+        user_id: &str,
+        subscription: &ThreadSubscription,
+    ) -> StorageResult<()> {
+        self.thread_subscriptions.lock().unwrap().insert(
+            (
+                user_id.to_string(),
+                subscription.room_id.clone(),
+                subscription.thread_root.clone(),
+            ),
+            subscription.clone(),
+        );
+        Ok(())
+    }
+
+    async fn get_thread_subscription(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        thread_root: &str,
+    ) -> StorageResult<ThreadSubscription> {
+        self.thread_subscriptions
+            .lock()
+            .unwrap()
+            .get(&(
+                user_id.to_string(),
+                room_id.to_string(),
+                thread_root.to_string(),
+            ))
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn get_thread_subscriptions(
+        &self,
+        user_id: &str,
+        since: i64,
+    ) -> StorageResult<Vec<ThreadSubscription>> {
+        Ok(self
+            .thread_subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((uid, _, _), sub)| uid == user_id && sub.bump_stamp > since)
+            .map(|(_, sub)| sub.clone())
+            .collect())
+    }
+
     async fn store_report(&self, report: &ReportRecord) -> StorageResult<()> {
         self.reports.lock().unwrap().push(report.clone());
         Ok(())
@@ -1298,16 +1882,41 @@ impl FederationKeyStore for MockStorage {
 
     async fn get_active_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>> {
         let store = self.server_keys.lock().unwrap();
-        Ok(store.values().cloned().collect())
+        let mut keys: Vec<ServerKeyRecord> = store
+            .values()
+            .filter(|k| k.expired_at.is_none())
+            .cloned()
+            .collect();
+        keys.sort_by_key(|k| std::cmp::Reverse(k.valid_until));
+        Ok(keys)
+    }
+
+    async fn get_expired_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>> {
+        let store = self.server_keys.lock().unwrap();
+        Ok(store
+            .values()
+            .filter(|k| k.expired_at.is_some())
+            .cloned()
+            .collect())
+    }
+
+    async fn expire_server_key(
+        &self,
+        key_id: &str,
+        expired_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<()> {
+        let mut store = self.server_keys.lock().unwrap();
+        let key = store.get_mut(key_id).ok_or(StorageError::NotFound)?;
+        key.expired_at = Some(expired_at);
+        Ok(())
     }
 
     async fn store_remote_server_keys(&self, keys: &[RemoteKeyRecord]) -> StorageResult<()> {
         let mut store = self.remote_keys.lock().unwrap();
         for key in keys {
-            store
-                .entry(key.server_name.clone())
-                .or_default()
-                .push(key.clone());
+            let cached = store.entry(key.server_name.clone()).or_default();
+            cached.retain(|k| k.key_id != key.key_id);
+            cached.push(key.clone());
         }
         Ok(())
     }
@@ -1320,6 +1929,26 @@ impl FederationKeyStore for MockStorage {
         Ok(store.get(server_name).cloned().unwrap_or_default())
     }
 
+    async fn store_server_key_response(
+        &self,
+        record: &ServerKeyResponseRecord,
+    ) -> StorageResult<()> {
+        let mut store = self.server_key_responses.lock().unwrap();
+        store.insert(record.server_name.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn get_server_key_response(
+        &self,
+        server_name: &str,
+    ) -> StorageResult<ServerKeyResponseRecord> {
+        let store = self.server_key_responses.lock().unwrap();
+        store
+            .get(server_name)
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
     async fn store_federation_txn(&self, origin: &str, txn_id: &str) -> StorageResult<()> {
         let mut store = self.federation_txns.lock().unwrap();
         let key = (origin.to_string(), txn_id.to_string());
@@ -1339,6 +1968,27 @@ impl FederationKeyStore for MockStorage {
         // Mock storage does not track timestamps -- no-op.
         Ok(0)
     }
+
+    async fn upsert_destination(&self, record: &DestinationRecord) -> StorageResult<()> {
+        let mut store = self.destinations.lock().unwrap();
+        store.insert(record.server_name.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn get_destination(&self, server_name: &str) -> StorageResult<DestinationRecord> {
+        let store = self.destinations.lock().unwrap();
+        store
+            .get(server_name)
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn list_destinations(&self) -> StorageResult<Vec<DestinationRecord>> {
+        let store = self.destinations.lock().unwrap();
+        let mut destinations: Vec<DestinationRecord> = store.values().cloned().collect();
+        destinations.sort_by(|a, b| a.server_name.cmp(&b.server_name));
+        Ok(destinations)
+    }
 }
 
 #[async_trait]
@@ -1372,7 +2022,7 @@ impl MediaStore for MockStorage {
             .filter(|m| m.user_id == user_id)
             .cloned()
             .collect();
-        records.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        records.sort_by_key(|r| std::cmp::Reverse(r.created_at));
         records.truncate(limit);
         Ok(records)
     }
@@ -1408,7 +2058,7 @@ impl MediaStore for MockStorage {
             .filter(|m| m.created_at < before)
             .cloned()
             .collect();
-        records.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        records.sort_by_key(|a| a.created_at);
         records.truncate(limit);
         Ok(records)
     }
@@ -1455,8 +2105,91 @@ impl ApplicationServiceStore for MockStorage {
         if store.len() == before {
             return Err(StorageError::NotFound);
         }
+        self.appservice_queue
+            .lock()
+            .unwrap()
+            .retain(|item| item.appservice_id != id);
+        self.appservice_delivery.lock().unwrap().remove(id);
+        self.appservice_leases.lock().unwrap().remove(id);
         Ok(())
     }
+
+    async fn enqueue_appservice_item(
+        &self,
+        appservice_id: &str,
+        kind: AppServiceItemKind,
+        content: &serde_json::Value,
+    ) -> StorageResult<i64> {
+        let mut queue = self.appservice_queue.lock().unwrap();
+        let id = self.appservice_queue_seq.fetch_add(1, Ordering::SeqCst) + 1;
+        queue.push(AppServiceQueueItem {
+            id,
+            appservice_id: appservice_id.to_string(),
+            kind,
+            content: content.clone(),
+        });
+        Ok(id)
+    }
+
+    async fn get_appservice_queue(
+        &self,
+        appservice_id: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<AppServiceQueueItem>> {
+        Ok(self
+            .appservice_queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|item| item.appservice_id == appservice_id)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_appservice_queue(&self, appservice_id: &str, up_to: i64) -> StorageResult<()> {
+        self.appservice_queue
+            .lock()
+            .unwrap()
+            .retain(|item| item.appservice_id != appservice_id || item.id > up_to);
+        Ok(())
+    }
+
+    async fn upsert_appservice_delivery(&self, record: &AppServiceDelivery) -> StorageResult<()> {
+        self.appservice_delivery
+            .lock()
+            .unwrap()
+            .insert(record.appservice_id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn get_appservice_delivery(
+        &self,
+        appservice_id: &str,
+    ) -> StorageResult<AppServiceDelivery> {
+        self.appservice_delivery
+            .lock()
+            .unwrap()
+            .get(appservice_id)
+            .cloned()
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn acquire_appservice_lease(
+        &self,
+        appservice_id: &str,
+        holder: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<bool> {
+        let mut leases = self.appservice_leases.lock().unwrap();
+        let free = leases
+            .get(appservice_id)
+            .is_none_or(|(h, until)| h == holder || *until < chrono::Utc::now());
+        if free {
+            leases.insert(appservice_id.to_string(), (holder.to_string(), expires_at));
+        }
+        Ok(free)
+    }
 }
 
 #[async_trait]
@@ -3,9 +3,16 @@
 //! Manages application service (bridge/bot) registration records in the
 //! `appservice` table.  Each record holds authentication tokens, the AS
 //! URL, namespace patterns (serialized as JSON strings), and protocol info.
+//!
+//! Delivery uses two more tables: `appservice_queue` holds the items waiting
+//! to be pushed to each AS, ordered by a `seq` drawn from the
+//! `stream_counter:appservice_queue` counter, and `appservice_delivery` holds
+//! one row per AS with its stream position, outstanding transaction and
+//! backoff state.  `appservice_lease` records which node delivers to each AS,
+//! so that only one node of a cluster does.
 
 use async_trait::async_trait;
-use surrealdb::types::SurrealValue;
+use surrealdb::types::{Datetime, RecordId, SurrealValue};
 use tracing::debug;
 
 use super::SurrealStorage;
@@ -35,7 +42,10 @@ impl ApplicationServiceStore for SurrealStorage {
                      user_namespaces: $user_namespaces, \
                      alias_namespaces: $alias_namespaces, \
                      rate_limited: $rate_limited, \
-                     protocols: $protocols \
+                     protocols: $protocols, \
+                     receive_ephemeral: $receive_ephemeral, \
+                     msc3202: $msc3202, \
+                     registration_file: $registration_file \
                  }",
             )
             .bind(("id", record.id.clone()))
@@ -47,6 +57,9 @@ impl ApplicationServiceStore for SurrealStorage {
             .bind(("alias_namespaces", alias_ns))
             .bind(("rate_limited", record.rate_limited))
             .bind(("protocols", protocols))
+            .bind(("receive_ephemeral", record.receive_ephemeral))
+            .bind(("msc3202", record.msc3202))
+            .bind(("registration_file", record.registration_file.clone()))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
@@ -108,7 +121,12 @@ impl ApplicationServiceStore for SurrealStorage {
 
         let mut response = self
             .db()
-            .query("DELETE appservice WHERE id = $id RETURN BEFORE")
+            .query(
+                "DELETE appservice WHERE id = $id RETURN BEFORE; \
+                 DELETE appservice_queue WHERE appservice_id = $id; \
+                 DELETE appservice_delivery WHERE appservice_id = $id; \
+                 DELETE appservice_lease WHERE appservice_id = $id",
+            )
             .bind(("id", id.to_string()))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
@@ -123,6 +141,216 @@ impl ApplicationServiceStore for SurrealStorage {
 
         Ok(())
     }
+
+    async fn enqueue_appservice_item(
+        &self,
+        appservice_id: &str,
+        kind: AppServiceItemKind,
+        content: &serde_json::Value,
+    ) -> StorageResult<i64> {
+        let mut response = self
+            .db()
+            .query("UPDATE $rid SET position += 1 RETURN AFTER")
+            .bind(("rid", RecordId::new("stream_counter", "appservice_queue")))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let rows: Vec<PositionRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let seq = rows.into_iter().next().map(|r| r.position).ok_or_else(|| {
+            StorageError::Internal("stream_counter:appservice_queue not found".to_string())
+        })?;
+
+        self.db()
+            .query(
+                "CREATE appservice_queue SET \
+                 appservice_id = $appservice_id, seq = $seq, kind = $kind, content = $content",
+            )
+            .bind(("appservice_id", appservice_id.to_string()))
+            .bind(("seq", seq))
+            .bind(("kind", kind.as_str().to_string()))
+            .bind(("content", content.clone()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(seq)
+    }
+
+    async fn get_appservice_queue(
+        &self,
+        appservice_id: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<AppServiceQueueItem>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT appservice_id, seq, kind, content FROM appservice_queue \
+                 WHERE appservice_id = $appservice_id ORDER BY seq LIMIT $limit",
+            )
+            .bind(("appservice_id", appservice_id.to_string()))
+            .bind(("limit", limit as i64))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<QueueRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        rows.into_iter()
+            .map(|r| {
+                let kind = AppServiceItemKind::parse(&r.kind).ok_or_else(|| {
+                    StorageError::Serialization(format!("Unknown queue item kind: {}", r.kind))
+                })?;
+                Ok(AppServiceQueueItem {
+                    id: r.seq,
+                    appservice_id: r.appservice_id,
+                    kind,
+                    content: r.content,
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_appservice_queue(&self, appservice_id: &str, up_to: i64) -> StorageResult<()> {
+        self.db()
+            .query("DELETE appservice_queue WHERE appservice_id = $appservice_id AND seq <= $up_to")
+            .bind(("appservice_id", appservice_id.to_string()))
+            .bind(("up_to", up_to))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn upsert_appservice_delivery(&self, record: &AppServiceDelivery) -> StorageResult<()> {
+        self.db()
+            .query(
+                "INSERT INTO appservice_delivery { \
+                 appservice_id: $id, stream_position: $sp, txn_id: $txn, \
+                 in_flight_up_to: $up_to, last_success_at: $ls, last_failure_at: $lf, \
+                 failure_count: $fc, retry_at: $ra, last_error: $le \
+                 } ON DUPLICATE KEY UPDATE \
+                 stream_position = $sp, txn_id = $txn, in_flight_up_to = $up_to, \
+                 last_success_at = $ls, last_failure_at = $lf, failure_count = $fc, \
+                 retry_at = $ra, last_error = $le",
+            )
+            .bind(("id", record.appservice_id.clone()))
+            .bind(("sp", record.stream_position))
+            .bind(("txn", record.txn_id))
+            .bind(("up_to", record.in_flight_up_to))
+            .bind(("ls", record.last_success_at.map(Datetime::from)))
+            .bind(("lf", record.last_failure_at.map(Datetime::from)))
+            .bind(("fc", i64::from(record.failure_count)))
+            .bind(("ra", record.retry_at.map(Datetime::from)))
+            .bind(("le", record.last_error.clone()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_appservice_delivery(
+        &self,
+        appservice_id: &str,
+    ) -> StorageResult<AppServiceDelivery> {
+        let mut response = self
+            .db()
+            .query("SELECT * FROM appservice_delivery WHERE appservice_id = $id LIMIT 1")
+            .bind(("id", appservice_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<DeliveryRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        rows.into_iter()
+            .next()
+            .map(AppServiceDelivery::from)
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn acquire_appservice_lease(
+        &self,
+        appservice_id: &str,
+        holder: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<bool> {
+        // Clear our own or an expired lease, then take it unless someone else
+        // still holds one.
+        self.db()
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE appservice_lease WHERE appservice_id = $id \
+                     AND (holder = $holder OR expires_at < time::now()); \
+                 INSERT INTO appservice_lease { \
+                     appservice_id: $id, holder: $holder, expires_at: $expires_at \
+                 } ON DUPLICATE KEY UPDATE holder = holder; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("id", appservice_id.to_string()))
+            .bind(("holder", holder.to_string()))
+            .bind(("expires_at", Datetime::from(expires_at)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let mut response = self
+            .db()
+            .query("SELECT holder FROM appservice_lease WHERE appservice_id = $id LIMIT 1")
+            .bind(("id", appservice_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let rows: Vec<LeaseRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().next().is_some_and(|r| r.holder == holder))
+    }
+}
+
+#[derive(Debug, Clone, SurrealValue)]
+struct LeaseRow {
+    holder: String,
+}
+
+#[derive(Debug, Clone, SurrealValue)]
+struct PositionRow {
+    position: i64,
+}
+
+#[derive(Debug, Clone, SurrealValue)]
+struct QueueRow {
+    appservice_id: String,
+    seq: i64,
+    kind: String,
+    content: serde_json::Value,
+}
+
+#[derive(Debug, Clone, SurrealValue)]
+struct DeliveryRow {
+    appservice_id: String,
+    stream_position: i64,
+    txn_id: i64,
+    in_flight_up_to: Option<i64>,
+    last_success_at: Option<Datetime>,
+    last_failure_at: Option<Datetime>,
+    failure_count: i64,
+    retry_at: Option<Datetime>,
+    last_error: Option<String>,
+}
+
+impl From<DeliveryRow> for AppServiceDelivery {
+    fn from(r: DeliveryRow) -> Self {
+        AppServiceDelivery {
+            appservice_id: r.appservice_id,
+            stream_position: r.stream_position,
+            txn_id: r.txn_id,
+            in_flight_up_to: r.in_flight_up_to,
+            last_success_at: r.last_success_at.map(|d| d.into_inner()),
+            last_failure_at: r.last_failure_at.map(|d| d.into_inner()),
+            failure_count: r.failure_count.max(0) as u32,
+            retry_at: r.retry_at.map(|d| d.into_inner()),
+            last_error: r.last_error,
+        }
+    }
 }
 
 /// Internal row type for deserializing from SurrealDB.
@@ -140,6 +368,9 @@ struct AppServiceRow {
     alias_namespaces: String,
     rate_limited: bool,
     protocols: String,
+    receive_ephemeral: bool,
+    msc3202: bool,
+    registration_file: Option<String>,
 }
 
 impl AppServiceRow {
@@ -161,6 +392,9 @@ impl AppServiceRow {
             alias_namespaces,
             rate_limited: self.rate_limited,
             protocols,
+            receive_ephemeral: self.receive_ephemeral,
+            msc3202: self.msc3202,
+            registration_file: self.registration_file,
         })
     }
 }
@@ -6,6 +6,10 @@
 //!
 //! Bulk operations (`remove_all_devices`, `remove_all_devices_except`) are used
 //! during logout-all and password-change flows.
+//!
+//! The `dehydrated_device` table holds at most one row per user, naming their
+//! dehydrated device and its pickle.  Removing a device deletes its row in the
+//! same transaction.
 
 use async_trait::async_trait;
 use surrealdb::types::{Datetime, RecordId, RecordIdKey, SurrealValue};
@@ -36,6 +40,13 @@ struct DeviceRow {
     created_at: Datetime,
 }
 
+/// Row returned when reading a dehydrated device.
+#[derive(Debug, Clone, SurrealValue)]
+struct DehydratedDeviceRow {
+    device_id: String,
+    device_data: serde_json::Value,
+}
+
 /// Extract the string key from a RecordId (e.g. `user:alice` -> `"alice"`).
 fn record_key_to_string(rid: &RecordId) -> String {
     match &rid.key {
@@ -190,8 +201,14 @@ impl DeviceStore for SurrealStorage {
         debug!(device_id = %device_id, user = %user_id.localpart(), "Removing device");
 
         self.db()
-            .query("DELETE FROM device WHERE user = $user_rid AND device_id = $did")
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE FROM device WHERE user = $user_rid AND device_id = $did; \
+                 DELETE dehydrated_device WHERE user_id = $uid AND device_id = $did; \
+                 COMMIT TRANSACTION;",
+            )
             .bind(("user_rid", user_rid))
+            .bind(("uid", user_id.to_string()))
             .bind(("did", did))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
@@ -204,8 +221,14 @@ impl DeviceStore for SurrealStorage {
         debug!(user = %user_id.localpart(), "Removing all devices");
 
         self.db()
-            .query("DELETE FROM device WHERE user = $user_rid")
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE FROM device WHERE user = $user_rid; \
+                 DELETE dehydrated_device WHERE user_id = $uid; \
+                 COMMIT TRANSACTION;",
+            )
             .bind(("user_rid", user_rid))
+            .bind(("uid", user_id.to_string()))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
@@ -222,8 +245,14 @@ impl DeviceStore for SurrealStorage {
         debug!(user = %user_id.localpart(), keep_device = %keep, "Removing all devices except one");
 
         self.db()
-            .query("DELETE FROM device WHERE user = $user_rid AND device_id != $keep")
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE FROM device WHERE user = $user_rid AND device_id != $keep; \
+                 DELETE dehydrated_device WHERE user_id = $uid AND device_id != $keep; \
+                 COMMIT TRANSACTION;",
+            )
             .bind(("user_rid", user_rid))
+            .bind(("uid", user_id.to_string()))
             .bind(("keep", keep))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
@@ -251,4 +280,50 @@ impl DeviceStore for SurrealStorage {
 
         Ok(())
     }
+
+    async fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device: &DehydratedDevice,
+    ) -> StorageResult<()> {
+        debug!(user = %user_id.localpart(), device_id = %device.device_id, "Setting dehydrated device");
+
+        self.db()
+            .query(
+                "INSERT INTO dehydrated_device { \
+                     user_id: $uid, device_id: $did, device_data: $data \
+                 } ON DUPLICATE KEY UPDATE device_id = $did, device_data = $data",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("did", device.device_id.clone()))
+            .bind(("data", device.device_data.clone()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get_dehydrated_device(&self, user_id: &UserId) -> StorageResult<DehydratedDevice> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT device_id, device_data FROM dehydrated_device \
+                 WHERE user_id = $uid LIMIT 1",
+            )
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<DehydratedDeviceRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        rows.into_iter()
+            .next()
+            .map(|row| DehydratedDevice {
+                device_id: row.device_id,
+                device_data: row.device_data,
+            })
+            .ok_or(StorageError::NotFound)
+    }
 }
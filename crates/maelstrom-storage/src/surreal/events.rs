@@ -244,6 +244,25 @@ impl EventStore for SurrealStorage {
         Ok(events)
     }
 
+    async fn get_events_after(&self, since: i64, limit: usize) -> StorageResult<Vec<Pdu>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT * FROM event WHERE stream_position > $since \
+                 ORDER BY stream_position ASC LIMIT $lim",
+            )
+            .bind(("since", since))
+            .bind(("lim", limit as i64))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<EventRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(|r| r.into_pdu()).collect())
+    }
+
     async fn set_room_state(
         &self,
         room_id: &str,
@@ -1,14 +1,19 @@
 //! Federation-related storage -- [`FederationKeyStore`](crate::traits::FederationKeyStore) implementation.
 //!
-//! Manages three concerns:
+//! Manages five concerns:
 //!
 //! 1. **Server signing keys** (`server_key` table) -- this server's own
 //!    ed25519 key pairs used to sign outgoing federation requests and events.
 //! 2. **Remote server keys** (`remote_key` table) -- cached public keys
 //!    fetched from other homeservers, used to verify incoming signatures.
-//! 3. **Transaction deduplication** (`federation_txn` table) -- tracks
+//! 3. **Remote key responses** (`remote_key_response` table) -- the full signed
+//!    `/_matrix/key/v2/server` response of each remote server, served back
+//!    unmodified (plus our co-signature) when acting as a notary.
+//! 4. **Transaction deduplication** (`federation_txn` table) -- tracks
 //!    `(origin, txn_id)` pairs so that replayed federation transactions are
 //!    rejected.
+//! 5. **Destination health** (`federation_destination` table) -- one row per
+//!    outbound destination with its backoff state, last error and queue lag.
 
 use async_trait::async_trait;
 use surrealdb::types::{Datetime, SurrealValue};
@@ -24,6 +29,20 @@ struct ServerKeyRow {
     public_key: String,
     private_key: String,
     valid_until: Datetime,
+    expired_at: Option<Datetime>,
+}
+
+impl From<ServerKeyRow> for ServerKeyRecord {
+    fn from(r: ServerKeyRow) -> Self {
+        ServerKeyRecord {
+            key_id: r.key_id,
+            algorithm: r.algorithm,
+            public_key: r.public_key,
+            private_key: r.private_key,
+            valid_until: r.valid_until.into_inner(),
+            expired_at: r.expired_at.map(|d| d.into_inner()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, SurrealValue)]
@@ -34,6 +53,43 @@ struct RemoteKeyRow {
     valid_until: Datetime,
 }
 
+#[derive(Debug, Clone, SurrealValue)]
+struct KeyResponseRow {
+    server_name: String,
+    response: serde_json::Value,
+    valid_until: Datetime,
+    fetched_at: Datetime,
+}
+
+#[derive(Debug, Clone, SurrealValue)]
+struct DestinationRow {
+    server_name: String,
+    last_success_at: Option<Datetime>,
+    last_failure_at: Option<Datetime>,
+    failure_count: i64,
+    retry_at: Option<Datetime>,
+    last_error: Option<String>,
+    pending_pdus: i64,
+    pending_edus: i64,
+    oldest_pending_pdu_ts: Option<i64>,
+}
+
+impl From<DestinationRow> for DestinationRecord {
+    fn from(r: DestinationRow) -> Self {
+        DestinationRecord {
+            server_name: r.server_name,
+            last_success_at: r.last_success_at.map(|d| d.into_inner()),
+            last_failure_at: r.last_failure_at.map(|d| d.into_inner()),
+            failure_count: r.failure_count.max(0) as u32,
+            retry_at: r.retry_at.map(|d| d.into_inner()),
+            last_error: r.last_error,
+            pending_pdus: r.pending_pdus.max(0) as u64,
+            pending_edus: r.pending_edus.max(0) as u64,
+            oldest_pending_pdu_ts: r.oldest_pending_pdu_ts,
+        }
+    }
+}
+
 #[async_trait]
 impl FederationKeyStore for SurrealStorage {
     async fn store_server_key(&self, key: &ServerKeyRecord) -> StorageResult<()> {
@@ -46,13 +102,15 @@ impl FederationKeyStore for SurrealStorage {
                  algorithm = $algo, \
                  public_key = $pub_key, \
                  private_key = $priv_key, \
-                 valid_until = $valid_until",
+                 valid_until = $valid_until, \
+                 expired_at = $expired_at",
             )
             .bind(("key_id", key.key_id.clone()))
             .bind(("algo", key.algorithm.clone()))
             .bind(("pub_key", key.public_key.clone()))
             .bind(("priv_key", key.private_key.clone()))
             .bind(("valid_until", Datetime::from(key.valid_until)))
+            .bind(("expired_at", key.expired_at.map(Datetime::from)))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
@@ -73,20 +131,14 @@ impl FederationKeyStore for SurrealStorage {
 
         rows.into_iter()
             .next()
-            .map(|r| ServerKeyRecord {
-                key_id: r.key_id,
-                algorithm: r.algorithm,
-                public_key: r.public_key,
-                private_key: r.private_key,
-                valid_until: r.valid_until.into_inner(),
-            })
+            .map(ServerKeyRecord::from)
             .ok_or(StorageError::NotFound)
     }
 
     async fn get_active_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>> {
         let mut response = self
             .db()
-            .query("SELECT * FROM server_key ORDER BY created_at DESC")
+            .query("SELECT * FROM server_key WHERE expired_at IS NONE ORDER BY created_at DESC")
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
@@ -94,16 +146,38 @@ impl FederationKeyStore for SurrealStorage {
             .take(0)
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
-        Ok(rows
-            .into_iter()
-            .map(|r| ServerKeyRecord {
-                key_id: r.key_id,
-                algorithm: r.algorithm,
-                public_key: r.public_key,
-                private_key: r.private_key,
-                valid_until: r.valid_until.into_inner(),
-            })
-            .collect())
+        Ok(rows.into_iter().map(ServerKeyRecord::from).collect())
+    }
+
+    async fn get_expired_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>> {
+        let mut response = self
+            .db()
+            .query("SELECT * FROM server_key WHERE expired_at IS NOT NONE ORDER BY expired_at DESC")
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<ServerKeyRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(ServerKeyRecord::from).collect())
+    }
+
+    async fn expire_server_key(
+        &self,
+        key_id: &str,
+        expired_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<()> {
+        debug!(key_id = %key_id, "Expiring server signing key");
+
+        self.db()
+            .query("UPDATE server_key SET expired_at = $expired_at WHERE key_id = $kid")
+            .bind(("kid", key_id.to_string()))
+            .bind(("expired_at", Datetime::from(expired_at)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
     }
 
     async fn store_remote_server_keys(&self, keys: &[RemoteKeyRecord]) -> StorageResult<()> {
@@ -150,6 +224,52 @@ impl FederationKeyStore for SurrealStorage {
             .collect())
     }
 
+    async fn store_server_key_response(
+        &self,
+        record: &ServerKeyResponseRecord,
+    ) -> StorageResult<()> {
+        self.db()
+            .query(
+                "INSERT INTO remote_key_response { \
+                 server_name: $sn, response: $resp, valid_until: $vu, fetched_at: $fa \
+                 } ON DUPLICATE KEY UPDATE \
+                 response = $resp, valid_until = $vu, fetched_at = $fa",
+            )
+            .bind(("sn", record.server_name.clone()))
+            .bind(("resp", record.response.clone()))
+            .bind(("vu", Datetime::from(record.valid_until)))
+            .bind(("fa", Datetime::from(record.fetched_at)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_server_key_response(
+        &self,
+        server_name: &str,
+    ) -> StorageResult<ServerKeyResponseRecord> {
+        let mut response = self
+            .db()
+            .query("SELECT * FROM remote_key_response WHERE server_name = $sn LIMIT 1")
+            .bind(("sn", server_name.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<KeyResponseRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        rows.into_iter()
+            .next()
+            .map(|r| ServerKeyResponseRecord {
+                server_name: r.server_name,
+                response: r.response,
+                valid_until: r.valid_until.into_inner(),
+                fetched_at: r.fetched_at.into_inner(),
+            })
+            .ok_or(StorageError::NotFound)
+    }
+
     async fn store_federation_txn(&self, origin: &str, txn_id: &str) -> StorageResult<()> {
         self.db()
             .query("CREATE federation_txn SET origin = $origin, txn_id = $tid")
@@ -204,4 +324,62 @@ impl FederationKeyStore for SurrealStorage {
         }
         Ok(count)
     }
+
+    async fn upsert_destination(&self, record: &DestinationRecord) -> StorageResult<()> {
+        self.db()
+            .query(
+                "INSERT INTO federation_destination { \
+                 server_name: $sn, last_success_at: $ls, last_failure_at: $lf, \
+                 failure_count: $fc, retry_at: $ra, last_error: $le, \
+                 pending_pdus: $pp, pending_edus: $pe, oldest_pending_pdu_ts: $op \
+                 } ON DUPLICATE KEY UPDATE \
+                 last_success_at = $ls, last_failure_at = $lf, failure_count = $fc, \
+                 retry_at = $ra, last_error = $le, pending_pdus = $pp, \
+                 pending_edus = $pe, oldest_pending_pdu_ts = $op",
+            )
+            .bind(("sn", record.server_name.clone()))
+            .bind(("ls", record.last_success_at.map(Datetime::from)))
+            .bind(("lf", record.last_failure_at.map(Datetime::from)))
+            .bind(("fc", i64::from(record.failure_count)))
+            .bind(("ra", record.retry_at.map(Datetime::from)))
+            .bind(("le", record.last_error.clone()))
+            .bind(("pp", record.pending_pdus as i64))
+            .bind(("pe", record.pending_edus as i64))
+            .bind(("op", record.oldest_pending_pdu_ts))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_destination(&self, server_name: &str) -> StorageResult<DestinationRecord> {
+        let mut response = self
+            .db()
+            .query("SELECT * FROM federation_destination WHERE server_name = $sn LIMIT 1")
+            .bind(("sn", server_name.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<DestinationRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        rows.into_iter()
+            .next()
+            .map(DestinationRecord::from)
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn list_destinations(&self) -> StorageResult<Vec<DestinationRecord>> {
+        let mut response = self
+            .db()
+            .query("SELECT * FROM federation_destination ORDER BY server_name")
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<DestinationRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(DestinationRecord::from).collect())
+    }
 }
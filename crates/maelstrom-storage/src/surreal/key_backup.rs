@@ -0,0 +1,341 @@
+//! Server-side key backup storage -- [`KeyBackupStore`](crate::traits::KeyBackupStore) implementation.
+//!
+//! Backup versions live in the `key_backup_version` table, keyed by
+//! `(user_id, version)`.  Versions are integers assigned as the user's
+//! previous maximum plus one; deleting a version only sets its `deleted`
+//! flag, so the number is never handed out again.  A concurrent assignment
+//! of the same number is rejected by the unique index and retried.
+//!
+//! Backed-up session keys live in the `backed_up_room_key` table, keyed by
+//! `(user_id, version, room_id, session_id)`.  An upload is written with a
+//! single `INSERT ... ON DUPLICATE KEY UPDATE`, and every write to the table
+//! also bumps the version's `etag` in the same transaction.
+
+use async_trait::async_trait;
+use surrealdb::types::SurrealValue;
+use tracing::debug;
+
+use super::SurrealStorage;
+use crate::traits::*;
+
+/// Row returned when reading backup versions.
+#[derive(Debug, Clone, SurrealValue)]
+struct VersionRow {
+    user_id: String,
+    version: i64,
+    algorithm: String,
+    auth_data: serde_json::Value,
+    etag: i64,
+}
+
+impl From<VersionRow> for KeyBackupVersion {
+    fn from(row: VersionRow) -> Self {
+        Self {
+            user_id: row.user_id,
+            version: row.version.to_string(),
+            algorithm: row.algorithm,
+            auth_data: row.auth_data,
+            etag: row.etag,
+        }
+    }
+}
+
+/// Row returned when reading backed-up room keys.
+#[derive(Debug, Clone, SurrealValue)]
+struct RoomKeyRow {
+    room_id: String,
+    session_id: String,
+    first_message_index: i64,
+    forwarded_count: i64,
+    is_verified: bool,
+    session_data: serde_json::Value,
+}
+
+impl From<RoomKeyRow> for BackedUpRoomKey {
+    fn from(row: RoomKeyRow) -> Self {
+        Self {
+            room_id: row.room_id,
+            session_id: row.session_id,
+            first_message_index: row.first_message_index,
+            forwarded_count: row.forwarded_count,
+            is_verified: row.is_verified,
+            session_data: row.session_data,
+        }
+    }
+}
+
+/// Row returned when reading a version number or key count.
+#[derive(Debug, Clone, SurrealValue)]
+struct CountRow {
+    count: i64,
+}
+
+/// Versions are numeric; anything else names no version.
+fn parse_version(version: &str) -> StorageResult<i64> {
+    version.parse().map_err(|_| StorageError::NotFound)
+}
+
+impl SurrealStorage {
+    /// Fail with [`StorageError::NotFound`] unless the version exists and is
+    /// not deleted.
+    async fn require_key_backup_version(&self, user_id: &str, version: i64) -> StorageResult<()> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT count() AS count FROM key_backup_version \
+                 WHERE user_id = $uid AND version = $ver AND deleted = false GROUP ALL",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let rows: Vec<CountRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        if rows.first().is_some_and(|row| row.count > 0) {
+            Ok(())
+        } else {
+            Err(StorageError::NotFound)
+        }
+    }
+}
+
+#[async_trait]
+impl KeyBackupStore for SurrealStorage {
+    async fn create_key_backup_version(
+        &self,
+        user_id: &str,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<String> {
+        debug!(user_id = %user_id, "Creating key backup version");
+
+        // Another request may take the same number; the unique index rejects
+        // the second insert, so re-read the latest and try again.
+        let mut last_error = None;
+        for _ in 0..3 {
+            let mut response = self
+                .db()
+                .query(
+                    "SELECT version AS count FROM key_backup_version WHERE user_id = $uid \
+                     ORDER BY version DESC LIMIT 1",
+                )
+                .bind(("uid", user_id.to_string()))
+                .await
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            let rows: Vec<CountRow> = response
+                .take(0)
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            let version = rows.first().map_or(0, |row| row.count) + 1;
+
+            let result = self
+                .db()
+                .query(
+                    "CREATE key_backup_version SET user_id = $uid, version = $ver, \
+                     algorithm = $alg, auth_data = $auth_data, etag = 0, deleted = false",
+                )
+                .bind(("uid", user_id.to_string()))
+                .bind(("ver", version))
+                .bind(("alg", algorithm.to_string()))
+                .bind(("auth_data", auth_data.clone()))
+                .await
+                .and_then(|response| response.check());
+            match result {
+                Ok(_) => return Ok(version.to_string()),
+                Err(e) => last_error = Some(e.to_string()),
+            }
+        }
+        Err(StorageError::Query(last_error.unwrap_or_default()))
+    }
+
+    async fn get_key_backup_version(
+        &self,
+        user_id: &str,
+        version: Option<&str>,
+    ) -> StorageResult<KeyBackupVersion> {
+        let mut response = match version {
+            Some(version) => self
+                .db()
+                .query(
+                    "SELECT user_id, version, algorithm, auth_data, etag FROM key_backup_version \
+                     WHERE user_id = $uid AND version = $ver AND deleted = false LIMIT 1",
+                )
+                .bind(("uid", user_id.to_string()))
+                .bind(("ver", parse_version(version)?))
+                .await,
+            None => self
+                .db()
+                .query(
+                    "SELECT user_id, version, algorithm, auth_data, etag FROM key_backup_version \
+                     WHERE user_id = $uid AND deleted = false ORDER BY version DESC LIMIT 1",
+                )
+                .bind(("uid", user_id.to_string()))
+                .await,
+        }
+        .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<VersionRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        rows.into_iter()
+            .next()
+            .map(KeyBackupVersion::from)
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn update_key_backup_version(
+        &self,
+        user_id: &str,
+        version: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<()> {
+        let version = parse_version(version)?;
+        self.require_key_backup_version(user_id, version).await?;
+        self.db()
+            .query(
+                "UPDATE key_backup_version SET auth_data = $auth_data \
+                 WHERE user_id = $uid AND version = $ver",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .bind(("auth_data", auth_data.clone()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn delete_key_backup_version(&self, user_id: &str, version: &str) -> StorageResult<()> {
+        debug!(user_id = %user_id, version = %version, "Deleting key backup version");
+        let version = parse_version(version)?;
+        self.require_key_backup_version(user_id, version).await?;
+        self.db()
+            .query(
+                "BEGIN TRANSACTION; \
+                 UPDATE key_backup_version SET deleted = true WHERE user_id = $uid AND version = $ver; \
+                 DELETE backed_up_room_key WHERE user_id = $uid AND version = $ver; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn put_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        keys: &[BackedUpRoomKey],
+    ) -> StorageResult<()> {
+        let version = parse_version(version)?;
+        self.require_key_backup_version(user_id, version).await?;
+        let rows: Vec<serde_json::Value> = keys
+            .iter()
+            .map(|key| {
+                serde_json::json!({
+                    "user_id": user_id,
+                    "version": version,
+                    "room_id": key.room_id,
+                    "session_id": key.session_id,
+                    "first_message_index": key.first_message_index,
+                    "forwarded_count": key.forwarded_count,
+                    "is_verified": key.is_verified,
+                    "session_data": key.session_data,
+                })
+            })
+            .collect();
+        self.db()
+            .query(
+                "BEGIN TRANSACTION; \
+                 INSERT INTO backed_up_room_key $rows ON DUPLICATE KEY UPDATE \
+                     first_message_index = $input.first_message_index, \
+                     forwarded_count = $input.forwarded_count, \
+                     is_verified = $input.is_verified, \
+                     session_data = $input.session_data; \
+                 UPDATE key_backup_version SET etag += 1 WHERE user_id = $uid AND version = $ver; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .bind(("rows", rows))
+            .await
+            .and_then(|response| response.check())
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<Vec<BackedUpRoomKey>> {
+        let version = parse_version(version)?;
+        let mut response = self
+            .db()
+            .query(
+                "SELECT room_id, session_id, first_message_index, forwarded_count, is_verified, \
+                 session_data FROM backed_up_room_key \
+                 WHERE user_id = $uid AND version = $ver \
+                 AND ($rid = NONE OR room_id = $rid) AND ($sid = NONE OR session_id = $sid)",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .bind(("rid", room_id.map(str::to_string)))
+            .bind(("sid", session_id.map(str::to_string)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<RoomKeyRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(rows.into_iter().map(BackedUpRoomKey::from).collect())
+    }
+
+    async fn delete_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<()> {
+        let version = parse_version(version)?;
+        self.require_key_backup_version(user_id, version).await?;
+        self.db()
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE backed_up_room_key WHERE user_id = $uid AND version = $ver \
+                 AND ($rid = NONE OR room_id = $rid) AND ($sid = NONE OR session_id = $sid); \
+                 UPDATE key_backup_version SET etag += 1 WHERE user_id = $uid AND version = $ver; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .bind(("rid", room_id.map(str::to_string)))
+            .bind(("sid", session_id.map(str::to_string)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn count_backed_up_room_keys(&self, user_id: &str, version: &str) -> StorageResult<i64> {
+        let version = parse_version(version)?;
+        let mut response = self
+            .db()
+            .query(
+                "SELECT count() AS count FROM backed_up_room_key \
+                 WHERE user_id = $uid AND version = $ver GROUP ALL",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("ver", version))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<CountRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(rows.first().map_or(0, |row| row.count))
+    }
+}
@@ -10,16 +10,33 @@
 //! atomically selects and deletes a key in a single transaction, ensuring each
 //! pre-key is used exactly once.
 //!
+//! **Fallback keys** (MSC2732) live in the `fallback_key` table, one per
+//! `(user_id, device_id, algorithm)`.  A claim that finds no one-time key
+//! returns the fallback key and sets its `used` flag instead of deleting it.
+//!
 //! **Cross-signing keys** are stored in the `cross_signing_key` table, keyed by
 //! `(user_id, key_type)` where `key_type` is `master`, `self_signing`, or
 //! `user_signing`.
 //!
+//! A replaced master key is copied to the `master_key_history` table in the
+//! same transaction.  An admin's approval to reset the keys without
+//! user-interactive auth is kept in the `cross_signing_reset` table.
+//!
+//! **Key signatures** uploaded by clients are stored in the `key_signature`
+//! table, one row per `(signed key, signing key)` pair.
+//!
+//! **Device list changes** are appended to the `device_list_change` table.
+//! Each user's `stream_id`s are assigned as the previous maximum plus one; the
+//! unique `(user_id, stream_id)` index turns a concurrent assignment into an
+//! error, which is retried.  The `remote_device_list` table holds the stream
+//! ID of each cached remote device list.
+//!
 //! **To-device messages** are queued in the `to_device` table with a
 //! `stream_position` for ordered delivery during `/sync`.  Messages are deleted
 //! once the client acknowledges receipt via the `since` token.
 
 use async_trait::async_trait;
-use surrealdb::types::SurrealValue;
+use surrealdb::types::{Datetime, SurrealValue};
 use tracing::debug;
 
 use super::SurrealStorage;
@@ -42,6 +59,12 @@ struct OneTimeKeyRow {
     key_data: serde_json::Value,
 }
 
+/// Row returned when reading fallback key records.
+#[derive(Debug, Clone, SurrealValue)]
+struct AlgorithmRow {
+    algorithm: String,
+}
+
 /// Row returned when reading cross-signing key records.
 #[derive(Debug, Clone, SurrealValue)]
 struct CrossSigningKeyRow {
@@ -49,6 +72,53 @@ struct CrossSigningKeyRow {
     key_data: serde_json::Value,
 }
 
+/// Row returned when reading replaced master keys.
+#[derive(Debug, Clone, SurrealValue)]
+struct MasterKeyHistoryRow {
+    key_data: serde_json::Value,
+    replaced_at: Datetime,
+}
+
+/// Row returned when reading a cross-signing reset approval.
+#[derive(Debug, Clone, SurrealValue)]
+struct CrossSigningResetRow {
+    allowed_until: Datetime,
+}
+
+/// Row returned when reading key signatures.
+#[derive(Debug, Clone, SurrealValue)]
+struct KeySignatureRow {
+    signed_user_id: String,
+    signed_key_id: String,
+    signing_user_id: String,
+    signing_key_id: String,
+    signature: String,
+}
+
+impl From<KeySignatureRow> for KeySignature {
+    fn from(row: KeySignatureRow) -> Self {
+        Self {
+            signed_user_id: row.signed_user_id,
+            signed_key_id: row.signed_key_id,
+            signing_user_id: row.signing_user_id,
+            signing_key_id: row.signing_key_id,
+            signature: row.signature,
+        }
+    }
+}
+
+/// Row returned when reading a device list stream ID.
+#[derive(Debug, Clone, SurrealValue)]
+struct StreamIdRow {
+    stream_id: i64,
+}
+
+/// Row returned when reading users with device list changes.
+#[derive(Debug, Clone, SurrealValue)]
+struct ChangedUserRow {
+    user_id: String,
+}
+
 /// Row returned when reading to-device messages.
 #[derive(Debug, Clone, SurrealValue)]
 struct ToDeviceRow {
@@ -58,6 +128,35 @@ struct ToDeviceRow {
     stream_position: i64,
 }
 
+impl SurrealStorage {
+    /// Hand out the device's fallback key for `algorithm`, marking it used.
+    async fn claim_fallback_key(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> StorageResult<Option<OneTimeKeyRow>> {
+        let mut response = self
+            .db()
+            .query(
+                "UPDATE fallback_key SET used = true \
+                 WHERE user_id = $uid AND device_id = $did AND algorithm = $alg \
+                 RETURN key_id, key_data",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("did", device_id.to_string()))
+            .bind(("alg", algorithm.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<OneTimeKeyRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().next())
+    }
+}
+
 #[async_trait]
 impl KeyStore for SurrealStorage {
     async fn set_device_keys(
@@ -141,6 +240,39 @@ impl KeyStore for SurrealStorage {
         Ok(serde_json::Value::Object(result))
     }
 
+    async fn delete_device_keys(
+        &self,
+        user_id: &str,
+        device_id: Option<&str>,
+    ) -> StorageResult<()> {
+        debug!(user_id = %user_id, device_id = ?device_id, "Deleting device keys");
+
+        let query = match device_id {
+            Some(_) => {
+                "BEGIN TRANSACTION; \
+                 DELETE device_key WHERE user_id = $uid AND device_id = $did; \
+                 DELETE one_time_key WHERE user_id = $uid AND device_id = $did; \
+                 DELETE fallback_key WHERE user_id = $uid AND device_id = $did; \
+                 COMMIT TRANSACTION;"
+            }
+            None => {
+                "BEGIN TRANSACTION; \
+                 DELETE device_key WHERE user_id = $uid; \
+                 DELETE one_time_key WHERE user_id = $uid; \
+                 DELETE fallback_key WHERE user_id = $uid; \
+                 COMMIT TRANSACTION;"
+            }
+        };
+        self.db()
+            .query(query)
+            .bind(("uid", user_id.to_string()))
+            .bind(("did", device_id.map(str::to_string)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
     async fn store_one_time_keys(
         &self,
         user_id: &str,
@@ -247,6 +379,10 @@ impl KeyStore for SurrealStorage {
                                 .await
                                 .map_err(|e| StorageError::Query(e.to_string()))?;
 
+                            let mut device_keys = serde_json::Map::new();
+                            device_keys.insert(row.key_id, row.key_data);
+                            user_result.insert(did.clone(), serde_json::Value::Object(device_keys));
+                        } else if let Some(row) = self.claim_fallback_key(uid, did, algo).await? {
                             let mut device_keys = serde_json::Map::new();
                             device_keys.insert(row.key_id, row.key_data);
                             user_result.insert(did.clone(), serde_json::Value::Object(device_keys));
@@ -262,6 +398,65 @@ impl KeyStore for SurrealStorage {
         Ok(serde_json::Value::Object(result))
     }
 
+    async fn store_fallback_keys(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        keys: &serde_json::Value,
+    ) -> StorageResult<()> {
+        debug!(user_id = %user_id, device_id = %device_id, "Storing fallback keys");
+
+        for (key_id, key_data) in keys.as_object().into_iter().flatten() {
+            let algorithm = key_id.split(':').next().unwrap_or_default();
+
+            // A different key replaces the old one; the same key only
+            // updates its data and keeps its `used` flag.
+            self.db()
+                .query(
+                    "BEGIN TRANSACTION; \
+                     DELETE fallback_key WHERE user_id = $uid AND device_id = $did \
+                     AND algorithm = $alg AND key_id != $kid; \
+                     INSERT INTO fallback_key { \
+                         user_id: $uid, device_id: $did, algorithm: $alg, key_id: $kid, \
+                         key_data: $kdata, used: false \
+                     } ON DUPLICATE KEY UPDATE key_data = $kdata; \
+                     COMMIT TRANSACTION;",
+                )
+                .bind(("uid", user_id.to_string()))
+                .bind(("did", device_id.to_string()))
+                .bind(("alg", algorithm.to_string()))
+                .bind(("kid", key_id.clone()))
+                .bind(("kdata", key_data.clone()))
+                .await
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    async fn get_unused_fallback_key_types(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> StorageResult<Vec<String>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT algorithm FROM fallback_key \
+                 WHERE user_id = $uid AND device_id = $did AND used = false ORDER BY algorithm",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("did", device_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<AlgorithmRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(|row| row.algorithm).collect())
+    }
+
     async fn set_cross_signing_keys(
         &self,
         user_id: &str,
@@ -280,6 +475,12 @@ impl KeyStore for SurrealStorage {
                     self.db()
                         .query(
                             "BEGIN TRANSACTION; \
+                             LET $old = (SELECT VALUE key_data FROM cross_signing_key \
+                                 WHERE user_id = $uid AND key_type = $kt)[0]; \
+                             IF $kt = 'master_key' AND $old != NONE AND $old.keys != $kdata.keys { \
+                                 CREATE master_key_history SET user_id = $uid, key_data = $old, \
+                                     replaced_at = time::now(); \
+                             }; \
                              DELETE cross_signing_key WHERE user_id = $uid AND key_type = $kt; \
                              CREATE cross_signing_key SET user_id = $uid, key_type = $kt, key_data = $kdata; \
                              COMMIT TRANSACTION;",
@@ -315,6 +516,277 @@ impl KeyStore for SurrealStorage {
 
         Ok(serde_json::Value::Object(result))
     }
+
+    async fn get_master_key_history(&self, user_id: &str) -> StorageResult<Vec<ReplacedMasterKey>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT key_data, replaced_at FROM master_key_history \
+                 WHERE user_id = $uid ORDER BY replaced_at ASC",
+            )
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<MasterKeyHistoryRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ReplacedMasterKey {
+                key: row.key_data,
+                replaced_at: row.replaced_at.into_inner(),
+            })
+            .collect())
+    }
+
+    async fn set_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> StorageResult<()> {
+        debug!(user_id = %user_id, ?until, "Setting cross-signing reset approval");
+
+        let query = match until {
+            Some(until) => self
+                .db()
+                .query(
+                    "INSERT INTO cross_signing_reset { user_id: $uid, allowed_until: $until } \
+                     ON DUPLICATE KEY UPDATE allowed_until = $until",
+                )
+                .bind(("until", Datetime::from(until))),
+            None => self
+                .db()
+                .query("DELETE cross_signing_reset WHERE user_id = $uid"),
+        };
+        query
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+    ) -> StorageResult<Option<chrono::DateTime<chrono::Utc>>> {
+        let mut response = self
+            .db()
+            .query("SELECT allowed_until FROM cross_signing_reset WHERE user_id = $uid")
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<CrossSigningResetRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows
+            .into_iter()
+            .next()
+            .map(|row| row.allowed_until.into_inner()))
+    }
+
+    async fn add_key_signature(&self, signature: &KeySignature) -> StorageResult<()> {
+        debug!(
+            signed_user_id = %signature.signed_user_id,
+            signed_key_id = %signature.signed_key_id,
+            "Storing key signature"
+        );
+
+        self.db()
+            .query(
+                "BEGIN TRANSACTION; \
+                 DELETE key_signature WHERE signed_user_id = $suid AND signed_key_id = $skid \
+                 AND signing_user_id = $guid AND signing_key_id = $gkid; \
+                 CREATE key_signature SET signed_user_id = $suid, signed_key_id = $skid, \
+                 signing_user_id = $guid, signing_key_id = $gkid, signature = $sig; \
+                 COMMIT TRANSACTION;",
+            )
+            .bind(("suid", signature.signed_user_id.clone()))
+            .bind(("skid", signature.signed_key_id.clone()))
+            .bind(("guid", signature.signing_user_id.clone()))
+            .bind(("gkid", signature.signing_key_id.clone()))
+            .bind(("sig", signature.signature.clone()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get_key_signatures(&self, signed_user_id: &str) -> StorageResult<Vec<KeySignature>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT signed_user_id, signed_key_id, signing_user_id, signing_key_id, signature \
+                 FROM key_signature WHERE signed_user_id = $suid",
+            )
+            .bind(("suid", signed_user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<KeySignatureRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(KeySignature::from).collect())
+    }
+
+    async fn delete_key_signatures(
+        &self,
+        signed_user_id: &str,
+        signed_key_id: &str,
+    ) -> StorageResult<()> {
+        self.db()
+            .query("DELETE key_signature WHERE signed_user_id = $suid AND signed_key_id = $skid")
+            .bind(("suid", signed_user_id.to_string()))
+            .bind(("skid", signed_key_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn add_device_list_change(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        deleted: bool,
+    ) -> StorageResult<DeviceListChange> {
+        let stream_pos = self.next_stream_position().await?;
+
+        // Another node may take the same stream ID; the unique index rejects
+        // the second insert, so re-read the latest and try again.
+        let mut last_error = None;
+        for _ in 0..3 {
+            let stream_id = self.get_device_list_stream_id(user_id).await? + 1;
+            let result = self
+                .db()
+                .query(
+                    "CREATE device_list_change SET user_id = $uid, device_id = $did, \
+                     stream_id = $sid, stream_pos = $pos, deleted = $deleted",
+                )
+                .bind(("uid", user_id.to_string()))
+                .bind(("did", device_id.to_string()))
+                .bind(("sid", stream_id))
+                .bind(("pos", stream_pos))
+                .bind(("deleted", deleted))
+                .await
+                .and_then(|response| response.check());
+            match result {
+                Ok(_) => {
+                    return Ok(DeviceListChange {
+                        user_id: user_id.to_string(),
+                        device_id: device_id.to_string(),
+                        stream_id,
+                        stream_pos,
+                        deleted,
+                    });
+                }
+                Err(e) => last_error = Some(e.to_string()),
+            }
+        }
+        Err(StorageError::Query(last_error.unwrap_or_default()))
+    }
+
+    async fn get_device_list_stream_id(&self, user_id: &str) -> StorageResult<i64> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT stream_id FROM device_list_change WHERE user_id = $uid \
+                 ORDER BY stream_id DESC LIMIT 1",
+            )
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<StreamIdRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.first().map_or(0, |row| row.stream_id))
+    }
+
+    async fn get_device_list_changed_users(
+        &self,
+        from: i64,
+        to: i64,
+    ) -> StorageResult<Vec<String>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT user_id FROM device_list_change \
+                 WHERE stream_pos >= $from AND stream_pos <= $to GROUP BY user_id; \
+                 SELECT user_id FROM key_change \
+                 WHERE stream_pos >= $from AND stream_pos <= $to GROUP BY user_id;",
+            )
+            .bind(("from", from))
+            .bind(("to", to))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let mut rows: Vec<ChangedUserRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let key_rows: Vec<ChangedUserRow> = response
+            .take(1)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        rows.extend(key_rows);
+
+        let users: std::collections::BTreeSet<String> =
+            rows.into_iter().map(|row| row.user_id).collect();
+        Ok(users.into_iter().collect())
+    }
+
+    async fn add_key_change(&self, user_id: &str) -> StorageResult<i64> {
+        let stream_pos = self.next_stream_position().await?;
+        self.db()
+            .query("CREATE key_change SET user_id = $uid, stream_pos = $pos")
+            .bind(("uid", user_id.to_string()))
+            .bind(("pos", stream_pos))
+            .await
+            .and_then(|response| response.check())
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(stream_pos)
+    }
+
+    async fn get_remote_device_stream_id(&self, user_id: &str) -> StorageResult<Option<i64>> {
+        let mut response = self
+            .db()
+            .query("SELECT stream_id FROM remote_device_list WHERE user_id = $uid LIMIT 1")
+            .bind(("uid", user_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<StreamIdRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.first().map(|row| row.stream_id))
+    }
+
+    async fn set_remote_device_stream_id(
+        &self,
+        user_id: &str,
+        stream_id: Option<i64>,
+    ) -> StorageResult<()> {
+        let query = match stream_id {
+            Some(_) => {
+                "INSERT INTO remote_device_list { user_id: $uid, stream_id: $sid } \
+                 ON DUPLICATE KEY UPDATE stream_id = $sid"
+            }
+            None => "DELETE remote_device_list WHERE user_id = $uid",
+        };
+        self.db()
+            .query(query)
+            .bind(("uid", user_id.to_string()))
+            .bind(("sid", stream_id))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -364,17 +836,20 @@ impl ToDeviceStore for SurrealStorage {
         user_id: &str,
         device_id: &str,
         since: i64,
+        up_to: i64,
     ) -> StorageResult<Vec<serde_json::Value>> {
         let mut response = self
             .db()
             .query(
                 "SELECT sender, event_type, content, stream_position FROM to_device_message \
-                 WHERE target_user_id = $uid AND target_device_id = $did AND stream_position > $since \
+                 WHERE target_user_id = $uid AND target_device_id = $did \
+                 AND stream_position > $since AND stream_position <= $up_to \
                  ORDER BY stream_position ASC",
             )
             .bind(("uid", user_id.to_string()))
             .bind(("did", device_id.to_string()))
             .bind(("since", since))
+            .bind(("up_to", up_to))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
 
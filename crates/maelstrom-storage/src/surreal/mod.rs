@@ -29,6 +29,7 @@
 //! | [`events`]      | [`EventStore`](crate::traits::EventStore)  |
 //! | [`receipts`]    | [`ReceiptStore`](crate::traits::ReceiptStore) |
 //! | [`keys`]        | [`KeyStore`](crate::traits::KeyStore) + [`ToDeviceStore`](crate::traits::ToDeviceStore) |
+//! | [`key_backup`]  | [`KeyBackupStore`](crate::traits::KeyBackupStore) |
 //! | [`account_data`]| [`AccountDataStore`](crate::traits::AccountDataStore) |
 //! | [`media`]       | [`MediaStore`](crate::traits::MediaStore)  |
 //! | [`federation`]  | [`FederationKeyStore`](crate::traits::FederationKeyStore) |
@@ -41,6 +42,7 @@ pub mod connection;
 mod devices;
 mod events;
 mod federation;
+mod key_backup;
 mod keys;
 mod media;
 mod receipts;
@@ -11,6 +11,10 @@
 //! Thread root discovery (`get_thread_roots`) finds parent events in a room
 //! that have at least one `m.thread` child, ordered by stream position for
 //! pagination.
+//!
+//! Thread subscriptions live in the `thread_subscription` table with a unique
+//! index on `(user_id, room_id, thread_root)`, so each change is a single
+//! `INSERT ... ON DUPLICATE KEY UPDATE`.
 
 use async_trait::async_trait;
 use surrealdb::types::{RecordId, SurrealValue};
@@ -49,6 +53,28 @@ struct ParentEventIdRow {
     parent_event_id: String,
 }
 
+/// Row returned when reading thread subscriptions.
+#[derive(Debug, Clone, SurrealValue)]
+struct ThreadSubscriptionRow {
+    room_id: String,
+    thread_root: String,
+    subscribed: bool,
+    automatic: bool,
+    bump_stamp: i64,
+}
+
+impl From<ThreadSubscriptionRow> for ThreadSubscription {
+    fn from(row: ThreadSubscriptionRow) -> Self {
+        Self {
+            room_id: row.room_id,
+            thread_root: row.thread_root,
+            subscribed: row.subscribed,
+            automatic: row.automatic,
+            bump_stamp: row.bump_stamp,
+        }
+    }
+}
+
 #[async_trait]
 impl RelationStore for SurrealStorage {
     async fn store_relation(&self, relation: &RelationRecord) -> StorageResult<()> {
@@ -263,12 +289,88 @@ impl RelationStore for SurrealStorage {
 
         // Sort by latest reply position descending
         let mut threads: Vec<(String, i64)> = thread_latest.into_iter().collect();
-        threads.sort_by(|a, b| b.1.cmp(&a.1));
+        threads.sort_by_key(|t| std::cmp::Reverse(t.1));
         threads.truncate(limit);
 
         Ok(threads.into_iter().map(|(root, _)| root).collect())
     }
 
+    async fn set_thread_subscription(
+        &self,
+        user_id: &str,
+        subscription: &ThreadSubscription,
+    ) -> StorageResult<()> {
+        self.db()
+            .query(
+                "INSERT INTO thread_subscription { \
+                 user_id: $uid, room_id: $rid, thread_root: $root, \
+                 subscribed: $subscribed, automatic: $automatic, bump_stamp: $bump \
+                 } ON DUPLICATE KEY UPDATE \
+                 subscribed = $subscribed, automatic = $automatic, bump_stamp = $bump",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("rid", subscription.room_id.clone()))
+            .bind(("root", subscription.thread_root.clone()))
+            .bind(("subscribed", subscription.subscribed))
+            .bind(("automatic", subscription.automatic))
+            .bind(("bump", subscription.bump_stamp))
+            .await
+            .and_then(|response| response.check())
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
+    async fn get_thread_subscription(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        thread_root: &str,
+    ) -> StorageResult<ThreadSubscription> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT room_id, thread_root, subscribed, automatic, bump_stamp \
+                 FROM thread_subscription \
+                 WHERE user_id = $uid AND room_id = $rid AND thread_root = $root LIMIT 1",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("rid", room_id.to_string()))
+            .bind(("root", thread_root.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<ThreadSubscriptionRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        rows.into_iter()
+            .next()
+            .map(ThreadSubscription::from)
+            .ok_or(StorageError::NotFound)
+    }
+
+    async fn get_thread_subscriptions(
+        &self,
+        user_id: &str,
+        since: i64,
+    ) -> StorageResult<Vec<ThreadSubscription>> {
+        let mut response = self
+            .db()
+            .query(
+                "SELECT room_id, thread_root, subscribed, automatic, bump_stamp \
+                 FROM thread_subscription WHERE user_id = $uid AND bump_stamp > $since",
+            )
+            .bind(("uid", user_id.to_string()))
+            .bind(("since", since))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<ThreadSubscriptionRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+        Ok(rows.into_iter().map(ThreadSubscription::from).collect())
+    }
+
     async fn store_report(&self, report: &ReportRecord) -> StorageResult<()> {
         self.db()
             .query(
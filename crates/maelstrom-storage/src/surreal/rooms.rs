@@ -8,7 +8,9 @@
 //!
 //! Room aliases are stored in a separate `room_alias` table.  The public room
 //! directory (`get_public_rooms`) supports optional keyword filtering and
-//! cursor-based pagination.
+//! cursor-based pagination.  Rooms that appservices publish under a bridged
+//! network live in `appservice_room_list`, one row per (appservice, network,
+//! room).
 //!
 //! Room upgrades are modeled as `room ->upgrades_to-> room` graph edges,
 //! allowing `get_room_predecessors` to walk the chain backward in a single
@@ -43,6 +45,7 @@ struct RoomRow {
     version: String,
     creator: String,
     is_direct: bool,
+    peek_only: bool,
 }
 
 impl RoomRow {
@@ -52,6 +55,7 @@ impl RoomRow {
             version: self.version,
             creator: self.creator,
             is_direct: self.is_direct,
+            peek_only: self.peek_only,
         }
     }
 }
@@ -85,10 +89,26 @@ impl RoomStore for SurrealStorage {
 
         let rid = room_rid(&room.room_id);
 
+        // A room we only peeked into becomes one we are in
+        if !room.peek_only {
+            let mut response = self
+                .db()
+                .query("UPDATE $rid SET peek_only = false WHERE peek_only = true RETURN room_id")
+                .bind(("rid", rid.clone()))
+                .await
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            let rows: Vec<RoomIdRow> = response
+                .take(0)
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            if !rows.is_empty() {
+                return Ok(());
+            }
+        }
+
         let mut response = self
             .db()
             .query(
-                "INSERT INTO room { id: $rid, room_id: $room_id, version: $ver, creator: $creator, is_direct: $direct } \
+                "INSERT INTO room { id: $rid, room_id: $room_id, version: $ver, creator: $creator, is_direct: $direct, peek_only: $peek_only } \
                  ON DUPLICATE KEY UPDATE room_id = $room_id",
             )
             .bind(("rid", rid))
@@ -96,6 +116,7 @@ impl RoomStore for SurrealStorage {
             .bind(("ver", room.version.clone()))
             .bind(("creator", room.creator.clone()))
             .bind(("direct", room.is_direct))
+            .bind(("peek_only", room.peek_only))
             .await
             .map_err(|e| {
                 let msg = e.to_string();
@@ -116,7 +137,7 @@ impl RoomStore for SurrealStorage {
     async fn get_room(&self, room_id: &str) -> StorageResult<RoomRecord> {
         let mut response = self
             .db()
-            .query("SELECT room_id, version, creator, is_direct FROM room WHERE room_id = $rid")
+            .query("SELECT room_id, version, creator, is_direct, peek_only ?? false AS peek_only FROM room WHERE room_id = $rid")
             .bind(("rid", room_id.to_string()))
             .await
             .map_err(|e| StorageError::Query(e.to_string()))?;
@@ -224,6 +245,21 @@ impl RoomStore for SurrealStorage {
         Ok(rows.into_iter().map(|r| r.room_id).collect())
     }
 
+    async fn get_knocked_rooms(&self, user_id: &str) -> StorageResult<Vec<String>> {
+        let mut response = self
+            .db()
+            .query("SELECT room_id FROM member_of WHERE in = $user_rid AND membership = 'knock'")
+            .bind(("user_rid", user_rid_from_matrix_id(user_id)))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        let rows: Vec<RoomIdRow> = response
+            .take(0)
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(rows.into_iter().map(|r| r.room_id).collect())
+    }
+
     async fn get_left_rooms(&self, user_id: &str) -> StorageResult<Vec<String>> {
         let mut response = self
             .db()
@@ -384,21 +420,80 @@ impl RoomStore for SurrealStorage {
         Ok(())
     }
 
+    async fn set_appservice_room_visibility(
+        &self,
+        appservice_id: &str,
+        network_id: &str,
+        room_id: &str,
+        visibility: &str,
+    ) -> StorageResult<()> {
+        debug!(appservice_id = %appservice_id, network_id = %network_id, room_id = %room_id, visibility = %visibility, "Setting appservice room visibility");
+
+        let query = if visibility == "public" {
+            "INSERT INTO appservice_room_list { appservice_id: $as_id, network_id: $net, room_id: $rid } \
+             ON DUPLICATE KEY UPDATE room_id = $rid"
+        } else {
+            "DELETE appservice_room_list WHERE appservice_id = $as_id AND network_id = $net AND room_id = $rid"
+        };
+
+        self.db()
+            .query(query)
+            .bind(("as_id", appservice_id.to_string()))
+            .bind(("net", network_id.to_string()))
+            .bind(("rid", room_id.to_string()))
+            .await
+            .map_err(|e| StorageError::Query(e.to_string()))?;
+
+        Ok(())
+    }
+
     async fn get_public_rooms(
         &self,
         limit: usize,
         since: Option<&str>,
         filter: Option<&str>,
+        network: DirectoryNetwork<'_>,
     ) -> StorageResult<(Vec<PublicRoom>, usize)> {
-        let mut response = self
-            .db()
-            .query("SELECT room_id FROM room WHERE visibility = 'public'")
-            .await
-            .map_err(|e| StorageError::Query(e.to_string()))?;
+        let mut all_rooms: Vec<RoomIdRow> = Vec::new();
 
-        let all_rooms: Vec<RoomIdRow> = response
-            .take(0)
-            .map_err(|e| StorageError::Query(e.to_string()))?;
+        if matches!(network, DirectoryNetwork::Matrix | DirectoryNetwork::All) {
+            let mut response = self
+                .db()
+                .query("SELECT room_id FROM room WHERE visibility = 'public'")
+                .await
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            all_rooms = response
+                .take(0)
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+        }
+
+        if network != DirectoryNetwork::Matrix {
+            let query = match network {
+                DirectoryNetwork::AppService {
+                    appservice_id,
+                    network_id,
+                } => self
+                    .db()
+                    .query(
+                        "SELECT room_id FROM appservice_room_list \
+                         WHERE appservice_id = $as_id AND network_id = $net",
+                    )
+                    .bind(("as_id", appservice_id.to_string()))
+                    .bind(("net", network_id.to_string())),
+                _ => self.db().query("SELECT room_id FROM appservice_room_list"),
+            };
+            let mut response = query
+                .await
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            let bridged: Vec<RoomIdRow> = response
+                .take(0)
+                .map_err(|e| StorageError::Query(e.to_string()))?;
+            for row in bridged {
+                if !all_rooms.iter().any(|r| r.room_id == row.room_id) {
+                    all_rooms.push(row);
+                }
+            }
+        }
 
         let total = all_rooms.len();
         let start = since.and_then(|s| s.parse::<usize>().ok()).unwrap_or(0);
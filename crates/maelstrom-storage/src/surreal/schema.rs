@@ -8,8 +8,13 @@
 //! uses `DEFINE ... IF NOT EXISTS`, so re-running it against an existing
 //! database is a no-op for tables/indexes that already exist.  This makes
 //! rolling deployments safe without a separate migration tool.
+//!
+//! Data that moved out of account data is migrated right after, see
+//! [`migrate_legacy_key_backups`].
+
+use std::collections::HashSet;
 
-use tracing::info;
+use tracing::{info, warn};
 
 use super::SurrealStorage;
 use crate::traits::StorageError;
@@ -32,6 +37,187 @@ pub async fn bootstrap(storage: &SurrealStorage) -> Result<(), StorageError> {
         .await
         .map_err(|e| StorageError::Query(format!("Schema bootstrap failed: {e}")))?;
 
+    migrate_legacy_key_backups(storage).await?;
+
     info!("Schema bootstrap complete");
     Ok(())
 }
+
+/// Move key backups kept in account data into the key backup tables.
+///
+/// Backups used to be stored as global account data: each version's info
+/// (`algorithm`, `auth_data`) under `_maelstrom.key_backup.{version}`, the
+/// latest version number under `_maelstrom.key_backup_version` and each key
+/// under `_maelstrom.room_key.{version}.{roomId}.{sessionId}`.  Each entry is
+/// deleted once it has been copied over; one that could not be is kept and
+/// tried again on the next start.
+async fn migrate_legacy_key_backups(storage: &SurrealStorage) -> Result<(), StorageError> {
+    let query_error =
+        |e: surrealdb::Error| StorageError::Query(format!("Key backup migration failed: {e}"));
+    let mut response = storage
+        .db()
+        .query(
+            "SELECT user_id, data_type, content FROM account_data WHERE room_id = '' \
+             AND (string::starts_with(data_type, '_maelstrom.key_backup') \
+                  OR string::starts_with(data_type, '_maelstrom.room_key.'))",
+        )
+        .await
+        .map_err(query_error)?;
+    let rows: Vec<serde_json::Value> = response.take(0).map_err(query_error)?;
+    if rows.is_empty() {
+        return Ok(());
+    }
+    info!(
+        entries = rows.len(),
+        "Migrating key backups out of account data"
+    );
+
+    let field = |row: &serde_json::Value, name: &str| {
+        row.get(name)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    };
+    // Versions first, so the keys have somewhere to go
+    let (versions, keys): (Vec<_>, Vec<_>) = rows
+        .iter()
+        .partition(|row| field(row, "data_type").starts_with("_maelstrom.key_backup"));
+    let mut migrated = HashSet::new();
+    // (user_id, data_type) of each entry safe to delete
+    let mut done = Vec::new();
+    for row in &versions {
+        let user_id = field(row, "user_id");
+        let Some(version) = field(row, "data_type")
+            .strip_prefix("_maelstrom.key_backup.")
+            .and_then(|v| v.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        let content = row.get("content").cloned().unwrap_or_default();
+        storage
+            .db()
+            .query(
+                "INSERT IGNORE INTO key_backup_version { \
+                     user_id: $uid, version: $ver, algorithm: $alg, auth_data: $auth_data, \
+                     etag: 0, deleted: false \
+                 }",
+            )
+            .bind(("uid", user_id.clone()))
+            .bind(("ver", version))
+            .bind((
+                "alg",
+                content
+                    .get("algorithm")
+                    .and_then(|a| a.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            ))
+            .bind((
+                "auth_data",
+                content
+                    .get("auth_data")
+                    .filter(|a| a.is_object())
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!({})),
+            ))
+            .await
+            .and_then(|response| response.check())
+            .map_err(query_error)?;
+        migrated.insert((user_id.clone(), version));
+        done.push((user_id, field(row, "data_type")));
+    }
+    // The latest version number is only needed while its version is unmigrated
+    for row in &versions {
+        let user_id = field(row, "user_id");
+        let latest = row
+            .get("content")
+            .and_then(|c| c.get("version"))
+            .and_then(|v| v.as_i64());
+        if field(row, "data_type") == "_maelstrom.key_backup_version"
+            && latest.is_some_and(|version| migrated.contains(&(user_id.clone(), version)))
+        {
+            done.push((user_id, field(row, "data_type")));
+        }
+    }
+    for row in keys {
+        let user_id = field(row, "user_id");
+        let data_type = field(row, "data_type");
+        // Room IDs contain dots; versions and session IDs do not
+        let Some((version, rest)) = data_type
+            .strip_prefix("_maelstrom.room_key.")
+            .and_then(|rest| rest.split_once('.'))
+        else {
+            continue;
+        };
+        let (Ok(version), Some((room_id, session_id))) =
+            (version.parse::<i64>(), rest.rsplit_once('.'))
+        else {
+            continue;
+        };
+        // Keys uploaded before any version was created belong to none
+        if !migrated.contains(&(user_id.clone(), version)) {
+            continue;
+        }
+        let content = row.get("content").cloned().unwrap_or_default();
+        let int = |name: &str| content.get(name).and_then(|v| v.as_i64()).unwrap_or(0);
+        let result = storage
+            .db()
+            .query(
+                "INSERT IGNORE INTO backed_up_room_key { \
+                     user_id: $uid, version: $ver, room_id: $rid, session_id: $sid, \
+                     first_message_index: $fmi, forwarded_count: $fc, \
+                     is_verified: $verified, session_data: $data \
+                 }",
+            )
+            .bind(("uid", user_id.clone()))
+            .bind(("ver", version))
+            .bind(("rid", room_id.to_string()))
+            .bind(("sid", session_id.to_string()))
+            .bind(("fmi", int("first_message_index")))
+            .bind(("fc", int("forwarded_count")))
+            .bind((
+                "verified",
+                content
+                    .get("is_verified")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            ))
+            .bind((
+                "data",
+                content
+                    .get("session_data")
+                    .filter(|d| d.is_object())
+                    .cloned()
+                    .unwrap_or_else(|| serde_json::json!({})),
+            ))
+            .await
+            .and_then(|response| response.check());
+        match result {
+            Ok(_) => done.push((user_id, data_type)),
+            Err(e) => {
+                warn!(data_type = %data_type, error = %e, "Skipping unreadable backed-up key")
+            }
+        }
+    }
+
+    if done.len() < rows.len() {
+        warn!(
+            kept = rows.len() - done.len(),
+            "Keeping key backup entries that could not be migrated"
+        );
+    }
+    for (user_id, data_type) in done {
+        storage
+            .db()
+            .query(
+                "DELETE account_data WHERE user_id = $uid AND room_id = '' \
+                 AND data_type = $dt",
+            )
+            .bind(("uid", user_id))
+            .bind(("dt", data_type))
+            .await
+            .and_then(|response| response.check())
+            .map_err(query_error)?;
+    }
+    Ok(())
+}
@@ -14,11 +14,12 @@
 //! | [`RoomStore`]        | Room metadata, membership, aliases, visibility, upgrades. |
 //! | [`EventStore`]       | PDU storage, room state map, stream positions, search.    |
 //! | [`ReceiptStore`]     | Read receipts (per-room, per-thread).                     |
-//! | [`KeyStore`]         | E2EE device keys, one-time keys, cross-signing keys.      |
+//! | [`KeyStore`]         | E2EE device keys, one-time keys, cross-signing keys, device list changes. |
+//! | [`KeyBackupStore`]   | Server-side key backup versions and backed-up room keys.   |
 //! | [`ToDeviceStore`]    | Queued to-device messages for offline delivery.            |
 //! | [`AccountDataStore`] | Per-user and per-room account data blobs.                 |
 //! | [`MediaStore`]       | Media metadata (the blobs live in object storage).        |
-//! | [`FederationKeyStore`] | Server signing keys and cached remote server keys.      |
+//! | [`FederationKeyStore`] | Server signing keys, remote key cache, destination health. |
 //! | [`RelationStore`]    | Event relations (threads, reactions, edits, reports).      |
 //! | [`ApplicationServiceStore`] | Application service (bridge/bot) registrations.    |
 //! | [`HealthCheck`]      | Liveness probe for the storage backend.                   |
@@ -96,6 +97,18 @@ pub struct DeviceRecord {
     pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
+/// A user's dehydrated device (MSC3814).
+///
+/// A device with no client behind it that keeps receiving to-device messages
+/// while the user is offline; a new client rehydrates it from the pickled
+/// `device_data` and collects the messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DehydratedDevice {
+    pub device_id: String,
+    /// The client's encrypted device pickle, opaque to the server.
+    pub device_data: serde_json::Value,
+}
+
 /// A stored user record.
 ///
 /// Represents a registered Matrix user account.  The `localpart` is the portion
@@ -129,13 +142,19 @@ pub struct ProfileRecord {
 /// Core metadata about a room.  `version` is the room version string (e.g.
 /// `"11"`), which determines the event format and state resolution algorithm.
 /// `creator` is the fully-qualified Matrix user ID of the room creator.
-/// `is_direct` indicates a 1:1 direct message room.
+/// `is_direct` indicates a 1:1 direct message room.  `peek_only` marks a
+/// remote room this server only holds because a local user peeked into it
+/// over federation: it does not take part in the room, so joining it goes
+/// through the room's servers.  Creating the room again without the flag
+/// clears it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomRecord {
     pub room_id: String,
     pub version: String,
     pub creator: String,
     pub is_direct: bool,
+    #[serde(default)]
+    pub peek_only: bool,
 }
 
 /// A public room listing entry.
@@ -155,6 +174,20 @@ pub struct PublicRoom {
     pub guest_can_join: bool,
 }
 
+/// Which part of the room directory [`RoomStore::get_public_rooms`] lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryNetwork<'a> {
+    /// Rooms published in this server's own (Matrix) directory.
+    Matrix,
+    /// Rooms an appservice published under one of its bridged networks.
+    AppService {
+        appservice_id: &'a str,
+        network_id: &'a str,
+    },
+    /// The Matrix directory plus every bridged network.
+    All,
+}
+
 /// User account storage operations.
 ///
 /// Covers account creation, password management, admin flags, deactivation,
@@ -186,7 +219,8 @@ pub trait UserStore: Send + Sync {
 /// A "device" in Matrix is a login session identified by `(user_id, device_id)`.
 /// This trait manages the full lifecycle: creation at login, token lookup on
 /// every authenticated request, display name updates, and bulk removal at
-/// logout / password change.
+/// logout / password change.  It also records which device, if any, is the
+/// user's dehydrated device.
 #[async_trait]
 pub trait DeviceStore: Send + Sync {
     async fn create_device(&self, device: &DeviceRecord) -> StorageResult<()>;
@@ -210,6 +244,18 @@ pub trait DeviceStore: Send + Sync {
         device_id: &DeviceId,
         display_name: Option<&str>,
     ) -> StorageResult<()>;
+
+    /// Mark a device (created with [`create_device`](Self::create_device)) as
+    /// the user's dehydrated device, replacing any previous one.  Removing
+    /// the device also forgets it as the dehydrated device.
+    async fn set_dehydrated_device(
+        &self,
+        user_id: &UserId,
+        device: &DehydratedDevice,
+    ) -> StorageResult<()>;
+
+    /// The user's dehydrated device, or [`StorageError::NotFound`].
+    async fn get_dehydrated_device(&self, user_id: &UserId) -> StorageResult<DehydratedDevice>;
 }
 
 /// Room storage operations.
@@ -231,6 +277,8 @@ pub trait RoomStore: Send + Sync {
     async fn get_membership(&self, user_id: &str, room_id: &str) -> StorageResult<String>;
     async fn get_joined_rooms(&self, user_id: &str) -> StorageResult<Vec<String>>;
     async fn get_invited_rooms(&self, user_id: &str) -> StorageResult<Vec<String>>;
+    /// Get rooms the user has knocked on and is waiting to be let into.
+    async fn get_knocked_rooms(&self, user_id: &str) -> StorageResult<Vec<String>>;
     async fn get_left_rooms(&self, user_id: &str) -> StorageResult<Vec<String>>;
     /// Get rooms the user has left AND forgotten (for incremental sync).
     async fn get_forgotten_rooms(&self, user_id: &str) -> StorageResult<Vec<String>>;
@@ -242,11 +290,23 @@ pub trait RoomStore: Send + Sync {
     async fn delete_room_alias(&self, alias: &str) -> StorageResult<()>;
     async fn get_room_aliases(&self, room_id: &str) -> StorageResult<Vec<String>>;
     async fn set_room_visibility(&self, room_id: &str, visibility: &str) -> StorageResult<()>;
+    /// Publish (`"public"`) or remove (`"private"`) a room in the directory of
+    /// an appservice's bridged network.
+    async fn set_appservice_room_visibility(
+        &self,
+        appservice_id: &str,
+        network_id: &str,
+        room_id: &str,
+        visibility: &str,
+    ) -> StorageResult<()>;
+    /// List a page of the public rooms in `network`, returning the page and the
+    /// total number of rooms in that network.
     async fn get_public_rooms(
         &self,
         limit: usize,
         since: Option<&str>,
         filter: Option<&str>,
+        network: DirectoryNetwork<'_>,
     ) -> StorageResult<(Vec<PublicRoom>, usize)>;
     async fn forget_room(&self, user_id: &str, room_id: &str) -> StorageResult<()>;
 
@@ -296,6 +356,10 @@ pub trait EventStore: Send + Sync {
     /// Get all events across all rooms since a stream position (for incremental sync).
     async fn get_events_since(&self, since: i64) -> StorageResult<Vec<Pdu>>;
 
+    /// Get the first `limit` events across all rooms after a stream position,
+    /// oldest first (for background consumers that page through the stream).
+    async fn get_events_after(&self, since: i64, limit: usize) -> StorageResult<Vec<Pdu>>;
+
     /// Update the current room state map for a state event.
     async fn set_room_state(
         &self,
@@ -408,6 +472,10 @@ pub struct ReceiptRecord {
 ///   each key is deleted after a single claim.
 /// * **Cross-signing keys** -- master, self-signing, and user-signing keys
 ///   that form the cross-signing trust chain.
+///
+/// It also keeps each user's **device list change stream** (see
+/// [`DeviceListChange`]) and, for remote users, the stream ID up to which
+/// their cached device keys are known to be complete.
 #[async_trait]
 pub trait KeyStore: Send + Sync {
     /// Store/update device keys for a user's device.
@@ -421,6 +489,11 @@ pub trait KeyStore: Send + Sync {
     /// Get device keys for a list of users. Returns map: user_id -> { device_id -> keys }
     async fn get_device_keys(&self, user_ids: &[String]) -> StorageResult<serde_json::Value>;
 
+    /// Delete the device keys, one-time keys and fallback keys of one device,
+    /// or of all the user's devices when `device_id` is `None`.
+    async fn delete_device_keys(&self, user_id: &str, device_id: Option<&str>)
+    -> StorageResult<()>;
+
     /// Store one-time keys. Keys is a map of key_id -> key_data.
     async fn store_one_time_keys(
         &self,
@@ -438,13 +511,37 @@ pub trait KeyStore: Send + Sync {
 
     /// Claim one-time keys. Takes map: user_id -> { device_id -> algorithm }.
     /// Returns map: user_id -> { device_id -> { key_id -> key_data } }.
-    /// Claimed keys are deleted from storage.
+    /// Claimed keys are deleted from storage.  A device with no one-time key
+    /// left for the algorithm hands out its fallback key instead, which is
+    /// marked used but kept until the device replaces it.
     async fn claim_one_time_keys(
         &self,
         claims: &serde_json::Value,
     ) -> StorageResult<serde_json::Value>;
 
+    /// Store fallback keys (MSC2732). Keys is a map of key_id -> key_data,
+    /// where key_id is `algorithm:id`.  Each replaces the device's fallback
+    /// key for its algorithm and starts out unused; re-uploading the current
+    /// key keeps its state.
+    async fn store_fallback_keys(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        keys: &serde_json::Value,
+    ) -> StorageResult<()>;
+
+    /// Algorithms for which the device has a fallback key that was not
+    /// claimed yet.
+    async fn get_unused_fallback_key_types(
+        &self,
+        user_id: &str,
+        device_id: &str,
+    ) -> StorageResult<Vec<String>>;
+
     /// Store cross-signing keys (master, self_signing, user_signing).
+    ///
+    /// A master key whose `keys` differ from the stored one's is a
+    /// replacement; the stored key is moved to the user's master key history.
     async fn set_cross_signing_keys(
         &self,
         user_id: &str,
@@ -453,6 +550,212 @@ pub trait KeyStore: Send + Sync {
 
     /// Get cross-signing keys for a user.
     async fn get_cross_signing_keys(&self, user_id: &str) -> StorageResult<serde_json::Value>;
+
+    /// The master keys the user replaced, oldest first.
+    async fn get_master_key_history(&self, user_id: &str) -> StorageResult<Vec<ReplacedMasterKey>>;
+
+    /// Allow the user to replace their cross-signing keys without
+    /// user-interactive auth until `until` (or, with `None`, withdraw the
+    /// approval).
+    async fn set_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> StorageResult<()>;
+
+    /// Until when the user may replace their cross-signing keys without
+    /// user-interactive auth, if an approval was given.
+    async fn get_cross_signing_reset_allowed_until(
+        &self,
+        user_id: &str,
+    ) -> StorageResult<Option<chrono::DateTime<chrono::Utc>>>;
+
+    /// Store a signature on a device key or cross-signing key, replacing any
+    /// earlier signature by the same signing key.
+    async fn add_key_signature(&self, signature: &KeySignature) -> StorageResult<()>;
+
+    /// All signatures uploaded for the keys of `signed_user_id`.
+    async fn get_key_signatures(&self, signed_user_id: &str) -> StorageResult<Vec<KeySignature>>;
+
+    /// Delete every signature on one of the user's keys, e.g. when the device
+    /// it belongs to was deleted or uploaded different keys.
+    async fn delete_key_signatures(
+        &self,
+        signed_user_id: &str,
+        signed_key_id: &str,
+    ) -> StorageResult<()>;
+
+    /// Append a change to the user's device list stream, assigning the next
+    /// per-user `stream_id` and a new global stream position.
+    async fn add_device_list_change(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        deleted: bool,
+    ) -> StorageResult<DeviceListChange>;
+
+    /// The `stream_id` of the user's latest device list change (0 if none).
+    async fn get_device_list_stream_id(&self, user_id: &str) -> StorageResult<i64>;
+
+    /// Record at a new global stream position that the user's cross-signing
+    /// keys, or the signatures on them, changed.  Unlike a device list change
+    /// it takes no `stream_id`, since it is not sent in an
+    /// `m.device_list_update` EDU.  Returns the stream position.
+    async fn add_key_change(&self, user_id: &str) -> StorageResult<i64>;
+
+    /// Users with a device list change or key change at a global stream
+    /// position in `from..=to`.
+    async fn get_device_list_changed_users(&self, from: i64, to: i64)
+    -> StorageResult<Vec<String>>;
+
+    /// The stream ID up to which the cached device list of a remote user is
+    /// complete, or `None` if it is not cached (or needs a resync).
+    async fn get_remote_device_stream_id(&self, user_id: &str) -> StorageResult<Option<i64>>;
+
+    /// Record (or with `None`, invalidate) the stream ID of a remote user's
+    /// cached device list.
+    async fn set_remote_device_stream_id(
+        &self,
+        user_id: &str,
+        stream_id: Option<i64>,
+    ) -> StorageResult<()>;
+}
+
+/// One entry in a user's device list change stream.
+///
+/// Local users' `stream_id`s are sent in `m.device_list_update` EDUs, where
+/// the previous one forms the `prev_id` chain.  `stream_pos` is on the global
+/// stream, so `/sync` and `/keys/changes` can compare it with their tokens.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceListChange {
+    pub user_id: String,
+    /// The device that was added, updated or deleted.
+    pub device_id: String,
+    /// Position in this user's change stream, counting from 1.
+    pub stream_id: i64,
+    /// Global stream position at which the change was recorded.
+    pub stream_pos: i64,
+    pub deleted: bool,
+}
+
+/// A master key that was replaced by a newer one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplacedMasterKey {
+    /// The key as it was uploaded.
+    pub key: serde_json::Value,
+    pub replaced_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A signature uploaded through `/keys/signatures/upload`.
+///
+/// The signed key is a device (`signed_key_id` is the device ID) or a
+/// master key (`signed_key_id` is its unpadded base64 public key).  The
+/// signature is merged into the key's `signatures` when the key is queried.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySignature {
+    pub signed_user_id: String,
+    pub signed_key_id: String,
+    pub signing_user_id: String,
+    /// Key ID of the signing key, e.g. `ed25519:<public key>`.
+    pub signing_key_id: String,
+    pub signature: String,
+}
+
+/// Server-side key backup storage (`/room_keys`).
+///
+/// A user's backup has numbered versions; the newest version that has not
+/// been deleted is the current one, and version numbers are never reused.
+/// Each version holds the encrypted Megolm session keys the client backed up,
+/// keyed by `(room_id, session_id)`.  Its `etag` changes whenever its keys
+/// do, so clients can tell whether they need to download them again.
+#[async_trait]
+pub trait KeyBackupStore: Send + Sync {
+    /// Create a backup version, which becomes the user's current one.
+    /// Returns the new version.
+    async fn create_key_backup_version(
+        &self,
+        user_id: &str,
+        algorithm: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<String>;
+
+    /// Get a backup version, or the current one when `version` is `None`.
+    /// Deleted versions are [`StorageError::NotFound`].
+    async fn get_key_backup_version(
+        &self,
+        user_id: &str,
+        version: Option<&str>,
+    ) -> StorageResult<KeyBackupVersion>;
+
+    /// Replace the `auth_data` of a backup version.
+    async fn update_key_backup_version(
+        &self,
+        user_id: &str,
+        version: &str,
+        auth_data: &serde_json::Value,
+    ) -> StorageResult<()>;
+
+    /// Delete a backup version and every key in it.
+    async fn delete_key_backup_version(&self, user_id: &str, version: &str) -> StorageResult<()>;
+
+    /// Store session keys in a backup version, replacing any stored keys for
+    /// the same sessions, and change the version's etag once.
+    async fn put_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        keys: &[BackedUpRoomKey],
+    ) -> StorageResult<()>;
+
+    /// Get the keys of a backup version, optionally only those of one room
+    /// or one session.
+    async fn get_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<Vec<BackedUpRoomKey>>;
+
+    /// Delete the keys of a backup version, optionally only those of one
+    /// room or one session, and change the version's etag.
+    async fn delete_backed_up_room_keys(
+        &self,
+        user_id: &str,
+        version: &str,
+        room_id: Option<&str>,
+        session_id: Option<&str>,
+    ) -> StorageResult<()>;
+
+    /// Number of keys stored in a backup version.
+    async fn count_backed_up_room_keys(&self, user_id: &str, version: &str) -> StorageResult<i64>;
+}
+
+/// A version of a user's server-side key backup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyBackupVersion {
+    pub user_id: String,
+    pub version: String,
+    /// E.g. `m.megolm_backup.v1.curve25519-aes-sha2`.
+    pub algorithm: String,
+    /// Algorithm-specific data (public key, signatures), opaque to the server.
+    pub auth_data: serde_json::Value,
+    /// Changes whenever a key in this version is added, replaced or deleted.
+    pub etag: i64,
+}
+
+/// One Megolm session key in a key backup.
+///
+/// `session_data` is encrypted by the client; the other fields are what the
+/// server uses to decide whether an uploaded key should replace this one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BackedUpRoomKey {
+    pub room_id: String,
+    pub session_id: String,
+    pub first_message_index: i64,
+    pub forwarded_count: i64,
+    pub is_verified: bool,
+    pub session_data: serde_json::Value,
 }
 
 /// To-device message storage.
@@ -473,12 +776,14 @@ pub trait ToDeviceStore: Send + Sync {
         content: &serde_json::Value,
     ) -> StorageResult<()>;
 
-    /// Get pending to-device messages for a user's device since a position.
+    /// Get pending to-device messages for a user's device after `since`, up
+    /// to and including `up_to`.
     async fn get_to_device_messages(
         &self,
         user_id: &str,
         device_id: &str,
         since: i64,
+        up_to: i64,
     ) -> StorageResult<Vec<serde_json::Value>>;
 
     /// Delete to-device messages up to a position (after client acknowledges via sync).
@@ -602,8 +907,9 @@ pub trait MediaStore: Send + Sync {
 /// A server signing key record.
 ///
 /// This server's own ed25519 signing key pair, used to sign federation
-/// requests and events.  `valid_until` controls key rotation; expired keys
-/// are kept for verification of old signatures.
+/// requests and events.  When the key is rotated, `expired_at` is set and
+/// the key is kept so it can be published under `old_verify_keys` for
+/// verification of old signatures.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerKeyRecord {
     pub key_id: String,
@@ -611,6 +917,9 @@ pub struct ServerKeyRecord {
     pub public_key: String,
     pub private_key: String,
     pub valid_until: chrono::DateTime<chrono::Utc>,
+    /// When the key was retired by a rotation; `None` while it is active.
+    #[serde(default)]
+    pub expired_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 /// A cached remote server's public key.
@@ -626,21 +935,83 @@ pub struct RemoteKeyRecord {
     pub valid_until: chrono::DateTime<chrono::Utc>,
 }
 
+/// A cached `/_matrix/key/v2/server` response from a remote server.
+///
+/// The individual keys are also stored as [`RemoteKeyRecord`]s for signature
+/// verification, but a notary must hand out the response exactly as the origin
+/// signed it, so the full JSON is kept as well.  `valid_until` mirrors the
+/// response's `valid_until_ts`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerKeyResponseRecord {
+    pub server_name: String,
+    pub response: serde_json::Value,
+    pub valid_until: chrono::DateTime<chrono::Utc>,
+    pub fetched_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Delivery health of an outbound federation destination.
+///
+/// Written by the transaction sender after every attempt so that backoff
+/// survives restarts and operators can see which servers are lagging behind.
+/// `oldest_pending_pdu_ts` is the `origin_server_ts` of the oldest PDU still
+/// waiting to be delivered; the PDU lag is the time elapsed since then.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DestinationRecord {
+    pub server_name: String,
+    pub last_success_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_failure_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Consecutive failed transactions since the last success.
+    pub failure_count: u32,
+    /// When the destination may be tried again; `None` if it is not backing off.
+    pub retry_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+    pub pending_pdus: u64,
+    pub pending_edus: u64,
+    pub oldest_pending_pdu_ts: Option<i64>,
+}
+
 /// Federation key storage.
 ///
 /// Manages this server's signing key pairs, cached remote server public keys,
-/// and federation transaction deduplication (preventing replay of already-
-/// processed transaction IDs from a given origin server).
+/// federation transaction deduplication (preventing replay of already-
+/// processed transaction IDs from a given origin server), and the delivery
+/// health of outbound destinations.
 #[async_trait]
 pub trait FederationKeyStore: Send + Sync {
     async fn store_server_key(&self, key: &ServerKeyRecord) -> StorageResult<()>;
     async fn get_server_key(&self, key_id: &str) -> StorageResult<ServerKeyRecord>;
+
+    /// Keys that have not been rotated out, newest first.
     async fn get_active_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>>;
+
+    /// Keys retired by a rotation, published as `old_verify_keys`.
+    async fn get_expired_server_keys(&self) -> StorageResult<Vec<ServerKeyRecord>>;
+
+    /// Mark a key as retired at `expired_at`.
+    async fn expire_server_key(
+        &self,
+        key_id: &str,
+        expired_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<()>;
+
     async fn store_remote_server_keys(&self, keys: &[RemoteKeyRecord]) -> StorageResult<()>;
     async fn get_remote_server_keys(
         &self,
         server_name: &str,
     ) -> StorageResult<Vec<RemoteKeyRecord>>;
+
+    /// Cache the signed key response of a remote server, replacing any previous one.
+    async fn store_server_key_response(
+        &self,
+        record: &ServerKeyResponseRecord,
+    ) -> StorageResult<()>;
+
+    /// Get the cached key response of a remote server (even if it has expired).
+    async fn get_server_key_response(
+        &self,
+        server_name: &str,
+    ) -> StorageResult<ServerKeyResponseRecord>;
+
     async fn store_federation_txn(&self, origin: &str, txn_id: &str) -> StorageResult<()>;
     async fn has_federation_txn(&self, origin: &str, txn_id: &str) -> StorageResult<bool>;
 
@@ -649,6 +1020,15 @@ pub trait FederationKeyStore: Send + Sync {
     /// Called periodically by a background task to prevent unbounded growth of
     /// the deduplication table.  Returns the number of records removed.
     async fn cleanup_old_federation_txns(&self, max_age_secs: u64) -> StorageResult<u64>;
+
+    /// Create or replace the delivery state of a destination.
+    async fn upsert_destination(&self, record: &DestinationRecord) -> StorageResult<()>;
+
+    /// Get the delivery state of a destination.
+    async fn get_destination(&self, server_name: &str) -> StorageResult<DestinationRecord>;
+
+    /// All destinations we have ever tried to send to, sorted by server name.
+    async fn list_destinations(&self) -> StorageResult<Vec<DestinationRecord>>;
 }
 
 /// An event relation record.
@@ -682,12 +1062,26 @@ pub struct ReportRecord {
     pub score: i64,
 }
 
+/// A user's subscription to a thread (MSC4306), or their unsubscription from
+/// it, which is kept so later automatic subscriptions can be refused.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadSubscription {
+    pub room_id: String,
+    pub thread_root: String,
+    pub subscribed: bool,
+    /// Whether a client subscribed on the user's behalf.
+    pub automatic: bool,
+    /// Stream position of the last change.
+    pub bump_stamp: i64,
+}
+
 /// Event relation storage (threads, reactions, edits, references).
 ///
 /// Relations link a child event to a parent event via `rel_type`.  This
 /// trait supports storing relations, querying them with pagination, computing
 /// aggregated reaction counts, finding the latest edit for an event, listing
-/// thread roots in a room, and storing abuse reports.
+/// thread roots in a room, keeping users' thread subscriptions, and storing
+/// abuse reports.
 #[async_trait]
 pub trait RelationStore: Send + Sync {
     /// Store a relation between events.
@@ -718,6 +1112,29 @@ pub trait RelationStore: Send + Sync {
         from: Option<i64>,
     ) -> StorageResult<Vec<String>>;
 
+    /// Store a user's subscription to a thread, replacing the previous one.
+    async fn set_thread_subscription(
+        &self,
+        user_id: &str,
+        subscription: &ThreadSubscription,
+    ) -> StorageResult<()>;
+
+    /// Get a user's subscription to a thread; `NotFound` if they never
+    /// subscribed to it.
+    async fn get_thread_subscription(
+        &self,
+        user_id: &str,
+        room_id: &str,
+        thread_root: &str,
+    ) -> StorageResult<ThreadSubscription>;
+
+    /// Get a user's thread subscriptions changed after `since`.
+    async fn get_thread_subscriptions(
+        &self,
+        user_id: &str,
+        since: i64,
+    ) -> StorageResult<Vec<ThreadSubscription>>;
+
     /// Store an event report.
     async fn store_report(&self, report: &ReportRecord) -> StorageResult<()>;
 }
@@ -747,6 +1164,19 @@ pub struct AppServiceRecord {
     pub rate_limited: bool,
     /// Third-party protocols this AS bridges (e.g., ["slack", "irc"]).
     pub protocols: Vec<String>,
+    /// Whether typing, receipts, presence and to-device messages are pushed
+    /// to this AS alongside events (MSC2409).
+    #[serde(default, alias = "de.sorunome.msc2409.push_ephemeral")]
+    pub receive_ephemeral: bool,
+    /// Whether device list changes and one-time key counts of the AS's users
+    /// are included in its transactions (MSC3202).
+    #[serde(default, rename = "org.matrix.msc3202")]
+    pub msc3202: bool,
+    /// Path of the registration file this AS was loaded from, or `None` when
+    /// it was registered through the admin API.  File-loaded services are
+    /// removed again when their file leaves the configuration.
+    #[serde(default)]
+    pub registration_file: Option<String>,
 }
 
 /// A namespace pattern with exclusivity flag.
@@ -759,10 +1189,87 @@ pub struct NamespaceRule {
     pub exclusive: bool,
 }
 
+/// What an entry in an application service's delivery queue carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppServiceItemKind {
+    /// A room event in client format.
+    Event,
+    /// A typing, receipt or presence EDU (MSC2409).
+    Ephemeral,
+    /// A to-device message for one of the AS's users (MSC2409).
+    ToDevice,
+    /// `{"user_id"}` whose device list changed (MSC3202).
+    DeviceListChanged,
+    /// `{"user_id"}` who no longer shares a room with the AS's users (MSC3202).
+    DeviceListLeft,
+    /// `{"user_id", "device_id"}` whose one-time key count changed (MSC3202).
+    /// The count itself is read when the transaction is built.
+    OneTimeKeyCount,
+}
+
+impl AppServiceItemKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Event => "event",
+            Self::Ephemeral => "ephemeral",
+            Self::ToDevice => "to_device",
+            Self::DeviceListChanged => "device_list_changed",
+            Self::DeviceListLeft => "device_list_left",
+            Self::OneTimeKeyCount => "one_time_key_count",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "event" => Some(Self::Event),
+            "ephemeral" => Some(Self::Ephemeral),
+            "to_device" => Some(Self::ToDevice),
+            "device_list_changed" => Some(Self::DeviceListChanged),
+            "device_list_left" => Some(Self::DeviceListLeft),
+            "one_time_key_count" => Some(Self::OneTimeKeyCount),
+            _ => None,
+        }
+    }
+}
+
+/// An entry in an application service's durable delivery queue.
+#[derive(Debug, Clone)]
+pub struct AppServiceQueueItem {
+    /// Position in the queue; IDs only ever increase, so they give delivery order.
+    pub id: i64,
+    pub appservice_id: String,
+    pub kind: AppServiceItemKind,
+    pub content: serde_json::Value,
+}
+
+/// Delivery state of an application service, persisted after every attempt.
+///
+/// While a transaction is outstanding, `in_flight_up_to` holds the ID of the
+/// last queue item in it: retries resend exactly those items under the same
+/// `txn_id`, as the Application Service API requires.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AppServiceDelivery {
+    pub appservice_id: String,
+    /// Event stream position up to which events have been queued for the AS.
+    pub stream_position: i64,
+    /// ID of the outstanding transaction, or of the next one.
+    pub txn_id: i64,
+    pub in_flight_up_to: Option<i64>,
+    pub last_success_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_failure_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Consecutive failed attempts since the last success.
+    pub failure_count: u32,
+    /// When delivery may be tried again; `None` if the AS is not backing off.
+    pub retry_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+}
+
 /// Application Service storage.
 ///
 /// Manages registration records for application services (bridges, bots).
 /// The homeserver queries these to determine event routing and namespace ownership.
+/// Each AS also has a durable delivery queue and delivery state, so events
+/// survive restarts of either side until the AS acknowledges them.
 #[async_trait]
 pub trait ApplicationServiceStore: Send + Sync {
     /// Register or update an application service.
@@ -773,8 +1280,48 @@ pub trait ApplicationServiceStore: Send + Sync {
     async fn get_appservice(&self, id: &str) -> StorageResult<AppServiceRecord>;
     /// Look up an application service by its as_token.
     async fn get_appservice_by_token(&self, as_token: &str) -> StorageResult<AppServiceRecord>;
-    /// Remove an application service registration.
+    /// Remove an application service registration, its queue, delivery state
+    /// and lease.
     async fn delete_appservice(&self, id: &str) -> StorageResult<()>;
+
+    /// Append an item to an application service's delivery queue.
+    async fn enqueue_appservice_item(
+        &self,
+        appservice_id: &str,
+        kind: AppServiceItemKind,
+        content: &serde_json::Value,
+    ) -> StorageResult<i64>;
+
+    /// The oldest `limit` queued items of an application service, in queue order.
+    async fn get_appservice_queue(
+        &self,
+        appservice_id: &str,
+        limit: usize,
+    ) -> StorageResult<Vec<AppServiceQueueItem>>;
+
+    /// Remove delivered items, i.e. every item with an ID up to `up_to`.
+    async fn delete_appservice_queue(&self, appservice_id: &str, up_to: i64) -> StorageResult<()>;
+
+    /// Create or replace the delivery state of an application service.
+    async fn upsert_appservice_delivery(&self, record: &AppServiceDelivery) -> StorageResult<()>;
+
+    /// Get the delivery state of an application service.
+    async fn get_appservice_delivery(
+        &self,
+        appservice_id: &str,
+    ) -> StorageResult<AppServiceDelivery>;
+
+    /// Take or renew the lease on delivering to an application service.
+    ///
+    /// `holder` gets the lease, valid until `expires_at`, if it already holds
+    /// it, nobody does, or the previous holder's lease has expired.  Returns
+    /// whether `holder` holds the lease afterwards.
+    async fn acquire_appservice_lease(
+        &self,
+        appservice_id: &str,
+        holder: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> StorageResult<bool>;
 }
 
 /// Health check for storage backends.
@@ -800,6 +1347,7 @@ pub trait Storage:
     + EventStore
     + ReceiptStore
     + KeyStore
+    + KeyBackupStore
     + ToDeviceStore
     + AccountDataStore
     + MediaStore
@@ -821,6 +1369,7 @@ impl<T> Storage for T where
         + EventStore
         + ReceiptStore
         + KeyStore
+        + KeyBackupStore
         + ToDeviceStore
         + AccountDataStore
         + MediaStore
@@ -25,11 +25,13 @@
 //!
 //! 6. **Signing key** -- Loads the server's Ed25519 signing key from the DB, or
 //!    generates and stores a new one on first boot. Used for federation event
-//!    signatures.
+//!    signatures. A background task re-reads the active key every minute so a
+//!    rotation on any node reaches the whole cluster without a restart.
 //!
 //! 7. **Ephemeral store and cluster** -- Builds the in-memory ephemeral store
 //!    for typing notifications and presence. If a `[cluster]` section is present,
 //!    starts chitchat UDP gossip for cross-node propagation of ephemeral state.
+//!    The presence timers (idle/offline) are spawned once the state is built.
 //!
 //! 8. **Federation** -- Builds the federation HTTP client (with optional CA for
 //!    Complement testing) and the federation router (Server-Server API).
@@ -37,15 +39,25 @@
 //! 9. **Admin and CS API** -- Builds the admin dashboard/API router and the
 //!    Client-Server API router, then merges all three into one Axum application.
 //!
+//!    Application service registration files from `[appservice]` are loaded
+//!    here and reloaded whenever the process receives `SIGHUP`.
+//!
 //! 10. **TLS listener** (optional) -- If `server.federation_address`, `tls_cert`,
 //!     and `tls_key` are all set, spawns a separate TLS listener on port 8448
 //!     for federation traffic.
 //!
 //! 11. **Serve** -- Binds the main listener on `server.bind_address` and serves.
 //!
+//! ## Commands
+//!
+//! `maelstrom rotate-signing-key` connects to the database, generates a new
+//! signing key, marks the current one as expiring after a grace period (it is
+//! then published under `old_verify_keys`), and exits. Running servers pick up
+//! the new key on their next refresh, well within the grace period.
+//!
 //! ## Config file format
 //!
-//! The configuration is TOML with four sections:
+//! The configuration is TOML with seven sections:
 //!
 //! ```toml
 //! [server]
@@ -77,6 +89,19 @@
 //! listen_addr = "0.0.0.0:7280"
 //! seed_nodes = ["node2:7280"]
 //! cluster_id = "maelstrom"
+//!
+//! [federation]                       # optional
+//! trusted_key_servers = [
+//!     { server_name = "matrix.org", verify_keys = { "ed25519:auto" = "Noi6WqcDj0QmPxCNQqgezwTlBKrfqehY1u2FyWP9uYw" } },
+//! ]
+//! domain_allowlist = []              # if non-empty, only federate with these
+//! domain_denylist = ["*.spam.example"] # never federate with these
+//!
+//! [appservice]                       # optional
+//! registration_paths = ["/etc/maelstrom/appservices"] # YAML files or directories
+//!
+//! [presence]                         # optional
+//! mode = "federated"                 # "off", "local_only" or "federated" (default)
 //! ```
 //!
 //! ## Single-node vs. cluster mode
@@ -116,6 +141,12 @@ struct Config {
     media: Option<MediaConfig>,
     #[serde(default)]
     cluster: Option<ClusterConfig>,
+    #[serde(default)]
+    federation: FederationConfig,
+    #[serde(default)]
+    appservice: AppServiceConfig,
+    #[serde(default)]
+    presence: PresenceConfig,
 }
 
 /// Listener addresses, TLS paths, and server identity.
@@ -182,6 +213,40 @@ fn default_cluster_id() -> String {
     "maelstrom".to_string()
 }
 
+/// Server-to-server behaviour.
+#[derive(Debug, Default, Deserialize)]
+struct FederationConfig {
+    /// Notaries asked for a server's signing keys when the server itself is
+    /// unreachable (e.g. `[{ server_name = "matrix.org", verify_keys = { ... } }]`).
+    #[serde(default)]
+    trusted_key_servers: Vec<maelstrom_federation::key_server::TrustedKeyServer>,
+    /// If non-empty, only servers matching one of these glob patterns are
+    /// federated with (same syntax as `m.room.server_acl`).
+    #[serde(default)]
+    domain_allowlist: Vec<String>,
+    /// Servers matching any of these glob patterns are never federated with.
+    #[serde(default)]
+    domain_denylist: Vec<String>,
+}
+
+/// Application service registrations managed through configuration.
+#[derive(Debug, Default, Deserialize)]
+struct AppServiceConfig {
+    /// Registration YAML files, or directories of them, loaded at startup
+    /// and on `SIGHUP`.
+    #[serde(default)]
+    registration_paths: Vec<std::path::PathBuf>,
+}
+
+/// Server-wide presence behaviour.
+#[derive(Debug, Default, Deserialize)]
+struct PresenceConfig {
+    /// `off` disables presence, `local_only` keeps it to this server and
+    /// `federated` (the default) also exchanges it with remote servers.
+    #[serde(default)]
+    mode: maelstrom_core::matrix::ephemeral::PresenceMode,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Install rustls crypto provider (ring)
@@ -217,6 +282,11 @@ async fn main() -> Result<()> {
         .await
         .context("Failed to connect to SurrealDB")?;
 
+    // One-shot maintenance commands
+    if let Some(command) = std::env::args().nth(1) {
+        return run_command(&command, &storage).await;
+    }
+
     // Bootstrap admin user from config if specified
     if let Some(admin_username) = &config.server.admin_user {
         use maelstrom_storage::traits::UserStore;
@@ -285,41 +355,45 @@ async fn main() -> Result<()> {
         None
     };
 
-    // Initialize federation signing key
+    // Initialize federation signing key (shared, so rotations take effect in place)
     let server_name = ServerName::new(&config.server.server_name);
-    let signing_key = {
-        use maelstrom_storage::traits::FederationKeyStore;
-        let keys = storage.get_active_server_keys().await.unwrap_or_default();
-        if let Some(key_record) = keys.first() {
-            use base64::Engine;
-            let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
-            let private_bytes = engine
-                .decode(&key_record.private_key)
-                .context("Invalid stored private key")?;
-            let key_bytes: [u8; 32] = private_bytes
-                .try_into()
-                .map_err(|_| anyhow::anyhow!("Invalid key length"))?;
-            info!(key_id = %key_record.key_id, "Loaded existing signing key");
-            maelstrom_core::matrix::keys::KeyPair::from_bytes(key_record.key_id.clone(), &key_bytes)
-        } else {
-            let kp = maelstrom_core::matrix::keys::KeyPair::generate();
-            use base64::Engine;
-            let engine = base64::engine::general_purpose::STANDARD_NO_PAD;
-            let record = maelstrom_storage::traits::ServerKeyRecord {
-                key_id: kp.key_id().to_string(),
-                algorithm: "ed25519".to_string(),
-                public_key: kp.public_key_base64(),
-                private_key: engine.encode(kp.private_key_bytes()),
-                valid_until: chrono::Utc::now() + chrono::Duration::days(365),
-            };
-            storage
-                .store_server_key(&record)
-                .await
-                .context("Failed to store signing key")?;
-            info!(key_id = %kp.key_id(), "Generated new signing key");
+    let signing_key = match maelstrom_admin::signing_keys::load_signing_key(&storage)
+        .await
+        .context("Failed to load signing key")?
+    {
+        Some(kp) => {
+            info!(key_id = %kp.key_id(), "Loaded existing signing key");
             kp
         }
+        None => maelstrom_admin::signing_keys::rotate_signing_key(&storage)
+            .await
+            .context("Failed to store signing key")?,
     };
+    let signing_key = maelstrom_core::matrix::keys::SharedKeyPair::new(signing_key);
+
+    // Follow key rotations made on other nodes or from the CLI.
+    {
+        let key_storage = storage.clone();
+        let shared_key = signing_key.clone();
+        tokio::spawn(async move {
+            use maelstrom_admin::signing_keys::KEY_REFRESH_INTERVAL_SECS;
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(KEY_REFRESH_INTERVAL_SECS)).await;
+                match maelstrom_admin::signing_keys::load_signing_key(&key_storage).await {
+                    Ok(Some(kp)) => {
+                        let key_id = kp.key_id().to_string();
+                        if shared_key.replace(kp) {
+                            info!(key_id = %key_id, "Switched to rotated signing key");
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Signing key refresh failed");
+                    }
+                }
+            }
+        });
+    }
 
     // Build shared ephemeral store for typing/presence.
     // In cluster mode, wire up chitchat gossip for cross-node propagation.
@@ -356,7 +430,7 @@ async fn main() -> Result<()> {
         };
 
         let (store, delta_rx) = maelstrom_core::matrix::ephemeral::EphemeralStore::with_gossip();
-        let ephemeral = std::sync::Arc::new(store);
+        let ephemeral = std::sync::Arc::new(store.with_presence_mode(config.presence.mode));
 
         let chitchat_handle =
             chitchat::spawn_chitchat(chitchat_config, vec![], &chitchat::transport::UdpTransport)
@@ -379,30 +453,47 @@ async fn main() -> Result<()> {
 
         (ephemeral, Some((chitchat_handle, bridge)))
     } else {
-        let ephemeral =
-            std::sync::Arc::new(maelstrom_core::matrix::ephemeral::EphemeralStore::new());
+        let ephemeral = std::sync::Arc::new(
+            maelstrom_core::matrix::ephemeral::EphemeralStore::new()
+                .with_presence_mode(config.presence.mode),
+        );
         info!("Single-node mode (no [cluster] config)");
         (ephemeral, None)
     };
 
+    // Server-wide federation allow/deny lists, shared by every federation
+    // client, the federation router and the admin API
+    let federation_policy =
+        std::sync::Arc::new(maelstrom_federation::policy::FederationPolicy::new(
+            maelstrom_federation::policy::DomainLists {
+                allow: config.federation.domain_allowlist,
+                deny: config.federation.domain_denylist,
+            },
+        ));
+
     // Build federation client (shared between federation state and CS API)
-    let federation_client =
-        std::sync::Arc::new(maelstrom_federation::client::FederationClient::with_ca(
+    let federation_client = std::sync::Arc::new(
+        maelstrom_federation::client::FederationClient::with_ca(
             signing_key.clone(),
             server_name.clone(),
             config.server.complement_ca.as_deref(),
-        ));
+        )
+        .with_policy(federation_policy.clone()),
+    );
 
     // Build outbound federation transaction sender
-    let transaction_sender =
-        std::sync::Arc::new(maelstrom_federation::sender::TransactionSender::new(
+    let transaction_sender = std::sync::Arc::new(
+        maelstrom_federation::sender::TransactionSender::new(
             maelstrom_federation::client::FederationClient::with_ca(
                 signing_key.clone(),
                 server_name.clone(),
                 config.server.complement_ca.as_deref(),
-            ),
+            )
+            .with_policy(federation_policy.clone()),
             server_name.to_string(),
-        ));
+        )
+        .with_storage(storage.clone()),
+    );
     // Spawn the sender background loop
     tokio::spawn(transaction_sender.clone().run());
 
@@ -418,13 +509,27 @@ async fn main() -> Result<()> {
                     .await;
             });
         });
+    let notifier_for_fed = notifier.clone();
+    let user_notify: maelstrom_federation::UserNotifyFn =
+        std::sync::Arc::new(move |user_id: &str| {
+            let notifier = notifier_for_fed.clone();
+            let user_id = user_id.to_string();
+            tokio::spawn(async move {
+                notifier
+                    .notify(maelstrom_api::notify::Notification::ToDevice { user_id })
+                    .await;
+            });
+        });
     let federation_state = maelstrom_federation::FederationState::with_room_notify(
         storage.clone(),
         ephemeral.clone(),
-        signing_key,
+        signing_key.clone(),
         server_name.clone(),
         room_notify,
-    );
+    )
+    .with_user_notify(user_notify)
+    .with_trusted_key_servers(config.federation.trusted_key_servers)
+    .with_federation_policy(federation_policy.clone());
     let federation_router = maelstrom_federation::router::build(federation_state);
 
     // Spawn background task to clean up old federation transaction dedup records.
@@ -467,7 +572,10 @@ async fn main() -> Result<()> {
         storage.clone(),
         server_name.clone(),
         admin_retention,
-    );
+    )
+    .with_signing_key(signing_key.clone())
+    .with_transaction_sender(transaction_sender.clone())
+    .with_federation_policy(federation_policy);
     let admin_router = maelstrom_admin::router::build(admin_state);
 
     // Build application state
@@ -489,9 +597,45 @@ async fn main() -> Result<()> {
             config.server.public_base_url,
         )
     };
+    let appservice_sender =
+        std::sync::Arc::new(maelstrom_api::appservice_sender::AppServiceSender::new());
     let state = state
         .with_federation(federation_client)
-        .with_transaction_sender(transaction_sender);
+        .with_transaction_sender(transaction_sender)
+        .with_appservice_sender(appservice_sender.clone());
+
+    // Spawn the application service sender, which reads events and its queues
+    // through the application state
+    tokio::spawn(appservice_sender.run(state.clone()));
+
+    // Spawn the presence idle/offline timers and federation refresh
+    tokio::spawn(maelstrom_api::presence::run(state.clone()));
+
+    // Load application service registration files, and reload them (re-reading
+    // the config for the list of paths) on SIGHUP
+    maelstrom_api::appservice::load_registration_files(
+        &state,
+        &config.appservice.registration_paths,
+    )
+    .await;
+    {
+        let state = state.clone();
+        let mut paths = config.appservice.registration_paths.clone();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .context("Failed to install SIGHUP handler")?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading application service registrations");
+                match load_config() {
+                    Ok(config) => paths = config.appservice.registration_paths,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to reload configuration, keeping previous registration paths");
+                    }
+                }
+                maelstrom_api::appservice::load_registration_files(&state, &paths).await;
+            }
+        });
+    }
 
     let app = maelstrom_api::router::build(state)
         .merge(federation_router)
@@ -542,6 +686,24 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Run a maintenance command instead of starting the server.
+///
+/// - `rotate-signing-key` -- generate a new signing key and expire the current
+///   one after a grace period. Running servers switch to it within a minute,
+///   and the old key stays valid for ten.
+async fn run_command(command: &str, storage: &maelstrom_storage::SurrealStorage) -> Result<()> {
+    match command {
+        "rotate-signing-key" => {
+            let key = maelstrom_admin::signing_keys::rotate_signing_key(storage)
+                .await
+                .context("Failed to rotate signing key")?;
+            println!("New signing key: {}", key.key_id());
+            Ok(())
+        }
+        other => anyhow::bail!("Unknown command: {other} (available: rotate-signing-key)"),
+    }
+}
+
 fn load_config() -> Result<Config> {
     let config_path =
         std::env::var("MAELSTROM_CONFIG").unwrap_or_else(|_| "config/local.toml".to_string());
@@ -552,5 +714,11 @@ fn load_config() -> Result<Config> {
     let config: Config = toml::from_str(&content)
         .with_context(|| format!("Failed to parse config: {config_path}"))?;
 
+    for notary in &config.federation.trusted_key_servers {
+        notary
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Invalid config {config_path}: {e}"))?;
+    }
+
     Ok(config)
 }
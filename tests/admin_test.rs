@@ -205,3 +205,410 @@ async fn test_admin_dashboard_page() {
     // No inline styles
     assert!(!html.contains("style="));
 }
+
+#[tokio::test]
+async fn test_admin_rotate_signing_key() {
+    use maelstrom_core::matrix::keys::SharedKeyPair;
+
+    let storage = MockStorage::new();
+    let token = setup_admin_user(&storage).await;
+
+    // First boot: generate the initial key
+    let initial = maelstrom_admin::signing_keys::rotate_signing_key(&storage)
+        .await
+        .unwrap();
+    let shared = SharedKeyPair::new(initial.clone());
+
+    let state = maelstrom_admin::AdminState::new(storage, ServerName::new("localhost"))
+        .with_signing_key(shared.clone());
+    let router = maelstrom_admin::router::build(state);
+
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/federation/keys/rotate")
+        .method("POST")
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    let resp = router.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let new_key_id = json["key_id"].as_str().unwrap().to_string();
+    assert_ne!(new_key_id, initial.key_id());
+
+    // The running server switched to the new key immediately
+    assert_eq!(shared.current().key_id(), new_key_id);
+
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/federation/keys")
+        .method("GET")
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    let resp = router.oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(json["current"], new_key_id.as_str());
+    let active = json["active"].as_array().unwrap();
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0]["key_id"], new_key_id.as_str());
+    assert!(active[0].get("private_key").is_none());
+    let expired = json["expired"].as_array().unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0]["key_id"], initial.key_id());
+    assert!(expired[0]["expired_ts"].is_number());
+}
+
+#[tokio::test]
+async fn test_admin_destination_health_and_reset() {
+    use maelstrom_storage::traits::{DestinationRecord, FederationKeyStore};
+
+    let storage = MockStorage::new();
+    let token = setup_admin_user(&storage).await;
+    storage
+        .upsert_destination(&DestinationRecord {
+            server_name: "remote.example.com".to_string(),
+            failure_count: 3,
+            last_failure_at: Some(chrono::Utc::now()),
+            retry_at: Some(chrono::Utc::now() + chrono::Duration::minutes(5)),
+            last_error: Some("connection refused".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let state = maelstrom_admin::AdminState::new(storage, ServerName::new("localhost"));
+    let router = maelstrom_admin::router::build(state);
+
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/federation/destinations",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["total"], 1);
+    assert_eq!(json["destinations"][0]["server_name"], "remote.example.com");
+    assert_eq!(json["destinations"][0]["failure_count"], 3);
+    assert_eq!(json["destinations"][0]["last_error"], "connection refused");
+    assert!(json["destinations"][0]["retry_at_ts"].is_number());
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/_maelstrom/admin/v1/federation/destinations/remote.example.com/reset-backoff",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/federation/destinations/remote.example.com",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["failure_count"], 0);
+    assert!(json["retry_at_ts"].is_null());
+    assert!(json["last_error"].is_null());
+
+    let resp = router
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/federation/destinations/unknown.example.com",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_admin_destination_queue_purge() {
+    use maelstrom_core::matrix::keys::KeyPair;
+    use maelstrom_federation::client::FederationClient;
+    use maelstrom_federation::sender::TransactionSender;
+    use std::sync::Arc;
+
+    let storage = MockStorage::new();
+    let token = setup_admin_user(&storage).await;
+
+    // The sender loop is not running, so queued events stay put.
+    let sender = Arc::new(TransactionSender::new(
+        FederationClient::new(KeyPair::generate(), ServerName::new("localhost")),
+        "localhost".to_string(),
+    ));
+    let pdu = maelstrom_core::matrix::event::Pdu {
+        event_id: "$queued".to_string(),
+        room_id: "!room:localhost".to_string(),
+        sender: "@alice:localhost".to_string(),
+        event_type: "m.room.message".to_string(),
+        state_key: None,
+        content: serde_json::json!({"body": "hi"}),
+        origin_server_ts: 1_000,
+        unsigned: None,
+        stream_position: 1,
+        origin: Some("localhost".to_string()),
+        auth_events: None,
+        prev_events: None,
+        depth: None,
+        hashes: None,
+        signatures: None,
+    };
+    sender.queue_pdu("remote.example.com", &pdu);
+    sender.queue_edu(
+        "remote.example.com",
+        serde_json::json!({"edu_type": "m.typing"}),
+    );
+
+    let state = maelstrom_admin::AdminState::new(storage, ServerName::new("localhost"))
+        .with_transaction_sender(sender.clone());
+    let router = maelstrom_admin::router::build(state);
+
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/federation/destinations/remote.example.com",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["pending_pdus"], 1);
+    assert_eq!(json["pending_edus"], 1);
+    assert!(json["pdu_lag_ms"].as_i64().unwrap() > 0);
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/_maelstrom/admin/v1/federation/destinations/remote.example.com/purge",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["purged_pdus"], 1);
+    assert_eq!(json["purged_edus"], 1);
+
+    let record = sender.destination("remote.example.com").unwrap();
+    assert_eq!(record.pending_pdus, 0);
+    assert_eq!(record.pending_edus, 0);
+    assert!(record.oldest_pending_pdu_ts.is_none());
+
+    // The dashboard lists the destination
+    let resp = router
+        .oneshot(request("GET", "/_maelstrom/admin/federation"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(String::from_utf8_lossy(&body).contains("remote.example.com"));
+}
+
+#[tokio::test]
+async fn test_admin_federation_policy() {
+    use maelstrom_federation::policy::{FederationPolicy, PolicyCheck};
+    use std::sync::Arc;
+
+    let storage = MockStorage::new();
+    let token = setup_admin_user(&storage).await;
+
+    let policy = Arc::new(FederationPolicy::default());
+    let state = maelstrom_admin::AdminState::new(storage, ServerName::new("localhost"))
+        .with_federation_policy(policy.clone());
+    let router = maelstrom_admin::router::build(state);
+
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/federation/policy")
+        .method("PUT")
+        .header("Authorization", format!("Bearer {token}"))
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"deny": ["*.evil.example"]}"#))
+        .unwrap();
+    let resp = router.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The shared policy enforces the new list right away
+    assert!(!policy.check("spam.evil.example", PolicyCheck::Media));
+    assert!(policy.is_allowed("friendly.example"));
+
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/federation/policy")
+        .method("GET")
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    let resp = router.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["deny"], serde_json::json!(["*.evil.example"]));
+    assert_eq!(json["allow"], serde_json::json!([]));
+    assert_eq!(json["rejections"]["media"], 1);
+
+    // Empty patterns are rejected
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/federation/policy")
+        .method("PUT")
+        .header("Authorization", format!("Bearer {token}"))
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"allow": [""]}"#))
+        .unwrap();
+    let resp = router.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let req = Request::builder()
+        .uri("/_maelstrom/admin/v1/metrics")
+        .method("GET")
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    let resp = router.oneshot(req).await.unwrap();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(r#"maelstrom_federation_policy_rejections_total{direction="media"} 1"#));
+}
+
+#[tokio::test]
+async fn test_admin_cross_signing_history_and_reset_approval() {
+    use maelstrom_storage::traits::KeyStore;
+
+    let storage = MockStorage::new();
+    let token = setup_admin_user(&storage).await;
+    let master = |key: &str| {
+        serde_json::json!({"master_key": {
+            "user_id": "@admin:localhost", "usage": ["master"], "keys": {"ed25519:k": key},
+        }})
+    };
+    for key in ["first", "second"] {
+        storage
+            .set_cross_signing_keys("@admin:localhost", &master(key))
+            .await
+            .unwrap();
+    }
+
+    let state = maelstrom_admin::AdminState::new(storage, ServerName::new("localhost"));
+    let router = maelstrom_admin::router::build(state);
+    let request = |method: &str, uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .method(method)
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let get_json = |resp: axum::response::Response| async move {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/users/@admin:localhost/cross-signing",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let json = get_json(resp).await;
+    assert_eq!(json["keys"]["master_key"]["keys"]["ed25519:k"], "second");
+    assert_eq!(
+        json["master_key_history"][0]["key"]["keys"]["ed25519:k"],
+        "first"
+    );
+    assert_eq!(json["reset_allowed_until"], serde_json::Value::Null);
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/_maelstrom/admin/v1/users/@admin:localhost/cross-signing/approve-reset",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "GET",
+            "/_maelstrom/admin/v1/users/@admin:localhost/cross-signing",
+        ))
+        .await
+        .unwrap();
+    assert!(get_json(resp).await["reset_allowed_until"].is_string());
+
+    let resp = router
+        .clone()
+        .oneshot(request(
+            "POST",
+            "/_maelstrom/admin/v1/users/@nobody:localhost/cross-signing/approve-reset",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // A remote user sharing a local user's localpart
+    let resp = router
+        .oneshot(request(
+            "POST",
+            "/_maelstrom/admin/v1/users/@admin:remote.example.com/cross-signing/approve-reset",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,695 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::Json;
+use axum::extract::{Path, State};
+use axum::routing::put;
+use http::StatusCode;
+use maelstrom_api::appservice_sender::AppServiceSender;
+use maelstrom_api::router;
+
+/// Transactions received by the fake application service: `(txn_id, body)`.
+type Received = Arc<Mutex<Vec<(String, serde_json::Value)>>>;
+
+/// Start a fake application service that fails its first `failures`
+/// transactions with a 500 and records every transaction it receives.
+async fn fake_appservice(failures: usize) -> (String, Received) {
+    #[derive(Clone)]
+    struct Fake {
+        received: Received,
+        failures: usize,
+    }
+
+    async fn transaction(
+        State(fake): State<Fake>,
+        Path(txn_id): Path<String>,
+        Json(body): Json<serde_json::Value>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let mut received = fake.received.lock().unwrap();
+        received.push((txn_id, body));
+        if received.len() <= fake.failures {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({})),
+            )
+        } else {
+            (StatusCode::OK, Json(serde_json::json!({})))
+        }
+    }
+
+    let received = Received::default();
+    let app = axum::Router::new()
+        .route("/_matrix/app/v1/transactions/{txnId}", put(transaction))
+        .with_state(Fake {
+            received: received.clone(),
+            failures,
+        });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, received)
+}
+
+/// Wait until the received transactions satisfy `predicate`.
+async fn wait_for(received: &Received, predicate: impl Fn(&[(String, serde_json::Value)]) -> bool) {
+    for _ in 0..100 {
+        if predicate(&received.lock().unwrap()) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!(
+        "transaction not received; got {:?}",
+        received.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_appservice_transactions_are_retried_in_order() {
+    let (url, received) = fake_appservice(1).await;
+
+    let sender = Arc::new(AppServiceSender::new());
+    let state = common::test_state().with_appservice_sender(sender.clone());
+    tokio::spawn(sender.run(state.clone()));
+    let router = router::build(state);
+
+    let registration = serde_json::json!({
+        "id": "ircbridge",
+        "url": url,
+        "as_token": "as_token_irc",
+        "hs_token": "hs_token_irc",
+        "sender_localpart": "ircbot",
+        "user_namespaces": [{ "regex": "@irc_.*:localhost", "exclusive": false }],
+        "alias_namespaces": [],
+        "rate_limited": false,
+        "protocols": ["irc"],
+        "receive_ephemeral": true,
+    });
+    let (status, resp) =
+        common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+    assert_eq!(status, StatusCode::OK, "register appservice failed: {resp}");
+    // Let the sender pick up the registration before any events exist
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (token, _, _) = common::register_user(&router, "irc_alice", "pass").await;
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({ "name": "Bridged" }),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "createRoom failed: {resp}");
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    for (txn, body) in [("t1", "first"), ("t2", "second")] {
+        let (status, _) = common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn}"),
+            &serde_json::json!({ "msgtype": "m.text", "body": body }),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let bodies = |txn: &serde_json::Value| -> Vec<String> {
+        txn["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|e| e["content"]["body"].as_str().map(str::to_string))
+            .collect()
+    };
+    // The first attempt fails, so wait for the "second" message after it
+    wait_for(&received, |txns| {
+        txns.iter()
+            .skip(1)
+            .any(|(_, txn)| bodies(txn).contains(&"second".to_string()))
+    })
+    .await;
+
+    {
+        let received = received.lock().unwrap();
+        // The failed first transaction was retried with the same ID and contents
+        assert_eq!(received[0].0, received[1].0);
+        assert_eq!(received[0].1, received[1].1);
+
+        let delivered: Vec<String> = received[1..].iter().flat_map(|(_, b)| bodies(b)).collect();
+        let first = delivered.iter().position(|b| b == "first").unwrap();
+        let second = delivered.iter().position(|b| b == "second").unwrap();
+        assert!(first < second, "events out of order: {delivered:?}");
+    }
+
+    // Typing in a room with the bridge's users is pushed as ephemeral data
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/typing/@irc_alice:localhost"),
+        &serde_json::json!({ "typing": true, "timeout": 30000 }),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    wait_for(&received, |txns| {
+        txns.iter().any(|(_, txn)| {
+            txn["ephemeral"].as_array().is_some_and(|edus| {
+                edus.iter().any(|edu| {
+                    edu["type"] == "m.typing"
+                        && edu["room_id"] == room_id.as_str()
+                        && edu["content"]["user_ids"][0] == "@irc_alice:localhost"
+                })
+            })
+        })
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_appservice_delivery_is_leased_to_one_node() {
+    let (url, received) = fake_appservice(0).await;
+
+    // Two nodes sharing one database
+    let sender = Arc::new(AppServiceSender::new());
+    let state = common::test_state().with_appservice_sender(sender.clone());
+    tokio::spawn(sender.run(state.clone()));
+    tokio::spawn(Arc::new(AppServiceSender::new()).run(state.clone()));
+    let router = router::build(state);
+
+    let registration = serde_json::json!({
+        "id": "ircbridge",
+        "url": url,
+        "as_token": "as_token_irc",
+        "hs_token": "hs_token_irc",
+        "sender_localpart": "ircbot",
+        "user_namespaces": [{ "regex": "@irc_.*:localhost", "exclusive": false }],
+        "alias_namespaces": [],
+        "rate_limited": false,
+        "protocols": ["irc"],
+    });
+    let (status, resp) =
+        common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+    assert_eq!(status, StatusCode::OK, "register appservice failed: {resp}");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (token, _, _) = common::register_user(&router, "irc_bob", "pass").await;
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    for txn in ["a", "b", "c"] {
+        let (status, _) = common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn}"),
+            &serde_json::json!({ "msgtype": "m.text", "body": txn }),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let bodies = |txns: &[(String, serde_json::Value)]| -> Vec<String> {
+        txns.iter()
+            .flat_map(|(_, txn)| txn["events"].as_array().cloned().unwrap_or_default())
+            .filter_map(|e| e["content"]["body"].as_str().map(str::to_string))
+            .collect()
+    };
+    wait_for(&received, |txns| bodies(txns).contains(&"c".to_string())).await;
+    tokio::time::sleep(Duration::from_millis(600)).await;
+
+    // Every event and transaction arrives once
+    let received = received.lock().unwrap();
+    assert_eq!(bodies(&received), vec!["a", "b", "c"]);
+    let mut txn_ids: Vec<&str> = received.iter().map(|(id, _)| id.as_str()).collect();
+    txn_ids.sort();
+    txn_ids.dedup();
+    assert_eq!(txn_ids.len(), received.len());
+}
+
+#[tokio::test]
+async fn test_mock_appservice_leases() {
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::ApplicationServiceStore;
+
+    let store = MockStorage::new();
+    let later = chrono::Utc::now() + chrono::Duration::seconds(60);
+    assert!(
+        store
+            .acquire_appservice_lease("bridge", "node-a", later)
+            .await
+            .unwrap()
+    );
+    // Held by node-a until it expires; node-a may renew it
+    assert!(
+        !store
+            .acquire_appservice_lease("bridge", "node-b", later)
+            .await
+            .unwrap()
+    );
+    assert!(
+        store
+            .acquire_appservice_lease("bridge", "node-a", later)
+            .await
+            .unwrap()
+    );
+
+    let expired = chrono::Utc::now() - chrono::Duration::seconds(1);
+    store
+        .acquire_appservice_lease("bridge", "node-a", expired)
+        .await
+        .unwrap();
+    assert!(
+        store
+            .acquire_appservice_lease("bridge", "node-b", later)
+            .await
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn test_appservice_identity_assertion_and_exclusive_namespaces() {
+    let router = common::test_router();
+    let registration = serde_json::json!({
+        "id": "slack",
+        "url": "http://127.0.0.1:1",
+        "as_token": "as_token_slack",
+        "hs_token": "hs_token_slack",
+        "sender_localpart": "slackbot",
+        "user_namespaces": [{ "regex": "@slack_.*:localhost", "exclusive": true }],
+        "alias_namespaces": [{ "regex": "#slack_.*:localhost", "exclusive": true }],
+        "rate_limited": false,
+        "protocols": [],
+    });
+    let (status, _) =
+        common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Ordinary users cannot take names or aliases in the exclusive namespaces
+    let (status, resp) = common::post_json(
+        &router,
+        "/_matrix/client/v3/register",
+        &serde_json::json!({
+            "username": "slack_mallory",
+            "password": "pass",
+            "auth": { "type": "m.login.dummy" },
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(resp.contains("M_EXCLUSIVE"), "{resp}");
+
+    // The AS registers its own users without UIA or a password
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/register",
+        &serde_json::json!({
+            "type": "m.login.application_service",
+            "username": "slack_bob",
+            "inhibit_login": true,
+        }),
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "AS registration failed: {resp}");
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/register",
+        &serde_json::json!({ "type": "m.login.application_service", "username": "carol" }),
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(resp.contains("M_EXCLUSIVE"), "{resp}");
+
+    // Identity assertion: the AS acts as slack_bob
+    let (status, resp) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/account/whoami?user_id=%40slack_bob%3Alocalhost",
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "whoami failed: {resp}");
+    assert!(resp.contains("@slack_bob:localhost"), "{resp}");
+
+    // ...but not as users outside its namespaces, or unregistered ones
+    let (user_token, _, _) = common::register_user(&router, "alice", "pass").await;
+    for user_id in ["%40alice%3Alocalhost", "%40slack_nobody%3Alocalhost"] {
+        let (status, _) = common::get_authed(
+            &router,
+            &format!("/_matrix/client/v3/account/whoami?user_id={user_id}"),
+            "as_token_slack",
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "masqueraded as {user_id}");
+    }
+
+    // AS login gives slack_bob a real device
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/login",
+        &serde_json::json!({
+            "type": "m.login.application_service",
+            "identifier": { "type": "m.id.user", "user": "slack_bob" },
+        }),
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "AS login failed: {resp}");
+    let device_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["device_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // MSC3202: the AS may act as one of the user's devices
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/account/whoami?user_id=%40slack_bob%3Alocalhost&device_id={device_id}"
+        ),
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "whoami failed: {resp}");
+    assert!(resp.contains(&device_id), "{resp}");
+    let (status, _) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/account/whoami?user_id=%40slack_bob%3Alocalhost&device_id=NOPE",
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Exclusive aliases are reserved for the AS
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({ "room_alias_name": "slack_general" }),
+        &user_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(resp.contains("M_EXCLUSIVE"), "{resp}");
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom?user_id=%40slack_bob%3Alocalhost",
+        &serde_json::json!({ "room_alias_name": "slack_general" }),
+        "as_token_slack",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "AS createRoom failed: {resp}");
+}
+
+#[tokio::test]
+async fn test_thirdparty_lookups_are_proxied_to_bridges() {
+    // A bridge answering third-party queries, counting protocol requests
+    let protocol_requests = Arc::new(Mutex::new(0usize));
+    let counter = protocol_requests.clone();
+    // ...and a broken one behind the same listener
+    let failed_requests = Arc::new(Mutex::new(0usize));
+    let failures = failed_requests.clone();
+    let app = axum::Router::new()
+        .route(
+            "/broken/_matrix/app/v1/thirdparty/protocol/irc",
+            axum::routing::get(move || async move {
+                *failures.lock().unwrap() += 1;
+                StatusCode::INTERNAL_SERVER_ERROR
+            }),
+        )
+        .route(
+            "/_matrix/app/v1/thirdparty/protocol/irc",
+            axum::routing::get(move || async move {
+                *counter.lock().unwrap() += 1;
+                Json(serde_json::json!({
+                    "user_fields": ["network", "nickname"],
+                    "location_fields": ["network", "channel"],
+                    "icon": "mxc://example.org/irc",
+                    "field_types": {},
+                    "instances": [{ "desc": "Libera", "network_id": "libera", "fields": {} }],
+                }))
+            }),
+        )
+        .route(
+            "/_matrix/app/v1/thirdparty/location/irc",
+            axum::routing::get(
+                |axum::extract::Query(q): axum::extract::Query<
+                    std::collections::HashMap<String, String>,
+                >| async move {
+                    assert!(!q.contains_key("access_token"));
+                    Json(serde_json::json!([{
+                        "alias": "#irc_libera_matrix:localhost",
+                        "protocol": "irc",
+                        "fields": { "channel": q.get("channel") },
+                    }]))
+                },
+            ),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let router = common::test_router();
+    let broken = format!("{url}/broken");
+    for (id, url) in [
+        ("libera", url.as_str()),
+        ("down", "http://127.0.0.1:1"),
+        ("broken", broken.as_str()),
+    ] {
+        let registration = serde_json::json!({
+            "id": id,
+            "url": url,
+            "as_token": format!("as_{id}"),
+            "hs_token": format!("hs_{id}"),
+            "sender_localpart": format!("{id}bot"),
+            "user_namespaces": [],
+            "alias_namespaces": [],
+            "rate_limited": false,
+            "protocols": ["irc"],
+        });
+        let (status, _) =
+            common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (token, _, _) = common::register_user(&router, "alice", "pass").await;
+
+    for _ in 0..2 {
+        let (status, resp) = common::get_authed(
+            &router,
+            "/_matrix/client/v3/thirdparty/protocol/irc",
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{resp}");
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(json["icon"], "mxc://example.org/irc");
+        let instances: Vec<&str> = json["instances"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i["instance_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(instances, ["libera|libera", "down|irc", "broken|irc"]);
+    }
+    // The second request was answered from the cache, failures included
+    assert_eq!(*protocol_requests.lock().unwrap(), 1);
+    assert_eq!(*failed_requests.lock().unwrap(), 1);
+
+    let (status, resp) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/thirdparty/location/irc?channel=%23matrix",
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 1);
+    assert_eq!(json[0]["fields"]["channel"], "#matrix");
+
+    let (status, _) =
+        common::get_authed(&router, "/_matrix/client/v3/thirdparty/user/xmpp", &token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) =
+        common::get_authed(&router, "/_matrix/client/v3/thirdparty/location", &token).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+fn registration_yaml(id: &str, url: &str) -> String {
+    format!(
+        "id: {id}\nurl: {url}\nas_token: as_{id}\nhs_token: hs_{id}\nsender_localpart: {id}bot\n\
+         namespaces:\n  users:\n    - exclusive: true\n      regex: \"@{id}_.*:localhost\"\n"
+    )
+}
+
+#[tokio::test]
+async fn test_appservice_registration_files_are_reloaded() {
+    use maelstrom_api::appservice::{RegistrationReload, load_registration_files};
+
+    let dir = std::env::temp_dir().join(format!("maelstrom-as-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("irc.yaml"), registration_yaml("irc", "http://irc")).unwrap();
+    std::fs::write(
+        dir.join("slack.yml"),
+        registration_yaml("slack", "http://slack"),
+    )
+    .unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a registration").unwrap();
+
+    let state = common::test_state();
+    let router = router::build(state.clone());
+    // Services registered through the admin API are not managed by the files
+    let (status, _) = common::post_json(
+        &router,
+        "/_maelstrom/admin/v1/appservice",
+        &serde_json::json!({
+            "id": "manual",
+            "url": "http://manual",
+            "as_token": "as_manual",
+            "hs_token": "hs_manual",
+            "sender_localpart": "manualbot",
+            "user_namespaces": [],
+            "alias_namespaces": [],
+            "rate_limited": false,
+            "protocols": [],
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let paths = [dir.clone()];
+    let report = load_registration_files(&state, &paths).await;
+    assert_eq!(
+        report,
+        RegistrationReload {
+            loaded: 2,
+            removed: 0,
+            failed: 0
+        }
+    );
+    let irc = state.storage().get_appservice("irc").await.unwrap();
+    assert_eq!(irc.url, "http://irc");
+    assert!(irc.registration_file.unwrap().ends_with("irc.yaml"));
+
+    // The loaded service's token works for its namespace
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/register",
+        &serde_json::json!({ "type": "m.login.application_service", "username": "irc_bob" }),
+        "as_irc",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Edit one file, remove another and break a third
+    std::fs::write(
+        dir.join("irc.yaml"),
+        registration_yaml("irc", "http://irc2"),
+    )
+    .unwrap();
+    std::fs::remove_file(dir.join("slack.yml")).unwrap();
+    std::fs::write(dir.join("broken.yaml"), "id: [").unwrap();
+    let report = load_registration_files(&state, &paths).await;
+    assert_eq!(
+        report,
+        RegistrationReload {
+            loaded: 1,
+            removed: 1,
+            failed: 1
+        }
+    );
+    assert_eq!(
+        state.storage().get_appservice("irc").await.unwrap().url,
+        "http://irc2"
+    );
+    assert!(state.storage().get_appservice("slack").await.is_err());
+    assert!(state.storage().get_appservice("manual").await.is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_appservice_ping() {
+    async fn ping(Json(body): Json<serde_json::Value>) -> (StatusCode, Json<serde_json::Value>) {
+        if body["transaction_id"] == "fail" {
+            (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({ "errcode": "M_FORBIDDEN" })),
+            )
+        } else {
+            (StatusCode::OK, Json(serde_json::json!({})))
+        }
+    }
+    let app = axum::Router::new().route("/_matrix/app/v1/ping", axum::routing::post(ping));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let router = common::test_router();
+    for (id, url) in [("bridge", url.as_str()), ("down", "http://127.0.0.1:1")] {
+        let (status, _) = common::post_json(
+            &router,
+            "/_maelstrom/admin/v1/appservice",
+            &serde_json::json!({
+                "id": id,
+                "url": url,
+                "as_token": format!("as_{id}"),
+                "hs_token": format!("hs_{id}"),
+                "sender_localpart": format!("{id}bot"),
+                "user_namespaces": [],
+                "alias_namespaces": [],
+                "rate_limited": false,
+                "protocols": [],
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let path = "/_matrix/client/v1/appservice/bridge/ping";
+    let (status, resp) = common::post_json_authed(
+        &router,
+        path,
+        &serde_json::json!({ "transaction_id": "t1" }),
+        "as_bridge",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(json["duration_ms"].is_u64());
+
+    let (status, resp) = common::post_json_authed(
+        &router,
+        path,
+        &serde_json::json!({ "transaction_id": "fail" }),
+        "as_bridge",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["errcode"], "M_BAD_STATUS");
+    assert_eq!(json["status"], 403);
+
+    // An AS may only ping itself
+    let (status, _) =
+        common::post_json_authed(&router, path, &serde_json::json!({}), "as_down").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v1/appservice/down/ping",
+        &serde_json::json!({}),
+        "as_down",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(resp.contains("M_CONNECTION_FAILED"), "{resp}");
+}
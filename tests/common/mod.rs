@@ -129,6 +129,23 @@ pub async fn put_json_authed(
     (status, String::from_utf8(body.to_vec()).unwrap())
 }
 
+/// Send a DELETE request with an Authorization header.
+pub async fn delete_authed(router: &Router, uri: &str, token: &str) -> (http::StatusCode, String) {
+    let req = Request::builder()
+        .uri(uri)
+        .method("DELETE")
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+
+    let response = router.clone().oneshot(req).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
 /// Register a test user and return (access_token, user_id, device_id).
 pub async fn register_user(
     router: &Router,
@@ -188,3 +188,227 @@ async fn test_set_state_event() {
     let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
     assert_eq!(json["name"], "New Name");
 }
+
+#[tokio::test]
+async fn test_get_context() {
+    let router = common::test_router();
+    let (token, user_id, _) = common::register_user(&router, "contexter", "pass").await;
+    let (other_token, _, _) = common::register_user(&router, "outsider", "pass").await;
+    let room_id = create_room(&router, &token).await;
+
+    let mut event_ids = Vec::new();
+    for i in 0..7 {
+        let event_type = if i == 4 {
+            "org.example.ping"
+        } else {
+            "m.room.message"
+        };
+        let (_, resp) = common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{room_id}/send/{event_type}/ctx{i}"),
+            &serde_json::json!({"msgtype": "m.text", "body": format!("m{i}")}),
+            &token,
+        )
+        .await;
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        event_ids.push(json["event_id"].as_str().unwrap().to_string());
+    }
+    let body = |event: &serde_json::Value| event["content"]["body"].as_str().unwrap().to_string();
+    let bodies = |events: &serde_json::Value| -> Vec<String> {
+        events.as_array().unwrap().iter().map(body).collect()
+    };
+
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/rooms/{room_id}/context/{}?limit=4",
+            event_ids[3]
+        ),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "context failed: {resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(body(&json["event"]), "m3");
+    assert_eq!(bodies(&json["events_before"]), vec!["m2", "m1"]);
+    assert_eq!(bodies(&json["events_after"]), vec!["m4", "m5"]);
+    assert!(
+        json["state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.room.create")
+    );
+
+    // start and end continue with /messages
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/rooms/{room_id}/messages?dir=b&limit=1&from={}",
+            json["start"].as_str().unwrap()
+        ),
+        &token,
+    )
+    .await;
+    let messages: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(bodies(&messages["chunk"]), vec!["m0"]);
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/rooms/{room_id}/messages?dir=f&limit=1&from={}",
+            json["end"].as_str().unwrap()
+        ),
+        &token,
+    )
+    .await;
+    let messages: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(bodies(&messages["chunk"]), vec!["m6"]);
+
+    // Filters apply to the surrounding events, and lazy-loading trims the state
+    let filter = serde_json::json!({"types": ["m.room.mess*"], "lazy_load_members": true});
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/rooms/{room_id}/context/{}?limit=4&filter={}",
+            event_ids[3],
+            maelstrom_api::handlers::util::percent_encode(&filter.to_string())
+        ),
+        &token,
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(bodies(&json["events_after"]), vec!["m5", "m6"]);
+    let state = json["state"].as_array().unwrap();
+    assert_eq!(state.len(), 1, "{state:?}");
+    assert_eq!(state[0]["state_key"], user_id);
+
+    // Someone who was never in the room cannot see it
+    let (status, _) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/rooms/{room_id}/context/{}",
+            event_ids[3]
+        ),
+        &other_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_history_visibility_on_read_paths() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "historian", "pass").await;
+    let (late_token, _, _) = common::register_user(&router, "latecomer", "pass").await;
+    let (peek_token, _, _) = common::register_user(&router, "peeker", "pass").await;
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat"}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let set_visibility = |visibility: &'static str| {
+        let router = router.clone();
+        let token = token.clone();
+        let room_id = room_id.clone();
+        async move {
+            let (status, _) = common::put_json_authed(
+                &router,
+                &format!("/_matrix/client/v3/rooms/{room_id}/state/m.room.history_visibility/"),
+                &serde_json::json!({"history_visibility": visibility}),
+                &token,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    };
+    let send = |txn: &'static str| {
+        let router = router.clone();
+        let token = token.clone();
+        let room_id = room_id.clone();
+        async move {
+            let (_, resp) = common::put_json_authed(
+                &router,
+                &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn}"),
+                &serde_json::json!({"msgtype": "m.text", "body": txn}),
+                &token,
+            )
+            .await;
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["event_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+    let bodies = |resp: &str, key: &str| -> Vec<String> {
+        let json: serde_json::Value = serde_json::from_str(resp).unwrap();
+        json[key]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|e| e["content"]["body"].as_str().map(String::from))
+            .collect()
+    };
+
+    set_visibility("joined").await;
+    let early = send("early").await;
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/join/{room_id}"),
+        &serde_json::json!({}),
+        &late_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let late = send("late").await;
+
+    // What was said before joining stays hidden on every read path
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/messages?dir=b&limit=20"),
+        &late_token,
+    )
+    .await;
+    assert_eq!(bodies(&resp, "chunk"), vec!["late"]);
+    let (status, _) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/event/{early}"),
+        &late_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/context/{late}?limit=10"),
+        &late_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "context failed: {resp}");
+    assert!(bodies(&resp, "events_before").is_empty());
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/search",
+        &serde_json::json!({"search_categories": {"room_events": {"search_term": "early"}}}),
+        &late_token,
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["search_categories"]["room_events"]["count"], 0);
+
+    // Non-members can only peek once the room is world_readable, and only
+    // at what was sent since
+    let messages = format!("/_matrix/client/v3/rooms/{room_id}/messages?dir=b&limit=20");
+    let (status, _) = common::get_authed(&router, &messages, &peek_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    set_visibility("world_readable").await;
+    send("public").await;
+    let (status, resp) = common::get_authed(&router, &messages, &peek_token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bodies(&resp, "chunk"), vec!["public"]);
+}
@@ -168,6 +168,7 @@ async fn test_mock_federation_key_store() {
         public_key: "base64pubkey".to_string(),
         private_key: "base64privkey".to_string(),
         valid_until: Utc::now() + chrono::Duration::days(30),
+        expired_at: None,
     };
 
     store.store_server_key(&key).await.unwrap();
@@ -197,6 +198,23 @@ async fn test_mock_federation_key_store() {
     assert_eq!(remote_keys.len(), 1);
     assert_eq!(remote_keys[0].key_id, "ed25519:remote1");
 
+    // Re-caching the same key replaces it rather than duplicating it
+    store
+        .store_remote_server_keys(&[RemoteKeyRecord {
+            server_name: "remote.example.com".to_string(),
+            key_id: "ed25519:remote1".to_string(),
+            public_key: "rotatedpubkey".to_string(),
+            valid_until: Utc::now() + chrono::Duration::days(7),
+        }])
+        .await
+        .unwrap();
+    let remote_keys = store
+        .get_remote_server_keys("remote.example.com")
+        .await
+        .unwrap();
+    assert_eq!(remote_keys.len(), 1);
+    assert_eq!(remote_keys[0].public_key, "rotatedpubkey");
+
     // Federation transaction dedup
     store
         .store_federation_txn("remote.example.com", "txn1")
@@ -252,3 +270,1381 @@ fn test_pdu_federation_fields() {
     assert!(client_event.get("auth_events").is_none());
     assert!(client_event.get("signatures").is_none());
 }
+
+// -- Space hierarchy endpoint tests --
+
+/// Store a state event in the mock and make it part of the room's current state.
+async fn put_state(
+    store: &maelstrom_storage::mock::MockStorage,
+    room_id: &str,
+    event_type: &str,
+    state_key: &str,
+    content: serde_json::Value,
+) {
+    use maelstrom_storage::traits::EventStore;
+
+    let event_id = format!("${event_type}-{state_key}-{room_id}");
+    let event = maelstrom_core::matrix::event::Pdu {
+        event_id: event_id.clone(),
+        room_id: room_id.to_string(),
+        sender: "@alice:localhost".to_string(),
+        event_type: event_type.to_string(),
+        state_key: Some(state_key.to_string()),
+        content,
+        origin_server_ts: 1234567890,
+        unsigned: None,
+        stream_position: 0,
+        origin: None,
+        auth_events: None,
+        prev_events: None,
+        depth: None,
+        hashes: None,
+        signatures: None,
+    };
+    store.store_event(&event).await.unwrap();
+    store
+        .set_room_state(room_id, event_type, state_key, &event_id)
+        .await
+        .unwrap();
+}
+
+async fn create_test_room(
+    store: &maelstrom_storage::mock::MockStorage,
+    room_id: &str,
+    join_rule: &str,
+    content: serde_json::Value,
+) {
+    use maelstrom_storage::traits::{RoomRecord, RoomStore};
+
+    store
+        .create_room(&RoomRecord {
+            room_id: room_id.to_string(),
+            version: "10".to_string(),
+            creator: "@alice:localhost".to_string(),
+            is_direct: false,
+            peek_only: false,
+        })
+        .await
+        .unwrap();
+    put_state(store, room_id, "m.room.create", "", content).await;
+    put_state(
+        store,
+        room_id,
+        "m.room.join_rules",
+        "",
+        serde_json::json!({"join_rule": join_rule}),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_federation_hierarchy_endpoint() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let storage = MockStorage::new();
+    create_test_room(
+        &storage,
+        "!space:localhost",
+        "public",
+        serde_json::json!({"type": "m.space"}),
+    )
+    .await;
+    create_test_room(
+        &storage,
+        "!public:localhost",
+        "public",
+        serde_json::json!({}),
+    )
+    .await;
+    create_test_room(
+        &storage,
+        "!secret:localhost",
+        "invite",
+        serde_json::json!({}),
+    )
+    .await;
+    put_state(
+        &storage,
+        "!space:localhost",
+        "m.space.child",
+        "!public:localhost",
+        serde_json::json!({"via": ["localhost"], "suggested": true}),
+    )
+    .await;
+    put_state(
+        &storage,
+        "!space:localhost",
+        "m.space.child",
+        "!secret:localhost",
+        serde_json::json!({"via": ["localhost"]}),
+    )
+    .await;
+    put_state(
+        &storage,
+        "!space:localhost",
+        "m.space.child",
+        "!elsewhere:remote.example.com",
+        serde_json::json!({"via": ["remote.example.com"]}),
+    )
+    .await;
+
+    let router = maelstrom_federation::router::build(maelstrom_federation::FederationState::new(
+        storage,
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    ));
+
+    let fetch = |uri: &str| {
+        http::Request::builder()
+            .uri(uri)
+            .method("GET")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = router
+        .clone()
+        .oneshot(fetch("/_matrix/federation/v1/hierarchy/!space:localhost"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(json["room"]["room_id"], "!space:localhost");
+    assert_eq!(json["room"]["room_type"], "m.space");
+    assert_eq!(json["room"]["children_state"].as_array().unwrap().len(), 3);
+    let children = json["children"].as_array().unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0]["room_id"], "!public:localhost");
+    assert!(children[0].get("children_state").is_none());
+    assert_eq!(json["inaccessible_children"][0], "!secret:localhost");
+
+    // suggested_only trims the advertised children
+    let response = router
+        .clone()
+        .oneshot(fetch(
+            "/_matrix/federation/v1/hierarchy/!space:localhost?suggested_only=true",
+        ))
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["room"]["children_state"].as_array().unwrap().len(), 1);
+    assert!(json["inaccessible_children"].as_array().unwrap().is_empty());
+
+    // Invite-only rooms are not exposed over federation
+    let response = router
+        .clone()
+        .oneshot(fetch("/_matrix/federation/v1/hierarchy/!secret:localhost"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+// -- Notary key server tests --
+
+#[tokio::test]
+async fn test_notary_serves_cached_keys_co_signed() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::{FederationKeyStore, ServerKeyResponseRecord};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let origin_key = KeyPair::generate();
+    let valid_until = chrono::Utc::now() + chrono::Duration::days(1);
+    let origin_response = signing::sign_json(
+        &serde_json::json!({
+            "server_name": "origin.example.com",
+            "verify_keys": {origin_key.key_id(): {"key": origin_key.public_key_base64()}},
+            "old_verify_keys": {},
+            "valid_until_ts": valid_until.timestamp_millis(),
+        }),
+        &origin_key,
+        "origin.example.com",
+    );
+
+    let storage = MockStorage::new();
+    storage
+        .store_server_key_response(&ServerKeyResponseRecord {
+            server_name: "origin.example.com".to_string(),
+            response: origin_response,
+            valid_until,
+            fetched_at: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+
+    let notary_key = KeyPair::generate();
+    let router = maelstrom_federation::router::build(maelstrom_federation::FederationState::new(
+        storage,
+        Arc::new(EphemeralStore::new()),
+        notary_key.clone(),
+        ServerName::new("localhost"),
+    ));
+
+    let req = http::Request::builder()
+        .uri("/_matrix/key/v2/query/origin.example.com")
+        .method("GET")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.clone().oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let keys = &json["server_keys"][0];
+    assert_eq!(keys["server_name"], "origin.example.com");
+    assert!(signing::verify_event_signature(
+        keys,
+        &origin_key.public_key_bytes(),
+        "origin.example.com",
+        origin_key.key_id(),
+    ));
+    assert!(signing::verify_event_signature(
+        keys,
+        &notary_key.public_key_bytes(),
+        "localhost",
+        notary_key.key_id(),
+    ));
+
+    // Batch query: our own keys plus the cached remote keys
+    let body = serde_json::json!({
+        "server_keys": {
+            "localhost": {},
+            "origin.example.com": {origin_key.key_id(): {"minimum_valid_until_ts": 0}},
+        }
+    });
+    let req = http::Request::builder()
+        .uri("/_matrix/key/v2/query")
+        .method("POST")
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = router.oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let server_keys = json["server_keys"].as_array().unwrap();
+    assert_eq!(server_keys.len(), 2);
+    assert!(
+        server_keys
+            .iter()
+            .all(|k| k["signatures"]["localhost"][notary_key.key_id()].is_string())
+    );
+}
+
+#[test]
+fn test_trusted_key_servers_require_verify_keys() {
+    use maelstrom_federation::key_server::TrustedKeyServer;
+
+    // verify_keys cannot be left out
+    assert!(
+        serde_json::from_value::<TrustedKeyServer>(serde_json::json!({
+            "server_name": "notary.example"
+        }))
+        .is_err()
+    );
+
+    let empty: TrustedKeyServer = serde_json::from_value(serde_json::json!({
+        "server_name": "notary.example",
+        "verify_keys": {}
+    }))
+    .unwrap();
+    assert!(empty.validate().is_err());
+
+    let bad: TrustedKeyServer = serde_json::from_value(serde_json::json!({
+        "server_name": "notary.example",
+        "verify_keys": {"ed25519:a": "not base64!"}
+    }))
+    .unwrap();
+    assert!(bad.validate().is_err());
+
+    let kp = KeyPair::generate();
+    let good: TrustedKeyServer = serde_json::from_value(serde_json::json!({
+        "server_name": "notary.example",
+        "verify_keys": {kp.key_id(): kp.public_key_base64()}
+    }))
+    .unwrap();
+    assert!(good.validate().is_ok());
+}
+
+#[tokio::test]
+async fn test_key_server_publishes_rotated_keys() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let storage = MockStorage::new();
+    let old_key = maelstrom_admin::signing_keys::rotate_signing_key(&storage)
+        .await
+        .unwrap();
+    let new_key = maelstrom_admin::signing_keys::rotate_signing_key(&storage)
+        .await
+        .unwrap();
+
+    let state = maelstrom_federation::FederationState::new(
+        storage,
+        Arc::new(EphemeralStore::new()),
+        new_key.clone(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state.clone());
+
+    let fetch_keys = |router: axum::Router| async move {
+        let req = http::Request::builder()
+            .uri("/_matrix/key/v2/server")
+            .method("GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    // Through the grace period the old key is still valid, and the response
+    // only lasts until its end
+    let json = fetch_keys(router.clone()).await;
+    assert!(json["verify_keys"][new_key.key_id()]["key"].is_string());
+    assert_eq!(
+        json["verify_keys"][old_key.key_id()]["key"],
+        old_key.public_key_base64()
+    );
+    let grace_end = chrono::Utc::now()
+        + chrono::Duration::seconds(maelstrom_admin::signing_keys::ROTATION_GRACE_SECS as i64);
+    assert!(json["valid_until_ts"].as_i64().unwrap() <= grace_end.timestamp_millis());
+
+    // Then it is only published as an old key
+    state
+        .storage()
+        .expire_server_key(old_key.key_id(), chrono::Utc::now())
+        .await
+        .unwrap();
+    let json = fetch_keys(router.clone()).await;
+    assert!(json["verify_keys"].get(old_key.key_id()).is_none());
+    assert_eq!(
+        json["old_verify_keys"][old_key.key_id()]["key"],
+        old_key.public_key_base64()
+    );
+    assert!(json["old_verify_keys"][old_key.key_id()]["expired_ts"].is_number());
+    assert!(signing::verify_event_signature(
+        &json,
+        &new_key.public_key_bytes(),
+        "localhost",
+        new_key.key_id(),
+    ));
+
+    // Switching the shared key re-signs responses with the new key, no restart needed
+    let swapped = KeyPair::generate();
+    assert!(state.shared_signing_key().replace(swapped.clone()));
+    let json = fetch_keys(router).await;
+    assert!(json["verify_keys"][swapped.key_id()]["key"].is_string());
+    assert!(signing::verify_event_signature(
+        &json,
+        &swapped.public_key_bytes(),
+        "localhost",
+        swapped.key_id(),
+    ));
+}
+
+#[tokio::test]
+async fn test_federation_policy_blocks_servers() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_federation::client::FederationError;
+    use maelstrom_federation::policy::{DomainLists, FederationPolicy};
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let policy = Arc::new(FederationPolicy::new(DomainLists {
+        allow: vec![],
+        deny: vec!["*.evil.example".to_string()],
+    }));
+    let state = maelstrom_federation::FederationState::new(
+        MockStorage::new(),
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    )
+    .with_federation_policy(policy.clone());
+    let router = maelstrom_federation::router::build(state.clone());
+
+    let send = |origin: &str, signed: bool| {
+        let txn = serde_json::json!({ "origin": origin, "pdus": [], "edus": [] });
+        let mut req = http::Request::builder()
+            .uri("/_matrix/federation/v1/send/txn1")
+            .method("PUT")
+            .header("Content-Type", "application/json");
+        if signed {
+            let auth = maelstrom_federation::signing::sign_request(
+                &KeyPair::generate(),
+                origin,
+                "localhost",
+                "PUT",
+                "/_matrix/federation/v1/send/txn1",
+                Some(&txn),
+            );
+            req = req.header("Authorization", auth);
+        }
+        req.body(Body::from(txn.to_string())).unwrap()
+    };
+
+    // Blocked by the X-Matrix origin, and by the transaction origin when unsigned
+    let response = router
+        .clone()
+        .oneshot(send("spam.evil.example", true))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = router
+        .clone()
+        .oneshot(send("spam.evil.example:8448", false))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Notary lookups for blocked servers return nothing
+    let req = http::Request::builder()
+        .uri("/_matrix/key/v2/query/spam.evil.example")
+        .method("GET")
+        .body(Body::empty())
+        .unwrap();
+    let response = router.oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["server_keys"], serde_json::json!([]));
+
+    // Outbound requests fail before anything is sent
+    let err = state
+        .client()
+        .get("spam.evil.example", "/_matrix/federation/v1/version")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, FederationError::Blocked(_)));
+
+    let rejections = policy.rejections();
+    assert_eq!(rejections.inbound, 2);
+    assert_eq!(rejections.key_fetch, 1);
+    assert_eq!(rejections.outbound, 1);
+    assert!(state.policy().is_allowed("good.example"));
+
+    // Lifting the block takes effect immediately
+    policy.set_lists(DomainLists::default());
+    assert!(state.policy().is_allowed("spam.evil.example"));
+}
+
+#[tokio::test]
+async fn test_inbound_typing_and_receipt_edus() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let state = maelstrom_federation::FederationState::new(
+        MockStorage::new(),
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state.clone());
+
+    let room_id = "!room:remote.example";
+    let txn = serde_json::json!({
+        "origin": "remote.example",
+        "pdus": [],
+        "edus": [
+            { "edu_type": "m.typing", "content": {
+                "room_id": room_id, "user_id": "@alice:remote.example", "typing": true,
+            }},
+            { "edu_type": "m.typing", "content": {
+                "room_id": room_id, "user_id": "@mallory:other.example", "typing": true,
+            }},
+            { "edu_type": "m.presence", "content": { "push": [
+                { "user_id": "@alice:remote.example", "presence": "online", "last_active_ago": 0 },
+                { "user_id": "@local:localhost", "presence": "online", "last_active_ago": 0 },
+                { "user_id": "@mallory:other.example", "presence": "online", "last_active_ago": 0 },
+            ]}},
+            { "edu_type": "m.receipt", "content": { room_id: {
+                "m.read": {
+                    "@alice:remote.example": {
+                        "event_ids": ["$thread_event"],
+                        "data": { "ts": 1, "thread_id": "$root" },
+                    },
+                    "@bob:remote.example": {
+                        "event_ids": ["$main_event"],
+                        "data": { "ts": 2 },
+                    },
+                    "@mallory:other.example": {
+                        "event_ids": ["$forged"],
+                        "data": { "ts": 3 },
+                    },
+                },
+                "m.read.private": {
+                    "@alice:remote.example": {
+                        "event_ids": ["$private"],
+                        "data": { "ts": 4 },
+                    },
+                },
+            }}},
+        ],
+    });
+    let req = http::Request::builder()
+        .uri("/_matrix/federation/v1/send/txn1")
+        .method("PUT")
+        .header("Content-Type", "application/json")
+        .body(Body::from(txn.to_string()))
+        .unwrap();
+    let response = router.oneshot(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Only the origin's own users are accepted
+    assert_eq!(
+        state.ephemeral().get_typing_users(room_id),
+        vec!["@alice:remote.example".to_string()]
+    );
+    assert_eq!(
+        state
+            .ephemeral()
+            .get_presence("@alice:remote.example")
+            .map(|p| p.status),
+        Some("online".to_string())
+    );
+    assert!(state.ephemeral().get_presence("@local:localhost").is_none());
+    assert!(
+        state
+            .ephemeral()
+            .get_presence("@mallory:other.example")
+            .is_none()
+    );
+
+    let mut receipts: Vec<_> = state
+        .storage()
+        .get_receipts(room_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.user_id, r.receipt_type, r.event_id, r.thread_id))
+        .collect();
+    receipts.sort();
+    assert_eq!(
+        receipts,
+        vec![
+            (
+                "@alice:remote.example".to_string(),
+                "m.read".to_string(),
+                "$thread_event".to_string(),
+                "$root".to_string()
+            ),
+            (
+                "@bob:remote.example".to_string(),
+                "m.read".to_string(),
+                "$main_event".to_string(),
+                String::new()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_queued_typing_edus_are_coalesced() {
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_federation::client::FederationClient;
+    use maelstrom_federation::sender::TransactionSender;
+
+    let sender = TransactionSender::new(
+        FederationClient::new(KeyPair::generate(), ServerName::new("localhost")),
+        "localhost".to_string(),
+    );
+    let typing = |user_id: &str, typing: bool| {
+        serde_json::json!({
+            "edu_type": "m.typing",
+            "content": { "room_id": "!room:localhost", "user_id": user_id, "typing": typing },
+        })
+    };
+
+    sender.queue_edu("remote.example", typing("@alice:localhost", true));
+    sender.queue_edu("remote.example", typing("@alice:localhost", true));
+    sender.queue_edu("remote.example", typing("@bob:localhost", true));
+    sender.queue_edu("remote.example", typing("@alice:localhost", false));
+    sender.queue_edu(
+        "remote.example",
+        serde_json::json!({ "edu_type": "m.receipt", "content": {} }),
+    );
+
+    let destination = sender.destination("remote.example").unwrap();
+    assert_eq!(destination.pending_edus, 3);
+}
+
+#[tokio::test]
+async fn test_inbound_device_list_updates_follow_the_stream() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let state = maelstrom_federation::FederationState::new(
+        MockStorage::new(),
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state.clone());
+    let storage = state.storage();
+    let alice = "@alice:remote.example";
+    storage
+        .set_membership(alice, "!room:localhost", "join")
+        .await
+        .unwrap();
+    storage
+        .set_device_keys(alice, "OLD", &serde_json::json!({"device_id": "OLD"}))
+        .await
+        .unwrap();
+    storage
+        .set_remote_device_stream_id(alice, Some(5))
+        .await
+        .unwrap();
+
+    let send = |txn_id: &str, content: serde_json::Value| {
+        let txn = serde_json::json!({
+            "origin": "remote.example",
+            "pdus": [],
+            "edus": [{ "edu_type": "m.device_list_update", "content": content }],
+        });
+        http::Request::builder()
+            .uri(format!("/_matrix/federation/v1/send/{txn_id}"))
+            .method("PUT")
+            .header("Content-Type", "application/json")
+            .body(Body::from(txn.to_string()))
+            .unwrap()
+    };
+    let device_ids = |keys: serde_json::Value| -> Vec<String> {
+        let mut ids: Vec<String> = keys[alice]
+            .as_object()
+            .map(|d| d.keys().cloned().collect())
+            .unwrap_or_default();
+        ids.sort();
+        ids
+    };
+
+    // Follows on from the cached stream ID: applied
+    let response = router
+        .clone()
+        .oneshot(send(
+            "t1",
+            serde_json::json!({
+                "user_id": alice, "device_id": "NEW", "stream_id": 6, "prev_id": [5],
+                "keys": { "user_id": alice, "device_id": "NEW" },
+            }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let keys = storage.get_device_keys(&[alice.to_string()]).await.unwrap();
+    assert_eq!(device_ids(keys), vec!["NEW", "OLD"]);
+    assert_eq!(
+        storage.get_remote_device_stream_id(alice).await.unwrap(),
+        Some(6)
+    );
+
+    // Deletion
+    router
+        .clone()
+        .oneshot(send(
+            "t2",
+            serde_json::json!({
+                "user_id": alice, "device_id": "OLD", "stream_id": 7, "prev_id": [6],
+                "deleted": true,
+            }),
+        ))
+        .await
+        .unwrap();
+    let keys = storage.get_device_keys(&[alice.to_string()]).await.unwrap();
+    assert_eq!(device_ids(keys), vec!["NEW"]);
+    assert!(
+        !storage
+            .get_device_list_changed_users(0, i64::MAX)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // A duplicate is ignored
+    router
+        .clone()
+        .oneshot(send(
+            "t3",
+            serde_json::json!({
+                "user_id": alice, "device_id": "DUP", "stream_id": 7, "prev_id": [6],
+                "keys": { "user_id": alice, "device_id": "DUP" },
+            }),
+        ))
+        .await
+        .unwrap();
+    let keys = storage.get_device_keys(&[alice.to_string()]).await.unwrap();
+    assert_eq!(device_ids(keys), vec!["NEW"]);
+
+    // A gap invalidates the cache
+    router
+        .clone()
+        .oneshot(send(
+            "t4",
+            serde_json::json!({
+                "user_id": alice, "device_id": "GAP", "stream_id": 10, "prev_id": [9],
+                "keys": { "user_id": alice, "device_id": "GAP" },
+            }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.get_remote_device_stream_id(alice).await.unwrap(),
+        None
+    );
+
+    // Updates for another server's users are rejected
+    router
+        .oneshot(send(
+            "t5",
+            serde_json::json!({
+                "user_id": "@mallory:other.example", "device_id": "X", "stream_id": 1,
+                "prev_id": [], "keys": {},
+            }),
+        ))
+        .await
+        .unwrap();
+    let keys = storage
+        .get_device_keys(&["@mallory:other.example".to_string()])
+        .await
+        .unwrap();
+    assert!(
+        keys.get("@mallory:other.example")
+            .and_then(|d| d.as_object())
+            .is_none_or(|d| d.is_empty())
+    );
+}
+
+#[tokio::test]
+async fn test_inbound_to_device_edus() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::DeviceRecord;
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    let woken: Arc<Mutex<Vec<String>>> = Arc::default();
+    let woken_by_notify = woken.clone();
+    let state = maelstrom_federation::FederationState::new(
+        MockStorage::new(),
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    )
+    .with_user_notify(Arc::new(move |user_id: &str| {
+        woken_by_notify.lock().unwrap().push(user_id.to_string());
+    }));
+    let router = maelstrom_federation::router::build(state.clone());
+    let storage = state.storage();
+    for device_id in ["PHONE", "LAPTOP"] {
+        storage
+            .create_device(&DeviceRecord {
+                device_id: device_id.to_string(),
+                user_id: "@bob:localhost".to_string(),
+                display_name: None,
+                access_token: format!("token_{device_id}"),
+                created_at: chrono::Utc::now(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let send = |txn_id: &str, sender: &str| {
+        let txn = serde_json::json!({
+            "origin": "remote.example",
+            "pdus": [],
+            "edus": [{ "edu_type": "m.direct_to_device", "content": {
+                "sender": sender,
+                "type": "m.room_key_request",
+                "message_id": "msg1",
+                "messages": {
+                    "@bob:localhost": { "*": { "action": "request" } },
+                    "@carol:localhost": { "TABLET": { "action": "request" } },
+                },
+            }}],
+        });
+        http::Request::builder()
+            .uri(format!("/_matrix/federation/v1/send/{txn_id}"))
+            .method("PUT")
+            .header("Content-Type", "application/json")
+            .body(Body::from(txn.to_string()))
+            .unwrap()
+    };
+
+    // Sent again in a second transaction: only queued once
+    for txn_id in ["t1", "t2"] {
+        let response = router
+            .clone()
+            .oneshot(send(txn_id, "@alice:remote.example"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    // Claiming to come from another server's user: dropped
+    router
+        .oneshot(send("t3", "@mallory:other.example"))
+        .await
+        .unwrap();
+
+    for (user_id, device_id) in [
+        ("@bob:localhost", "PHONE"),
+        ("@bob:localhost", "LAPTOP"),
+        ("@carol:localhost", "TABLET"),
+    ] {
+        let messages = storage
+            .get_to_device_messages(user_id, device_id, 0, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1, "{user_id} {device_id}: {messages:?}");
+        assert_eq!(messages[0]["sender"], "@alice:remote.example");
+        assert_eq!(messages[0]["type"], "m.room_key_request");
+    }
+    let mut woken = woken.lock().unwrap().clone();
+    woken.sort();
+    assert_eq!(woken, vec!["@bob:localhost", "@carol:localhost"]);
+}
+
+#[tokio::test]
+async fn test_federation_claims_fallback_keys() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let store = MockStorage::new();
+    let remote_key = KeyPair::generate();
+    trust_server_key(&store, "remote.example", &remote_key).await;
+    let state = maelstrom_federation::FederationState::new(
+        store,
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state.clone());
+    state
+        .storage()
+        .store_fallback_keys(
+            "@bob:localhost",
+            "PHONE",
+            &serde_json::json!({"signed_curve25519:AAAA": {"key": "fallback", "fallback": true}}),
+        )
+        .await
+        .unwrap();
+
+    let claim = serde_json::json!({"one_time_keys": {
+        "@bob:localhost": {"PHONE": "signed_curve25519"},
+        "@eve:other.example": {"DEV": "signed_curve25519"},
+    }});
+    let request = |auth: Option<String>| {
+        let mut builder = http::Request::builder()
+            .uri("/_matrix/federation/v1/user/keys/claim")
+            .method("POST")
+            .header("Content-Type", "application/json");
+        if let Some(auth) = auth {
+            builder = builder.header("Authorization", auth);
+        }
+        builder.body(Body::from(claim.to_string())).unwrap()
+    };
+
+    // Unsigned claims would use keys up for anyone
+    let response = router.clone().oneshot(request(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let auth = maelstrom_federation::signing::sign_request(
+        &remote_key,
+        "remote.example",
+        "localhost",
+        "POST",
+        "/_matrix/federation/v1/user/keys/claim",
+        Some(&claim),
+    );
+    let response = router.oneshot(request(Some(auth))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        json["one_time_keys"],
+        serde_json::json!({"@bob:localhost": {"PHONE": {
+            "signed_curve25519:AAAA": {"key": "fallback", "fallback": true}
+        }}})
+    );
+    assert!(
+        state
+            .storage()
+            .get_unused_fallback_key_types("@bob:localhost", "PHONE")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_inbound_signing_key_updates() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let state = maelstrom_federation::FederationState::new(
+        MockStorage::new(),
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state.clone());
+    let storage = state.storage();
+    let alice = "@alice:remote.example";
+    storage
+        .set_membership(alice, "!room:localhost", "join")
+        .await
+        .unwrap();
+
+    let send = |txn_id: &str, content: serde_json::Value| {
+        let txn = serde_json::json!({
+            "origin": "remote.example",
+            "pdus": [],
+            "edus": [{ "edu_type": "m.signing_key_update", "content": content }],
+        });
+        http::Request::builder()
+            .uri(format!("/_matrix/federation/v1/send/{txn_id}"))
+            .method("PUT")
+            .header("Content-Type", "application/json")
+            .body(Body::from(txn.to_string()))
+            .unwrap()
+    };
+    let key = |user_id: &str, usage: &str| serde_json::json!({"user_id": user_id, "usage": [usage], "keys": {"ed25519:k": usage}});
+
+    let response = router
+        .clone()
+        .oneshot(send(
+            "t1",
+            serde_json::json!({
+                "user_id": alice,
+                "master_key": key(alice, "master"),
+                "self_signing_key": key(alice, "self_signing"),
+            }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let keys = storage.get_cross_signing_keys(alice).await.unwrap();
+    assert_eq!(keys["master_key"], key(alice, "master"));
+    assert_eq!(keys["self_signing_key"], key(alice, "self_signing"));
+    assert_eq!(
+        storage
+            .get_device_list_changed_users(0, i64::MAX)
+            .await
+            .unwrap(),
+        vec![alice.to_string()]
+    );
+
+    // Keys for another server's users are rejected
+    let mallory = "@mallory:other.example";
+    storage
+        .set_membership(mallory, "!room:localhost", "join")
+        .await
+        .unwrap();
+    router
+        .oneshot(send(
+            "t2",
+            serde_json::json!({"user_id": mallory, "master_key": key(mallory, "master")}),
+        ))
+        .await
+        .unwrap();
+    let keys = storage.get_cross_signing_keys(mallory).await.unwrap();
+    assert!(keys.get("master_key").is_none());
+}
+
+#[tokio::test]
+async fn test_backfill_respects_history_visibility() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::EventStore;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let store = MockStorage::new();
+    let room_id = "!history:localhost";
+    create_test_room(&store, room_id, "public", serde_json::json!({})).await;
+    put_state(
+        &store,
+        room_id,
+        "m.room.history_visibility",
+        "",
+        serde_json::json!({"history_visibility": "joined"}),
+    )
+    .await;
+    let message = |event_id: &str, body: &str| maelstrom_core::matrix::event::Pdu {
+        event_id: event_id.to_string(),
+        room_id: room_id.to_string(),
+        sender: "@alice:localhost".to_string(),
+        event_type: "m.room.message".to_string(),
+        state_key: None,
+        content: serde_json::json!({"msgtype": "m.text", "body": body}),
+        origin_server_ts: 1234567890,
+        unsigned: None,
+        stream_position: 0,
+        origin: None,
+        auth_events: None,
+        prev_events: None,
+        depth: None,
+        hashes: None,
+        signatures: None,
+    };
+    store
+        .store_event(&message("$secret", "before bob"))
+        .await
+        .unwrap();
+    put_state(
+        &store,
+        room_id,
+        "m.room.member",
+        "@bob:remote.example",
+        serde_json::json!({"membership": "join"}),
+    )
+    .await;
+    store
+        .store_event(&message("$hello", "after bob"))
+        .await
+        .unwrap();
+
+    let remote_key = KeyPair::generate();
+    let other_key = KeyPair::generate();
+    trust_server_key(&store, "remote.example", &remote_key).await;
+    trust_server_key(&store, "other.example", &other_key).await;
+
+    let state = maelstrom_federation::FederationState::new(
+        store,
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state);
+
+    let backfill = |origin: &str, key: &KeyPair| {
+        let uri = format!(
+            "/_matrix/federation/v1/backfill/{}?limit=10",
+            maelstrom_api::handlers::util::percent_encode(room_id)
+        );
+        let auth = maelstrom_federation::signing::sign_request(
+            key,
+            origin,
+            "localhost",
+            "GET",
+            &uri,
+            None,
+        );
+        http::Request::builder()
+            .uri(uri)
+            .method("GET")
+            .header("Authorization", auth)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let bodies = |json: &serde_json::Value| -> Vec<(String, Option<String>)> {
+        json["pdus"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|pdu| pdu["type"] == "m.room.message")
+            .map(|pdu| {
+                (
+                    pdu["event_id"].as_str().unwrap().to_string(),
+                    pdu["content"]["body"].as_str().map(String::from),
+                )
+            })
+            .collect()
+    };
+
+    // Bob's server gets what was sent since he joined; earlier content is
+    // stripped, but the event itself is still there for the DAG
+    let response = router
+        .clone()
+        .oneshot(backfill("remote.example", &remote_key))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        bodies(&json),
+        vec![
+            ("$hello".to_string(), Some("after bob".to_string())),
+            ("$secret".to_string(), None),
+        ]
+    );
+
+    // A server without members in the room sees no content at all, and
+    // neither does one claiming to be Bob's server without its key
+    for (origin, key) in [
+        ("other.example", &other_key),
+        ("remote.example", &other_key),
+    ] {
+        let response = router.clone().oneshot(backfill(origin, key)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(
+            bodies(&json).iter().all(|(_, body)| body.is_none()),
+            "{origin}"
+        );
+    }
+}
+
+/// Cache `key` as a verify key of `server_name`, so requests it signs verify.
+async fn trust_server_key(
+    store: &maelstrom_storage::mock::MockStorage,
+    server_name: &str,
+    key: &KeyPair,
+) {
+    use maelstrom_storage::traits::{FederationKeyStore, RemoteKeyRecord};
+
+    store
+        .store_remote_server_keys(&[RemoteKeyRecord {
+            server_name: server_name.to_string(),
+            key_id: key.key_id().to_string(),
+            public_key: key.public_key_base64(),
+            valid_until: chrono::Utc::now() + chrono::Duration::days(1),
+        }])
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_federation_peek_world_readable_room() {
+    use axum::body::Body;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    let store = MockStorage::new();
+    let room_id = "!preview:localhost";
+    create_test_room(&store, room_id, "public", serde_json::json!({})).await;
+    put_state(
+        &store,
+        room_id,
+        "m.room.history_visibility",
+        "",
+        serde_json::json!({"history_visibility": "world_readable"}),
+    )
+    .await;
+    create_test_room(&store, "!shared:localhost", "public", serde_json::json!({})).await;
+    let state = maelstrom_federation::FederationState::new(
+        store,
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+    let router = maelstrom_federation::router::build(state);
+    let peek = |room_id: &str| {
+        http::Request::builder()
+            .uri(format!(
+                "/_matrix/federation/unstable/org.matrix.msc2444/peek/{}/peek1",
+                maelstrom_api::handlers::util::percent_encode(room_id)
+            ))
+            .method("PUT")
+            .header("Content-Type", "application/json")
+            .body(Body::from("{}"))
+            .unwrap()
+    };
+
+    // History that is merely shared cannot be peeked
+    let response = router
+        .clone()
+        .oneshot(peek("!shared:localhost"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = router
+        .clone()
+        .oneshot(peek("!unknown:localhost"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = router.oneshot(peek(room_id)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["room_version"], "10");
+    assert!(
+        json["state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.room.create")
+    );
+    assert_eq!(json["latest_event"]["type"], "m.room.history_visibility");
+    assert!(json["renewal_interval"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_joining_a_peeked_room_goes_over_federation() {
+    use maelstrom_api::notify::LocalNotifier;
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::{RoomRecord, RoomStore};
+    use std::sync::Arc;
+
+    // What a federated peek leaves behind: the remote room's record and state
+    let store = MockStorage::new();
+    let room_id = "!peeked:remote.example";
+    store
+        .create_room(&RoomRecord {
+            room_id: room_id.to_string(),
+            version: "10".to_string(),
+            creator: String::new(),
+            is_direct: false,
+            peek_only: true,
+        })
+        .await
+        .unwrap();
+    put_state(&store, room_id, "m.room.create", "", serde_json::json!({})).await;
+    put_state(
+        &store,
+        room_id,
+        "m.room.join_rules",
+        "",
+        serde_json::json!({"join_rule": "public"}),
+    )
+    .await;
+    let state = maelstrom_api::state::AppState::new(
+        store,
+        LocalNotifier::new(),
+        Arc::new(EphemeralStore::new()),
+        ServerName::new("localhost"),
+        "http://localhost:8008".to_string(),
+    );
+    let router = maelstrom_api::router::build(state.clone());
+    let (token, user_id, _) = common::register_user(&router, "visitor", "pass").await;
+
+    // The join must go to the room's servers, which this test server has no
+    // federation to reach, rather than being made up locally
+    let (status, resp) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &token,
+    )
+    .await;
+    assert_ne!(status, StatusCode::OK, "peeked room joined locally: {resp}");
+    assert!(resp.contains("Federation not configured"), "{resp}");
+    assert!(
+        state
+            .storage()
+            .get_membership(&user_id, room_id)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_old_keys_only_verify_what_they_signed_in_time() {
+    use maelstrom_core::matrix::ephemeral::EphemeralStore;
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_federation::key_server::resolve_verify_key;
+    use maelstrom_storage::mock::MockStorage;
+    use maelstrom_storage::traits::{FederationKeyStore, ServerKeyResponseRecord};
+    use std::sync::Arc;
+
+    let current = KeyPair::generate();
+    let old = KeyPair::generate();
+    let expired_ts = 1_000_000;
+    let store = MockStorage::new();
+    store
+        .store_server_key_response(&ServerKeyResponseRecord {
+            server_name: "remote.example".to_string(),
+            response: serde_json::json!({
+                "server_name": "remote.example",
+                "verify_keys": {current.key_id(): {"key": current.public_key_base64()}},
+                "old_verify_keys": {
+                    "ed25519:old": {"key": old.public_key_base64(), "expired_ts": expired_ts}
+                },
+            }),
+            valid_until: chrono::Utc::now() + chrono::Duration::days(1),
+            fetched_at: chrono::Utc::now(),
+        })
+        .await
+        .unwrap();
+    let state = maelstrom_federation::FederationState::new(
+        store,
+        Arc::new(EphemeralStore::new()),
+        KeyPair::generate(),
+        ServerName::new("localhost"),
+    );
+
+    let resolve = |key_id: &str, signed_at: Option<i64>| {
+        let state = state.clone();
+        let key_id = key_id.to_string();
+        async move { resolve_verify_key(&state, "remote.example", &key_id, signed_at).await }
+    };
+    assert_eq!(
+        resolve(current.key_id(), None).await,
+        Some(current.public_key_bytes())
+    );
+    // Requests are only authenticated by current keys
+    assert_eq!(resolve("ed25519:old", None).await, None);
+    // Events are verified by old keys they were signed before the expiry of
+    assert_eq!(
+        resolve("ed25519:old", Some(expired_ts - 1)).await,
+        Some(old.public_key_bytes())
+    );
+    assert_eq!(resolve("ed25519:old", Some(expired_ts)).await, None);
+}
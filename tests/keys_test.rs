@@ -150,3 +150,842 @@ async fn test_upload_cross_signing_keys() {
         "Upload cross-signing keys failed: {resp}"
     );
 }
+
+#[tokio::test]
+async fn test_device_changes_reach_room_members() {
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "dlalice", "pass").await;
+    let (bob_token, _, _) = common::register_user(&router, "dlbob", "pass").await;
+
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat"}),
+        &alice_token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (status, resp) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "join failed: {resp}");
+
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &bob_token).await;
+    let since = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["next_batch"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Alice logs in on a second device and uploads its keys
+    let (status, resp) = common::post_json(
+        &router,
+        "/_matrix/client/v3/login",
+        &serde_json::json!({
+            "type": "m.login.password",
+            "identifier": {"type": "m.id.user", "user": "dlalice"},
+            "password": "pass",
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "login failed: {resp}");
+    let login: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let phone_token = login["access_token"].as_str().unwrap().to_string();
+    let phone_id = login["device_id"].as_str().unwrap().to_string();
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/upload",
+        &serde_json::json!({"device_keys": {
+            "user_id": alice_id,
+            "device_id": phone_id,
+            "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+            "keys": { format!("ed25519:{phone_id}"): "key" },
+        }}),
+        &phone_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/sync?since={since}&timeout=0"),
+        &bob_token,
+    )
+    .await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(
+        sync["device_lists"]["changed"]
+            .as_array()
+            .is_some_and(|c| c.iter().any(|u| u == alice_id.as_str())),
+        "Alice missing from device_lists.changed: {sync}"
+    );
+
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/keys/changes?from={since}&to=999999999"),
+        &bob_token,
+    )
+    .await;
+    let changes: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(changes["changed"], serde_json::json!([alice_id]));
+
+    // Logging the device out deletes its keys
+    let query = serde_json::json!({"device_keys": {&alice_id: []}});
+    let (_, resp) =
+        common::post_json_authed(&router, "/_matrix/client/v3/keys/query", &query, &bob_token)
+            .await;
+    let keys: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(keys["device_keys"][&alice_id].get(&phone_id).is_some());
+
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/logout",
+        &serde_json::json!({}),
+        &phone_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, resp) =
+        common::post_json_authed(&router, "/_matrix/client/v3/keys/query", &query, &bob_token)
+            .await;
+    let keys: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(keys["device_keys"][&alice_id].get(&phone_id).is_none());
+}
+
+#[tokio::test]
+async fn test_key_backup_lifecycle() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "backupuser", "pass").await;
+    let json = |body: &str| serde_json::from_str::<serde_json::Value>(body).unwrap();
+    let session_key = |first_message_index: i64, is_verified: bool, marker: &str| {
+        serde_json::json!({
+            "first_message_index": first_message_index,
+            "forwarded_count": 0,
+            "is_verified": is_verified,
+            "session_data": { "ciphertext": marker },
+        })
+    };
+    let session_uri = |version: &str| {
+        format!("/_matrix/client/v3/room_keys/keys/!room:localhost/sess1?version={version}")
+    };
+
+    let (status, _) =
+        common::get_authed(&router, "/_matrix/client/v3/room_keys/version", &token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let backup = serde_json::json!({
+        "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+        "auth_data": { "public_key": "abc" },
+    });
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/room_keys/version",
+        &backup,
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let v1 = json(&resp)["version"].as_str().unwrap().to_string();
+
+    // Store a key, then try to replace it with worse and better ones
+    let (status, resp) = common::put_json_authed(
+        &router,
+        &session_uri(&v1),
+        &session_key(5, false, "a"),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let first = json(&resp);
+    assert_eq!(first["count"], 1);
+
+    common::put_json_authed(
+        &router,
+        &session_uri(&v1),
+        &session_key(9, false, "b"),
+        &token,
+    )
+    .await;
+    let (_, resp) = common::get_authed(&router, &session_uri(&v1), &token).await;
+    assert_eq!(json(&resp)["session_data"]["ciphertext"], "a");
+
+    let (_, resp) = common::put_json_authed(
+        &router,
+        &session_uri(&v1),
+        &session_key(9, true, "c"),
+        &token,
+    )
+    .await;
+    assert_ne!(json(&resp)["etag"], first["etag"]);
+    let (_, resp) = common::get_authed(&router, &session_uri(&v1), &token).await;
+    assert_eq!(json(&resp)["session_data"]["ciphertext"], "c");
+
+    // Bulk upload and download
+    let (_, resp) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/keys?version={v1}"),
+        &serde_json::json!({"rooms": {"!other:localhost": {"sessions": {
+            "sess2": session_key(0, false, "d"),
+        }}}}),
+        &token,
+    )
+    .await;
+    assert_eq!(json(&resp)["count"], 2);
+    let (_, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/keys?version={v1}"),
+        &token,
+    )
+    .await;
+    let all = json(&resp);
+    assert_eq!(
+        all["rooms"]["!other:localhost"]["sessions"]["sess2"]["session_data"]["ciphertext"],
+        "d"
+    );
+    let (_, resp) =
+        common::get_authed(&router, "/_matrix/client/v3/room_keys/version", &token).await;
+    let info = json(&resp);
+    assert_eq!(info["version"], v1.as_str());
+    assert_eq!(info["count"], 2);
+    assert_eq!(info["auth_data"]["public_key"], "abc");
+
+    // A new version becomes current; uploads to the old one are refused
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/room_keys/version",
+        &backup,
+        &token,
+    )
+    .await;
+    let v2 = json(&resp)["version"].as_str().unwrap().to_string();
+    assert_ne!(v1, v2);
+    let (status, resp) = common::put_json_authed(
+        &router,
+        &session_uri(&v1),
+        &session_key(0, true, "e"),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let err = json(&resp);
+    assert_eq!(err["errcode"], "M_WRONG_ROOM_KEYS_VERSION");
+    assert_eq!(err["current_version"], v2.as_str());
+
+    // Updating auth_data keeps the algorithm
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/version/{v2}"),
+        &serde_json::json!({
+            "algorithm": "m.megolm_backup.v1.curve25519-aes-sha2",
+            "auth_data": { "public_key": "def" },
+            "version": v2,
+        }),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, resp) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/version/{v2}"),
+        &serde_json::json!({"algorithm": "other", "auth_data": {}}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(json(&resp)["errcode"], "M_INVALID_PARAM");
+
+    // Deleting the current version makes the previous one current again
+    let (status, _) = common::delete_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/version/{v2}"),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/room_keys/version/{v2}"),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, resp) =
+        common::get_authed(&router, "/_matrix/client/v3/room_keys/version", &token).await;
+    assert_eq!(json(&resp)["version"], v1.as_str());
+
+    // Deleting keys
+    let (_, resp) = common::delete_authed(&router, &session_uri(&v1), &token).await;
+    assert_eq!(json(&resp)["count"], 1);
+    let (status, _) = common::get_authed(&router, &session_uri(&v1), &token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, resp) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/room_keys/keys/!room:localhost",
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(json(&resp)["errcode"], "M_MISSING_PARAM");
+}
+
+/// An Ed25519 key whose key ID is `ed25519:<public key>`, as for cross-signing keys.
+fn cross_signing_keypair() -> maelstrom_core::matrix::keys::KeyPair {
+    use maelstrom_core::matrix::keys::KeyPair;
+    let key = KeyPair::generate();
+    KeyPair::from_bytes(
+        format!("ed25519:{}", key.public_key_base64()),
+        key.private_key_bytes(),
+    )
+}
+
+fn cross_signing_key(
+    user_id: &str,
+    usage: &str,
+    key: &maelstrom_core::matrix::keys::KeyPair,
+) -> serde_json::Value {
+    serde_json::json!({
+        "user_id": user_id,
+        "usage": [usage],
+        "keys": { key.key_id(): key.public_key_base64() },
+    })
+}
+
+#[tokio::test]
+async fn test_signature_upload_and_query() {
+    use maelstrom_core::matrix::keys::KeyPair;
+    use maelstrom_core::matrix::signing::sign_json;
+
+    let router = common::test_router();
+    let (alice_token, alice_id, device_id) =
+        common::register_user(&router, "sigalice", "pass").await;
+    let (bob_token, bob_id, _) = common::register_user(&router, "sigbob", "pass").await;
+
+    let device_key = KeyPair::from_bytes(
+        format!("ed25519:{device_id}"),
+        KeyPair::generate().private_key_bytes(),
+    );
+    let device_keys = sign_json(
+        &serde_json::json!({
+            "user_id": alice_id,
+            "device_id": device_id,
+            "algorithms": ["m.megolm.v1.aes-sha2"],
+            "keys": { device_key.key_id(): device_key.public_key_base64() },
+        }),
+        &device_key,
+        &alice_id,
+    );
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/upload",
+        &serde_json::json!({"device_keys": device_keys}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (master, self_signing, user_signing) = (
+        cross_signing_keypair(),
+        cross_signing_keypair(),
+        cross_signing_keypair(),
+    );
+    let alice_master = cross_signing_key(&alice_id, "master", &master);
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/device_signing/upload",
+        &serde_json::json!({
+            "auth": {"type": "m.login.dummy"},
+            "master_key": alice_master,
+            "self_signing_key": sign_json(
+                &cross_signing_key(&alice_id, "self_signing", &self_signing),
+                &master,
+                &alice_id,
+            ),
+            "user_signing_key": sign_json(
+                &cross_signing_key(&alice_id, "user_signing", &user_signing),
+                &master,
+                &alice_id,
+            ),
+        }),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let bob_master_key = cross_signing_keypair();
+    let bob_master = cross_signing_key(&bob_id, "master", &bob_master_key);
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/device_signing/upload",
+        &serde_json::json!({"auth": {"type": "m.login.dummy"}, "master_key": bob_master}),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Alice signs her device with her self-signing key, her master key with
+    // her device, and Bob's master key with her user-signing key
+    let master_id = master.public_key_base64();
+    let bob_master_id = bob_master_key.public_key_base64();
+    let upload = serde_json::json!({
+        &alice_id: {
+            &device_id: sign_json(&device_keys, &self_signing, &alice_id),
+            &master_id: sign_json(&alice_master, &device_key, &alice_id),
+            "NOSUCHKEY": sign_json(&device_keys, &self_signing, &alice_id),
+        },
+        &bob_id: {
+            &bob_master_id: sign_json(&bob_master, &user_signing, &alice_id),
+        },
+    });
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/signatures/upload",
+        &upload,
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "upload failed: {resp}");
+    let failures = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["failures"].clone();
+    assert_eq!(
+        failures,
+        serde_json::json!({&alice_id: {"NOSUCHKEY": {
+            "errcode": "M_NOT_FOUND",
+            "error": format!("No key 'NOSUCHKEY' of '{alice_id}' to sign"),
+        }}})
+    );
+
+    // A key Alice may not sign Bob's master key with, and a forged object
+    let mut forged = bob_master.clone();
+    forged["usage"] = serde_json::json!(["self_signing"]);
+    for signed in [
+        sign_json(&bob_master, &self_signing, &alice_id),
+        sign_json(&forged, &user_signing, &alice_id),
+    ] {
+        let (_, resp) = common::post_json_authed(
+            &router,
+            "/_matrix/client/v3/keys/signatures/upload",
+            &serde_json::json!({&bob_id: {&bob_master_id: signed}}),
+            &alice_token,
+        )
+        .await;
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(
+            json["failures"][&bob_id][&bob_master_id]["errcode"], "M_INVALID_SIGNATURE",
+            "{json}"
+        );
+    }
+
+    let query = serde_json::json!({"device_keys": {&alice_id: [], &bob_id: []}});
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/query",
+        &query,
+        &alice_token,
+    )
+    .await;
+    let keys: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let alice_sigs = &keys["device_keys"][&alice_id][&device_id]["signatures"][&alice_id];
+    assert!(alice_sigs.get(self_signing.key_id()).is_some(), "{keys}");
+    assert!(alice_sigs.get(device_key.key_id()).is_some(), "{keys}");
+    assert!(
+        keys["master_keys"][&alice_id]["signatures"][&alice_id]
+            .get(device_key.key_id())
+            .is_some(),
+        "{keys}"
+    );
+    assert!(
+        keys["master_keys"][&bob_id]["signatures"][&alice_id]
+            .get(user_signing.key_id())
+            .is_some(),
+        "{keys}"
+    );
+
+    // Alice's signature on Bob's master key is private to her
+    let (_, resp) =
+        common::post_json_authed(&router, "/_matrix/client/v3/keys/query", &query, &bob_token)
+            .await;
+    let keys: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(
+        keys["master_keys"][&bob_id]["signatures"]
+            .get(&alice_id)
+            .is_none(),
+        "{keys}"
+    );
+    assert!(
+        keys["device_keys"][&alice_id][&device_id]["signatures"][&alice_id]
+            .get(self_signing.key_id())
+            .is_some(),
+        "{keys}"
+    );
+
+    // New keys for the device drop the signatures on the old ones
+    let new_device_key = KeyPair::from_bytes(
+        format!("ed25519:{device_id}"),
+        KeyPair::generate().private_key_bytes(),
+    );
+    let new_device_keys = sign_json(
+        &serde_json::json!({
+            "user_id": alice_id,
+            "device_id": device_id,
+            "algorithms": ["m.megolm.v1.aes-sha2"],
+            "keys": { new_device_key.key_id(): new_device_key.public_key_base64() },
+        }),
+        &new_device_key,
+        &alice_id,
+    );
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/upload",
+        &serde_json::json!({"device_keys": new_device_keys}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/query",
+        &query,
+        &alice_token,
+    )
+    .await;
+    let keys: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(
+        keys["device_keys"][&alice_id][&device_id]["signatures"][&alice_id]
+            .get(self_signing.key_id())
+            .is_none(),
+        "{keys}"
+    );
+}
+
+#[tokio::test]
+async fn test_fallback_keys_in_claim_and_sync() {
+    let router = common::test_router();
+    let (alice_token, _, _) = common::register_user(&router, "fbalice", "pass").await;
+    let (bob_token, bob_id, bob_device) = common::register_user(&router, "fbbob", "pass").await;
+
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/upload",
+        &serde_json::json!({
+            "one_time_keys": { "signed_curve25519:AAAAAQ": {"key": "otk"} },
+            "fallback_keys": { "signed_curve25519:AAAAAG": {"key": "fallback", "fallback": true} },
+        }),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "upload failed: {resp}");
+
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &bob_token).await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(sync["device_one_time_keys_count"]["signed_curve25519"], 1);
+    assert_eq!(
+        sync["device_unused_fallback_key_types"],
+        serde_json::json!(["signed_curve25519"])
+    );
+
+    let claim = serde_json::json!({"one_time_keys": {&bob_id: {&bob_device: "signed_curve25519"}}});
+    let mut claimed_ids = Vec::new();
+    for _ in 0..2 {
+        let (status, resp) = common::post_json_authed(
+            &router,
+            "/_matrix/client/v3/keys/claim",
+            &claim,
+            &alice_token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        let keys = json["one_time_keys"][&bob_id][&bob_device]
+            .as_object()
+            .unwrap_or_else(|| panic!("nothing claimed: {json}"));
+        claimed_ids.extend(keys.keys().cloned());
+    }
+    assert_eq!(
+        claimed_ids,
+        vec!["signed_curve25519:AAAAAQ", "signed_curve25519:AAAAAG"]
+    );
+
+    // The fallback key is now used, so the device should replace it
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &bob_token).await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(sync["device_one_time_keys_count"]["signed_curve25519"], 0);
+    assert_eq!(
+        sync["device_unused_fallback_key_types"],
+        serde_json::json!([])
+    );
+
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/sync",
+        &serde_json::json!({"extensions": {"e2ee": {"enabled": true}}}),
+        &bob_token,
+    )
+    .await;
+    let sliding: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let e2ee = &sliding["extensions"]["e2ee"];
+    assert_eq!(
+        e2ee["device_one_time_keys_count"]["signed_curve25519"], 0,
+        "{sliding}"
+    );
+    assert_eq!(
+        e2ee["device_unused_fallback_key_types"],
+        serde_json::json!([])
+    );
+}
+
+#[tokio::test]
+async fn test_dehydrated_device_lifecycle() {
+    const PATH: &str = "/_matrix/client/unstable/org.matrix.msc3814.v1/dehydrated_device";
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "dhalice", "pass").await;
+    let (bob_token, _, _) = common::register_user(&router, "dhbob", "pass").await;
+
+    let (status, _) = common::get_authed(&router, PATH, &alice_token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, resp) = common::put_json_authed(
+        &router,
+        PATH,
+        &serde_json::json!({
+            "device_id": "DEHYDRATED1",
+            "device_data": {"algorithm": "m.dehydration.v1.olm", "device_pickle": "pickle"},
+            "initial_device_display_name": "Dehydrated device",
+            "device_keys": {
+                "user_id": &alice_id,
+                "device_id": "DEHYDRATED1",
+                "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+                "keys": {"curve25519:DEHYDRATED1": "curve", "ed25519:DEHYDRATED1": "ed"},
+                "dehydrated": true,
+            },
+            "one_time_keys": { "signed_curve25519:AAAAAQ": {"key": "otk"} },
+        }),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "upload failed: {resp}");
+
+    let (status, resp) = common::get_authed(&router, PATH, &alice_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["device_id"], "DEHYDRATED1");
+    assert_eq!(json["device_data"]["device_pickle"], "pickle");
+
+    // Hidden from the user's own device list...
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/devices", &alice_token).await;
+    assert!(!resp.contains("DEHYDRATED1"), "listed: {resp}");
+    let (status, _) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/devices/DEHYDRATED1",
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // ...but others can find it to encrypt to it
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/query",
+        &serde_json::json!({"device_keys": {&alice_id: []}}),
+        &bob_token,
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(
+        json["device_keys"][&alice_id]["DEHYDRATED1"]["dehydrated"],
+        true
+    );
+
+    let (status, _) = common::put_json_authed(
+        &router,
+        "/_matrix/client/v3/sendToDevice/m.room.encrypted/txn1",
+        &serde_json::json!({"messages": {&alice_id: {"DEHYDRATED1": {"ciphertext": "secret"}}}}),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let events_path = format!("{PATH}/DEHYDRATED1/events");
+    let (status, resp) =
+        common::post_json_authed(&router, &events_path, &serde_json::json!({}), &alice_token).await;
+    assert_eq!(status, StatusCode::OK, "events failed: {resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let events = json["events"].as_array().unwrap();
+    assert_eq!(events.len(), 1, "{json}");
+    assert_eq!(events[0]["content"]["ciphertext"], "secret");
+    let next_batch = json["next_batch"].as_str().unwrap().to_string();
+
+    // Passing next_batch back acknowledges the messages
+    let (_, resp) = common::post_json_authed(
+        &router,
+        &events_path,
+        &serde_json::json!({"next_batch": next_batch}),
+        &alice_token,
+    )
+    .await;
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["events"], serde_json::json!([]));
+
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("{PATH}/OTHER/events"),
+        &serde_json::json!({}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // A new dehydrated device replaces the old one
+    let (status, _) = common::put_json_authed(
+        &router,
+        PATH,
+        &serde_json::json!({"device_id": "DEHYDRATED2", "device_data": {"device_pickle": "new"}}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/keys/query",
+        &serde_json::json!({"device_keys": {&alice_id: []}}),
+        &bob_token,
+    )
+    .await;
+    assert!(!resp.contains("DEHYDRATED1"), "old device kept: {resp}");
+
+    let (status, resp) = common::delete_authed(&router, PATH, &alice_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["device_id"], "DEHYDRATED2");
+    let (status, _) = common::get_authed(&router, PATH, &alice_token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_cross_signing_replacement_requires_auth() {
+    const UPLOAD: &str = "/_matrix/client/v3/keys/device_signing/upload";
+    let state = common::test_state();
+    let router = maelstrom_api::router::build(state.clone());
+    let (token, user_id, _) = common::register_user(&router, "csreset", "pass").await;
+    let master = |key: &str| {
+        serde_json::json!({
+            "user_id": user_id,
+            "usage": ["master"],
+            "keys": { format!("ed25519:{key}"): key },
+        })
+    };
+
+    // A self-signing key needs a master key to hang off
+    let (status, _) = common::post_json_authed(
+        &router,
+        UPLOAD,
+        &serde_json::json!({"self_signing_key": {
+            "user_id": user_id, "usage": ["self_signing"], "keys": {"ed25519:ssk": "ssk"},
+        }}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Setting up cross-signing needs no UIA, and neither does a retry
+    for _ in 0..2 {
+        let (status, resp) = common::post_json_authed(
+            &router,
+            UPLOAD,
+            &serde_json::json!({"master_key": master("first")}),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "upload failed: {resp}");
+    }
+
+    // Replacing the master key does
+    for auth in [
+        serde_json::Value::Null,
+        serde_json::json!({"type": "m.login.dummy"}),
+        serde_json::json!({"type": "m.login.password", "password": "wrong"}),
+        serde_json::json!({"type": "org.matrix.cross_signing_reset"}),
+    ] {
+        let (status, resp) = common::post_json_authed(
+            &router,
+            UPLOAD,
+            &serde_json::json!({"master_key": master("second"), "auth": auth}),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "replaced with {auth}");
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        assert_eq!(
+            json["flows"],
+            serde_json::json!([
+                {"stages": ["m.login.password"]},
+                {"stages": ["org.matrix.cross_signing_reset"]},
+            ])
+        );
+    }
+    let keys = state
+        .storage()
+        .get_cross_signing_keys(&user_id)
+        .await
+        .unwrap();
+    assert_eq!(keys["master_key"], master("first"));
+
+    let (status, _) = common::post_json_authed(
+        &router,
+        UPLOAD,
+        &serde_json::json!({
+            "master_key": master("second"),
+            "auth": {"type": "m.login.password", "password": "pass"},
+        }),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // An approved reset needs no password, once
+    state
+        .storage()
+        .set_cross_signing_reset_allowed_until(
+            &user_id,
+            Some(chrono::Utc::now() + chrono::Duration::minutes(10)),
+        )
+        .await
+        .unwrap();
+    let (status, _) = common::post_json_authed(
+        &router,
+        UPLOAD,
+        &serde_json::json!({"master_key": master("third")}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::post_json_authed(
+        &router,
+        UPLOAD,
+        &serde_json::json!({"master_key": master("fourth")}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let history = state
+        .storage()
+        .get_master_key_history(&user_id)
+        .await
+        .unwrap();
+    let replaced: Vec<&serde_json::Value> = history.iter().map(|h| &h.key).collect();
+    assert_eq!(replaced, vec![&master("first"), &master("second")]);
+
+    // The user's own devices are told to re-query their keys
+    let (_, resp) = common::get_authed(
+        &router,
+        "/_matrix/client/v3/keys/changes?from=0&to=999999",
+        &token,
+    )
+    .await;
+    assert!(resp.contains(&user_id), "not in changes: {resp}");
+}
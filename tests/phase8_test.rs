@@ -282,6 +282,103 @@ async fn test_space_hierarchy() {
     assert!(!rooms.is_empty());
 }
 
+#[tokio::test]
+async fn test_space_hierarchy_children_and_pagination() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "spacer2", "pass").await;
+
+    let create = |body: serde_json::Value| {
+        let router = router.clone();
+        let token = token.clone();
+        async move {
+            let (_, resp) =
+                common::post_json_authed(&router, "/_matrix/client/v3/createRoom", &body, &token)
+                    .await;
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    let space_id = create(serde_json::json!({
+        "preset": "public_chat",
+        "creation_content": {"type": "m.space"}
+    }))
+    .await;
+    let first = create(serde_json::json!({"preset": "public_chat", "name": "First"})).await;
+    let second = create(serde_json::json!({"preset": "public_chat", "name": "Second"})).await;
+
+    for (child, order, suggested) in [(&first, "a", true), (&second, "b", false)] {
+        let (status, _) = common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{space_id}/state/m.space.child/{child}"),
+            &serde_json::json!({"via": ["localhost"], "order": order, "suggested": suggested}),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let hierarchy = |query: String| {
+        let router = router.clone();
+        let token = token.clone();
+        let space_id = space_id.clone();
+        async move {
+            let (status, resp) = common::get_authed(
+                &router,
+                &format!("/_matrix/client/v1/rooms/{space_id}/hierarchy{query}"),
+                &token,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()
+        }
+    };
+
+    // Full walk: space first, then children in `order`
+    let json = hierarchy(String::new()).await;
+    let ids: Vec<&str> = json["rooms"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["room_id"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![space_id.as_str(), first.as_str(), second.as_str()]
+    );
+    assert!(json.get("next_batch").is_none());
+
+    // Paginate one room at a time
+    let page1 = hierarchy("?limit=2".to_string()).await;
+    assert_eq!(page1["rooms"].as_array().unwrap().len(), 2);
+    let token_from = page1["next_batch"].as_str().unwrap().to_string();
+    let page2 = hierarchy(format!("?limit=2&from={token_from}")).await;
+    assert_eq!(page2["rooms"].as_array().unwrap().len(), 1);
+    assert_eq!(page2["rooms"][0]["room_id"], second.as_str());
+    assert!(page2.get("next_batch").is_none());
+
+    // The token is tied to the query it was issued for
+    let (status, _) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v1/rooms/{space_id}/hierarchy?limit=2&suggested_only=true&from={token_from}"),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // suggested_only drops the non-suggested child
+    let json = hierarchy("?suggested_only=true".to_string()).await;
+    let rooms = json["rooms"].as_array().unwrap();
+    assert_eq!(rooms.len(), 2);
+    assert_eq!(rooms[1]["room_id"], first.as_str());
+
+    // max_depth=0 returns only the root
+    let json = hierarchy("?max_depth=0".to_string()).await;
+    assert_eq!(json["rooms"].as_array().unwrap().len(), 1);
+}
+
 // -- MockStorage relation tests --
 
 #[tokio::test]
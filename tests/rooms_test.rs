@@ -155,3 +155,473 @@ async fn test_invite_and_join() {
     let rooms = json["joined_rooms"].as_array().unwrap();
     assert!(rooms.iter().any(|r| r.as_str() == Some(&room_id)));
 }
+
+#[tokio::test]
+async fn test_public_rooms_networks_and_remote_servers() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "browser", "pass").await;
+
+    let body =
+        serde_json::json!({ "name": "Lobby", "visibility": "public", "preset": "public_chat" });
+    let (status, resp) =
+        common::post_json_authed(&router, "/_matrix/client/v3/createRoom", &body, &token).await;
+    assert_eq!(status, StatusCode::OK, "createRoom failed: {resp}");
+
+    let registration = serde_json::json!({
+        "id": "ircbridge",
+        "url": "http://localhost:9999",
+        "as_token": "as_token_irc",
+        "hs_token": "hs_token_irc",
+        "sender_localpart": "ircbot",
+        "user_namespaces": [],
+        "alias_namespaces": [],
+        "rate_limited": false,
+        "protocols": ["irc"],
+    });
+    let (status, resp) =
+        common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+    assert_eq!(status, StatusCode::OK, "register appservice failed: {resp}");
+
+    // The bridge's network is advertised as a directory instance
+    let (status, resp) =
+        common::get_authed(&router, "/_matrix/client/v3/thirdparty/protocols", &token).await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["irc"]["instances"][0]["instance_id"], "ircbridge|irc");
+
+    let (status, resp) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=ircbridge%7Circ",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["chunk"], serde_json::json!([]));
+
+    let (status, _) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=nobody%7Cxmpp",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?include_all_networks=true&third_party_instance_id=ircbridge%7Circ",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Matrix rooms are part of "all networks"
+    let (status, resp) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?include_all_networks=true",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["chunk"][0]["name"], "Lobby");
+
+    // Naming ourselves is the same as a local query
+    let (status, resp) =
+        common::get(&router, "/_matrix/client/v3/publicRooms?server=localhost").await;
+    assert_eq!(status, StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["chunk"].as_array().unwrap().len(), 1);
+
+    // Remote directories need federation
+    let (status, _) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/publicRooms?server=remote.example",
+        &serde_json::json!({ "filter": { "generic_search_term": "lobby" } }),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_appservice_directory_lists() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "bridger", "pass").await;
+
+    let mut room_ids = Vec::new();
+    for (name, visibility, alias) in [
+        ("Matrix Lobby", "public", "lobby"),
+        ("#irc-channel", "private", "irc_channel"),
+    ] {
+        let body = serde_json::json!({
+            "name": name,
+            "visibility": visibility,
+            "room_alias_name": alias,
+        });
+        let (status, resp) =
+            common::post_json_authed(&router, "/_matrix/client/v3/createRoom", &body, &token).await;
+        assert_eq!(status, StatusCode::OK, "createRoom failed: {resp}");
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        room_ids.push(json["room_id"].as_str().unwrap().to_string());
+    }
+    let bridged = &room_ids[1];
+
+    let registration = serde_json::json!({
+        "id": "ircbridge",
+        "url": "http://localhost:9999",
+        "as_token": "as_token_irc",
+        "hs_token": "hs_token_irc",
+        "sender_localpart": "ircbot",
+        "user_namespaces": [],
+        "alias_namespaces": [{ "regex": "#irc_.*:localhost", "exclusive": false }],
+        "rate_limited": false,
+        "protocols": ["libera", "oftc"],
+    });
+    let (status, resp) =
+        common::post_json(&router, "/_maelstrom/admin/v1/appservice", &registration).await;
+    assert_eq!(status, StatusCode::OK, "register appservice failed: {resp}");
+
+    let path = format!("/_matrix/client/v3/directory/list/appservice/libera/{bridged}");
+    let public = serde_json::json!({ "visibility": "public" });
+
+    // Only networks of the registration, and rooms in the bridge's namespaces
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/directory/list/appservice/efnet/{bridged}"),
+        &public,
+        "as_token_irc",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/directory/list/appservice/libera/{}",
+            room_ids[0]
+        ),
+        &public,
+        "as_token_irc",
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Only the appservice itself may publish to its networks
+    let (status, _) = common::put_json_authed(&router, &path, &public, &token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = common::put_json_authed(&router, &path, &public, "bogus").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, resp) = common::put_json_authed(&router, &path, &public, "as_token_irc").await;
+    assert_eq!(status, StatusCode::OK, "publish failed: {resp}");
+
+    let names = |resp: &str| -> Vec<String> {
+        let json: serde_json::Value = serde_json::from_str(resp).unwrap();
+        json["chunk"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["name"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let (status, resp) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=ircbridge%7Clibera",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    assert_eq!(names(&resp), vec!["#irc-channel"]);
+
+    // Other networks of the same bridge are empty
+    let (_, resp) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=ircbridge%7Coftc",
+    )
+    .await;
+    assert!(names(&resp).is_empty());
+
+    // Networks the bridge did not register are unknown
+    let (status, _) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=ircbridge%7Cefnet",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // The bridged room is not in the Matrix directory...
+    let (_, resp) = common::get(&router, "/_matrix/client/v3/publicRooms").await;
+    assert_eq!(names(&resp), vec!["Matrix Lobby"]);
+
+    // ...but is part of "all networks"
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/publicRooms",
+        &serde_json::json!({ "include_all_networks": true }),
+        &token,
+    )
+    .await;
+    let mut all = names(&resp);
+    all.sort();
+    assert_eq!(all, vec!["#irc-channel", "Matrix Lobby"]);
+
+    // Unpublishing removes it again
+    let (status, _) = common::put_json_authed(
+        &router,
+        &path,
+        &serde_json::json!({ "visibility": "private" }),
+        "as_token_irc",
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, resp) = common::get(
+        &router,
+        "/_matrix/client/v3/publicRooms?third_party_instance_id=ircbridge%7Clibera",
+    )
+    .await;
+    assert!(names(&resp).is_empty());
+
+    let (status, _) = common::put_json_authed(
+        &router,
+        "/_matrix/client/v3/directory/list/appservice/libera/!missing:localhost",
+        &public,
+        "as_token_irc",
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_peek_world_readable_room() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "host", "pass").await;
+    let (peek_token, _, _) = common::register_user(&router, "window", "pass").await;
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat", "name": "Lobby"}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let send = |body: &'static str| {
+        let router = router.clone();
+        let token = token.clone();
+        let room_id = room_id.clone();
+        async move {
+            common::put_json_authed(
+                &router,
+                &format!(
+                    "/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{}",
+                    body.replace(' ', "-")
+                ),
+                &serde_json::json!({"msgtype": "m.text", "body": body}),
+                &token,
+            )
+            .await;
+        }
+    };
+    let bodies = |events: &serde_json::Value| -> Vec<String> {
+        events
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|e| e["content"]["body"].as_str().map(String::from))
+            .collect()
+    };
+
+    // Not peekable while the history is shared
+    send("members only").await;
+    let initial_sync = format!("/_matrix/client/v3/rooms/{room_id}/initialSync");
+    let (status, _) = common::get_authed(&router, &initial_sync, &peek_token).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/state/m.room.history_visibility/"),
+        &serde_json::json!({"history_visibility": "world_readable"}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    send("hello visitors").await;
+
+    let (status, resp) = common::get_authed(&router, &initial_sync, &peek_token).await;
+    assert_eq!(status, StatusCode::OK, "initialSync failed: {resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["room_id"], room_id.as_str());
+    assert!(json.get("membership").is_none());
+    assert_eq!(bodies(&json["messages"]["chunk"]), vec!["hello visitors"]);
+    assert!(
+        json["state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.room.name" && e["content"]["name"] == "Lobby")
+    );
+
+    // Follow the room from the end of the snapshot
+    send("still here").await;
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/v3/events?room_id={}&from={}",
+            maelstrom_api::handlers::util::percent_encode(&room_id),
+            json["messages"]["end"].as_str().unwrap()
+        ),
+        &peek_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "events failed: {resp}");
+    let events: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(bodies(&events["chunk"]), vec!["still here"]);
+
+    // Sliding sync can subscribe to the room without joining it
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/sync",
+        &serde_json::json!({
+            "room_subscriptions": {
+                room_id.clone(): {"required_state": [["m.room.name", ""]], "timeline_limit": 5}
+            }
+        }),
+        &peek_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "sliding sync failed: {resp}");
+    let sliding: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let room = &sliding["rooms"][&room_id];
+    assert_eq!(room["name"], "Lobby");
+    assert_eq!(
+        bodies(&room["timeline"]),
+        vec!["hello visitors", "still here"]
+    );
+}
+
+#[tokio::test]
+async fn test_events_across_rooms_skip_nothing() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "busy", "pass").await;
+    let mut room_ids = Vec::new();
+    for name in ["Busy", "Quiet"] {
+        let (_, resp) = common::post_json_authed(
+            &router,
+            "/_matrix/client/v3/createRoom",
+            &serde_json::json!({"name": name}),
+            &token,
+        )
+        .await;
+        room_ids.push(
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+                .as_str()
+                .unwrap()
+                .to_string(),
+        );
+    }
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/events", &token).await;
+    let from = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["end"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // More new events in one room than a single fetch returns, then one in
+    // the other room after all of them
+    for (room_id, count) in [(&room_ids[0], 150), (&room_ids[1], 1)] {
+        for i in 0..count {
+            common::put_json_authed(
+                &router,
+                &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/txn{i}"),
+                &serde_json::json!({"msgtype": "m.text", "body": format!("{i}")}),
+                &token,
+            )
+            .await;
+        }
+    }
+
+    let mut messages = Vec::new();
+    let mut from = from;
+    loop {
+        let (status, resp) = common::get_authed(
+            &router,
+            &format!("/_matrix/client/v3/events?from={from}"),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "events failed: {resp}");
+        let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+        let chunk = json["chunk"].as_array().unwrap();
+        if chunk.is_empty() {
+            break;
+        }
+        messages.extend(
+            chunk
+                .iter()
+                .filter(|e| e["type"] == "m.room.message")
+                .map(|e| e["room_id"].as_str().unwrap().to_string()),
+        );
+        from = json["end"].as_str().unwrap().to_string();
+    }
+    assert_eq!(messages.iter().filter(|r| **r == room_ids[0]).count(), 150);
+    assert_eq!(messages.last(), Some(&room_ids[1]));
+}
+
+#[tokio::test]
+async fn test_initial_sync_after_leaving_shows_state_at_leave() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "keeper", "pass").await;
+    let (guest_token, _, _) = common::register_user(&router, "guest", "pass").await;
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat", "name": "Before"}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &guest_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/leave"),
+        &serde_json::json!({}),
+        &guest_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/state/m.room.name/"),
+        &serde_json::json!({"name": "After"}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The renamed room still has a name for the guest: the one it had when they left
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/initialSync"),
+        &guest_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "initialSync failed: {resp}");
+    let json: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(json["membership"], "leave");
+    let names: Vec<&serde_json::Value> = json["state"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|e| e["type"] == "m.room.name")
+        .map(|e| &e["content"]["name"])
+        .collect();
+    assert_eq!(names, vec!["Before"]);
+}
@@ -176,3 +176,469 @@ async fn test_health_check() {
     store.set_healthy(false);
     assert!(!store.is_healthy().await);
 }
+
+#[tokio::test]
+async fn test_appservice_queue() {
+    let store = MockStorage::new();
+    let first = store
+        .enqueue_appservice_item(
+            "bridge",
+            AppServiceItemKind::Event,
+            &serde_json::json!({"n": 1}),
+        )
+        .await
+        .unwrap();
+    store
+        .enqueue_appservice_item(
+            "other",
+            AppServiceItemKind::Event,
+            &serde_json::json!({"n": 2}),
+        )
+        .await
+        .unwrap();
+    let last = store
+        .enqueue_appservice_item(
+            "bridge",
+            AppServiceItemKind::ToDevice,
+            &serde_json::json!({"n": 3}),
+        )
+        .await
+        .unwrap();
+    assert!(first < last);
+
+    let queue = store.get_appservice_queue("bridge", 10).await.unwrap();
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue[1].kind, AppServiceItemKind::ToDevice);
+
+    store
+        .delete_appservice_queue("bridge", first)
+        .await
+        .unwrap();
+    let queue = store.get_appservice_queue("bridge", 10).await.unwrap();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0].id, last);
+    assert_eq!(
+        store.get_appservice_queue("other", 10).await.unwrap().len(),
+        1
+    );
+
+    let delivery = AppServiceDelivery {
+        appservice_id: "bridge".to_string(),
+        txn_id: 3,
+        in_flight_up_to: Some(last),
+        ..Default::default()
+    };
+    store.upsert_appservice_delivery(&delivery).await.unwrap();
+    let loaded = store.get_appservice_delivery("bridge").await.unwrap();
+    assert_eq!(loaded.txn_id, 3);
+    assert_eq!(loaded.in_flight_up_to, Some(last));
+    assert!(matches!(
+        store.get_appservice_delivery("other").await,
+        Err(StorageError::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn test_device_list_change_stream() {
+    let store = MockStorage::new();
+
+    let first = store
+        .add_device_list_change("@alice:test", "DEV1", false)
+        .await
+        .unwrap();
+    let second = store
+        .add_device_list_change("@alice:test", "DEV1", true)
+        .await
+        .unwrap();
+    let other = store
+        .add_device_list_change("@bob:test", "DEV2", false)
+        .await
+        .unwrap();
+
+    // Stream IDs count per user, stream positions are global
+    assert_eq!((first.stream_id, second.stream_id), (1, 2));
+    assert_eq!(other.stream_id, 1);
+    assert!(second.deleted);
+    assert!(first.stream_pos < second.stream_pos && second.stream_pos < other.stream_pos);
+    assert_eq!(
+        store
+            .get_device_list_stream_id("@alice:test")
+            .await
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        store
+            .get_device_list_stream_id("@carol:test")
+            .await
+            .unwrap(),
+        0
+    );
+
+    let mut changed = store
+        .get_device_list_changed_users(second.stream_pos, i64::MAX)
+        .await
+        .unwrap();
+    changed.sort();
+    assert_eq!(changed, vec!["@alice:test", "@bob:test"]);
+    assert_eq!(
+        store
+            .get_device_list_changed_users(other.stream_pos, other.stream_pos)
+            .await
+            .unwrap(),
+        vec!["@bob:test"]
+    );
+
+    // Key changes are on the global stream only
+    let key_change = store.add_key_change("@carol:test").await.unwrap();
+    assert!(key_change > other.stream_pos);
+    assert_eq!(
+        store
+            .get_device_list_stream_id("@carol:test")
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        store
+            .get_device_list_changed_users(key_change, i64::MAX)
+            .await
+            .unwrap(),
+        vec!["@carol:test"]
+    );
+
+    assert_eq!(
+        store
+            .get_remote_device_stream_id("@dave:remote")
+            .await
+            .unwrap(),
+        None
+    );
+    store
+        .set_remote_device_stream_id("@dave:remote", Some(7))
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_remote_device_stream_id("@dave:remote")
+            .await
+            .unwrap(),
+        Some(7)
+    );
+    store
+        .set_remote_device_stream_id("@dave:remote", None)
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_remote_device_stream_id("@dave:remote")
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_fallback_keys_claimed_when_one_time_keys_run_out() {
+    let store = MockStorage::new();
+    store
+        .store_one_time_keys(
+            "@alice:test",
+            "DEV1",
+            &serde_json::json!({"signed_curve25519:AAAA": {"key": "otk"}}),
+        )
+        .await
+        .unwrap();
+    store
+        .store_fallback_keys(
+            "@alice:test",
+            "DEV1",
+            &serde_json::json!({"signed_curve25519:BBBB": {"key": "fb1", "fallback": true}}),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_unused_fallback_key_types("@alice:test", "DEV1")
+            .await
+            .unwrap(),
+        vec!["signed_curve25519"]
+    );
+
+    let claims = serde_json::json!({"@alice:test": {"DEV1": "signed_curve25519"}});
+    let first = store.claim_one_time_keys(&claims).await.unwrap();
+    assert!(first["@alice:test"]["DEV1"]["signed_curve25519:AAAA"].is_object());
+
+    // Out of one-time keys: the fallback key is handed out, and kept
+    for _ in 0..2 {
+        let claimed = store.claim_one_time_keys(&claims).await.unwrap();
+        assert_eq!(
+            claimed["@alice:test"]["DEV1"]["signed_curve25519:BBBB"]["key"],
+            "fb1"
+        );
+    }
+    assert!(
+        store
+            .get_unused_fallback_key_types("@alice:test", "DEV1")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    // Re-uploading the same key keeps it used; a new key replaces it
+    let fallback = |id: &str| serde_json::json!({ format!("signed_curve25519:{id}"): {"key": id} });
+    store
+        .store_fallback_keys("@alice:test", "DEV1", &fallback("BBBB"))
+        .await
+        .unwrap();
+    assert!(
+        store
+            .get_unused_fallback_key_types("@alice:test", "DEV1")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    store
+        .store_fallback_keys("@alice:test", "DEV1", &fallback("CCCC"))
+        .await
+        .unwrap();
+    let claimed = store.claim_one_time_keys(&claims).await.unwrap();
+    assert!(claimed["@alice:test"]["DEV1"]["signed_curve25519:CCCC"].is_object());
+
+    // Deleting the device's keys removes its fallback key too
+    store
+        .delete_device_keys("@alice:test", Some("DEV1"))
+        .await
+        .unwrap();
+    let claimed = store.claim_one_time_keys(&claims).await.unwrap();
+    assert_eq!(claimed, serde_json::json!({}));
+}
+
+#[tokio::test]
+async fn test_key_signatures_replace_per_signing_key() {
+    let store = MockStorage::new();
+    let signature = |signing_key_id: &str, signature: &str| KeySignature {
+        signed_user_id: "@alice:test".to_string(),
+        signed_key_id: "DEV1".to_string(),
+        signing_user_id: "@alice:test".to_string(),
+        signing_key_id: signing_key_id.to_string(),
+        signature: signature.to_string(),
+    };
+
+    store
+        .add_key_signature(&signature("ed25519:ssk", "first"))
+        .await
+        .unwrap();
+    store
+        .add_key_signature(&signature("ed25519:ssk", "second"))
+        .await
+        .unwrap();
+    store
+        .add_key_signature(&signature("ed25519:other", "third"))
+        .await
+        .unwrap();
+
+    let mut signatures = store.get_key_signatures("@alice:test").await.unwrap();
+    signatures.sort_by(|a, b| a.signing_key_id.cmp(&b.signing_key_id));
+    let values: Vec<&str> = signatures.iter().map(|s| s.signature.as_str()).collect();
+    assert_eq!(values, vec!["third", "second"]);
+    assert!(
+        store
+            .get_key_signatures("@bob:test")
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn test_key_backup_versions_and_keys() {
+    let store = MockStorage::new();
+    let auth_data = serde_json::json!({"public_key": "abc"});
+    let key = |session_id: &str| BackedUpRoomKey {
+        room_id: "!room:test".to_string(),
+        session_id: session_id.to_string(),
+        first_message_index: 0,
+        forwarded_count: 0,
+        is_verified: false,
+        session_data: serde_json::json!({"ciphertext": session_id}),
+    };
+
+    let v1 = store
+        .create_key_backup_version("@alice:test", "alg", &auth_data)
+        .await
+        .unwrap();
+    let v2 = store
+        .create_key_backup_version("@alice:test", "alg", &auth_data)
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_key_backup_version("@alice:test", None)
+            .await
+            .unwrap()
+            .version,
+        v2
+    );
+
+    store
+        .put_backed_up_room_keys("@alice:test", &v2, &[key("s1")])
+        .await
+        .unwrap();
+    store
+        .put_backed_up_room_keys("@alice:test", &v2, &[key("s2")])
+        .await
+        .unwrap();
+    store
+        .put_backed_up_room_keys("@alice:test", &v2, &[key("s1")])
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .count_backed_up_room_keys("@alice:test", &v2)
+            .await
+            .unwrap(),
+        2
+    );
+    let version = store
+        .get_key_backup_version("@alice:test", Some(&v2))
+        .await
+        .unwrap();
+    assert_eq!(version.etag, 3);
+    let keys = store
+        .get_backed_up_room_keys("@alice:test", &v2, Some("!room:test"), Some("s2"))
+        .await
+        .unwrap();
+    assert_eq!(keys, vec![key("s2")]);
+
+    store
+        .delete_backed_up_room_keys("@alice:test", &v2, None, Some("s1"))
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .count_backed_up_room_keys("@alice:test", &v2)
+            .await
+            .unwrap(),
+        1
+    );
+
+    // Deleting a version drops its keys, and its number is not reused
+    store
+        .delete_key_backup_version("@alice:test", &v2)
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.get_key_backup_version("@alice:test", Some(&v2)).await,
+        Err(StorageError::NotFound)
+    ));
+    assert_eq!(
+        store
+            .get_key_backup_version("@alice:test", None)
+            .await
+            .unwrap()
+            .version,
+        v1
+    );
+    assert!(matches!(
+        store
+            .put_backed_up_room_keys("@alice:test", &v2, &[key("s3")])
+            .await,
+        Err(StorageError::NotFound)
+    ));
+    let v3 = store
+        .create_key_backup_version("@alice:test", "alg", &auth_data)
+        .await
+        .unwrap();
+    assert_ne!(v3, v2);
+    assert_eq!(
+        store
+            .count_backed_up_room_keys("@alice:test", &v2)
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_dehydrated_device_forgotten_with_device() {
+    let store = MockStorage::new();
+    let user_id = UserId::parse("@alice:test").unwrap();
+    store
+        .create_device(&test_device("@alice:test", "DEHYDRATED"))
+        .await
+        .unwrap();
+    store
+        .set_dehydrated_device(
+            &user_id,
+            &DehydratedDevice {
+                device_id: "DEHYDRATED".to_string(),
+                device_data: serde_json::json!({"device_pickle": "pickle"}),
+            },
+        )
+        .await
+        .unwrap();
+
+    let device = store.get_dehydrated_device(&user_id).await.unwrap();
+    assert_eq!(device.device_id, "DEHYDRATED");
+    assert_eq!(device.device_data["device_pickle"], "pickle");
+
+    store
+        .remove_device(&user_id, &DeviceId::new("DEHYDRATED"))
+        .await
+        .unwrap();
+    assert!(matches!(
+        store.get_dehydrated_device(&user_id).await,
+        Err(StorageError::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn test_thread_subscriptions_replace_per_thread() {
+    let store = MockStorage::new();
+    let subscription = |thread_root: &str, subscribed: bool, bump_stamp: i64| ThreadSubscription {
+        room_id: "!room:test".to_string(),
+        thread_root: thread_root.to_string(),
+        subscribed,
+        automatic: false,
+        bump_stamp,
+    };
+    store
+        .set_thread_subscription("@alice:test", &subscription("$a", true, 1))
+        .await
+        .unwrap();
+    store
+        .set_thread_subscription("@alice:test", &subscription("$b", true, 2))
+        .await
+        .unwrap();
+    store
+        .set_thread_subscription("@alice:test", &subscription("$a", false, 3))
+        .await
+        .unwrap();
+
+    let a = store
+        .get_thread_subscription("@alice:test", "!room:test", "$a")
+        .await
+        .unwrap();
+    assert!(!a.subscribed);
+    assert!(matches!(
+        store
+            .get_thread_subscription("@bob:test", "!room:test", "$a")
+            .await,
+        Err(StorageError::NotFound)
+    ));
+
+    let since = store
+        .get_thread_subscriptions("@alice:test", 2)
+        .await
+        .unwrap();
+    assert_eq!(since, vec![subscription("$a", false, 3)]);
+    assert_eq!(
+        store
+            .get_thread_subscriptions("@alice:test", 0)
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
@@ -125,3 +125,734 @@ async fn test_sync_no_token_returns_unauthorized() {
     let (status, _) = common::get(&router, "/_matrix/client/v3/sync").await;
     assert_eq!(status, StatusCode::UNAUTHORIZED);
 }
+
+/// The presence event for `user_id` in a sync response, if any.
+fn presence_of(sync: &serde_json::Value, user_id: &str) -> Option<serde_json::Value> {
+    sync["presence"]["events"]
+        .as_array()?
+        .iter()
+        .find(|event| event["sender"] == user_id)
+        .map(|event| event["content"].clone())
+}
+
+#[tokio::test]
+async fn test_sync_drives_presence() {
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "presalice", "pass").await;
+    let (bob_token, _, _) = common::register_user(&router, "presbob", "pass").await;
+
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat"}),
+        &alice_token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (status, resp) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "join failed: {resp}");
+
+    // Syncing with set_presence=offline leaves Alice offline
+    common::get_authed(
+        &router,
+        "/_matrix/client/v3/sync?set_presence=offline",
+        &alice_token,
+    )
+    .await;
+    let status_uri = format!("/_matrix/client/v3/presence/{alice_id}/status");
+    let (_, resp) = common::get_authed(&router, &status_uri, &bob_token).await;
+    let presence: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(presence["presence"], "offline");
+
+    // A plain sync brings her online, which Bob sees in his sync
+    common::get_authed(&router, "/_matrix/client/v3/sync", &alice_token).await;
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &bob_token).await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let content = presence_of(&sync, &alice_id).expect("Alice's presence in Bob's sync");
+    assert_eq!(content["presence"], "online");
+    assert_eq!(content["currently_active"], true);
+
+    // An explicit status message survives later syncs
+    let (status, _) = common::put_json_authed(
+        &router,
+        &status_uri,
+        &serde_json::json!({"presence": "online", "status_msg": "In a meeting"}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    common::get_authed(&router, "/_matrix/client/v3/sync", &alice_token).await;
+    let (_, resp) = common::get_authed(&router, &status_uri, &bob_token).await;
+    let presence: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(presence["presence"], "online");
+    assert_eq!(presence["status_msg"], "In a meeting");
+
+    // set_presence=unavailable marks her idle
+    common::get_authed(
+        &router,
+        "/_matrix/client/v3/sync?set_presence=unavailable",
+        &alice_token,
+    )
+    .await;
+    let (_, resp) = common::get_authed(&router, &status_uri, &bob_token).await;
+    let presence: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(presence["presence"], "unavailable");
+}
+
+#[tokio::test]
+async fn test_presence_off_mode() {
+    use std::sync::Arc;
+
+    use maelstrom_api::notify::LocalNotifier;
+    use maelstrom_api::state::AppState;
+    use maelstrom_core::matrix::ephemeral::{EphemeralStore, PresenceMode};
+    use maelstrom_core::matrix::id::ServerName;
+    use maelstrom_storage::mock::MockStorage;
+
+    let state = AppState::new(
+        MockStorage::new(),
+        LocalNotifier::new(),
+        Arc::new(EphemeralStore::new().with_presence_mode(PresenceMode::Off)),
+        ServerName::new("localhost"),
+        "http://localhost:8008".to_string(),
+    );
+    let router = maelstrom_api::router::build(state);
+    let (token, user_id, _) = common::register_user(&router, "presoff", "pass").await;
+
+    let status_uri = format!("/_matrix/client/v3/presence/{user_id}/status");
+    let (status, _) = common::put_json_authed(
+        &router,
+        &status_uri,
+        &serde_json::json!({"presence": "online", "status_msg": "Hello"}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &token).await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert!(sync["presence"].is_null(), "unexpected presence: {sync}");
+
+    let (_, resp) = common::get_authed(&router, &status_uri, &token).await;
+    let presence: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(presence["presence"], "offline");
+}
+
+#[tokio::test]
+async fn test_private_receipts_only_synced_to_sender() {
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "rcptalice", "pass").await;
+    let (bob_token, bob_id, _) = common::register_user(&router, "rcptbob", "pass").await;
+
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({"preset": "public_chat"}),
+        &alice_token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &bob_token,
+    )
+    .await;
+    let (_, resp) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/txn1"),
+        &serde_json::json!({"msgtype": "m.text", "body": "Read me"}),
+        &alice_token,
+    )
+    .await;
+    let event_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["event_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    // Alice reads the thread publicly, Bob reads privately
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/receipt/m.read/{event_id}"),
+        &serde_json::json!({"thread_id": "main"}),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/receipt/m.read.private/{event_id}"),
+        &serde_json::json!({}),
+        &bob_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let receipts_for = |sync: serde_json::Value| {
+        sync["rooms"]["join"][&room_id]["ephemeral"]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["type"] == "m.receipt")
+            .map(|e| e["content"][&event_id].clone())
+            .unwrap()
+    };
+
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &alice_token).await;
+    let receipts = receipts_for(serde_json::from_str(&resp).unwrap());
+    assert_eq!(receipts["m.read"][&alice_id]["thread_id"], "main");
+    assert!(receipts.get("m.read.private").is_none(), "{receipts}");
+
+    let (_, resp) = common::get_authed(&router, "/_matrix/client/v3/sync", &bob_token).await;
+    let receipts = receipts_for(serde_json::from_str(&resp).unwrap());
+    assert!(
+        receipts["m.read.private"][&bob_id].is_object(),
+        "{receipts}"
+    );
+}
+
+#[tokio::test]
+async fn test_sync_and_messages_apply_filters() {
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "filteralice", "pass").await;
+    let (bob_token, bob_id, _) = common::register_user(&router, "filterbob", "pass").await;
+
+    let create = |body: serde_json::Value| {
+        let router = router.clone();
+        let token = alice_token.clone();
+        async move {
+            let (_, resp) =
+                common::post_json_authed(&router, "/_matrix/client/v3/createRoom", &body, &token)
+                    .await;
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+    let room_id = create(serde_json::json!({"preset": "public_chat"})).await;
+    let other_room = create(serde_json::json!({})).await;
+    common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/join"),
+        &serde_json::json!({}),
+        &bob_token,
+    )
+    .await;
+
+    let send = |token: &str, txn: &str, content: serde_json::Value| {
+        let router = router.clone();
+        let uri = format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/{txn}");
+        let token = token.to_string();
+        async move {
+            let (_, resp) = common::put_json_authed(&router, &uri, &content, &token).await;
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["event_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+    let root = send(
+        &alice_token,
+        "t1",
+        serde_json::json!({"msgtype": "m.text", "body": "Thread root"}),
+    )
+    .await;
+    send(
+        &bob_token,
+        "t2",
+        serde_json::json!({"msgtype": "m.text", "body": "Plain"}),
+    )
+    .await;
+    send(
+        &bob_token,
+        "t3",
+        serde_json::json!({"msgtype": "m.image", "body": "Picture", "url": "mxc://localhost/pic"}),
+    )
+    .await;
+    send(
+        &bob_token,
+        "t4",
+        serde_json::json!({
+            "msgtype": "m.text",
+            "body": "In thread",
+            "m.relates_to": {"rel_type": "m.thread", "event_id": root},
+        }),
+    )
+    .await;
+
+    let sync_with = |filter: serde_json::Value| {
+        let router = router.clone();
+        let token = alice_token.clone();
+        async move {
+            let (status, resp) = common::get_authed(
+                &router,
+                &format!(
+                    "/_matrix/client/v3/sync?filter={}",
+                    maelstrom_api::handlers::util::percent_encode(&filter.to_string())
+                ),
+                &token,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "sync failed: {resp}");
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()
+        }
+    };
+
+    // Rooms, timeline types and URLs, lazy members, thread counts and fields
+    let sync = sync_with(serde_json::json!({
+        "event_fields": ["type", "sender", "content.body", "state_key"],
+        "room": {
+            "not_rooms": [other_room],
+            "timeline": {
+                "types": ["m.room.mess*"],
+                "contains_url": true,
+                "unread_thread_notifications": true,
+            },
+            "state": {"lazy_load_members": true},
+        },
+    }))
+    .await;
+    let joined = &sync["rooms"]["join"];
+    assert!(joined.get(&other_room).is_none(), "{sync}");
+    let room = &joined[&room_id];
+    let timeline = room["timeline"]["events"].as_array().unwrap();
+    assert_eq!(timeline.len(), 1, "{room}");
+    assert_eq!(
+        timeline[0],
+        serde_json::json!({"type": "m.room.message", "sender": bob_id, "content": {"body": "Picture"}})
+    );
+    let members: Vec<&str> = room["state"]["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter(|e| e["type"] == "m.room.member")
+        .map(|e| e["state_key"].as_str().unwrap())
+        .collect();
+    assert!(members.contains(&bob_id.as_str()), "{members:?}");
+    assert_eq!(room["unread_notifications"]["notification_count"], 0);
+    // Counts are not tracked, so there are no per-thread ones to split off
+    assert!(room.get("unread_thread_notifications").is_none(), "{room}");
+
+    // /messages pages through the filter, and lazy-loaded members the device
+    // already has are only resent on request
+    let messages = |filter: serde_json::Value| {
+        let router = router.clone();
+        let token = alice_token.clone();
+        let room_id = room_id.clone();
+        async move {
+            let (status, resp) = common::get_authed(
+                &router,
+                &format!(
+                    "/_matrix/client/v3/rooms/{room_id}/messages?dir=b&limit=2&filter={}",
+                    maelstrom_api::handlers::util::percent_encode(&filter.to_string())
+                ),
+                &token,
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK, "messages failed: {resp}");
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()
+        }
+    };
+    let page = messages(serde_json::json!({
+        "types": ["m.room.message"],
+        "not_senders": [alice_id],
+        "lazy_load_members": true,
+    }))
+    .await;
+    let bodies: Vec<&str> = page["chunk"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["content"]["body"].as_str().unwrap())
+        .collect();
+    assert_eq!(bodies, ["In thread", "Picture"]);
+    assert!(
+        page.get("state")
+            .is_none_or(|s| s.as_array().unwrap().is_empty()),
+        "{page}"
+    );
+
+    let page = messages(serde_json::json!({
+        "types": ["m.room.message"],
+        "not_senders": [alice_id],
+        "lazy_load_members": true,
+        "include_redundant_members": true,
+    }))
+    .await;
+    assert!(
+        page["state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["state_key"] == bob_id.as_str()),
+        "{page}"
+    );
+
+    // Senders and the federation format
+    let sync = sync_with(serde_json::json!({
+        "event_format": "federation",
+        "room": {
+            "rooms": [room_id],
+            "timeline": {"not_senders": [bob_id], "not_types": ["m.room.member"]},
+        },
+    }))
+    .await;
+    let timeline = sync["rooms"]["join"][&room_id]["timeline"]["events"]
+        .as_array()
+        .unwrap();
+    assert!(!timeline.is_empty());
+    assert!(timeline.iter().all(|e| e["sender"] == alice_id.as_str()));
+    assert!(timeline.iter().all(|e| e.get("unsigned").is_none()));
+    assert!(
+        timeline
+            .iter()
+            .any(|e| e["content"]["body"] == "Thread root")
+    );
+}
+
+const SIMPLIFIED_SYNC: &str = "/_matrix/client/unstable/org.matrix.simplified_msc3575/sync";
+
+#[tokio::test]
+async fn test_simplified_sliding_sync() {
+    let router = common::test_router();
+    let (alice_token, alice_id, _) = common::register_user(&router, "ssalice", "pass").await;
+    let (bob_token, bob_id, _) = common::register_user(&router, "ssbob", "pass").await;
+
+    let create = |token: &str, body: serde_json::Value| {
+        let router = router.clone();
+        let token = token.to_string();
+        async move {
+            let (_, resp) =
+                common::post_json_authed(&router, "/_matrix/client/v3/createRoom", &body, &token)
+                    .await;
+            serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+    let join = |token: &str, room_id: &str| {
+        let router = router.clone();
+        let token = token.to_string();
+        let uri = format!("/_matrix/client/v3/rooms/{room_id}/join");
+        async move {
+            common::post_json_authed(&router, &uri, &serde_json::json!({}), &token).await;
+        }
+    };
+    let general = create(
+        &alice_token,
+        serde_json::json!({"preset": "public_chat", "name": "General"}),
+    )
+    .await;
+    join(&bob_token, &general).await;
+    let dm = create(&alice_token, serde_json::json!({"preset": "public_chat"})).await;
+    join(&bob_token, &dm).await;
+    common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/user/{alice_id}/account_data/m.direct"),
+        &serde_json::json!({ bob_id.clone(): [dm] }),
+        &alice_token,
+    )
+    .await;
+    let invited = create(&bob_token, serde_json::json!({"name": "Invite"})).await;
+    common::post_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{invited}/invite"),
+        &serde_json::json!({"user_id": alice_id}),
+        &bob_token,
+    )
+    .await;
+
+    let request = serde_json::json!({
+        "conn_id": "main",
+        "lists": {
+            "all": {
+                "range": [0, 10],
+                "timeline_limit": 1,
+                "required_state": [["m.room.name", ""], ["m.room.member", "$LAZY"]],
+            },
+            "dms": {"range": [0, 10], "timeline_limit": 0, "filters": {"is_dm": true}},
+            "invites": {"timeline_limit": 0, "filters": {"is_invite": true}},
+        },
+        "extensions": {"account_data": {"enabled": true}},
+    });
+    let sync = |pos: Option<String>, timeout: u64| {
+        let router = router.clone();
+        let token = alice_token.clone();
+        let request = request.clone();
+        async move {
+            let uri = match pos {
+                Some(pos) => format!("{SIMPLIFIED_SYNC}?pos={pos}&timeout={timeout}"),
+                None => SIMPLIFIED_SYNC.to_string(),
+            };
+            let (status, resp) = common::post_json_authed(&router, &uri, &request, &token).await;
+            (
+                status,
+                serde_json::from_str::<serde_json::Value>(&resp).unwrap(),
+            )
+        }
+    };
+
+    // A new connection gets every room in the lists
+    let (status, first) = sync(None, 0).await;
+    assert_eq!(status, StatusCode::OK, "sliding sync failed: {first}");
+    assert_eq!(first["lists"]["all"]["count"], 3);
+    assert_eq!(first["lists"]["dms"]["count"], 1);
+    assert_eq!(first["lists"]["invites"]["count"], 1);
+    assert!(first["lists"]["all"].get("ops").is_none());
+
+    let room = &first["rooms"][&general];
+    assert_eq!(room["name"], "General");
+    assert_eq!(room["initial"], true);
+    assert_eq!(room["timeline"].as_array().unwrap().len(), 1);
+    assert!(room["bump_stamp"].is_i64());
+    assert!(
+        room["required_state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.room.name")
+    );
+    let room = &first["rooms"][&dm];
+    assert_eq!(room["is_dm"], true);
+    assert_eq!(room["heroes"][0]["user_id"], bob_id.as_str());
+    let room = &first["rooms"][&invited];
+    assert!(room.get("timeline").is_none());
+    assert!(
+        room["invite_state"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.room.name" && e["content"]["name"] == "Invite")
+    );
+    assert!(
+        first["extensions"]["account_data"]["global"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == "m.direct")
+    );
+
+    // Nothing changed, so nothing is resent
+    let pos = first["pos"].as_str().unwrap().to_string();
+    let (_, second) = sync(Some(pos.clone()), 0).await;
+    assert!(second.get("rooms").is_none(), "{second}");
+    assert!(
+        second["extensions"]["account_data"]["global"]
+            .as_array()
+            .unwrap()
+            .is_empty()
+    );
+
+    // A long-poll wakes up for a new message and only sends what is new
+    let pos = second["pos"].as_str().unwrap().to_string();
+    let send_later = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{general}/send/m.room.message/late"),
+            &serde_json::json!({"msgtype": "m.text", "body": "Wake up"}),
+            &bob_token,
+        )
+        .await;
+    };
+    let ((_, third), _) = tokio::join!(sync(Some(pos.clone()), 5000), send_later);
+    let rooms = third["rooms"].as_object().unwrap();
+    assert_eq!(rooms.len(), 1, "{third}");
+    let room = &rooms[&general];
+    assert!(room.get("initial").is_none());
+    assert_eq!(room["num_live"], 1);
+    assert_eq!(room["timeline"][0]["content"]["body"], "Wake up");
+    assert!(room.get("notification_count").is_none());
+
+    // Retrying a request gets the same answer; an unknown pos does not
+    let (_, retried) = sync(Some(pos), 0).await;
+    assert_eq!(
+        retried["rooms"][&general]["timeline"][0]["content"]["body"],
+        "Wake up"
+    );
+    let (status, resp) = sync(Some("999_999".to_string()), 0).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(resp["errcode"], "M_UNKNOWN_POS");
+
+    // MSC3575 clients get list ops, here sorted by name
+    let (status, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/sync",
+        &serde_json::json!({
+            "lists": {"named": {"ranges": [[0, 10]], "sort": ["by_name"], "timeline_limit": 0}},
+        }),
+        &alice_token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "sliding sync failed: {resp}");
+    let legacy: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(
+        legacy["lists"]["named"]["ops"][0]["room_ids"],
+        serde_json::json!([dm, general, invited])
+    );
+}
+
+#[tokio::test]
+async fn test_thread_subscriptions() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "subscriber", "pass").await;
+
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let (_, resp) = common::put_json_authed(
+        &router,
+        &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/root"),
+        &serde_json::json!({"msgtype": "m.text", "body": "Thread root"}),
+        &token,
+    )
+    .await;
+    let root = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["event_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let subscription = format!(
+        "/_matrix/client/unstable/io.element.msc4306/rooms/{room_id}/thread/{root}/subscription"
+    );
+
+    let (status, _) = common::get_authed(&router, &subscription, &token).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) =
+        common::put_json_authed(&router, &subscription, &serde_json::json!({}), &token).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, resp) = common::get_authed(&router, &subscription, &token).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&resp).unwrap(),
+        serde_json::json!({"automatic": false})
+    );
+
+    // The sliding sync extension reports it
+    let (_, resp) = common::post_json_authed(
+        &router,
+        SIMPLIFIED_SYNC,
+        &serde_json::json!({"extensions": {"thread_subscriptions": {"enabled": true}}}),
+        &token,
+    )
+    .await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(
+        sync["extensions"]["thread_subscriptions"]["subscribed"][&room_id][&root]["automatic"],
+        false
+    );
+
+    // An automatic subscription caused by an event from before the user
+    // unsubscribed is refused
+    let (status, _) = common::delete_authed(&router, &subscription, &token).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, resp) = common::put_json_authed(
+        &router,
+        &subscription,
+        &serde_json::json!({"automatic": root}),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(resp.contains("IO_ELEMENT_MSC4306_CONFLICTING_UNSUBSCRIPTION"));
+}
+
+#[tokio::test]
+async fn test_thread_subscriptions_past_the_limit_are_paged() {
+    let router = common::test_router();
+    let (token, _, _) = common::register_user(&router, "follower", "pass").await;
+    let (_, resp) = common::post_json_authed(
+        &router,
+        "/_matrix/client/v3/createRoom",
+        &serde_json::json!({}),
+        &token,
+    )
+    .await;
+    let room_id = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["room_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let mut roots = Vec::new();
+    for i in 0..3 {
+        let (_, resp) = common::put_json_authed(
+            &router,
+            &format!("/_matrix/client/v3/rooms/{room_id}/send/m.room.message/root{i}"),
+            &serde_json::json!({"msgtype": "m.text", "body": format!("Thread {i}")}),
+            &token,
+        )
+        .await;
+        let root = serde_json::from_str::<serde_json::Value>(&resp).unwrap()["event_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let (status, _) = common::put_json_authed(
+            &router,
+            &format!(
+                "/_matrix/client/unstable/io.element.msc4306/rooms/{room_id}/thread/{root}/subscription"
+            ),
+            &serde_json::json!({}),
+            &token,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        roots.push(root);
+    }
+    let threads = |changes: &serde_json::Value| -> Vec<String> {
+        changes["subscribed"][&room_id]
+            .as_object()
+            .map(|threads| threads.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+
+    // The newest two come with the response, and a token for the rest
+    let (_, resp) = common::post_json_authed(
+        &router,
+        SIMPLIFIED_SYNC,
+        &serde_json::json!({"extensions": {"thread_subscriptions": {"enabled": true, "limit": 2}}}),
+        &token,
+    )
+    .await;
+    let sync: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    let extension = &sync["extensions"]["thread_subscriptions"];
+    let mut sent = threads(extension);
+    sent.sort();
+    let mut newest = roots[1..].to_vec();
+    newest.sort();
+    assert_eq!(sent, newest);
+    let prev_batch = extension["prev_batch"].as_str().unwrap();
+
+    let (status, resp) = common::get_authed(
+        &router,
+        &format!(
+            "/_matrix/client/unstable/io.element.msc4308/thread_subscriptions?dir=b&from={prev_batch}"
+        ),
+        &token,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{resp}");
+    let page: serde_json::Value = serde_json::from_str(&resp).unwrap();
+    assert_eq!(threads(&page), vec![roots[0].clone()]);
+    assert!(page.get("end").is_none());
+}